@@ -0,0 +1,370 @@
+use crate::{backup, caller_is_admin, config, ratelimit, Error, Memory, StringKey, MEMORY_MANAGER};
+use candid::{Decode, Encode, Principal};
+use ic_cdk::api::call::{accept_message, method_name};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use std::{borrow::Cow, cell::RefCell};
+
+// Define an enum for who may call a method, from the most to the least open
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq, PartialOrd)]
+pub enum Access {
+    // Anyone, including the anonymous principal
+    Public,
+    // Any signed-in identity
+    AuthRequired,
+    // Admins only
+    RoleRequired,
+}
+
+// Define a struct for a row of the policy matrix
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub struct MethodPolicy {
+    method: String,
+    default_access: Access,
+    access: Access,
+}
+
+// Default access of the update methods, those not listed require a signed-in caller.
+// Queries aren't listed, the IC only lets a canister inspect update calls.
+const METHOD_ACCESS: &[(&str, Access)] = &[
+    // Events, users and tickets
+    ("create_event", Access::AuthRequired),
+    ("update_event", Access::AuthRequired),
+    ("expand_event_capacity", Access::AuthRequired),
+    ("complete_event", Access::AuthRequired),
+    ("set_event_timezone", Access::AuthRequired),
+    ("set_event_translation", Access::AuthRequired),
+    ("delete_event", Access::AuthRequired),
+    ("create_user", Access::AuthRequired),
+    ("update_user", Access::AuthRequired),
+    ("delete_user", Access::AuthRequired),
+    ("create_ticket", Access::AuthRequired),
+    ("update_ticket", Access::AuthRequired),
+    ("check_in_ticket", Access::AuthRequired),
+    ("check_in_by_code", Access::AuthRequired),
+    ("cancel_my_ticket", Access::AuthRequired),
+    ("icrc7_transfer", Access::AuthRequired),
+    ("link_wallet", Access::AuthRequired),
+    ("unlink_wallet", Access::AuthRequired),
+    ("delete_ticket", Access::AuthRequired),
+    ("remove_user_ticket", Access::AuthRequired),
+    ("create_tickets_bulk", Access::AuthRequired),
+    ("checkout", Access::AuthRequired),
+    ("cancel_tickets_bulk", Access::AuthRequired),
+    ("import_users", Access::AuthRequired),
+    ("restore_event", Access::AuthRequired),
+    ("restore_user", Access::AuthRequired),
+    ("purge_deleted", Access::RoleRequired),
+    ("place_legal_hold", Access::RoleRequired),
+    ("lift_legal_hold", Access::RoleRequired),
+    // Sign-in
+    ("login", Access::AuthRequired),
+    ("logout", Access::AuthRequired),
+    ("change_password", Access::AuthRequired),
+    ("create_api_key", Access::RoleRequired),
+    ("revoke_api_key", Access::RoleRequired),
+    ("set_method_access", Access::RoleRequired),
+    ("set_reauth_policy", Access::RoleRequired),
+    // Announcements and embargoes
+    ("watch_event", Access::AuthRequired),
+    ("unwatch_event", Access::AuthRequired),
+    ("announce_event", Access::AuthRequired),
+    ("set_event_embargo", Access::RoleRequired),
+    ("lift_event_embargo", Access::RoleRequired),
+    ("view_embargoed_event", Access::AuthRequired),
+    // Invitations
+    ("invite_users", Access::AuthRequired),
+    ("create_invite_link", Access::AuthRequired),
+    ("redeem_invite_link", Access::AuthRequired),
+    ("rsvp", Access::AuthRequired),
+    // Sales
+    ("create_ticket_tier", Access::AuthRequired),
+    ("update_ticket_tier", Access::AuthRequired),
+    ("delete_ticket_tier", Access::AuthRequired),
+    ("create_event_addon", Access::AuthRequired),
+    ("update_event_addon", Access::AuthRequired),
+    ("delete_event_addon", Access::AuthRequired),
+    ("register_organizer", Access::AuthRequired),
+    ("add_event_organizer", Access::AuthRequired),
+    ("remove_event_organizer", Access::AuthRequired),
+    ("set_seat_map", Access::AuthRequired),
+    ("hold_seat", Access::AuthRequired),
+    ("reserve_ticket", Access::AuthRequired),
+    ("confirm_reservation", Access::AuthRequired),
+    ("cancel_reservation", Access::AuthRequired),
+    ("create_group_booking", Access::AuthRequired),
+    ("cancel_group_booking", Access::AuthRequired),
+    ("claim_group_ticket", Access::AuthRequired),
+    ("issue_pass", Access::AuthRequired),
+    ("claim_pass_ticket", Access::AuthRequired),
+    ("check_in_pass", Access::AuthRequired),
+    ("set_resale_blackouts", Access::AuthRequired),
+    ("set_event_payment", Access::AuthRequired),
+    ("get_ckbtc_deposit", Access::AuthRequired),
+    ("set_refund_policy", Access::AuthRequired),
+    ("set_cancellation_policy", Access::AuthRequired),
+    ("cancel_ticket", Access::AuthRequired),
+    ("reschedule_event", Access::AuthRequired),
+    ("refund_rescheduled_ticket", Access::AuthRequired),
+    ("set_capacity_alert_settings", Access::AuthRequired),
+    ("get_event_revenue", Access::AuthRequired),
+    ("set_refund_preference", Access::AuthRequired),
+    ("register_event_webhook", Access::AuthRequired),
+    ("remove_event_webhook", Access::AuthRequired),
+    ("set_resale_cap", Access::AuthRequired),
+    ("list_ticket_for_resale", Access::AuthRequired),
+    ("cancel_resale_listing", Access::AuthRequired),
+    ("buy_resale_ticket", Access::AuthRequired),
+    ("create_promo_code", Access::AuthRequired),
+    ("update_promo_code", Access::AuthRequired),
+    ("delete_promo_code", Access::AuthRequired),
+    ("create_flash_sale", Access::AuthRequired),
+    ("update_flash_sale", Access::AuthRequired),
+    ("cancel_flash_sale", Access::AuthRequired),
+    ("set_event_insurance", Access::AuthRequired),
+    ("remove_event_insurance", Access::AuthRequired),
+    ("refund_insured_ticket", Access::AuthRequired),
+    ("enable_event_donations", Access::AuthRequired),
+    ("disable_event_donations", Access::AuthRequired),
+    ("set_ticket_code_format", Access::AuthRequired),
+    ("remove_ticket_code_format", Access::AuthRequired),
+    ("enable_qr_tokens", Access::AuthRequired),
+    ("disable_qr_tokens", Access::AuthRequired),
+    ("enable_test_mode", Access::AuthRequired),
+    ("disable_test_mode", Access::AuthRequired),
+    ("reset_test_event", Access::AuthRequired),
+    ("join_waitlist", Access::AuthRequired),
+    ("leave_waitlist", Access::AuthRequired),
+    // Series
+    ("create_event_series", Access::AuthRequired),
+    ("import_event_template", Access::AuthRequired),
+    ("clone_event", Access::AuthRequired),
+    ("save_event_template", Access::AuthRequired),
+    ("create_event_from_template", Access::AuthRequired),
+    ("delete_event_template", Access::AuthRequired),
+    ("update_series_event", Access::AuthRequired),
+    // Sponsorship
+    ("create_sponsorship_offer", Access::AuthRequired),
+    ("record_sponsorship_commitment", Access::AuthRequired),
+    ("mark_sponsorship_paid", Access::AuthRequired),
+    ("fulfill_sponsorship", Access::AuthRequired),
+    ("cancel_sponsorship", Access::AuthRequired),
+    // Statements
+    ("close_statement", Access::AuthRequired),
+    ("record_payout", Access::RoleRequired),
+    ("set_payout_account", Access::AuthRequired),
+    ("set_revenue_split", Access::AuthRequired),
+    ("withdraw_event_revenue", Access::AuthRequired),
+    ("set_resale_fee", Access::RoleRequired),
+    // Images
+    ("begin_upload", Access::AuthRequired),
+    ("upload_chunk", Access::AuthRequired),
+    ("finish_upload", Access::AuthRequired),
+    ("delete_event_image", Access::AuthRequired),
+    // Privacy
+    ("set_anonymization_policy", Access::AuthRequired),
+    ("erase_my_data", Access::AuthRequired),
+    ("set_display_preferences", Access::AuthRequired),
+    // Attendees
+    ("set_attendee_note", Access::AuthRequired),
+    ("set_attendance_badge", Access::AuthRequired),
+    ("claim_attendance_proof", Access::AuthRequired),
+    ("delete_attendee_note", Access::AuthRequired),
+    // Memberships
+    ("create_membership_tier", Access::AuthRequired),
+    ("update_membership_tier", Access::AuthRequired),
+    ("grant_membership", Access::AuthRequired),
+    ("revoke_membership", Access::AuthRequired),
+    ("set_event_on_sale", Access::AuthRequired),
+    // Pre-sale registration
+    ("set_presale_registration", Access::AuthRequired),
+    ("register_for_presale", Access::AuthRequired),
+    ("verify_registrations", Access::AuthRequired),
+    // Favorites
+    ("favorite_event", Access::AuthRequired),
+    ("unfavorite_event", Access::AuthRequired),
+    ("follow_organizer", Access::AuthRequired),
+    ("unfollow_organizer", Access::AuthRequired),
+    ("set_event_survey", Access::AuthRequired),
+    ("submit_survey_response", Access::AuthRequired),
+    ("submit_event_review", Access::AuthRequired),
+    ("set_review_hidden", Access::AuthRequired),
+    ("report_review", Access::AuthRequired),
+    ("remove_review", Access::RoleRequired),
+    ("send_event_reminder", Access::AuthRequired),
+    ("resend_failed", Access::RoleRequired),
+    ("set_notification_webhook", Access::RoleRequired),
+    // Venue
+    ("register_gate_device", Access::RoleRequired),
+    ("remove_gate_device", Access::RoleRequired),
+    ("report_gate_count", Access::AuthRequired),
+    ("register_scanner_device", Access::AuthRequired),
+    ("remove_scanner_device", Access::AuthRequired),
+    ("sync_checkins", Access::AuthRequired),
+    ("set_event_rooms", Access::AuthRequired),
+    // Operations
+    ("set_validation_hook", Access::RoleRequired),
+    ("configure_job", Access::RoleRequired),
+    ("run_job", Access::RoleRequired),
+    ("start_backup", Access::RoleRequired),
+    ("finish_backup", Access::RoleRequired),
+    ("restore_chunk", Access::RoleRequired),
+    ("finalize_restore", Access::RoleRequired),
+    ("start_compaction", Access::RoleRequired),
+    ("set_archival_settings", Access::RoleRequired),
+    ("set_federation_config", Access::RoleRequired),
+    ("set_test_time", Access::RoleRequired),
+    ("set_rate_limit", Access::RoleRequired),
+    ("deny_principal", Access::RoleRequired),
+    ("allow_principal", Access::RoleRequired),
+    ("set_ckbtc_config", Access::RoleRequired),
+    ("refresh_exchange_rates", Access::RoleRequired),
+    ("set_low_cycles_threshold", Access::RoleRequired),
+    ("update_config", Access::RoleRequired),
+    ("set_storage_quotas", Access::RoleRequired),
+    // Moderation
+    ("report_event", Access::AuthRequired),
+    ("report_user", Access::AuthRequired),
+    ("review_report", Access::RoleRequired),
+    ("suspend_event", Access::RoleRequired),
+    ("reinstate_event", Access::RoleRequired),
+    ("ban_user", Access::RoleRequired),
+    ("unban_user", Access::RoleRequired),
+    // Support
+    ("begin_support_session", Access::RoleRequired),
+    ("end_support_session", Access::RoleRequired),
+    ("support_get_tickets", Access::RoleRequired),
+    ("support_resend_ticket", Access::RoleRequired),
+    ("support_transfer_ticket", Access::RoleRequired),
+    // Disputes
+    ("open_dispute", Access::AuthRequired),
+    ("reply_to_dispute", Access::AuthRequired),
+    ("resolve_dispute", Access::AuthRequired),
+    ("claim_dispute_refund", Access::AuthRequired),
+    // Fraud
+    ("review_fraud_flag", Access::AuthRequired),
+    // Venues
+    ("create_venue", Access::AuthRequired),
+    ("update_venue", Access::AuthRequired),
+    ("delete_venue", Access::AuthRequired),
+    ("set_event_venue", Access::AuthRequired),
+    // The HTTP gateway calls in as the anonymous principal
+    ("http_request_update", Access::Public),
+];
+
+impl Storable for Access {
+    // Conversion to bytes
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    // Conversion from bytes
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for Access {
+    const MAX_SIZE: u32 = 32;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    // Access set by an admin in place of the default, keyed by method name
+    static ACCESS_OVERRIDES: RefCell<StableBTreeMap<StringKey, Access, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(32)))
+    ));
+}
+
+// The single guard of every update call, run by the IC before the call is accepted
+#[ic_cdk::inspect_message]
+fn inspect_message() {
+    let method = method_name();
+    if backup::in_maintenance() && !backup::allowed_in_maintenance(&method) {
+        ic_cdk::trap("the canister is busy with a backup, restore or compaction, try again later");
+    }
+    // Admins keep access, so they can turn the switch back off
+    if config::is_read_only() && caller_is_admin().is_err() {
+        ic_cdk::trap("the canister is read-only for now, try again later");
+    }
+    if ic_cdk::api::call::arg_data_raw_size() > config::max_arg_bytes() {
+        ic_cdk::trap(&format!(
+            "the argument of {} is larger than the {} bytes accepted",
+            method,
+            config::max_arg_bytes()
+        ));
+    }
+    // Abusive principals are turned away here, before their calls cost any cycles
+    match check_caller(effective_access(&method)).and_then(|_| ratelimit::check_caller()) {
+        Ok(()) => accept_message(),
+        Err(msg) => ic_cdk::trap(&msg),
+    }
+}
+
+#[ic_cdk::query(guard = "caller_is_admin")]
+fn get_access_policy() -> Vec<MethodPolicy> {
+    METHOD_ACCESS
+        .iter()
+        .map(|(method, _)| method_policy(method))
+        .collect()
+}
+
+#[ic_cdk::update(guard = "caller_is_admin")]
+fn set_method_access(method: String, access: Option<Access>) -> Result<MethodPolicy, Error> {
+    let default_access = default_access(&method).ok_or(Error::NotFound {
+        entity: "method".to_string(),
+        id: method.to_string(),
+        msg: format!("method {} does not exist", method),
+    })?;
+
+    // Admin methods check the caller themselves, as calls from other canisters aren't inspected
+    if default_access == Access::RoleRequired
+        && access.is_some_and(|access| access < default_access)
+    {
+        return Err(Error::ValidationFailed {
+            msg: format!("method {} is restricted to admins", method),
+        });
+    }
+
+    let key = StringKey(method.clone());
+    ACCESS_OVERRIDES.with(|overrides| match access {
+        Some(access) => overrides.borrow_mut().insert(key, access),
+        None => overrides.borrow_mut().remove(&key),
+    });
+
+    Ok(method_policy(&method))
+}
+
+// Check the caller against an access level
+fn check_caller(access: Access) -> Result<(), String> {
+    match access {
+        Access::Public => Ok(()),
+        Access::AuthRequired if ic_cdk::caller() == Principal::anonymous() => {
+            Err("anonymous callers cannot make changes, sign in first".to_string())
+        }
+        Access::AuthRequired => Ok(()),
+        Access::RoleRequired => caller_is_admin(),
+    }
+}
+
+fn default_access(method: &str) -> Option<Access> {
+    METHOD_ACCESS
+        .iter()
+        .find(|(name, _)| *name == method)
+        .map(|(_, access)| *access)
+}
+
+fn effective_access(method: &str) -> Access {
+    ACCESS_OVERRIDES
+        .with(|overrides| overrides.borrow().get(&StringKey(method.to_string())))
+        .or(default_access(method))
+        .unwrap_or(Access::AuthRequired)
+}
+
+fn method_policy(method: &str) -> MethodPolicy {
+    MethodPolicy {
+        method: method.to_string(),
+        default_access: default_access(method).unwrap_or(Access::AuthRequired),
+        access: effective_access(method),
+    }
+}
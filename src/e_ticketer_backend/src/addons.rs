@@ -0,0 +1,327 @@
+use crate::clock::time;
+use crate::ratelimit::rate_limit;
+use crate::{
+    _get_event, _get_ticket, organizers, Error, Memory, Ticket, ID_COUNTER, MEMORY_MANAGER,
+};
+use candid::{Decode, Encode};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use std::{borrow::Cow, cell::RefCell};
+
+const MAX_NAME_LEN: usize = 64;
+// Lines on one ticket, so they fit in the ticket's stable map entry
+const MAX_LINES: usize = 4;
+const MAX_QUANTITY: u64 = 20;
+
+// Define a struct for an extra sold along with an event's tickets (e.g. parking, a t-shirt)
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub struct AddOn {
+    id: u64,
+    event_id: u64,
+    name: String,
+    // Charged per unit in the currency of the ticket it is bought with
+    price: u64,
+    // None for no limit
+    stock: Option<u64>,
+    sold: u64,
+    created_at: u64,
+    updated_at: Option<u64>,
+}
+
+// Define a struct for the payload used to create and update add-ons
+#[derive(candid::CandidType, Serialize, Deserialize, Default)]
+pub struct AddOnPayload {
+    name: String,
+    price: u64,
+    stock: Option<u64>,
+}
+
+// Define a struct for an add-on picked in a ticket purchase
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct AddOnSelection {
+    addon_id: u64,
+    quantity: u64,
+}
+
+// Define a struct for an add-on bought with a ticket, priced as it was at the purchase
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct AddOnLine {
+    addon_id: u64,
+    quantity: u64,
+    unit_price: u64,
+}
+
+// Define a struct for what is to be handed out of an add-on, counted over the tickets held now
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub struct AddOnSales {
+    addon_id: u64,
+    name: String,
+    quantity: u64,
+    tickets: u64,
+    revenue: u64,
+    // None for no limit
+    remaining: Option<u64>,
+}
+
+impl Storable for AddOn {
+    // Conversion to bytes
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    // Conversion from bytes
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for AddOn {
+    const MAX_SIZE: u32 = 256;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    // Add-ons keyed by (event id, add-on id)
+    static ADDON_STORAGE: RefCell<StableBTreeMap<(u64, u64), AddOn, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(135)))
+    ));
+}
+
+#[ic_cdk::query]
+fn get_event_addons(event_id: u64) -> Result<Vec<AddOn>, Error> {
+    // Make sure the event exists, or return a NotFound error if not found
+    _get_event(&event_id).ok_or(Error::not_found("event", event_id))?;
+    Ok(event_addons(event_id))
+}
+
+#[ic_cdk::update(guard = "rate_limit")]
+fn create_event_addon(event_id: u64, payload: AddOnPayload) -> Result<AddOn, Error> {
+    organizers::authorize_event(event_id)?;
+    validate_payload(&payload).map_err(|msg| Error::ValidationFailed { msg })?;
+
+    // Increment the global ID counter to get a new ID for the add-on
+    let id = ID_COUNTER
+        .with(|counter| {
+            let current_id = *counter.borrow().get();
+            counter.borrow_mut().set(current_id + 1)
+        })
+        .expect("Cannot increment Ids");
+
+    let addon = AddOn {
+        id,
+        event_id,
+        name: payload.name,
+        price: payload.price,
+        stock: payload.stock,
+        sold: 0,
+        created_at: time(),
+        updated_at: None,
+    };
+    ADDON_STORAGE.with(|addons| addons.borrow_mut().insert((event_id, id), addon.clone()));
+
+    Ok(addon)
+}
+
+// Change an add-on, tickets already sold keep the price they were bought at
+#[ic_cdk::update(guard = "rate_limit")]
+fn update_event_addon(event_id: u64, addon_id: u64, payload: AddOnPayload) -> Result<AddOn, Error> {
+    organizers::authorize_event(event_id)?;
+    let addon = _get_addon(event_id, addon_id).ok_or(addon_not_found(event_id, addon_id))?;
+    validate_payload(&payload).map_err(|msg| Error::ValidationFailed { msg })?;
+
+    // Stock can't shrink below what has already been sold
+    if let Some(stock) = payload.stock {
+        if stock < addon.sold {
+            return Err(Error::ValidationFailed {
+                msg: format!(
+                    "add-on id:{} already sold {}, more than stock {}",
+                    addon_id, addon.sold, stock
+                ),
+            });
+        }
+    }
+
+    let updated_addon = AddOn {
+        name: payload.name,
+        price: payload.price,
+        stock: payload.stock,
+        updated_at: Some(time()),
+        ..addon
+    };
+    ADDON_STORAGE.with(|addons| {
+        addons
+            .borrow_mut()
+            .insert((event_id, addon_id), updated_addon.clone())
+    });
+
+    Ok(updated_addon)
+}
+
+#[ic_cdk::update(guard = "rate_limit")]
+fn delete_event_addon(event_id: u64, addon_id: u64) -> Result<String, Error> {
+    organizers::authorize_event(event_id)?;
+    let addon = _get_addon(event_id, addon_id).ok_or(addon_not_found(event_id, addon_id))?;
+
+    // Tickets keep referencing their add-ons, so sold ones can't be removed
+    if addon.sold > 0 {
+        return Err(Error::ValidationFailed {
+            msg: format!("add-on id:{} has been sold", addon_id),
+        });
+    }
+
+    ADDON_STORAGE.with(|addons| addons.borrow_mut().remove(&(event_id, addon_id)));
+    Ok(format!("add-on id: {} deleted", addon_id))
+}
+
+// Quantities of each add-on of an event to hand out, from the line items of its tickets
+#[ic_cdk::query]
+fn get_event_addon_sales(event_id: u64) -> Result<Vec<AddOnSales>, Error> {
+    let event = organizers::authorize_event(event_id)?;
+    let mut sales: Vec<AddOnSales> = event_addons(event_id)
+        .into_iter()
+        .map(|addon| AddOnSales {
+            addon_id: addon.id,
+            remaining: remaining(&addon),
+            name: addon.name,
+            quantity: 0,
+            tickets: 0,
+            revenue: 0,
+        })
+        .collect();
+
+    for ticket in event.ticket_ids.iter().filter_map(_get_ticket) {
+        for line in ticket.addons.unwrap_or_default() {
+            if let Some(sale) = sales.iter_mut().find(|sale| sale.addon_id == line.addon_id) {
+                sale.quantity += line.quantity;
+                sale.tickets += 1;
+                sale.revenue += line.quantity * line.unit_price;
+            }
+        }
+    }
+    Ok(sales)
+}
+
+// Check the add-ons picked for a ticket are on sale and in stock, and price them
+pub(crate) fn quote_addons(
+    event_id: u64,
+    selections: &[AddOnSelection],
+) -> Result<Vec<AddOnLine>, String> {
+    if selections.len() > MAX_LINES {
+        return Err(format!(
+            "a ticket can come with at most {} add-ons",
+            MAX_LINES
+        ));
+    }
+    let mut lines: Vec<AddOnLine> = vec![];
+    for selection in selections {
+        if selection.quantity == 0 || selection.quantity > MAX_QUANTITY {
+            return Err(format!(
+                "add-on id:{} must be bought 1 to {} times",
+                selection.addon_id, MAX_QUANTITY
+            ));
+        }
+        if lines.iter().any(|line| line.addon_id == selection.addon_id) {
+            return Err(format!(
+                "add-on id:{} is picked more than once",
+                selection.addon_id
+            ));
+        }
+        let addon = _get_addon(event_id, selection.addon_id).ok_or(format!(
+            "add-on id:{} does not exist for event id:{}",
+            selection.addon_id, event_id
+        ))?;
+        if remaining(&addon).is_some_and(|remaining| remaining < selection.quantity) {
+            return Err(format!(
+                "add-on id:{} has {} left",
+                addon.id,
+                remaining(&addon).unwrap_or(0)
+            ));
+        }
+        lines.push(AddOnLine {
+            addon_id: addon.id,
+            quantity: selection.quantity,
+            unit_price: addon.price,
+        });
+    }
+    Ok(lines)
+}
+
+// What the add-ons of a ticket cost
+pub(crate) fn lines_total(lines: &[AddOnLine]) -> u64 {
+    lines
+        .iter()
+        .map(|line| line.quantity * line.unit_price)
+        .sum()
+}
+
+// What the add-ons bought with a ticket cost
+pub(crate) fn ticket_addons_total(ticket: &Ticket) -> u64 {
+    ticket.addons.as_deref().map_or(0, lines_total)
+}
+
+// Take the add-ons of a sold ticket out of stock
+pub(crate) fn record_addon_sale(event_id: u64, lines: &[AddOnLine]) {
+    for line in lines {
+        if let Some(mut addon) = _get_addon(event_id, line.addon_id) {
+            addon.sold += line.quantity;
+            ADDON_STORAGE.with(|addons| addons.borrow_mut().insert((event_id, addon.id), addon));
+        }
+    }
+}
+
+// Put the add-ons of a ticket back in stock, e.g. after a cancellation
+pub(crate) fn record_addon_release(event_id: u64, lines: &[AddOnLine]) {
+    for line in lines {
+        if let Some(mut addon) = _get_addon(event_id, line.addon_id) {
+            addon.sold = addon.sold.saturating_sub(line.quantity);
+            ADDON_STORAGE.with(|addons| addons.borrow_mut().insert((event_id, addon.id), addon));
+        }
+    }
+}
+
+// Drop the add-ons of a deleted event
+pub(crate) fn remove_event_addons(event_id: u64) {
+    for addon in event_addons(event_id) {
+        ADDON_STORAGE.with(|addons| addons.borrow_mut().remove(&(event_id, addon.id)));
+    }
+}
+
+fn _get_addon(event_id: u64, addon_id: u64) -> Option<AddOn> {
+    ADDON_STORAGE.with(|addons| addons.borrow().get(&(event_id, addon_id)))
+}
+
+fn event_addons(event_id: u64) -> Vec<AddOn> {
+    ADDON_STORAGE.with(|addons| {
+        addons
+            .borrow()
+            .range((event_id, 0)..=(event_id, u64::MAX))
+            .map(|(_, addon)| addon)
+            .collect()
+    })
+}
+
+fn remaining(addon: &AddOn) -> Option<u64> {
+    addon.stock.map(|stock| stock.saturating_sub(addon.sold))
+}
+
+fn validate_payload(payload: &AddOnPayload) -> Result<(), String> {
+    let name_len = payload.name.trim().chars().count();
+    if name_len == 0 || name_len > MAX_NAME_LEN {
+        return Err(format!(
+            "add-on name must be 1 to {} characters",
+            MAX_NAME_LEN
+        ));
+    }
+    Ok(())
+}
+
+fn addon_not_found(event_id: u64, addon_id: u64) -> Error {
+    Error::NotFound {
+        entity: "add-on".to_string(),
+        id: addon_id.to_string(),
+        msg: format!(
+            "add-on id:{} does not exist for event id:{}",
+            addon_id, event_id
+        ),
+    }
+}
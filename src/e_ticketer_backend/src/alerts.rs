@@ -0,0 +1,285 @@
+use crate::clock::time;
+use crate::notifications::{self, NotificationKind};
+use crate::ratelimit::rate_limit;
+use crate::webhooks::{self, WebhookEventType};
+use crate::{_get_event, _get_user, organizers, stats, Error, Event, Memory, MEMORY_MANAGER};
+use candid::{Decode, Encode};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use std::{borrow::Cow, cell::RefCell};
+
+const NANOS_PER_DAY: u64 = 24 * 60 * 60 * 1_000_000_000;
+// Days of sales the sell-out forecast goes by
+const FORECAST_WINDOW_DAYS: u64 = 7;
+const MAX_THRESHOLDS: usize = 10;
+const MAX_ALERT_RECIPIENTS: usize = 5;
+// Key of the forecast alert among the thresholds, which are 1 to 100 percent
+const FORECAST_KEY: u8 = 0;
+
+// Define a struct for when the organizers of an event are alerted about its sales
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct CapacityAlertSettings {
+    // Percent of the capacity sold, 100 alerts once the event sells out
+    thresholds: Vec<u8>,
+    // Alert once sales are on course to sell out before this time
+    sellout_by: Option<u64>,
+    // Users emailed with every alert, webhooks subscribed to alerts get them too
+    notify_user_ids: Vec<u64>,
+}
+
+// Define an enum for what an alert is about
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub enum CapacityAlertKind {
+    SellThrough { percent: u8 },
+    SoldOut,
+    // The recent pace of sales sells the event out by this time
+    SelloutForecast { projected_at: u64 },
+}
+
+// Define a struct for an alert raised about an event's sales
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct CapacityAlert {
+    event_id: u64,
+    kind: CapacityAlertKind,
+    sold: u64,
+    capacity: u64,
+    raised_at: u64,
+}
+
+impl Default for CapacityAlertSettings {
+    fn default() -> Self {
+        CapacityAlertSettings {
+            thresholds: vec![50, 80, 100],
+            sellout_by: None,
+            notify_user_ids: vec![],
+        }
+    }
+}
+
+impl Storable for CapacityAlertSettings {
+    // Conversion to bytes
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    // Conversion from bytes
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for CapacityAlertSettings {
+    const MAX_SIZE: u32 = 256;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl Storable for CapacityAlert {
+    // Conversion to bytes
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    // Conversion from bytes
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for CapacityAlert {
+    const MAX_SIZE: u32 = 256;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    // Settings keyed by event id, events without any get the default thresholds
+    static SETTINGS_STORAGE: RefCell<StableBTreeMap<u64, CapacityAlertSettings, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(83)))
+    ));
+
+    // Alerts raised keyed by (event id, threshold), each is raised once
+    static ALERT_STORAGE: RefCell<StableBTreeMap<(u64, u8), CapacityAlert, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(84)))
+    ));
+}
+
+#[ic_cdk::query]
+fn get_capacity_alert_settings(event_id: u64) -> Result<CapacityAlertSettings, Error> {
+    organizers::authorize_event(event_id)?;
+    Ok(alert_settings(event_id))
+}
+
+#[ic_cdk::update(guard = "rate_limit")]
+fn set_capacity_alert_settings(
+    event_id: u64,
+    mut settings: CapacityAlertSettings,
+) -> Result<CapacityAlertSettings, Error> {
+    organizers::authorize_event(event_id)?;
+    settings.thresholds.sort();
+    settings.thresholds.dedup();
+    if settings.thresholds.len() > MAX_THRESHOLDS
+        || settings
+            .thresholds
+            .iter()
+            .any(|percent| !(1..=100).contains(percent))
+    {
+        return Err(Error::ValidationFailed {
+            msg: format!(
+                "at most {} thresholds between 1 and 100 percent can be set",
+                MAX_THRESHOLDS
+            ),
+        });
+    }
+    if settings.notify_user_ids.len() > MAX_ALERT_RECIPIENTS {
+        return Err(Error::ValidationFailed {
+            msg: format!(
+                "alerts can be sent to at most {} users",
+                MAX_ALERT_RECIPIENTS
+            ),
+        });
+    }
+    if let Some(user_id) = settings
+        .notify_user_ids
+        .iter()
+        .find(|user_id| _get_user(user_id).is_none())
+    {
+        return Err(Error::not_found("user", user_id));
+    }
+    SETTINGS_STORAGE.with(|storage| storage.borrow_mut().insert(event_id, settings.clone()));
+    Ok(settings)
+}
+
+#[ic_cdk::query]
+fn get_capacity_alerts(event_id: u64) -> Result<Vec<CapacityAlert>, Error> {
+    organizers::authorize_event(event_id)?;
+    let mut alerts: Vec<CapacityAlert> = ALERT_STORAGE.with(|alerts| {
+        alerts
+            .borrow()
+            .range((event_id, 0)..=(event_id, u8::MAX))
+            .map(|(_, alert)| alert)
+            .collect()
+    });
+    alerts.sort_by_key(|alert| alert.raised_at);
+    Ok(alerts)
+}
+
+fn alert_settings(event_id: u64) -> CapacityAlertSettings {
+    SETTINGS_STORAGE
+        .with(|storage| storage.borrow().get(&event_id))
+        .unwrap_or_default()
+}
+
+// Raise the alerts a sale brought an event to. Thresholds are only crossed once, an event
+// that falls back under one after cancellations isn't alerted about it again.
+pub(crate) fn check_capacity(event_id: u64) {
+    let Some(event) = _get_event(&event_id) else {
+        return;
+    };
+    let Some(capacity) = event.capacity.filter(|capacity| *capacity > 0) else {
+        return;
+    };
+    let settings = alert_settings(event_id);
+    let sold = event.ticket_ids.len() as u64;
+
+    for percent in &settings.thresholds {
+        if sold * 100 >= *percent as u64 * capacity && !raised(event_id, *percent) {
+            let kind = match percent {
+                100 => CapacityAlertKind::SoldOut,
+                _ => CapacityAlertKind::SellThrough { percent: *percent },
+            };
+            raise(&event, &settings, *percent, kind, sold, capacity);
+        }
+    }
+
+    if let Some(sellout_by) = settings.sellout_by {
+        if sold < capacity && !raised(event_id, FORECAST_KEY) {
+            if let Some(projected_at) =
+                forecast_sellout(event_id, capacity - sold).filter(|at| *at <= sellout_by)
+            {
+                let kind = CapacityAlertKind::SelloutForecast { projected_at };
+                raise(&event, &settings, FORECAST_KEY, kind, sold, capacity);
+            }
+        }
+    }
+}
+
+// When the remaining places sell out at the pace of the last days of sales
+fn forecast_sellout(event_id: u64, remaining: u64) -> Option<u64> {
+    let now = time();
+    let today = now / NANOS_PER_DAY;
+    let (sold, days) = stats::recent_sales(event_id, today, FORECAST_WINDOW_DAYS);
+    if sold == 0 {
+        return None;
+    }
+    // Rounded up, so a forecast doesn't come in earlier than the pace allows
+    let days_left = (remaining * days).div_ceil(sold);
+    Some(now + days_left * NANOS_PER_DAY)
+}
+
+fn raised(event_id: u64, key: u8) -> bool {
+    ALERT_STORAGE.with(|alerts| alerts.borrow().contains_key(&(event_id, key)))
+}
+
+fn raise(
+    event: &Event,
+    settings: &CapacityAlertSettings,
+    key: u8,
+    kind: CapacityAlertKind,
+    sold: u64,
+    capacity: u64,
+) {
+    let alert = CapacityAlert {
+        event_id: event.id,
+        kind,
+        sold,
+        capacity,
+        raised_at: time(),
+    };
+    ALERT_STORAGE.with(|alerts| alerts.borrow_mut().insert((event.id, key), alert.clone()));
+
+    let (subject, body, mut data) = match alert.kind {
+        CapacityAlertKind::SellThrough { percent } => (
+            format!("{} is {}% sold", event.name, percent),
+            format!("{} of {} tickets are sold.", sold, capacity),
+            serde_json::json!({ "alert": "sell_through", "percent": percent }),
+        ),
+        CapacityAlertKind::SoldOut => (
+            format!("{} is sold out", event.name),
+            format!("All {} tickets are sold.", capacity),
+            serde_json::json!({ "alert": "sold_out" }),
+        ),
+        CapacityAlertKind::SelloutForecast { projected_at } => (
+            format!("{} is on course to sell out", event.name),
+            format!(
+                "{} of {} tickets are sold, at the recent pace the rest sell by {}.",
+                sold, capacity, projected_at
+            ),
+            serde_json::json!({ "alert": "sellout_forecast", "projected_at": projected_at }),
+        ),
+    };
+    data["sold"] = sold.into();
+    data["capacity"] = capacity.into();
+    webhooks::enqueue_webhooks(event.id, WebhookEventType::CapacityAlert, data);
+    for user_id in &settings.notify_user_ids {
+        notifications::enqueue_notification(
+            NotificationKind::CapacityAlert,
+            event.id,
+            *user_id,
+            subject.clone(),
+            body.clone(),
+        );
+    }
+}
+
+pub(crate) fn remove_event_alerts(event_id: u64) {
+    SETTINGS_STORAGE.with(|storage| storage.borrow_mut().remove(&event_id));
+    ALERT_STORAGE.with(|alerts| {
+        let mut alerts = alerts.borrow_mut();
+        let keys: Vec<(u64, u8)> = alerts
+            .range((event_id, 0)..=(event_id, u8::MAX))
+            .map(|(key, _)| key)
+            .collect();
+        for key in keys {
+            alerts.remove(&key);
+        }
+    });
+}
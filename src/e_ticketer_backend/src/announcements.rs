@@ -0,0 +1,220 @@
+use crate::clock::time;
+use crate::geo::{self, Venue};
+use crate::notifications::NotificationKind;
+use crate::organizers;
+use crate::ratelimit::rate_limit;
+use crate::{
+    _get_event, _get_user, broadcasts, certification, check_event_size, dates, history,
+    reschedules, store_event, venues, Error, Event, Memory, MEMORY_MANAGER,
+};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
+
+// Define an enum for how much of an event has been announced, in the order stages complete
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq, PartialOrd)]
+pub enum AnnouncementStage {
+    // Name and description only, date and venue to be announced
+    Teaser,
+    // Date and start time are final, venue to be announced
+    DateAnnounced,
+    // All details are final and tickets are on sale
+    FullyAnnounced,
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub struct AnnouncementPayload {
+    stage: AnnouncementStage,
+    date: String,
+    start_time: String,
+    // Left out while the venue is still to be announced
+    venue: Option<Venue>,
+}
+
+impl AnnouncementStage {
+    fn label(&self) -> &'static str {
+        match self {
+            AnnouncementStage::Teaser => "teaser",
+            AnnouncementStage::DateAnnounced => "date announced",
+            AnnouncementStage::FullyAnnounced => "fully announced",
+        }
+    }
+}
+
+thread_local! {
+    // Time each user started watching an event, keyed by (event id, user id)
+    static WATCHERS: RefCell<StableBTreeMap<(u64, u64), u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(28)))
+    ));
+}
+
+#[ic_cdk::update(guard = "rate_limit")]
+fn watch_event(event_id: u64, user_id: u64) -> Result<String, Error> {
+    // Retrieve the event and the user, or return a NotFound error if not found
+    _get_event(&event_id).ok_or(Error::not_found("event", event_id))?;
+    _get_user(&user_id).ok_or(Error::not_found("user", user_id))?;
+
+    WATCHERS.with(|watchers| watchers.borrow_mut().insert((event_id, user_id), time()));
+
+    Ok(format!(
+        "user id: {} is watching event id: {}",
+        user_id, event_id
+    ))
+}
+
+#[ic_cdk::update(guard = "rate_limit")]
+fn unwatch_event(event_id: u64, user_id: u64) -> Result<String, Error> {
+    match WATCHERS.with(|watchers| watchers.borrow_mut().remove(&(event_id, user_id))) {
+        Some(_) => Ok(format!(
+            "user id: {} stopped watching event id: {}",
+            user_id, event_id
+        )),
+        None => Err(Error::NotFound {
+            entity: "watcher".to_string(),
+            id: user_id.to_string(),
+            msg: format!("user id:{} is not watching event id:{}", user_id, event_id),
+        }),
+    }
+}
+
+#[ic_cdk::update(guard = "rate_limit")]
+fn announce_event(id: u64, mut payload: AnnouncementPayload) -> Result<Event, Error> {
+    // Retrieve the existing event with the given ID, or return a NotFound error if not found
+    let mut event = _get_event(&id).ok_or(Error::not_found("event", id))?;
+    organizers::check_event_organizer(&event)?;
+
+    // Stages only move forward, an announced date or venue can't go back to TBA
+    let current = stage(&event);
+    if payload.stage <= current {
+        return Err(Error::ValidationFailed {
+            msg: format!("event id:{} is already {}", id, current.label()),
+        });
+    }
+    // An event held at a managed venue is announced there
+    if let Some(venue) = venues::linked_venue(id) {
+        payload.venue = Some(venue);
+    }
+    validate_stage(
+        payload.stage,
+        &payload.date,
+        &payload.start_time,
+        payload.venue.as_ref(),
+    )
+    .map_err(|msg| Error::ValidationFailed { msg })?;
+
+    if let Some(venue) = &payload.venue {
+        geo::validate_venue(venue).map_err(|msg| Error::ValidationFailed { msg })?;
+    }
+
+    // Published events must fit in the schedule of the venue they are held at
+    let announced = Event {
+        date: payload.date.clone(),
+        start_time: payload.start_time.clone(),
+        venue: payload.venue.clone(),
+        announcement: Some(payload.stage),
+        ..event.clone()
+    };
+    venues::check_schedule(&announced)?;
+    check_event_size(&announced)?;
+
+    geo::unindex_event(&event);
+    event.date = payload.date;
+    event.start_time = payload.start_time;
+    event.venue = payload.venue;
+    event.announcement = Some(payload.stage);
+    event.updated_at = Some(time());
+    store_event(&mut event);
+    certification::certify_event(id);
+    geo::index_event(&event);
+    history::record_event_change(
+        id,
+        "announcement",
+        current.label().to_string(),
+        payload.stage.label().to_string(),
+    );
+
+    notify_watchers(&event);
+
+    Ok(event)
+}
+
+// Announcement stage of an event, events from before stages existed were published in full
+pub(crate) fn stage(event: &Event) -> AnnouncementStage {
+    event
+        .announcement
+        .unwrap_or(AnnouncementStage::FullyAnnounced)
+}
+
+// Whether the date of an event is final, so it can be reminded of and completed. A postponed
+// event's date no longer is.
+pub(crate) fn date_is_final(event: &Event) -> bool {
+    stage(event) >= AnnouncementStage::DateAnnounced && !reschedules::is_postponed(event.id)
+}
+
+// Check that the details required by a stage are final
+pub(crate) fn validate_stage(
+    stage: AnnouncementStage,
+    date: &str,
+    start_time: &str,
+    venue: Option<&Venue>,
+) -> Result<(), String> {
+    if stage >= AnnouncementStage::DateAnnounced {
+        dates::parse_date(date).ok_or(format!("date {} is not a YYYY-MM-DD date", date))?;
+        dates::parse_time(start_time)
+            .ok_or(format!("start time {} is not a HH:MM time", start_time))?;
+    }
+    if stage >= AnnouncementStage::FullyAnnounced && venue.is_none() {
+        return Err("venue must be announced before tickets go on sale".to_string());
+    }
+    Ok(())
+}
+
+// Let everyone watching an event know which details were just announced
+fn notify_watchers(event: &Event) {
+    let (subject, place) = match stage(event) {
+        AnnouncementStage::Teaser => return,
+        AnnouncementStage::DateAnnounced => (
+            format!("{} has a date", event.name),
+            "venue to be announced".to_string(),
+        ),
+        AnnouncementStage::FullyAnnounced => (
+            format!("Tickets for {} are on sale", event.name),
+            geo::venue_label(event.venue.as_ref()),
+        ),
+    };
+
+    let user_ids: Vec<u64> = WATCHERS.with(|watchers| {
+        watchers
+            .borrow()
+            .range((event.id, 0)..=(event.id, u64::MAX))
+            .map(|((_, user_id), _)| user_id)
+            .collect()
+    });
+    broadcasts::broadcast(
+        event,
+        NotificationKind::EventAnnounced,
+        subject,
+        format!(
+            "{} takes place {}, {}.",
+            event.name,
+            broadcasts::WHEN,
+            place
+        ),
+        user_ids,
+    );
+}
+
+// Drop the watchers of a deleted event
+pub(crate) fn remove_event_watchers(event_id: u64) {
+    WATCHERS.with(|watchers| {
+        let mut watchers = watchers.borrow_mut();
+        let keys: Vec<(u64, u64)> = watchers
+            .range((event_id, 0)..=(event_id, u64::MAX))
+            .map(|(key, _)| key)
+            .collect();
+        for key in keys {
+            watchers.remove(&key);
+        }
+    });
+}
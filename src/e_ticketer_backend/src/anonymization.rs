@@ -0,0 +1,171 @@
+use crate::clock::time;
+use crate::ratelimit::rate_limit;
+use crate::{
+    _get_all_events, _get_stored_user, _get_ticket, attendee_notes, certification, dates,
+    invitations, legal_holds, organizers, store_event, store_ticket, store_user, surveys, waitlist,
+    Error, Event, Memory, User, MEMORY_MANAGER,
+};
+use candid::{Decode, Encode};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use std::{borrow::Cow, cell::RefCell};
+
+const NANOS_PER_DAY: u64 = 24 * 60 * 60 * 1_000_000_000;
+// Ten years, longer than any retention policy asks for
+const MAX_RETENTION_MONTHS: u32 = 120;
+// Events anonymized per run, so a run stays well within the instruction limit
+const MAX_EVENTS_PER_RUN: usize = 10;
+
+// Holder of the tickets of an anonymized event, no user is ever given this id
+pub(crate) const ANONYMOUS_USER_ID: u64 = u64::MAX;
+
+// Define a struct for how long an organizer keeps attendee data of its events
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct AnonymizationPolicy {
+    // Calendar months after an event is completed
+    after_months: u32,
+}
+
+impl Storable for AnonymizationPolicy {
+    // Conversion to bytes
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    // Conversion from bytes
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for AnonymizationPolicy {
+    const MAX_SIZE: u32 = 64;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    // Policies keyed by organizer id, organizers without one keep attendee data
+    static POLICY_STORAGE: RefCell<StableBTreeMap<u64, AnonymizationPolicy, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(55)))
+    ));
+
+    // Time each anonymized event was anonymized, keyed by event id
+    static ANONYMIZED_EVENTS: RefCell<StableBTreeMap<u64, u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(56)))
+    ));
+}
+
+#[ic_cdk::query]
+fn get_anonymization_policy(organizer_id: u64) -> Result<Option<AnonymizationPolicy>, Error> {
+    organizers::check_organizer(organizer_id)?;
+    Ok(POLICY_STORAGE.with(|policies| policies.borrow().get(&organizer_id)))
+}
+
+#[ic_cdk::update(guard = "rate_limit")]
+fn set_anonymization_policy(
+    organizer_id: u64,
+    policy: Option<AnonymizationPolicy>,
+) -> Result<Option<AnonymizationPolicy>, Error> {
+    organizers::check_organizer(organizer_id)?;
+
+    match &policy {
+        Some(policy) => {
+            if !(1..=MAX_RETENTION_MONTHS).contains(&policy.after_months) {
+                return Err(Error::ValidationFailed {
+                    msg: format!(
+                        "attendee data must be kept between 1 and {} months",
+                        MAX_RETENTION_MONTHS
+                    ),
+                });
+            }
+            POLICY_STORAGE
+                .with(|policies| policies.borrow_mut().insert(organizer_id, policy.clone()));
+        }
+        // Events already anonymized stay that way
+        None => {
+            POLICY_STORAGE.with(|policies| policies.borrow_mut().remove(&organizer_id));
+        }
+    }
+
+    Ok(policy)
+}
+
+#[ic_cdk::query]
+fn get_event_anonymized_at(event_id: u64) -> Option<u64> {
+    ANONYMIZED_EVENTS.with(|events| events.borrow().get(&event_id))
+}
+
+// Anonymize the completed events whose retention period is over, returning how many were
+pub(crate) fn anonymize_due_events() -> u64 {
+    let now = time();
+    let due: Vec<Event> = _get_all_events()
+        .into_iter()
+        .filter(|event| get_event_anonymized_at(event.id).is_none())
+        .filter(|event| anonymize_at(event).is_some_and(|at| at <= now))
+        // Held events are kept as they are until the hold is lifted
+        .filter(|event| legal_holds::check_event(event).is_ok())
+        .take(MAX_EVENTS_PER_RUN)
+        .collect();
+    for event in &due {
+        anonymize_event(event, now);
+    }
+    due.len() as u64
+}
+
+// When the attendee data of a completed event is due to go, following the shortest policy
+// among its organizers
+fn anonymize_at(event: &Event) -> Option<u64> {
+    let completed_at = event.completed_at?;
+    let months = event
+        .organizer_ids
+        .iter()
+        .flatten()
+        .filter_map(|id| POLICY_STORAGE.with(|policies| policies.borrow().get(id)))
+        .map(|policy| policy.after_months)
+        .min()?;
+    let completed_on = dates::civil_from_days((completed_at / NANOS_PER_DAY) as i64);
+    let due_on = dates::add_months(completed_on, months as i64);
+    Some(dates::days_from_civil(due_on) as u64 * NANOS_PER_DAY)
+}
+
+// Unlink an event's tickets and attendees from their users and drop what attendees told or
+// were told about them. Ticket counts, revenue and check-ins are kept, so the event's stats
+// and survey tallies come out the same.
+fn anonymize_event(event: &Event, now: u64) {
+    for mut ticket in event.ticket_ids.iter().filter_map(_get_ticket) {
+        update_user(ticket.user_id, |user| {
+            user.ticket_ids.retain(|&id| id != ticket.id)
+        });
+        ticket.user_id = ANONYMOUS_USER_ID;
+        ticket.metadata = None;
+        ticket.updated_at = Some(now);
+        store_ticket(&mut ticket);
+    }
+    for user_id in &event.attendee_ids {
+        update_user(*user_id, |user| user.event_ids.retain(|&id| id != event.id));
+    }
+    let mut event = event.clone();
+    event.attendee_ids.clear();
+    let event_id = event.id;
+    store_event(&mut event);
+    certification::certify_event(event_id);
+
+    surveys::anonymize_event_responses(event_id);
+    attendee_notes::remove_event_attendee_notes(event_id);
+    invitations::remove_event_invitations(event_id);
+    waitlist::remove_event_waitlist(event_id);
+    ANONYMIZED_EVENTS.with(|events| events.borrow_mut().insert(event_id, now));
+}
+
+fn update_user(user_id: u64, f: impl FnOnce(&mut User)) {
+    if let Some(mut user) = _get_stored_user(&user_id) {
+        f(&mut user);
+        store_user(&mut user);
+    }
+}
+
+// Forget that a purged event was anonymized
+pub(crate) fn remove_event_anonymization(event_id: u64) {
+    ANONYMIZED_EVENTS.with(|events| events.borrow_mut().remove(&event_id));
+}
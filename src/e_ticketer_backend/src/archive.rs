@@ -0,0 +1,263 @@
+use crate::clock::time;
+use crate::stats::{self, EventStats};
+use crate::{
+    _get_all_events, _get_stored_user, _get_ticket, caller_is_admin, dates, legal_holds,
+    organizers, payouts, store_user, trash, Error, Event, Memory, Ticket, User, MEMORY_MANAGER,
+};
+use candid::{Decode, Encode};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, Cell, StableBTreeMap, Storable};
+use std::{borrow::Cow, cell::RefCell};
+
+const NANOS_PER_DAY: u64 = 24 * 60 * 60 * 1_000_000_000;
+// Ten years, as for anonymization policies
+const MAX_RETENTION_MONTHS: u32 = 120;
+// Events archived per run, so a run stays well within the instruction limit
+const MAX_EVENTS_PER_RUN: usize = 10;
+// Compressed records are split in blocks of this size to fit stable map entries
+const BLOCK_SIZE: usize = 16 * 1024;
+
+// Define a struct for how long completed events stay in the live maps
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub struct ArchivalSettings {
+    // Calendar months after an event is completed, None keeps every event live
+    after_months: Option<u32>,
+}
+
+// Define a struct for what is kept at hand of an archived event, its records are compressed
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct ArchivedEvent {
+    event_id: u64,
+    name: String,
+    date: String,
+    organizer_ids: Option<Vec<u64>>,
+    stats: EventStats,
+    tickets: u64,
+    // Size of the candid-encoded records before and after compression
+    records_size: u64,
+    compressed_size: u64,
+    archived_at: u64,
+}
+
+// Define a struct for the records of an event, compressed together when it is archived
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct ArchivedRecords {
+    event: Event,
+    tickets: Vec<Ticket>,
+}
+
+// Define a struct for an archived event along with its decompressed records
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub struct HydratedEvent {
+    summary: ArchivedEvent,
+    event: Event,
+    tickets: Vec<Ticket>,
+}
+
+// Define a struct for a block of compressed records
+struct Block(Vec<u8>);
+
+impl Storable for ArchivalSettings {
+    // Conversion to bytes
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    // Conversion from bytes
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl Storable for ArchivedEvent {
+    // Conversion to bytes
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    // Conversion from bytes
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl Storable for Block {
+    // Conversion to bytes, kept raw as the bytes are already compressed
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Borrowed(&self.0)
+    }
+    // Conversion from bytes
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Block(bytes.into_owned())
+    }
+}
+
+impl BoundedStorable for ArchivedEvent {
+    const MAX_SIZE: u32 = 2048;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl BoundedStorable for Block {
+    const MAX_SIZE: u32 = BLOCK_SIZE as u32;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    static SETTINGS: RefCell<Cell<ArchivalSettings, Memory>> = RefCell::new(
+        Cell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(123))),
+            ArchivalSettings::default(),
+        )
+        .expect("Cannot create the archival settings")
+    );
+
+    // Archived events keyed by event id
+    static ARCHIVE_STORAGE: RefCell<StableBTreeMap<u64, ArchivedEvent, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(124)))
+    ));
+
+    // Compressed records keyed by (event id, block index), in a region of their own
+    static BLOCK_STORAGE: RefCell<StableBTreeMap<(u64, u64), Block, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(125)))
+    ));
+}
+
+#[ic_cdk::query(guard = "caller_is_admin")]
+fn get_archival_settings() -> ArchivalSettings {
+    SETTINGS.with(|settings| settings.borrow().get().clone())
+}
+
+#[ic_cdk::update(guard = "caller_is_admin")]
+fn set_archival_settings(settings: ArchivalSettings) -> Result<ArchivalSettings, Error> {
+    if settings
+        .after_months
+        .is_some_and(|months| !(1..=MAX_RETENTION_MONTHS).contains(&months))
+    {
+        return Err(Error::ValidationFailed {
+            msg: format!(
+                "events must stay live between 1 and {} months",
+                MAX_RETENTION_MONTHS
+            ),
+        });
+    }
+    SETTINGS
+        .with(|cell| cell.borrow_mut().set(settings.clone()))
+        .expect("Cannot store the archival settings");
+    Ok(settings)
+}
+
+// An archived event with its records decompressed, for its organizers
+#[ic_cdk::query]
+fn get_archived_event(id: u64) -> Result<HydratedEvent, Error> {
+    let summary = ARCHIVE_STORAGE
+        .with(|archive| archive.borrow().get(&id))
+        .ok_or(Error::NotFound {
+            entity: "archived event".to_string(),
+            id: id.to_string(),
+            msg: format!("event id:{} is not archived", id),
+        })?;
+    let records = hydrate(id).map_err(|msg| Error::InternalError {
+        msg,
+        ticket_id: None,
+    })?;
+    organizers::check_event_organizer(&records.event)?;
+
+    Ok(HydratedEvent {
+        summary,
+        event: records.event,
+        tickets: records.tickets,
+    })
+}
+
+// Archive the completed events past the retention period, returning how many were
+pub(crate) fn archive_due_events() -> u64 {
+    let Some(months) = get_archival_settings().after_months else {
+        return 0;
+    };
+    let now = time();
+    let due: Vec<Event> = _get_all_events()
+        .into_iter()
+        .filter(|event| archive_at(event, months).is_some_and(|at| at <= now))
+        // Held events and events with revenue left to withdraw stay live
+        .filter(|event| legal_holds::check_event(event).is_ok())
+        .filter(|event| !payouts::has_balance(event.id))
+        .take(MAX_EVENTS_PER_RUN)
+        .collect();
+    for event in &due {
+        archive_event(event, now);
+    }
+    due.len() as u64
+}
+
+// When a completed event is due to be archived
+fn archive_at(event: &Event, months: u32) -> Option<u64> {
+    let completed_at = event.completed_at?;
+    let completed_on = dates::civil_from_days((completed_at / NANOS_PER_DAY) as i64);
+    let due_on = dates::add_months(completed_on, months as i64);
+    Some(dates::days_from_civil(due_on) as u64 * NANOS_PER_DAY)
+}
+
+// Compress the event and its tickets into the archive and purge them from the live maps. The
+// summary and statistics stay at hand, everything else kept for the event goes with the purge.
+fn archive_event(event: &Event, now: u64) {
+    let tickets: Vec<Ticket> = event.ticket_ids.iter().filter_map(_get_ticket).collect();
+    let summary_stats = stats::summary_stats(event);
+    let records = Encode!(&ArchivedRecords {
+        event: event.clone(),
+        tickets: tickets.clone(),
+    })
+    .unwrap();
+    let compressed = lz4_flex::compress_prepend_size(&records);
+
+    BLOCK_STORAGE.with(|blocks| {
+        let mut blocks = blocks.borrow_mut();
+        for (index, chunk) in compressed.chunks(BLOCK_SIZE).enumerate() {
+            blocks.insert((event.id, index as u64), Block(chunk.to_vec()));
+        }
+    });
+    let summary = ArchivedEvent {
+        event_id: event.id,
+        name: event.name.clone(),
+        date: event.date.clone(),
+        organizer_ids: event.organizer_ids.clone(),
+        stats: summary_stats,
+        tickets: tickets.len() as u64,
+        records_size: records.len() as u64,
+        compressed_size: compressed.len() as u64,
+        archived_at: now,
+    };
+    ARCHIVE_STORAGE.with(|archive| archive.borrow_mut().insert(event.id, summary));
+
+    // Users no longer list what moved to the archive
+    for ticket in &tickets {
+        update_user(ticket.user_id, |user| {
+            user.ticket_ids.retain(|&id| id != ticket.id)
+        });
+    }
+    for user_id in &event.attendee_ids {
+        update_user(*user_id, |user| user.event_ids.retain(|&id| id != event.id));
+    }
+    trash::purge_event(event);
+}
+
+// Reassemble and decompress the records of an archived event
+fn hydrate(event_id: u64) -> Result<ArchivedRecords, String> {
+    let compressed: Vec<u8> = BLOCK_STORAGE.with(|blocks| {
+        blocks
+            .borrow()
+            .range((event_id, 0)..=(event_id, u64::MAX))
+            .flat_map(|(_, block)| block.0)
+            .collect()
+    });
+    let records = lz4_flex::decompress_size_prepended(&compressed)
+        .map_err(|error| format!("archive of event id:{} is corrupt: {}", event_id, error))?;
+    Decode!(&records, ArchivedRecords)
+        .map_err(|error| format!("archive of event id:{} is corrupt: {}", event_id, error))
+}
+
+fn update_user(user_id: u64, f: impl FnOnce(&mut User)) {
+    if let Some(mut user) = _get_stored_user(&user_id) {
+        f(&mut user);
+        store_user(&mut user);
+    }
+}
@@ -0,0 +1,436 @@
+use crate::clock::time;
+use crate::ratelimit::rate_limit;
+use crate::{
+    _get_public_event, certification, organizers, Error, Memory, ID_COUNTER, MEMORY_MANAGER,
+};
+use candid::{Decode, Encode};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use serde_bytes::ByteBuf;
+use sha2::{Digest, Sha256};
+use std::{borrow::Cow, cell::RefCell};
+
+// Bytes are stored in blocks of this size, small enough for a stable map entry
+const BLOCK_SIZE: usize = 16 * 1024;
+// Largest chunk accepted per upload call and served per download call
+const CHUNK_SIZE: usize = 1024 * 1024;
+// Per event image, small enough to be served in a single HTTP response
+const MAX_IMAGE_BYTES: u64 = 2 * 1024 * 1024;
+// All images and unfinished uploads together
+const MAX_TOTAL_BYTES: u64 = 512 * 1024 * 1024;
+// Unfinished uploads are dropped after this long, freeing their share of the quota
+const UPLOAD_TTL_NANOS: u64 = 24 * 60 * 60 * 1_000_000_000;
+const CONTENT_TYPES: [&str; 4] = ["image/png", "image/jpeg", "image/gif", "image/webp"];
+
+// Define a struct for the image of an event
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct EventImage {
+    event_id: u64,
+    content_type: String,
+    total_len: u64,
+    // SHA-256 of the image, also certified for the HTTP gateway
+    sha256: ByteBuf,
+    uploaded_at: u64,
+    // Upload the bytes were stored under
+    upload_id: u64,
+}
+
+// Define a struct for an image being uploaded in chunks
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct Upload {
+    id: u64,
+    event_id: u64,
+    content_type: String,
+    total_len: u64,
+    received: u64,
+    started_at: u64,
+}
+
+// Define a struct for a chunk of an event image
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub struct ImageChunk {
+    content_type: String,
+    total_len: u64,
+    chunk: u64,
+    chunk_count: u64,
+    bytes: ByteBuf,
+}
+
+// Define a struct for a block of stored image bytes
+struct Block(Vec<u8>);
+
+impl Storable for EventImage {
+    // Conversion to bytes
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    // Conversion from bytes
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl Storable for Upload {
+    // Conversion to bytes
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    // Conversion from bytes
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl Storable for Block {
+    // Conversion to bytes, kept raw as the bytes are the image itself
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Borrowed(&self.0)
+    }
+    // Conversion from bytes
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Block(bytes.into_owned())
+    }
+}
+
+impl BoundedStorable for EventImage {
+    const MAX_SIZE: u32 = 256;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl BoundedStorable for Upload {
+    const MAX_SIZE: u32 = 128;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl BoundedStorable for Block {
+    const MAX_SIZE: u32 = BLOCK_SIZE as u32;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    // Image bytes keyed by (upload id, block index), in a region of their own
+    static BLOCK_STORAGE: RefCell<StableBTreeMap<(u64, u64), Block, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(58)))
+    ));
+
+    // Finished images keyed by event id
+    static IMAGE_STORAGE: RefCell<StableBTreeMap<u64, EventImage, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(59)))
+    ));
+
+    // Unfinished uploads keyed by upload id
+    static UPLOAD_STORAGE: RefCell<StableBTreeMap<u64, Upload, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(60)))
+    ));
+}
+
+#[ic_cdk::update(guard = "rate_limit")]
+fn begin_upload(event_id: u64, content_type: String, total_len: u64) -> Result<Upload, Error> {
+    organizers::authorize_event(event_id)?;
+    if !CONTENT_TYPES.contains(&content_type.as_str()) {
+        return Err(Error::ValidationFailed {
+            msg: format!("content type must be one of {}", CONTENT_TYPES.join(", ")),
+        });
+    }
+    if total_len == 0 || total_len > MAX_IMAGE_BYTES {
+        return Err(Error::ValidationFailed {
+            msg: format!("an image must be between 1 and {} bytes", MAX_IMAGE_BYTES),
+        });
+    }
+
+    // An event uploads one image at a time, starting over drops the earlier attempt
+    let now = time();
+    let dropped: Vec<u64> = UPLOAD_STORAGE.with(|uploads| {
+        uploads
+            .borrow()
+            .iter()
+            .filter(|(_, upload)| {
+                upload.event_id == event_id || upload.started_at + UPLOAD_TTL_NANOS <= now
+            })
+            .map(|(id, _)| id)
+            .collect()
+    });
+    for id in dropped {
+        UPLOAD_STORAGE.with(|uploads| uploads.borrow_mut().remove(&id));
+        remove_blocks(id);
+    }
+
+    // The image being replaced keeps counting until the new one is finished
+    if stored_bytes() + total_len > MAX_TOTAL_BYTES {
+        return Err(Error::InternalError {
+            msg: "image storage is full".to_string(),
+            ticket_id: None,
+        });
+    }
+
+    // Increment the global ID counter to get a new ID for the upload
+    let id = ID_COUNTER
+        .with(|counter| {
+            let current_id = *counter.borrow().get();
+            counter.borrow_mut().set(current_id + 1)
+        })
+        .expect("Cannot increment Ids");
+    let upload = Upload {
+        id,
+        event_id,
+        content_type,
+        total_len,
+        received: 0,
+        started_at: now,
+    };
+    UPLOAD_STORAGE.with(|uploads| uploads.borrow_mut().insert(id, upload.clone()));
+
+    Ok(upload)
+}
+
+#[ic_cdk::update(guard = "rate_limit")]
+fn upload_chunk(upload_id: u64, offset: u64, bytes: ByteBuf) -> Result<Upload, Error> {
+    let mut upload = authorize_upload(upload_id)?;
+    // Chunks are appended in order, a chunk sent again after a lost reply is told apart here
+    if offset != upload.received {
+        return Err(Error::ValidationFailed {
+            msg: format!(
+                "upload id:{} expects the chunk at offset {}",
+                upload_id, upload.received
+            ),
+        });
+    }
+    if bytes.is_empty() || bytes.len() > CHUNK_SIZE {
+        return Err(Error::ValidationFailed {
+            msg: format!("a chunk must be between 1 and {} bytes", CHUNK_SIZE),
+        });
+    }
+    if upload.received + bytes.len() as u64 > upload.total_len {
+        return Err(Error::ValidationFailed {
+            msg: format!("upload id:{} is {} bytes long", upload_id, upload.total_len),
+        });
+    }
+
+    append_bytes(upload_id, upload.received, &bytes);
+    upload.received += bytes.len() as u64;
+    UPLOAD_STORAGE.with(|uploads| uploads.borrow_mut().insert(upload_id, upload.clone()));
+
+    Ok(upload)
+}
+
+#[ic_cdk::update(guard = "rate_limit")]
+fn finish_upload(upload_id: u64) -> Result<EventImage, Error> {
+    let upload = authorize_upload(upload_id)?;
+    if upload.received != upload.total_len {
+        return Err(Error::ValidationFailed {
+            msg: format!(
+                "upload id:{} has {} of {} bytes",
+                upload_id, upload.received, upload.total_len
+            ),
+        });
+    }
+    let bytes = read_blocks(upload_id);
+    if !matches_content_type(&bytes, &upload.content_type) {
+        return Err(Error::ValidationFailed {
+            msg: format!(
+                "upload id:{} is not a {} image",
+                upload_id, upload.content_type
+            ),
+        });
+    }
+
+    let image = EventImage {
+        event_id: upload.event_id,
+        content_type: upload.content_type,
+        total_len: upload.total_len,
+        sha256: ByteBuf::from(Sha256::digest(&bytes).to_vec()),
+        uploaded_at: time(),
+        upload_id,
+    };
+    UPLOAD_STORAGE.with(|uploads| uploads.borrow_mut().remove(&upload_id));
+    let replaced =
+        IMAGE_STORAGE.with(|images| images.borrow_mut().insert(image.event_id, image.clone()));
+    if let Some(replaced) = replaced {
+        remove_blocks(replaced.upload_id);
+    }
+    certification::certify_event(image.event_id);
+
+    Ok(image)
+}
+
+#[ic_cdk::update(guard = "rate_limit")]
+fn delete_event_image(event_id: u64) -> Result<String, Error> {
+    organizers::authorize_event(event_id)?;
+    let image = IMAGE_STORAGE
+        .with(|images| images.borrow_mut().remove(&event_id))
+        .ok_or(Error::NotFound {
+            entity: "image".to_string(),
+            id: event_id.to_string(),
+            msg: format!("event id:{} has no image", event_id),
+        })?;
+    remove_blocks(image.upload_id);
+    certification::certify_event(event_id);
+    Ok(format!("image of event id:{} deleted", event_id))
+}
+
+#[ic_cdk::query]
+fn get_event_image(event_id: u64, chunk: u64) -> Result<ImageChunk, Error> {
+    let image = public_image(event_id).ok_or(Error::NotFound {
+        entity: "image".to_string(),
+        id: event_id.to_string(),
+        msg: format!("event id:{} has no image", event_id),
+    })?;
+    let chunk_count = image.total_len.div_ceil(CHUNK_SIZE as u64);
+    if chunk >= chunk_count {
+        return Err(Error::ValidationFailed {
+            msg: format!(
+                "the image of event id:{} has {} chunks",
+                event_id, chunk_count
+            ),
+        });
+    }
+
+    let start = chunk * CHUNK_SIZE as u64;
+    let end = (start + CHUNK_SIZE as u64).min(image.total_len);
+    Ok(ImageChunk {
+        content_type: image.content_type,
+        total_len: image.total_len,
+        chunk,
+        chunk_count,
+        bytes: ByteBuf::from(read_range(image.upload_id, start, end)),
+    })
+}
+
+// The caller's upload, for an event the caller may change
+fn authorize_upload(upload_id: u64) -> Result<Upload, Error> {
+    let upload = UPLOAD_STORAGE
+        .with(|uploads| uploads.borrow().get(&upload_id))
+        .ok_or(Error::not_found("upload", upload_id))?;
+    organizers::authorize_event(upload.event_id)?;
+    Ok(upload)
+}
+
+// Image of an event visible to everyone, events in the trash or under embargo show none
+fn public_image(event_id: u64) -> Option<EventImage> {
+    _get_public_event(&event_id)?;
+    IMAGE_STORAGE.with(|images| images.borrow().get(&event_id))
+}
+
+// Bytes held by images and reserved by unfinished uploads
+fn stored_bytes() -> u64 {
+    let images: u64 = IMAGE_STORAGE.with(|images| {
+        images
+            .borrow()
+            .iter()
+            .map(|(_, image)| image.total_len)
+            .sum()
+    });
+    let uploads: u64 = UPLOAD_STORAGE.with(|uploads| {
+        uploads
+            .borrow()
+            .iter()
+            .map(|(_, upload)| upload.total_len)
+            .sum()
+    });
+    images + uploads
+}
+
+// Write bytes at the end of an upload, topping up its last partial block first
+fn append_bytes(upload_id: u64, received: u64, bytes: &[u8]) {
+    BLOCK_STORAGE.with(|blocks| {
+        let mut blocks = blocks.borrow_mut();
+        let mut index = received / BLOCK_SIZE as u64;
+        let mut filled = (received % BLOCK_SIZE as u64) as usize;
+        let mut rest = bytes;
+        while !rest.is_empty() {
+            let mut block = blocks
+                .get(&(upload_id, index))
+                .map(|block| block.0)
+                .unwrap_or_default();
+            let (fill, tail) = rest.split_at((BLOCK_SIZE - filled).min(rest.len()));
+            block.extend_from_slice(fill);
+            blocks.insert((upload_id, index), Block(block));
+            rest = tail;
+            index += 1;
+            filled = 0;
+        }
+    });
+}
+
+fn read_blocks(upload_id: u64) -> Vec<u8> {
+    BLOCK_STORAGE.with(|blocks| {
+        blocks
+            .borrow()
+            .range((upload_id, 0)..=(upload_id, u64::MAX))
+            .flat_map(|(_, block)| block.0)
+            .collect()
+    })
+}
+
+// Bytes of an upload from 'start' up to 'end'
+fn read_range(upload_id: u64, start: u64, end: u64) -> Vec<u8> {
+    let block_size = BLOCK_SIZE as u64;
+    let first = start / block_size;
+    let last = (end - 1) / block_size;
+    let bytes: Vec<u8> = BLOCK_STORAGE.with(|blocks| {
+        blocks
+            .borrow()
+            .range((upload_id, first)..=(upload_id, last))
+            .flat_map(|(_, block)| block.0)
+            .collect()
+    });
+    let offset = (start - first * block_size) as usize;
+    bytes[offset..offset + (end - start) as usize].to_vec()
+}
+
+fn remove_blocks(upload_id: u64) {
+    BLOCK_STORAGE.with(|blocks| {
+        let mut blocks = blocks.borrow_mut();
+        let keys: Vec<(u64, u64)> = blocks
+            .range((upload_id, 0)..=(upload_id, u64::MAX))
+            .map(|(key, _)| key)
+            .collect();
+        for key in keys {
+            blocks.remove(&key);
+        }
+    });
+}
+
+// Check the leading bytes of an image match its declared type, so it is served as what it is
+fn matches_content_type(bytes: &[u8], content_type: &str) -> bool {
+    match content_type {
+        "image/png" => bytes.starts_with(b"\x89PNG\r\n\x1a\n"),
+        "image/jpeg" => bytes.starts_with(b"\xff\xd8\xff"),
+        "image/gif" => bytes.starts_with(b"GIF87a") || bytes.starts_with(b"GIF89a"),
+        "image/webp" => bytes.len() >= 12 && &bytes[..4] == b"RIFF" && &bytes[8..12] == b"WEBP",
+        _ => false,
+    }
+}
+
+// Content type and bytes of an event image served by the HTTP gateway
+pub(crate) fn image_body(event_id: u64) -> Option<(String, Vec<u8>)> {
+    let image = public_image(event_id)?;
+    Some((image.content_type, read_blocks(image.upload_id)))
+}
+
+// SHA-256 of the image served for an event, kept so certifying doesn't hash the image again
+pub(crate) fn image_hash(event_id: u64) -> Option<[u8; 32]> {
+    public_image(event_id).and_then(|image| image.sha256.as_slice().try_into().ok())
+}
+
+// Drop the image and any unfinished upload of a purged event
+pub(crate) fn remove_event_image(event_id: u64) {
+    if let Some(image) = IMAGE_STORAGE.with(|images| images.borrow_mut().remove(&event_id)) {
+        remove_blocks(image.upload_id);
+    }
+    let uploads: Vec<u64> = UPLOAD_STORAGE.with(|uploads| {
+        uploads
+            .borrow()
+            .iter()
+            .filter(|(_, upload)| upload.event_id == event_id)
+            .map(|(id, _)| id)
+            .collect()
+    });
+    for id in uploads {
+        UPLOAD_STORAGE.with(|uploads| uploads.borrow_mut().remove(&id));
+        remove_blocks(id);
+    }
+}
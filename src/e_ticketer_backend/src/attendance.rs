@@ -0,0 +1,395 @@
+use crate::clock::time;
+use crate::ratelimit::rate_limit;
+use crate::{
+    _get_event, _get_ticket, certification, organizers, privacy, Error, Memory, Ticket,
+    MEMORY_MANAGER,
+};
+use candid::{Decode, Encode, Principal};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use serde_bytes::ByteBuf;
+use std::{borrow::Cow, cell::RefCell};
+
+const MAX_BADGE_NAME_LEN: usize = 64;
+const MAX_BADGE_DESCRIPTION_LEN: usize = 256;
+const MAX_IMAGE_URL_LEN: usize = 256;
+
+// Define a struct for the badge an event's attendees can claim once checked in
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct AttendanceBadge {
+    name: String,
+    description: String,
+    // Artwork shown by wallets and frontends, served over https
+    image_url: Option<String>,
+    // Claims open right after check-in if unset, and never close if no end is set
+    claim_opens_at: Option<u64>,
+    claim_closes_at: Option<u64>,
+}
+
+// Define a struct for the proof that the holder of a ticket attended its event
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct AttendanceProof {
+    ticket_id: u64,
+    event_id: u64,
+    user_id: u64,
+    event_name: String,
+    event_date: String,
+    badge_name: String,
+    badge_description: String,
+    image_url: Option<String>,
+    // Identity that claimed the proof, who it can be presented as belonging to
+    claimed_by: Principal,
+    checked_in_at: u64,
+    claimed_at: u64,
+}
+
+// Define a struct for the record kept of a user getting into an event, minted on check-in and
+// staying with the user whatever becomes of the ticket
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct AttendanceRecord {
+    event_id: u64,
+    user_id: u64,
+    // Ticket the user got in with, the first one checked in if they held several
+    ticket_id: u64,
+    checked_in_at: u64,
+}
+
+// Define a struct for the share of an event's tickets that were checked in
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub struct EventAttendanceRate {
+    event_id: u64,
+    tickets: u64,
+    checked_in: u64,
+    // Users who got in, a user checking in several tickets counts once
+    attendees: u64,
+    // Checked in tickets out of the tickets held, in basis points
+    rate_bps: u64,
+}
+
+// Define a struct for a proof returned along with its certification
+#[derive(candid::CandidType, Serialize)]
+pub struct CertifiedAttendanceProof {
+    proof: AttendanceProof,
+    // IC certificate of the canister's certified data, absent in replicated calls
+    certificate: Option<ByteBuf>,
+    // CBOR hash tree proving the SHA-256 of the candid-encoded proof under /attendance/<ticket id>
+    witness: ByteBuf,
+}
+
+impl Storable for AttendanceBadge {
+    // Conversion to bytes
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    // Conversion from bytes
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl Storable for AttendanceProof {
+    // Conversion to bytes
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    // Conversion from bytes
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl Storable for AttendanceRecord {
+    // Conversion to bytes
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    // Conversion from bytes
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for AttendanceBadge {
+    const MAX_SIZE: u32 = 1024;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl BoundedStorable for AttendanceProof {
+    const MAX_SIZE: u32 = 2048;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl BoundedStorable for AttendanceRecord {
+    const MAX_SIZE: u32 = 128;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    // Badges keyed by event id
+    static BADGE_STORAGE: RefCell<StableBTreeMap<u64, AttendanceBadge, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(46)))
+    ));
+
+    // Claimed proofs keyed by ticket id, kept after the ticket or event is gone
+    static PROOF_STORAGE: RefCell<StableBTreeMap<u64, AttendanceProof, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(47)))
+    ));
+
+    // Attendance records keyed by (user id, event id), kept after the ticket or event is gone
+    static RECORD_STORAGE: RefCell<StableBTreeMap<(u64, u64), AttendanceRecord, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(140)))
+    ));
+}
+
+#[ic_cdk::query]
+fn get_attendance_badge(event_id: u64) -> Option<AttendanceBadge> {
+    BADGE_STORAGE.with(|badges| badges.borrow().get(&event_id))
+}
+
+#[ic_cdk::update(guard = "rate_limit")]
+fn set_attendance_badge(
+    event_id: u64,
+    badge: Option<AttendanceBadge>,
+) -> Result<Option<AttendanceBadge>, Error> {
+    organizers::authorize_event(event_id)?;
+
+    match &badge {
+        Some(badge) => {
+            validate_badge(badge).map_err(|msg| Error::ValidationFailed { msg })?;
+            BADGE_STORAGE.with(|badges| badges.borrow_mut().insert(event_id, badge.clone()));
+        }
+        // Proofs claimed so far stay valid
+        None => {
+            BADGE_STORAGE.with(|badges| badges.borrow_mut().remove(&event_id));
+        }
+    }
+
+    Ok(badge)
+}
+
+#[ic_cdk::update(guard = "rate_limit")]
+fn claim_attendance_proof(ticket_id: u64, user_id: u64) -> Result<AttendanceProof, Error> {
+    let ticket = _get_ticket(&ticket_id)
+        .filter(|ticket| ticket.user_id == user_id)
+        .ok_or(Error::NotFound {
+            entity: "ticket".to_string(),
+            id: ticket_id.to_string(),
+            msg: format!(
+                "ticket id:{} does not exist for user id:{}",
+                ticket_id, user_id
+            ),
+        })?;
+    let checked_in_at = ticket.checked_in_at.ok_or(Error::ValidationFailed {
+        msg: format!("ticket id:{} is not checked in", ticket_id),
+    })?;
+    if _get_proof(ticket_id).is_some() {
+        return Err(Error::ValidationFailed {
+            msg: format!("ticket id:{} already claimed its proof", ticket_id),
+        });
+    }
+
+    let event = _get_event(&ticket.event_id).ok_or(Error::not_found("event", ticket.event_id))?;
+    let badge = get_attendance_badge(event.id).ok_or(Error::NotFound {
+        entity: "attendance badge".to_string(),
+        id: event.id.to_string(),
+        msg: format!("event id:{} has no attendance badge", event.id),
+    })?;
+    let now = time();
+    if badge.claim_opens_at.is_some_and(|opens_at| now < opens_at) {
+        return Err(Error::ValidationFailed {
+            msg: format!("claims for event id:{} are not open yet", event.id),
+        });
+    }
+    if badge
+        .claim_closes_at
+        .is_some_and(|closes_at| now > closes_at)
+    {
+        return Err(Error::ValidationFailed {
+            msg: format!("claims for event id:{} have closed", event.id),
+        });
+    }
+
+    let proof = AttendanceProof {
+        ticket_id,
+        event_id: event.id,
+        user_id,
+        event_name: event.name,
+        event_date: event.date,
+        badge_name: badge.name,
+        badge_description: badge.description,
+        image_url: badge.image_url,
+        claimed_by: ic_cdk::caller(),
+        checked_in_at,
+        claimed_at: now,
+    };
+    PROOF_STORAGE.with(|proofs| proofs.borrow_mut().insert(ticket_id, proof.clone()));
+    certification::certify_attendance_proof(ticket_id, &proof);
+
+    Ok(proof)
+}
+
+#[ic_cdk::query]
+fn get_attendance_proof(ticket_id: u64) -> Result<CertifiedAttendanceProof, Error> {
+    // Return the proof with a witness anyone can check against the canister's certificate
+    let proof = _get_proof(ticket_id).ok_or(Error::NotFound {
+        entity: "attendance proof".to_string(),
+        id: ticket_id.to_string(),
+        msg: format!("ticket id:{} has no attendance proof", ticket_id),
+    })?;
+    Ok(CertifiedAttendanceProof {
+        proof,
+        certificate: certification::certificate(),
+        witness: certification::attendance_witness(ticket_id),
+    })
+}
+
+#[ic_cdk::query]
+pub(crate) fn get_user_attendance_proofs(user_id: u64) -> Vec<AttendanceProof> {
+    PROOF_STORAGE.with(|proofs| {
+        proofs
+            .borrow()
+            .iter()
+            .map(|(_, proof)| proof)
+            .filter(|proof| proof.user_id == user_id)
+            .collect()
+    })
+}
+
+// Events the caller got into, in the order of their ids
+#[ic_cdk::query]
+fn get_my_attendance_history() -> Result<Vec<AttendanceRecord>, Error> {
+    let user = privacy::caller_user()?;
+    Ok(user_attendance_records(user.id))
+}
+
+#[ic_cdk::query]
+fn get_event_attendance_rate(event_id: u64) -> Result<EventAttendanceRate, Error> {
+    // Make sure the event exists, or return a NotFound error if not found
+    let event = _get_event(&event_id).ok_or(Error::not_found("event", event_id))?;
+
+    // Cancelled tickets are deleted, so only the tickets still held count
+    let tickets: Vec<Ticket> = event.ticket_ids.iter().filter_map(_get_ticket).collect();
+    let mut attendees: Vec<u64> = tickets
+        .iter()
+        .filter(|ticket| ticket.checked_in_at.is_some())
+        .map(|ticket| ticket.user_id)
+        .collect();
+    let checked_in = attendees.len() as u64;
+    attendees.sort_unstable();
+    attendees.dedup();
+
+    Ok(EventAttendanceRate {
+        event_id,
+        tickets: tickets.len() as u64,
+        checked_in,
+        attendees: attendees.len() as u64,
+        rate_bps: (checked_in * 10_000)
+            .checked_div(tickets.len() as u64)
+            .unwrap_or(0),
+    })
+}
+
+// Mint the attendance record of a ticket's holder once it is checked in
+pub(crate) fn record_attendance(ticket: &Ticket) {
+    let Some(checked_in_at) = ticket.checked_in_at else {
+        return;
+    };
+    let key = (ticket.user_id, ticket.event_id);
+    RECORD_STORAGE.with(|records| {
+        let mut records = records.borrow_mut();
+        if !records.contains_key(&key) {
+            records.insert(
+                key,
+                AttendanceRecord {
+                    event_id: ticket.event_id,
+                    user_id: ticket.user_id,
+                    ticket_id: ticket.id,
+                    checked_in_at,
+                },
+            );
+        }
+    });
+}
+
+pub(crate) fn user_attendance_records(user_id: u64) -> Vec<AttendanceRecord> {
+    RECORD_STORAGE.with(|records| {
+        records
+            .borrow()
+            .range((user_id, 0)..=(user_id, u64::MAX))
+            .map(|(_, record)| record)
+            .collect()
+    })
+}
+
+// Drop the attendance records of an erased user
+pub(crate) fn erase_user_attendance(user_id: u64) {
+    let keys: Vec<(u64, u64)> = RECORD_STORAGE.with(|records| {
+        records
+            .borrow()
+            .range((user_id, 0)..=(user_id, u64::MAX))
+            .map(|(key, _)| key)
+            .collect()
+    });
+    RECORD_STORAGE.with(|records| {
+        let mut records = records.borrow_mut();
+        for key in &keys {
+            records.remove(key);
+        }
+    });
+}
+
+// Drop the attendance records of an event's users, e.g. when a test event is reset
+pub(crate) fn remove_event_attendance(event_id: u64, user_ids: &[u64]) {
+    RECORD_STORAGE.with(|records| {
+        let mut records = records.borrow_mut();
+        for user_id in user_ids {
+            records.remove(&(*user_id, event_id));
+        }
+    });
+}
+
+fn _get_proof(ticket_id: u64) -> Option<AttendanceProof> {
+    PROOF_STORAGE.with(|proofs| proofs.borrow().get(&ticket_id))
+}
+
+fn validate_badge(badge: &AttendanceBadge) -> Result<(), String> {
+    if badge.name.trim().is_empty() || badge.name.len() > MAX_BADGE_NAME_LEN {
+        return Err(format!(
+            "badge name must be 1 to {} bytes",
+            MAX_BADGE_NAME_LEN
+        ));
+    }
+    if badge.description.len() > MAX_BADGE_DESCRIPTION_LEN {
+        return Err(format!(
+            "badge description must be at most {} bytes",
+            MAX_BADGE_DESCRIPTION_LEN
+        ));
+    }
+    if let Some(url) = &badge.image_url {
+        if !url.starts_with("https://") || url.len() > MAX_IMAGE_URL_LEN {
+            return Err(format!(
+                "badge image url must use https and be at most {} bytes",
+                MAX_IMAGE_URL_LEN
+            ));
+        }
+    }
+    if let (Some(opens_at), Some(closes_at)) = (badge.claim_opens_at, badge.claim_closes_at) {
+        if closes_at <= opens_at {
+            return Err("claim window must close after it opens".to_string());
+        }
+    }
+    Ok(())
+}
+
+// All claimed proofs, to certify them again after an upgrade
+pub(crate) fn all_proofs() -> Vec<(u64, AttendanceProof)> {
+    PROOF_STORAGE.with(|proofs| proofs.borrow().iter().collect())
+}
+
+// Drop the badge of a purged event, the proofs claimed for it are kept
+pub(crate) fn remove_event_badge(event_id: u64) {
+    BADGE_STORAGE.with(|badges| badges.borrow_mut().remove(&event_id));
+}
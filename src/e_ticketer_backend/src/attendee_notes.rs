@@ -0,0 +1,229 @@
+use crate::clock::time;
+use crate::ratelimit::rate_limit;
+use crate::{_get_event, Error, Memory, MEMORY_MANAGER};
+use candid::{Decode, Encode, Principal};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use std::{borrow::Cow, cell::RefCell};
+
+// Bounds keeping the notes of an attendee within a single stable map entry
+const MAX_NOTE_LEN: usize = 500;
+const MAX_TAGS: usize = 10;
+const MAX_TAG_LEN: usize = 32;
+
+// Define a struct for the private note an organizer keeps on an attendee
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct AttendeeNote {
+    event_id: u64,
+    pub(crate) user_id: u64,
+    // Only the principal who wrote the note can read it
+    author: Principal,
+    pub(crate) note: String,
+    pub(crate) tags: Vec<String>,
+    created_at: u64,
+    updated_at: Option<u64>,
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub struct AttendeeNotePayload {
+    note: String,
+    tags: Vec<String>,
+}
+
+// Define a struct for all notes kept on an attendee of an event, one per author
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct AttendeeNotes {
+    notes: Vec<AttendeeNote>,
+}
+
+impl Storable for AttendeeNotes {
+    // Conversion to bytes
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    // Conversion from bytes
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for AttendeeNotes {
+    const MAX_SIZE: u32 = 8192;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    // Notes keyed by (event id, user id)
+    static ATTENDEE_NOTES: RefCell<StableBTreeMap<(u64, u64), AttendeeNotes, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(20)))
+    ));
+}
+
+#[ic_cdk::query]
+fn get_attendee_note(event_id: u64, user_id: u64) -> Result<AttendeeNote, Error> {
+    let author = note_author()?;
+    _get_notes(event_id, user_id)
+        .notes
+        .into_iter()
+        .find(|note| note.author == author)
+        .ok_or(Error::NotFound {
+            entity: "attendee note".to_string(),
+            id: user_id.to_string(),
+            msg: format!("no note on user id:{} for event id:{}", user_id, event_id),
+        })
+}
+
+#[ic_cdk::query]
+fn get_event_attendee_notes(event_id: u64) -> Result<Vec<AttendeeNote>, Error> {
+    // Make sure the event exists, or return a NotFound error if not found
+    _get_event(&event_id).ok_or(Error::not_found("event", event_id))?;
+
+    Ok(caller_event_notes(event_id, note_author()?))
+}
+
+#[ic_cdk::update(guard = "rate_limit")]
+fn set_attendee_note(
+    event_id: u64,
+    user_id: u64,
+    payload: AttendeeNotePayload,
+) -> Result<AttendeeNote, Error> {
+    let author = note_author()?;
+
+    // Notes can only be kept on people attending the event
+    let event = _get_event(&event_id).ok_or(Error::not_found("event", event_id))?;
+    if !event.attendee_ids.contains(&user_id) {
+        return Err(Error::ValidationFailed {
+            msg: format!("user id:{} is not attending event id:{}", user_id, event_id),
+        });
+    }
+
+    let tags = validate_note(&payload).map_err(|msg| Error::ValidationFailed { msg })?;
+
+    let mut notes = _get_notes(event_id, user_id);
+    let existing = notes.notes.iter().position(|note| note.author == author);
+    let note = AttendeeNote {
+        event_id,
+        user_id,
+        author,
+        note: payload.note,
+        tags,
+        created_at: existing.map_or(time(), |index| notes.notes[index].created_at),
+        updated_at: existing.map(|_| time()),
+    };
+    match existing {
+        Some(index) => notes.notes[index] = note.clone(),
+        None => notes.notes.push(note.clone()),
+    }
+
+    ATTENDEE_NOTES.with(|storage| storage.borrow_mut().insert((event_id, user_id), notes));
+
+    Ok(note)
+}
+
+#[ic_cdk::update(guard = "rate_limit")]
+fn delete_attendee_note(event_id: u64, user_id: u64) -> Result<String, Error> {
+    let author = note_author()?;
+
+    let mut notes = _get_notes(event_id, user_id);
+    let count = notes.notes.len();
+    notes.notes.retain(|note| note.author != author);
+    if notes.notes.len() == count {
+        return Err(Error::NotFound {
+            entity: "attendee note".to_string(),
+            id: user_id.to_string(),
+            msg: format!("no note on user id:{} for event id:{}", user_id, event_id),
+        });
+    }
+
+    ATTENDEE_NOTES.with(|storage| {
+        let mut storage = storage.borrow_mut();
+        match notes.notes.is_empty() {
+            true => storage.remove(&(event_id, user_id)),
+            false => storage.insert((event_id, user_id), notes),
+        }
+    });
+
+    Ok(format!(
+        "note on user id: {} for event id: {} deleted",
+        user_id, event_id
+    ))
+}
+
+// Helper function to get the notes kept on an attendee of an event
+fn _get_notes(event_id: u64, user_id: u64) -> AttendeeNotes {
+    ATTENDEE_NOTES
+        .with(|storage| storage.borrow().get(&(event_id, user_id)))
+        .unwrap_or_default()
+}
+
+// Notes written by the given principal on the attendees of an event
+pub(crate) fn caller_event_notes(event_id: u64, author: Principal) -> Vec<AttendeeNote> {
+    ATTENDEE_NOTES.with(|storage| {
+        storage
+            .borrow()
+            .range((event_id, 0)..=(event_id, u64::MAX))
+            .flat_map(|(_, notes)| notes.notes)
+            .filter(|note| note.author == author)
+            .collect()
+    })
+}
+
+// Notes are private to their author, so an anonymous caller can't keep any
+fn note_author() -> Result<Principal, Error> {
+    let caller = ic_cdk::caller();
+    if caller == Principal::anonymous() {
+        return Err(Error::Unauthorized {
+            msg: "attendee notes require an authenticated caller".to_string(),
+        });
+    }
+    Ok(caller)
+}
+
+// Check the length of a note and normalize its tags
+fn validate_note(payload: &AttendeeNotePayload) -> Result<Vec<String>, String> {
+    if payload.note.len() > MAX_NOTE_LEN {
+        return Err(format!("note is longer than {} bytes", MAX_NOTE_LEN));
+    }
+    if payload.tags.len() > MAX_TAGS {
+        return Err(format!("a note can have at most {} tags", MAX_TAGS));
+    }
+
+    let mut tags: Vec<String> = payload
+        .tags
+        .iter()
+        .map(|tag| tag.trim().to_string())
+        .collect();
+    if let Some(tag) = tags
+        .iter()
+        .find(|tag| tag.is_empty() || tag.len() > MAX_TAG_LEN)
+    {
+        return Err(format!(
+            "tag '{}' must be between 1 and {} bytes",
+            tag, MAX_TAG_LEN
+        ));
+    }
+    tags.sort();
+    tags.dedup();
+
+    Ok(tags)
+}
+
+// Drop the notes kept on an attendee of an event, when the attendee's data is erased
+pub(crate) fn remove_attendee_notes(event_id: u64, user_id: u64) {
+    ATTENDEE_NOTES.with(|storage| storage.borrow_mut().remove(&(event_id, user_id)));
+}
+
+// Drop the notes kept on the attendees of a deleted event
+pub(crate) fn remove_event_attendee_notes(event_id: u64) {
+    ATTENDEE_NOTES.with(|storage| {
+        let mut storage = storage.borrow_mut();
+        let keys: Vec<(u64, u64)> = storage
+            .range((event_id, 0)..=(event_id, u64::MAX))
+            .map(|(key, _)| key)
+            .collect();
+        for key in keys {
+            storage.remove(&key);
+        }
+    });
+}
@@ -0,0 +1,194 @@
+use crate::clock::time;
+use crate::{caller_is_admin, entropy, Error, Memory, ID_COUNTER, MEMORY_MANAGER};
+use candid::{Decode, Encode, Principal};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use sha2::{Digest, Sha256};
+use std::{borrow::Cow, cell::RefCell};
+
+// Keys can't outlive this, so a forgotten device stops working eventually
+const MAX_KEY_EXPIRY_SECS: u64 = 90 * 24 * 60 * 60;
+
+// Define an enum for what an API key lets its holder do
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq)]
+pub enum ApiScope {
+    // Check tickets in at the entrance
+    CheckIn,
+    // List the attendees of events
+    ViewAttendees,
+}
+
+// Define a struct for an API key, only the hash of its secret is kept
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct ApiKey {
+    id: u64,
+    owner: Principal,
+    scopes: Vec<ApiScope>,
+    secret_hash: Vec<u8>,
+    created_at: u64,
+    expires_at: u64,
+}
+
+// Define a struct for an API key as shown to its owner
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub struct ApiKeyInfo {
+    id: u64,
+    owner: Principal,
+    scopes: Vec<ApiScope>,
+    created_at: u64,
+    expires_at: u64,
+}
+
+// Define a struct for a newly created API key, the token is only ever returned here
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub struct CreatedApiKey {
+    key: ApiKeyInfo,
+    token: String,
+}
+
+impl From<&ApiKey> for ApiKeyInfo {
+    fn from(key: &ApiKey) -> Self {
+        ApiKeyInfo {
+            id: key.id,
+            owner: key.owner,
+            scopes: key.scopes.clone(),
+            created_at: key.created_at,
+            expires_at: key.expires_at,
+        }
+    }
+}
+
+impl Storable for ApiKey {
+    // Conversion to bytes
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    // Conversion from bytes
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for ApiKey {
+    const MAX_SIZE: u32 = 512;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    // API keys keyed by id
+    static API_KEYS: RefCell<StableBTreeMap<u64, ApiKey, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(29)))
+    ));
+}
+
+#[ic_cdk::query(guard = "caller_is_admin")]
+fn get_api_keys() -> Vec<ApiKeyInfo> {
+    let caller = ic_cdk::caller();
+    API_KEYS.with(|keys| {
+        keys.borrow()
+            .iter()
+            .filter(|(_, key)| key.owner == caller)
+            .map(|(_, key)| ApiKeyInfo::from(&key))
+            .collect()
+    })
+}
+
+#[ic_cdk::update(guard = "caller_is_admin")]
+fn create_api_key(scopes: Vec<ApiScope>, expiry_secs: u64) -> Result<CreatedApiKey, Error> {
+    if scopes.is_empty() {
+        return Err(Error::ValidationFailed {
+            msg: "an API key needs at least one scope".to_string(),
+        });
+    }
+    if expiry_secs == 0 || expiry_secs > MAX_KEY_EXPIRY_SECS {
+        return Err(Error::ValidationFailed {
+            msg: format!(
+                "API key expiry must be between 1 and {} seconds",
+                MAX_KEY_EXPIRY_SECS
+            ),
+        });
+    }
+    // Without the random seed the secret would only depend on the time
+    if !entropy::is_seeded() {
+        return Err(Error::InternalError {
+            msg: "randomness is not available yet, try again shortly".to_string(),
+            ticket_id: None,
+        });
+    }
+
+    // Increment the global ID counter to get a new ID for the key
+    let id = ID_COUNTER
+        .with(|counter| {
+            let current_id = *counter.borrow().get();
+            counter.borrow_mut().set(current_id + 1)
+        })
+        .expect("Cannot increment Ids");
+
+    let secret = entropy::random_token(b"api key", id);
+
+    let mut unique_scopes = vec![];
+    for scope in scopes {
+        if !unique_scopes.contains(&scope) {
+            unique_scopes.push(scope);
+        }
+    }
+    let key = ApiKey {
+        id,
+        owner: ic_cdk::caller(),
+        scopes: unique_scopes,
+        secret_hash: Sha256::digest(secret.as_bytes()).to_vec(),
+        created_at: time(),
+        expires_at: time() + expiry_secs * 1_000_000_000,
+    };
+    API_KEYS.with(|keys| keys.borrow_mut().insert(id, key.clone()));
+
+    Ok(CreatedApiKey {
+        key: ApiKeyInfo::from(&key),
+        // The id lets the key be looked up without scanning every hash
+        token: format!("{}.{}", id, secret),
+    })
+}
+
+#[ic_cdk::update(guard = "caller_is_admin")]
+fn revoke_api_key(id: u64) -> Result<String, Error> {
+    let caller = ic_cdk::caller();
+    let owned = API_KEYS.with(|keys| {
+        keys.borrow()
+            .get(&id)
+            .is_some_and(|key| key.owner == caller)
+    });
+    if !owned {
+        return Err(Error::not_found("API key", id));
+    }
+
+    API_KEYS.with(|keys| keys.borrow_mut().remove(&id));
+
+    Ok(format!("API key id: {} revoked", id))
+}
+
+// Let the call through for admins, or for holders of a valid key with the given scope
+pub(crate) fn authorize(api_key: Option<String>, scope: ApiScope) -> Result<(), Error> {
+    if caller_is_admin().is_ok() {
+        return Ok(());
+    }
+
+    let unauthorized = || Error::Unauthorized {
+        msg: "caller is not an admin and has no valid API key for this call".to_string(),
+    };
+    let token = api_key.ok_or_else(unauthorized)?;
+    let (id, secret) = token.split_once('.').ok_or_else(unauthorized)?;
+    let id: u64 = id.parse().map_err(|_| unauthorized())?;
+    let key = API_KEYS
+        .with(|keys| keys.borrow().get(&id))
+        .ok_or_else(unauthorized)?;
+
+    let hash = Sha256::digest(secret.as_bytes());
+    if hash.as_slice() != key.secret_hash.as_slice()
+        || key.expires_at <= time()
+        || !key.scopes.contains(&scope)
+    {
+        return Err(unauthorized());
+    }
+    Ok(())
+}
@@ -0,0 +1,330 @@
+use crate::clock::time;
+use crate::{
+    caller_is_admin, Error, EVENT_STORAGE, ID_COUNTER, MEMORY_MANAGER, TICKET_STORAGE, USER_STORAGE,
+};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::Memory as _;
+use serde_bytes::ByteBuf;
+use std::cell::RefCell;
+
+// Marks the start of a snapshot, bumped whenever its layout changes
+const MAGIC: &[u8; 8] = b"ETBK0001";
+// Every memory the memory manager can hand out, unused ones are stored as empty
+const MEMORY_COUNT: u8 = 255;
+// The magic followed by the length of each memory
+const HEADER_LEN: u64 = MAGIC.len() as u64 + MEMORY_COUNT as u64 * 8;
+// Largest chunk moved in one call, kept under the message size limit
+const MAX_CHUNK_LEN: u64 = 1_500_000;
+const WASM_PAGE_SIZE: u64 = 65_536;
+
+// Define a struct for the size of a memory in a snapshot
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub struct MemoryImage {
+    memory_id: u8,
+    len: u64,
+}
+
+// Define a struct for the layout of a snapshot, returned when a backup starts
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub struct BackupManifest {
+    total_len: u64,
+    memories: Vec<MemoryImage>,
+    started_at: u64,
+}
+
+// What the canister is busy with while updates are held off
+enum Maintenance {
+    Backup,
+    // Memory lengths read from the snapshot header, and the bytes written so far
+    Restore { lens: Vec<u64>, received: u64 },
+    // Written in full, the canister must be upgraded to load the restored state
+    Restored,
+    // Maps being rebuilt by 'compaction', which ends it once done
+    Compaction,
+}
+
+thread_local! {
+    // Kept on the heap on purpose, an upgrade ends any backup or restore
+    static MAINTENANCE: RefCell<Option<Maintenance>> = const { RefCell::new(None) };
+}
+
+#[ic_cdk::update(guard = "caller_is_admin")]
+fn start_backup() -> Result<BackupManifest, Error> {
+    if in_maintenance() {
+        return Err(Error::ValidationFailed {
+            msg: "a backup, restore or compaction is already in progress".to_string(),
+        });
+    }
+    // Changes are held off until 'finish_backup', so the chunks add up to one consistent snapshot
+    MAINTENANCE.with(|maintenance| *maintenance.borrow_mut() = Some(Maintenance::Backup));
+
+    let lens = memory_lens();
+    Ok(BackupManifest {
+        total_len: HEADER_LEN + lens.iter().sum::<u64>(),
+        memories: lens
+            .iter()
+            .enumerate()
+            .filter(|(_, len)| **len > 0)
+            .map(|(memory_id, len)| MemoryImage {
+                memory_id: memory_id as u8,
+                len: *len,
+            })
+            .collect(),
+        started_at: time(),
+    })
+}
+
+#[ic_cdk::query(guard = "caller_is_admin")]
+fn backup_chunk(offset: u64, len: u64) -> Result<ByteBuf, Error> {
+    let backing_up =
+        MAINTENANCE.with(|maintenance| matches!(*maintenance.borrow(), Some(Maintenance::Backup)));
+    if !backing_up {
+        return Err(Error::ValidationFailed {
+            msg: "no backup in progress, call 'start_backup' first".to_string(),
+        });
+    }
+    check_chunk_len(len)?;
+
+    let lens = memory_lens();
+    let total_len = HEADER_LEN + lens.iter().sum::<u64>();
+    let end = total_len.min(offset.saturating_add(len));
+    if offset >= end {
+        return Err(Error::ValidationFailed {
+            msg: format!(
+                "offset {} is past the end of the {} byte backup",
+                offset, total_len
+            ),
+        });
+    }
+
+    let mut chunk = vec![0; (end - offset) as usize];
+    let header = header(&lens);
+    for_each_segment(
+        &lens,
+        offset,
+        &mut chunk,
+        |segment, at, buf| match segment {
+            None => buf.copy_from_slice(&header[at as usize..at as usize + buf.len()]),
+            Some(memory_id) => memory(memory_id).read(at, buf),
+        },
+    );
+    Ok(ByteBuf::from(chunk))
+}
+
+#[ic_cdk::update(guard = "caller_is_admin")]
+fn finish_backup() -> Result<String, Error> {
+    MAINTENANCE.with(|maintenance| {
+        let mut maintenance = maintenance.borrow_mut();
+        match *maintenance {
+            Some(Maintenance::Backup) => {
+                *maintenance = None;
+                Ok("backup finished".to_string())
+            }
+            _ => Err(Error::ValidationFailed {
+                msg: "no backup in progress".to_string(),
+            }),
+        }
+    })
+}
+
+#[ic_cdk::update(guard = "caller_is_admin")]
+fn restore_chunk(offset: u64, data: ByteBuf) -> Result<String, Error> {
+    check_chunk_len(data.len() as u64)?;
+    if !in_maintenance() {
+        if offset != 0 {
+            return Err(Error::ValidationFailed {
+                msg: "a restore starts with the chunk at offset 0".to_string(),
+            });
+        }
+        start_restore(&data)?;
+    }
+
+    MAINTENANCE.with(|maintenance| {
+        let mut maintenance = maintenance.borrow_mut();
+        let Some(Maintenance::Restore { lens, received }) = maintenance.as_mut() else {
+            return Err(Error::ValidationFailed {
+                msg: "a backup or compaction is in progress, or the restore was finalized"
+                    .to_string(),
+            });
+        };
+
+        // Chunks are written in order, so a gap or a resent chunk can't go unnoticed
+        if offset != *received {
+            return Err(Error::ValidationFailed {
+                msg: format!("expected the chunk at offset {}", received),
+            });
+        }
+        let total_len = HEADER_LEN + lens.iter().sum::<u64>();
+        if offset + data.len() as u64 > total_len {
+            return Err(Error::ValidationFailed {
+                msg: format!("chunk runs past the end of the {} byte backup", total_len),
+            });
+        }
+
+        let mut data = data.into_vec();
+        for_each_segment(lens, offset, &mut data, |segment, at, buf| {
+            if let Some(memory_id) = segment {
+                write_memory(memory_id, at, buf);
+            }
+        });
+        *received += data.len() as u64;
+
+        Ok(format!("{} of {} bytes restored", received, total_len))
+    })
+}
+
+#[ic_cdk::update(guard = "caller_is_admin")]
+fn finalize_restore() -> Result<String, Error> {
+    MAINTENANCE.with(|maintenance| {
+        let mut maintenance = maintenance.borrow_mut();
+        let Some(Maintenance::Restore { lens, received }) = maintenance.as_ref() else {
+            return Err(Error::ValidationFailed {
+                msg: "no restore in progress".to_string(),
+            });
+        };
+        let total_len = HEADER_LEN + lens.iter().sum::<u64>();
+        if *received != total_len {
+            return Err(Error::ValidationFailed {
+                msg: format!("only {} of {} bytes restored", received, total_len),
+            });
+        }
+
+        // The maps cache their roots on the heap, only an upgrade reloads them from the restored memory
+        *maintenance = Some(Maintenance::Restored);
+        Ok(format!(
+            "restore of {} bytes complete, upgrade the canister to load it",
+            total_len
+        ))
+    })
+}
+
+// Whether updates are held off for a backup or restore, timers check it before changing state
+pub(crate) fn in_maintenance() -> bool {
+    MAINTENANCE.with(|maintenance| maintenance.borrow().is_some())
+}
+
+// Hold off updates for a compaction, unless the canister is already busy
+pub(crate) fn begin_compaction() -> bool {
+    MAINTENANCE.with(|maintenance| {
+        let mut maintenance = maintenance.borrow_mut();
+        match *maintenance {
+            None | Some(Maintenance::Compaction) => {
+                *maintenance = Some(Maintenance::Compaction);
+                true
+            }
+            _ => false,
+        }
+    })
+}
+
+pub(crate) fn end_compaction() {
+    MAINTENANCE.with(|maintenance| {
+        let mut maintenance = maintenance.borrow_mut();
+        if matches!(*maintenance, Some(Maintenance::Compaction)) {
+            *maintenance = None;
+        }
+    })
+}
+
+// Whether a method may still be called while updates are held off
+pub(crate) fn allowed_in_maintenance(method: &str) -> bool {
+    matches!(
+        method,
+        "start_backup" | "finish_backup" | "restore_chunk" | "finalize_restore"
+    )
+}
+
+// Begin a restore from the first chunk, which must carry the whole header
+fn start_restore(data: &[u8]) -> Result<(), Error> {
+    let fresh = ID_COUNTER.with(|counter| *counter.borrow().get() == 0)
+        && EVENT_STORAGE.with(|events| events.borrow().is_empty())
+        && USER_STORAGE.with(|users| users.borrow().is_empty())
+        && TICKET_STORAGE.with(|tickets| tickets.borrow().is_empty());
+    if !fresh {
+        return Err(Error::ValidationFailed {
+            msg: "a backup can only be restored on a fresh canister".to_string(),
+        });
+    }
+    if (data.len() as u64) < HEADER_LEN || &data[..MAGIC.len()] != MAGIC {
+        return Err(Error::ValidationFailed {
+            msg: format!(
+                "the first chunk must start with the {} byte backup header",
+                HEADER_LEN
+            ),
+        });
+    }
+
+    let lens = data[MAGIC.len()..HEADER_LEN as usize]
+        .chunks(8)
+        .map(|len| u64::from_be_bytes(len.try_into().unwrap()))
+        .collect();
+    MAINTENANCE.with(|maintenance| {
+        *maintenance.borrow_mut() = Some(Maintenance::Restore { lens, received: 0 })
+    });
+    Ok(())
+}
+
+fn check_chunk_len(len: u64) -> Result<(), Error> {
+    if len == 0 || len > MAX_CHUNK_LEN {
+        return Err(Error::ValidationFailed {
+            msg: format!("chunks must be between 1 and {} bytes", MAX_CHUNK_LEN),
+        });
+    }
+    Ok(())
+}
+
+fn memory(memory_id: u8) -> crate::Memory {
+    MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(memory_id)))
+}
+
+fn memory_lens() -> Vec<u64> {
+    (0..MEMORY_COUNT)
+        .map(|memory_id| memory(memory_id).size() * WASM_PAGE_SIZE)
+        .collect()
+}
+
+fn header(lens: &[u64]) -> Vec<u8> {
+    let mut header = MAGIC.to_vec();
+    for len in lens {
+        header.extend_from_slice(&len.to_be_bytes());
+    }
+    header
+}
+
+// Split a range of the snapshot into the header and the memories it covers.
+// 'f' gets the memory of each part, None for the header, with the offset within it.
+fn for_each_segment(
+    lens: &[u64],
+    offset: u64,
+    buf: &mut [u8],
+    mut f: impl FnMut(Option<u8>, u64, &mut [u8]),
+) {
+    let mut start = 0;
+    let segments = std::iter::once((None, HEADER_LEN)).chain(
+        lens.iter()
+            .enumerate()
+            .map(|(memory_id, len)| (Some(memory_id as u8), *len)),
+    );
+    for (segment, len) in segments {
+        let end = start + len;
+        let from = offset.max(start);
+        let to = (offset + buf.len() as u64).min(end);
+        if from < to {
+            f(
+                segment,
+                from - start,
+                &mut buf[(from - offset) as usize..(to - offset) as usize],
+            );
+        }
+        start = end;
+    }
+}
+
+fn write_memory(memory_id: u8, at: u64, buf: &[u8]) {
+    let memory = memory(memory_id);
+    let pages = (at + buf.len() as u64).div_ceil(WASM_PAGE_SIZE);
+    if pages > memory.size() && memory.grow(pages - memory.size()) == -1 {
+        ic_cdk::trap("out of stable memory while restoring");
+    }
+    memory.write(at, buf);
+}
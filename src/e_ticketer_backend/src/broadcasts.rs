@@ -0,0 +1,222 @@
+use crate::clock::time;
+use crate::display::{self, EventTime};
+use crate::notifications::{self, NotificationKind};
+use crate::{
+    backup, caller_is_admin, organizers, Error, Event, Memory, ID_COUNTER, MEMORY_MANAGER,
+};
+use candid::{Decode, Encode};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use std::time::Duration;
+use std::{borrow::Cow, cell::RefCell};
+
+// Stands in a message body for when the event starts, written for each recipient
+pub(crate) const WHEN: &str = "{when}";
+
+// Recipients queued per timer tick, small enough to stay well within the instruction limit
+const FAN_OUT_BATCH: usize = 100;
+// How long to wait for a backup or restore to finish before carrying on
+const MAINTENANCE_RETRY: Duration = Duration::from_secs(60);
+// Finished broadcasts stay this long, so their progress can be looked up
+const FINISHED_RETENTION: u64 = 7 * 24 * 60 * 60 * 1_000_000_000;
+
+// Define a struct for a message going out to many users of an event, queued for them a batch at
+// a time from a timer rather than all within the call that sent it
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct Broadcast {
+    id: u64,
+    kind: NotificationKind,
+    event_id: u64,
+    subject: String,
+    // '{when}' is replaced with when the event starts, as each recipient prefers it written
+    body: String,
+    event_time: EventTime,
+    // Recipients in all, and how many have had the message queued so far
+    total: u64,
+    queued: u64,
+    created_at: u64,
+    finished_at: Option<u64>,
+}
+
+impl Storable for Broadcast {
+    // Conversion to bytes
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    // Conversion from bytes
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for Broadcast {
+    const MAX_SIZE: u32 = 4096;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    // Broadcasts keyed by id, finished ones are kept for a while as a record of their progress
+    static BROADCAST_STORAGE: RefCell<StableBTreeMap<u64, Broadcast, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(109)))
+    ));
+
+    // Recipients still to be queued, keyed by (broadcast id, user id)
+    static PENDING_RECIPIENTS: RefCell<StableBTreeMap<(u64, u64), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(110)))
+    ));
+
+    // Set while a timer is set to fan out the next batch, so only one chain of timers runs
+    static FAN_OUT_SCHEDULED: std::cell::Cell<bool> = const { std::cell::Cell::new(false) };
+}
+
+// Broadcasts still being fanned out
+#[ic_cdk::query(guard = "caller_is_admin")]
+fn get_active_broadcasts() -> Vec<Broadcast> {
+    BROADCAST_STORAGE.with(|broadcasts| {
+        broadcasts
+            .borrow()
+            .iter()
+            .map(|(_, broadcast)| broadcast)
+            .filter(|broadcast| broadcast.finished_at.is_none())
+            .collect()
+    })
+}
+
+#[ic_cdk::query]
+fn get_event_broadcasts(event_id: u64) -> Result<Vec<Broadcast>, Error> {
+    organizers::authorize_event(event_id)?;
+    Ok(BROADCAST_STORAGE.with(|broadcasts| {
+        broadcasts
+            .borrow()
+            .iter()
+            .map(|(_, broadcast)| broadcast)
+            .filter(|broadcast| broadcast.event_id == event_id)
+            .collect()
+    }))
+}
+
+// Send a message to users of an event, it is queued for them from a timer. The body is written
+// for each recipient, with '{when}' standing for when the event starts.
+pub(crate) fn broadcast(
+    event: &Event,
+    kind: NotificationKind,
+    subject: String,
+    body: String,
+    mut user_ids: Vec<u64>,
+) {
+    user_ids.sort_unstable();
+    user_ids.dedup();
+    if user_ids.is_empty() {
+        return;
+    }
+
+    // Increment the global ID counter to get a new ID for the broadcast
+    let id = ID_COUNTER
+        .with(|counter| {
+            let current_id = *counter.borrow().get();
+            counter.borrow_mut().set(current_id + 1)
+        })
+        .expect("Cannot increment Ids");
+    let broadcast = Broadcast {
+        id,
+        kind,
+        event_id: event.id,
+        subject,
+        body,
+        event_time: display::event_time(event),
+        total: user_ids.len() as u64,
+        queued: 0,
+        created_at: time(),
+        finished_at: None,
+    };
+    BROADCAST_STORAGE.with(|broadcasts| broadcasts.borrow_mut().insert(id, broadcast));
+    PENDING_RECIPIENTS.with(|recipients| {
+        let mut recipients = recipients.borrow_mut();
+        for user_id in user_ids {
+            recipients.insert((id, user_id), ());
+        }
+    });
+    schedule_fan_out(Duration::ZERO);
+}
+
+// Carry on with the broadcasts an upgrade interrupted, the recipients left are in stable memory
+pub(crate) fn resume_fan_out() {
+    schedule_fan_out(Duration::ZERO);
+}
+
+fn schedule_fan_out(delay: Duration) {
+    if FAN_OUT_SCHEDULED.with(|scheduled| scheduled.replace(true)) {
+        return;
+    }
+    ic_cdk_timers::set_timer(delay, || {
+        FAN_OUT_SCHEDULED.with(|scheduled| scheduled.set(false));
+        if backup::in_maintenance() {
+            schedule_fan_out(MAINTENANCE_RETRY);
+            return;
+        }
+        if fan_out_batch() {
+            schedule_fan_out(Duration::ZERO);
+        }
+    });
+}
+
+// Queue the message for the next batch of recipients, returning whether any are left
+fn fan_out_batch() -> bool {
+    prune_finished();
+    let batch: Vec<(u64, u64)> = PENDING_RECIPIENTS.with(|recipients| {
+        recipients
+            .borrow()
+            .iter()
+            .map(|(key, _)| key)
+            .take(FAN_OUT_BATCH + 1)
+            .collect()
+    });
+
+    for (broadcast_id, user_id) in batch.iter().take(FAN_OUT_BATCH) {
+        PENDING_RECIPIENTS
+            .with(|recipients| recipients.borrow_mut().remove(&(*broadcast_id, *user_id)));
+        let Some(mut broadcast) =
+            BROADCAST_STORAGE.with(|broadcasts| broadcasts.borrow().get(broadcast_id))
+        else {
+            continue;
+        };
+        notifications::enqueue_notification(
+            broadcast.kind,
+            broadcast.event_id,
+            *user_id,
+            broadcast.subject.clone(),
+            broadcast
+                .body
+                .replace(WHEN, &display::when(&broadcast.event_time, *user_id)),
+        );
+        broadcast.queued += 1;
+        if broadcast.queued == broadcast.total {
+            broadcast.finished_at = Some(time());
+        }
+        BROADCAST_STORAGE
+            .with(|broadcasts| broadcasts.borrow_mut().insert(*broadcast_id, broadcast));
+    }
+    batch.len() > FAN_OUT_BATCH
+}
+
+// Drop finished broadcasts once their progress no longer needs to be looked up
+fn prune_finished() {
+    let now = time();
+    BROADCAST_STORAGE.with(|broadcasts| {
+        let mut broadcasts = broadcasts.borrow_mut();
+        let expired: Vec<u64> = broadcasts
+            .iter()
+            .filter(|(_, broadcast)| {
+                broadcast
+                    .finished_at
+                    .is_some_and(|at| at + FINISHED_RETENTION <= now)
+            })
+            .map(|(id, _)| id)
+            .collect();
+        for id in expired {
+            broadcasts.remove(&id);
+        }
+    });
+}
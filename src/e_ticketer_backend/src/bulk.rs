@@ -0,0 +1,289 @@
+use crate::ratelimit::rate_limit;
+use crate::{
+    _create_ticket, _delete_ticket, _get_event, _get_ticket, _get_user, config, create_user,
+    credentials, emails, health, legal_holds, organizers, privacy, promo, quote_ticket,
+    remaining_capacity, seats, tiers, user_ticket_count, validation, Error, Ticket, TicketPayload,
+    User, UserPayload,
+};
+use std::collections::{HashMap, HashSet};
+
+// Every imported password is hashed within the call, which costs far more than a ticket
+const MAX_IMPORTED_USERS: usize = 20;
+
+// Define a struct for an item of a batch that failed validation
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub struct BulkItemError {
+    // Position of the item in the batch
+    pub(crate) index: u64,
+    pub(crate) msg: String,
+}
+
+#[ic_cdk::update(guard = "rate_limit")]
+async fn create_tickets_bulk(
+    payloads: Vec<TicketPayload>,
+) -> Result<Vec<Ticket>, Vec<BulkItemError>> {
+    health::check_sales_open().map_err(|msg| vec![BulkItemError { index: 0, msg }])?;
+    check_batch_size(payloads.len(), config::max_batch_items())?;
+    check_ticket_batch(&payloads)?;
+
+    // The hook approves or rejects the batch as a whole
+    validation::validate_tickets(&payloads)
+        .await
+        .map_err(|msg| vec![BulkItemError { index: 0, msg }])?;
+    // Sales may have gone on while the hook was consulted
+    check_ticket_batch(&payloads)?;
+
+    // A failure past validation traps, which rolls back the items already applied
+    Ok(payloads
+        .into_iter()
+        .enumerate()
+        .map(|(index, payload)| {
+            _create_ticket(payload).unwrap_or_else(|error| abort(index, error_msg(error)))
+        })
+        .collect())
+}
+
+#[ic_cdk::update(guard = "rate_limit")]
+fn cancel_tickets_bulk(ids: Vec<u64>) -> Result<Vec<String>, Vec<BulkItemError>> {
+    check_batch_size(ids.len(), config::max_batch_items())?;
+
+    let caller_id = privacy::caller_user().ok().map(|user| user.id);
+    let mut errors = vec![];
+    for (index, id) in ids.iter().enumerate() {
+        if let Err(msg) = check_cancel_item(*id, &ids[..index], caller_id) {
+            errors.push(BulkItemError {
+                index: index as u64,
+                msg,
+            });
+        }
+    }
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+
+    // A failure past validation traps, which rolls back the items already applied
+    Ok(ids
+        .into_iter()
+        .enumerate()
+        .map(|(index, id)| {
+            _delete_ticket(id).unwrap_or_else(|error| abort(index, error_msg(error)))
+        })
+        .collect())
+}
+
+#[ic_cdk::update(guard = "rate_limit")]
+fn import_users(payloads: Vec<UserPayload>) -> Result<Vec<User>, Vec<BulkItemError>> {
+    check_batch_size(payloads.len(), MAX_IMPORTED_USERS)?;
+
+    // Emails must be free, and not repeated within the batch either
+    let mut emails_taken: HashSet<String> = HashSet::new();
+    let errors: Vec<BulkItemError> = payloads
+        .iter()
+        .enumerate()
+        .filter_map(|(index, payload)| {
+            let email = emails::normalize(&payload.email);
+            credentials::validate_password(&payload.password)
+                .and_then(|_| emails::check_available(&payload.email, None).map_err(error_msg))
+                .and_then(|_| {
+                    if email.is_empty() || emails_taken.insert(email) {
+                        Ok(())
+                    } else {
+                        Err(format!(
+                            "email {} is used by an earlier item",
+                            payload.email.trim()
+                        ))
+                    }
+                })
+                .err()
+                .map(|msg| BulkItemError {
+                    index: index as u64,
+                    msg,
+                })
+        })
+        .collect();
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+
+    // A failure past validation traps, which rolls back the items already applied
+    Ok(payloads
+        .into_iter()
+        .enumerate()
+        .map(|(index, payload)| {
+            create_user(payload).unwrap_or_else(|error| abort(index, error_msg(error)))
+        })
+        .collect())
+}
+
+pub(crate) fn check_batch_size(len: usize, max: usize) -> Result<(), Vec<BulkItemError>> {
+    if len == 0 || len > max {
+        return Err(vec![BulkItemError {
+            index: 0,
+            msg: format!("a batch must have between 1 and {} items", max),
+        }]);
+    }
+    Ok(())
+}
+
+// Validate every ticket of a batch against what the items before it use up
+pub(crate) fn check_ticket_batch(payloads: &[TicketPayload]) -> Result<(), Vec<BulkItemError>> {
+    let mut errors = vec![];
+    let mut sold_per_event: HashMap<u64, u64> = HashMap::new();
+    let mut sold_per_tier: HashMap<(u64, u64), u64> = HashMap::new();
+    let mut promo_uses: HashMap<(u64, String), u64> = HashMap::new();
+    let mut seats_taken: HashSet<(u64, String)> = HashSet::new();
+    let mut bought_per_user: HashMap<(u64, u64), u64> = HashMap::new();
+    for (index, payload) in payloads.iter().enumerate() {
+        if let Err(msg) = check_ticket_item(
+            payload,
+            &mut sold_per_event,
+            &mut sold_per_tier,
+            &mut promo_uses,
+            &mut seats_taken,
+            &mut bought_per_user,
+        ) {
+            errors.push(BulkItemError {
+                index: index as u64,
+                msg,
+            });
+        }
+    }
+    match errors.is_empty() {
+        true => Ok(()),
+        false => Err(errors),
+    }
+}
+
+// Check a ticket of a batch, counting what it uses up towards the items after it
+fn check_ticket_item(
+    payload: &TicketPayload,
+    sold_per_event: &mut HashMap<u64, u64>,
+    sold_per_tier: &mut HashMap<(u64, u64), u64>,
+    promo_uses: &mut HashMap<(u64, String), u64>,
+    seats_taken: &mut HashSet<(u64, String)>,
+    bought_per_user: &mut HashMap<(u64, u64), u64>,
+) -> Result<(), String> {
+    let event = _get_event(&payload.event_id)
+        .ok_or(format!("event id:{} does not exist", payload.event_id))?;
+    _get_user(&payload.user_id).ok_or(format!("user id:{} does not exist", payload.user_id))?;
+    let quote = quote_ticket(payload).map_err(error_msg)?;
+
+    let sold = sold_per_event.entry(event.id).or_default();
+    if remaining_capacity(&event).is_some_and(|remaining| *sold >= remaining) {
+        return Err(format!("event id:{} would be sold out", event.id));
+    }
+    *sold += 1;
+
+    if let Some(limit) = event.max_tickets_per_user {
+        let bought = bought_per_user
+            .entry((event.id, payload.user_id))
+            .or_default();
+        let count = user_ticket_count(event.id, payload.user_id) + *bought;
+        if count >= limit {
+            return Err(format!(
+                "user id:{} would hold more than the {} tickets allowed for event id:{}",
+                payload.user_id, limit, event.id
+            ));
+        }
+        *bought += 1;
+    }
+
+    if let Some(tier_id) = payload.tier_id {
+        let sold = sold_per_tier.entry((event.id, tier_id)).or_default();
+        let remaining =
+            tiers::_get_tier(event.id, tier_id).and_then(|tier| tiers::tier_remaining(&tier));
+        if remaining.is_some_and(|remaining| *sold >= remaining) {
+            return Err(format!("tier id:{} would be sold out", tier_id));
+        }
+        *sold += 1;
+    }
+
+    if let (Some(promo), Some(code)) = (&quote.promo, &payload.promo_code) {
+        let used = promo_uses.entry((event.id, code.clone())).or_default();
+        if *used >= promo::remaining_uses(promo) {
+            return Err(format!("promo code {} would have no uses left", code));
+        }
+        *used += 1;
+    }
+
+    if let Some(seat) = &payload.seat {
+        let label = seats::seat_label(seat);
+        if !seats_taken.insert((event.id, label.clone())) {
+            return Err(format!("seat {} appears more than once", label));
+        }
+    }
+    Ok(())
+}
+
+// Check a ticket of a batch can be cancelled by the caller, and isn't cancelled by an earlier
+// item already. 'caller_id' is the user of the caller's wallet, if any.
+fn check_cancel_item(id: u64, earlier: &[u64], caller_id: Option<u64>) -> Result<(), String> {
+    if earlier.contains(&id) {
+        return Err(format!("ticket id:{} appears more than once", id));
+    }
+    let ticket = _get_ticket(&id).ok_or(format!("ticket id:{} does not exist", id))?;
+    _get_user(&ticket.user_id).ok_or(format!("user id:{} does not exist", ticket.user_id))?;
+    let event = _get_event(&ticket.event_id)
+        .ok_or(format!("event id:{} does not exist", ticket.event_id))?;
+    // Holders cancel their own tickets, the event's organizers any of its tickets
+    if caller_id != Some(ticket.user_id) {
+        organizers::check_event_organizer(&event).map_err(error_msg)?;
+    }
+    legal_holds::check_ticket(&ticket).map_err(error_msg)?;
+    Ok(())
+}
+
+pub(crate) fn error_msg(error: Error) -> String {
+    match error {
+        Error::NotFound { msg, .. }
+        | Error::ValidationFailed { msg }
+        | Error::Unauthorized { msg }
+        | Error::Conflict { msg, .. }
+        | Error::HasDependents { msg }
+        | Error::TransferFrozen { msg, .. }
+        | Error::AlreadyExists { msg }
+        | Error::OnLegalHold { msg, .. }
+        | Error::CapacityExceeded { msg }
+        | Error::PurchaseLimitExceeded { msg, .. }
+        | Error::PaymentFailed { msg }
+        | Error::StorageQuotaExceeded { msg, .. }
+        | Error::InternalError { msg, .. } => msg,
+    }
+}
+
+// Undo the whole batch, a trap discards every change made by the call
+pub(crate) fn abort(index: usize, msg: String) -> ! {
+    ic_cdk::trap(&format!(
+        "batch item {} failed, nothing was applied: {}",
+        index, msg
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    #[test]
+    fn holder_can_cancel_their_own_ticket() {
+        testing::event(1001);
+        testing::user(2001);
+        testing::ticket(3001, 1001, 2001, 100);
+        assert!(check_cancel_item(3001, &[], Some(2001)).is_ok());
+    }
+
+    #[test]
+    fn ticket_listed_twice_is_refused() {
+        testing::event(1001);
+        testing::user(2001);
+        testing::ticket(3001, 1001, 2001, 100);
+        let msg = check_cancel_item(3001, &[3001], Some(2001)).unwrap_err();
+        assert!(msg.contains("more than once"));
+    }
+
+    #[test]
+    fn unknown_ticket_is_refused() {
+        let msg = check_cancel_item(3001, &[], Some(2001)).unwrap_err();
+        assert!(msg.contains("does not exist"));
+    }
+}
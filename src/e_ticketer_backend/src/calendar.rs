@@ -0,0 +1,111 @@
+use crate::{_get_public_event, announcements, dates, geo, jobs, Error, Event};
+
+const NANOS_PER_SECOND: u64 = 1_000_000_000;
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+// Content lines are folded at 75 octets, as RFC 5545 asks
+const MAX_LINE_LEN: usize = 75;
+
+// iCalendar file of an event for attendees to add it to their calendars, also served over HTTP
+// at '/events/<id>/event.ics'
+#[ic_cdk::query]
+fn get_event_ics(event_id: u64) -> Result<String, Error> {
+    let event = _get_public_event(&event_id).ok_or(Error::not_found("event", event_id))?;
+    event_ics(&event)
+}
+
+// Build the iCalendar file of an event. It only changes along with the event, so its certified
+// hash stays valid: the stamp is the event's last change rather than the time of the call.
+pub(crate) fn event_ics(event: &Event) -> Result<String, Error> {
+    if !announcements::date_is_final(event) {
+        return Err(Error::ValidationFailed {
+            msg: format!("event id:{} has no announced date yet", event.id),
+        });
+    }
+    let date = dates::parse_date(&event.date).ok_or(Error::ValidationFailed {
+        msg: format!("event id:{} has an invalid date {}", event.id, event.date),
+    })?;
+    // Events without a valid start time take the whole day
+    let start = match (
+        dates::parse_time(&event.start_time),
+        jobs::event_start(event),
+    ) {
+        (Some(_), Some(start)) => format!("DTSTART:{}", utc_stamp(start)),
+        _ => format!(
+            "DTSTART;VALUE=DATE:{}",
+            dates::format_date(date).replace('-', "")
+        ),
+    };
+
+    let mut lines = vec![
+        "BEGIN:VCALENDAR".to_string(),
+        "VERSION:2.0".to_string(),
+        "PRODID:-//e_ticketer//events//EN".to_string(),
+        "CALSCALE:GREGORIAN".to_string(),
+        "METHOD:PUBLISH".to_string(),
+        "BEGIN:VEVENT".to_string(),
+        format!("UID:event-{}@{}", event.id, ic_cdk::id()),
+        format!(
+            "DTSTAMP:{}",
+            utc_stamp(event.updated_at.unwrap_or(event.created_at))
+        ),
+        // Lets calendars replace their copy when the event is moved
+        format!("SEQUENCE:{}", event.version.unwrap_or(0)),
+        start,
+        format!("SUMMARY:{}", escape(&event.name)),
+    ];
+    if !event.description.is_empty() {
+        lines.push(format!("DESCRIPTION:{}", escape(&event.description)));
+    }
+    if let Some(venue) = &event.venue {
+        lines.push(format!(
+            "LOCATION:{}",
+            escape(&geo::venue_label(Some(venue)))
+        ));
+    }
+    lines.push("STATUS:CONFIRMED".to_string());
+    lines.push("END:VEVENT".to_string());
+    lines.push("END:VCALENDAR".to_string());
+
+    Ok(lines.iter().map(|line| fold(line) + "\r\n").collect())
+}
+
+// Nanoseconds since the epoch as a UTC date-time, e.g. "20261016T193000Z"
+fn utc_stamp(nanos: u64) -> String {
+    let seconds = nanos / NANOS_PER_SECOND;
+    let (year, month, day) = dates::civil_from_days((seconds / SECONDS_PER_DAY) as i64);
+    let seconds = seconds % SECONDS_PER_DAY;
+    format!(
+        "{:04}{:02}{:02}T{:02}{:02}{:02}Z",
+        year,
+        month,
+        day,
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60
+    )
+}
+
+// Escape the characters that have a meaning in text values
+fn escape(text: &str) -> String {
+    text.replace('\\', "\\\\")
+        .replace(';', "\\;")
+        .replace(',', "\\,")
+        .replace("\r\n", "\\n")
+        .replace('\n', "\\n")
+}
+
+// Fold a content line longer than 75 octets, continuation lines start with a space. Lines are
+// only split between characters, so multi-byte ones stay whole.
+fn fold(line: &str) -> String {
+    let mut folded = String::with_capacity(line.len());
+    let mut line_len = 0;
+    for c in line.chars() {
+        if line_len + c.len_utf8() > MAX_LINE_LEN {
+            folded.push_str("\r\n ");
+            line_len = 1;
+        }
+        folded.push(c);
+        line_len += c.len_utf8();
+    }
+    folded
+}
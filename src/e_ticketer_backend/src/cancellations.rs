@@ -0,0 +1,376 @@
+use crate::clock::time;
+use crate::credits::{self, CreditReason, RefundTarget};
+use crate::ratelimit::rate_limit;
+use crate::{
+    _delete_ticket, _get_event, _get_ticket, addons, jobs, organizers, privacy, stats, waitlist,
+    Error, Memory, Ticket, MEMORY_MANAGER,
+};
+use candid::{Decode, Encode};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use std::{borrow::Cow, cell::RefCell};
+
+const NANOS_PER_HOUR: u64 = 60 * 60 * 1_000_000_000;
+// Holders can give up a free ticket until this long before the event starts
+const SELF_CANCELLATION_CUTOFF: u64 = 24 * NANOS_PER_HOUR;
+const MAX_POLICY_RULES: usize = 10;
+
+// Define a struct for a step of a cancellation policy, e.g. 50% back until 24 hours before
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct CancellationRule {
+    hours_before: u64,
+    refund_percent: u8,
+}
+
+// Define a struct for the cancellation policy of an event. The rule with the longest notice
+// the holder still gives applies, and nothing is refunded once none does.
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct CancellationPolicy {
+    event_id: u64,
+    // Longest notice first
+    rules: Vec<CancellationRule>,
+    updated_at: u64,
+}
+
+// Define a struct for the refund of a ticket cancelled under its event's policy
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct CancellationRefund {
+    ticket_id: u64,
+    event_id: u64,
+    user_id: u64,
+    price: u64,
+    pub(crate) amount: u64,
+    // Rule the amount was worked out with, None when the cancellation came too late for any
+    rule: Option<CancellationRule>,
+    // Credit refunds are added to the ticket holder's balance
+    pub(crate) refund_to: RefundTarget,
+    pub(crate) refunded_at: u64,
+    // Set when refunded in full because the event was rescheduled, no rule applies then
+    rescheduled_at: Option<u64>,
+}
+
+impl Storable for CancellationPolicy {
+    // Conversion to bytes
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    // Conversion from bytes
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for CancellationPolicy {
+    const MAX_SIZE: u32 = 512;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl Storable for CancellationRefund {
+    // Conversion to bytes
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    // Conversion from bytes
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for CancellationRefund {
+    const MAX_SIZE: u32 = 256;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    // Cancellation policies keyed by event id
+    static POLICY_STORAGE: RefCell<StableBTreeMap<u64, CancellationPolicy, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(116)))
+    ));
+
+    // Refunds keyed by (event id, ticket id), kept as the record when tickets or events go
+    static REFUND_STORAGE: RefCell<StableBTreeMap<(u64, u64), CancellationRefund, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(117)))
+    ));
+}
+
+// Give up the caller's free or RSVP ticket, so the place goes to the waitlist or back on sale.
+// It counts as a cancellation in the event's statistics rather than a no-show.
+#[ic_cdk::update(guard = "rate_limit")]
+fn cancel_my_ticket(ticket_id: u64) -> Result<String, Error> {
+    let ticket = caller_ticket(ticket_id)?;
+    // Paid tickets go through the refund rules instead
+    if ticket.price > 0 || ticket.insurance.is_some() || ticket.addons.is_some() {
+        return Err(Error::ValidationFailed {
+            msg: format!(
+                "ticket id:{} was paid for and can't be cancelled",
+                ticket_id
+            ),
+        });
+    }
+    let event = _get_event(&ticket.event_id).ok_or(Error::not_found("event", ticket.event_id))?;
+    let closed = event.completed_at.is_some()
+        || jobs::event_start(&event).is_some_and(|start| time() + SELF_CANCELLATION_CUTOFF > start);
+    if closed {
+        return Err(Error::ValidationFailed {
+            msg: format!(
+                "tickets of event id:{} can no longer be cancelled",
+                event.id
+            ),
+        });
+    }
+
+    let msg = _delete_ticket(ticket_id)?;
+    waitlist::promote_waitlist(event.id);
+    Ok(msg)
+}
+
+#[ic_cdk::query]
+fn get_cancellation_policy(event_id: u64) -> Option<CancellationPolicy> {
+    POLICY_STORAGE.with(|policies| policies.borrow().get(&event_id))
+}
+
+// Set the cancellation policy of an event, None to stop holders cancelling paid tickets
+#[ic_cdk::update(guard = "rate_limit")]
+fn set_cancellation_policy(
+    event_id: u64,
+    rules: Option<Vec<CancellationRule>>,
+) -> Result<Option<CancellationPolicy>, Error> {
+    organizers::authorize_event(event_id)?;
+    let Some(mut rules) = rules else {
+        POLICY_STORAGE.with(|policies| policies.borrow_mut().remove(&event_id));
+        return Ok(None);
+    };
+    validate_rules(&mut rules).map_err(|msg| Error::ValidationFailed { msg })?;
+
+    let policy = CancellationPolicy {
+        event_id,
+        rules,
+        updated_at: time(),
+    };
+    POLICY_STORAGE.with(|policies| policies.borrow_mut().insert(event_id, policy.clone()));
+    Ok(Some(policy))
+}
+
+// Cancel the caller's ticket and refund what the event's policy gives back for the notice given
+#[ic_cdk::update(guard = "rate_limit")]
+fn cancel_ticket(
+    ticket_id: u64,
+    refund_to: Option<RefundTarget>,
+) -> Result<CancellationRefund, Error> {
+    let ticket = caller_ticket(ticket_id)?;
+    let event = _get_event(&ticket.event_id).ok_or(Error::not_found("event", ticket.event_id))?;
+    let policy = get_cancellation_policy(event.id).ok_or(Error::ValidationFailed {
+        msg: format!("event id:{} has no cancellation policy", event.id),
+    })?;
+    if event.completed_at.is_some() {
+        return Err(Error::ValidationFailed {
+            msg: format!("event id:{} has already completed", event.id),
+        });
+    }
+    let start = jobs::event_start(&event).ok_or(Error::ValidationFailed {
+        msg: format!("event id:{} has no start time to give notice of", event.id),
+    })?;
+
+    // Add-ons are refunded along with the ticket they came with
+    let paid = ticket.price + addons::ticket_addons_total(&ticket);
+    let (rule, amount) = refund_for_notice(policy.rules, paid, time(), start);
+    refund_ticket(ticket, amount, rule, refund_to, None)
+}
+
+// Rule that applies to a cancellation at 'now' of an event starting at 'start', and what it
+// gives back of the price. Nothing comes back once no rule's notice is given.
+fn refund_for_notice(
+    rules: Vec<CancellationRule>,
+    price: u64,
+    now: u64,
+    start: u64,
+) -> (Option<CancellationRule>, u64) {
+    let rule = rules
+        .into_iter()
+        .find(|rule| now.saturating_add(rule.hours_before.saturating_mul(NANOS_PER_HOUR)) <= start);
+    let amount = rule.as_ref().map_or(0, |rule| {
+        (price as u128 * rule.refund_percent as u128 / 100) as u64
+    });
+    (rule, amount)
+}
+
+#[ic_cdk::query]
+fn get_cancellation_refunds(event_id: u64) -> Result<Vec<CancellationRefund>, Error> {
+    organizers::authorize_event(event_id)?;
+    Ok(REFUND_STORAGE.with(|refunds| {
+        refunds
+            .borrow()
+            .range((event_id, 0)..=(event_id, u64::MAX))
+            .map(|(_, refund)| refund)
+            .collect()
+    }))
+}
+
+// Cancel a ticket, refund the amount and record the refund
+pub(crate) fn refund_ticket(
+    ticket: Ticket,
+    amount: u64,
+    rule: Option<CancellationRule>,
+    refund_to: Option<RefundTarget>,
+    rescheduled_at: Option<u64>,
+) -> Result<CancellationRefund, Error> {
+    let (ticket_id, event_id) = (ticket.id, ticket.event_id);
+    _delete_ticket(ticket_id)?;
+    stats::record_refund(event_id, amount);
+    // The organizer's refund policy decides before the holder's wish
+    let refund_to = credits::refund_target(event_id, ticket.user_id, refund_to);
+    if refund_to == RefundTarget::Credit {
+        credits::credit(
+            ticket.user_id,
+            amount,
+            CreditReason::Refund {
+                event_id,
+                ticket_id: Some(ticket_id),
+            },
+        );
+    }
+    waitlist::promote_waitlist(event_id);
+
+    let refund = CancellationRefund {
+        ticket_id,
+        event_id,
+        user_id: ticket.user_id,
+        price: ticket.price,
+        amount,
+        rule,
+        refund_to,
+        refunded_at: time(),
+        rescheduled_at,
+    };
+    REFUND_STORAGE.with(|refunds| {
+        refunds
+            .borrow_mut()
+            .insert((event_id, ticket_id), refund.clone())
+    });
+    Ok(refund)
+}
+
+// Retrieve a ticket the caller holds
+pub(crate) fn caller_ticket(ticket_id: u64) -> Result<Ticket, Error> {
+    let user = privacy::caller_user()?;
+    let ticket = _get_ticket(&ticket_id).ok_or(Error::not_found("ticket", ticket_id))?;
+    if ticket.user_id != user.id {
+        return Err(Error::Unauthorized {
+            msg: format!("ticket id:{} is not held by the caller", ticket_id),
+        });
+    }
+    if ticket.checked_in_at.is_some() {
+        return Err(Error::ValidationFailed {
+            msg: format!("ticket id:{} is already checked in", ticket_id),
+        });
+    }
+    Ok(ticket)
+}
+
+// Check the rules of a policy and sort them by notice, longest first. Less notice can't get more
+// back.
+fn validate_rules(rules: &mut [CancellationRule]) -> Result<(), String> {
+    if rules.is_empty() || rules.len() > MAX_POLICY_RULES {
+        return Err(format!(
+            "a cancellation policy has 1 to {} rules",
+            MAX_POLICY_RULES
+        ));
+    }
+    if let Some(rule) = rules.iter().find(|rule| rule.refund_percent > 100) {
+        return Err(format!("refund of {}% is above 100%", rule.refund_percent));
+    }
+    rules.sort_by_key(|rule| std::cmp::Reverse(rule.hours_before));
+    for pair in rules.windows(2) {
+        if pair[0].hours_before == pair[1].hours_before {
+            return Err(format!(
+                "more than one rule for {} hours before",
+                pair[0].hours_before
+            ));
+        }
+        if pair[1].refund_percent > pair[0].refund_percent {
+            return Err(format!(
+                "{}% back {} hours before is more than the {}% {} hours before",
+                pair[1].refund_percent,
+                pair[1].hours_before,
+                pair[0].refund_percent,
+                pair[0].hours_before
+            ));
+        }
+    }
+    Ok(())
+}
+
+pub(crate) fn get_user_refunds(user_id: u64) -> Vec<CancellationRefund> {
+    REFUND_STORAGE.with(|refunds| {
+        refunds
+            .borrow()
+            .iter()
+            .map(|(_, refund)| refund)
+            .filter(|refund| refund.user_id == user_id)
+            .collect()
+    })
+}
+
+pub(crate) fn remove_event_cancellation_policy(event_id: u64) {
+    POLICY_STORAGE.with(|policies| policies.borrow_mut().remove(&event_id));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(hours_before: u64, refund_percent: u8) -> CancellationRule {
+        CancellationRule {
+            hours_before,
+            refund_percent,
+        }
+    }
+
+    #[test]
+    fn rules_are_sorted_by_notice() {
+        let mut rules = vec![rule(24, 50), rule(168, 100), rule(2, 0)];
+        validate_rules(&mut rules).unwrap();
+        let notice: Vec<u64> = rules.iter().map(|rule| rule.hours_before).collect();
+        assert_eq!(notice, vec![168, 24, 2]);
+    }
+
+    #[test]
+    fn less_notice_cannot_get_more_back() {
+        assert!(validate_rules(&mut [rule(168, 50), rule(24, 80)]).is_err());
+        assert!(validate_rules(&mut [rule(24, 50), rule(24, 40)]).is_err());
+        assert!(validate_rules(&mut [rule(24, 101)]).is_err());
+        assert!(validate_rules(&mut []).is_err());
+    }
+
+    #[test]
+    fn longest_notice_given_decides_the_refund() {
+        let rules = vec![rule(168, 100), rule(24, 50)];
+        let start = 200 * NANOS_PER_HOUR;
+
+        let (_, amount) = refund_for_notice(rules.clone(), 1_000, 0, start);
+        assert_eq!(amount, 1_000);
+        let (applied, amount) =
+            refund_for_notice(rules.clone(), 1_000, 100 * NANOS_PER_HOUR, start);
+        assert_eq!(applied.map(|rule| rule.hours_before), Some(24));
+        assert_eq!(amount, 500);
+    }
+
+    #[test]
+    fn too_little_notice_gets_nothing_back() {
+        let rules = vec![rule(168, 100), rule(24, 50)];
+        let start = 200 * NANOS_PER_HOUR;
+        let (applied, amount) = refund_for_notice(rules, 1_000, 190 * NANOS_PER_HOUR, start);
+        assert!(applied.is_none());
+        assert_eq!(amount, 0);
+    }
+
+    #[test]
+    fn partial_refund_rounds_down_without_overflowing() {
+        let (_, amount) = refund_for_notice(vec![rule(0, 33)], 100, 0, 0);
+        assert_eq!(amount, 33);
+        let (_, amount) = refund_for_notice(vec![rule(0, 50)], u64::MAX, 0, 0);
+        assert_eq!(amount, u64::MAX / 2);
+    }
+}
@@ -0,0 +1,161 @@
+// Checks the interface 'export_candid!' generates against the committed 'e_ticketer_backend.did',
+// which dfx and the frontend bindings are built from. Run with UPDATE_CANDID=1 to write the
+// generated interface to the file, as 'npm run generate' does, after an intended change.
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::PathBuf;
+
+const UPDATE_VAR: &str = "UPDATE_CANDID";
+
+#[test]
+fn interface_matches_committed_did() {
+    let path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("e_ticketer_backend.did");
+    let generated = format!("{}\n", crate::__export_service());
+    if std::env::var_os(UPDATE_VAR).is_some() {
+        std::fs::write(&path, &generated).unwrap();
+        return;
+    }
+    let committed = std::fs::read_to_string(&path).unwrap_or_default();
+    if generated == committed {
+        return;
+    }
+
+    let old = Interface::parse(&committed);
+    let new = Interface::parse(&generated);
+    let mut report = Vec::new();
+    for (name, signature) in &old.methods {
+        match new.methods.get(name) {
+            None => report.push(format!("BREAKING: '{}' was removed", name)),
+            Some(changed) if old.expand(signature) != new.expand(changed) => report.push(format!(
+                "CHANGED: '{}' went from\n    {}\n  to\n    {}",
+                name, signature, changed
+            )),
+            Some(_) => {}
+        }
+    }
+    for name in new.methods.keys() {
+        if !old.methods.contains_key(name) {
+            report.push(format!("ADDED: '{}'", name));
+        }
+    }
+    if report.is_empty() {
+        report.push("only type names or their order changed".to_string());
+    }
+    panic!(
+        "the generated interface differs from {}:\n  {}\n\
+         Changed signatures must stay compatible with deployed frontends. Once the change is \
+         intended, rerun with {}=1 to update the file.",
+        path.display(),
+        report.join("\n  "),
+        UPDATE_VAR
+    );
+}
+
+// The methods and named types of a .did file, as the generator prints them
+struct Interface {
+    types: BTreeMap<String, Vec<String>>,
+    methods: BTreeMap<String, String>,
+}
+
+impl Interface {
+    fn parse(did: &str) -> Interface {
+        let (definitions, service) = did.split_once("service :").unwrap_or((did, ""));
+        let types = definitions
+            .split("\ntype ")
+            .filter_map(|definition| {
+                let definition = definition.trim().trim_start_matches("type ");
+                let (name, body) = definition.split_once(" = ")?;
+                Some((
+                    name.trim().to_string(),
+                    tokens(body.trim().trim_end_matches(';')),
+                ))
+            })
+            .collect();
+        // Methods are separated by the ';' outside of any parentheses or braces
+        let body = service
+            .split_once('{')
+            .and_then(|(_, body)| body.rsplit_once('}'))
+            .map_or("", |(body, _)| body);
+        let mut methods = BTreeMap::new();
+        let (mut depth, mut start) = (0, 0);
+        for (i, c) in body.char_indices() {
+            match c {
+                '(' | '{' => depth += 1,
+                ')' | '}' => depth -= 1,
+                ';' if depth == 0 => {
+                    if let Some((name, signature)) = body[start..i].split_once(" : ") {
+                        methods.insert(name.trim().to_string(), tokens(signature).join(" "));
+                    }
+                    start = i + 1;
+                }
+                _ => {}
+            }
+        }
+        Interface { types, methods }
+    }
+
+    // A signature with the named types spelled out, so renumbered names like 'Result_12' don't
+    // count as a change. Recursive types stay named where they refer to themselves.
+    fn expand(&self, signature: &str) -> String {
+        let mut expanded = Vec::new();
+        self.expand_into(&tokens(signature), &mut BTreeSet::new(), &mut expanded);
+        expanded.join(" ")
+    }
+
+    fn expand_into(
+        &self,
+        tokens: &[String],
+        open: &mut BTreeSet<String>,
+        expanded: &mut Vec<String>,
+    ) {
+        for (i, token) in tokens.iter().enumerate() {
+            // Labels come before a ':', and bare variant tags after a '{' or ';'
+            let is_label = tokens.get(i + 1).is_some_and(|next| next == ":")
+                || (i > 0 && (tokens[i - 1] == "{" || tokens[i - 1] == ";"));
+            match self.types.get(token) {
+                Some(body) if !is_label && !open.contains(token) => {
+                    open.insert(token.clone());
+                    self.expand_into(body, open, expanded);
+                    open.remove(token);
+                }
+                _ => expanded.push(token.clone()),
+            }
+        }
+    }
+}
+
+// Split candid text into identifiers, quoted labels and punctuation
+fn tokens(text: &str) -> Vec<String> {
+    let mut tokens = Vec::new();
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c.is_whitespace() {
+            continue;
+        }
+        if c == '"' {
+            let mut quoted = String::from(c);
+            for c in chars.by_ref() {
+                quoted.push(c);
+                if c == '"' {
+                    break;
+                }
+            }
+            tokens.push(quoted);
+        } else if c.is_alphanumeric() || c == '_' {
+            let mut word = String::from(c);
+            while let Some(&next) = chars.peek() {
+                if !(next.is_alphanumeric() || next == '_') {
+                    break;
+                }
+                word.push(next);
+                chars.next();
+            }
+            tokens.push(word);
+        } else if c == '-' && chars.peek() == Some(&'>') {
+            chars.next();
+            tokens.push("->".to_string());
+        } else {
+            tokens.push(c.to_string());
+        }
+    }
+    tokens
+}
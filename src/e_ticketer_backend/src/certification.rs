@@ -0,0 +1,310 @@
+use crate::attendance::{self, AttendanceProof};
+use crate::{
+    _get_public_event, assets, federation, http, live, waiting_room, waitlist, EVENT_STORAGE,
+};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use candid::Encode;
+use ic_cdk::api::{data_certificate, set_certified_data};
+use ic_certified_map::{
+    fork, fork_hash, labeled, labeled_hash, AsHashTree, Hash, HashTree, RbTree,
+};
+use serde::Serialize;
+use serde_bytes::ByteBuf;
+use sha2::{Digest, Sha256};
+use std::cell::RefCell;
+
+// Labels of the subtrees under the certified root, in sorted order
+const ATTENDANCE_LABEL: &[u8] = b"attendance";
+const EVENTS_LABEL: &[u8] = b"events";
+const HTTP_ASSETS_LABEL: &[u8] = b"http_assets";
+const LIVE_LABEL: &[u8] = b"live";
+
+thread_local! {
+    // SHA-256 of each candid-encoded attendance proof, keyed by the big-endian ticket id
+    static ATTENDANCE_TREE: RefCell<RbTree<[u8; 8], Hash>> = const { RefCell::new(RbTree::new()) };
+
+    // SHA-256 of each candid-encoded event, keyed by the big-endian event id
+    static EVENT_TREE: RefCell<RbTree<[u8; 8], Hash>> = const { RefCell::new(RbTree::new()) };
+
+    // SHA-256 of each HTTP gateway response body, keyed by path
+    static HTTP_ASSETS: RefCell<RbTree<String, Hash>> = const { RefCell::new(RbTree::new()) };
+
+    // SHA-256 of the candid-encoded live counters of each event, keyed by the big-endian event id
+    static LIVE_TREE: RefCell<RbTree<[u8; 8], Hash>> = const { RefCell::new(RbTree::new()) };
+}
+
+// Rebuild the certified tree from storage, the tree lives on the heap and is lost on upgrade
+pub(crate) fn certify_all_events() {
+    let ids: Vec<u64> =
+        EVENT_STORAGE.with(|events| events.borrow().iter().map(|(id, _)| id).collect());
+    for id in ids {
+        update_event_hashes(id);
+    }
+    for (ticket_id, proof) in attendance::all_proofs() {
+        update_attendance_hash(ticket_id, &proof);
+    }
+    // Certify the '/events' listing even without events, so witnesses match the certified data
+    certify_event_list();
+}
+
+// Recompute the hashes of an event and the responses that include it, after any change to it
+pub(crate) fn certify_event(event_id: u64) {
+    update_event_hashes(event_id);
+    certify_event_list();
+    // Every change to an event comes through here, so does its summary for the aggregator
+    federation::track_event(event_id);
+}
+
+// Certify a newly claimed attendance proof, proofs are never changed once claimed
+pub(crate) fn certify_attendance_proof(ticket_id: u64, proof: &AttendanceProof) {
+    update_attendance_hash(ticket_id, proof);
+    publish_root_hash();
+}
+
+// Recertify the live counters of an event after a sale, check-in or waitlist change
+pub(crate) fn certify_live_counters(event_id: u64) {
+    update_live_hash(event_id);
+    publish_root_hash();
+}
+
+// Recertify the waiting room pages of an event after its waitlist changed, dropping those of the
+// entries that left it, along with the live counters showing its length
+pub(crate) fn certify_waiting_room(event_id: u64, left: &[u64]) {
+    for entry_id in left {
+        for (path, _) in waiting_room_paths(event_id, *entry_id) {
+            HTTP_ASSETS.with(|assets| assets.borrow_mut().delete(path.as_bytes()));
+        }
+    }
+    update_waiting_room_hashes(event_id);
+    update_live_hash(event_id);
+    publish_root_hash();
+}
+
+fn update_attendance_hash(ticket_id: u64, proof: &AttendanceProof) {
+    let hash = sha256(&Encode!(proof).unwrap());
+    ATTENDANCE_TREE.with(|tree| tree.borrow_mut().insert(ticket_id.to_be_bytes(), hash));
+}
+
+// Recompute the hashes kept for a single event, or drop them if it is gone, in the trash or embargoed
+fn update_event_hashes(event_id: u64) {
+    let event = _get_public_event(&event_id);
+    let key = event_id.to_be_bytes();
+    let paths = [
+        format!("/events/{}", event_id),
+        format!("/events/{}/tickets", event_id),
+        format!("/events/{}/event.ics", event_id),
+    ];
+    let image_path = format!("/events/{}/image", event_id);
+
+    match event {
+        Some(event) => {
+            let hash = sha256(&Encode!(&event).unwrap());
+            EVENT_TREE.with(|tree| tree.borrow_mut().insert(key, hash));
+            for path in paths {
+                let hash = sha256(&http::render(&path).body);
+                HTTP_ASSETS.with(|assets| assets.borrow_mut().insert(path, hash));
+            }
+            // The image is hashed once on upload rather than on every change to the event
+            match assets::image_hash(event_id) {
+                Some(hash) => {
+                    HTTP_ASSETS.with(|assets| assets.borrow_mut().insert(image_path, hash))
+                }
+                None => {
+                    HTTP_ASSETS.with(|assets| assets.borrow_mut().delete(image_path.as_bytes()))
+                }
+            }
+        }
+        None => {
+            EVENT_TREE.with(|tree| tree.borrow_mut().delete(&key));
+            for path in paths.iter().chain([&image_path]) {
+                HTTP_ASSETS.with(|assets| assets.borrow_mut().delete(path.as_bytes()));
+            }
+        }
+    }
+    update_waiting_room_hashes(event_id);
+    update_live_hash(event_id);
+}
+
+// Recompute the hashes of the waiting room pages of everyone on an event's waitlist, or drop
+// them if the event is gone, in the trash or embargoed
+fn update_waiting_room_hashes(event_id: u64) {
+    match waiting_room::event_statuses(event_id) {
+        Ok(statuses) => {
+            for status in statuses {
+                for (path, json) in waiting_room_paths(event_id, status.entry_id) {
+                    let hash = sha256(&http::waiting_room_response(&status, json).body);
+                    HTTP_ASSETS.with(|assets| assets.borrow_mut().insert(path, hash));
+                }
+            }
+        }
+        Err(_) => {
+            for (entry_id, _) in waitlist::queue(event_id) {
+                for (path, _) in waiting_room_paths(event_id, entry_id) {
+                    HTTP_ASSETS.with(|assets| assets.borrow_mut().delete(path.as_bytes()));
+                }
+            }
+        }
+    }
+}
+
+// The page and JSON paths of a waitlist entry, along with whether the response is JSON
+fn waiting_room_paths(event_id: u64, entry_id: u64) -> [(String, bool); 2] {
+    [
+        (format!("/events/{}/waitlist/{}", event_id, entry_id), false),
+        (
+            format!("/events/{}/waitlist/{}.json", event_id, entry_id),
+            true,
+        ),
+    ]
+}
+
+fn update_live_hash(event_id: u64) {
+    let key = event_id.to_be_bytes();
+    match live::refresh_counters(event_id) {
+        Some(counters) => {
+            let hash = sha256(&Encode!(&counters).unwrap());
+            LIVE_TREE.with(|tree| tree.borrow_mut().insert(key, hash));
+        }
+        None => LIVE_TREE.with(|tree| tree.borrow_mut().delete(&key)),
+    }
+}
+
+// Recompute the hash of the '/events' response and publish the new root hash
+fn certify_event_list() {
+    let hash = sha256(&http::render("/events").body);
+    HTTP_ASSETS.with(|assets| assets.borrow_mut().insert("/events".to_string(), hash));
+    publish_root_hash();
+}
+
+fn publish_root_hash() {
+    let attendance_hash = ATTENDANCE_TREE.with(|tree| tree.borrow().root_hash());
+    let events_hash = EVENT_TREE.with(|tree| tree.borrow().root_hash());
+    let assets_hash = HTTP_ASSETS.with(|assets| assets.borrow().root_hash());
+    let live_hash = LIVE_TREE.with(|tree| tree.borrow().root_hash());
+    set_certified_data(&fork_hash(
+        &labeled_hash(ATTENDANCE_LABEL, &attendance_hash),
+        &fork_hash(
+            &labeled_hash(EVENTS_LABEL, &events_hash),
+            &fork_hash(
+                &labeled_hash(HTTP_ASSETS_LABEL, &assets_hash),
+                &labeled_hash(LIVE_LABEL, &live_hash),
+            ),
+        ),
+    ));
+}
+
+// Witness proving the hash of a single event, or its absence
+pub(crate) fn event_witness(event_id: u64) -> ByteBuf {
+    EVENT_TREE.with(|tree| {
+        let tree = tree.borrow();
+        events_witness_tree(tree.witness(&event_id.to_be_bytes()))
+    })
+}
+
+// Witness proving the hashes of all events
+pub(crate) fn all_events_witness() -> ByteBuf {
+    EVENT_TREE.with(|tree| {
+        let tree = tree.borrow();
+        events_witness_tree(tree.as_hash_tree())
+    })
+}
+
+// Witness proving the hash of the attendance proof of a ticket, or its absence
+pub(crate) fn attendance_witness(ticket_id: u64) -> ByteBuf {
+    ATTENDANCE_TREE.with(|tree| {
+        let tree = tree.borrow();
+        let events_hash = EVENT_TREE.with(|tree| tree.borrow().root_hash());
+        let assets_hash = HTTP_ASSETS.with(|assets| assets.borrow().root_hash());
+        let live_hash = LIVE_TREE.with(|tree| tree.borrow().root_hash());
+        ByteBuf::from(serialize_tree(root_tree(
+            labeled(ATTENDANCE_LABEL, tree.witness(&ticket_id.to_be_bytes())),
+            HashTree::Pruned(labeled_hash(EVENTS_LABEL, &events_hash)),
+            HashTree::Pruned(labeled_hash(HTTP_ASSETS_LABEL, &assets_hash)),
+            HashTree::Pruned(labeled_hash(LIVE_LABEL, &live_hash)),
+        )))
+    })
+}
+
+// Witness proving the hash of the live counters of an event, or their absence
+pub(crate) fn live_counters_witness(event_id: u64) -> ByteBuf {
+    LIVE_TREE.with(|tree| {
+        let tree = tree.borrow();
+        let attendance_hash = ATTENDANCE_TREE.with(|tree| tree.borrow().root_hash());
+        let events_hash = EVENT_TREE.with(|tree| tree.borrow().root_hash());
+        let assets_hash = HTTP_ASSETS.with(|assets| assets.borrow().root_hash());
+        ByteBuf::from(serialize_tree(root_tree(
+            HashTree::Pruned(labeled_hash(ATTENDANCE_LABEL, &attendance_hash)),
+            HashTree::Pruned(labeled_hash(EVENTS_LABEL, &events_hash)),
+            HashTree::Pruned(labeled_hash(HTTP_ASSETS_LABEL, &assets_hash)),
+            labeled(LIVE_LABEL, tree.witness(&event_id.to_be_bytes())),
+        )))
+    })
+}
+
+// Certificate of the current root hash, only available in non-replicated queries
+pub(crate) fn certificate() -> Option<ByteBuf> {
+    data_certificate().map(ByteBuf::from)
+}
+
+// 'IC-Certificate' header for a response served by the HTTP gateway, if the path is certified
+pub(crate) fn http_certificate_header(path: &str) -> Option<(String, String)> {
+    let certificate = data_certificate()?;
+    let witness = HTTP_ASSETS.with(|assets| {
+        let assets = assets.borrow();
+        assets.get(path.as_bytes())?;
+        let attendance_hash = ATTENDANCE_TREE.with(|tree| tree.borrow().root_hash());
+        let events_hash = EVENT_TREE.with(|tree| tree.borrow().root_hash());
+        let live_hash = LIVE_TREE.with(|tree| tree.borrow().root_hash());
+        Some(serialize_tree(root_tree(
+            HashTree::Pruned(labeled_hash(ATTENDANCE_LABEL, &attendance_hash)),
+            HashTree::Pruned(labeled_hash(EVENTS_LABEL, &events_hash)),
+            labeled(HTTP_ASSETS_LABEL, assets.witness(path.as_bytes())),
+            HashTree::Pruned(labeled_hash(LIVE_LABEL, &live_hash)),
+        )))
+    })?;
+
+    Some((
+        "IC-Certificate".to_string(),
+        format!(
+            "certificate=:{}:, tree=:{}:",
+            STANDARD.encode(certificate),
+            STANDARD.encode(witness)
+        ),
+    ))
+}
+
+// Wrap a witness of the events subtree into a witness of the whole certified tree
+fn events_witness_tree(witness: HashTree<'_>) -> ByteBuf {
+    let attendance_hash = ATTENDANCE_TREE.with(|tree| tree.borrow().root_hash());
+    let assets_hash = HTTP_ASSETS.with(|assets| assets.borrow().root_hash());
+    let live_hash = LIVE_TREE.with(|tree| tree.borrow().root_hash());
+    ByteBuf::from(serialize_tree(root_tree(
+        HashTree::Pruned(labeled_hash(ATTENDANCE_LABEL, &attendance_hash)),
+        labeled(EVENTS_LABEL, witness),
+        HashTree::Pruned(labeled_hash(HTTP_ASSETS_LABEL, &assets_hash)),
+        HashTree::Pruned(labeled_hash(LIVE_LABEL, &live_hash)),
+    )))
+}
+
+// Assemble the witnesses, or pruned hashes, of the subtrees in the shape of the certified root
+fn root_tree<'a>(
+    attendance: HashTree<'a>,
+    events: HashTree<'a>,
+    assets: HashTree<'a>,
+    live: HashTree<'a>,
+) -> HashTree<'a> {
+    fork(attendance, fork(events, fork(assets, live)))
+}
+
+// Encode a hash tree as self-describing CBOR, as expected by agents and boundary nodes
+fn serialize_tree(tree: HashTree<'_>) -> Vec<u8> {
+    let mut bytes = vec![];
+    let mut serializer = serde_cbor::Serializer::new(&mut bytes);
+    serializer.self_describe().unwrap();
+    tree.serialize(&mut serializer).unwrap();
+    bytes
+}
+
+fn sha256(bytes: &[u8]) -> Hash {
+    Sha256::digest(bytes).into()
+}
@@ -0,0 +1,182 @@
+use crate::clock::time;
+use crate::{
+    _get_event, _get_public_event, caller_is_admin, icrc7, organizers, Memory, MEMORY_MANAGER,
+};
+use candid::{Decode, Encode};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, Cell, StableBTreeMap, Storable};
+use std::{borrow::Cow, cell::RefCell};
+
+// Changes kept, the oldest are dropped past this. Clients behind by more fetch the records again.
+const MAX_CHANGES: u64 = 100_000;
+// Changes returned per call, clients ask again from the last sequence number they got
+const MAX_PAGE: usize = 500;
+
+// Define an enum for the kind of record a change was made to
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq)]
+pub enum ChangeEntity {
+    Event,
+    User,
+    Ticket,
+}
+
+// Define an enum for what was done to the record
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq)]
+pub enum ChangeOperation {
+    Created,
+    Updated,
+    // Moved to the trash or removed for good, either way clients drop the record
+    Deleted,
+}
+
+// Define a struct for an entry of the change log, saying which record to fetch again
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct ChangeRecord {
+    // Increases by one with every change
+    seq: u64,
+    entity: ChangeEntity,
+    id: u64,
+    operation: ChangeOperation,
+    changed_at: u64,
+    // User a user or ticket record belongs to, who is the only one besides admins to see it
+    user_id: Option<u64>,
+}
+
+// Define a struct for a page of the change log, as seen by the caller
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub struct ChangePage {
+    changes: Vec<ChangeRecord>,
+    // Where the next page starts, changes the caller can't see are skipped over
+    next_seq: u64,
+    // Oldest change kept, above 'seq + 1' the ones in between were dropped
+    oldest_seq: u64,
+    // Latest change, below 'seq' the canister was restored from a backup taken earlier
+    latest_seq: u64,
+}
+
+impl Storable for ChangeRecord {
+    // Conversion to bytes
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    // Conversion from bytes
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for ChangeRecord {
+    const MAX_SIZE: u32 = 128;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    // Sequence number of the latest change
+    static SEQUENCE: RefCell<Cell<u64, Memory>> = RefCell::new(
+        Cell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(145))), 0)
+            .expect("Cannot create the change sequence")
+    );
+
+    // Change log keyed by sequence number
+    static CHANGE_LOG: RefCell<StableBTreeMap<u64, ChangeRecord, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(146)))
+    ));
+}
+
+// Changes made after the given sequence number, oldest first, starting from 0. The log covers
+// writes of events, users and tickets, so clients can keep those cached; other records aren't
+// logged. Callers see the changes of events everyone can see or that they organize, and those
+// of their own user and tickets, admins see all. A page showing changes were dropped or the
+// canister was restored means the caches must be fetched again in full; a restore from a backup
+// brings back the log of the snapshot along with its records.
+#[ic_cdk::query]
+fn get_changes_since(seq: u64) -> ChangePage {
+    let is_admin = caller_is_admin().is_ok();
+    let caller_user = icrc7::wallet_user(ic_cdk::caller());
+    let scanned: Vec<ChangeRecord> = CHANGE_LOG.with(|log| {
+        log.borrow()
+            .range(seq.saturating_add(1)..)
+            .take(MAX_PAGE)
+            .map(|(_, change)| change)
+            .collect()
+    });
+    let next_seq = scanned.last().map_or(seq, |change| change.seq);
+    let (oldest_seq, latest_seq) = CHANGE_LOG.with(|log| {
+        let log = log.borrow();
+        (
+            log.first_key_value().map_or(0, |(seq, _)| seq),
+            log.last_key_value().map_or(0, |(seq, _)| seq),
+        )
+    });
+
+    ChangePage {
+        changes: scanned
+            .into_iter()
+            .filter(|change| is_admin || is_visible(change, caller_user))
+            .collect(),
+        next_seq,
+        oldest_seq,
+        latest_seq,
+    }
+}
+
+// Append a change of a record to the log
+pub(crate) fn record_change(
+    entity: ChangeEntity,
+    id: u64,
+    user_id: Option<u64>,
+    operation: ChangeOperation,
+) {
+    let seq = SEQUENCE
+        .with(|sequence| {
+            let current = *sequence.borrow().get();
+            sequence.borrow_mut().set(current + 1)
+        })
+        .expect("Cannot increment the change sequence")
+        + 1;
+    CHANGE_LOG.with(|log| {
+        let mut log = log.borrow_mut();
+        log.insert(
+            seq,
+            ChangeRecord {
+                seq,
+                entity,
+                id,
+                operation,
+                changed_at: time(),
+                user_id,
+            },
+        );
+        if log.len() > MAX_CHANGES {
+            if let Some((oldest, _)) = log.first_key_value() {
+                log.remove(&oldest);
+            }
+        }
+    });
+}
+
+// What storing a record did, given whether it was stored before and whether it is in the trash
+pub(crate) fn stored(existed: bool, deleted_at: Option<u64>) -> ChangeOperation {
+    match (existed, deleted_at) {
+        (_, Some(_)) => ChangeOperation::Deleted,
+        (true, None) => ChangeOperation::Updated,
+        (false, None) => ChangeOperation::Created,
+    }
+}
+
+// Whether a change can be seen by a caller who isn't an admin. Deleted events are shown to all,
+// so the ones cached while they could be seen are dropped.
+fn is_visible(change: &ChangeRecord, caller_user: Option<u64>) -> bool {
+    match change.entity {
+        ChangeEntity::Event => {
+            change.operation == ChangeOperation::Deleted
+                || _get_public_event(&change.id).is_some()
+                || _get_event(&change.id)
+                    .is_some_and(|event| organizers::check_event_organizer(&event).is_ok())
+        }
+        ChangeEntity::User | ChangeEntity::Ticket => {
+            caller_user.is_some() && change.user_id == caller_user
+        }
+    }
+}
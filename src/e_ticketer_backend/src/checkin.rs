@@ -0,0 +1,428 @@
+use crate::auth::{self, ApiScope};
+use crate::clock::time;
+use crate::ratelimit::rate_limit;
+use crate::{
+    _check_in_ticket, _get_event, _get_ticket, attendance, certification, fraud, organizers,
+    qr_tokens, rooms, stats, store_ticket, ticket_codes, webhooks, Error, Memory, StringKey,
+    Ticket, ID_COUNTER, MEMORY_MANAGER,
+};
+use candid::{Decode, Encode, Principal};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use std::collections::BTreeSet;
+use std::{borrow::Cow, cell::RefCell};
+
+// Scans pushed per call, so a sync stays well within the instruction limit
+const MAX_SYNC_RECORDS: usize = 500;
+const MAX_DEVICE_NAME_LEN: usize = 64;
+// A scan repeated this soon after the check-in is taken for a scanner retry, not a second entry
+const CHECK_IN_GRACE: u64 = 30 * 1_000_000_000;
+
+// Define a struct for a scanning device registered to check tickets in for an event
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct ScannerDevice {
+    event_id: u64,
+    // Devices pull manifests and sync with their own identity
+    device: Principal,
+    name: String,
+    registered_at: u64,
+    last_sync_at: Option<u64>,
+}
+
+// Define a struct for a ticket as listed in a device manifest
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub struct ManifestEntry {
+    ticket_id: u64,
+    code: Option<String>,
+    checked_in: bool,
+}
+
+// Define a struct for the tickets a device validates while offline
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub struct CheckInManifest {
+    event_id: u64,
+    tickets: Vec<ManifestEntry>,
+    generated_at: u64,
+}
+
+// Define a struct for a check-in a device recorded while offline
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct CheckInRecord {
+    ticket_id: u64,
+    // When the device scanned the ticket, in nanoseconds since the epoch
+    scanned_at: u64,
+    // Room the attendee asked for, None for the event's first
+    zone: Option<String>,
+}
+
+// Define an enum for how a pushed check-in was reconciled
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub enum CheckInOutcome {
+    // Zone is the room the ticket was sent to, None for events without rooms
+    Accepted {
+        ticket_id: u64,
+        zone: Option<String>,
+    },
+    // The ticket was already checked in, online or by an earlier sync
+    Duplicate {
+        ticket_id: u64,
+        checked_in_at: u64,
+    },
+    Rejected {
+        ticket_id: u64,
+        msg: String,
+    },
+}
+
+// Define a struct for the outcome of checking a ticket in by its code
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub struct CheckInResult {
+    ticket: Ticket,
+    // Room the ticket was sent to, None for events without rooms
+    zone: Option<String>,
+    // The ticket was checked in moments before, this is the earlier check-in again
+    duplicate: bool,
+}
+
+// Define a struct for a duplicate check-in kept for the organizer to review
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct CheckInConflict {
+    ticket_id: u64,
+    device: Principal,
+    scanned_at: u64,
+    // Check-in the scan collided with
+    checked_in_at: u64,
+    synced_at: u64,
+}
+
+impl Storable for ScannerDevice {
+    // Conversion to bytes
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    // Conversion from bytes
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl Storable for CheckInConflict {
+    // Conversion to bytes
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    // Conversion from bytes
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for ScannerDevice {
+    const MAX_SIZE: u32 = 256;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl BoundedStorable for CheckInConflict {
+    const MAX_SIZE: u32 = 256;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    // Devices keyed by (event id, the device's principal in text form)
+    static DEVICE_STORAGE: RefCell<StableBTreeMap<(u64, StringKey), ScannerDevice, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(51)))
+    ));
+
+    // Duplicate scans keyed by (event id, conflict id)
+    static CONFLICT_STORAGE: RefCell<StableBTreeMap<(u64, u64), CheckInConflict, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(52)))
+    ));
+}
+
+#[ic_cdk::query]
+fn get_scanner_devices(event_id: u64) -> Result<Vec<ScannerDevice>, Error> {
+    organizers::authorize_event(event_id)?;
+    Ok(DEVICE_STORAGE.with(|devices| {
+        devices
+            .borrow()
+            .range((event_id, StringKey::default())..)
+            .take_while(|((id, _), _)| *id == event_id)
+            .map(|(_, device)| device)
+            .collect()
+    }))
+}
+
+#[ic_cdk::update(guard = "rate_limit")]
+fn register_scanner_device(
+    event_id: u64,
+    device: Principal,
+    name: String,
+) -> Result<ScannerDevice, Error> {
+    organizers::authorize_event(event_id)?;
+    if device == Principal::anonymous() {
+        return Err(Error::ValidationFailed {
+            msg: "scanner devices need their own identity".to_string(),
+        });
+    }
+    if name.trim().is_empty() || name.len() > MAX_DEVICE_NAME_LEN {
+        return Err(Error::ValidationFailed {
+            msg: format!(
+                "device name must be between 1 and {} bytes",
+                MAX_DEVICE_NAME_LEN
+            ),
+        });
+    }
+
+    let scanner = ScannerDevice {
+        event_id,
+        device,
+        name,
+        registered_at: time(),
+        last_sync_at: None,
+    };
+    DEVICE_STORAGE.with(|devices| {
+        devices
+            .borrow_mut()
+            .insert(device_key(event_id, device), scanner.clone())
+    });
+
+    Ok(scanner)
+}
+
+#[ic_cdk::update(guard = "rate_limit")]
+fn remove_scanner_device(event_id: u64, device: Principal) -> Result<String, Error> {
+    organizers::authorize_event(event_id)?;
+    DEVICE_STORAGE
+        .with(|devices| devices.borrow_mut().remove(&device_key(event_id, device)))
+        .ok_or(Error::NotFound {
+            entity: "device".to_string(),
+            id: device.to_string(),
+            msg: format!(
+                "device {} is not registered for event id:{}",
+                device, event_id
+            ),
+        })?;
+    Ok(format!(
+        "device {} removed from event id:{}",
+        device, event_id
+    ))
+}
+
+#[ic_cdk::query]
+fn get_checkin_manifest(event_id: u64) -> Result<CheckInManifest, Error> {
+    let event = _get_event(&event_id).ok_or(Error::not_found("event", event_id))?;
+    caller_device(event_id).map_err(|msg| Error::ValidationFailed { msg })?;
+
+    Ok(CheckInManifest {
+        event_id,
+        tickets: event
+            .ticket_ids
+            .iter()
+            .filter_map(_get_ticket)
+            .map(|ticket| ManifestEntry {
+                ticket_id: ticket.id,
+                code: ticket.code,
+                checked_in: ticket.checked_in_at.is_some(),
+            })
+            .collect(),
+        generated_at: time(),
+    })
+}
+
+// Check in the ticket with the printed code. Scanning it again within a short window returns the
+// same check-in flagged as a duplicate, later scans are turned down as before.
+#[ic_cdk::update(guard = "rate_limit")]
+fn check_in_by_code(
+    code: String,
+    api_key: Option<String>,
+    zone: Option<String>,
+) -> Result<CheckInResult, Error> {
+    auth::authorize(api_key, ApiScope::CheckIn)?;
+    let code = code.trim();
+    let ticket = if code.starts_with(qr_tokens::TOKEN_PREFIX) {
+        qr_tokens::verify_token(code).map_err(|msg| Error::ValidationFailed { msg })?
+    } else {
+        let ticket = ticket_codes::_get_ticket_by_code(code)?;
+        // Codes can be guessed, events signing their QR codes only take the tokens
+        if qr_tokens::is_enabled(ticket.event_id) {
+            fraud::record_failed_check_in(&ticket);
+            return Err(Error::ValidationFailed {
+                msg: format!(
+                    "event id:{} only admits tickets by their signed QR code",
+                    ticket.event_id
+                ),
+            });
+        }
+        ticket
+    };
+
+    if let Some(checked_in_at) = ticket.checked_in_at {
+        if time().saturating_sub(checked_in_at) <= CHECK_IN_GRACE {
+            return Ok(CheckInResult {
+                zone: rooms::assigned_zone(ticket.event_id, ticket.id),
+                ticket,
+                duplicate: true,
+            });
+        }
+    }
+    let (ticket, zone) = _check_in_ticket(ticket.id, zone).inspect_err(|_| {
+        fraud::record_failed_check_in(&ticket);
+    })?;
+    Ok(CheckInResult {
+        ticket,
+        zone,
+        duplicate: false,
+    })
+}
+
+#[ic_cdk::update(guard = "rate_limit")]
+fn sync_checkins(records: Vec<CheckInRecord>) -> Result<Vec<CheckInOutcome>, Error> {
+    if records.is_empty() || records.len() > MAX_SYNC_RECORDS {
+        return Err(Error::ValidationFailed {
+            msg: format!(
+                "between 1 and {} check-ins can be synced at once",
+                MAX_SYNC_RECORDS
+            ),
+        });
+    }
+
+    let now = time();
+    let mut event_ids = BTreeSet::new();
+    let outcomes = records
+        .into_iter()
+        .map(|record| reconcile(record, now, &mut event_ids))
+        .collect();
+
+    // Each event the device synced for gets its sync time and its check-ins certified
+    let caller = ic_cdk::caller();
+    for event_id in event_ids {
+        if let Ok(mut device) = caller_device(event_id) {
+            device.last_sync_at = Some(now);
+            DEVICE_STORAGE.with(|devices| {
+                devices
+                    .borrow_mut()
+                    .insert(device_key(event_id, caller), device)
+            });
+        }
+        certification::certify_event(event_id);
+    }
+
+    Ok(outcomes)
+}
+
+#[ic_cdk::query]
+fn get_checkin_conflicts(event_id: u64) -> Result<Vec<CheckInConflict>, Error> {
+    organizers::authorize_event(event_id)?;
+    Ok(CONFLICT_STORAGE.with(|conflicts| {
+        conflicts
+            .borrow()
+            .range((event_id, 0)..=(event_id, u64::MAX))
+            .map(|(_, conflict)| conflict)
+            .collect()
+    }))
+}
+
+// Apply a single offline check-in, the first scan of a ticket wins. The events the device
+// synced for are collected along the way.
+fn reconcile(record: CheckInRecord, now: u64, event_ids: &mut BTreeSet<u64>) -> CheckInOutcome {
+    let ticket_id = record.ticket_id;
+    let Some(mut ticket) = _get_ticket(&ticket_id) else {
+        return CheckInOutcome::Rejected {
+            ticket_id,
+            msg: format!("ticket id:{} does not exist", ticket_id),
+        };
+    };
+    let event_id = ticket.event_id;
+    let device = match caller_device(event_id) {
+        Ok(device) => device.device,
+        Err(msg) => return CheckInOutcome::Rejected { ticket_id, msg },
+    };
+    event_ids.insert(event_id);
+
+    if let Some(checked_in_at) = ticket.checked_in_at {
+        let conflict_id = ID_COUNTER
+            .with(|counter| {
+                let current_id = *counter.borrow().get();
+                counter.borrow_mut().set(current_id + 1)
+            })
+            .expect("Cannot increment Ids");
+        let conflict = CheckInConflict {
+            ticket_id,
+            device,
+            scanned_at: record.scanned_at,
+            checked_in_at,
+            synced_at: now,
+        };
+        CONFLICT_STORAGE.with(|conflicts| {
+            conflicts
+                .borrow_mut()
+                .insert((event_id, conflict_id), conflict)
+        });
+        fraud::record_failed_check_in(&ticket);
+        return CheckInOutcome::Duplicate {
+            ticket_id,
+            checked_in_at,
+        };
+    }
+
+    let zone = match rooms::assign_zone(event_id, ticket_id, record.zone) {
+        Ok(zone) => zone,
+        Err(msg) => return CheckInOutcome::Rejected { ticket_id, msg },
+    };
+
+    // Device clocks can run ahead, a check-in can't be later than its sync
+    ticket.checked_in_at = Some(record.scanned_at.min(now));
+    ticket.updated_at = Some(now);
+    store_ticket(&mut ticket);
+    stats::record_check_in(event_id);
+    attendance::record_attendance(&ticket);
+    webhooks::enqueue_checked_in(&ticket, zone.clone());
+    CheckInOutcome::Accepted { ticket_id, zone }
+}
+
+// The registered device making the call, devices only serve the events they are registered for
+pub(crate) fn caller_device(event_id: u64) -> Result<ScannerDevice, String> {
+    let caller = ic_cdk::caller();
+    DEVICE_STORAGE
+        .with(|devices| devices.borrow().get(&device_key(event_id, caller)))
+        .ok_or(format!(
+            "caller is not a scanner device of event id:{}",
+            event_id
+        ))
+}
+
+fn device_key(event_id: u64, device: Principal) -> (u64, StringKey) {
+    (event_id, StringKey(device.to_text()))
+}
+
+// Drop the devices and conflicts of a purged event
+pub(crate) fn remove_event_checkins(event_id: u64) {
+    remove_event_conflicts(event_id);
+    DEVICE_STORAGE.with(|devices| {
+        let mut devices = devices.borrow_mut();
+        let keys: Vec<(u64, StringKey)> = devices
+            .range((event_id, StringKey::default())..)
+            .take_while(|((id, _), _)| *id == event_id)
+            .map(|(key, _)| key)
+            .collect();
+        for key in keys {
+            devices.remove(&key);
+        }
+    });
+}
+
+// Drop the duplicate scans of an event, its devices stay registered
+pub(crate) fn remove_event_conflicts(event_id: u64) {
+    CONFLICT_STORAGE.with(|conflicts| {
+        let mut conflicts = conflicts.borrow_mut();
+        let keys: Vec<(u64, u64)> = conflicts
+            .range((event_id, 0)..=(event_id, u64::MAX))
+            .map(|(key, _)| key)
+            .collect();
+        for key in keys {
+            conflicts.remove(&key);
+        }
+    });
+}
@@ -0,0 +1,59 @@
+use std::cell::RefCell;
+
+#[cfg(feature = "test-clock")]
+use crate::{caller_is_admin, Error};
+
+// Source of the current time, in nanoseconds since the epoch
+trait Clock {
+    fn now(&self) -> u64;
+}
+
+// Time as seen by the replica, the only clock outside of test builds
+struct CanisterClock;
+
+impl Clock for CanisterClock {
+    fn now(&self) -> u64 {
+        ic_cdk::api::time()
+    }
+}
+
+// Time set by a test, standing still until it is set again
+#[cfg(any(test, feature = "test-clock"))]
+struct FixedClock(u64);
+
+#[cfg(any(test, feature = "test-clock"))]
+impl Clock for FixedClock {
+    fn now(&self) -> u64 {
+        self.0
+    }
+}
+
+thread_local! {
+    // Kept on the heap on purpose, an upgrade goes back to the canister's clock
+    static CLOCK: RefCell<Box<dyn Clock>> = RefCell::new(Box::new(CanisterClock));
+}
+
+// Current time, everything time-dependent reads it from here so tests can set it
+pub(crate) fn time() -> u64 {
+    CLOCK.with(|clock| clock.borrow().now())
+}
+
+// Only built with the 'test-clock' feature, so integration tests can step through sale windows,
+// reminders and expiries. Timers still fire on the replica's time, jobs are started with 'run_job'.
+#[cfg(feature = "test-clock")]
+#[ic_cdk::update(guard = "caller_is_admin")]
+fn set_test_time(now: Option<u64>) -> Result<u64, Error> {
+    CLOCK.with(|clock| {
+        *clock.borrow_mut() = match now {
+            Some(now) => Box::new(FixedClock(now)),
+            None => Box::new(CanisterClock),
+        }
+    });
+    Ok(time())
+}
+
+// Unit tests run off the replica, which has no clock of its own
+#[cfg(test)]
+pub(crate) fn set_time(now: u64) {
+    CLOCK.with(|clock| *clock.borrow_mut() = Box::new(FixedClock(now)));
+}
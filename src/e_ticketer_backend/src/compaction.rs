@@ -0,0 +1,228 @@
+use crate::clock::time;
+use crate::{
+    backup, caller_is_admin, certification, Error, Memory, EVENT_STORAGE, MEMORY_MANAGER,
+    TICKET_STORAGE, USER_STORAGE,
+};
+use candid::{Decode, Encode};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, Cell, StableBTreeMap, Storable};
+use std::ops::Bound;
+use std::{borrow::Cow, cell::RefCell, thread::LocalKey, time::Duration};
+
+// Entries copied per timer tick, well within the instruction limit of a message
+const BATCH: u64 = 500;
+// Memory the live entries of a map are copied out to while it is rebuilt
+const SCRATCH_MEMORY_ID: u8 = 40;
+
+// Maps rebuilt, in order
+const TARGETS: [&str; 3] = ["events", "users", "tickets"];
+
+// Define an enum for the step a compaction is at
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq)]
+pub enum CompactionPhase {
+    // Live entries are copied to the scratch memory, the map is untouched
+    CopyOut,
+    // The map was reset and the entries are copied back, packed from the start of its memory
+    CopyBack,
+    Done,
+}
+
+// Define a struct for the progress of the last compaction
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct CompactionStatus {
+    map: String,
+    phase: CompactionPhase,
+    // Entries of the current map copied in this phase
+    copied: u64,
+    total: u64,
+    maps_done: u64,
+    maps_total: u64,
+    started_at: u64,
+    finished_at: Option<u64>,
+    // Last key copied in this phase
+    cursor: Option<u64>,
+}
+
+// Define a struct for the compaction state, kept in stable memory so an upgrade doesn't
+// leave a map half copied back
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct CompactionState {
+    status: Option<CompactionStatus>,
+}
+
+impl Storable for CompactionState {
+    // Conversion to bytes
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    // Conversion from bytes
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+thread_local! {
+    static STATE: RefCell<Cell<CompactionState, Memory>> = RefCell::new(
+        Cell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(41))),
+            CompactionState::default(),
+        )
+        .expect("Cannot create the compaction state")
+    );
+}
+
+// Rebuild the event, user and ticket maps so their live entries are packed together.
+// Virtual memories never shrink, so the pages already allocated stay with the canister,
+// but the rebuilt maps drop their free lists and grow into the space left after them.
+#[ic_cdk::update(guard = "caller_is_admin")]
+fn start_compaction() -> Result<CompactionStatus, Error> {
+    if status().is_some_and(|status| status.phase != CompactionPhase::Done) {
+        return Err(Error::ValidationFailed {
+            msg: "a compaction is already in progress".to_string(),
+        });
+    }
+    // Changes are held off until every map is copied back
+    if !backup::begin_compaction() {
+        return Err(Error::ValidationFailed {
+            msg: "a backup or restore is in progress".to_string(),
+        });
+    }
+
+    let status = begin_target(0, time());
+    save(status.clone());
+    schedule_step();
+    Ok(status)
+}
+
+#[ic_cdk::query(guard = "caller_is_admin")]
+fn get_compaction_status() -> Option<CompactionStatus> {
+    status()
+}
+
+// Pick a compaction interrupted by an upgrade back up, updates stay held off until it ends
+pub(crate) fn resume_compaction() {
+    if status().is_some_and(|status| status.phase != CompactionPhase::Done)
+        && backup::begin_compaction()
+    {
+        schedule_step();
+    }
+}
+
+fn status() -> Option<CompactionStatus> {
+    STATE.with(|state| state.borrow().get().status.clone())
+}
+
+fn save(status: CompactionStatus) {
+    STATE
+        .with(|state| {
+            state.borrow_mut().set(CompactionState {
+                status: Some(status),
+            })
+        })
+        .expect("Cannot save the compaction state");
+}
+
+fn schedule_step() {
+    ic_cdk_timers::set_timer(Duration::ZERO, || {
+        let Some(mut status) = status() else {
+            return;
+        };
+        let target = status.maps_done as usize;
+        let target_done = match target {
+            0 => step(&EVENT_STORAGE, 1, &mut status),
+            1 => step(&USER_STORAGE, 2, &mut status),
+            _ => step(&TICKET_STORAGE, 3, &mut status),
+        };
+
+        if target_done && target + 1 < TARGETS.len() {
+            status = begin_target(target + 1, status.started_at);
+        } else if target_done {
+            status.maps_done = TARGETS.len() as u64;
+            status.phase = CompactionPhase::Done;
+            status.finished_at = Some(time());
+            // An upgrade during the compaction certified the events copied back by then only
+            certification::certify_all_events();
+            backup::end_compaction();
+        }
+        let done = status.phase == CompactionPhase::Done;
+        save(status);
+        if !done {
+            schedule_step();
+        }
+    });
+}
+
+fn begin_target(target: usize, started_at: u64) -> CompactionStatus {
+    CompactionStatus {
+        map: TARGETS[target].to_string(),
+        phase: CompactionPhase::CopyOut,
+        copied: 0,
+        total: 0,
+        maps_done: target as u64,
+        maps_total: TARGETS.len() as u64,
+        started_at,
+        finished_at: None,
+        cursor: None,
+    }
+}
+
+// Copy a batch of the current map, stored in the given memory, returning whether it is rebuilt
+fn step<V: BoundedStorable>(
+    live: &'static LocalKey<RefCell<StableBTreeMap<u64, V, Memory>>>,
+    memory_id: u8,
+    status: &mut CompactionStatus,
+) -> bool {
+    match status.phase {
+        CompactionPhase::CopyOut => {
+            let mut scratch = match status.cursor {
+                None => {
+                    status.total = live.with(|map| map.borrow().len());
+                    StableBTreeMap::new(memory(SCRATCH_MEMORY_ID))
+                }
+                Some(_) => StableBTreeMap::load(memory(SCRATCH_MEMORY_ID)),
+            };
+            let copied = live.with(|map| copy_batch(&map.borrow(), &mut scratch, status));
+
+            // Once copied out, the map starts over empty in its own memory
+            if copied < BATCH {
+                live.with(|map| *map.borrow_mut() = StableBTreeMap::new(memory(memory_id)));
+                status.phase = CompactionPhase::CopyBack;
+                status.copied = 0;
+                status.cursor = None;
+            }
+            false
+        }
+        CompactionPhase::CopyBack => {
+            let scratch = StableBTreeMap::load(memory(SCRATCH_MEMORY_ID));
+            let copied = live.with(|map| copy_batch(&scratch, &mut map.borrow_mut(), status));
+            copied < BATCH
+        }
+        CompactionPhase::Done => true,
+    }
+}
+
+// Copy the entries after the cursor, up to a batch, returning how many were copied
+fn copy_batch<V: BoundedStorable>(
+    from: &StableBTreeMap<u64, V, Memory>,
+    to: &mut StableBTreeMap<u64, V, Memory>,
+    status: &mut CompactionStatus,
+) -> u64 {
+    let entries: Vec<(u64, V)> = match status.cursor {
+        Some(cursor) => from
+            .range((Bound::Excluded(cursor), Bound::Unbounded))
+            .take(BATCH as usize)
+            .collect(),
+        None => from.iter().take(BATCH as usize).collect(),
+    };
+    let copied = entries.len() as u64;
+    for (key, value) in entries {
+        to.insert(key, value);
+        status.cursor = Some(key);
+    }
+    status.copied += copied;
+    copied
+}
+
+fn memory(memory_id: u8) -> Memory {
+    MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(memory_id)))
+}
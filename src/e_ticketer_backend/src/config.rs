@@ -0,0 +1,161 @@
+use crate::clock::time;
+use crate::{caller_is_admin, webhooks, Error, Memory, MEMORY_MANAGER};
+use candid::{Decode, Encode, Principal};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{Cell, Storable};
+use std::{borrow::Cow, cell::RefCell};
+
+const NANOS_PER_SECOND: u64 = 1_000_000_000;
+const MIN_RESERVATION_TTL_SECS: u64 = 60;
+const MAX_RESERVATION_TTL_SECS: u64 = 24 * 60 * 60;
+// The IC turns down ingress messages above 2 MiB anyway
+const MAX_ARG_BYTES: u64 = 2 * 1024 * 1024;
+const MIN_ARG_BYTES: u64 = 1024;
+// Larger batches would run into the instruction limit
+const MAX_BATCH_ITEMS: u64 = 500;
+
+// Define a struct for the operational parameters of the platform, changed by admins without an
+// upgrade
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct Config {
+    // Withheld from donations of events that pass fees on, in basis points
+    platform_fee_bps: u64,
+    // How long a reservation holds its place before it must be confirmed
+    reservation_ttl_secs: u64,
+    // Largest argument an update call from a client may carry
+    max_arg_bytes: u64,
+    // Items accepted in one call of the bulk endpoints
+    max_batch_items: u64,
+    // Turns down every update call but the admins', e.g. while an incident is looked into
+    read_only: bool,
+    // The only ledger events can take payment through, None allows any
+    ledger: Option<Principal>,
+    // Webhook deliveries are posted here with the webhook's URL in the 'X-Forward-To' header,
+    // None posts them to the webhooks directly
+    webhook_relay_url: Option<String>,
+    updated_at: Option<u64>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            platform_fee_bps: 250,
+            reservation_ttl_secs: 10 * 60,
+            max_arg_bytes: MAX_ARG_BYTES,
+            max_batch_items: 200,
+            read_only: false,
+            ledger: None,
+            webhook_relay_url: None,
+            updated_at: None,
+        }
+    }
+}
+
+impl Storable for Config {
+    // Conversion to bytes
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    // Conversion from bytes
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+thread_local! {
+    static CONFIG: RefCell<Cell<Config, Memory>> = RefCell::new(
+        Cell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(137))),
+            Config::default(),
+        )
+        .expect("Cannot create the platform config")
+    );
+}
+
+#[ic_cdk::query(guard = "caller_is_admin")]
+fn get_config() -> Config {
+    config()
+}
+
+#[ic_cdk::update(guard = "caller_is_admin")]
+fn update_config(mut config: Config) -> Result<Config, Error> {
+    validate_config(&config).map_err(|msg| Error::ValidationFailed { msg })?;
+    config.updated_at = Some(time());
+    CONFIG
+        .with(|cell| cell.borrow_mut().set(config.clone()))
+        .map_err(|_| Error::InternalError {
+            msg: "platform config could not be saved".to_string(),
+            ticket_id: None,
+        })?;
+    Ok(config)
+}
+
+pub(crate) fn platform_fee_bps() -> u64 {
+    config().platform_fee_bps
+}
+
+pub(crate) fn reservation_ttl() -> u64 {
+    config().reservation_ttl_secs * NANOS_PER_SECOND
+}
+
+pub(crate) fn max_arg_bytes() -> usize {
+    config().max_arg_bytes as usize
+}
+
+pub(crate) fn max_batch_items() -> usize {
+    config().max_batch_items as usize
+}
+
+pub(crate) fn is_read_only() -> bool {
+    config().read_only
+}
+
+// Check a ledger events are to take payment through is the platform's, if it names one
+pub(crate) fn check_ledger(ledger: Principal) -> Result<(), String> {
+    match config().ledger {
+        Some(platform_ledger) if platform_ledger != ledger => Err(format!(
+            "payments are only taken through ledger {}",
+            platform_ledger
+        )),
+        _ => Ok(()),
+    }
+}
+
+pub(crate) fn webhook_relay_url() -> Option<String> {
+    config().webhook_relay_url
+}
+
+fn config() -> Config {
+    CONFIG.with(|cell| cell.borrow().get().clone())
+}
+
+fn validate_config(config: &Config) -> Result<(), String> {
+    if config.platform_fee_bps > 10_000 {
+        return Err("platform fee can't be more than 10000 basis points".to_string());
+    }
+    if !(MIN_RESERVATION_TTL_SECS..=MAX_RESERVATION_TTL_SECS).contains(&config.reservation_ttl_secs)
+    {
+        return Err(format!(
+            "reservations must be held for {} to {} seconds",
+            MIN_RESERVATION_TTL_SECS, MAX_RESERVATION_TTL_SECS
+        ));
+    }
+    if !(MIN_ARG_BYTES..=MAX_ARG_BYTES).contains(&config.max_arg_bytes) {
+        return Err(format!(
+            "the largest argument must be {} to {} bytes",
+            MIN_ARG_BYTES, MAX_ARG_BYTES
+        ));
+    }
+    if !(1..=MAX_BATCH_ITEMS).contains(&config.max_batch_items) {
+        return Err(format!("batches must allow 1 to {} items", MAX_BATCH_ITEMS));
+    }
+    if let Some(ledger) = config.ledger {
+        if ledger == Principal::anonymous() || ledger == ic_cdk::id() {
+            return Err(format!("{} is not a ledger", ledger));
+        }
+    }
+    if let Some(url) = &config.webhook_relay_url {
+        webhooks::validate_url(url)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,409 @@
+use crate::clock::time;
+use crate::ratelimit::rate_limit;
+use crate::{
+    _get_stored_user, _get_user, backup, caller_is_admin, entropy, store_user, Error, Memory,
+    StringKey, MEMORY_MANAGER, USER_STORAGE,
+};
+use candid::{Decode, Encode};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, Cell, StableBTreeMap, Storable};
+use sha2::{Digest, Sha256};
+use std::time::Duration;
+use std::{borrow::Cow, cell::RefCell};
+
+// PBKDF2 rounds for new hashes, stored along with each hash so it can be raised later
+const PBKDF2_ROUNDS: u32 = 100_000;
+const HASH_LEN: usize = 32;
+const SALT_LEN: usize = 16;
+
+const MIN_PASSWORD_LEN: usize = 8;
+const MAX_PASSWORD_LEN: usize = 128;
+
+// Sessions returned by 'login' stay valid for a day
+const SESSION_TTL: u64 = 24 * 60 * 60 * 1_000_000_000;
+
+// Plaintext passwords hashed per timer tick, hashing is too expensive to migrate all users at once
+const MIGRATION_BATCH: usize = 5;
+
+// Define a struct for the salted password hash of a user
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct Credential {
+    rounds: u32,
+    salt: Vec<u8>,
+    hash: Vec<u8>,
+    updated_at: u64,
+}
+
+// Define a struct for the session handed out on login
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct SessionToken {
+    token: String,
+    user_id: u64,
+    expires_at: u64,
+}
+
+// Define a struct for how recently callers must have logged in to make sensitive calls. The
+// replica checks Internet Identity delegations at ingress without passing on when they expire, so
+// a recent 'login' from the calling principal is what proves a recent authentication.
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub struct ReauthPolicy {
+    // Logins older than this don't count, None lets any signed-in caller make sensitive calls
+    max_login_age_secs: Option<u64>,
+    // Moving a ticket priced above this to another holder is sensitive
+    transfer_threshold: u64,
+}
+
+// Users as stored before their passwords moved out, only read to migrate them
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct LegacyUser {
+    password: Option<String>,
+}
+
+impl Storable for Credential {
+    // Conversion to bytes
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    // Conversion from bytes
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl Storable for SessionToken {
+    // Conversion to bytes
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    // Conversion from bytes
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl Storable for ReauthPolicy {
+    // Conversion to bytes
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    // Conversion from bytes
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl Storable for LegacyUser {
+    // Conversion to bytes
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    // Conversion from bytes
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for Credential {
+    const MAX_SIZE: u32 = 256;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl BoundedStorable for SessionToken {
+    const MAX_SIZE: u32 = 256;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Must not exceed the bound of 'User', as both read the same memory
+impl BoundedStorable for LegacyUser {
+    const MAX_SIZE: u32 = 1024;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    // Password hashes keyed by user id
+    static CREDENTIALS: RefCell<StableBTreeMap<u64, Credential, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(26)))
+    ));
+
+    // Sessions keyed by token
+    static SESSIONS: RefCell<StableBTreeMap<StringKey, SessionToken, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(27)))
+    ));
+
+    // Time of the last login keyed by the principal it came from
+    static LOGIN_TIMES: RefCell<StableBTreeMap<StringKey, u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(151)))
+    ));
+
+    static REAUTH_POLICY: RefCell<Cell<ReauthPolicy, Memory>> = RefCell::new(
+        Cell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(152))),
+            ReauthPolicy::default(),
+        )
+        .expect("Cannot create the reauthentication policy")
+    );
+}
+
+#[ic_cdk::update(guard = "rate_limit")]
+fn login(email: String, password: String) -> Result<SessionToken, Error> {
+    // Several users can share an email, the password tells them apart
+    let user_ids: Vec<u64> = USER_STORAGE.with(|users| {
+        users
+            .borrow()
+            .iter()
+            .filter(|(_, user)| user.email == email && user.deleted_at.is_none())
+            .map(|(id, _)| id)
+            .collect()
+    });
+    let user_id = user_ids
+        .into_iter()
+        .find(|user_id| verify_password(*user_id, &password))
+        .ok_or(Error::Unauthorized {
+            msg: "invalid email or password".to_string(),
+        })?;
+
+    remove_expired_sessions();
+    LOGIN_TIMES.with(|logins| {
+        logins
+            .borrow_mut()
+            .insert(StringKey(ic_cdk::caller().to_text()), time())
+    });
+
+    let session = SessionToken {
+        token: entropy::random_token(b"session", user_id),
+        user_id,
+        expires_at: time() + SESSION_TTL,
+    };
+    SESSIONS.with(|sessions| {
+        sessions
+            .borrow_mut()
+            .insert(StringKey(session.token.clone()), session.clone())
+    });
+
+    Ok(session)
+}
+
+// User a session token was handed out to, while it is valid
+pub(crate) fn session_user_id(token: &str) -> Option<u64> {
+    SESSIONS
+        .with(|sessions| sessions.borrow().get(&StringKey(token.to_string())))
+        .filter(|session| session.expires_at > time())
+        .map(|session| session.user_id)
+}
+
+#[ic_cdk::update(guard = "rate_limit")]
+fn logout(token: String) -> Result<String, Error> {
+    match SESSIONS.with(|sessions| sessions.borrow_mut().remove(&StringKey(token))) {
+        Some(session) => Ok(format!("session of user id: {} ended", session.user_id)),
+        None => Err(Error::NotFound {
+            entity: "session".to_string(),
+            id: String::new(),
+            msg: "session does not exist".to_string(),
+        }),
+    }
+}
+
+#[ic_cdk::update(guard = "rate_limit")]
+fn change_password(
+    user_id: u64,
+    current_password: String,
+    new_password: String,
+) -> Result<String, Error> {
+    // Make sure the user exists, or return a NotFound error if not found
+    _get_user(&user_id).ok_or(Error::not_found("user", user_id))?;
+
+    if !verify_password(user_id, &current_password) {
+        return Err(Error::Unauthorized {
+            msg: "current password is incorrect".to_string(),
+        });
+    }
+    validate_password(&new_password).map_err(|msg| Error::ValidationFailed { msg })?;
+    store_password(user_id, &new_password);
+
+    // Sessions opened with the old password are no longer trusted
+    remove_user_sessions(user_id);
+
+    Ok(format!("password of user id: {} changed", user_id))
+}
+
+#[ic_cdk::query]
+fn get_reauth_policy() -> ReauthPolicy {
+    REAUTH_POLICY.with(|policy| policy.borrow().get().clone())
+}
+
+#[ic_cdk::update(guard = "caller_is_admin")]
+fn set_reauth_policy(policy: ReauthPolicy) -> Result<ReauthPolicy, Error> {
+    if policy.max_login_age_secs == Some(0) {
+        return Err(Error::ValidationFailed {
+            msg: "logins must count for at least a second".to_string(),
+        });
+    }
+    REAUTH_POLICY
+        .with(|cell| cell.borrow_mut().set(policy.clone()))
+        .expect("Cannot store the reauthentication policy");
+    Ok(policy)
+}
+
+// Check the caller logged in recently enough for a sensitive call, if the policy asks for it.
+// Admins and the canister itself are trusted without.
+pub(crate) fn check_recent_login() -> Result<(), Error> {
+    let caller = ic_cdk::caller();
+    let Some(max_age_secs) = get_reauth_policy().max_login_age_secs else {
+        return Ok(());
+    };
+    if caller == ic_cdk::id() || ic_cdk::api::is_controller(&caller) {
+        return Ok(());
+    }
+    let logged_in_at = LOGIN_TIMES.with(|logins| logins.borrow().get(&StringKey(caller.to_text())));
+    let cutoff = time().saturating_sub(max_age_secs.saturating_mul(1_000_000_000));
+    match logged_in_at {
+        Some(logged_in_at) if logged_in_at >= cutoff => Ok(()),
+        _ => Err(Error::Unauthorized {
+            msg: format!(
+                "this call needs a login within the last {} seconds, call 'login' again",
+                max_age_secs
+            ),
+        }),
+    }
+}
+
+// Check the caller may move a ticket of the given price to another holder
+pub(crate) fn check_transfer(price: u64) -> Result<(), Error> {
+    if price > get_reauth_policy().transfer_threshold {
+        check_recent_login()?;
+    }
+    Ok(())
+}
+
+// Check that a password can be used for a new or changed credential
+pub(crate) fn validate_password(password: &str) -> Result<(), String> {
+    if password.len() < MIN_PASSWORD_LEN || password.len() > MAX_PASSWORD_LEN {
+        return Err(format!(
+            "password must be between {} and {} bytes",
+            MIN_PASSWORD_LEN, MAX_PASSWORD_LEN
+        ));
+    }
+    Ok(())
+}
+
+// Hash a password with a fresh salt and store it as the credential of a user
+pub(crate) fn store_password(user_id: u64, password: &str) {
+    let mut hasher = entropy::seeded_hasher();
+    hasher.update(b"salt");
+    hasher.update(user_id.to_be_bytes());
+    let salt = hasher.finalize()[..SALT_LEN].to_vec();
+
+    let credential = Credential {
+        rounds: PBKDF2_ROUNDS,
+        hash: hash_password(password, &salt, PBKDF2_ROUNDS),
+        salt,
+        updated_at: time(),
+    };
+    CREDENTIALS.with(|credentials| credentials.borrow_mut().insert(user_id, credential));
+}
+
+// Check a password against the credential of a user, migrating a plaintext one on the way
+fn verify_password(user_id: u64, password: &str) -> bool {
+    if let Some(credential) = CREDENTIALS.with(|credentials| credentials.borrow().get(&user_id)) {
+        let hash = hash_password(password, &credential.salt, credential.rounds);
+        return constant_time_eq(&hash, &credential.hash);
+    }
+
+    // Users not reached by the migration yet still have their password in plaintext
+    let Some(stored) = legacy_password(user_id) else {
+        return false;
+    };
+    if !constant_time_eq(stored.as_bytes(), password.as_bytes()) {
+        return false;
+    }
+    migrate_user(user_id, &stored);
+    true
+}
+
+fn hash_password(password: &str, salt: &[u8], rounds: u32) -> Vec<u8> {
+    let mut hash = vec![0; HASH_LEN];
+    pbkdf2::pbkdf2_hmac::<Sha256>(password.as_bytes(), salt, rounds, &mut hash);
+    hash
+}
+
+// Compare without returning early, so the time taken doesn't leak how much matched
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+fn remove_expired_sessions() {
+    let now = time();
+    SESSIONS.with(|sessions| {
+        let mut sessions = sessions.borrow_mut();
+        let expired: Vec<StringKey> = sessions
+            .iter()
+            .filter(|(_, session)| session.expires_at <= now)
+            .map(|(token, _)| token)
+            .collect();
+        for token in expired {
+            sessions.remove(&token);
+        }
+    });
+}
+
+pub(crate) fn remove_user_sessions(user_id: u64) {
+    SESSIONS.with(|sessions| {
+        let mut sessions = sessions.borrow_mut();
+        let tokens: Vec<StringKey> = sessions
+            .iter()
+            .filter(|(_, session)| session.user_id == user_id)
+            .map(|(token, _)| token)
+            .collect();
+        for token in tokens {
+            sessions.remove(&token);
+        }
+    });
+}
+
+// Drop the credential and sessions of a deleted user
+pub(crate) fn remove_user_credentials(user_id: u64) {
+    CREDENTIALS.with(|credentials| credentials.borrow_mut().remove(&user_id));
+    remove_user_sessions(user_id);
+}
+
+// View of the user storage that still sees the plaintext passwords of records written before
+fn legacy_users() -> StableBTreeMap<u64, LegacyUser, Memory> {
+    StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(2))))
+}
+
+fn legacy_password(user_id: u64) -> Option<String> {
+    legacy_users().get(&user_id)?.password
+}
+
+// Hash a plaintext password and rewrite the user without it
+fn migrate_user(user_id: u64, password: &str) {
+    store_password(user_id, password);
+    // Users in the trash are rewritten too, so no plaintext is left behind
+    if let Some(mut user) = _get_stored_user(&user_id) {
+        store_user(&mut user);
+    }
+}
+
+// Hash the plaintext passwords left from before, a batch per timer tick
+pub(crate) fn schedule_migration() {
+    ic_cdk_timers::set_timer(Duration::ZERO, || {
+        // Picked up again on the next upgrade, and by 'login' in the meantime
+        if backup::in_maintenance() {
+            return;
+        }
+        let batch: Vec<(u64, String)> = legacy_users()
+            .iter()
+            .filter_map(|(user_id, user)| user.password.map(|password| (user_id, password)))
+            .take(MIGRATION_BATCH + 1)
+            .collect();
+        for (user_id, password) in batch.iter().take(MIGRATION_BATCH) {
+            migrate_user(*user_id, password);
+        }
+        if batch.len() > MIGRATION_BATCH {
+            schedule_migration();
+        }
+    });
+}
@@ -0,0 +1,281 @@
+use crate::clock::time;
+use crate::ratelimit::rate_limit;
+use crate::{_get_user, organizers, Error, Memory, ID_COUNTER, MEMORY_MANAGER};
+use candid::{Decode, Encode};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use std::{borrow::Cow, cell::RefCell};
+
+// Entries returned with a balance, the latest first
+const MAX_CREDIT_ENTRIES: usize = 100;
+
+// Define an enum for where an event's refunds go
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default, PartialEq)]
+pub enum RefundPolicy {
+    // Where each buyer prefers, back over the ledger unless they asked for credit
+    #[default]
+    BuyerChoice,
+    CreditOnly,
+    LedgerOnly,
+}
+
+// Define an enum for where a refund is paid to
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, Default, PartialEq)]
+pub enum RefundTarget {
+    #[default]
+    Ledger,
+    // Kept as platform credit, spent at the buyer's next checkouts
+    Credit,
+}
+
+// Define an enum for why a credit balance changed
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub enum CreditReason {
+    Refund {
+        event_id: u64,
+        ticket_id: Option<u64>,
+    },
+    // Spent on a purchase, and given back if the purchase didn't go through
+    Purchase,
+    PurchaseFailed,
+}
+
+// Define a struct for an entry of the credit ledger. Amounts are in the unit tickets are
+// priced in.
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct CreditEntry {
+    id: u64,
+    user_id: u64,
+    credited: u64,
+    debited: u64,
+    // Balance after the entry
+    balance: u64,
+    reason: CreditReason,
+    created_at: u64,
+}
+
+// Define a struct for the credit of a user with its latest entries
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub struct CreditBalance {
+    user_id: u64,
+    balance: u64,
+    refund_preference: RefundTarget,
+    entries: Vec<CreditEntry>,
+}
+
+impl Storable for RefundPolicy {
+    // Conversion to bytes
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    // Conversion from bytes
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for RefundPolicy {
+    const MAX_SIZE: u32 = 32;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl Storable for RefundTarget {
+    // Conversion to bytes
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    // Conversion from bytes
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for RefundTarget {
+    const MAX_SIZE: u32 = 32;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl Storable for CreditEntry {
+    // Conversion to bytes
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    // Conversion from bytes
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for CreditEntry {
+    const MAX_SIZE: u32 = 256;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    static BALANCE_STORAGE: RefCell<StableBTreeMap<u64, u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(78)))
+    ));
+
+    // Entries keyed by (user id, entry id)
+    static ENTRY_STORAGE: RefCell<StableBTreeMap<(u64, u64), CreditEntry, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(79)))
+    ));
+
+    static POLICY_STORAGE: RefCell<StableBTreeMap<u64, RefundPolicy, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(80)))
+    ));
+
+    static PREFERENCE_STORAGE: RefCell<StableBTreeMap<u64, RefundTarget, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(81)))
+    ));
+}
+
+#[ic_cdk::query]
+pub(crate) fn get_user_credit(user_id: u64) -> Result<CreditBalance, Error> {
+    _get_user(&user_id).ok_or(Error::not_found("user", user_id))?;
+    let mut entries: Vec<CreditEntry> = ENTRY_STORAGE.with(|entries| {
+        entries
+            .borrow()
+            .range((user_id, 0)..=(user_id, u64::MAX))
+            .map(|(_, entry)| entry)
+            .collect()
+    });
+    entries.reverse();
+    entries.truncate(MAX_CREDIT_ENTRIES);
+    Ok(CreditBalance {
+        user_id,
+        balance: balance(user_id),
+        refund_preference: refund_preference(user_id),
+        entries,
+    })
+}
+
+#[ic_cdk::update(guard = "rate_limit")]
+fn set_refund_preference(user_id: u64, target: RefundTarget) -> Result<RefundTarget, Error> {
+    _get_user(&user_id).ok_or(Error::not_found("user", user_id))?;
+    PREFERENCE_STORAGE.with(|preferences| preferences.borrow_mut().insert(user_id, target));
+    Ok(target)
+}
+
+#[ic_cdk::query]
+fn get_refund_policy(event_id: u64) -> RefundPolicy {
+    refund_policy(event_id)
+}
+
+#[ic_cdk::update(guard = "rate_limit")]
+fn set_refund_policy(event_id: u64, policy: RefundPolicy) -> Result<RefundPolicy, Error> {
+    organizers::authorize_event(event_id)?;
+    match policy {
+        RefundPolicy::BuyerChoice => {
+            POLICY_STORAGE.with(|policies| policies.borrow_mut().remove(&event_id));
+        }
+        _ => {
+            POLICY_STORAGE.with(|policies| policies.borrow_mut().insert(event_id, policy.clone()));
+        }
+    }
+    Ok(policy)
+}
+
+fn refund_policy(event_id: u64) -> RefundPolicy {
+    POLICY_STORAGE
+        .with(|policies| policies.borrow().get(&event_id))
+        .unwrap_or_default()
+}
+
+fn refund_preference(user_id: u64) -> RefundTarget {
+    PREFERENCE_STORAGE
+        .with(|preferences| preferences.borrow().get(&user_id))
+        .unwrap_or_default()
+}
+
+// Where a refund of an event's ticket goes, the organizer's policy before the buyer's wish
+pub(crate) fn refund_target(
+    event_id: u64,
+    user_id: u64,
+    requested: Option<RefundTarget>,
+) -> RefundTarget {
+    match refund_policy(event_id) {
+        RefundPolicy::CreditOnly => RefundTarget::Credit,
+        RefundPolicy::LedgerOnly => RefundTarget::Ledger,
+        RefundPolicy::BuyerChoice => requested.unwrap_or_else(|| refund_preference(user_id)),
+    }
+}
+
+pub(crate) fn balance(user_id: u64) -> u64 {
+    BALANCE_STORAGE
+        .with(|balances| balances.borrow().get(&user_id))
+        .unwrap_or(0)
+}
+
+// Add to a user's credit
+pub(crate) fn credit(user_id: u64, amount: u64, reason: CreditReason) {
+    if amount > 0 {
+        book(user_id, amount, 0, reason);
+    }
+}
+
+// Take from a user's credit, all of the amount or nothing
+pub(crate) fn debit(user_id: u64, amount: u64, reason: CreditReason) -> Result<(), String> {
+    if amount == 0 {
+        return Ok(());
+    }
+    if balance(user_id) < amount {
+        return Err(format!(
+            "user id:{} has {} in credit, not the {} to spend",
+            user_id,
+            balance(user_id),
+            amount
+        ));
+    }
+    book(user_id, 0, amount, reason);
+    Ok(())
+}
+
+fn book(user_id: u64, credited: u64, debited: u64, reason: CreditReason) {
+    let balance = balance(user_id) + credited - debited;
+    // Increment the global ID counter to get a new ID for the entry
+    let id = ID_COUNTER
+        .with(|counter| {
+            let current_id = *counter.borrow().get();
+            counter.borrow_mut().set(current_id + 1)
+        })
+        .expect("Cannot increment Ids");
+    let entry = CreditEntry {
+        id,
+        user_id,
+        credited,
+        debited,
+        balance,
+        reason,
+        created_at: time(),
+    };
+    BALANCE_STORAGE.with(|balances| balances.borrow_mut().insert(user_id, balance));
+    ENTRY_STORAGE.with(|entries| entries.borrow_mut().insert((user_id, id), entry));
+}
+
+pub(crate) fn remove_event_refund_policy(event_id: u64) {
+    POLICY_STORAGE.with(|policies| policies.borrow_mut().remove(&event_id));
+}
+
+// Purged users lose their credit along with their entries
+pub(crate) fn remove_user_credit(user_id: u64) {
+    BALANCE_STORAGE.with(|balances| balances.borrow_mut().remove(&user_id));
+    PREFERENCE_STORAGE.with(|preferences| preferences.borrow_mut().remove(&user_id));
+    let keys: Vec<(u64, u64)> = ENTRY_STORAGE.with(|entries| {
+        entries
+            .borrow()
+            .range((user_id, 0)..=(user_id, u64::MAX))
+            .map(|(key, _)| key)
+            .collect()
+    });
+    ENTRY_STORAGE.with(|entries| {
+        let mut entries = entries.borrow_mut();
+        for key in keys {
+            entries.remove(&key);
+        }
+    });
+}
@@ -0,0 +1,575 @@
+use crate::announcements::{self, AnnouncementStage};
+use crate::geo::AccessibilityFeature;
+use crate::{
+    _get_listed_event, _get_public_events, backup, dates, geo, organizers, remaining_capacity,
+    tiers, Error, Event, Memory, StringKey, EVENT_STORAGE, MEMORY_MANAGER,
+};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::time::Duration;
+
+// Tags are kept small, as they are stored within the size bound of an event
+const MAX_TAGS: usize = 5;
+const MAX_TAG_LEN: usize = 20;
+
+const PAGE_SIZE: usize = 20;
+const POPULAR_TAGS: usize = 20;
+
+// Suggestions returned for a prefix, enough to fill a search box dropdown
+const MAX_SUGGESTIONS: usize = 10;
+// Longer words of a name aren't indexed, nobody types that much before picking a suggestion
+const MAX_WORD_LEN: usize = 40;
+// Events indexed per timer tick when the name index is filled in after an upgrade
+const INDEXING_BATCH: usize = 200;
+
+// Define an enum for the category an event is listed under
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq)]
+pub enum EventCategory {
+    Music,
+    Sports,
+    Arts,
+    Theatre,
+    Comedy,
+    Conference,
+    Workshop,
+    Festival,
+    Community,
+    Other,
+}
+
+// Define an enum for where an event stands, as filtered on by 'query_events'
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq)]
+pub enum EventStatus {
+    // Published with some details still to be announced, not on sale yet
+    Upcoming,
+    OnSale,
+    SoldOut,
+    Completed,
+}
+
+// Define an enum for the order events are returned in
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, Default)]
+pub enum EventSort {
+    // Soonest first, events without a final date last
+    #[default]
+    Date,
+    // Most recently created first
+    Newest,
+    // Cheapest first, by the lowest price a ticket sells at
+    Price,
+    Name,
+}
+
+// Define a struct for the criteria of 'query_events', all given ones must match
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub struct EventFilter {
+    status: Option<EventStatus>,
+    category: Option<EventCategory>,
+    tag: Option<String>,
+    // Inclusive YYYY-MM-DD bounds, events without a final date never match
+    date_from: Option<String>,
+    date_to: Option<String>,
+    // Inclusive bounds on the lowest price a ticket sells at
+    min_price: Option<u64>,
+    max_price: Option<u64>,
+    // Matched case-insensitively against the name, description and venue
+    text: Option<String>,
+    organizer: Option<u64>,
+    // Every feature must be offered by the event or its venue
+    accessibility: Option<Vec<AccessibilityFeature>>,
+    // Matched case-insensitively against the venue's name and address
+    location: Option<String>,
+}
+
+// Define a struct for a page of events matching a discovery filter
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub struct EventPage {
+    events: Vec<Event>,
+    page: u64,
+    // Number of matching events over all pages
+    total: u64,
+}
+
+// Define a struct for an event as listed by 'list_events', without the attendee and ticket ids
+// that make up most of a full event
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub struct EventListing {
+    id: u64,
+    name: String,
+    // Left out until the date is announced
+    date: Option<String>,
+    start_time: String,
+    venue: Option<String>,
+    category: Option<EventCategory>,
+    tags: Vec<String>,
+    status: EventStatus,
+    // Lowest price a ticket sells at publicly
+    price_from: u64,
+    // None for events of unlimited capacity
+    tickets_left: Option<u64>,
+}
+
+// Define a struct for a page of event listings
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub struct EventListingPage {
+    events: Vec<EventListing>,
+    page: u64,
+    // Number of matching events over all pages
+    total: u64,
+}
+
+// Define a struct for an event suggested while a search is typed, just enough to show it
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub struct EventSuggestion {
+    id: u64,
+    name: String,
+    // Left out until the date is announced
+    date: Option<String>,
+}
+
+// Define a struct for a tag along with the number of events using it
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub struct TagCount {
+    tag: String,
+    events: u64,
+}
+
+thread_local! {
+    // Events keyed by (tag, event id)
+    static TAG_INDEX: RefCell<StableBTreeMap<(StringKey, u64), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(33)))
+    ));
+
+    // Events keyed by (category, event id)
+    static CATEGORY_INDEX: RefCell<StableBTreeMap<(u8, u64), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(34)))
+    ));
+
+    // Events keyed by (lowercase word of their name, event id)
+    static NAME_INDEX: RefCell<StableBTreeMap<(StringKey, u64), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(108)))
+    ));
+}
+
+#[ic_cdk::query]
+fn query_events(
+    filter: EventFilter,
+    sort: Option<EventSort>,
+    page: u64,
+) -> Result<EventPage, Error> {
+    let mut events = matching_events(&filter)?;
+    sort_events(&mut events, sort.unwrap_or_default());
+    Ok(page_of(events, page))
+}
+
+// Listing of the events matching a filter, a light alternative to 'get_all_events' that leaves
+// out the attendee and ticket ids growing with the crowd
+#[ic_cdk::query]
+fn list_events(
+    filter: EventFilter,
+    sort: Option<EventSort>,
+    page: u64,
+) -> Result<EventListingPage, Error> {
+    let mut events = matching_events(&filter)?;
+    sort_events(&mut events, sort.unwrap_or_default());
+    let EventPage {
+        events,
+        page,
+        total,
+    } = page_of(events, page);
+    Ok(EventListingPage {
+        events: events.iter().map(listing).collect(),
+        page,
+        total,
+    })
+}
+
+// The public events matching every criterion of a filter, in id order
+pub(crate) fn matching_events(filter: &EventFilter) -> Result<Vec<Event>, Error> {
+    let date_from = filter_date(&filter.date_from)?;
+    let date_to = filter_date(&filter.date_to)?;
+    let text = filter.text.as_ref().map(|text| text.trim().to_lowercase());
+    let location = filter
+        .location
+        .as_ref()
+        .map(|location| location.trim().to_lowercase());
+
+    // Drive the scan from the narrowest index the filter allows, or every event without one
+    let candidates = [
+        filter.tag.as_ref().map(|tag| tag_event_ids(tag)),
+        filter.category.map(category_event_ids),
+        filter.organizer.map(organizers::organizer_event_ids),
+    ];
+    let mut events: Vec<Event> = match candidates.into_iter().flatten().min_by_key(Vec::len) {
+        Some(event_ids) => event_ids.iter().filter_map(_get_listed_event).collect(),
+        None => _get_public_events(),
+    };
+
+    events.retain(|event| {
+        let date = final_date(event);
+        let price = lowest_price(event);
+        filter
+            .status
+            .is_none_or(|status| event_status(event) == status)
+            && filter
+                .category
+                .is_none_or(|category| event.category == Some(category))
+            && filter.tag.as_ref().is_none_or(|tag| {
+                let tag = normalize_tag(tag);
+                event
+                    .tags
+                    .iter()
+                    .flatten()
+                    .any(|event_tag| *event_tag == tag)
+            })
+            && date_from.is_none_or(|from| date.is_some_and(|date| date >= from))
+            && date_to.is_none_or(|to| date.is_some_and(|date| date <= to))
+            && filter.min_price.is_none_or(|min| price >= min)
+            && filter.max_price.is_none_or(|max| price <= max)
+            && text.as_ref().is_none_or(|text| matches_text(event, text))
+            && filter.organizer.is_none_or(|organizer_id| {
+                event
+                    .organizer_ids
+                    .iter()
+                    .flatten()
+                    .any(|id| *id == organizer_id)
+            })
+            && filter.accessibility.as_ref().is_none_or(|needs| {
+                let offered = geo::event_accessibility(event);
+                needs.iter().all(|need| offered.contains(need))
+            })
+            && location.as_ref().is_none_or(|location| {
+                event.venue.as_ref().is_some_and(|venue| {
+                    geo::venue_label(Some(venue))
+                        .to_lowercase()
+                        .contains(location)
+                })
+            })
+    });
+
+    Ok(events)
+}
+
+fn sort_events(events: &mut [Event], sort: EventSort) {
+    match sort {
+        EventSort::Date => events.sort_by_key(|event| {
+            let date = final_date(event);
+            (date.is_none(), date, dates::parse_time(&event.start_time))
+        }),
+        EventSort::Newest => events.sort_by_key(|event| std::cmp::Reverse(event.created_at)),
+        EventSort::Price => events.sort_by_key(lowest_price),
+        EventSort::Name => events.sort_by_key(|event| event.name.to_lowercase()),
+    }
+}
+
+#[ic_cdk::query]
+fn get_events_by_category(category: EventCategory, page: u64) -> EventPage {
+    let event_ids = category_event_ids(category);
+    page_of(
+        event_ids.iter().filter_map(_get_listed_event).collect(),
+        page,
+    )
+}
+
+#[ic_cdk::query]
+fn get_events_by_tag(tag: String, page: u64) -> EventPage {
+    let event_ids = tag_event_ids(&tag);
+    page_of(
+        event_ids.iter().filter_map(_get_listed_event).collect(),
+        page,
+    )
+}
+
+#[ic_cdk::query]
+fn get_popular_tags() -> Vec<TagCount> {
+    // Only events everyone can see count towards a tag
+    let mut counts: HashMap<String, u64> = HashMap::new();
+    TAG_INDEX.with(|index| {
+        for ((tag, event_id), _) in index.borrow().iter() {
+            if _get_listed_event(&event_id).is_some() {
+                *counts.entry(tag.0).or_default() += 1;
+            }
+        }
+    });
+
+    let mut tags: Vec<TagCount> = counts
+        .into_iter()
+        .map(|(tag, events)| TagCount { tag, events })
+        .collect();
+    tags.sort_by(|a, b| b.events.cmp(&a.events).then_with(|| a.tag.cmp(&b.tag)));
+    tags.truncate(POPULAR_TAGS);
+    tags
+}
+
+// Suggest events as a search is typed. A single word matches the start of any word of an event's
+// name or of its tags, more words must follow each other in the name, the last one as typed so far.
+#[ic_cdk::query]
+fn suggest_events(prefix: String) -> Vec<EventSuggestion> {
+    let query = name_words(&prefix).join(" ");
+    let Some(first) = query.split(' ').next().filter(|word| !word.is_empty()) else {
+        return vec![];
+    };
+    if first.len() > MAX_WORD_LEN {
+        return vec![];
+    }
+
+    let mut seen: HashSet<u64> = HashSet::new();
+    let mut suggestions = vec![];
+    let candidates = NAME_INDEX
+        .with(|index| prefixed_event_ids(&index.borrow(), first))
+        .into_iter()
+        .filter_map(|event_id| _get_listed_event(&event_id))
+        .filter(|event| name_matches(event, &query));
+    // Tags hold a single word, so only a single word can match one
+    let tagged = TAG_INDEX
+        .with(|index| prefixed_event_ids(&index.borrow(), first))
+        .into_iter()
+        .filter(|_| !query.contains(' '))
+        .filter_map(|event_id| _get_listed_event(&event_id));
+    for event in candidates.chain(tagged) {
+        if suggestions.len() == MAX_SUGGESTIONS {
+            break;
+        }
+        if seen.insert(event.id) {
+            suggestions.push(EventSuggestion {
+                id: event.id,
+                date: final_date(&event).map(dates::format_date),
+                name: event.name,
+            });
+        }
+    }
+    suggestions
+}
+
+// Check the tags of an event and normalize them, so the same tag is always spelled the same
+pub(crate) fn validate_tags(tags: &[String]) -> Result<Vec<String>, String> {
+    if tags.len() > MAX_TAGS {
+        return Err(format!("an event can have at most {} tags", MAX_TAGS));
+    }
+
+    let mut tags: Vec<String> = tags.iter().map(|tag| normalize_tag(tag)).collect();
+    if let Some(tag) = tags.iter().find(|tag| {
+        tag.is_empty()
+            || tag.len() > MAX_TAG_LEN
+            || !tag.chars().all(|c| c.is_alphanumeric() || c == '-')
+    }) {
+        return Err(format!(
+            "tag '{}' must be 1 to {} bytes of letters, digits and dashes",
+            tag, MAX_TAG_LEN
+        ));
+    }
+    tags.sort();
+    tags.dedup();
+
+    Ok(tags)
+}
+
+// Add an event to the indexes under its category and tags
+pub(crate) fn index_event(event: &Event) {
+    if let Some(category) = event.category {
+        CATEGORY_INDEX.with(|index| index.borrow_mut().insert((category as u8, event.id), ()));
+    }
+    for tag in event.tags.iter().flatten() {
+        TAG_INDEX.with(|index| {
+            index
+                .borrow_mut()
+                .insert((StringKey(tag.clone()), event.id), ())
+        });
+    }
+    for word in indexed_words(&event.name) {
+        NAME_INDEX.with(|index| index.borrow_mut().insert((StringKey(word), event.id), ()));
+    }
+}
+
+// Remove an event from the indexes, as it was last indexed
+pub(crate) fn unindex_event(event: &Event) {
+    if let Some(category) = event.category {
+        CATEGORY_INDEX.with(|index| index.borrow_mut().remove(&(category as u8, event.id)));
+    }
+    for tag in event.tags.iter().flatten() {
+        TAG_INDEX.with(|index| {
+            index
+                .borrow_mut()
+                .remove(&(StringKey(tag.clone()), event.id))
+        });
+    }
+    for word in indexed_words(&event.name) {
+        NAME_INDEX.with(|index| index.borrow_mut().remove(&(StringKey(word), event.id)));
+    }
+}
+
+// Index the names of events stored before names were, a batch of events at a time from the
+// given id. Events already indexed are indexed again, which changes nothing.
+pub(crate) fn schedule_name_indexing(from: u64) {
+    ic_cdk_timers::set_timer(Duration::ZERO, move || {
+        // Picked up again on the next upgrade
+        if backup::in_maintenance() {
+            return;
+        }
+        let batch: Vec<Event> = EVENT_STORAGE.with(|events| {
+            events
+                .borrow()
+                .range(from..)
+                .map(|(_, event)| event)
+                .take(INDEXING_BATCH + 1)
+                .collect()
+        });
+        for event in batch.iter().take(INDEXING_BATCH) {
+            // Events in the trash are indexed again when restored
+            if event.deleted_at.is_none() {
+                for word in indexed_words(&event.name) {
+                    NAME_INDEX
+                        .with(|index| index.borrow_mut().insert((StringKey(word), event.id), ()));
+                }
+            }
+        }
+        if let Some(next) = batch.get(INDEXING_BATCH) {
+            schedule_name_indexing(next.id);
+        }
+    });
+}
+
+// Lowercase words of a name, split at anything but letters and digits
+fn name_words(name: &str) -> Vec<String> {
+    name.to_lowercase()
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+fn indexed_words(name: &str) -> HashSet<String> {
+    name_words(name)
+        .into_iter()
+        .filter(|word| word.len() <= MAX_WORD_LEN)
+        .collect()
+}
+
+// Whether the words typed so far start at one of the words of the event's name
+fn name_matches(event: &Event, query: &str) -> bool {
+    let name = name_words(&event.name).join(" ");
+    name.starts_with(query) || name.contains(&format!(" {}", query))
+}
+
+// Events under the keys of an index starting with the prefix, in key order
+fn prefixed_event_ids(
+    index: &StableBTreeMap<(StringKey, u64), (), Memory>,
+    prefix: &str,
+) -> Vec<u64> {
+    index
+        .range((StringKey(prefix.to_string()), 0)..)
+        .take_while(|((key, _), _)| key.0.starts_with(prefix))
+        .map(|((_, event_id), _)| event_id)
+        .collect()
+}
+
+fn normalize_tag(tag: &str) -> String {
+    tag.trim().to_lowercase()
+}
+
+fn category_event_ids(category: EventCategory) -> Vec<u64> {
+    CATEGORY_INDEX.with(|index| {
+        index
+            .borrow()
+            .range((category as u8, 0)..=(category as u8, u64::MAX))
+            .map(|((_, event_id), _)| event_id)
+            .collect()
+    })
+}
+
+fn tag_event_ids(tag: &str) -> Vec<u64> {
+    let tag = normalize_tag(tag);
+    if tag.len() > MAX_TAG_LEN {
+        return vec![];
+    }
+    TAG_INDEX.with(|index| {
+        index
+            .borrow()
+            .range((StringKey(tag.clone()), 0)..=(StringKey(tag), u64::MAX))
+            .map(|((_, event_id), _)| event_id)
+            .collect()
+    })
+}
+
+fn filter_date(date: &Option<String>) -> Result<Option<dates::Date>, Error> {
+    date.as_ref()
+        .map(|date| {
+            dates::parse_date(date).ok_or(Error::ValidationFailed {
+                msg: format!("date {} is not a YYYY-MM-DD date", date),
+            })
+        })
+        .transpose()
+}
+
+// Date of an event once announced, teasers have placeholder dates
+fn final_date(event: &Event) -> Option<dates::Date> {
+    dates::parse_date(&event.date).filter(|_| announcements::date_is_final(event))
+}
+
+fn event_status(event: &Event) -> EventStatus {
+    if event.completed_at.is_some() {
+        EventStatus::Completed
+    } else if announcements::stage(event) != AnnouncementStage::FullyAnnounced {
+        EventStatus::Upcoming
+    } else if remaining_capacity(event) == Some(0) {
+        EventStatus::SoldOut
+    } else {
+        EventStatus::OnSale
+    }
+}
+
+// Lowest price a ticket sells at publicly, free for events without tiers
+fn lowest_price(event: &Event) -> u64 {
+    tiers::_get_public_tiers(event.id)
+        .iter()
+        .map(|tier| tier.price)
+        .min()
+        .unwrap_or(0)
+}
+
+fn matches_text(event: &Event, text: &str) -> bool {
+    let venue = event
+        .venue
+        .as_ref()
+        .map(|venue| geo::venue_label(Some(venue)))
+        .unwrap_or_default();
+    [&event.name, &event.description, &venue]
+        .iter()
+        .any(|field| field.to_lowercase().contains(text))
+}
+
+fn listing(event: &Event) -> EventListing {
+    EventListing {
+        id: event.id,
+        name: event.name.clone(),
+        date: final_date(event).map(dates::format_date),
+        start_time: event.start_time.clone(),
+        venue: event
+            .venue
+            .as_ref()
+            .map(|venue| geo::venue_label(Some(venue))),
+        category: event.category,
+        tags: event.tags.clone().unwrap_or_default(),
+        status: event_status(event),
+        price_from: lowest_price(event),
+        tickets_left: remaining_capacity(event),
+    }
+}
+
+// Cut a page out of the matching events
+pub(crate) fn page_of(events: Vec<Event>, page: u64) -> EventPage {
+    EventPage {
+        total: events.len() as u64,
+        events: events
+            .into_iter()
+            .skip((page as usize).saturating_mul(PAGE_SIZE))
+            .take(PAGE_SIZE)
+            .collect(),
+        page,
+    }
+}
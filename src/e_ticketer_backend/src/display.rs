@@ -0,0 +1,275 @@
+use crate::ratelimit::rate_limit;
+use crate::{certification, dates, organizers, privacy, Error, Event, Memory, MEMORY_MANAGER};
+use candid::{Decode, Encode};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use std::{borrow::Cow, cell::RefCell};
+
+const MINUTES_PER_DAY: i64 = 24 * 60;
+// Offsets in use around the world run from UTC-12:00 to UTC+14:00
+const MIN_UTC_OFFSET: i32 = -12 * 60;
+const MAX_UTC_OFFSET: i32 = 14 * 60;
+const MONTH_NAMES: [&str; 12] = [
+    "January",
+    "February",
+    "March",
+    "April",
+    "May",
+    "June",
+    "July",
+    "August",
+    "September",
+    "October",
+    "November",
+    "December",
+];
+
+// Define an enum for how a time of day is written
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, Default)]
+pub enum ClockFormat {
+    // 19:30
+    #[default]
+    TwentyFourHour,
+    // 7:30 PM
+    TwelveHour,
+}
+
+// Define an enum for how a date is written
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, Default)]
+pub enum DateFormat {
+    // 2026-10-16
+    #[default]
+    Iso,
+    // 16/10/2026
+    DayMonthYear,
+    // 10/16/2026
+    MonthDayYear,
+    // 16 October 2026
+    Long,
+}
+
+// Define a struct for how a user wants dates and times written in what the canister sends them
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub struct DisplayPreferences {
+    // Minutes east of UTC, event times are shown in the event's own timezone if left out
+    utc_offset_minutes: Option<i32>,
+    clock: Option<ClockFormat>,
+    date_format: Option<DateFormat>,
+}
+
+// Define a struct for the timezone an event's date and start time are given in
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct EventTimezone {
+    utc_offset_minutes: i32,
+}
+
+// Define a struct for when an event starts, kept apart from the event so it can still be written
+// once the event is gone
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct EventTime {
+    date: String,
+    start_time: String,
+    utc_offset_minutes: Option<i32>,
+}
+
+impl Storable for DisplayPreferences {
+    // Conversion to bytes
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    // Conversion from bytes
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for DisplayPreferences {
+    const MAX_SIZE: u32 = 128;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl Storable for EventTimezone {
+    // Conversion to bytes
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    // Conversion from bytes
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for EventTimezone {
+    const MAX_SIZE: u32 = 64;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    // Preferences keyed by user id, users without any get ISO dates and a 24-hour clock
+    static PREFERENCE_STORAGE: RefCell<StableBTreeMap<u64, DisplayPreferences, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(105)))
+    ));
+
+    // Timezones keyed by event id, events without one keep their times as given
+    static EVENT_TIMEZONES: RefCell<StableBTreeMap<u64, EventTimezone, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(106)))
+    ));
+}
+
+#[ic_cdk::query]
+fn get_display_preferences() -> Result<DisplayPreferences, Error> {
+    let user = privacy::caller_user()?;
+    Ok(user_preferences(user.id).unwrap_or_default())
+}
+
+#[ic_cdk::update(guard = "rate_limit")]
+fn set_display_preferences(preferences: DisplayPreferences) -> Result<DisplayPreferences, Error> {
+    let user = privacy::caller_user()?;
+    if let Some(offset) = preferences.utc_offset_minutes {
+        validate_offset(offset).map_err(|msg| Error::ValidationFailed { msg })?;
+    }
+    PREFERENCE_STORAGE.with(|storage| storage.borrow_mut().insert(user.id, preferences.clone()));
+    Ok(preferences)
+}
+
+#[ic_cdk::query]
+fn get_event_timezone(event_id: u64) -> Option<i32> {
+    event_offset(event_id)
+}
+
+// Set the timezone the event's date and start time are given in, None to clear it
+#[ic_cdk::update(guard = "rate_limit")]
+fn set_event_timezone(
+    event_id: u64,
+    utc_offset_minutes: Option<i32>,
+) -> Result<Option<i32>, Error> {
+    organizers::authorize_event(event_id)?;
+    match utc_offset_minutes {
+        Some(offset) => {
+            validate_offset(offset).map_err(|msg| Error::ValidationFailed { msg })?;
+            EVENT_TIMEZONES.with(|timezones| {
+                timezones.borrow_mut().insert(
+                    event_id,
+                    EventTimezone {
+                        utc_offset_minutes: offset,
+                    },
+                )
+            });
+        }
+        None => {
+            EVENT_TIMEZONES.with(|timezones| timezones.borrow_mut().remove(&event_id));
+        }
+    }
+    // The event's calendar file gives its start in UTC
+    certification::certify_event(event_id);
+    Ok(utc_offset_minutes)
+}
+
+fn validate_offset(offset: i32) -> Result<(), String> {
+    if !(MIN_UTC_OFFSET..=MAX_UTC_OFFSET).contains(&offset) || offset % 15 != 0 {
+        return Err(format!(
+            "UTC offset {} must be a multiple of 15 minutes from {} to {}",
+            offset, MIN_UTC_OFFSET, MAX_UTC_OFFSET
+        ));
+    }
+    Ok(())
+}
+
+pub(crate) fn user_preferences(user_id: u64) -> Option<DisplayPreferences> {
+    PREFERENCE_STORAGE.with(|storage| storage.borrow().get(&user_id))
+}
+
+fn event_offset(event_id: u64) -> Option<i32> {
+    EVENT_TIMEZONES
+        .with(|timezones| timezones.borrow().get(&event_id))
+        .map(|timezone| timezone.utc_offset_minutes)
+}
+
+// Minutes to take off an event's local start to get its start in UTC, 0 without a timezone
+pub(crate) fn event_utc_offset(event_id: u64) -> i64 {
+    event_offset(event_id).unwrap_or(0) as i64
+}
+
+pub(crate) fn event_time(event: &Event) -> EventTime {
+    EventTime {
+        date: event.date.clone(),
+        start_time: event.start_time.clone(),
+        utc_offset_minutes: event_offset(event.id),
+    }
+}
+
+// When an event starts, as in "on 2026-10-16 at 19:30", written the way the user prefers. The
+// time is moved to the user's timezone when both it and the event's are known.
+pub(crate) fn event_when(event: &Event, user_id: u64) -> String {
+    when(&event_time(event), user_id)
+}
+
+pub(crate) fn when(time: &EventTime, user_id: u64) -> String {
+    let preferences = user_preferences(user_id).unwrap_or_default();
+    let (Some(date), Some(minutes)) = (
+        dates::parse_date(&time.date),
+        dates::parse_time(&time.start_time),
+    ) else {
+        return format!("on {} at {}", time.date, time.start_time);
+    };
+
+    let (date, minutes, offset) = match (time.utc_offset_minutes, preferences.utc_offset_minutes) {
+        (Some(from), Some(to)) => {
+            let total = minutes as i64 - from as i64 + to as i64;
+            (
+                dates::add_days(date, total.div_euclid(MINUTES_PER_DAY)),
+                total.rem_euclid(MINUTES_PER_DAY) as u32,
+                Some(to),
+            )
+        }
+        (from, _) => (date, minutes, from),
+    };
+    let zone = offset.map_or(String::new(), |offset| {
+        format!(" ({})", offset_label(offset))
+    });
+
+    format!(
+        "on {} at {}{}",
+        format_date(date, preferences.date_format.unwrap_or_default()),
+        format_time(minutes, preferences.clock.unwrap_or_default()),
+        zone
+    )
+}
+
+fn format_date((year, month, day): dates::Date, format: DateFormat) -> String {
+    match format {
+        DateFormat::Iso => dates::format_date((year, month, day)),
+        DateFormat::DayMonthYear => format!("{:02}/{:02}/{:04}", day, month, year),
+        DateFormat::MonthDayYear => format!("{:02}/{:02}/{:04}", month, day, year),
+        DateFormat::Long => format!("{} {} {}", day, MONTH_NAMES[month as usize - 1], year),
+    }
+}
+
+fn format_time(minutes: u32, clock: ClockFormat) -> String {
+    let (hours, minutes) = (minutes / 60, minutes % 60);
+    match clock {
+        ClockFormat::TwentyFourHour => format!("{:02}:{:02}", hours, minutes),
+        ClockFormat::TwelveHour => format!(
+            "{}:{:02} {}",
+            (hours + 11) % 12 + 1,
+            minutes,
+            if hours < 12 { "AM" } else { "PM" }
+        ),
+    }
+}
+
+fn offset_label(offset: i32) -> String {
+    let sign = if offset < 0 { '-' } else { '+' };
+    let offset = offset.unsigned_abs();
+    format!("UTC{}{:02}:{:02}", sign, offset / 60, offset % 60)
+}
+
+pub(crate) fn remove_user_preferences(user_id: u64) {
+    PREFERENCE_STORAGE.with(|storage| storage.borrow_mut().remove(&user_id));
+}
+
+pub(crate) fn remove_event_timezone(event_id: u64) {
+    EVENT_TIMEZONES.with(|timezones| timezones.borrow_mut().remove(&event_id));
+}
@@ -0,0 +1,434 @@
+use crate::cancellations::{self, CancellationRefund};
+use crate::clock::time;
+use crate::credits::RefundTarget;
+use crate::ratelimit::rate_limit;
+use crate::{
+    _get_event, _get_ticket, addons, caller_is_admin, organizers, privacy, Error, Memory,
+    ID_COUNTER, MEMORY_MANAGER,
+};
+use candid::{Decode, Encode};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use std::{borrow::Cow, cell::RefCell};
+
+const NANOS_PER_DAY: u64 = 24 * 60 * 60 * 1_000_000_000;
+// Disputes still unresolved this long after they were opened escalate
+const RESOLUTION_SLA: u64 = 7 * NANOS_PER_DAY;
+const MAX_TEXT_LEN: usize = 500;
+// Messages on one dispute before it has to be resolved, so it fits in a stable map entry
+const MAX_MESSAGES: usize = 10;
+const ERASED_TEXT: &str = "[erased]";
+
+// Define an enum for where a dispute stands
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq)]
+pub enum DisputeStatus {
+    // Waiting on the organizer
+    Open,
+    // The organizer answered, waiting on the attendee or a resolution
+    Responded,
+    // Left unresolved past the SLA, the holder can claim a full refund
+    Escalated,
+    Resolved,
+}
+
+// Define an enum for who wrote a message or moved a dispute along
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq)]
+pub enum DisputeParty {
+    Attendee,
+    Organizer,
+    Admin,
+    // The escalation job
+    System,
+}
+
+// Define an enum for how a dispute ended
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub enum DisputeOutcome {
+    Refunded { amount: u64 },
+    Dismissed,
+}
+
+// Define a struct for a message on a dispute
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct DisputeMessage {
+    from: DisputeParty,
+    text: String,
+    sent_at: u64,
+}
+
+// Define a struct for a change of a dispute's status
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct DisputeTransition {
+    status: DisputeStatus,
+    by: DisputeParty,
+    at: u64,
+}
+
+// Define a struct for a complaint an attendee raises about a ticket
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct Dispute {
+    id: u64,
+    ticket_id: u64,
+    event_id: u64,
+    user_id: u64,
+    reason: String,
+    status: DisputeStatus,
+    messages: Vec<DisputeMessage>,
+    transitions: Vec<DisputeTransition>,
+    outcome: Option<DisputeOutcome>,
+    opened_at: u64,
+    // When it escalates if still unresolved
+    escalates_at: u64,
+    updated_at: Option<u64>,
+    resolved_at: Option<u64>,
+}
+
+impl Storable for Dispute {
+    // Conversion to bytes
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    // Conversion from bytes
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for Dispute {
+    const MAX_SIZE: u32 = 8192;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    // Disputes keyed by dispute id, kept as the record when tickets or events go
+    static DISPUTE_STORAGE: RefCell<StableBTreeMap<u64, Dispute, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(136)))
+    ));
+}
+
+// A dispute, for the attendee who opened it and the event's organizers
+#[ic_cdk::query]
+fn get_dispute(dispute_id: u64) -> Result<Dispute, Error> {
+    let dispute = _get_dispute(dispute_id)?;
+    caller_party(&dispute)?;
+    Ok(dispute)
+}
+
+#[ic_cdk::query]
+fn get_my_disputes() -> Result<Vec<Dispute>, Error> {
+    let user = privacy::caller_user()?;
+    Ok(user_disputes(user.id))
+}
+
+#[ic_cdk::query]
+fn get_event_disputes(event_id: u64, status: Option<DisputeStatus>) -> Result<Vec<Dispute>, Error> {
+    organizers::authorize_event(event_id)?;
+    Ok(DISPUTE_STORAGE.with(|disputes| {
+        disputes
+            .borrow()
+            .iter()
+            .map(|(_, dispute)| dispute)
+            .filter(|dispute| dispute.event_id == event_id)
+            .filter(|dispute| status.is_none_or(|status| dispute.status == status))
+            .collect()
+    }))
+}
+
+// Raise a complaint about one of the caller's tickets, one unresolved dispute per ticket
+#[ic_cdk::update(guard = "rate_limit")]
+fn open_dispute(ticket_id: u64, reason: String) -> Result<Dispute, Error> {
+    let user = privacy::caller_user()?;
+    let ticket = _get_ticket(&ticket_id).ok_or(Error::not_found("ticket", ticket_id))?;
+    if ticket.user_id != user.id {
+        return Err(Error::Unauthorized {
+            msg: format!("ticket id:{} is not held by the caller", ticket_id),
+        });
+    }
+    validate_text(&reason).map_err(|msg| Error::ValidationFailed { msg })?;
+    let unresolved = DISPUTE_STORAGE.with(|disputes| {
+        disputes.borrow().iter().any(|(_, dispute)| {
+            dispute.ticket_id == ticket_id && dispute.status != DisputeStatus::Resolved
+        })
+    });
+    if unresolved {
+        return Err(Error::AlreadyExists {
+            msg: format!("ticket id:{} already has an unresolved dispute", ticket_id),
+        });
+    }
+
+    // Increment the global ID counter to get a new ID for the dispute
+    let id = ID_COUNTER
+        .with(|counter| {
+            let current_id = *counter.borrow().get();
+            counter.borrow_mut().set(current_id + 1)
+        })
+        .expect("Cannot increment Ids");
+    let now = time();
+    let dispute = Dispute {
+        id,
+        ticket_id,
+        event_id: ticket.event_id,
+        user_id: user.id,
+        reason,
+        status: DisputeStatus::Open,
+        messages: vec![],
+        transitions: vec![DisputeTransition {
+            status: DisputeStatus::Open,
+            by: DisputeParty::Attendee,
+            at: now,
+        }],
+        outcome: None,
+        opened_at: now,
+        escalates_at: now + RESOLUTION_SLA,
+        updated_at: None,
+        resolved_at: None,
+    };
+    save(&dispute);
+    Ok(dispute)
+}
+
+// Add a message to an unresolved dispute. An organizer's answer marks it responded, the
+// attendee's reply puts it back to open.
+#[ic_cdk::update(guard = "rate_limit")]
+fn reply_to_dispute(dispute_id: u64, text: String) -> Result<Dispute, Error> {
+    let mut dispute = _get_dispute(dispute_id)?;
+    let party = caller_party(&dispute)?;
+    check_unresolved(&dispute)?;
+    validate_text(&text).map_err(|msg| Error::ValidationFailed { msg })?;
+    if dispute.messages.len() >= MAX_MESSAGES {
+        return Err(Error::ValidationFailed {
+            msg: format!(
+                "dispute id:{} has {} messages, it has to be resolved now",
+                dispute_id, MAX_MESSAGES
+            ),
+        });
+    }
+
+    let now = time();
+    dispute.messages.push(DisputeMessage {
+        from: party,
+        text,
+        sent_at: now,
+    });
+    // An escalated dispute stays escalated, the refund is owed either way
+    if dispute.status != DisputeStatus::Escalated {
+        let status = match party {
+            DisputeParty::Attendee => DisputeStatus::Open,
+            _ => DisputeStatus::Responded,
+        };
+        transition(&mut dispute, status, party, now);
+    }
+    dispute.updated_at = Some(now);
+    save(&dispute);
+    Ok(dispute)
+}
+
+// Close a dispute, refunding the ticket in full or dismissing the complaint. Only an admin can
+// dismiss a dispute that escalated.
+#[ic_cdk::update(guard = "rate_limit")]
+fn resolve_dispute(dispute_id: u64, refund: bool, note: Option<String>) -> Result<Dispute, Error> {
+    let mut dispute = _get_dispute(dispute_id)?;
+    let party = caller_party(&dispute)?;
+    if party == DisputeParty::Attendee {
+        return Err(Error::Unauthorized {
+            msg: format!(
+                "dispute id:{} is resolved by the organizers of event id:{}",
+                dispute_id, dispute.event_id
+            ),
+        });
+    }
+    check_unresolved(&dispute)?;
+    if !refund && dispute.status == DisputeStatus::Escalated && party != DisputeParty::Admin {
+        return Err(Error::Unauthorized {
+            msg: format!(
+                "dispute id:{} has escalated, only an admin can dismiss it",
+                dispute_id
+            ),
+        });
+    }
+    if let Some(note) = &note {
+        validate_text(note).map_err(|msg| Error::ValidationFailed { msg })?;
+    }
+
+    let outcome = match refund {
+        true => DisputeOutcome::Refunded {
+            amount: refund_disputed_ticket(&dispute, None)?.amount,
+        },
+        false => DisputeOutcome::Dismissed,
+    };
+    let now = time();
+    if let Some(text) = note {
+        dispute.messages.push(DisputeMessage {
+            from: party,
+            text,
+            sent_at: now,
+        });
+    }
+    resolve(&mut dispute, outcome, party, now);
+    Ok(dispute)
+}
+
+// Refund the ticket of an escalated dispute in full, for the attendee who opened it
+#[ic_cdk::update(guard = "rate_limit")]
+fn claim_dispute_refund(
+    dispute_id: u64,
+    refund_to: Option<RefundTarget>,
+) -> Result<CancellationRefund, Error> {
+    let mut dispute = _get_dispute(dispute_id)?;
+    if caller_party(&dispute)? != DisputeParty::Attendee {
+        return Err(Error::Unauthorized {
+            msg: format!("dispute id:{} was not opened by the caller", dispute_id),
+        });
+    }
+    if dispute.status != DisputeStatus::Escalated {
+        return Err(Error::ValidationFailed {
+            msg: format!(
+                "dispute id:{} has not escalated, refunds can be claimed once it has been \
+                 unresolved for {} days",
+                dispute_id,
+                RESOLUTION_SLA / NANOS_PER_DAY
+            ),
+        });
+    }
+
+    let refund = refund_disputed_ticket(&dispute, refund_to)?;
+    let outcome = DisputeOutcome::Refunded {
+        amount: refund.amount,
+    };
+    resolve(&mut dispute, outcome, DisputeParty::Attendee, time());
+    Ok(refund)
+}
+
+// Escalate the disputes left unresolved past the SLA, returning how many were escalated
+pub(crate) fn escalate_overdue_disputes() -> u64 {
+    let now = time();
+    let overdue: Vec<Dispute> = DISPUTE_STORAGE.with(|disputes| {
+        disputes
+            .borrow()
+            .iter()
+            .map(|(_, dispute)| dispute)
+            .filter(|dispute| {
+                matches!(
+                    dispute.status,
+                    DisputeStatus::Open | DisputeStatus::Responded
+                ) && dispute.escalates_at <= now
+            })
+            .collect()
+    });
+    let escalated = overdue.len() as u64;
+    for mut dispute in overdue {
+        transition(
+            &mut dispute,
+            DisputeStatus::Escalated,
+            DisputeParty::System,
+            now,
+        );
+        dispute.updated_at = Some(now);
+        save(&dispute);
+    }
+    escalated
+}
+
+pub(crate) fn user_disputes(user_id: u64) -> Vec<Dispute> {
+    DISPUTE_STORAGE.with(|disputes| {
+        disputes
+            .borrow()
+            .iter()
+            .map(|(_, dispute)| dispute)
+            .filter(|dispute| dispute.user_id == user_id)
+            .collect()
+    })
+}
+
+// Blank out what a user wrote in their disputes, the disputes stay for the organizers' records
+pub(crate) fn erase_user_disputes(user_id: u64) {
+    for mut dispute in user_disputes(user_id) {
+        dispute.reason = ERASED_TEXT.to_string();
+        for message in &mut dispute.messages {
+            if message.from == DisputeParty::Attendee {
+                message.text = ERASED_TEXT.to_string();
+            }
+        }
+        save(&dispute);
+    }
+}
+
+fn _get_dispute(dispute_id: u64) -> Result<Dispute, Error> {
+    DISPUTE_STORAGE
+        .with(|disputes| disputes.borrow().get(&dispute_id))
+        .ok_or(Error::not_found("dispute", dispute_id))
+}
+
+fn save(dispute: &Dispute) {
+    DISPUTE_STORAGE.with(|disputes| disputes.borrow_mut().insert(dispute.id, dispute.clone()));
+}
+
+// Which side of a dispute the caller is on, admins before organizers
+fn caller_party(dispute: &Dispute) -> Result<DisputeParty, Error> {
+    if privacy::caller_user().is_ok_and(|user| user.id == dispute.user_id) {
+        return Ok(DisputeParty::Attendee);
+    }
+    if caller_is_admin().is_ok() {
+        return Ok(DisputeParty::Admin);
+    }
+    let event = _get_event(&dispute.event_id).ok_or(Error::Unauthorized {
+        msg: format!("caller is not a party to dispute id:{}", dispute.id),
+    })?;
+    organizers::check_event_organizer(&event)?;
+    Ok(DisputeParty::Organizer)
+}
+
+fn check_unresolved(dispute: &Dispute) -> Result<(), Error> {
+    match dispute.status {
+        DisputeStatus::Resolved => Err(Error::ValidationFailed {
+            msg: format!("dispute id:{} is already resolved", dispute.id),
+        }),
+        _ => Ok(()),
+    }
+}
+
+// Cancel the disputed ticket and give back all that was paid for it and its add-ons
+fn refund_disputed_ticket(
+    dispute: &Dispute,
+    refund_to: Option<RefundTarget>,
+) -> Result<CancellationRefund, Error> {
+    let ticket = _get_ticket(&dispute.ticket_id)
+        .filter(|ticket| ticket.user_id == dispute.user_id)
+        .ok_or(Error::ValidationFailed {
+            msg: format!(
+                "ticket id:{} is no longer held by user id:{}, it can't be refunded",
+                dispute.ticket_id, dispute.user_id
+            ),
+        })?;
+    let amount = ticket.price + addons::ticket_addons_total(&ticket);
+    cancellations::refund_ticket(ticket, amount, None, refund_to, None)
+}
+
+fn transition(dispute: &mut Dispute, status: DisputeStatus, by: DisputeParty, at: u64) {
+    if dispute.status == status {
+        return;
+    }
+    dispute.status = status;
+    dispute
+        .transitions
+        .push(DisputeTransition { status, by, at });
+}
+
+fn resolve(dispute: &mut Dispute, outcome: DisputeOutcome, by: DisputeParty, at: u64) {
+    transition(dispute, DisputeStatus::Resolved, by, at);
+    dispute.outcome = Some(outcome);
+    dispute.updated_at = Some(at);
+    dispute.resolved_at = Some(at);
+    save(dispute);
+}
+
+fn validate_text(text: &str) -> Result<(), String> {
+    if text.trim().is_empty() || text.len() > MAX_TEXT_LEN {
+        return Err(format!(
+            "text must not be empty or longer than {} bytes",
+            MAX_TEXT_LEN
+        ));
+    }
+    Ok(())
+}
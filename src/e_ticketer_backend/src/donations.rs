@@ -0,0 +1,201 @@
+use crate::clock::time;
+use crate::ratelimit::rate_limit;
+use crate::{
+    _get_event, _get_user, config, organizers, stats, Error, Memory, ID_COUNTER, MEMORY_MANAGER,
+};
+use candid::{Decode, Encode};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use std::{borrow::Cow, cell::RefCell};
+
+// Define a struct for the donation settings of an event
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub struct DonationSettings {
+    event_id: u64,
+    // Whether the platform fee is withheld from donations or they are routed in full
+    charge_fees: bool,
+    created_at: u64,
+    updated_at: Option<u64>,
+}
+
+// Define a struct for the receipt handed to a donor
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub struct DonationReceipt {
+    id: u64,
+    event_id: u64,
+    user_id: u64,
+    ticket_id: u64,
+    amount: u64,
+    fee: u64,
+    net_amount: u64,
+    created_at: u64,
+}
+
+impl Storable for DonationSettings {
+    // Conversion to bytes
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    // Conversion from bytes
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl Storable for DonationReceipt {
+    // Conversion to bytes
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    // Conversion from bytes
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for DonationSettings {
+    const MAX_SIZE: u32 = 128;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl BoundedStorable for DonationReceipt {
+    const MAX_SIZE: u32 = 256;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    // Donation settings keyed by event id, events without an entry don't accept donations
+    static DONATION_SETTINGS: RefCell<StableBTreeMap<u64, DonationSettings, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(14)))
+    ));
+
+    static DONATION_STORAGE: RefCell<StableBTreeMap<u64, DonationReceipt, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(15)))
+    ));
+}
+
+#[ic_cdk::update(guard = "rate_limit")]
+fn enable_event_donations(event_id: u64, charge_fees: bool) -> Result<DonationSettings, Error> {
+    // Only the event's organizers can change it, or return an error if the caller isn't one
+    organizers::authorize_event(event_id)?;
+
+    let existing = DONATION_SETTINGS.with(|settings| settings.borrow().get(&event_id));
+    let settings = DonationSettings {
+        event_id,
+        charge_fees,
+        created_at: existing
+            .as_ref()
+            .map_or(time(), |settings| settings.created_at),
+        updated_at: existing.map(|_| time()),
+    };
+
+    DONATION_SETTINGS.with(|storage| storage.borrow_mut().insert(event_id, settings.clone()));
+
+    Ok(settings)
+}
+
+#[ic_cdk::update(guard = "rate_limit")]
+fn disable_event_donations(event_id: u64) -> Result<String, Error> {
+    organizers::authorize_event(event_id)?;
+    match DONATION_SETTINGS.with(|settings| settings.borrow_mut().remove(&event_id)) {
+        Some(_) => Ok(format!("donations of event id: {} disabled", event_id)),
+        None => Err(Error::NotFound {
+            entity: "donation settings".to_string(),
+            id: event_id.to_string(),
+            msg: format!("event id:{} does not accept donations", event_id),
+        }),
+    }
+}
+
+#[ic_cdk::query]
+fn get_donation_receipt(id: u64) -> Result<DonationReceipt, Error> {
+    DONATION_STORAGE
+        .with(|donations| donations.borrow().get(&id))
+        .ok_or(Error::not_found("donation", id))
+}
+
+#[ic_cdk::query]
+pub(crate) fn get_user_donations(user_id: u64) -> Result<Vec<DonationReceipt>, Error> {
+    // Make sure the user exists, or return a NotFound error if not found
+    _get_user(&user_id).ok_or(Error::not_found("user", user_id))?;
+
+    Ok(DONATION_STORAGE.with(|donations| {
+        donations
+            .borrow()
+            .iter()
+            .filter(|(_, receipt)| receipt.user_id == user_id)
+            .map(|(_, receipt)| receipt)
+            .collect()
+    }))
+}
+
+#[ic_cdk::query]
+fn get_event_donations(event_id: u64) -> Result<Vec<DonationReceipt>, Error> {
+    // Make sure the event exists, or return a NotFound error if not found
+    _get_event(&event_id).ok_or(Error::not_found("event", event_id))?;
+
+    Ok(DONATION_STORAGE.with(|donations| {
+        donations
+            .borrow()
+            .iter()
+            .filter(|(_, receipt)| receipt.event_id == event_id)
+            .map(|(_, receipt)| receipt)
+            .collect()
+    }))
+}
+
+// Check that an event accepts a donation before the purchase goes through
+pub(crate) fn check_donation(event_id: u64, amount: u64) -> Result<(), String> {
+    if amount == 0 {
+        return Err("donation amount must be above zero".to_string());
+    }
+    if DONATION_SETTINGS.with(|settings| !settings.borrow().contains_key(&event_id)) {
+        return Err(format!("event id:{} does not accept donations", event_id));
+    }
+    Ok(())
+}
+
+// Store the receipt of a donation made along with a ticket
+pub(crate) fn record_donation(event_id: u64, user_id: u64, ticket_id: u64, amount: u64) {
+    let fee = donation_fee(event_id, amount);
+
+    // Increment the global ID counter to get a new ID for the receipt
+    let id = ID_COUNTER
+        .with(|counter| {
+            let current_id = *counter.borrow().get();
+            counter.borrow_mut().set(current_id + 1)
+        })
+        .expect("Cannot increment Ids");
+
+    let receipt = DonationReceipt {
+        id,
+        event_id,
+        user_id,
+        ticket_id,
+        amount,
+        fee,
+        net_amount: amount - fee,
+        created_at: time(),
+    };
+
+    DONATION_STORAGE.with(|donations| donations.borrow_mut().insert(id, receipt));
+    stats::record_donation(event_id, amount, fee);
+}
+
+// Fee withheld from a donation to an event, if its organizer passes fees on
+pub(crate) fn donation_fee(event_id: u64, amount: u64) -> u64 {
+    let charge_fees = DONATION_SETTINGS
+        .with(|settings| settings.borrow().get(&event_id))
+        .is_some_and(|settings| settings.charge_fees);
+    match charge_fees {
+        true => amount * config::platform_fee_bps() / 10_000,
+        false => 0,
+    }
+}
+
+// Drop the donation settings of a deleted event, receipts are kept for the donors
+pub(crate) fn remove_event_donation_settings(event_id: u64) {
+    DONATION_SETTINGS.with(|settings| settings.borrow_mut().remove(&event_id));
+}
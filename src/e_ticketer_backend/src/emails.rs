@@ -0,0 +1,129 @@
+use crate::{
+    _get_user, backup, organizers, Error, Memory, StringKey, User, MEMORY_MANAGER, USER_STORAGE,
+};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
+use std::time::Duration;
+
+// Longest email the index can hold, the bound of a 'StringKey'
+const MAX_EMAIL_LEN: usize = 128;
+// Users indexed per timer tick when the index is filled in after an upgrade
+const INDEXING_BATCH: usize = 500;
+
+thread_local! {
+    // User ids keyed by their normalized email, users in the trash keep theirs until purged
+    static EMAIL_INDEX: RefCell<StableBTreeMap<StringKey, u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(107)))
+    ));
+}
+
+// Look up an attendee by email. Admins can find any user, organizers only users attending one of
+// their events.
+#[ic_cdk::query]
+fn get_user_by_email(email: String) -> Result<User, Error> {
+    let trusted = ic_cdk::api::is_controller(&ic_cdk::caller());
+    let organizer_id = organizers::caller_organizer_id();
+    if !trusted && organizer_id.is_none() {
+        return Err(Error::Unauthorized {
+            msg: "only admins and organizers can look up users by email".to_string(),
+        });
+    }
+
+    let user = indexed_user_id(&email)
+        .and_then(|id| _get_user(&id))
+        .filter(|user| match organizer_id {
+            Some(organizer_id) if !trusted => {
+                let event_ids = organizers::organizer_event_ids(organizer_id);
+                user.event_ids.iter().any(|id| event_ids.contains(id))
+            }
+            _ => true,
+        });
+    // Organizers can't tell users of other events from emails nobody uses
+    user.ok_or(Error::NotFound {
+        entity: "user".to_string(),
+        id: email.clone(),
+        msg: format!("no user with email {}", email),
+    })
+}
+
+// Emails are matched whatever their case and surrounding whitespace
+pub(crate) fn normalize(email: &str) -> String {
+    email.trim().to_lowercase()
+}
+
+fn indexed_user_id(email: &str) -> Option<u64> {
+    EMAIL_INDEX.with(|index| index.borrow().get(&StringKey(normalize(email))))
+}
+
+// Check an email can be given to a user, passing if it is already theirs. Blank emails aren't
+// indexed, any number of users can leave theirs out.
+pub(crate) fn check_available(email: &str, user_id: Option<u64>) -> Result<(), Error> {
+    let normalized = normalize(email);
+    if normalized.len() > MAX_EMAIL_LEN {
+        return Err(Error::ValidationFailed {
+            msg: format!("email must be at most {} bytes", MAX_EMAIL_LEN),
+        });
+    }
+    if normalized.is_empty() {
+        return Ok(());
+    }
+    match indexed_user_id(&normalized) {
+        Some(owner) if Some(owner) != user_id => Err(Error::AlreadyExists {
+            msg: format!("email {} is already in use", email.trim()),
+        }),
+        _ => Ok(()),
+    }
+}
+
+pub(crate) fn index_user(user: &User) {
+    let email = normalize(&user.email);
+    if !email.is_empty() {
+        EMAIL_INDEX.with(|index| index.borrow_mut().insert(StringKey(email), user.id));
+    }
+}
+
+// Free the email of a user, unless it is indexed under someone else
+pub(crate) fn unindex_user(user: &User) {
+    let email = StringKey(normalize(&user.email));
+    EMAIL_INDEX.with(|index| {
+        let mut index = index.borrow_mut();
+        if index.get(&email) == Some(user.id) {
+            index.remove(&email);
+        }
+    });
+}
+
+// Index the users stored before emails were, a few at a time. Where older users share an email
+// the first one is found by it, the others keep it but can't be looked up until they change it.
+pub(crate) fn schedule_indexing() {
+    ic_cdk_timers::set_timer(Duration::ZERO, || {
+        // Picked up again on the next upgrade
+        if backup::in_maintenance() {
+            return;
+        }
+        let batch: Vec<User> = USER_STORAGE.with(|users| {
+            users
+                .borrow()
+                .iter()
+                .map(|(_, user)| user)
+                .filter(|user| {
+                    let email = normalize(&user.email);
+                    !email.is_empty()
+                        && email.len() <= MAX_EMAIL_LEN
+                        && indexed_user_id(&email).is_none()
+                })
+                .take(INDEXING_BATCH + 1)
+                .collect()
+        });
+        for user in batch.iter().take(INDEXING_BATCH) {
+            if indexed_user_id(&user.email).is_none() {
+                index_user(user);
+            }
+        }
+        if batch.len() > INDEXING_BATCH {
+            schedule_indexing();
+        }
+    });
+}
@@ -0,0 +1,257 @@
+use crate::clock::time;
+use crate::ratelimit::rate_limit;
+use crate::{
+    _get_event, backup, caller_is_admin, certification, Error, Event, Memory, ID_COUNTER,
+    MEMORY_MANAGER,
+};
+use candid::{Decode, Encode, Principal};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use std::time::Duration;
+use std::{borrow::Cow, cell::RefCell};
+
+// Bound keeping the press list within a single stable map entry
+const MAX_PRESS: usize = 50;
+
+// Define a struct for the embargo keeping an event from the public until it lifts
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct EventEmbargo {
+    event_id: u64,
+    lifts_at: u64,
+    // Principals allowed to see the event before it lifts
+    press: Vec<Principal>,
+    // Set once the event went public, early through 'lift_event_embargo' or on time
+    lifted_at: Option<u64>,
+    created_at: u64,
+    updated_at: Option<u64>,
+}
+
+// Define a struct for a look at an event while it was still embargoed
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct PressView {
+    event_id: u64,
+    viewer: Principal,
+    viewed_at: u64,
+}
+
+impl Storable for EventEmbargo {
+    // Conversion to bytes
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    // Conversion from bytes
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl Storable for PressView {
+    // Conversion to bytes
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    // Conversion from bytes
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for EventEmbargo {
+    const MAX_SIZE: u32 = 4096;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl BoundedStorable for PressView {
+    const MAX_SIZE: u32 = 128;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    // Embargoes keyed by event id
+    static EMBARGOES: RefCell<StableBTreeMap<u64, EventEmbargo, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(30)))
+    ));
+
+    // Audit of embargoed views keyed by (event id, view id)
+    static PRESS_VIEWS: RefCell<StableBTreeMap<(u64, u64), PressView, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(31)))
+    ));
+}
+
+fn _get_embargo(event_id: &u64) -> Option<EventEmbargo> {
+    // Helper function to get the embargo of an event
+    EMBARGOES.with(|embargoes| embargoes.borrow().get(event_id))
+}
+
+#[ic_cdk::query(guard = "caller_is_admin")]
+fn get_event_embargo(event_id: u64) -> Result<EventEmbargo, Error> {
+    _get_embargo(&event_id).ok_or(Error::NotFound {
+        entity: "embargo".to_string(),
+        id: event_id.to_string(),
+        msg: format!("event id:{} has no embargo", event_id),
+    })
+}
+
+#[ic_cdk::update(guard = "caller_is_admin")]
+fn set_event_embargo(
+    event_id: u64,
+    lifts_at: u64,
+    press: Vec<Principal>,
+) -> Result<EventEmbargo, Error> {
+    // Make sure the event exists, or return a NotFound error if not found
+    _get_event(&event_id).ok_or(Error::not_found("event", event_id))?;
+
+    // An event that went public can't be taken back
+    let existing = _get_embargo(&event_id);
+    if existing
+        .as_ref()
+        .is_some_and(|embargo| embargo.lifted_at.is_some())
+    {
+        return Err(Error::ValidationFailed {
+            msg: format!("embargo of event id:{} already lifted", event_id),
+        });
+    }
+    if lifts_at <= time() {
+        return Err(Error::ValidationFailed {
+            msg: "embargo must lift in the future".to_string(),
+        });
+    }
+    if press.len() > MAX_PRESS {
+        return Err(Error::ValidationFailed {
+            msg: format!("an embargo can have at most {} press principals", MAX_PRESS),
+        });
+    }
+
+    let embargo = EventEmbargo {
+        event_id,
+        lifts_at,
+        press,
+        lifted_at: None,
+        created_at: existing
+            .as_ref()
+            .map_or(time(), |embargo| embargo.created_at),
+        updated_at: existing.map(|_| time()),
+    };
+    EMBARGOES.with(|embargoes| embargoes.borrow_mut().insert(event_id, embargo.clone()));
+    certification::certify_event(event_id);
+    schedule_lift(&embargo);
+
+    Ok(embargo)
+}
+
+#[ic_cdk::update(guard = "caller_is_admin")]
+fn lift_event_embargo(event_id: u64) -> Result<EventEmbargo, Error> {
+    lift_embargo(event_id).ok_or(Error::NotFound {
+        entity: "embargo".to_string(),
+        id: event_id.to_string(),
+        msg: format!("event id:{} has no embargo to lift", event_id),
+    })
+}
+
+#[ic_cdk::update(guard = "rate_limit")]
+fn view_embargoed_event(event_id: u64) -> Result<Event, Error> {
+    let event = _get_event(&event_id).ok_or(Error::not_found("event", event_id))?;
+    if is_public(event_id) {
+        return Ok(event);
+    }
+
+    // Only the press and admins see the event early, and every look is recorded
+    let caller = ic_cdk::caller();
+    let is_press = _get_embargo(&event_id).is_some_and(|embargo| embargo.press.contains(&caller));
+    if !is_press && caller_is_admin().is_err() {
+        return Err(Error::not_found("event", event_id));
+    }
+
+    // Increment the global ID counter to get a new ID for the view
+    let id = ID_COUNTER
+        .with(|counter| {
+            let current_id = *counter.borrow().get();
+            counter.borrow_mut().set(current_id + 1)
+        })
+        .expect("Cannot increment Ids");
+    let view = PressView {
+        event_id,
+        viewer: caller,
+        viewed_at: time(),
+    };
+    PRESS_VIEWS.with(|views| views.borrow_mut().insert((event_id, id), view));
+
+    Ok(event)
+}
+
+#[ic_cdk::query(guard = "caller_is_admin")]
+fn get_press_views(event_id: u64) -> Vec<PressView> {
+    PRESS_VIEWS.with(|views| {
+        views
+            .borrow()
+            .range((event_id, 0)..=(event_id, u64::MAX))
+            .map(|(_, view)| view)
+            .collect()
+    })
+}
+
+// Whether an event can be seen by everyone, i.e. it has no embargo or it lifted
+pub(crate) fn is_public(event_id: u64) -> bool {
+    _get_embargo(&event_id)
+        .is_none_or(|embargo| embargo.lifted_at.is_some() || embargo.lifts_at <= time())
+}
+
+// Make an embargoed event public and certify it, returning None if it had no embargo
+fn lift_embargo(event_id: u64) -> Option<EventEmbargo> {
+    let mut embargo = _get_embargo(&event_id)?;
+    if embargo.lifted_at.is_none() {
+        embargo.lifted_at = Some(time());
+        EMBARGOES.with(|embargoes| embargoes.borrow_mut().insert(event_id, embargo.clone()));
+        certification::certify_event(event_id);
+    }
+    Some(embargo)
+}
+
+// Lift an embargo when its time comes, a changed embargo gets a timer of its own
+fn schedule_lift(embargo: &EventEmbargo) {
+    let (event_id, lifts_at) = (embargo.event_id, embargo.lifts_at);
+    let delay = Duration::from_nanos(lifts_at.saturating_sub(time()));
+    ic_cdk_timers::set_timer(delay, move || {
+        // Skipped lifts still take effect through 'is_public', and are re-armed on upgrade
+        if backup::in_maintenance() {
+            return;
+        }
+        let due = _get_embargo(&event_id)
+            .is_some_and(|embargo| embargo.lifted_at.is_none() && embargo.lifts_at <= time());
+        if due {
+            lift_embargo(event_id);
+        }
+    });
+}
+
+// Arm the timers of the embargoes still pending, timers don't survive upgrades
+pub(crate) fn schedule_embargoes() {
+    let pending: Vec<EventEmbargo> = EMBARGOES.with(|embargoes| {
+        embargoes
+            .borrow()
+            .iter()
+            .map(|(_, embargo)| embargo)
+            .filter(|embargo| embargo.lifted_at.is_none())
+            .collect()
+    });
+    for embargo in &pending {
+        schedule_lift(embargo);
+    }
+}
+
+// Drop the embargo of a purged event along with its audit
+pub(crate) fn remove_event_embargo(event_id: u64) {
+    EMBARGOES.with(|embargoes| embargoes.borrow_mut().remove(&event_id));
+    PRESS_VIEWS.with(|views| {
+        let mut views = views.borrow_mut();
+        let keys: Vec<(u64, u64)> = views
+            .range((event_id, 0)..=(event_id, u64::MAX))
+            .map(|(key, _)| key)
+            .collect();
+        for key in keys {
+            views.remove(&key);
+        }
+    });
+}
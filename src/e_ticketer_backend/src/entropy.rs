@@ -0,0 +1,46 @@
+use crate::clock::time;
+use ic_cdk::api::management_canister::main::raw_rand;
+use sha2::{Digest, Sha256};
+use std::cell::RefCell;
+use std::time::Duration;
+
+thread_local! {
+    // Seed drawn from the management canister after every install and upgrade
+    static SEED: RefCell<Vec<u8>> = const { RefCell::new(vec![]) };
+}
+
+// Draw a fresh seed, timers run outside of init so the call can be awaited
+pub(crate) fn schedule_seeding() {
+    ic_cdk_timers::set_timer(Duration::ZERO, || {
+        ic_cdk::spawn(async {
+            if let Ok((seed,)) = raw_rand().await {
+                SEED.with(|s| *s.borrow_mut() = seed);
+            }
+        })
+    });
+}
+
+// Hasher primed with the seed and the current time, callers add their own inputs to tell draws apart
+pub(crate) fn seeded_hasher() -> Sha256 {
+    let mut hasher = Sha256::new();
+    SEED.with(|seed| hasher.update(&*seed.borrow()));
+    hasher.update(time().to_be_bytes());
+    hasher
+}
+
+// Whether the seed was drawn yet, secrets derived before that would only depend on the time
+pub(crate) fn is_seeded() -> bool {
+    SEED.with(|seed| !seed.borrow().is_empty())
+}
+
+// Hex token derived from the seed, the purpose of the token and the id it is issued for
+pub(crate) fn random_token(purpose: &[u8], id: u64) -> String {
+    let mut hasher = seeded_hasher();
+    hasher.update(purpose);
+    hasher.update(id.to_be_bytes());
+    hasher
+        .finalize()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
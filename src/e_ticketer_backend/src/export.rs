@@ -0,0 +1,242 @@
+use crate::auth::{self, ApiScope};
+use crate::{_get_event, _get_ticket, _get_user, attendee_notes, Error, Event};
+use serde_bytes::ByteBuf;
+
+// Bytes per chunk, kept under the reply size limit with room for the envelope
+const CHUNK_SIZE: usize = 1_500_000;
+
+// Define an enum for the file formats an export can be produced in
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq)]
+pub enum ExportFormat {
+    Csv,
+    Json,
+}
+
+// Define a struct for a part of an export, the parts are concatenated in order
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub struct ExportChunk {
+    content_type: String,
+    chunk: u64,
+    total_chunks: u64,
+    data: ByteBuf,
+}
+
+// A row of the attendee export, with the notes the caller keeps on the attendee
+#[derive(Serialize)]
+struct AttendeeRow {
+    user_id: u64,
+    name: String,
+    email: String,
+    ticket_ids: Vec<u64>,
+    checked_in: bool,
+    note: Option<String>,
+    tags: Vec<String>,
+}
+
+// A row of the ticket export
+#[derive(Serialize)]
+struct TicketRow {
+    ticket_id: u64,
+    code: Option<String>,
+    user_id: u64,
+    tier_id: Option<u64>,
+    price: u64,
+    promo_code: Option<String>,
+    insured: bool,
+    checked_in_at: Option<u64>,
+    created_at: u64,
+}
+
+#[ic_cdk::query]
+fn export_event_attendees(
+    event_id: u64,
+    format: ExportFormat,
+    chunk: Option<u64>,
+    api_key: Option<String>,
+) -> Result<ExportChunk, Error> {
+    auth::authorize(api_key, ApiScope::ViewAttendees)?;
+    let event = event_to_export(event_id)?;
+
+    let mut notes = attendee_notes::caller_event_notes(event_id, ic_cdk::caller());
+    let tickets: Vec<_> = event.ticket_ids.iter().filter_map(_get_ticket).collect();
+    let rows: Vec<AttendeeRow> = event
+        .attendee_ids
+        .iter()
+        // Attendees in the trash are left out
+        .filter_map(_get_user)
+        .map(|user| {
+            let user_tickets: Vec<_> = tickets
+                .iter()
+                .filter(|ticket| ticket.user_id == user.id)
+                .collect();
+            let note = notes
+                .iter()
+                .position(|note| note.user_id == user.id)
+                .map(|index| notes.swap_remove(index));
+            AttendeeRow {
+                user_id: user.id,
+                name: user.name,
+                email: user.email,
+                ticket_ids: user_tickets.iter().map(|ticket| ticket.id).collect(),
+                checked_in: user_tickets
+                    .iter()
+                    .any(|ticket| ticket.checked_in_at.is_some()),
+                tags: note.as_ref().map_or(vec![], |note| note.tags.clone()),
+                note: note.map(|note| note.note),
+            }
+        })
+        .collect();
+
+    let data = match format {
+        ExportFormat::Json => serde_json::to_vec(&rows).unwrap(),
+        ExportFormat::Csv => to_csv(
+            &[
+                "user_id",
+                "name",
+                "email",
+                "ticket_ids",
+                "checked_in",
+                "note",
+                "tags",
+            ],
+            rows.into_iter().map(|row| {
+                vec![
+                    row.user_id.to_string(),
+                    row.name,
+                    row.email,
+                    join(&row.ticket_ids),
+                    row.checked_in.to_string(),
+                    row.note.unwrap_or_default(),
+                    row.tags.join(";"),
+                ]
+            }),
+        ),
+    };
+    export_chunk(data, format, chunk)
+}
+
+#[ic_cdk::query]
+fn export_event_tickets(
+    event_id: u64,
+    format: ExportFormat,
+    chunk: Option<u64>,
+    api_key: Option<String>,
+) -> Result<ExportChunk, Error> {
+    auth::authorize(api_key, ApiScope::ViewAttendees)?;
+    let event = event_to_export(event_id)?;
+
+    let rows: Vec<TicketRow> = event
+        .ticket_ids
+        .iter()
+        .filter_map(_get_ticket)
+        .map(|ticket| TicketRow {
+            ticket_id: ticket.id,
+            code: ticket.code,
+            user_id: ticket.user_id,
+            tier_id: ticket.tier_id,
+            price: ticket.price,
+            promo_code: ticket.promo_code,
+            insured: ticket.insurance.is_some(),
+            checked_in_at: ticket.checked_in_at,
+            created_at: ticket.created_at,
+        })
+        .collect();
+
+    let data = match format {
+        ExportFormat::Json => serde_json::to_vec(&rows).unwrap(),
+        ExportFormat::Csv => to_csv(
+            &[
+                "ticket_id",
+                "code",
+                "user_id",
+                "tier_id",
+                "price",
+                "promo_code",
+                "insured",
+                "checked_in_at",
+                "created_at",
+            ],
+            rows.into_iter().map(|row| {
+                vec![
+                    row.ticket_id.to_string(),
+                    row.code.unwrap_or_default(),
+                    row.user_id.to_string(),
+                    row.tier_id.map_or(String::new(), |id| id.to_string()),
+                    row.price.to_string(),
+                    row.promo_code.unwrap_or_default(),
+                    row.insured.to_string(),
+                    row.checked_in_at.map_or(String::new(), |at| at.to_string()),
+                    row.created_at.to_string(),
+                ]
+            }),
+        ),
+    };
+    export_chunk(data, format, chunk)
+}
+
+fn event_to_export(event_id: u64) -> Result<Event, Error> {
+    _get_event(&event_id).ok_or(Error::not_found("event", event_id))
+}
+
+// Cut an export into parts that fit a reply, returning the requested one
+pub(crate) fn export_chunk(
+    data: Vec<u8>,
+    format: ExportFormat,
+    chunk: Option<u64>,
+) -> Result<ExportChunk, Error> {
+    let total_chunks = data.len().div_ceil(CHUNK_SIZE).max(1);
+    let chunk = chunk.unwrap_or(0);
+    if chunk >= total_chunks as u64 {
+        return Err(Error::ValidationFailed {
+            msg: format!(
+                "export has {} chunks, chunk {} does not exist",
+                total_chunks, chunk
+            ),
+        });
+    }
+
+    let start = chunk as usize * CHUNK_SIZE;
+    let end = data.len().min(start + CHUNK_SIZE);
+    Ok(ExportChunk {
+        content_type: match format {
+            ExportFormat::Csv => "text/csv",
+            ExportFormat::Json => "application/json",
+        }
+        .to_string(),
+        chunk,
+        total_chunks: total_chunks as u64,
+        data: ByteBuf::from(&data[start..end]),
+    })
+}
+
+pub(crate) fn to_csv(header: &[&str], rows: impl Iterator<Item = Vec<String>>) -> Vec<u8> {
+    let mut csv = header.join(",");
+    csv.push_str("\r\n");
+    for row in rows {
+        let fields: Vec<String> = row.iter().map(|field| csv_field(field)).collect();
+        csv.push_str(&fields.join(","));
+        csv.push_str("\r\n");
+    }
+    csv.into_bytes()
+}
+
+// Quote a field when needed, and defuse text a spreadsheet would run as a formula.
+// Negative numbers are left as they are.
+fn csv_field(field: &str) -> String {
+    let field = match field.starts_with(['=', '+', '-', '@']) && field.parse::<i64>().is_err() {
+        true => format!("'{}", field),
+        false => field.to_string(),
+    };
+    if field.contains([',', '"', '\r', '\n']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field
+    }
+}
+
+fn join(ids: &[u64]) -> String {
+    ids.iter()
+        .map(|id| id.to_string())
+        .collect::<Vec<_>>()
+        .join(";")
+}
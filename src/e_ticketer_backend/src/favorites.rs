@@ -0,0 +1,166 @@
+use crate::clock::time;
+use crate::discovery::{self, EventPage};
+use crate::ratelimit::rate_limit;
+use crate::{
+    _get_listed_event, _get_public_event, dates, organizers, privacy, Error, Event, Memory,
+    MEMORY_MANAGER,
+};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
+
+const NANOS_PER_DAY: u64 = 24 * 60 * 60 * 1_000_000_000;
+const MAX_FAVORITES: usize = 500;
+const MAX_FOLLOWS: usize = 200;
+
+thread_local! {
+    // Favorite events keyed by (user id, event id)
+    static FAVORITE_INDEX: RefCell<StableBTreeMap<(u64, u64), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(86)))
+    ));
+
+    // Followed organizers keyed by (user id, organizer id)
+    static FOLLOW_INDEX: RefCell<StableBTreeMap<(u64, u64), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(87)))
+    ));
+}
+
+#[ic_cdk::update(guard = "rate_limit")]
+fn favorite_event(id: u64) -> Result<(), Error> {
+    let user = privacy::caller_user()?;
+    _get_public_event(&id).ok_or(Error::not_found("event", id))?;
+    if favorite_event_ids(user.id).len() >= MAX_FAVORITES {
+        return Err(Error::ValidationFailed {
+            msg: format!("at most {} events can be favorites", MAX_FAVORITES),
+        });
+    }
+    FAVORITE_INDEX.with(|index| index.borrow_mut().insert((user.id, id), ()));
+    Ok(())
+}
+
+#[ic_cdk::update(guard = "rate_limit")]
+fn unfavorite_event(id: u64) -> Result<(), Error> {
+    let user = privacy::caller_user()?;
+    FAVORITE_INDEX
+        .with(|index| index.borrow_mut().remove(&(user.id, id)))
+        .ok_or(Error::NotFound {
+            entity: "favorite".to_string(),
+            id: id.to_string(),
+            msg: format!("event id:{} is not a favorite", id),
+        })
+}
+
+#[ic_cdk::update(guard = "rate_limit")]
+fn follow_organizer(id: u64) -> Result<(), Error> {
+    let user = privacy::caller_user()?;
+    organizers::_get_organizer(id).ok_or(Error::not_found("organizer", id))?;
+    if followed_organizer_ids(user.id).len() >= MAX_FOLLOWS {
+        return Err(Error::ValidationFailed {
+            msg: format!("at most {} organizers can be followed", MAX_FOLLOWS),
+        });
+    }
+    FOLLOW_INDEX.with(|index| index.borrow_mut().insert((user.id, id), ()));
+    Ok(())
+}
+
+#[ic_cdk::update(guard = "rate_limit")]
+fn unfollow_organizer(id: u64) -> Result<(), Error> {
+    let user = privacy::caller_user()?;
+    FOLLOW_INDEX
+        .with(|index| index.borrow_mut().remove(&(user.id, id)))
+        .ok_or(Error::NotFound {
+            entity: "follow".to_string(),
+            id: id.to_string(),
+            msg: format!("organizer id:{} is not followed", id),
+        })
+}
+
+// The caller's favorite events, the soonest first
+#[ic_cdk::query]
+fn get_my_favorites(page: u64) -> Result<EventPage, Error> {
+    let user = privacy::caller_user()?;
+    let mut events: Vec<Event> = favorite_event_ids(user.id)
+        .iter()
+        .filter_map(_get_public_event)
+        .collect();
+    events.sort_by_key(start_of);
+    Ok(discovery::page_of(events, page))
+}
+
+// Upcoming events of the organizers the caller follows, the soonest first
+#[ic_cdk::query]
+fn get_feed(page: u64) -> Result<EventPage, Error> {
+    let user = privacy::caller_user()?;
+    let today = dates::civil_from_days((time() / NANOS_PER_DAY) as i64);
+    let mut event_ids: Vec<u64> = followed_organizer_ids(user.id)
+        .into_iter()
+        .flat_map(organizers::organizer_event_ids)
+        .collect();
+    // Events run by several followed organizers show up once
+    event_ids.sort();
+    event_ids.dedup();
+    let mut events: Vec<Event> = event_ids
+        .iter()
+        .filter_map(_get_listed_event)
+        .filter(|event| {
+            event.completed_at.is_none()
+                && dates::parse_date(&event.date).is_some_and(|date| date >= today)
+        })
+        .collect();
+    events.sort_by_key(start_of);
+    Ok(discovery::page_of(events, page))
+}
+
+fn start_of(event: &Event) -> (bool, Option<dates::Date>, Option<u32>) {
+    let date = dates::parse_date(&event.date);
+    (date.is_none(), date, dates::parse_time(&event.start_time))
+}
+
+fn favorite_event_ids(user_id: u64) -> Vec<u64> {
+    FAVORITE_INDEX.with(|index| {
+        index
+            .borrow()
+            .range((user_id, 0)..=(user_id, u64::MAX))
+            .map(|((_, event_id), _)| event_id)
+            .collect()
+    })
+}
+
+fn followed_organizer_ids(user_id: u64) -> Vec<u64> {
+    FOLLOW_INDEX.with(|index| {
+        index
+            .borrow()
+            .range((user_id, 0)..=(user_id, u64::MAX))
+            .map(|((_, organizer_id), _)| organizer_id)
+            .collect()
+    })
+}
+
+// Drop a purged event from every user's favorites
+pub(crate) fn remove_event_favorites(event_id: u64) {
+    let keys: Vec<(u64, u64)> = FAVORITE_INDEX.with(|index| {
+        index
+            .borrow()
+            .iter()
+            .map(|(key, _)| key)
+            .filter(|(_, id)| *id == event_id)
+            .collect()
+    });
+    FAVORITE_INDEX.with(|index| {
+        let mut index = index.borrow_mut();
+        for key in keys {
+            index.remove(&key);
+        }
+    });
+}
+
+pub(crate) fn remove_user_favorites(user_id: u64) {
+    for event_id in favorite_event_ids(user_id) {
+        FAVORITE_INDEX.with(|index| index.borrow_mut().remove(&(user_id, event_id)));
+    }
+    for organizer_id in followed_organizer_ids(user_id) {
+        FOLLOW_INDEX.with(|index| index.borrow_mut().remove(&(user_id, organizer_id)));
+    }
+}
@@ -0,0 +1,379 @@
+use crate::clock::time;
+use crate::discovery::EventCategory;
+use crate::{
+    _get_listed_event, announcements, backup, caller_is_admin, geo, jobs, reschedules, Error,
+    Event, Memory, EVENT_STORAGE, MEMORY_MANAGER,
+};
+use candid::{Decode, Encode, Principal};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, Cell, StableBTreeMap, Storable};
+use std::ops::Bound;
+use std::time::Duration;
+use std::{borrow::Cow, cell::RefCell};
+
+// How often queued summaries are pushed, and how many go in a single call
+const PUSH_INTERVAL: Duration = Duration::from_secs(30);
+const PUSH_BATCH: usize = 50;
+
+// Retries back off exponentially from the base delay, after that the aggregator has to catch up
+// through 'pull_events_since'
+const RETRY_BASE_DELAY: u64 = 30 * 1_000_000_000;
+const MAX_ATTEMPTS: u32 = 8;
+
+// Summaries returned by a single 'pull_events_since' call, unless asked for fewer
+const MAX_PULL_LIMIT: u64 = 100;
+
+// Define a struct for the aggregator canister events are published to
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub struct FederationConfig {
+    // Leave out to stop pushing, the feed can still be pulled
+    aggregator: Option<Principal>,
+}
+
+// Define an enum for where a published event stands
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq)]
+pub enum FederatedStatus {
+    Scheduled,
+    // Waiting on a new date
+    Postponed,
+    Completed,
+    // Deleted by its organizers
+    Cancelled,
+    // Still on, but no longer listed here, e.g. made invite-only or suspended
+    Withdrawn,
+}
+
+// Define a struct for an event as other canisters see it, the same shape whichever canister it
+// comes from
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, PartialEq)]
+pub struct EventSummary {
+    event_id: u64,
+    // Canister the event is kept in, together with the id it identifies the event
+    source: Principal,
+    name: String,
+    date: String,
+    start_time: String,
+    // UTC start in nanoseconds since the epoch, None until the date is announced
+    starts_at: Option<u64>,
+    venue: Option<String>,
+    category: Option<EventCategory>,
+    tags: Vec<String>,
+    capacity: Option<u64>,
+    status: FederatedStatus,
+    // When the summary last changed, unique across summaries so it can be pulled from
+    updated_at: u64,
+}
+
+// Define a struct for a page of the feed of event summaries
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub struct EventFeed {
+    events: Vec<EventSummary>,
+    // Pass as 'since' for the next page, None once the feed is caught up with
+    next_since: Option<u64>,
+}
+
+// Define a struct for a summary waiting to be pushed to the aggregator
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct FederationPush {
+    event_id: u64,
+    // 'updated_at' of the summary to push, a newer summary replaces the push
+    summary_updated_at: u64,
+    attempts: u32,
+    next_attempt_at: u64,
+    last_error: Option<String>,
+}
+
+impl Storable for FederationConfig {
+    // Conversion to bytes
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    // Conversion from bytes
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl Storable for EventSummary {
+    // Conversion to bytes
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    // Conversion from bytes
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl Storable for FederationPush {
+    // Conversion to bytes
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    // Conversion from bytes
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for EventSummary {
+    const MAX_SIZE: u32 = 2048;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl BoundedStorable for FederationPush {
+    const MAX_SIZE: u32 = 1024;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    static CONFIG: RefCell<Cell<FederationConfig, Memory>> = RefCell::new(
+        Cell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(126))),
+            FederationConfig::default(),
+        )
+        .expect("Cannot create the federation config")
+    );
+
+    // Last summary published of each event keyed by event id, kept after the event is gone so
+    // canisters pulling the feed learn it was cancelled
+    static SUMMARY_STORAGE: RefCell<StableBTreeMap<u64, EventSummary, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(127)))
+    ));
+
+    // Event ids keyed by the 'updated_at' of their summary, the order the feed is pulled in
+    static FEED_INDEX: RefCell<StableBTreeMap<u64, u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(128)))
+    ));
+
+    // Summaries to push keyed by event id
+    static PUSH_STORAGE: RefCell<StableBTreeMap<u64, FederationPush, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(129)))
+    ));
+
+    // Set while a push is waiting on the aggregator, so timer ticks don't overlap
+    static PUSHING: std::cell::Cell<bool> = const { std::cell::Cell::new(false) };
+}
+
+#[ic_cdk::query(guard = "caller_is_admin")]
+fn get_federation_config() -> FederationConfig {
+    CONFIG.with(|config| config.borrow().get().clone())
+}
+
+// Set the aggregator to publish to. Summaries queued for another aggregator are dropped, a new
+// one starts from the feed with 'pull_events_since(0)'.
+#[ic_cdk::update(guard = "caller_is_admin")]
+fn set_federation_config(config: FederationConfig) -> Result<FederationConfig, Error> {
+    if config.aggregator.is_some_and(|aggregator| {
+        aggregator == Principal::anonymous() || aggregator == ic_cdk::id()
+    }) {
+        return Err(Error::ValidationFailed {
+            msg: "aggregator must be another canister".to_string(),
+        });
+    }
+    if config.aggregator != get_federation_config().aggregator {
+        PUSH_STORAGE.with(|pushes| {
+            let mut pushes = pushes.borrow_mut();
+            let ids: Vec<u64> = pushes.iter().map(|(id, _)| id).collect();
+            for id in ids {
+                pushes.remove(&id);
+            }
+        });
+    }
+    CONFIG
+        .with(|cell| cell.borrow_mut().set(config.clone()))
+        .map_err(|_| Error::InternalError {
+            msg: "federation config could not be saved".to_string(),
+            ticket_id: None,
+        })?;
+    Ok(config)
+}
+
+// Summaries waiting to be pushed to the aggregator, with the error of their last attempt
+#[ic_cdk::query(guard = "caller_is_admin")]
+fn get_pending_pushes() -> Vec<FederationPush> {
+    PUSH_STORAGE.with(|pushes| pushes.borrow().iter().map(|(_, push)| push).collect())
+}
+
+// Summaries of the listed events that changed after 'since', oldest change first, for other
+// canisters to sync from. Events that were cancelled or withdrawn stay in the feed with that status.
+#[ic_cdk::query]
+fn pull_events_since(since: u64, limit: Option<u64>) -> EventFeed {
+    let limit = limit.unwrap_or(MAX_PULL_LIMIT).clamp(1, MAX_PULL_LIMIT) as usize;
+    let event_ids: Vec<(u64, u64)> = FEED_INDEX.with(|index| {
+        index
+            .borrow()
+            .range((Bound::Excluded(since), Bound::Unbounded))
+            .take(limit + 1)
+            .collect()
+    });
+    let more = event_ids.len() > limit;
+    let events: Vec<EventSummary> = event_ids
+        .iter()
+        .take(limit)
+        .filter_map(|(_, event_id)| _get_summary(*event_id))
+        .collect();
+
+    EventFeed {
+        next_since: events
+            .last()
+            .filter(|_| more)
+            .map(|summary| summary.updated_at),
+        events,
+    }
+}
+
+// Publish the summary of an event if it changed, called on every change to an event. Only
+// changes others can see make it to the feed, sales and check-ins don't.
+pub(crate) fn track_event(event_id: u64) {
+    let previous = _get_summary(event_id);
+    let stored = EVENT_STORAGE.with(|events| events.borrow().get(&event_id));
+    let mut summary = match (stored, previous.clone()) {
+        (Some(event), _) if event.deleted_at.is_none() => match _get_listed_event(&event_id) {
+            Some(event) => summarize(&event),
+            None => match previous.clone() {
+                Some(previous) => with_status(previous, FederatedStatus::Withdrawn),
+                None => return,
+            },
+        },
+        (Some(_), Some(previous)) => with_status(previous, FederatedStatus::Cancelled),
+        // Completed events leave the live maps when they are archived, they stay completed
+        (None, Some(previous)) if previous.status != FederatedStatus::Completed => {
+            with_status(previous, FederatedStatus::Cancelled)
+        }
+        _ => return,
+    };
+
+    // Compared without the stamp, as it's only set on a change
+    summary.updated_at = previous.as_ref().map_or(0, |previous| previous.updated_at);
+    if previous.as_ref() == Some(&summary) {
+        return;
+    }
+    summary.updated_at = next_stamp();
+    FEED_INDEX.with(|index| {
+        let mut index = index.borrow_mut();
+        if let Some(previous) = &previous {
+            index.remove(&previous.updated_at);
+        }
+        index.insert(summary.updated_at, event_id);
+    });
+    SUMMARY_STORAGE.with(|summaries| summaries.borrow_mut().insert(event_id, summary.clone()));
+
+    if get_federation_config().aggregator.is_some() {
+        let push = FederationPush {
+            event_id,
+            summary_updated_at: summary.updated_at,
+            attempts: 0,
+            next_attempt_at: time(),
+            last_error: None,
+        };
+        PUSH_STORAGE.with(|pushes| pushes.borrow_mut().insert(event_id, push));
+    }
+}
+
+// Start the timer pushing queued summaries, timers don't survive upgrades
+pub(crate) fn schedule_pushes() {
+    ic_cdk_timers::set_timer_interval(PUSH_INTERVAL, || ic_cdk::spawn(push_summaries()));
+}
+
+fn _get_summary(event_id: u64) -> Option<EventSummary> {
+    SUMMARY_STORAGE.with(|summaries| summaries.borrow().get(&event_id))
+}
+
+fn summarize(event: &Event) -> EventSummary {
+    let status = if event.completed_at.is_some() {
+        FederatedStatus::Completed
+    } else if reschedules::is_postponed(event.id) {
+        FederatedStatus::Postponed
+    } else {
+        FederatedStatus::Scheduled
+    };
+    EventSummary {
+        event_id: event.id,
+        source: ic_cdk::id(),
+        name: event.name.clone(),
+        date: event.date.clone(),
+        start_time: event.start_time.clone(),
+        starts_at: jobs::event_start(event).filter(|_| announcements::date_is_final(event)),
+        venue: event
+            .venue
+            .as_ref()
+            .map(|venue| geo::venue_label(Some(venue))),
+        category: event.category,
+        tags: event.tags.clone().unwrap_or_default(),
+        capacity: event.capacity,
+        status,
+        updated_at: 0,
+    }
+}
+
+fn with_status(summary: EventSummary, status: FederatedStatus) -> EventSummary {
+    EventSummary { status, ..summary }
+}
+
+// The time of the call, moved past the latest stamp so summaries changed in the same call can
+// still be told apart
+fn next_stamp() -> u64 {
+    let latest = FEED_INDEX.with(|index| index.borrow().last_key_value().map(|(stamp, _)| stamp));
+    latest.map_or(time(), |latest| time().max(latest + 1))
+}
+
+// Push the queued summaries that are due in a single call, rescheduling them if it fails
+async fn push_summaries() {
+    if backup::in_maintenance() {
+        return;
+    }
+    let Some(aggregator) = get_federation_config().aggregator else {
+        return;
+    };
+    if PUSHING.with(|pushing| pushing.replace(true)) {
+        return;
+    }
+
+    let now = time();
+    let due: Vec<FederationPush> = PUSH_STORAGE.with(|pushes| {
+        pushes
+            .borrow()
+            .iter()
+            .map(|(_, push)| push)
+            .filter(|push| push.next_attempt_at <= now)
+            .take(PUSH_BATCH)
+            .collect()
+    });
+    let summaries: Vec<EventSummary> = due
+        .iter()
+        .filter_map(|push| _get_summary(push.event_id))
+        .collect();
+    if summaries.is_empty() {
+        PUSHING.with(|pushing| pushing.set(false));
+        return;
+    }
+
+    let result: Result<(), String> =
+        ic_cdk::call::<_, ()>(aggregator, "ingest_events", (summaries,))
+            .await
+            .map_err(|(_, msg)| format!("aggregator could not be reached: {}", msg));
+    for mut push in due {
+        // The event changed while the call was out, its newer summary is pushed next
+        let current = PUSH_STORAGE.with(|pushes| pushes.borrow().get(&push.event_id));
+        if current.map(|current| current.summary_updated_at) != Some(push.summary_updated_at) {
+            continue;
+        }
+        push.attempts += 1;
+        match &result {
+            Err(error) if push.attempts < MAX_ATTEMPTS => {
+                push.last_error = Some(error.clone());
+                push.next_attempt_at = time() + (RETRY_BASE_DELAY << (push.attempts - 1));
+                PUSH_STORAGE.with(|pushes| pushes.borrow_mut().insert(push.event_id, push));
+            }
+            _ => {
+                PUSH_STORAGE.with(|pushes| pushes.borrow_mut().remove(&push.event_id));
+            }
+        }
+    }
+
+    PUSHING.with(|pushing| pushing.set(false));
+}
@@ -0,0 +1,397 @@
+use crate::clock::time;
+use crate::promo::{self, Discount};
+use crate::ratelimit::rate_limit;
+use crate::tiers::_get_tier;
+use crate::{_get_event, organizers, Error, Memory, TicketPayload, ID_COUNTER, MEMORY_MANAGER};
+use candid::{Decode, Encode};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use std::{borrow::Cow, cell::RefCell};
+
+const MAX_NAME_LENGTH: usize = 100;
+
+// Define a struct for a time-boxed sale of a discounted allocation of tickets
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct FlashSale {
+    id: u64,
+    event_id: u64,
+    // None puts every tier of the event on sale
+    tier_id: Option<u64>,
+    name: String,
+    discount: Discount,
+    // Tickets sold at the discount, the sale is over once they are gone
+    allocation: u64,
+    // Discounted tickets a user can buy, further tickets are sold at the full price
+    per_user_limit: u64,
+    starts_at: u64,
+    ends_at: u64,
+    created_at: u64,
+    updated_at: Option<u64>,
+}
+
+// Define a struct for the payload used to create and update flash sales
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub struct FlashSalePayload {
+    tier_id: Option<u64>,
+    name: String,
+    discount: Discount,
+    allocation: u64,
+    per_user_limit: u64,
+    starts_at: u64,
+    ends_at: u64,
+}
+
+// Define a struct for the discounted tickets sold, and held by purchases still being paid
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub struct FlashCounter {
+    sold: u64,
+    held: u64,
+}
+
+// Define a struct for a flash sale and what is left of its allocation
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub struct FlashSaleStatus {
+    sale: FlashSale,
+    sold: u64,
+    remaining: u64,
+}
+
+// Place in a flash sale held by a purchase while it is paid
+pub(crate) struct FlashHold {
+    sale_id: u64,
+    user_id: u64,
+}
+
+impl Storable for FlashSale {
+    // Conversion to bytes
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    // Conversion from bytes
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for FlashSale {
+    const MAX_SIZE: u32 = 512;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl Storable for FlashCounter {
+    // Conversion to bytes
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    // Conversion from bytes
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for FlashCounter {
+    const MAX_SIZE: u32 = 64;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    // Flash sales keyed by (event id, sale id)
+    static SALE_STORAGE: RefCell<StableBTreeMap<(u64, u64), FlashSale, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(96)))
+    ));
+
+    // Allocation counters keyed by sale id, kept apart from the sales so changing a sale
+    // never writes over a count
+    static COUNTER_STORAGE: RefCell<StableBTreeMap<u64, FlashCounter, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(97)))
+    ));
+
+    // Counters of each buyer keyed by (sale id, user id), for the per-user limit
+    static USER_COUNTER_STORAGE: RefCell<StableBTreeMap<(u64, u64), FlashCounter, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(98)))
+    ));
+}
+
+#[ic_cdk::query]
+fn get_flash_sales(event_id: u64) -> Result<Vec<FlashSaleStatus>, Error> {
+    _get_event(&event_id).ok_or(Error::not_found("event", event_id))?;
+    Ok(event_sales(event_id)
+        .into_iter()
+        .map(|sale| {
+            let counter = sale_counter(sale.id);
+            FlashSaleStatus {
+                sold: counter.sold,
+                remaining: sale.allocation.saturating_sub(counter.sold + counter.held),
+                sale,
+            }
+        })
+        .collect())
+}
+
+#[ic_cdk::update(guard = "rate_limit")]
+fn create_flash_sale(event_id: u64, payload: FlashSalePayload) -> Result<FlashSale, Error> {
+    organizers::authorize_event(event_id)?;
+    validate_sale(event_id, None, &payload).map_err(|msg| Error::ValidationFailed { msg })?;
+
+    // Increment the global ID counter to get a new ID for the flash sale
+    let id = ID_COUNTER
+        .with(|counter| {
+            let current_id = *counter.borrow().get();
+            counter.borrow_mut().set(current_id + 1)
+        })
+        .expect("Cannot increment Ids");
+    let sale = FlashSale {
+        id,
+        event_id,
+        tier_id: payload.tier_id,
+        name: payload.name,
+        discount: payload.discount,
+        allocation: payload.allocation,
+        per_user_limit: payload.per_user_limit,
+        starts_at: payload.starts_at,
+        ends_at: payload.ends_at,
+        created_at: time(),
+        updated_at: None,
+    };
+    SALE_STORAGE.with(|sales| sales.borrow_mut().insert((event_id, id), sale.clone()));
+    Ok(sale)
+}
+
+// Change a flash sale, its allocation can't go below the tickets sold and held
+#[ic_cdk::update(guard = "rate_limit")]
+fn update_flash_sale(
+    event_id: u64,
+    sale_id: u64,
+    payload: FlashSalePayload,
+) -> Result<FlashSale, Error> {
+    organizers::authorize_event(event_id)?;
+    let sale = get_sale(event_id, sale_id)?;
+    validate_sale(event_id, Some(sale_id), &payload)
+        .map_err(|msg| Error::ValidationFailed { msg })?;
+    let counter = sale_counter(sale_id);
+    if payload.allocation < counter.sold + counter.held {
+        return Err(Error::ValidationFailed {
+            msg: format!(
+                "{} tickets of flash sale id:{} are sold or being paid for",
+                counter.sold + counter.held,
+                sale_id
+            ),
+        });
+    }
+    let sale = FlashSale {
+        tier_id: payload.tier_id,
+        name: payload.name,
+        discount: payload.discount,
+        allocation: payload.allocation,
+        per_user_limit: payload.per_user_limit,
+        starts_at: payload.starts_at,
+        ends_at: payload.ends_at,
+        updated_at: Some(time()),
+        ..sale
+    };
+    SALE_STORAGE.with(|sales| sales.borrow_mut().insert((event_id, sale_id), sale.clone()));
+    Ok(sale)
+}
+
+// End a flash sale early, purchases still being paid at its price are refunded
+#[ic_cdk::update(guard = "rate_limit")]
+fn cancel_flash_sale(event_id: u64, sale_id: u64) -> Result<FlashSale, Error> {
+    organizers::authorize_event(event_id)?;
+    let mut sale = get_sale(event_id, sale_id)?;
+    let now = time();
+    sale.ends_at = sale.ends_at.min(now);
+    sale.starts_at = sale.starts_at.min(sale.ends_at);
+    sale.updated_at = Some(now);
+    SALE_STORAGE.with(|sales| sales.borrow_mut().insert((event_id, sale_id), sale.clone()));
+    Ok(sale)
+}
+
+fn validate_sale(
+    event_id: u64,
+    sale_id: Option<u64>,
+    payload: &FlashSalePayload,
+) -> Result<(), String> {
+    if payload.name.trim().is_empty() || payload.name.len() > MAX_NAME_LENGTH {
+        return Err(format!(
+            "flash sale name must be 1 to {} characters",
+            MAX_NAME_LENGTH
+        ));
+    }
+    if let Discount::Percent { percent } = payload.discount {
+        if percent == 0 || percent > 100 {
+            return Err(format!(
+                "discount of {}% is not between 1% and 100%",
+                percent
+            ));
+        }
+    }
+    if payload.allocation == 0 || payload.per_user_limit == 0 {
+        return Err("allocation and per-user limit must be at least 1".to_string());
+    }
+    if payload.ends_at <= payload.starts_at {
+        return Err("flash sale must end after it starts".to_string());
+    }
+    if let Some(tier_id) = payload.tier_id {
+        _get_tier(event_id, tier_id).ok_or(format!(
+            "tier id:{} does not exist for event id:{}",
+            tier_id, event_id
+        ))?;
+    }
+    // A ticket is only ever on one flash sale at a time
+    let overlapping = event_sales(event_id).into_iter().find(|sale| {
+        Some(sale.id) != sale_id
+            && covers(sale.tier_id, payload.tier_id)
+            && sale.starts_at < payload.ends_at
+            && payload.starts_at < sale.ends_at
+    });
+    if let Some(sale) = overlapping {
+        return Err(format!(
+            "flash sale id:{} runs at the same time for the same tickets",
+            sale.id
+        ));
+    }
+    Ok(())
+}
+
+// Whether sales of two tier scopes take the same tickets
+fn covers(a: Option<u64>, b: Option<u64>) -> bool {
+    a.is_none() || b.is_none() || a == b
+}
+
+fn get_sale(event_id: u64, sale_id: u64) -> Result<FlashSale, Error> {
+    SALE_STORAGE
+        .with(|sales| sales.borrow().get(&(event_id, sale_id)))
+        .ok_or(Error::NotFound {
+            entity: "flash sale".to_string(),
+            id: sale_id.to_string(),
+            msg: format!(
+                "flash sale id:{} of event id:{} does not exist",
+                sale_id, event_id
+            ),
+        })
+}
+
+fn event_sales(event_id: u64) -> Vec<FlashSale> {
+    SALE_STORAGE.with(|sales| {
+        sales
+            .borrow()
+            .range((event_id, 0)..=(event_id, u64::MAX))
+            .map(|(_, sale)| sale)
+            .collect()
+    })
+}
+
+// Flash sale running now for a ticket of an event
+fn active_sale(event_id: u64, tier_id: Option<u64>) -> Option<FlashSale> {
+    let now = time();
+    event_sales(event_id).into_iter().find(|sale| {
+        sale.starts_at <= now
+            && now < sale.ends_at
+            && sale.tier_id.is_none_or(|id| Some(id) == tier_id)
+    })
+}
+
+fn sale_counter(sale_id: u64) -> FlashCounter {
+    COUNTER_STORAGE
+        .with(|counters| counters.borrow().get(&sale_id))
+        .unwrap_or_default()
+}
+
+fn user_counter(sale_id: u64, user_id: u64) -> FlashCounter {
+    USER_COUNTER_STORAGE
+        .with(|counters| counters.borrow().get(&(sale_id, user_id)))
+        .unwrap_or_default()
+}
+
+fn update_counters(sale_id: u64, user_id: u64, f: impl Fn(&mut FlashCounter)) {
+    let mut counter = sale_counter(sale_id);
+    f(&mut counter);
+    COUNTER_STORAGE.with(|counters| counters.borrow_mut().insert(sale_id, counter));
+    let mut counter = user_counter(sale_id, user_id);
+    f(&mut counter);
+    USER_COUNTER_STORAGE.with(|counters| counters.borrow_mut().insert((sale_id, user_id), counter));
+}
+
+// Price of a ticket on a flash sale running for it, with the sale's id. The places held by
+// the buyer's own purchases are theirs, so a paid purchase always finds the place it held.
+pub(crate) fn flash_price(
+    event_id: u64,
+    tier_id: Option<u64>,
+    user_id: u64,
+    price: u64,
+) -> Option<(u64, u64)> {
+    let sale = active_sale(event_id, tier_id)?;
+    let counter = sale_counter(sale.id);
+    let user = user_counter(sale.id, user_id);
+    let free = sale.allocation + user.held > counter.sold + counter.held;
+    if !free || user.sold >= sale.per_user_limit {
+        return None;
+    }
+    Some((sale.id, promo::discounted(&sale.discount, price)))
+}
+
+// Hold a place in the flash sales running for the tickets of a purchase before it is paid.
+// All the counting happens here without awaiting, so concurrent purchases can't hold the
+// same place. Tickets beyond the allocation or the buyer's limit are sold at the full price.
+pub(crate) fn hold(payloads: &[TicketPayload]) -> Result<Vec<FlashHold>, String> {
+    let mut holds: Vec<FlashHold> = vec![];
+    for payload in payloads {
+        let Some(sale) = active_sale(payload.event_id, payload.tier_id) else {
+            continue;
+        };
+        let counter = sale_counter(sale.id);
+        let user = user_counter(sale.id, payload.user_id);
+        if counter.sold + counter.held >= sale.allocation
+            || user.sold + user.held >= sale.per_user_limit
+        {
+            continue;
+        }
+        if holds.iter().any(|hold| hold.sale_id == sale.id) {
+            release(holds);
+            return Err(format!(
+                "tickets of flash sale id:{} are bought one per purchase",
+                sale.id
+            ));
+        }
+        update_counters(sale.id, payload.user_id, |counter| counter.held += 1);
+        holds.push(FlashHold {
+            sale_id: sale.id,
+            user_id: payload.user_id,
+        });
+    }
+    Ok(holds)
+}
+
+// Give back the places a purchase held once it is done, sold or not
+pub(crate) fn release(holds: Vec<FlashHold>) {
+    for hold in holds {
+        update_counters(hold.sale_id, hold.user_id, |counter| {
+            counter.held = counter.held.saturating_sub(1)
+        });
+    }
+}
+
+// Count a ticket sold at a flash sale's price
+pub(crate) fn record_sale(sale_id: u64, user_id: u64) {
+    update_counters(sale_id, user_id, |counter| counter.sold += 1);
+}
+
+pub(crate) fn remove_event_flash_sales(event_id: u64) {
+    for sale in event_sales(event_id) {
+        SALE_STORAGE.with(|sales| sales.borrow_mut().remove(&(event_id, sale.id)));
+        COUNTER_STORAGE.with(|counters| counters.borrow_mut().remove(&sale.id));
+        USER_COUNTER_STORAGE.with(|counters| {
+            let mut counters = counters.borrow_mut();
+            let keys: Vec<(u64, u64)> = counters
+                .range((sale.id, 0)..=(sale.id, u64::MAX))
+                .map(|(key, _)| key)
+                .collect();
+            for key in keys {
+                counters.remove(&key);
+            }
+        });
+    }
+}
@@ -0,0 +1,322 @@
+use crate::clock::time;
+use crate::ratelimit::rate_limit;
+use crate::{
+    _get_user, organizers, user_ticket_count, Error, Event, Memory, Ticket, MEMORY_MANAGER,
+};
+use candid::{Decode, Encode, Principal};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use std::collections::HashMap;
+use std::{borrow::Cow, cell::RefCell};
+
+const NANOS_PER_SECOND: u64 = 1_000_000_000;
+// More purchases than this from one principal within the window are flagged
+const RAPID_PURCHASES: usize = 5;
+const RAPID_WINDOW_SECS: u64 = 60;
+// Failed check-ins of one ticket before it is flagged
+const FAILED_CHECK_INS: u64 = 3;
+// Accounts listed in a shared email signal, so a flag fits in its stable map entry
+const MAX_LINKED_USERS: usize = 10;
+
+// Define an enum for why a ticket was flagged
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, PartialEq)]
+pub enum FraudSignal {
+    // Accounts whose emails only differ by dots, '+' tags or trailing digits on the same domain
+    // hold more of the event's tickets together than one user may buy
+    SharedEmailPattern {
+        pattern: String,
+        user_ids: Vec<u64>,
+        tickets: u64,
+        limit: u64,
+    },
+    // One principal bought more often than a person would in a short time
+    RapidPurchases {
+        purchases: u64,
+        window_secs: u64,
+    },
+    // The ticket's code was turned down at the door again and again
+    FailedCheckIns {
+        attempts: u64,
+    },
+}
+
+// Define an enum for where the review of a flagged ticket is at
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq)]
+pub enum FlagStatus {
+    // Waiting for an organizer, the ticket can't be transferred or resold meanwhile
+    PendingReview,
+    // Looked into and found fine
+    Cleared,
+    // Looked into and found fraudulent, the ticket stays locked
+    Confirmed,
+}
+
+// Define a struct for a ticket flagged as suspicious
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct FraudFlag {
+    ticket_id: u64,
+    event_id: u64,
+    user_id: u64,
+    signals: Vec<FraudSignal>,
+    status: FlagStatus,
+    flagged_at: u64,
+    reviewed_at: Option<u64>,
+    reviewed_by: Option<Principal>,
+}
+
+impl Storable for FraudFlag {
+    // Conversion to bytes
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    // Conversion from bytes
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for FraudFlag {
+    const MAX_SIZE: u32 = 1024;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    // Flags keyed by (event id, ticket id)
+    static FLAG_STORAGE: RefCell<StableBTreeMap<(u64, u64), FraudFlag, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(138)))
+    ));
+
+    // Failed check-ins keyed by (event id, ticket id)
+    static FAILED_CHECK_IN_STORAGE: RefCell<StableBTreeMap<(u64, u64), u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(139)))
+    ));
+
+    // Recent purchase times per principal, kept on the heap as they only matter for a minute
+    static RECENT_PURCHASES: RefCell<HashMap<Principal, Vec<u64>>> = RefCell::new(HashMap::new());
+}
+
+#[ic_cdk::query]
+fn get_flagged_tickets(event_id: u64, status: Option<FlagStatus>) -> Result<Vec<FraudFlag>, Error> {
+    organizers::authorize_event(event_id)?;
+    Ok(FLAG_STORAGE.with(|flags| {
+        flags
+            .borrow()
+            .range((event_id, 0)..=(event_id, u64::MAX))
+            .map(|(_, flag)| flag)
+            .filter(|flag| status.is_none_or(|status| flag.status == status))
+            .collect()
+    }))
+}
+
+// Settle the review of a flagged ticket, a cleared ticket can change hands again
+#[ic_cdk::update(guard = "rate_limit")]
+fn review_fraud_flag(
+    event_id: u64,
+    ticket_id: u64,
+    status: FlagStatus,
+) -> Result<FraudFlag, Error> {
+    organizers::authorize_event(event_id)?;
+    let mut flag = _get_flag(event_id, ticket_id).ok_or(Error::NotFound {
+        entity: "fraud flag".to_string(),
+        id: ticket_id.to_string(),
+        msg: format!(
+            "ticket id:{} of event id:{} is not flagged",
+            ticket_id, event_id
+        ),
+    })?;
+    if status == FlagStatus::PendingReview {
+        return Err(Error::ValidationFailed {
+            msg: "a review settles a flag as cleared or confirmed".to_string(),
+        });
+    }
+
+    flag.status = status;
+    flag.reviewed_at = Some(time());
+    flag.reviewed_by = Some(ic_cdk::caller());
+    save(flag.clone());
+    Ok(flag)
+}
+
+// Look a sold ticket over for signs of fraud, flagging it for review if any show
+pub(crate) fn screen_purchase(ticket: &Ticket, event: &Event) {
+    let mut signals = vec![];
+    // Organizers and admins sell on behalf of others, e.g. at the box office
+    if organizers::check_event_organizer(event).is_err() {
+        if let Some(purchases) = record_purchase(ic_cdk::caller()) {
+            signals.push(FraudSignal::RapidPurchases {
+                purchases,
+                window_secs: RAPID_WINDOW_SECS,
+            });
+        }
+    }
+    if let Some(signal) = shared_email_signal(ticket.user_id, event) {
+        signals.push(signal);
+    }
+    for signal in signals {
+        flag_ticket(ticket, signal);
+    }
+}
+
+// Count a check-in of a ticket that was turned down, flagging the ticket once it adds up
+pub(crate) fn record_failed_check_in(ticket: &Ticket) {
+    let key = (ticket.event_id, ticket.id);
+    let attempts = FAILED_CHECK_IN_STORAGE.with(|failures| {
+        let mut failures = failures.borrow_mut();
+        let attempts = failures.get(&key).unwrap_or(0) + 1;
+        failures.insert(key, attempts);
+        attempts
+    });
+    if attempts >= FAILED_CHECK_INS {
+        flag_ticket(ticket, FraudSignal::FailedCheckIns { attempts });
+    }
+}
+
+// Check a ticket isn't held back by a fraud review before it changes hands
+pub(crate) fn check_not_flagged(ticket: &Ticket) -> Result<(), String> {
+    match _get_flag(ticket.event_id, ticket.id) {
+        Some(flag) if flag.status != FlagStatus::Cleared => Err(format!(
+            "ticket id:{} is held for a fraud review by the organizers",
+            ticket.id
+        )),
+        _ => Ok(()),
+    }
+}
+
+// Drop the flags and failed check-ins of a deleted event
+pub(crate) fn remove_event_fraud(event_id: u64) {
+    let range = (event_id, 0)..=(event_id, u64::MAX);
+    let flagged: Vec<(u64, u64)> = FLAG_STORAGE.with(|flags| {
+        flags
+            .borrow()
+            .range(range.clone())
+            .map(|(key, _)| key)
+            .collect()
+    });
+    FLAG_STORAGE.with(|flags| {
+        let mut flags = flags.borrow_mut();
+        for key in &flagged {
+            flags.remove(key);
+        }
+    });
+    let failed: Vec<(u64, u64)> = FAILED_CHECK_IN_STORAGE
+        .with(|failures| failures.borrow().range(range).map(|(key, _)| key).collect());
+    FAILED_CHECK_IN_STORAGE.with(|failures| {
+        let mut failures = failures.borrow_mut();
+        for key in &failed {
+            failures.remove(key);
+        }
+    });
+}
+
+fn _get_flag(event_id: u64, ticket_id: u64) -> Option<FraudFlag> {
+    FLAG_STORAGE.with(|flags| flags.borrow().get(&(event_id, ticket_id)))
+}
+
+fn save(flag: FraudFlag) {
+    FLAG_STORAGE.with(|flags| {
+        flags
+            .borrow_mut()
+            .insert((flag.event_id, flag.ticket_id), flag)
+    });
+}
+
+// Add a signal to a ticket's flag, a new signal puts a cleared ticket back up for review
+fn flag_ticket(ticket: &Ticket, signal: FraudSignal) {
+    let now = time();
+    let mut flag = _get_flag(ticket.event_id, ticket.id).unwrap_or(FraudFlag {
+        ticket_id: ticket.id,
+        event_id: ticket.event_id,
+        user_id: ticket.user_id,
+        signals: vec![],
+        status: FlagStatus::PendingReview,
+        flagged_at: now,
+        reviewed_at: None,
+        reviewed_by: None,
+    });
+    if flag.status == FlagStatus::Confirmed {
+        return;
+    }
+    // Repeated signals of a kind replace the earlier one, e.g. a growing failed check-in count
+    let known = flag
+        .signals
+        .iter()
+        .position(|existing| std::mem::discriminant(existing) == std::mem::discriminant(&signal));
+    match known {
+        Some(index) if flag.signals[index] == signal => return,
+        Some(index) => flag.signals[index] = signal,
+        None => flag.signals.push(signal),
+    }
+    if flag.status == FlagStatus::Cleared {
+        flag.status = FlagStatus::PendingReview;
+        flag.flagged_at = now;
+    }
+    save(flag);
+}
+
+// Note a purchase by a principal, returning how many it made within the window if too many.
+// Tickets bought in one call, e.g. an order, count as one purchase.
+fn record_purchase(caller: Principal) -> Option<u64> {
+    let now = time();
+    let window = RAPID_WINDOW_SECS * NANOS_PER_SECOND;
+    RECENT_PURCHASES.with(|recent| {
+        let mut recent = recent.borrow_mut();
+        // Principals quiet for a window are forgotten, so the map doesn't grow
+        recent.retain(|_, times| {
+            times
+                .last()
+                .is_some_and(|last| now.saturating_sub(*last) < window)
+        });
+        let times = recent.entry(caller).or_default();
+        if times.last() != Some(&now) {
+            times.push(now);
+        }
+        times.retain(|at| now.saturating_sub(*at) < window);
+        (times.len() > RAPID_PURCHASES).then_some(times.len() as u64)
+    })
+}
+
+// Accounts of an event's attendees sharing the buyer's email pattern, if together they hold
+// more tickets than one user may buy
+fn shared_email_signal(user_id: u64, event: &Event) -> Option<FraudSignal> {
+    let limit = event.max_tickets_per_user?;
+    let pattern = email_pattern(&_get_user(&user_id)?.email)?;
+    let user_ids: Vec<u64> = event
+        .attendee_ids
+        .iter()
+        .filter(|attendee_id| {
+            **attendee_id == user_id
+                || _get_user(attendee_id)
+                    .and_then(|attendee| email_pattern(&attendee.email))
+                    .is_some_and(|attendee_pattern| attendee_pattern == pattern)
+        })
+        .copied()
+        .collect();
+    if user_ids.len() < 2 {
+        return None;
+    }
+    let tickets: u64 = user_ids
+        .iter()
+        .map(|id| user_ticket_count(event.id, *id))
+        .sum();
+    (tickets > limit).then(|| FraudSignal::SharedEmailPattern {
+        pattern,
+        user_ids: user_ids.into_iter().take(MAX_LINKED_USERS).collect(),
+        tickets,
+        limit,
+    })
+}
+
+// Email with the local part stripped of dots, a '+' tag and trailing digits, e.g.
+// 'j.doe+2@mail.com' and 'jdoe7@mail.com' are both 'jdoe@mail.com'
+fn email_pattern(email: &str) -> Option<String> {
+    let email = email.trim().to_lowercase();
+    let (local, domain) = email.rsplit_once('@')?;
+    let local = local.split('+').next().unwrap_or_default().replace('.', "");
+    let local = local.trim_end_matches(|c: char| c.is_ascii_digit());
+    if local.is_empty() || domain.is_empty() {
+        return None;
+    }
+    Some(format!("{}@{}", local, domain))
+}
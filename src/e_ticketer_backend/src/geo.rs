@@ -0,0 +1,356 @@
+use crate::{
+    _get_listed_event, _get_stored_event, backup, certification, store_event, Event, Memory,
+    StringKey, MEMORY_MANAGER,
+};
+use candid::{Decode, Encode};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use std::{borrow::Cow, cell::RefCell, time::Duration};
+
+// Venue details are kept small, as they are stored within the size bound of an event
+const MAX_VENUE_NAME_LEN: usize = 64;
+const MAX_VENUE_ADDRESS_LEN: usize = 128;
+
+// Events are indexed at cells of about 1.2 by 0.6 km, searches widen to coarser prefixes
+const GEOHASH_PRECISION: usize = 6;
+const GEOHASH_ALPHABET: &[u8; 32] = b"0123456789bcdefghjkmnpqrstuvwxyz";
+const EARTH_RADIUS_KM: f64 = 6371.0;
+const KM_PER_DEGREE: f64 = 111.32;
+const MAX_RADIUS_KM: f64 = 500.0;
+
+const PAGE_SIZE: usize = 20;
+// Events stored before venues existed, migrated per timer tick
+const MIGRATION_BATCH: usize = 100;
+
+// Define an enum for the accessibility features a venue or event can offer
+#[derive(
+    candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord,
+)]
+pub enum AccessibilityFeature {
+    WheelchairAccess,
+    HearingLoop,
+    StepFree,
+    QuietRoom,
+}
+
+// Define a struct for the place an event is held at
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, PartialEq)]
+pub struct Venue {
+    name: String,
+    address: String,
+    // Both set or both left out, a venue without coordinates isn't found by nearby search
+    lat: Option<f64>,
+    lng: Option<f64>,
+    // Features of the building, None on venues stored before accessibility
+    accessibility: Option<Vec<AccessibilityFeature>>,
+}
+
+// Define a struct for an event along with its distance from the searched point
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub struct NearbyEvent {
+    event: Event,
+    distance_km: f64,
+}
+
+// Define a struct for a page of events near a point, closest first
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub struct NearbyEventPage {
+    events: Vec<NearbyEvent>,
+    page: u64,
+    // Number of events within the radius over all pages
+    total: u64,
+}
+
+// Events as stored before venues replaced the free-form location, only read to migrate them
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct LegacyEvent {
+    location: Option<String>,
+}
+
+impl Storable for LegacyEvent {
+    // Conversion to bytes
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    // Conversion from bytes
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for LegacyEvent {
+    const MAX_SIZE: u32 = 1024;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    // Events with coordinates keyed by (geohash, event id)
+    static GEO_INDEX: RefCell<StableBTreeMap<(StringKey, u64), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(36)))
+    ));
+}
+
+#[ic_cdk::query]
+fn get_events_near(lat: f64, lng: f64, radius_km: f64, page: u64) -> NearbyEventPage {
+    let mut events: Vec<NearbyEvent> = match check_point(lat, lng) {
+        Ok(()) if radius_km > 0.0 && radius_km <= MAX_RADIUS_KM => {
+            search_prefixes(lat, lng, radius_km)
+                .iter()
+                .flat_map(|prefix| indexed_events(prefix))
+                .filter_map(|event_id| _get_listed_event(&event_id))
+                .filter_map(|event| {
+                    let (event_lat, event_lng) = coordinates(event.venue.as_ref()?)?;
+                    let distance_km = distance_km(lat, lng, event_lat, event_lng);
+                    (distance_km <= radius_km).then_some(NearbyEvent { event, distance_km })
+                })
+                .collect()
+        }
+        _ => vec![],
+    };
+    events.sort_by(|a, b| a.distance_km.total_cmp(&b.distance_km));
+
+    NearbyEventPage {
+        total: events.len() as u64,
+        events: events
+            .into_iter()
+            .skip((page as usize).saturating_mul(PAGE_SIZE))
+            .take(PAGE_SIZE)
+            .collect(),
+        page,
+    }
+}
+
+// Check the details of a venue, a venue left out is to be announced
+pub(crate) fn validate_venue(venue: &Venue) -> Result<(), String> {
+    if venue.name.trim().is_empty() || venue.name.len() > MAX_VENUE_NAME_LEN {
+        return Err(format!(
+            "venue name must be 1 to {} bytes",
+            MAX_VENUE_NAME_LEN
+        ));
+    }
+    if venue.address.len() > MAX_VENUE_ADDRESS_LEN {
+        return Err(format!(
+            "venue address must be at most {} bytes",
+            MAX_VENUE_ADDRESS_LEN
+        ));
+    }
+    // Kept within the size bound of an event
+    if let Some(features) = &venue.accessibility {
+        if normalize_accessibility(features).len() != features.len() {
+            return Err("venue accessibility features must not repeat".to_string());
+        }
+    }
+    match (venue.lat, venue.lng) {
+        (Some(lat), Some(lng)) => check_point(lat, lng),
+        (None, None) => Ok(()),
+        _ => Err("venue latitude and longitude must be given together".to_string()),
+    }
+}
+
+// Sort features and drop repeated ones
+pub(crate) fn normalize_accessibility(
+    features: &[AccessibilityFeature],
+) -> Vec<AccessibilityFeature> {
+    let mut features = features.to_vec();
+    features.sort();
+    features.dedup();
+    features
+}
+
+// Features offered at an event, its own along with those of its venue
+pub(crate) fn event_accessibility(event: &Event) -> Vec<AccessibilityFeature> {
+    let venue = event
+        .venue
+        .iter()
+        .flat_map(|venue| venue.accessibility.iter().flatten());
+    let features: Vec<AccessibilityFeature> = event
+        .accessibility
+        .iter()
+        .flatten()
+        .chain(venue)
+        .copied()
+        .collect();
+    normalize_accessibility(&features)
+}
+
+// How a venue reads in notifications
+pub(crate) fn venue_label(venue: Option<&Venue>) -> String {
+    match venue {
+        Some(venue) if venue.address.is_empty() => venue.name.clone(),
+        Some(venue) => format!("{}, {}", venue.name, venue.address),
+        None => "venue to be announced".to_string(),
+    }
+}
+
+// Add an event to the index under the geohash of its venue
+pub(crate) fn index_event(event: &Event) {
+    if let Some(hash) = event_geohash(event) {
+        GEO_INDEX.with(|index| index.borrow_mut().insert((StringKey(hash), event.id), ()));
+    }
+}
+
+// Remove an event from the index, as it was last indexed
+pub(crate) fn unindex_event(event: &Event) {
+    if let Some(hash) = event_geohash(event) {
+        GEO_INDEX.with(|index| index.borrow_mut().remove(&(StringKey(hash), event.id)));
+    }
+}
+
+// Turn the free-form locations left from before into venues, a batch per timer tick
+pub(crate) fn schedule_migration() {
+    ic_cdk_timers::set_timer(Duration::ZERO, || {
+        // Picked up again on the next upgrade
+        if backup::in_maintenance() {
+            return;
+        }
+        let batch: Vec<(u64, String)> = legacy_events()
+            .iter()
+            .filter_map(|(event_id, event)| event.location.map(|location| (event_id, location)))
+            .take(MIGRATION_BATCH + 1)
+            .collect();
+        for (event_id, location) in batch.iter().take(MIGRATION_BATCH) {
+            migrate_event(*event_id, location);
+        }
+        if batch.len() > MIGRATION_BATCH {
+            schedule_migration();
+        }
+    });
+}
+
+// View of the event storage that still sees the locations of records written before
+fn legacy_events() -> StableBTreeMap<u64, LegacyEvent, Memory> {
+    StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(1))))
+}
+
+// Rewrite an event without its location, keeping it as the name of a venue with no coordinates
+fn migrate_event(event_id: u64, location: &str) {
+    let Some(mut event) = _get_stored_event(&event_id) else {
+        return;
+    };
+    let mut name = location.trim();
+    // Longer locations are cut at a character boundary to fit a venue name
+    if name.len() > MAX_VENUE_NAME_LEN {
+        let end = (0..=MAX_VENUE_NAME_LEN)
+            .rev()
+            .find(|at| name.is_char_boundary(*at))
+            .unwrap_or(0);
+        name = &name[..end];
+    }
+    // Locations still to be announced become no venue at all
+    if event.venue.is_none() && !name.is_empty() && !name.eq_ignore_ascii_case("TBA") {
+        event.venue = Some(Venue {
+            name: name.to_string(),
+            address: String::new(),
+            lat: None,
+            lng: None,
+            accessibility: None,
+        });
+    }
+    // Events in the trash are rewritten too, they keep their venue when restored
+    store_event(&mut event);
+    certification::certify_event(event_id);
+}
+
+fn check_point(lat: f64, lng: f64) -> Result<(), String> {
+    if !(-90.0..=90.0).contains(&lat) || !(-180.0..=180.0).contains(&lng) {
+        return Err(format!(
+            "latitude {} and longitude {} must be within -90..90 and -180..180",
+            lat, lng
+        ));
+    }
+    Ok(())
+}
+
+fn coordinates(venue: &Venue) -> Option<(f64, f64)> {
+    Some((venue.lat?, venue.lng?))
+}
+
+fn event_geohash(event: &Event) -> Option<String> {
+    let (lat, lng) = coordinates(event.venue.as_ref()?)?;
+    Some(geohash(lat, lng, GEOHASH_PRECISION))
+}
+
+// Encode a point as a geohash, interleaving longitude and latitude bits from the longitude
+fn geohash(lat: f64, lng: f64, precision: usize) -> String {
+    let (mut lat_range, mut lng_range) = ((-90.0, 90.0), (-180.0, 180.0));
+    let mut hash = String::with_capacity(precision);
+    let mut bits = 0;
+    for bit in 0..precision * 5 {
+        let (range, value) = match bit % 2 {
+            0 => (&mut lng_range, lng),
+            _ => (&mut lat_range, lat),
+        };
+        let mid = (range.0 + range.1) / 2.0;
+        bits <<= 1;
+        if value >= mid {
+            bits |= 1;
+            range.0 = mid;
+        } else {
+            range.1 = mid;
+        }
+        if bit % 5 == 4 {
+            hash.push(GEOHASH_ALPHABET[bits] as char);
+            bits = 0;
+        }
+    }
+    hash
+}
+
+// Size in degrees of a geohash cell, as (latitude, longitude)
+fn cell_size(precision: usize) -> (f64, f64) {
+    let bits = precision as i32 * 5;
+    (
+        180.0 / 2f64.powi(bits / 2),
+        360.0 / 2f64.powi(bits - bits / 2),
+    )
+}
+
+// Geohash prefixes whose cells cover a circle: the cell of the centre and its neighbours,
+// at the finest precision where a cell is at least as large as the radius
+fn search_prefixes(lat: f64, lng: f64, radius_km: f64) -> Vec<String> {
+    let precision = (1..=GEOHASH_PRECISION).rev().find(|precision| {
+        let (lat_size, lng_size) = cell_size(*precision);
+        lat_size * KM_PER_DEGREE >= radius_km
+            && lng_size * KM_PER_DEGREE * lat.to_radians().cos() >= radius_km
+    });
+    // Close to the poles cells get too narrow, so the whole index is searched
+    let Some(precision) = precision else {
+        return vec![String::new()];
+    };
+
+    let (lat_size, lng_size) = cell_size(precision);
+    let mut prefixes: Vec<String> = [-1.0, 0.0, 1.0]
+        .iter()
+        .flat_map(|dlat| [-1.0, 0.0, 1.0].iter().map(move |dlng| (dlat, dlng)))
+        .map(|(dlat, dlng)| {
+            let lat = (lat + dlat * lat_size).clamp(-90.0, 90.0);
+            // Wrap around the antimeridian
+            let lng = (lng + dlng * lng_size + 540.0).rem_euclid(360.0) - 180.0;
+            geohash(lat, lng, precision)
+        })
+        .collect();
+    prefixes.sort();
+    prefixes.dedup();
+    prefixes
+}
+
+fn indexed_events(prefix: &str) -> Vec<u64> {
+    GEO_INDEX.with(|index| {
+        index
+            .borrow()
+            .range((StringKey(prefix.to_string()), 0)..)
+            .take_while(|((hash, _), _)| hash.0.starts_with(prefix))
+            .map(|((_, event_id), _)| event_id)
+            .collect()
+    })
+}
+
+// Great-circle distance between two points
+fn distance_km(lat1: f64, lng1: f64, lat2: f64, lng2: f64) -> f64 {
+    let dlat = (lat2 - lat1).to_radians();
+    let dlng = (lng2 - lng1).to_radians();
+    let a = (dlat / 2.0).sin().powi(2)
+        + lat1.to_radians().cos() * lat2.to_radians().cos() * (dlng / 2.0).sin().powi(2);
+    2.0 * EARTH_RADIUS_KM * a.sqrt().asin()
+}
@@ -0,0 +1,296 @@
+use crate::clock::time;
+use crate::ratelimit::rate_limit;
+use crate::{
+    entropy, issued_ticket_ids, organizers, privacy, quote_ticket, remaining_capacity, sell_ticket,
+    tiers, Error, Memory, StringKey, Ticket, TicketPayload, ID_COUNTER, MEMORY_MANAGER,
+};
+use candid::{Decode, Encode};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use std::{borrow::Cow, cell::RefCell};
+
+const NANOS_PER_HOUR: u64 = 60 * 60 * 1_000_000_000;
+const MAX_GROUP_SIZE: u64 = 50;
+// Hours a group has to claim its tickets unless the booking says otherwise
+const DEFAULT_CLAIM_HOURS: u64 = 72;
+const MAX_CLAIM_HOURS: u64 = 90 * 24;
+// Hex digits of a claim code, short enough to share and too many to guess
+const CLAIM_CODE_LEN: usize = 20;
+
+// Define a struct for places of an event held for a group, each claimed with its own code
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct GroupBooking {
+    id: u64,
+    event_id: u64,
+    tier_id: Option<u64>,
+    claims: Vec<GroupClaim>,
+    // Unclaimed places go back on sale after this time
+    expires_at: u64,
+    created_at: u64,
+}
+
+// Define a struct for a place of a group booking and who claimed it
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct GroupClaim {
+    code: String,
+    user_id: Option<u64>,
+    ticket_id: Option<u64>,
+    claimed_at: Option<u64>,
+}
+
+// Define a struct for the options of a group booking
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub struct GroupBookingOptions {
+    tier_id: Option<u64>,
+    // Hours the group has to claim its tickets, 72 if left out
+    claim_hours: Option<u64>,
+}
+
+impl Storable for GroupBooking {
+    // Conversion to bytes
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    // Conversion from bytes
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for GroupBooking {
+    const MAX_SIZE: u32 = 8192;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    // Group bookings keyed by (event id, booking id)
+    static BOOKING_STORAGE: RefCell<StableBTreeMap<(u64, u64), GroupBooking, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(101)))
+    ));
+
+    // Claim codes pointing at their (event id, booking id)
+    static CLAIM_CODE_INDEX: RefCell<StableBTreeMap<StringKey, (u64, u64), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(102)))
+    ));
+}
+
+// Hold places of an event for a group and hand out a claim code for each. No payment is taken,
+// the organizer settles with the group, and claimed tickets carry the price of their tier.
+#[ic_cdk::update(guard = "rate_limit")]
+fn create_group_booking(
+    event_id: u64,
+    quantity: u64,
+    options: Option<GroupBookingOptions>,
+) -> Result<GroupBooking, Error> {
+    let event = organizers::authorize_event(event_id)?;
+    let (tier_id, claim_hours) = options.map_or((None, None), |options| {
+        (options.tier_id, options.claim_hours)
+    });
+    let claim_hours = claim_hours.unwrap_or(DEFAULT_CLAIM_HOURS);
+    if quantity == 0 || quantity > MAX_GROUP_SIZE {
+        return Err(Error::ValidationFailed {
+            msg: format!("a group booking holds 1 to {} tickets", MAX_GROUP_SIZE),
+        });
+    }
+    if claim_hours == 0 || claim_hours > MAX_CLAIM_HOURS {
+        return Err(Error::ValidationFailed {
+            msg: format!("claim window must be 1 to {} hours", MAX_CLAIM_HOURS),
+        });
+    }
+    // Without the random seed the codes would only depend on the time
+    if !entropy::is_seeded() {
+        return Err(Error::ValidationFailed {
+            msg: "randomness is not available yet, try again shortly".to_string(),
+        });
+    }
+
+    // The same checks as a purchase, and room for the whole group
+    quote_ticket(&TicketPayload {
+        event_id,
+        tier_id,
+        ..Default::default()
+    })?;
+    let tier_remaining = tier_id
+        .and_then(|tier_id| tiers::_get_tier(event_id, tier_id))
+        .and_then(|tier| tiers::tier_remaining(&tier));
+    let remaining = match (remaining_capacity(&event), tier_remaining) {
+        (Some(event), Some(tier)) => Some(event.min(tier)),
+        (event, tier) => event.or(tier),
+    };
+    if remaining.is_some_and(|remaining| remaining < quantity) {
+        return Err(Error::CapacityExceeded {
+            msg: format!(
+                "event id:{} has {} tickets left for a group of {}",
+                event_id,
+                remaining.unwrap_or(0),
+                quantity
+            ),
+        });
+    }
+
+    // Increment the global ID counter to get a new ID for the group booking
+    let id = ID_COUNTER
+        .with(|counter| {
+            let current_id = *counter.borrow().get();
+            counter.borrow_mut().set(current_id + 1)
+        })
+        .expect("Cannot increment Ids");
+    let now = time();
+    let claims: Vec<GroupClaim> = (0..quantity)
+        .map(|index| {
+            let purpose = format!("group-claim-{}", index);
+            let mut code = entropy::random_token(purpose.as_bytes(), id);
+            code.truncate(CLAIM_CODE_LEN);
+            GroupClaim {
+                code,
+                user_id: None,
+                ticket_id: None,
+                claimed_at: None,
+            }
+        })
+        .collect();
+    let booking = GroupBooking {
+        id,
+        event_id,
+        tier_id,
+        claims,
+        expires_at: now + claim_hours * NANOS_PER_HOUR,
+        created_at: now,
+    };
+    CLAIM_CODE_INDEX.with(|index| {
+        let mut index = index.borrow_mut();
+        for claim in &booking.claims {
+            index.insert(StringKey(claim.code.clone()), (event_id, id));
+        }
+    });
+    store_booking(&booking);
+    Ok(booking)
+}
+
+#[ic_cdk::query]
+fn get_group_bookings(event_id: u64) -> Result<Vec<GroupBooking>, Error> {
+    organizers::authorize_event(event_id)?;
+    Ok(BOOKING_STORAGE.with(|bookings| {
+        bookings
+            .borrow()
+            .range((event_id, 0)..=(event_id, u64::MAX))
+            .map(|(_, booking)| booking)
+            .collect()
+    }))
+}
+
+// Put the places nobody claimed yet back on sale, claimed tickets are kept
+#[ic_cdk::update(guard = "rate_limit")]
+fn cancel_group_booking(event_id: u64, booking_id: u64) -> Result<GroupBooking, Error> {
+    organizers::authorize_event(event_id)?;
+    let mut booking = BOOKING_STORAGE
+        .with(|bookings| bookings.borrow().get(&(event_id, booking_id)))
+        .ok_or(Error::NotFound {
+            entity: "group booking".to_string(),
+            id: booking_id.to_string(),
+            msg: format!(
+                "group booking id:{} of event id:{} does not exist",
+                booking_id, event_id
+            ),
+        })?;
+    booking.expires_at = booking.expires_at.min(time());
+    store_booking(&booking);
+    Ok(booking)
+}
+
+// Claim a place of a group booking as a ticket of the caller's user
+#[ic_cdk::update(guard = "rate_limit")]
+fn claim_group_ticket(code: String) -> Result<Ticket, Error> {
+    let user = privacy::caller_user()?;
+    let invalid = || Error::ValidationFailed {
+        msg: "claim code does not exist or has expired".to_string(),
+    };
+    let key = CLAIM_CODE_INDEX
+        .with(|index| index.borrow().get(&StringKey(code.clone())))
+        .ok_or_else(invalid)?;
+    let booking = BOOKING_STORAGE
+        .with(|bookings| bookings.borrow().get(&key))
+        .filter(|booking| booking.expires_at > time())
+        .ok_or_else(invalid)?;
+    let index = booking
+        .claims
+        .iter()
+        .position(|claim| claim.code == code)
+        .ok_or_else(invalid)?;
+    if booking.claims[index].claimed_at.is_some() {
+        return Err(Error::ValidationFailed {
+            msg: "claim code was already used".to_string(),
+        });
+    }
+
+    // The place is taken off the booking first, so the sale doesn't count it as held
+    let mut claimed = booking.clone();
+    claimed.claims[index].user_id = Some(user.id);
+    claimed.claims[index].claimed_at = Some(time());
+    store_booking(&claimed);
+    let ticket = sell_ticket(TicketPayload {
+        event_id: booking.event_id,
+        tier_id: booking.tier_id,
+        user_id: user.id,
+        ..Default::default()
+    });
+    match issued_ticket_ids(&ticket).first() {
+        Some(ticket_id) => {
+            claimed.claims[index].ticket_id = Some(*ticket_id);
+            store_booking(&claimed);
+        }
+        None => store_booking(&booking),
+    }
+    ticket
+}
+
+fn store_booking(booking: &GroupBooking) {
+    BOOKING_STORAGE.with(|bookings| {
+        bookings
+            .borrow_mut()
+            .insert((booking.event_id, booking.id), booking.clone())
+    });
+}
+
+// Places of an event held by group bookings still open, in a single tier if one is given
+pub(crate) fn held(event_id: u64, tier_id: Option<u64>) -> u64 {
+    let now = time();
+    BOOKING_STORAGE.with(|bookings| {
+        bookings
+            .borrow()
+            .range((event_id, 0)..=(event_id, u64::MAX))
+            .map(|(_, booking)| booking)
+            .filter(|booking| {
+                booking.expires_at > now && (tier_id.is_none() || booking.tier_id == tier_id)
+            })
+            .map(|booking| {
+                booking
+                    .claims
+                    .iter()
+                    .filter(|claim| claim.claimed_at.is_none())
+                    .count() as u64
+            })
+            .sum()
+    })
+}
+
+pub(crate) fn remove_event_group_bookings(event_id: u64) {
+    let bookings: Vec<GroupBooking> = BOOKING_STORAGE.with(|bookings| {
+        bookings
+            .borrow()
+            .range((event_id, 0)..=(event_id, u64::MAX))
+            .map(|(_, booking)| booking)
+            .collect()
+    });
+    for booking in bookings {
+        CLAIM_CODE_INDEX.with(|index| {
+            let mut index = index.borrow_mut();
+            for claim in &booking.claims {
+                index.remove(&StringKey(claim.code.clone()));
+            }
+        });
+        BOOKING_STORAGE.with(|storage| storage.borrow_mut().remove(&(event_id, booking.id)));
+    }
+}
@@ -0,0 +1,488 @@
+use crate::clock::time;
+use crate::notifications::{self, Notification, NotificationSettings};
+use crate::validation::ValidationSettings;
+use crate::{
+    caller_is_admin, config, entropy, jobs, Error, Event, Memory, Organizer, Reservation, Ticket,
+    User, ID_COUNTER, MEMORY_MANAGER,
+};
+use candid::{CandidType, Decode, Encode};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, Cell, StableBTreeMap, Storable};
+use serde::de::DeserializeOwned;
+use std::{borrow::Cow, cell::RefCell};
+
+// Memories the memory manager can hand out
+pub(crate) const MEMORY_COUNT: u8 = 255;
+// Stable maps start with this magic, followed by their layout version, key and value sizes,
+// root address and length
+const BTREE_MAGIC: &[u8; 3] = b"BTR";
+const BTREE_LENGTH_OFFSET: u64 = 20;
+
+// What each memory holds, as reported by 'get_canister_health'
+const MEMORY_NAMES: &[(u8, &str)] = &[
+    (0, "id counter"),
+    (1, "events"),
+    (2, "users"),
+    (3, "tickets"),
+    (4, "event counters"),
+    (5, "daily sales"),
+    (6, "waitlist"),
+    (7, "event changes"),
+    (8, "ticket tiers"),
+    (9, "promo codes"),
+    (10, "insurance offers"),
+    (11, "event series"),
+    (12, "sponsorship offers"),
+    (13, "sponsorship commitments"),
+    (14, "donation settings"),
+    (15, "donations"),
+    (16, "surveys"),
+    (17, "survey invitations"),
+    (18, "notification outbox"),
+    (19, "notification settings"),
+    (20, "attendee notes"),
+    (21, "ticket code formats"),
+    (22, "ticket codes"),
+    (23, "jobs"),
+    (24, "reminders sent"),
+    (25, "gates"),
+    (26, "credentials"),
+    (27, "sessions"),
+    (28, "announcement watchers"),
+    (29, "api keys"),
+    (30, "embargoes"),
+    (31, "press views"),
+    (32, "access overrides"),
+    (33, "tag index"),
+    (34, "category index"),
+    (35, "validation settings"),
+    (36, "geo index"),
+    (37, "seat maps"),
+    (38, "sold seats"),
+    (39, "seat holds"),
+    (40, "compaction scratch"),
+    (41, "compaction state"),
+    (42, "reservations"),
+    (43, "organizers"),
+    (44, "organizer principals"),
+    (45, "organizer index"),
+    (46, "attendance badges"),
+    (47, "attendance proofs"),
+    (48, "resale blackouts"),
+    (49, "invitations"),
+    (50, "invitation links"),
+    (51, "check-in devices"),
+    (52, "check-in conflicts"),
+    (53, "organizer statements"),
+    (54, "idempotency keys"),
+    (55, "anonymization policies"),
+    (56, "anonymized events"),
+    (57, "orders"),
+    (58, "image blocks"),
+    (59, "images"),
+    (60, "uploads"),
+    (61, "rooms"),
+    (62, "room assignments"),
+    (63, "wallet users"),
+    (64, "user wallets"),
+    (65, "resale settings"),
+    (66, "resale caps"),
+    (67, "resale listings"),
+    (68, "resale sales"),
+    (69, "event payment methods"),
+    (70, "payments"),
+    (71, "webhooks"),
+    (72, "webhook deliveries"),
+    (73, "rate limit settings"),
+    (74, "denied principals"),
+    (75, "exchange rates"),
+    (76, "ckBTC config"),
+    (77, "health settings"),
+    (78, "credit balances"),
+    (79, "credit entries"),
+    (80, "refund policies"),
+    (81, "refund preferences"),
+    (82, "erasure log"),
+    (83, "capacity alert settings"),
+    (84, "capacity alerts"),
+    (85, "reviews"),
+    (86, "favorite events"),
+    (87, "followed organizers"),
+    (88, "tier visibility"),
+    (89, "membership tiers"),
+    (90, "memberships"),
+    (91, "on-sale times"),
+    (92, "payout accounts"),
+    (93, "revenue splits"),
+    (94, "event balances"),
+    (95, "payout ledger"),
+    (96, "flash sales"),
+    (97, "flash sale counters"),
+    (98, "flash sale buyer counters"),
+    (99, "support sessions"),
+    (100, "support log"),
+    (101, "group bookings"),
+    (102, "group claim codes"),
+    (103, "event templates"),
+    (104, "legal holds"),
+    (105, "display preferences"),
+    (106, "event timezones"),
+    (107, "user emails"),
+    (108, "event name words"),
+    (109, "broadcasts"),
+    (110, "broadcast recipients"),
+    (111, "event translations"),
+    (112, "presale registrations"),
+    (113, "registrants"),
+    (114, "passes"),
+    (115, "pass tickets"),
+    (116, "cancellation policies"),
+    (117, "cancellation refunds"),
+    (118, "reports"),
+    (119, "sanctions"),
+    (120, "suspended events"),
+    (121, "banned users"),
+    (122, "event reschedules"),
+    (123, "archival settings"),
+    (124, "archived events"),
+    (125, "archived records"),
+    (126, "federation config"),
+    (127, "federated event summaries"),
+    (128, "federation feed"),
+    (129, "federation pushes"),
+    (130, "transfer memos"),
+    (131, "waitlist admissions"),
+    (132, "QR token configs"),
+    (133, "QR token secret"),
+    (134, "test mode events"),
+    (135, "event add-ons"),
+    (136, "disputes"),
+    (137, "platform config"),
+    (138, "fraud flags"),
+    (139, "failed check-ins"),
+    (140, "attendance records"),
+    (141, "storage quotas"),
+    (142, "venues"),
+    (143, "venue bookings"),
+    (144, "event venues"),
+    (145, "change sequence"),
+    (146, "change log"),
+    (147, "event ticket ids"),
+    (148, "event attendee ids"),
+    (149, "user ticket ids"),
+    (150, "user event ids"),
+    (151, "login times"),
+    (152, "reauthentication policy"),
+    (153, "resale payouts"),
+];
+
+// Define a struct for the outcome of a single readiness check
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub struct HealthCheck {
+    name: String,
+    ok: bool,
+    detail: String,
+}
+
+// Define a struct for the readiness of the canister, ready once every check passes
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub struct HealthReport {
+    ready: bool,
+    checks: Vec<HealthCheck>,
+    checked_at: u64,
+}
+
+// Define a struct for the space a memory takes, and its records if it holds a map
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub struct MemoryUsage {
+    memory_id: u8,
+    name: Option<String>,
+    pages: u64,
+    records: Option<u64>,
+}
+
+// Define a struct for the resources of the canister, for monitoring
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub struct CanisterHealth {
+    cycle_balance: u128,
+    low_cycles_threshold: u128,
+    // Ticket sales are paused while the balance is below the threshold or an admin set the
+    // canister read-only
+    read_only: bool,
+    heap_memory_bytes: u64,
+    stable_memory_pages: u64,
+    memories: Vec<MemoryUsage>,
+    // Last id handed out, ids are shared by every kind of record
+    id_counter: u64,
+    checked_at: u64,
+}
+
+// Define a struct for the settings of the cycles monitoring
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct HealthSettings {
+    // Below this balance the canister stops selling tickets, so a purchase never runs the
+    // canister into its freezing threshold halfway. 0 turns the check off.
+    low_cycles_threshold: u128,
+}
+
+impl Default for HealthSettings {
+    fn default() -> Self {
+        HealthSettings {
+            low_cycles_threshold: 500_000_000_000,
+        }
+    }
+}
+
+impl Storable for HealthSettings {
+    // Conversion to bytes
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    // Conversion from bytes
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+thread_local! {
+    static SETTINGS: RefCell<Cell<HealthSettings, Memory>> = RefCell::new(
+        Cell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(77))),
+            HealthSettings::default(),
+        )
+        .expect("Cannot create the health settings")
+    );
+}
+
+// Define a struct for a stored record left undecoded, so a broken one is reported rather than
+// trapping. It is bounded like the record it stands in for, so the map opens on its memory.
+struct RawRecord<const N: u32>(Vec<u8>);
+
+impl<const N: u32> Storable for RawRecord<N> {
+    // Conversion to bytes
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Borrowed(&self.0)
+    }
+    // Conversion from bytes
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        RawRecord(bytes.into_owned())
+    }
+}
+
+impl<const N: u32> BoundedStorable for RawRecord<N> {
+    const MAX_SIZE: u32 = N;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Readiness probe for deployment pipelines, open to anyone as it only reports counts
+#[ic_cdk::query]
+fn health() -> HealthReport {
+    let checks = vec![
+        check_records::<Event, { Event::MAX_SIZE }>("events", 1),
+        check_records::<User, { User::MAX_SIZE }>("users", 2),
+        check_records::<Ticket, { Ticket::MAX_SIZE }>("tickets", 3),
+        check_records::<Notification, { Notification::MAX_SIZE }>("notifications", 18),
+        check_records::<Reservation, { Reservation::MAX_SIZE }>("reservations", 42),
+        check_records::<Organizer, { Organizer::MAX_SIZE }>("organizers", 43),
+        check_timers(),
+        check_config(),
+        check_cycles(),
+    ];
+    HealthReport {
+        ready: checks.iter().all(|check| check.ok),
+        checks,
+        checked_at: time(),
+    }
+}
+
+#[ic_cdk::query(guard = "caller_is_admin")]
+fn get_canister_health() -> CanisterHealth {
+    let settings = settings();
+    let memories: Vec<MemoryUsage> = (0..MEMORY_COUNT)
+        .filter_map(|memory_id| {
+            let memory = memory(memory_id);
+            let pages = ic_stable_structures::Memory::size(&memory);
+            (pages > 0).then(|| MemoryUsage {
+                memory_id,
+                name: memory_name(memory_id),
+                pages,
+                records: map_length(&memory),
+            })
+        })
+        .collect();
+
+    CanisterHealth {
+        cycle_balance: ic_cdk::api::canister_balance128(),
+        low_cycles_threshold: settings.low_cycles_threshold,
+        read_only: is_read_only() || config::is_read_only(),
+        heap_memory_bytes: heap_memory_bytes(),
+        stable_memory_pages: ic_cdk::api::stable::stable64_size(),
+        memories,
+        id_counter: ID_COUNTER.with(|counter| *counter.borrow().get()),
+        checked_at: time(),
+    }
+}
+
+#[ic_cdk::update(guard = "caller_is_admin")]
+fn set_low_cycles_threshold(low_cycles_threshold: u128) -> Result<HealthSettings, Error> {
+    let settings = HealthSettings {
+        low_cycles_threshold,
+    };
+    SETTINGS
+        .with(|cell| cell.borrow_mut().set(settings.clone()))
+        .map_err(|_| Error::InternalError {
+            msg: "health settings could not be saved".to_string(),
+            ticket_id: None,
+        })?;
+    Ok(settings)
+}
+
+// Whether the canister is too low on cycles to sell tickets
+pub(crate) fn is_read_only() -> bool {
+    ic_cdk::api::canister_balance128() < settings().low_cycles_threshold
+}
+
+// Checked before a sale takes any payment or makes any outcall, so the sale doesn't stop
+// halfway when the canister freezes
+pub(crate) fn check_sales_open() -> Result<(), String> {
+    if config::is_read_only() {
+        return Err("the canister is read-only, ticket sales are paused".to_string());
+    }
+    match is_read_only() {
+        true => Err("ticket sales are paused while the canister tops up its cycles".to_string()),
+        false => Ok(()),
+    }
+}
+
+// What a memory holds, if it was given a name
+pub(crate) fn memory_name(memory_id: u8) -> Option<String> {
+    MEMORY_NAMES
+        .iter()
+        .find(|(id, _)| *id == memory_id)
+        .map(|(_, name)| name.to_string())
+}
+
+// Pages a memory takes, and its records if it holds a map
+pub(crate) fn memory_usage(memory_id: u8) -> (u64, Option<u64>) {
+    let memory = memory(memory_id);
+    (
+        ic_stable_structures::Memory::size(&memory),
+        map_length(&memory),
+    )
+}
+
+fn settings() -> HealthSettings {
+    SETTINGS.with(|cell| cell.borrow().get().clone())
+}
+
+// Stable maps keep their length in their header, so any map is counted without its types
+fn map_length(memory: &Memory) -> Option<u64> {
+    let mut magic = [0; 3];
+    ic_stable_structures::Memory::read(memory, 0, &mut magic);
+    if &magic != BTREE_MAGIC {
+        return None;
+    }
+    let mut length = [0; 8];
+    ic_stable_structures::Memory::read(memory, BTREE_LENGTH_OFFSET, &mut length);
+    Some(u64::from_le_bytes(length))
+}
+
+#[cfg(target_arch = "wasm32")]
+fn heap_memory_bytes() -> u64 {
+    core::arch::wasm32::memory_size(0) as u64 * 65_536
+}
+
+// Only canisters have a wasm heap to measure
+#[cfg(not(target_arch = "wasm32"))]
+fn heap_memory_bytes() -> u64 {
+    0
+}
+
+// Decode the first and last record of a map, those written the longest ago and the latest
+fn check_records<V: CandidType + DeserializeOwned, const N: u32>(
+    name: &str,
+    memory_id: u8,
+) -> HealthCheck {
+    let records: StableBTreeMap<u64, RawRecord<N>, Memory> =
+        StableBTreeMap::init(memory(memory_id));
+    let samples = [records.first_key_value(), records.last_key_value()];
+    let result = samples
+        .into_iter()
+        .flatten()
+        .try_for_each(|(id, record)| {
+            Decode!(&record.0, V)
+                .map(|_| ())
+                .map_err(|err| format!("record id:{} does not decode: {}", id, err))
+        })
+        .map(|()| format!("{} records", records.len()));
+    health_check(&format!("storage:{}", name), result)
+}
+
+fn check_timers() -> HealthCheck {
+    let result = if jobs::unarmed_jobs() > 0 {
+        Err(format!(
+            "{} enabled jobs have no timer",
+            jobs::unarmed_jobs()
+        ))
+    } else if !notifications::delivery_scheduled() {
+        Err("notification delivery is not scheduled".to_string())
+    } else if !entropy::is_seeded() {
+        Err("the random seed was not drawn yet".to_string())
+    } else {
+        Ok("jobs, delivery and seeding timers ran".to_string())
+    };
+    health_check("timers", result)
+}
+
+// Settings cells must decode, and notifications need a webhook to leave the outbox
+fn check_config() -> HealthCheck {
+    let result = check_settings::<NotificationSettings>("notification settings", 19)
+        .and_then(|()| check_settings::<ValidationSettings>("validation settings", 35))
+        .and_then(|()| match notifications::webhook_configured() {
+            true => Ok("settings present".to_string()),
+            false => Err("the notification webhook is not set".to_string()),
+        });
+    health_check("config", result)
+}
+
+fn check_cycles() -> HealthCheck {
+    let balance = ic_cdk::api::canister_balance128();
+    let result = match is_read_only() {
+        true => Err(format!(
+            "{} cycles left, ticket sales are paused below {}",
+            balance,
+            settings().low_cycles_threshold
+        )),
+        false => Ok(format!("{} cycles left", balance)),
+    };
+    health_check("cycles", result)
+}
+
+fn check_settings<T: CandidType + DeserializeOwned>(
+    name: &str,
+    memory_id: u8,
+) -> Result<(), String> {
+    let memory = memory(memory_id);
+    // Left empty until the settings are first saved
+    if ic_stable_structures::Memory::size(&memory) == 0 {
+        return Err(format!("{} were never saved", name));
+    }
+    let settings = Cell::<Vec<u8>, Memory>::init(memory, vec![])
+        .map_err(|_| format!("{} are not a valid cell", name))?;
+    Decode!(settings.get(), T)
+        .map(|_| ())
+        .map_err(|err| format!("{} do not decode: {}", name, err))
+}
+
+fn health_check(name: &str, result: Result<String, String>) -> HealthCheck {
+    let ok = result.is_ok();
+    HealthCheck {
+        name: name.to_string(),
+        ok,
+        detail: result.unwrap_or_else(|err| err),
+    }
+}
+
+fn memory(memory_id: u8) -> Memory {
+    MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(memory_id)))
+}
@@ -0,0 +1,213 @@
+use crate::clock::time;
+use crate::{_get_event, Error, Event, Memory, ID_COUNTER, MEMORY_MANAGER};
+use candid::{Decode, Encode, Principal};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use std::{borrow::Cow, cell::RefCell};
+
+// Changes kept per event, the oldest are dropped past this
+const MAX_CHANGES_PER_EVENT: usize = 200;
+// Values are cut to this many bytes, so both fit in an entry
+const MAX_VALUE_LEN: usize = 300;
+const PAGE_SIZE: usize = 20;
+
+// Define a struct for an entry of an event's change log
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct EventChange {
+    id: u64,
+    event_id: u64,
+    field: String,
+    old_value: String,
+    new_value: String,
+    changed_by: Principal,
+    changed_at: u64,
+}
+
+// Define a struct for a page of an event's change log
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub struct EventChangePage {
+    changes: Vec<EventChange>,
+    page: u64,
+    // Number of changes kept over all pages
+    total: u64,
+}
+
+impl Storable for EventChange {
+    // Conversion to bytes
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    // Conversion from bytes
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for EventChange {
+    const MAX_SIZE: u32 = 1024;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    // Change log entries keyed by (event id, change id)
+    static EVENT_CHANGES: RefCell<StableBTreeMap<(u64, u64), EventChange, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(7)))
+    ));
+}
+
+// The changes of an event, the latest first
+#[ic_cdk::query]
+fn get_event_history(event_id: u64, page: u64) -> Result<EventChangePage, Error> {
+    // Make sure the event exists, or return a NotFound error if not found
+    _get_event(&event_id).ok_or(Error::not_found("event", event_id))?;
+
+    let mut changes = event_changes(event_id);
+    changes.reverse();
+    Ok(EventChangePage {
+        total: changes.len() as u64,
+        changes: changes
+            .into_iter()
+            .skip((page as usize).saturating_mul(PAGE_SIZE))
+            .take(PAGE_SIZE)
+            .collect(),
+        page,
+    })
+}
+
+// Append a change of a single event field to the event's change log
+pub(crate) fn record_event_change(
+    event_id: u64,
+    field: &str,
+    old_value: String,
+    new_value: String,
+) {
+    // Increment the global ID counter to get a new ID for the change
+    let id = ID_COUNTER
+        .with(|counter| {
+            let current_id = *counter.borrow().get();
+            counter.borrow_mut().set(current_id + 1)
+        })
+        .expect("Cannot increment Ids");
+
+    let change = EventChange {
+        id,
+        event_id,
+        field: field.to_string(),
+        old_value: truncate(old_value),
+        new_value: truncate(new_value),
+        changed_by: ic_cdk::caller(),
+        changed_at: time(),
+    };
+
+    EVENT_CHANGES.with(|changes| {
+        let mut changes = changes.borrow_mut();
+        changes.insert((event_id, id), change);
+        let keys: Vec<(u64, u64)> = changes
+            .range((event_id, 0)..=(event_id, u64::MAX))
+            .map(|(key, _)| key)
+            .collect();
+        for key in keys
+            .iter()
+            .take(keys.len().saturating_sub(MAX_CHANGES_PER_EVENT))
+        {
+            changes.remove(key);
+        }
+    });
+}
+
+// Log every field an update of an event changed. Text fields are logged as they are, others as
+// JSON.
+pub(crate) fn record_event_update(before: &Event, after: &Event) {
+    let text_fields = [
+        ("name", &before.name, &after.name),
+        ("description", &before.description, &after.description),
+        ("date", &before.date, &after.date),
+        ("start_time", &before.start_time, &after.start_time),
+    ];
+    for (field, old_value, new_value) in text_fields {
+        if old_value != new_value {
+            record_event_change(before.id, field, old_value.clone(), new_value.clone());
+        }
+    }
+
+    let json_fields = [
+        ("venue", json(&before.venue), json(&after.venue)),
+        (
+            "max_tickets_per_user",
+            json(&before.max_tickets_per_user),
+            json(&after.max_tickets_per_user),
+        ),
+        (
+            "visibility",
+            json(&before.visibility),
+            json(&after.visibility),
+        ),
+        ("category", json(&before.category), json(&after.category)),
+        ("tags", json(&before.tags), json(&after.tags)),
+        ("metadata", json(&before.metadata), json(&after.metadata)),
+        (
+            "accessibility",
+            json(&before.accessibility),
+            json(&after.accessibility),
+        ),
+    ];
+    for (field, old_value, new_value) in json_fields {
+        if old_value != new_value {
+            record_event_change(before.id, field, old_value, new_value);
+        }
+    }
+}
+
+fn json<T: serde::Serialize>(value: &T) -> String {
+    serde_json::to_string(value).unwrap_or_default()
+}
+
+// Cut a value at a character boundary, marking that it was cut
+fn truncate(mut value: String) -> String {
+    if value.len() > MAX_VALUE_LEN {
+        let end = (0..=MAX_VALUE_LEN - 3)
+            .rev()
+            .find(|at| value.is_char_boundary(*at))
+            .unwrap_or(0);
+        value.truncate(end);
+        value.push_str("...");
+    }
+    value
+}
+
+fn event_changes(event_id: u64) -> Vec<EventChange> {
+    EVENT_CHANGES.with(|changes| {
+        changes
+            .borrow()
+            .range((event_id, 0)..=(event_id, u64::MAX))
+            .map(|(_, change)| change)
+            .collect()
+    })
+}
+
+// Changes a principal made to any event, oldest first
+pub(crate) fn changes_by(principal: Principal) -> Vec<EventChange> {
+    EVENT_CHANGES.with(|changes| {
+        changes
+            .borrow()
+            .iter()
+            .map(|(_, change)| change)
+            .filter(|change| change.changed_by == principal)
+            .collect()
+    })
+}
+
+// Drop the change log of a deleted event
+pub(crate) fn remove_event_history(event_id: u64) {
+    EVENT_CHANGES.with(|changes| {
+        let mut changes = changes.borrow_mut();
+        let keys: Vec<(u64, u64)> = changes
+            .range((event_id, 0)..=(event_id, u64::MAX))
+            .map(|(key, _)| key)
+            .collect();
+        for key in keys {
+            changes.remove(&key);
+        }
+    });
+}
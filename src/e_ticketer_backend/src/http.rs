@@ -0,0 +1,190 @@
+use crate::waiting_room::WaitingRoomStatus;
+use crate::{
+    _get_public_event, _get_public_events, assets, calendar, certification, get_event_tickets,
+    waiting_room, Error, Event,
+};
+use serde_bytes::ByteBuf;
+
+// Define a struct for a request coming in through the HTTP gateway
+#[derive(candid::CandidType, Deserialize)]
+pub struct HttpRequest {
+    method: String,
+    url: String,
+    headers: Vec<(String, String)>,
+    body: ByteBuf,
+}
+
+// Define a struct for the response handed back to the HTTP gateway
+#[derive(candid::CandidType, Serialize)]
+pub struct HttpResponse {
+    status_code: u16,
+    headers: Vec<(String, String)>,
+    pub(crate) body: ByteBuf,
+    // Asks the gateway to replay the request as an update call
+    upgrade: Option<bool>,
+}
+
+#[ic_cdk::query]
+fn http_request(request: HttpRequest) -> HttpResponse {
+    // The REST surface is read-only, anything but a read goes through the update call
+    if request.method != "GET" && request.method != "HEAD" {
+        return HttpResponse {
+            upgrade: Some(true),
+            ..response(200, &())
+        };
+    }
+
+    // Drop the query string, the routes don't take parameters
+    let path = request.url.split('?').next().unwrap_or_default();
+
+    // Attach the certificate of the response body, so the gateway can verify it
+    let mut response = render(path);
+    if let Some(header) = certification::http_certificate_header(path) {
+        response.headers.push(header);
+    }
+    response
+}
+
+// Build the response served at a path, also used to compute its certified hash
+pub(crate) fn render(path: &str) -> HttpResponse {
+    let segments: Vec<&str> = path.split('/').filter(|s| !s.is_empty()).collect();
+
+    match segments.as_slice() {
+        ["events"] => response(200, &_get_public_events()),
+        ["events", id] => match parse_id(id).and_then(public_event) {
+            Ok(event) => response(200, &event),
+            Err(error) => error_response(error),
+        },
+        ["events", id, "tickets"] => match parse_id(id)
+            .and_then(public_event)
+            .and_then(|event| get_event_tickets(event.id))
+        {
+            Ok(tickets) => response(200, &tickets),
+            Err(error) => error_response(error),
+        },
+        ["events", id, "event.ics"] => match parse_id(id)
+            .and_then(public_event)
+            .and_then(|event| calendar::event_ics(&event))
+        {
+            Ok(ics) => HttpResponse {
+                status_code: 200,
+                headers: vec![(
+                    "Content-Type".to_string(),
+                    "text/calendar; charset=utf-8".to_string(),
+                )],
+                body: ByteBuf::from(ics.into_bytes()),
+                upgrade: None,
+            },
+            Err(error) => error_response(error),
+        },
+        ["events", id, "image"] => match parse_id(id).and_then(|id| {
+            assets::image_body(id).ok_or(Error::NotFound {
+                entity: "image".to_string(),
+                id: id.to_string(),
+                msg: format!("event id:{} has no image", id),
+            })
+        }) {
+            Ok((content_type, bytes)) => HttpResponse {
+                status_code: 200,
+                headers: vec![("Content-Type".to_string(), content_type)],
+                body: ByteBuf::from(bytes),
+                upgrade: None,
+            },
+            Err(error) => error_response(error),
+        },
+        // A page for browsers, or JSON with '.json' appended to the entry id
+        ["events", id, "waitlist", entry] => {
+            let (entry, json) = match entry.strip_suffix(".json") {
+                Some(entry) => (entry, true),
+                None => (*entry, false),
+            };
+            match parse_id(id).and_then(|id| {
+                parse_id(entry).and_then(|entry_id| waiting_room::waiting_room_status(id, entry_id))
+            }) {
+                Ok(status) => waiting_room_response(&status, json),
+                Err(error) => error_response(error),
+            }
+        }
+        _ => error_response(Error::NotFound {
+            entity: "path".to_string(),
+            id: path.to_string(),
+            msg: format!("path {} does not exist", path),
+        }),
+    }
+}
+
+// Build the waiting room response of a queued buyer, also used to certify the whole waitlist
+// without looking up each entry's place again
+pub(crate) fn waiting_room_response(status: &WaitingRoomStatus, json: bool) -> HttpResponse {
+    if json {
+        return response(200, status);
+    }
+    HttpResponse {
+        status_code: 200,
+        headers: vec![(
+            "Content-Type".to_string(),
+            "text/html; charset=utf-8".to_string(),
+        )],
+        body: ByteBuf::from(waiting_room::waiting_room_html(status).into_bytes()),
+        upgrade: None,
+    }
+}
+
+// Embargoed events are served as if they didn't exist
+fn public_event(id: u64) -> Result<Event, Error> {
+    _get_public_event(&id).ok_or(Error::not_found("event", id))
+}
+
+// Public in the access policy, as the HTTP gateway calls in as the anonymous principal
+// Not rate limited, the gateway makes the call as the anonymous principal for every visitor
+#[ic_cdk::update]
+fn http_request_update(request: HttpRequest) -> HttpResponse {
+    // Only upgraded requests land here, none of which the read-only surface accepts
+    let mut response = response(
+        405,
+        &Error::ValidationFailed {
+            msg: format!("method {} is not allowed", request.method),
+        },
+    );
+    response
+        .headers
+        .push(("Allow".to_string(), "GET, HEAD".to_string()));
+    response
+}
+
+// Parse the id segment of a path
+fn parse_id(id: &str) -> Result<u64, Error> {
+    id.parse().map_err(|_| Error::ValidationFailed {
+        msg: format!("{} is not a valid id", id),
+    })
+}
+
+// Build a JSON response with the given status
+fn response<T: serde::Serialize>(status_code: u16, body: &T) -> HttpResponse {
+    HttpResponse {
+        status_code,
+        headers: vec![("Content-Type".to_string(), "application/json".to_string())],
+        body: ByteBuf::from(serde_json::to_vec(body).unwrap()),
+        upgrade: None,
+    }
+}
+
+// Map an error onto the matching HTTP status
+fn error_response(error: Error) -> HttpResponse {
+    let status_code = match error {
+        Error::NotFound { .. } => 404,
+        Error::ValidationFailed { .. } => 400,
+        Error::Unauthorized { .. } => 403,
+        Error::Conflict { .. } => 409,
+        Error::HasDependents { .. } => 409,
+        Error::TransferFrozen { .. } => 409,
+        Error::AlreadyExists { .. } => 409,
+        Error::OnLegalHold { .. } => 409,
+        Error::CapacityExceeded { .. } => 409,
+        Error::PurchaseLimitExceeded { .. } => 409,
+        Error::PaymentFailed { .. } => 402,
+        Error::StorageQuotaExceeded { .. } => 507,
+        Error::InternalError { .. } => 500,
+    };
+    response(status_code, &error)
+}
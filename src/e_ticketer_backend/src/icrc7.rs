@@ -0,0 +1,461 @@
+use crate::ratelimit::rate_limit;
+use crate::{
+    _get_public_event, _get_ticket, _get_user, _update_ticket, bulk, credentials, seats, Error,
+    Memory, StringKey, TicketPayload, ID_COUNTER, MEMORY_MANAGER, TICKET_STORAGE,
+};
+use candid::{Int, Nat, Principal};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::StableBTreeMap;
+use serde_bytes::ByteBuf;
+use std::cell::RefCell;
+
+// Limits advertised to wallets, keeping a call well within the instruction limit
+const MAX_QUERY_BATCH_SIZE: usize = 100;
+const MAX_UPDATE_BATCH_SIZE: usize = 10;
+const DEFAULT_TAKE_VALUE: usize = 100;
+const MAX_TAKE_VALUE: usize = 1000;
+const MAX_MEMO_SIZE: usize = 32;
+
+// Define a struct for an ICRC-1 account, tickets are only held by the default subaccount
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct Account {
+    pub(crate) owner: Principal,
+    pub(crate) subaccount: Option<ByteBuf>,
+}
+
+// Define an enum for the ICRC-3 values metadata is made of
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub enum Value {
+    Blob(ByteBuf),
+    Text(String),
+    Nat(Nat),
+    Int(Int),
+    Array(Vec<Value>),
+    Map(Vec<(String, Value)>),
+}
+
+// Define a struct for a transfer of a ticket token to another wallet
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct TransferArg {
+    from_subaccount: Option<ByteBuf>,
+    to: Account,
+    // Same as the ticket id
+    token_id: Nat,
+    memo: Option<ByteBuf>,
+    created_at_time: Option<u64>,
+}
+
+// Define an enum for why a token transfer failed, as laid out by ICRC-7
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub enum TransferError {
+    NonExistingTokenId,
+    // The recipient has no linked user to hold the ticket, or already holds it
+    InvalidRecipient,
+    Unauthorized,
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    Duplicate { duplicate_of: Nat },
+    // The ticket can't change hands right now, e.g. during a blackout or after check-in
+    GenericError { error_code: Nat, message: String },
+    GenericBatchError { error_code: Nat, message: String },
+}
+
+// Define a struct for a standard the canister supports, as listed by ICRC-10
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub struct SupportedStandard {
+    name: String,
+    url: String,
+}
+
+thread_local! {
+    // User each linked wallet holds tickets for, keyed by the principal in text form
+    static WALLET_USERS: RefCell<StableBTreeMap<StringKey, u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(63)))
+    ));
+
+    // Wallet linked to each user, keyed by user id
+    static USER_WALLETS: RefCell<StableBTreeMap<u64, StringKey, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(64)))
+    ));
+}
+
+// Tickets belong to users, a user links a wallet to see and move them as tokens. The session
+// of a login proves the caller is that user.
+#[ic_cdk::update(guard = "rate_limit")]
+fn link_wallet(session_token: String) -> Result<String, Error> {
+    let caller = ic_cdk::caller();
+    if caller == Principal::anonymous() {
+        return Err(Error::Unauthorized {
+            msg: "wallets need their own identity".to_string(),
+        });
+    }
+    let user_id = credentials::session_user_id(&session_token).ok_or(Error::Unauthorized {
+        msg: "session does not exist or has expired".to_string(),
+    })?;
+    if wallet_user(caller).is_some_and(|id| id != user_id) {
+        return Err(Error::ValidationFailed {
+            msg: format!("wallet {} is linked to another user", caller),
+        });
+    }
+
+    // A user holds tickets through one wallet at a time, linking another replaces it
+    remove_user_wallet(user_id);
+    WALLET_USERS.with(|wallets| {
+        wallets
+            .borrow_mut()
+            .insert(StringKey(caller.to_text()), user_id)
+    });
+    USER_WALLETS.with(|wallets| {
+        wallets
+            .borrow_mut()
+            .insert(user_id, StringKey(caller.to_text()))
+    });
+
+    Ok(format!("wallet {} linked to user id: {}", caller, user_id))
+}
+
+#[ic_cdk::update(guard = "rate_limit")]
+fn unlink_wallet() -> Result<String, Error> {
+    let caller = ic_cdk::caller();
+    let user_id = wallet_user(caller).ok_or(Error::NotFound {
+        entity: "wallet".to_string(),
+        id: caller.to_string(),
+        msg: format!("wallet {} is not linked to a user", caller),
+    })?;
+    remove_user_wallet(user_id);
+    Ok(format!(
+        "wallet {} unlinked from user id: {}",
+        caller, user_id
+    ))
+}
+
+#[ic_cdk::query]
+fn get_user_wallet(user_id: u64) -> Option<Principal> {
+    user_wallet(user_id)
+}
+
+#[ic_cdk::query]
+fn icrc7_collection_metadata() -> Vec<(String, Value)> {
+    vec![
+        ("icrc7:symbol".to_string(), Value::Text(icrc7_symbol())),
+        ("icrc7:name".to_string(), Value::Text(icrc7_name())),
+        (
+            "icrc7:description".to_string(),
+            Value::Text(icrc7_description().unwrap_or_default()),
+        ),
+        (
+            "icrc7:total_supply".to_string(),
+            Value::Nat(icrc7_total_supply()),
+        ),
+        (
+            "icrc7:max_query_batch_size".to_string(),
+            Value::Nat(Nat::from(MAX_QUERY_BATCH_SIZE)),
+        ),
+        (
+            "icrc7:max_update_batch_size".to_string(),
+            Value::Nat(Nat::from(MAX_UPDATE_BATCH_SIZE)),
+        ),
+        (
+            "icrc7:default_take_value".to_string(),
+            Value::Nat(Nat::from(DEFAULT_TAKE_VALUE)),
+        ),
+        (
+            "icrc7:max_take_value".to_string(),
+            Value::Nat(Nat::from(MAX_TAKE_VALUE)),
+        ),
+        (
+            "icrc7:max_memo_size".to_string(),
+            Value::Nat(Nat::from(MAX_MEMO_SIZE)),
+        ),
+    ]
+}
+
+#[ic_cdk::query]
+fn icrc7_symbol() -> String {
+    "TIX".to_string()
+}
+
+#[ic_cdk::query]
+fn icrc7_name() -> String {
+    "e-Ticketer tickets".to_string()
+}
+
+#[ic_cdk::query]
+fn icrc7_description() -> Option<String> {
+    Some("Event tickets, each token is the ticket of the same id".to_string())
+}
+
+#[ic_cdk::query]
+fn icrc7_logo() -> Option<String> {
+    None
+}
+
+#[ic_cdk::query]
+fn icrc7_total_supply() -> Nat {
+    Nat::from(TICKET_STORAGE.with(|tickets| tickets.borrow().len()))
+}
+
+// Tickets are sold as long as events have room, there is no cap on the collection
+#[ic_cdk::query]
+fn icrc7_supply_cap() -> Option<Nat> {
+    None
+}
+
+#[ic_cdk::query]
+fn icrc7_max_query_batch_size() -> Option<Nat> {
+    Some(Nat::from(MAX_QUERY_BATCH_SIZE))
+}
+
+#[ic_cdk::query]
+fn icrc7_max_update_batch_size() -> Option<Nat> {
+    Some(Nat::from(MAX_UPDATE_BATCH_SIZE))
+}
+
+#[ic_cdk::query]
+fn icrc7_default_take_value() -> Option<Nat> {
+    Some(Nat::from(DEFAULT_TAKE_VALUE))
+}
+
+#[ic_cdk::query]
+fn icrc7_max_take_value() -> Option<Nat> {
+    Some(Nat::from(MAX_TAKE_VALUE))
+}
+
+#[ic_cdk::query]
+fn icrc7_max_memo_size() -> Option<Nat> {
+    Some(Nat::from(MAX_MEMO_SIZE))
+}
+
+// Each transfer in a batch succeeds or fails on its own
+#[ic_cdk::query]
+fn icrc7_atomic_batch_transfers() -> Option<bool> {
+    Some(false)
+}
+
+// Transfers are not deduplicated, so there is no window
+#[ic_cdk::query]
+fn icrc7_tx_window() -> Option<Nat> {
+    None
+}
+
+#[ic_cdk::query]
+fn icrc7_permitted_drift() -> Option<Nat> {
+    None
+}
+
+#[ic_cdk::query]
+fn icrc7_token_metadata(token_ids: Vec<Nat>) -> Vec<Option<Vec<(String, Value)>>> {
+    token_ids
+        .iter()
+        .take(MAX_QUERY_BATCH_SIZE)
+        .map(|token_id| token_metadata(ticket_id(token_id)?))
+        .collect()
+}
+
+#[ic_cdk::query]
+fn icrc7_owner_of(token_ids: Vec<Nat>) -> Vec<Option<Account>> {
+    token_ids
+        .iter()
+        .take(MAX_QUERY_BATCH_SIZE)
+        .map(|token_id| {
+            let ticket = _get_ticket(&ticket_id(token_id)?)?;
+            Some(Account {
+                owner: user_wallet(ticket.user_id)?,
+                subaccount: None,
+            })
+        })
+        .collect()
+}
+
+#[ic_cdk::query]
+fn icrc7_balance_of(accounts: Vec<Account>) -> Vec<Nat> {
+    accounts
+        .iter()
+        .take(MAX_QUERY_BATCH_SIZE)
+        .map(|account| Nat::from(account_ticket_ids(account).len()))
+        .collect()
+}
+
+#[ic_cdk::query]
+fn icrc7_tokens(prev: Option<Nat>, take: Option<Nat>) -> Vec<Nat> {
+    let start = prev.as_ref().and_then(ticket_id).map_or(0, |id| id + 1);
+    TICKET_STORAGE.with(|tickets| {
+        tickets
+            .borrow()
+            .range(start..)
+            .take(take_value(take))
+            .map(|(id, _)| Nat::from(id))
+            .collect()
+    })
+}
+
+#[ic_cdk::query]
+fn icrc7_tokens_of(account: Account, prev: Option<Nat>, take: Option<Nat>) -> Vec<Nat> {
+    let start = prev.as_ref().and_then(ticket_id).map_or(0, |id| id + 1);
+    account_ticket_ids(&account)
+        .into_iter()
+        .filter(|&id| id >= start)
+        .take(take_value(take))
+        .map(Nat::from)
+        .collect()
+}
+
+#[ic_cdk::update(guard = "rate_limit")]
+fn icrc7_transfer(args: Vec<TransferArg>) -> Vec<Option<Result<Nat, TransferError>>> {
+    if args.len() > MAX_UPDATE_BATCH_SIZE {
+        let error = || TransferError::GenericBatchError {
+            error_code: Nat::from(0u64),
+            message: format!(
+                "at most {} transfers can be made at once",
+                MAX_UPDATE_BATCH_SIZE
+            ),
+        };
+        return args.iter().map(|_| Some(Err(error()))).collect();
+    }
+    args.into_iter().map(|arg| Some(transfer(arg))).collect()
+}
+
+#[ic_cdk::query]
+fn icrc10_supported_standards() -> Vec<SupportedStandard> {
+    vec![
+        SupportedStandard {
+            name: "ICRC-7".to_string(),
+            url: "https://github.com/dfinity/ICRC/ICRCs/ICRC-7".to_string(),
+        },
+        SupportedStandard {
+            name: "ICRC-10".to_string(),
+            url: "https://github.com/dfinity/ICRC/ICRCs/ICRC-10".to_string(),
+        },
+    ]
+}
+
+// Move a ticket to the user of the recipient's wallet, the same way as an update of its holder
+fn transfer(arg: TransferArg) -> Result<Nat, TransferError> {
+    if arg
+        .memo
+        .as_ref()
+        .is_some_and(|memo| memo.len() > MAX_MEMO_SIZE)
+    {
+        return Err(TransferError::GenericError {
+            error_code: Nat::from(0u64),
+            message: format!("memo can be at most {} bytes", MAX_MEMO_SIZE),
+        });
+    }
+    let ticket = ticket_id(&arg.token_id)
+        .and_then(|id| _get_ticket(&id))
+        .ok_or(TransferError::NonExistingTokenId)?;
+    let from = wallet_user(ic_cdk::caller())
+        .filter(|_| is_default(&arg.from_subaccount))
+        .ok_or(TransferError::Unauthorized)?;
+    if from != ticket.user_id {
+        return Err(TransferError::Unauthorized);
+    }
+    let to = wallet_user(arg.to.owner)
+        .filter(|_| is_default(&arg.to.subaccount))
+        .filter(|&to| to != from)
+        .ok_or(TransferError::InvalidRecipient)?;
+
+    let payload = TicketPayload {
+        event_id: ticket.event_id,
+        user_id: to,
+        ..Default::default()
+    };
+    _update_ticket(ticket.id, payload, None).map_err(|error| TransferError::GenericError {
+        error_code: Nat::from(1u64),
+        message: bulk::error_msg(error),
+    })?;
+
+    // Increment the global ID counter to get an ID for the transfer
+    let id = ID_COUNTER
+        .with(|counter| {
+            let current_id = *counter.borrow().get();
+            counter.borrow_mut().set(current_id + 1)
+        })
+        .expect("Cannot increment Ids");
+    Ok(Nat::from(id))
+}
+
+// What a wallet shows for a ticket, events that aren't public show the ticket alone
+fn token_metadata(ticket_id: u64) -> Option<Vec<(String, Value)>> {
+    let ticket = _get_ticket(&ticket_id)?;
+    let mut metadata = vec![("ticket_id".to_string(), Value::Nat(Nat::from(ticket.id)))];
+    if let Some(event) = _get_public_event(&ticket.event_id) {
+        metadata.push((
+            "icrc7:name".to_string(),
+            Value::Text(format!("{} ticket #{}", event.name, ticket.id)),
+        ));
+        metadata.push((
+            "icrc7:description".to_string(),
+            Value::Text(format!(
+                "{} on {} at {}",
+                event.name, event.date, event.start_time
+            )),
+        ));
+        metadata.push(("event_id".to_string(), Value::Nat(Nat::from(event.id))));
+    }
+    if let Some(seat) = &ticket.seat {
+        metadata.push(("seat".to_string(), Value::Text(seats::seat_label(seat))));
+    }
+    metadata.push((
+        "checked_in".to_string(),
+        Value::Nat(Nat::from(ticket.checked_in_at.is_some() as u64)),
+    ));
+    Some(metadata)
+}
+
+// Tickets held by the user linked to an account, oldest first
+fn account_ticket_ids(account: &Account) -> Vec<u64> {
+    if !is_default(&account.subaccount) {
+        return vec![];
+    }
+    let Some(user) = wallet_user(account.owner).and_then(|user_id| _get_user(&user_id)) else {
+        return vec![];
+    };
+    // Users keep ids of tickets they have since passed on, the tickets tell who holds them
+    let mut ticket_ids = user.ticket_ids;
+    ticket_ids.retain(|id| _get_ticket(id).is_some_and(|ticket| ticket.user_id == user.id));
+    ticket_ids.sort_unstable();
+    ticket_ids.dedup();
+    ticket_ids
+}
+
+fn ticket_id(token_id: &Nat) -> Option<u64> {
+    match token_id.0.to_u64_digits().as_slice() {
+        [] => Some(0),
+        [id] => Some(*id),
+        _ => None,
+    }
+}
+
+fn take_value(take: Option<Nat>) -> usize {
+    take.as_ref()
+        .and_then(ticket_id)
+        .map_or(DEFAULT_TAKE_VALUE, |take| take as usize)
+        .min(MAX_TAKE_VALUE)
+}
+
+// A missing subaccount and the all-zero one are the same default subaccount
+fn is_default(subaccount: &Option<ByteBuf>) -> bool {
+    subaccount
+        .as_ref()
+        .is_none_or(|subaccount| subaccount.iter().all(|&byte| byte == 0))
+}
+
+pub(crate) fn wallet_user(wallet: Principal) -> Option<u64> {
+    WALLET_USERS.with(|wallets| wallets.borrow().get(&StringKey(wallet.to_text())))
+}
+
+pub(crate) fn user_wallet(user_id: u64) -> Option<Principal> {
+    USER_WALLETS
+        .with(|wallets| wallets.borrow().get(&user_id))
+        .and_then(|wallet| Principal::from_text(wallet.0).ok())
+}
+
+// Unlink the wallet of a user, also when the user is purged
+pub(crate) fn remove_user_wallet(user_id: u64) {
+    if let Some(wallet) = USER_WALLETS.with(|wallets| wallets.borrow_mut().remove(&user_id)) {
+        WALLET_USERS.with(|wallets| wallets.borrow_mut().remove(&wallet));
+    }
+}
@@ -0,0 +1,161 @@
+use crate::clock::time;
+use crate::{Memory, StringKey, MEMORY_MANAGER};
+use candid::{CandidType, Decode, Encode};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use serde::de::DeserializeOwned;
+use sha2::{Digest, Sha256};
+use std::{borrow::Cow, cell::RefCell};
+
+// How long a key keeps answering with its original result
+const KEY_TTL_NANOS: u64 = 24 * 60 * 60 * 1_000_000_000;
+// A request that trapped after an outcall never finishes, its key frees up after this long
+const PENDING_TTL_NANOS: u64 = 5 * 60 * 1_000_000_000;
+// Keeps the caller's principal and the key within a stable map key
+const MAX_KEY_LEN: usize = 48;
+
+// Define a struct for a request made with an idempotency key, along with its result
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct IdempotencyRecord {
+    // SHA-256 of the method and its arguments, a key only replays the request it was used for
+    fingerprint: Vec<u8>,
+    // Candid encoded result, None while the request is still running
+    response: Option<Vec<u8>>,
+    created_at: u64,
+}
+
+impl Storable for IdempotencyRecord {
+    // Conversion to bytes
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    // Conversion from bytes
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for IdempotencyRecord {
+    // Large enough for a ticket
+    const MAX_SIZE: u32 = 1280;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    // Records keyed by the caller's principal in text form and the key it sent
+    static IDEMPOTENCY_STORAGE: RefCell<StableBTreeMap<StringKey, IdempotencyRecord, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(54)))
+    ));
+}
+
+// Look up a key before running a request. Returns the original result of a repeated request,
+// or None after marking the key as in use so the request can go ahead.
+pub(crate) fn begin<T: DeserializeOwned + CandidType>(
+    key: &Option<String>,
+    method: &str,
+    args: impl CandidType,
+) -> Result<Option<T>, String> {
+    let Some(key) = key else {
+        return Ok(None);
+    };
+    if key.is_empty() || key.len() > MAX_KEY_LEN {
+        return Err(format!(
+            "idempotency key must be between 1 and {} bytes",
+            MAX_KEY_LEN
+        ));
+    }
+    let fingerprint = fingerprint(method, args);
+    let now = time();
+
+    if let Some(record) = _get_record(key).filter(|record| !is_expired(record, now)) {
+        if record.fingerprint != fingerprint {
+            return Err(format!(
+                "idempotency key {} was used for a different request",
+                key
+            ));
+        }
+        return match record.response {
+            Some(response) => Ok(Some(Decode!(&response, T).unwrap())),
+            None => Err(format!(
+                "a request with idempotency key {} is still running",
+                key
+            )),
+        };
+    }
+
+    IDEMPOTENCY_STORAGE.with(|records| {
+        records.borrow_mut().insert(
+            record_key(key),
+            IdempotencyRecord {
+                fingerprint,
+                response: None,
+                created_at: now,
+            },
+        )
+    });
+    Ok(None)
+}
+
+// Keep the result of a request for its key to replay. Failed requests changed nothing, so
+// their key is released for the client to retry with.
+pub(crate) fn finish<T: CandidType, E>(key: &Option<String>, result: &Result<T, E>) {
+    let Some(key) = key else {
+        return;
+    };
+    IDEMPOTENCY_STORAGE.with(|records| {
+        let mut records = records.borrow_mut();
+        match result {
+            Ok(value) => {
+                if let Some(mut record) = records.get(&record_key(key)) {
+                    record.response = Some(Encode!(value).unwrap());
+                    records.insert(record_key(key), record);
+                }
+            }
+            Err(_) => {
+                records.remove(&record_key(key));
+            }
+        }
+    });
+}
+
+// Drop the records whose window is over, returning how many were dropped
+pub(crate) fn expire_keys() -> u64 {
+    let now = time();
+    IDEMPOTENCY_STORAGE.with(|records| {
+        let mut records = records.borrow_mut();
+        let expired: Vec<StringKey> = records
+            .iter()
+            .filter(|(_, record)| is_expired(record, now))
+            .map(|(key, _)| key)
+            .collect();
+        for key in &expired {
+            records.remove(key);
+        }
+        expired.len() as u64
+    })
+}
+
+fn _get_record(key: &str) -> Option<IdempotencyRecord> {
+    IDEMPOTENCY_STORAGE.with(|records| records.borrow().get(&record_key(key)))
+}
+
+fn is_expired(record: &IdempotencyRecord, now: u64) -> bool {
+    let ttl = match record.response {
+        Some(_) => KEY_TTL_NANOS,
+        None => PENDING_TTL_NANOS,
+    };
+    record.created_at.saturating_add(ttl) <= now
+}
+
+// Keys are scoped to the caller, so clients can't collide with or replay each other's requests
+fn record_key(key: &str) -> StringKey {
+    StringKey(format!("{}/{}", ic_cdk::caller().to_text(), key))
+}
+
+fn fingerprint(method: &str, args: impl CandidType) -> Vec<u8> {
+    let mut hasher = Sha256::new();
+    hasher.update(method.as_bytes());
+    hasher.update(Encode!(&args).unwrap());
+    hasher.finalize().to_vec()
+}
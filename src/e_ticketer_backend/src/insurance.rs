@@ -0,0 +1,262 @@
+use crate::clock::time;
+use crate::credits::RefundTarget;
+use crate::ratelimit::rate_limit;
+use crate::{addons, cancellations, organizers, Error, Memory, Ticket, MEMORY_MANAGER};
+use candid::{Decode, Encode};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use std::{borrow::Cow, cell::RefCell};
+
+// Define an enum for the fee charged for ticket insurance
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub enum InsuranceFee {
+    Flat { amount: u64 },
+    Percent { percent: u8 },
+}
+
+// Define a struct for the insurance offered at checkout for an event
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct InsuranceOffer {
+    event_id: u64,
+    fee: InsuranceFee,
+    // Insured tickets can be refunded in full until this timestamp
+    refundable_until: u64,
+    created_at: u64,
+    updated_at: Option<u64>,
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub struct InsuranceOfferPayload {
+    fee: InsuranceFee,
+    refundable_until: u64,
+}
+
+// Define a struct for the insurance bought with a ticket
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct TicketInsurance {
+    pub(crate) fee: u64,
+    refundable_until: u64,
+}
+
+// Define a struct for the result of a self-service refund
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct InsuranceRefund {
+    ticket_id: u64,
+    event_id: u64,
+    amount: u64,
+    // Credit refunds are added to the ticket holder's balance
+    refund_to: Option<RefundTarget>,
+    refunded_at: u64,
+}
+
+impl Storable for InsuranceOffer {
+    // Conversion to bytes
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    // Conversion from bytes
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for InsuranceOffer {
+    const MAX_SIZE: u32 = 256;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    // Insurance offers keyed by event id
+    static INSURANCE_OFFERS: RefCell<StableBTreeMap<u64, InsuranceOffer, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(10)))
+    ));
+}
+
+#[ic_cdk::query]
+fn get_event_insurance(event_id: u64) -> Result<InsuranceOffer, Error> {
+    INSURANCE_OFFERS
+        .with(|offers| offers.borrow().get(&event_id))
+        .ok_or(Error::NotFound {
+            entity: "insurance offer".to_string(),
+            id: event_id.to_string(),
+            msg: format!("event id:{} does not offer insurance", event_id),
+        })
+}
+
+#[ic_cdk::update(guard = "rate_limit")]
+fn set_event_insurance(
+    event_id: u64,
+    payload: InsuranceOfferPayload,
+) -> Result<InsuranceOffer, Error> {
+    // Only the event's organizers can change it, or return an error if the caller isn't one
+    organizers::authorize_event(event_id)?;
+
+    if let InsuranceFee::Percent { percent } = payload.fee {
+        if percent > 100 {
+            return Err(Error::ValidationFailed {
+                msg: format!("insurance fee of {}% is above 100%", percent),
+            });
+        }
+    }
+
+    let existing = INSURANCE_OFFERS.with(|offers| offers.borrow().get(&event_id));
+    let offer = InsuranceOffer {
+        event_id,
+        fee: payload.fee,
+        refundable_until: payload.refundable_until,
+        created_at: existing.as_ref().map_or(time(), |offer| offer.created_at),
+        updated_at: existing.map(|_| time()),
+    };
+
+    INSURANCE_OFFERS.with(|offers| offers.borrow_mut().insert(event_id, offer.clone()));
+
+    Ok(offer)
+}
+
+#[ic_cdk::update(guard = "rate_limit")]
+fn remove_event_insurance(event_id: u64) -> Result<String, Error> {
+    organizers::authorize_event(event_id)?;
+    // Tickets already insured keep their cover, only new purchases are affected
+    match INSURANCE_OFFERS.with(|offers| offers.borrow_mut().remove(&event_id)) {
+        Some(_) => Ok(format!("insurance of event id: {} removed", event_id)),
+        None => Err(Error::NotFound {
+            entity: "insurance offer".to_string(),
+            id: event_id.to_string(),
+            msg: format!("event id:{} does not offer insurance", event_id),
+        }),
+    }
+}
+
+#[ic_cdk::update(guard = "rate_limit")]
+fn refund_insured_ticket(
+    ticket_id: u64,
+    refund_to: Option<RefundTarget>,
+) -> Result<InsuranceRefund, Error> {
+    // Only the holder of a ticket can have it refunded
+    let ticket = cancellations::caller_ticket(ticket_id)?;
+
+    check_refundable(&ticket)?;
+
+    // Refunded in full with its add-ons like any other cancellation, which frees the place for
+    // the waitlist
+    let event_id = ticket.event_id;
+    let amount = ticket.price + addons::ticket_addons_total(&ticket);
+    let refund = cancellations::refund_ticket(ticket, amount, None, refund_to, None)?;
+
+    Ok(InsuranceRefund {
+        ticket_id,
+        event_id,
+        amount,
+        refund_to: Some(refund.refund_to),
+        refunded_at: refund.refunded_at,
+    })
+}
+
+// Insurance overrides any other refund rule until its cutoff
+fn check_refundable(ticket: &Ticket) -> Result<(), Error> {
+    let insurance = ticket.insurance.as_ref().ok_or(Error::ValidationFailed {
+        msg: format!("ticket id:{} is not insured", ticket.id),
+    })?;
+    if time() > insurance.refundable_until {
+        return Err(Error::ValidationFailed {
+            msg: format!("refund window of ticket id:{} has closed", ticket.id),
+        });
+    }
+    Ok(())
+}
+
+// Quote the insurance for a ticket of an event at the given price
+pub(crate) fn quote_insurance(event_id: u64, price: u64) -> Result<TicketInsurance, String> {
+    let offer = INSURANCE_OFFERS
+        .with(|offers| offers.borrow().get(&event_id))
+        .ok_or(format!("event id:{} does not offer insurance", event_id))?;
+
+    if time() > offer.refundable_until {
+        return Err(format!(
+            "insurance of event id:{} is no longer available",
+            event_id
+        ));
+    }
+
+    let fee = match offer.fee {
+        InsuranceFee::Flat { amount } => amount,
+        InsuranceFee::Percent { percent } => price * percent as u64 / 100,
+    };
+
+    Ok(TicketInsurance {
+        fee,
+        refundable_until: offer.refundable_until,
+    })
+}
+
+// Drop the insurance offer of a deleted event
+pub(crate) fn remove_event_insurance_offer(event_id: u64) {
+    INSURANCE_OFFERS.with(|offers| offers.borrow_mut().remove(&event_id));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{clock, testing};
+
+    fn insured_ticket(refundable_until: u64) -> Ticket {
+        Ticket {
+            id: 3001,
+            price: 100,
+            insurance: Some(TicketInsurance {
+                fee: 10,
+                refundable_until,
+            }),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn insured_ticket_is_refundable_until_its_cutoff() {
+        clock::set_time(testing::NOW);
+        assert!(check_refundable(&insured_ticket(testing::NOW)).is_ok());
+        assert!(check_refundable(&insured_ticket(testing::NOW - 1)).is_err());
+    }
+
+    #[test]
+    fn uninsured_ticket_is_not_refundable() {
+        clock::set_time(testing::NOW);
+        let ticket = Ticket {
+            id: 3001,
+            price: 100,
+            ..Default::default()
+        };
+        assert!(matches!(
+            check_refundable(&ticket),
+            Err(Error::ValidationFailed { .. })
+        ));
+    }
+
+    #[test]
+    fn insurance_fee_is_flat_or_a_share_of_the_price() {
+        clock::set_time(testing::NOW);
+        let offer = |fee| InsuranceOffer {
+            event_id: 1001,
+            fee,
+            refundable_until: testing::NOW + 1,
+            created_at: testing::NOW,
+            updated_at: None,
+        };
+        INSURANCE_OFFERS.with(|offers| {
+            offers
+                .borrow_mut()
+                .insert(1001, offer(InsuranceFee::Percent { percent: 15 }))
+        });
+        assert_eq!(quote_insurance(1001, 200).unwrap().fee, 30);
+        INSURANCE_OFFERS.with(|offers| {
+            offers
+                .borrow_mut()
+                .insert(1001, offer(InsuranceFee::Flat { amount: 7 }))
+        });
+        assert_eq!(quote_insurance(1001, 200).unwrap().fee, 7);
+
+        clock::set_time(testing::NOW + 2);
+        assert!(quote_insurance(1001, 200).is_err());
+    }
+}
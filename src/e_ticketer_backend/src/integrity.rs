@@ -0,0 +1,148 @@
+use crate::changes::{self, ChangeEntity, ChangeOperation};
+use crate::{
+    _get_stored_event, _get_stored_user, addons, certification, resale, seats, stats, store_event,
+    store_user, ticket_codes, tiers, Error, Event, Ticket, User, TICKET_STORAGE,
+};
+
+// Define an enum for what happens to the tickets and attendee links of a deleted record
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq, Default)]
+pub enum DeleteMode {
+    // Refuse the delete while tickets or attendee links point at the record
+    #[default]
+    Restrict,
+    // Cancel the tickets and drop the links along with the record
+    Cascade,
+}
+
+// Reject deleting an event that still has tickets or attendees, unless cascading
+pub(crate) fn check_event_dependents(event: &Event, mode: DeleteMode) -> Result<(), Error> {
+    if mode == DeleteMode::Restrict
+        && (!event.ticket_ids.is_empty() || !event.attendee_ids.is_empty())
+    {
+        return Err(Error::HasDependents {
+            msg: format!(
+                "event id:{} still has {} tickets and {} attendees",
+                event.id,
+                event.ticket_ids.len(),
+                event.attendee_ids.len()
+            ),
+        });
+    }
+    Ok(())
+}
+
+// Reject deleting a user who still holds tickets or attends events, unless cascading
+pub(crate) fn check_user_dependents(user: &User, mode: DeleteMode) -> Result<(), Error> {
+    if mode == DeleteMode::Restrict && (!user.ticket_ids.is_empty() || !user.event_ids.is_empty()) {
+        return Err(Error::HasDependents {
+            msg: format!(
+                "user id:{} still holds {} tickets and attends {} events",
+                user.id,
+                user.ticket_ids.len(),
+                user.event_ids.len()
+            ),
+        });
+    }
+    Ok(())
+}
+
+// Cancel every ticket of an event and unlink its attendees, leaving the event itself untouched.
+// Nothing here can fail halfway, so the cascade applies in full within the calling message.
+pub(crate) fn cascade_event(event: &Event) {
+    for ticket in event.ticket_ids.iter().filter_map(get_ticket) {
+        cancel_ticket(&ticket);
+    }
+    for user_id in &event.attendee_ids {
+        update_user(*user_id, |user| user.event_ids.retain(|&id| id != event.id));
+    }
+    update_event(event.id, |event| {
+        event.ticket_ids.clear();
+        event.attendee_ids.clear();
+    });
+}
+
+// Cancel every ticket of a user and take them off the events they attend
+pub(crate) fn cascade_user(user: &User) {
+    for ticket in user.ticket_ids.iter().filter_map(get_ticket) {
+        cancel_ticket(&ticket);
+    }
+    for event_id in &user.event_ids {
+        update_event(*event_id, |event| {
+            event.attendee_ids.retain(|&id| id != user.id)
+        });
+    }
+    update_user(user.id, |user| {
+        user.ticket_ids.clear();
+        user.event_ids.clear();
+    });
+}
+
+// Remove a ticket the way a cascade does, along with any resale listing of it
+pub(crate) fn remove_ticket(ticket_id: u64) {
+    if let Some(ticket) = get_ticket(&ticket_id) {
+        cancel_ticket(&ticket);
+        resale::remove_listing(ticket.event_id, ticket.id);
+    }
+}
+
+// Take users off the attendees of an event, and the event off the events they attend
+pub(crate) fn unlink_attendees(event_id: u64, user_ids: &[u64]) {
+    for user_id in user_ids {
+        update_user(*user_id, |user| user.event_ids.retain(|&id| id != event_id));
+    }
+    update_event(event_id, |event| {
+        event.attendee_ids.retain(|id| !user_ids.contains(id))
+    });
+}
+
+// Remove a ticket along with the references its user and event hold to it
+fn cancel_ticket(ticket: &Ticket) {
+    TICKET_STORAGE.with(|tickets| tickets.borrow_mut().remove(&ticket.id));
+    changes::record_change(
+        ChangeEntity::Ticket,
+        ticket.id,
+        Some(ticket.user_id),
+        ChangeOperation::Deleted,
+    );
+    update_user(ticket.user_id, |user| {
+        user.ticket_ids.retain(|&id| id != ticket.id)
+    });
+    update_event(ticket.event_id, |event| {
+        event.ticket_ids.retain(|&id| id != ticket.id)
+    });
+
+    if let Some(tier_id) = ticket.tier_id {
+        tiers::record_tier_release(ticket.event_id, tier_id);
+    }
+    if let Some(seat) = &ticket.seat {
+        seats::release_seat(ticket.event_id, seat);
+    }
+    if let Some(lines) = &ticket.addons {
+        addons::record_addon_release(ticket.event_id, lines);
+    }
+    if let Some(code) = &ticket.code {
+        ticket_codes::release_code(code);
+    }
+    stats::record_ticket_cancelled(ticket.event_id);
+}
+
+fn get_ticket(id: &u64) -> Option<Ticket> {
+    TICKET_STORAGE.with(|tickets| tickets.borrow().get(id))
+}
+
+// Apply a change to a stored event, including one in the trash
+fn update_event(event_id: u64, f: impl FnOnce(&mut Event)) {
+    if let Some(mut event) = _get_stored_event(&event_id) {
+        f(&mut event);
+        store_event(&mut event);
+        certification::certify_event(event_id);
+    }
+}
+
+// Apply a change to a stored user, including one in the trash
+fn update_user(user_id: u64, f: impl FnOnce(&mut User)) {
+    if let Some(mut user) = _get_stored_user(&user_id) {
+        f(&mut user);
+        store_user(&mut user);
+    }
+}
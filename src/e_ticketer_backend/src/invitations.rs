@@ -0,0 +1,309 @@
+use crate::clock::time;
+use crate::ratelimit::rate_limit;
+use crate::{
+    _get_event, _get_user, entropy, organizers, Error, Event, Memory, StringKey, ID_COUNTER,
+    MEMORY_MANAGER,
+};
+use candid::{Decode, Encode};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use std::{borrow::Cow, cell::RefCell};
+
+// Users invited per call, so a call stays well within the instruction limit
+const MAX_INVITES_PER_CALL: usize = 100;
+
+// Define an enum for who can find and buy tickets for an event
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq, Default)]
+pub enum EventVisibility {
+    // Listed and open to everyone
+    #[default]
+    Public,
+    // Left out of listings, and only invited users can get tickets
+    Private,
+    // Left out of listings, but open to anyone who has its id
+    Unlisted,
+}
+
+// Define an enum for the answer of an invitee
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq)]
+pub enum RsvpStatus {
+    Pending,
+    Accepted,
+    Declined,
+}
+
+// Define a struct for an invitation of a user to a private event
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct Invitation {
+    event_id: u64,
+    user_id: u64,
+    status: RsvpStatus,
+    // Link the user redeemed, None when invited directly
+    link_code: Option<String>,
+    invited_at: u64,
+    responded_at: Option<u64>,
+}
+
+// Define a struct for a shareable code any user can redeem for an invitation
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct InviteLink {
+    code: String,
+    event_id: u64,
+    max_uses: u64,
+    uses: u64,
+    created_at: u64,
+}
+
+impl Storable for Invitation {
+    // Conversion to bytes
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    // Conversion from bytes
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl Storable for InviteLink {
+    // Conversion to bytes
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    // Conversion from bytes
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for Invitation {
+    const MAX_SIZE: u32 = 256;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl BoundedStorable for InviteLink {
+    const MAX_SIZE: u32 = 256;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    // Invitations keyed by (event id, user id)
+    static INVITATION_STORAGE: RefCell<StableBTreeMap<(u64, u64), Invitation, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(49)))
+    ));
+
+    // Invite links keyed by code
+    static LINK_STORAGE: RefCell<StableBTreeMap<StringKey, InviteLink, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(50)))
+    ));
+}
+
+#[ic_cdk::update(guard = "rate_limit")]
+fn invite_users(event_id: u64, user_ids: Vec<u64>) -> Result<Vec<Invitation>, Error> {
+    organizers::authorize_event(event_id)?;
+    if user_ids.is_empty() || user_ids.len() > MAX_INVITES_PER_CALL {
+        return Err(Error::ValidationFailed {
+            msg: format!(
+                "between 1 and {} users can be invited at once",
+                MAX_INVITES_PER_CALL
+            ),
+        });
+    }
+    for user_id in &user_ids {
+        _get_user(user_id).ok_or(Error::not_found("user", user_id))?;
+    }
+
+    // Users invited before keep their invitation and answer
+    Ok(user_ids
+        .into_iter()
+        .map(|user_id| {
+            _get_invitation(event_id, user_id)
+                .unwrap_or_else(|| insert_invitation(event_id, user_id, None))
+        })
+        .collect())
+}
+
+#[ic_cdk::update(guard = "rate_limit")]
+fn create_invite_link(event_id: u64, max_uses: u64) -> Result<InviteLink, Error> {
+    organizers::authorize_event(event_id)?;
+    if max_uses == 0 {
+        return Err(Error::ValidationFailed {
+            msg: "an invite link must allow at least one use".to_string(),
+        });
+    }
+    // Without the random seed the code would only depend on the time
+    if !entropy::is_seeded() {
+        return Err(Error::InternalError {
+            msg: "randomness is not available yet, try again shortly".to_string(),
+            ticket_id: None,
+        });
+    }
+
+    // Increment the global ID counter so every code is drawn from a different input
+    let id = ID_COUNTER
+        .with(|counter| {
+            let current_id = *counter.borrow().get();
+            counter.borrow_mut().set(current_id + 1)
+        })
+        .expect("Cannot increment Ids");
+    let link = InviteLink {
+        code: entropy::random_token(b"invite-link", id),
+        event_id,
+        max_uses,
+        uses: 0,
+        created_at: time(),
+    };
+    LINK_STORAGE.with(|links| {
+        links
+            .borrow_mut()
+            .insert(StringKey(link.code.clone()), link.clone())
+    });
+
+    Ok(link)
+}
+
+#[ic_cdk::update(guard = "rate_limit")]
+fn redeem_invite_link(code: String, user_id: u64) -> Result<Invitation, Error> {
+    let key = StringKey(code.clone());
+    let mut link = LINK_STORAGE
+        .with(|links| links.borrow().get(&key))
+        .filter(|link| _get_event(&link.event_id).is_some())
+        .ok_or(Error::NotFound {
+            entity: "invite link".to_string(),
+            id: String::new(),
+            msg: "invite link does not exist".to_string(),
+        })?;
+    _get_user(&user_id).ok_or(Error::not_found("user", user_id))?;
+    if let Some(invitation) = _get_invitation(link.event_id, user_id) {
+        return Err(Error::ValidationFailed {
+            msg: format!(
+                "user id:{} is already invited to event id:{}",
+                invitation.user_id, invitation.event_id
+            ),
+        });
+    }
+    if link.uses >= link.max_uses {
+        return Err(Error::ValidationFailed {
+            msg: "invite link has no uses left".to_string(),
+        });
+    }
+
+    link.uses += 1;
+    let event_id = link.event_id;
+    LINK_STORAGE.with(|links| links.borrow_mut().insert(key, link));
+    // Redeeming a link is an answer in itself
+    let mut invitation = insert_invitation(event_id, user_id, Some(code));
+    invitation.status = RsvpStatus::Accepted;
+    invitation.responded_at = Some(invitation.invited_at);
+    INVITATION_STORAGE.with(|invitations| {
+        invitations
+            .borrow_mut()
+            .insert((event_id, user_id), invitation.clone())
+    });
+
+    Ok(invitation)
+}
+
+#[ic_cdk::update(guard = "rate_limit")]
+fn rsvp(event_id: u64, user_id: u64, accept: bool) -> Result<Invitation, Error> {
+    let mut invitation = _get_invitation(event_id, user_id).ok_or(Error::NotFound {
+        entity: "invitation".to_string(),
+        id: user_id.to_string(),
+        msg: format!(
+            "user id:{} is not invited to event id:{}",
+            user_id, event_id
+        ),
+    })?;
+
+    invitation.status = match accept {
+        true => RsvpStatus::Accepted,
+        false => RsvpStatus::Declined,
+    };
+    invitation.responded_at = Some(time());
+    INVITATION_STORAGE.with(|invitations| {
+        invitations
+            .borrow_mut()
+            .insert((event_id, user_id), invitation.clone())
+    });
+
+    Ok(invitation)
+}
+
+#[ic_cdk::query]
+fn get_event_invitations(event_id: u64) -> Result<Vec<Invitation>, Error> {
+    organizers::authorize_event(event_id)?;
+    Ok(INVITATION_STORAGE.with(|invitations| {
+        invitations
+            .borrow()
+            .range((event_id, 0)..=(event_id, u64::MAX))
+            .map(|(_, invitation)| invitation)
+            .collect()
+    }))
+}
+
+fn _get_invitation(event_id: u64, user_id: u64) -> Option<Invitation> {
+    INVITATION_STORAGE.with(|invitations| invitations.borrow().get(&(event_id, user_id)))
+}
+
+fn insert_invitation(event_id: u64, user_id: u64, link_code: Option<String>) -> Invitation {
+    let invitation = Invitation {
+        event_id,
+        user_id,
+        status: RsvpStatus::Pending,
+        link_code,
+        invited_at: time(),
+        responded_at: None,
+    };
+    INVITATION_STORAGE.with(|invitations| {
+        invitations
+            .borrow_mut()
+            .insert((event_id, user_id), invitation.clone())
+    });
+    invitation
+}
+
+// Whether an event shows up in listings and search, events from before visibility are public
+pub(crate) fn is_listed(event: &Event) -> bool {
+    event.visibility.unwrap_or_default() == EventVisibility::Public
+}
+
+// Check a user may get a ticket for an event, private events need an invitation not declined
+pub(crate) fn check_invited(event: &Event, user_id: u64) -> Result<(), String> {
+    if event.visibility != Some(EventVisibility::Private) {
+        return Ok(());
+    }
+    match _get_invitation(event.id, user_id) {
+        Some(invitation) if invitation.status != RsvpStatus::Declined => Ok(()),
+        _ => Err(format!(
+            "event id:{} is private and user id:{} holds no invitation",
+            event.id, user_id
+        )),
+    }
+}
+
+// Drop the invitations and invite links of a purged event
+pub(crate) fn remove_event_invitations(event_id: u64) {
+    INVITATION_STORAGE.with(|invitations| {
+        let mut invitations = invitations.borrow_mut();
+        let keys: Vec<(u64, u64)> = invitations
+            .range((event_id, 0)..=(event_id, u64::MAX))
+            .map(|(key, _)| key)
+            .collect();
+        for key in keys {
+            invitations.remove(&key);
+        }
+    });
+    LINK_STORAGE.with(|links| {
+        let mut links = links.borrow_mut();
+        let codes: Vec<StringKey> = links
+            .iter()
+            .filter(|(_, link)| link.event_id == event_id)
+            .map(|(code, _)| code)
+            .collect();
+        for code in codes {
+            links.remove(&code);
+        }
+    });
+}
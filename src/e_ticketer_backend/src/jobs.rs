@@ -0,0 +1,317 @@
+use crate::clock::time;
+use crate::{
+    _get_all_events, _get_event, announcements, anonymization, archive, backup, bulk,
+    caller_is_admin, complete_event, dates, display, disputes, idempotency, memberships,
+    notifications, remaining_capacity, reservations, waitlist, Error, Event, Memory,
+    MEMORY_MANAGER,
+};
+use candid::{Decode, Encode};
+use ic_cdk_timers::TimerId;
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use std::collections::HashMap;
+use std::time::Duration;
+use std::{borrow::Cow, cell::RefCell};
+
+const NANOS_PER_SECOND: u64 = 1_000_000_000;
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+
+// Jobs can't run more often than this, each run walks through all events
+const MIN_JOB_INTERVAL: u64 = 60;
+
+// Attendees are reminded once, this long before the event starts
+const REMINDER_LEAD: u64 = SECONDS_PER_DAY * NANOS_PER_SECOND;
+
+// Failures kept on the last run of a job, so the status stays within a stable map entry
+const MAX_RUN_FAILURES: usize = 10;
+
+// Define an enum for the periodic tasks run by the canister
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq)]
+pub enum JobKind {
+    // Issue freed up seats to the waitlist
+    PromoteWaitlists,
+    // Mark events whose date has passed as completed
+    CompleteEvents,
+    // Remind attendees of events starting soon
+    SendReminders,
+    // Release reservations that were never confirmed
+    ExpireReservations,
+    // Forget idempotency keys whose replay window is over
+    ExpireIdempotencyKeys,
+    // Strip attendee data from events past their organizers' retention period
+    AnonymizeEvents,
+    // Remove memberships past their expiry
+    ExpireMemberships,
+    // Move completed events past the archival retention period to the archive
+    ArchiveEvents,
+    // Escalate disputes left unresolved past their SLA, making their tickets refundable
+    EscalateDisputes,
+}
+
+// Define a struct for how often a job runs
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct JobConfig {
+    interval_secs: u64,
+    enabled: bool,
+}
+
+// Define a struct for the outcome of a single job run
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct JobRun {
+    ran_at: u64,
+    // Events acted upon, e.g. tickets issued or reminders queued
+    processed: u64,
+    failures: Vec<String>,
+}
+
+// Define a struct for the configuration of a job along with its last run
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct JobStatus {
+    kind: JobKind,
+    config: JobConfig,
+    last_run: Option<JobRun>,
+}
+
+impl JobKind {
+    const ALL: [JobKind; 9] = [
+        JobKind::PromoteWaitlists,
+        JobKind::CompleteEvents,
+        JobKind::SendReminders,
+        JobKind::ExpireReservations,
+        JobKind::ExpireIdempotencyKeys,
+        JobKind::AnonymizeEvents,
+        JobKind::ExpireMemberships,
+        JobKind::ArchiveEvents,
+        JobKind::EscalateDisputes,
+    ];
+
+    fn default_interval(&self) -> u64 {
+        match self {
+            JobKind::PromoteWaitlists => 5 * 60,
+            JobKind::CompleteEvents => 60 * 60,
+            JobKind::SendReminders => 15 * 60,
+            JobKind::ExpireReservations => 60,
+            JobKind::ExpireIdempotencyKeys => 60 * 60,
+            JobKind::AnonymizeEvents => 24 * 60 * 60,
+            JobKind::ExpireMemberships => 60 * 60,
+            JobKind::ArchiveEvents => 24 * 60 * 60,
+            JobKind::EscalateDisputes => 60 * 60,
+        }
+    }
+}
+
+impl Storable for JobStatus {
+    // Conversion to bytes
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    // Conversion from bytes
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for JobStatus {
+    const MAX_SIZE: u32 = 4096;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    // Job statuses keyed by the job kind
+    static JOB_STORAGE: RefCell<StableBTreeMap<u8, JobStatus, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(23)))
+    ));
+
+    // Time the reminder of an event was queued, keyed by event id
+    static REMINDERS_SENT: RefCell<StableBTreeMap<u64, u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(24)))
+    ));
+
+    // Timers currently armed for each job, so they can be replaced when reconfigured
+    static JOB_TIMERS: RefCell<HashMap<u8, TimerId>> = RefCell::new(HashMap::new());
+}
+
+// Helper function to get the status of a job, falling back to its default configuration
+fn _get_job(kind: JobKind) -> JobStatus {
+    JOB_STORAGE
+        .with(|jobs| jobs.borrow().get(&(kind as u8)))
+        .unwrap_or(JobStatus {
+            kind,
+            config: JobConfig {
+                interval_secs: kind.default_interval(),
+                enabled: true,
+            },
+            last_run: None,
+        })
+}
+
+#[ic_cdk::query(guard = "caller_is_admin")]
+fn get_jobs() -> Vec<JobStatus> {
+    JobKind::ALL.into_iter().map(_get_job).collect()
+}
+
+#[ic_cdk::update(guard = "caller_is_admin")]
+fn configure_job(kind: JobKind, config: JobConfig) -> Result<JobStatus, Error> {
+    if config.interval_secs < MIN_JOB_INTERVAL {
+        return Err(Error::ValidationFailed {
+            msg: format!("job interval must be at least {} seconds", MIN_JOB_INTERVAL),
+        });
+    }
+
+    let mut job = _get_job(kind);
+    job.config = config;
+    JOB_STORAGE.with(|jobs| jobs.borrow_mut().insert(kind as u8, job.clone()));
+    arm_timer(kind);
+
+    Ok(job)
+}
+
+#[ic_cdk::update(guard = "caller_is_admin")]
+fn run_job(kind: JobKind) -> JobRun {
+    execute_job(kind)
+}
+
+// Arm the timers of all enabled jobs, timers don't survive upgrades
+pub(crate) fn schedule_jobs() {
+    for kind in JobKind::ALL {
+        arm_timer(kind);
+    }
+}
+
+// Enabled jobs left without a timer
+pub(crate) fn unarmed_jobs() -> usize {
+    JobKind::ALL
+        .iter()
+        .filter(|kind| {
+            _get_job(**kind).config.enabled
+                && JOB_TIMERS.with(|timers| !timers.borrow().contains_key(&(**kind as u8)))
+        })
+        .count()
+}
+
+// Replace the timer of a job with one matching its current configuration
+fn arm_timer(kind: JobKind) {
+    if let Some(timer) = JOB_TIMERS.with(|timers| timers.borrow_mut().remove(&(kind as u8))) {
+        ic_cdk_timers::clear_timer(timer);
+    }
+
+    let config = _get_job(kind).config;
+    if !config.enabled {
+        return;
+    }
+    let timer =
+        ic_cdk_timers::set_timer_interval(Duration::from_secs(config.interval_secs), move || {
+            if !backup::in_maintenance() {
+                execute_job(kind);
+            }
+        });
+    JOB_TIMERS.with(|timers| timers.borrow_mut().insert(kind as u8, timer));
+}
+
+// Run a job and record the outcome as its last run
+fn execute_job(kind: JobKind) -> JobRun {
+    let mut failures = vec![];
+    let processed = match kind {
+        JobKind::PromoteWaitlists => promote_waitlists(),
+        JobKind::CompleteEvents => complete_past_events(&mut failures),
+        JobKind::SendReminders => send_reminders(),
+        JobKind::ExpireReservations => reservations::expire_reservations(),
+        JobKind::ExpireIdempotencyKeys => idempotency::expire_keys(),
+        JobKind::AnonymizeEvents => anonymization::anonymize_due_events(),
+        JobKind::ExpireMemberships => memberships::expire_memberships(),
+        JobKind::ArchiveEvents => archive::archive_due_events(),
+        JobKind::EscalateDisputes => disputes::escalate_overdue_disputes(),
+    };
+    failures.truncate(MAX_RUN_FAILURES);
+
+    let run = JobRun {
+        ran_at: time(),
+        processed,
+        failures,
+    };
+    let mut job = _get_job(kind);
+    job.last_run = Some(run.clone());
+    JOB_STORAGE.with(|jobs| jobs.borrow_mut().insert(kind as u8, job));
+
+    run
+}
+
+// Hand seats freed by deleted tickets to the waitlist, returning the number of tickets issued
+fn promote_waitlists() -> u64 {
+    waitlist::waitlisted_event_ids()
+        .into_iter()
+        .filter(|event_id| {
+            _get_event(event_id).is_some_and(|event| {
+                event.completed_at.is_none() && remaining_capacity(&event) != Some(0)
+            })
+        })
+        .map(waitlist::promote_waitlist)
+        .sum()
+}
+
+// Complete the events whose date is over, returning how many were completed
+fn complete_past_events(failures: &mut Vec<String>) -> u64 {
+    let today = (time() / NANOS_PER_SECOND / SECONDS_PER_DAY) as i64;
+    let mut completed = 0;
+    for event in _get_all_events() {
+        if event.completed_at.is_some() || !announcements::date_is_final(&event) {
+            continue;
+        }
+        let Some(date) = dates::parse_date(&event.date) else {
+            failures.push(format!(
+                "event id:{} has an invalid date {}",
+                event.id, event.date
+            ));
+            continue;
+        };
+        // Events have no end time, so they run until the end of their day
+        if dates::days_from_civil(date) >= today {
+            continue;
+        }
+        match complete_event(event.id) {
+            Ok(_) => completed += 1,
+            Err(error) => failures.push(bulk::error_msg(error)),
+        }
+    }
+    completed
+}
+
+// Queue reminders for the events starting within 'REMINDER_LEAD', returning how many were reminded
+fn send_reminders() -> u64 {
+    let now = time();
+    let mut reminded = 0;
+    for event in _get_all_events() {
+        if event.completed_at.is_some()
+            || !announcements::date_is_final(&event)
+            || REMINDERS_SENT.with(|sent| sent.borrow().contains_key(&event.id))
+        {
+            continue;
+        }
+        let starts_soon =
+            event_start(&event).is_some_and(|start| start > now && start - now <= REMINDER_LEAD);
+        if !starts_soon {
+            continue;
+        }
+        notifications::remind_event_attendees(&event);
+        REMINDERS_SENT.with(|sent| sent.borrow_mut().insert(event.id, now));
+        reminded += 1;
+    }
+    reminded
+}
+
+// Start of an event in nanoseconds since the epoch, events without a valid time start at midnight
+pub(crate) fn event_start(event: &Event) -> Option<u64> {
+    let days = dates::days_from_civil(dates::parse_date(&event.date)?);
+    let minutes = dates::parse_time(&event.start_time).unwrap_or(0) as i64;
+    // The date and start time are local to the event's timezone when it has one
+    let minutes = minutes - display::event_utc_offset(event.id);
+    let seconds = u64::try_from(days * SECONDS_PER_DAY as i64 + minutes * 60).ok()?;
+    Some(seconds * NANOS_PER_SECOND)
+}
+
+// Forget the reminder of a deleted event
+pub(crate) fn remove_event_reminder(event_id: u64) {
+    REMINDERS_SENT.with(|sent| sent.borrow_mut().remove(&event_id));
+}
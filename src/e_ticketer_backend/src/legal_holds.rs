@@ -0,0 +1,223 @@
+use crate::clock::time;
+use crate::{
+    _get_ticket, caller_is_admin, orders, Error, Event, Memory, Ticket, User, EVENT_STORAGE,
+    ID_COUNTER, MEMORY_MANAGER, USER_STORAGE,
+};
+use candid::{Decode, Encode, Principal};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use std::{borrow::Cow, cell::RefCell};
+
+const MAX_REASON_LENGTH: usize = 200;
+
+// Define an enum for the records a legal hold can be placed on
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq)]
+pub enum HoldTarget {
+    User { id: u64 },
+    Event { id: u64 },
+    Order { id: u64 },
+}
+
+// Define a struct for a legal hold, which keeps a record from being deleted, purged or
+// anonymized until it is lifted, whatever retention policies say
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct LegalHold {
+    id: u64,
+    target: HoldTarget,
+    // Why the record is kept, e.g. a case or request reference
+    reason: String,
+    placed_by: Principal,
+    placed_at: u64,
+    lifted_by: Option<Principal>,
+    lifted_at: Option<u64>,
+}
+
+impl Storable for LegalHold {
+    // Conversion to bytes
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    // Conversion from bytes
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for LegalHold {
+    const MAX_SIZE: u32 = 512;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    // Holds keyed by id, lifted ones are kept as a record of the hold
+    static HOLD_STORAGE: RefCell<StableBTreeMap<u64, LegalHold, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(104)))
+    ));
+}
+
+// Records in the trash can be held too, which keeps them from being purged
+#[ic_cdk::update(guard = "caller_is_admin")]
+fn place_legal_hold(target: HoldTarget, reason: String) -> Result<LegalHold, Error> {
+    let exists = match target {
+        HoldTarget::User { id } => USER_STORAGE.with(|users| users.borrow().contains_key(&id)),
+        HoldTarget::Event { id } => EVENT_STORAGE.with(|events| events.borrow().contains_key(&id)),
+        HoldTarget::Order { id } => orders::order_ticket_ids(id).is_some(),
+    };
+    if !exists {
+        let (entity, id) = record(target);
+        return Err(Error::not_found(entity, id));
+    }
+    if reason.trim().is_empty() || reason.len() > MAX_REASON_LENGTH {
+        return Err(Error::ValidationFailed {
+            msg: format!(
+                "a legal hold needs a reason of at most {} characters",
+                MAX_REASON_LENGTH
+            ),
+        });
+    }
+    if let Some(hold) = active_hold(target) {
+        return Err(Error::ValidationFailed {
+            msg: format!(
+                "{} is already under legal hold id:{}",
+                describe(target),
+                hold.id
+            ),
+        });
+    }
+
+    // Increment the global ID counter to get a new ID for the legal hold
+    let id = ID_COUNTER
+        .with(|counter| {
+            let current_id = *counter.borrow().get();
+            counter.borrow_mut().set(current_id + 1)
+        })
+        .expect("Cannot increment Ids");
+    let hold = LegalHold {
+        id,
+        target,
+        reason,
+        placed_by: ic_cdk::caller(),
+        placed_at: time(),
+        lifted_by: None,
+        lifted_at: None,
+    };
+    HOLD_STORAGE.with(|holds| holds.borrow_mut().insert(id, hold.clone()));
+    Ok(hold)
+}
+
+// Let the record be deleted, purged or anonymized again. Nothing that was skipped while it was
+// held is caught up here, the next purge or retention run picks it up.
+#[ic_cdk::update(guard = "caller_is_admin")]
+fn lift_legal_hold(id: u64) -> Result<LegalHold, Error> {
+    let mut hold = HOLD_STORAGE
+        .with(|holds| holds.borrow().get(&id))
+        .filter(|hold| hold.lifted_at.is_none())
+        .ok_or(Error::NotFound {
+            entity: "legal hold".to_string(),
+            id: id.to_string(),
+            msg: format!("legal hold id:{} does not exist or was lifted", id),
+        })?;
+    hold.lifted_by = Some(ic_cdk::caller());
+    hold.lifted_at = Some(time());
+    HOLD_STORAGE.with(|holds| holds.borrow_mut().insert(id, hold.clone()));
+    Ok(hold)
+}
+
+// Holds in force, along with lifted ones if asked
+#[ic_cdk::query(guard = "caller_is_admin")]
+fn get_legal_holds(include_lifted: Option<bool>) -> Vec<LegalHold> {
+    let include_lifted = include_lifted.unwrap_or(false);
+    HOLD_STORAGE.with(|holds| {
+        holds
+            .borrow()
+            .iter()
+            .map(|(_, hold)| hold)
+            .filter(|hold| include_lifted || hold.lifted_at.is_none())
+            .collect()
+    })
+}
+
+fn record(target: HoldTarget) -> (&'static str, u64) {
+    match target {
+        HoldTarget::User { id } => ("user", id),
+        HoldTarget::Event { id } => ("event", id),
+        HoldTarget::Order { id } => ("order", id),
+    }
+}
+
+fn describe(target: HoldTarget) -> String {
+    let (entity, id) = record(target);
+    format!("{} id:{}", entity, id)
+}
+
+fn active_holds() -> Vec<LegalHold> {
+    HOLD_STORAGE.with(|holds| {
+        holds
+            .borrow()
+            .iter()
+            .map(|(_, hold)| hold)
+            .filter(|hold| hold.lifted_at.is_none())
+            .collect()
+    })
+}
+
+fn active_hold(target: HoldTarget) -> Option<LegalHold> {
+    active_holds()
+        .into_iter()
+        .find(|hold| hold.target == target)
+}
+
+fn held_error(target: HoldTarget, hold: LegalHold) -> Error {
+    Error::OnLegalHold {
+        msg: format!("{} is under legal hold id:{}", describe(target), hold.id),
+        hold_id: hold.id,
+    }
+}
+
+// Reject removing a user, an event or an order that is held
+pub(crate) fn check_record(target: HoldTarget) -> Result<(), Error> {
+    match active_hold(target) {
+        Some(hold) => Err(held_error(target, hold)),
+        None => Ok(()),
+    }
+}
+
+// Reject removing or anonymizing a ticket whose holder, event or order is held
+pub(crate) fn check_ticket(ticket: &Ticket) -> Result<(), Error> {
+    check_record(HoldTarget::User { id: ticket.user_id })?;
+    check_record(HoldTarget::Event {
+        id: ticket.event_id,
+    })?;
+    for hold in active_holds() {
+        if let HoldTarget::Order { id } = hold.target {
+            if orders::order_ticket_ids(id).is_some_and(|ids| ids.contains(&ticket.id)) {
+                return Err(held_error(hold.target, hold));
+            }
+        }
+    }
+    Ok(())
+}
+
+// Reject removing or anonymizing the tickets and attendees of an event, which are held along
+// with the event, any of its attendees and any order of its tickets
+pub(crate) fn check_event(event: &Event) -> Result<(), Error> {
+    check_record(HoldTarget::Event { id: event.id })?;
+    for user_id in &event.attendee_ids {
+        check_record(HoldTarget::User { id: *user_id })?;
+    }
+    event
+        .ticket_ids
+        .iter()
+        .filter_map(_get_ticket)
+        .try_for_each(|ticket| check_ticket(&ticket))
+}
+
+// Reject removing a user or stripping their tickets while they, or any of their tickets, are held
+pub(crate) fn check_user(user: &User) -> Result<(), Error> {
+    check_record(HoldTarget::User { id: user.id })?;
+    user.ticket_ids
+        .iter()
+        .filter_map(_get_ticket)
+        .try_for_each(|ticket| check_ticket(&ticket))
+}
@@ -1,14 +1,216 @@
 #[macro_use]
 extern crate serde;
-use candid::{Decode, Encode};
-use ic_cdk::api::time;
+use candid::{Decode, Encode, Nat, Principal};
+use ic_cdk::api::management_canister::http_request::{
+    HttpResponse as OutcallResponse, TransformArgs,
+};
 use ic_stable_structures::memory_manager::{MemoryId, MemoryManager, VirtualMemory};
 use ic_stable_structures::{BoundedStorable, Cell, DefaultMemoryImpl, StableBTreeMap, Storable};
-use std::{borrow::Cow, cell::RefCell};
+use serde_bytes::ByteBuf;
+use std::{borrow::Cow, cell::RefCell, thread::LocalKey};
+
+mod access;
+mod addons;
+mod alerts;
+mod announcements;
+mod anonymization;
+mod archive;
+mod assets;
+mod attendance;
+mod attendee_notes;
+mod auth;
+mod backup;
+mod broadcasts;
+mod bulk;
+mod calendar;
+mod cancellations;
+#[cfg(test)]
+mod candid_interface;
+mod certification;
+mod changes;
+mod checkin;
+mod clock;
+mod compaction;
+mod config;
+mod credentials;
+mod credits;
+mod dates;
+mod discovery;
+mod display;
+mod disputes;
+mod donations;
+mod emails;
+mod embargo;
+mod entropy;
+mod export;
+mod favorites;
+mod federation;
+mod flash_sales;
+mod fraud;
+mod geo;
+mod group_bookings;
+mod health;
+mod history;
+mod http;
+mod icrc7;
+mod idempotency;
+mod insurance;
+mod integrity;
+mod invitations;
+mod jobs;
+mod legal_holds;
+mod live;
+mod memberships;
+mod memos;
+mod metadata;
+mod moderation;
+mod notifications;
+mod occupancy;
+mod orders;
+mod organizers;
+mod passes;
+mod payments;
+mod payouts;
+mod privacy;
+mod promo;
+mod qr_tokens;
+mod questions;
+mod ratelimit;
+mod rates;
+mod registrations;
+mod remediation;
+mod resale;
+mod reschedules;
+mod reservations;
+mod reviews;
+mod rooms;
+mod sandbox;
+mod seats;
+mod series;
+mod sponsorship;
+mod statements;
+mod stats;
+mod storage;
+mod support;
+mod surveys;
+mod templates;
+#[cfg(test)]
+mod testing;
+mod ticket_codes;
+mod ticket_wall;
+mod tiers;
+mod translations;
+mod trash;
+mod validation;
+mod venues;
+mod waiting_room;
+mod waitlist;
+mod webhooks;
+
+use access::{Access, MethodPolicy};
+use addons::{AddOn, AddOnLine, AddOnPayload, AddOnSales, AddOnSelection};
+use alerts::{CapacityAlert, CapacityAlertSettings};
+use announcements::{AnnouncementPayload, AnnouncementStage};
+use anonymization::AnonymizationPolicy;
+use archive::{ArchivalSettings, HydratedEvent};
+use assets::{EventImage, ImageChunk, Upload};
+use attendance::{
+    AttendanceBadge, AttendanceProof, AttendanceRecord, CertifiedAttendanceProof,
+    EventAttendanceRate,
+};
+use attendee_notes::{AttendeeNote, AttendeeNotePayload};
+use auth::{ApiKeyInfo, ApiScope, CreatedApiKey};
+use backup::BackupManifest;
+use broadcasts::Broadcast;
+use bulk::BulkItemError;
+use cancellations::{CancellationPolicy, CancellationRefund, CancellationRule};
+use changes::{ChangeEntity, ChangeOperation, ChangePage};
+use checkin::{
+    CheckInConflict, CheckInManifest, CheckInOutcome, CheckInRecord, CheckInResult, ScannerDevice,
+};
+use clock::time;
+use compaction::CompactionStatus;
+use config::Config;
+use credentials::{ReauthPolicy, SessionToken};
+use credits::{CreditBalance, RefundPolicy, RefundTarget};
+use discovery::{
+    EventCategory, EventFilter, EventListingPage, EventPage, EventSort, EventSuggestion, TagCount,
+};
+use display::DisplayPreferences;
+use disputes::{Dispute, DisputeStatus};
+use donations::{DonationReceipt, DonationSettings};
+use embargo::{EventEmbargo, PressView};
+use export::{ExportChunk, ExportFormat};
+use federation::{EventFeed, FederationConfig, FederationPush};
+use flash_sales::{FlashSale, FlashSalePayload, FlashSaleStatus};
+use fraud::{FlagStatus, FraudFlag};
+use geo::{AccessibilityFeature, NearbyEventPage, Venue};
+use group_bookings::{GroupBooking, GroupBookingOptions};
+use health::{CanisterHealth, HealthReport, HealthSettings};
+use history::EventChangePage;
+use http::{HttpRequest, HttpResponse};
+use icrc7::{Account, SupportedStandard, TransferArg, TransferError, Value};
+use insurance::{InsuranceOffer, InsuranceOfferPayload, InsuranceRefund, TicketInsurance};
+use integrity::DeleteMode;
+use invitations::{EventVisibility, Invitation, InviteLink};
+use jobs::{JobConfig, JobKind, JobRun, JobStatus};
+use legal_holds::{HoldTarget, LegalHold};
+use live::CertifiedLiveCounters;
+use memberships::{Membership, MembershipTier, MembershipTierPayload, SaleWindows};
+use memos::TransferMemo;
+use metadata::Metadata;
+use moderation::{Report, ReportStatus, Sanction};
+use notifications::{Notification, NotificationKind};
+use occupancy::{EventOccupancy, GateDevice};
+use orders::{Order, OrderExportPage};
+use organizers::Organizer;
+use passes::{Pass, PassPayload, PassUsage};
+use payments::{CkBtcConfig, CkBtcDeposit, CkBtcFees, Payment, PaymentDeposit, PaymentMethod};
+use payouts::{EventBalance, PayoutAccount, PayoutEntry, RevenueShare, RevenueSplit};
+use privacy::{ErasureRecord, UserDataExport};
+use promo::{PromoCode, PromoCodePayload};
+use qr_tokens::{QrTokenConfig, QrVerificationKey};
+use questions::Answer;
+use ratelimit::{rate_limit, DeniedPrincipal, RateLimitSettings, RateLimitStats};
+use rates::{ExchangeRate, QuotedRate};
+use registrations::{PresaleRegistration, PresaleRegistrationPayload, Registration};
+use resale::{
+    BlackoutWindow, ResaleCap, ResaleListing, ResaleListingPage, ResalePayout, ResaleSale,
+};
+use reschedules::{NewTimes, Reschedule};
+use reservations::Reservation;
+use reviews::{EventRating, Review, ReviewPage};
+use rooms::{Room, RoomPayload, ZoneAssignment};
+use sandbox::TestModeEvent;
+use seats::{Seat, SeatHold, SeatMap};
+use series::{RecurrenceRule, SeriesDetails, SeriesUpdateScope};
+use sponsorship::{
+    CommitmentPayload, SponsorshipCommitment, SponsorshipOffer, SponsorshipOfferPayload,
+};
+use statements::Statement;
+use stats::{EventRevenue, EventStats, PlatformStats};
+use storage::{StorageBreakdown, StorageQuotas};
+use support::{SupportAction, SupportSession};
+use surveys::{EventSurvey, SurveyInvitation, SurveyPayload, SurveyResults};
+use templates::{EventOverrides, SavedTemplateInfo, TemplateFormat};
+use ticket_codes::{EventCodeFormat, TicketCodeFormat};
+use ticket_wall::{TicketPass, TicketViewPage};
+use tiers::{HiddenTier, TicketTier, TierPayload};
+use translations::{EventTranslation, TranslationPayload};
+use validation::ValidationHook;
+use venues::{ManagedVenue, ManagedVenuePayload, VenueScheduleEntry};
+use waiting_room::WaitingRoomStatus;
+use waitlist::WaitlistEntry;
+use webhooks::{Webhook, WebhookDelivery, WebhookEventType, WebhookPayload};
 
 // Define type aliases for convenience
 type Memory = VirtualMemory<DefaultMemoryImpl>;
 type IdCell = Cell<u64, Memory>;
+type IdList = StableBTreeMap<(u64, u64), u64, Memory>;
+
+// Define a bounded string key for stable maps indexed by text
+#[derive(Clone, Default, PartialEq, Eq, PartialOrd, Ord)]
+struct StringKey(String);
 
 // Define a struct for the 'Event'
 #[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
@@ -18,11 +220,53 @@ struct Event {
     description: String,
     date: String,
     start_time: String,
-    location: String,
+    // None while the venue is to be announced
+    venue: Option<Venue>,
+    capacity: Option<u64>,
+    // Tickets a single user can hold for the event, unlimited if None
+    max_tickets_per_user: Option<u64>,
+    series_id: Option<u64>,
+    // Organizers allowed to change the event, None on events from before organizers existed
+    organizer_ids: Option<Vec<u64>>,
+    // Who can find the event and get tickets, None on events from before visibility is public
+    visibility: Option<EventVisibility>,
+    // How much of the event has been announced, tickets only sell once it is fully announced
+    announcement: Option<AnnouncementStage>,
+    category: Option<EventCategory>,
+    // Normalized through 'discovery::validate_tags', None on events stored before tags
+    tags: Option<Vec<String>>,
+    // Sorted by key through 'metadata::validate_metadata', None on events stored before metadata
+    metadata: Option<Metadata>,
+    // Offered for this event on top of its venue's, e.g. a quiet room set up for the day
+    accessibility: Option<Vec<AccessibilityFeature>>,
+    // Set once the organizer marks the event as over
+    completed_at: Option<u64>,
     attendee_ids: Vec<u64>,
     ticket_ids: Vec<u64>,
     created_at: u64,
     updated_at: Option<u64>,
+    // Bumped on every write, None on events stored before versions
+    version: Option<u64>,
+    // Set while the record is in the trash, it can be restored until purged
+    deleted_at: Option<u64>,
+}
+
+// Define a struct for an event returned along with its certification
+#[derive(candid::CandidType, Serialize)]
+struct CertifiedEvent {
+    event: Event,
+    // IC certificate of the canister's certified data, absent in replicated calls
+    certificate: Option<ByteBuf>,
+    // CBOR hash tree proving the SHA-256 of the candid-encoded event under /events/<id>
+    witness: ByteBuf,
+}
+
+// Define a struct for all events returned along with their certification
+#[derive(candid::CandidType, Serialize)]
+struct CertifiedEvents {
+    events: Vec<Event>,
+    certificate: Option<ByteBuf>,
+    witness: ByteBuf,
 }
 
 // Define a struct for the 'User'
@@ -31,27 +275,50 @@ struct User {
     id: u64,
     name: String,
     email: String,
-    password: String,
     event_ids: Vec<u64>,
     ticket_ids: Vec<u64>,
     created_at: u64,
     updated_at: Option<u64>,
+    // Bumped on every write, None on users stored before versions
+    version: Option<u64>,
+    // Set while the record is in the trash, it can be restored until purged
+    deleted_at: Option<u64>,
 }
 
 // Define a struct for the 'Ticket'
 #[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
 struct Ticket {
     id: u64,
+    // Printed code following the event's code format, if it has one
+    code: Option<String>,
     event_id: u64,
     user_id: u64,
+    tier_id: Option<u64>,
+    // Price charged for the ticket, after any promo code discount
+    price: u64,
+    // Currency the price is in, None when in the event's payment token
+    currency: Option<String>,
+    // Rate the price was converted to the event's token at when it was paid
+    quoted_rate: Option<QuotedRate>,
+    promo_code: Option<String>,
+    insurance: Option<TicketInsurance>,
+    // Reserved seat on the event's seat map, if it has one
+    seat: Option<Seat>,
+    // Add-ons bought along with the ticket, None if there were none
+    addons: Option<Vec<AddOnLine>>,
+    // Sorted by key through 'metadata::validate_metadata', None on tickets stored before metadata
+    metadata: Option<Metadata>,
+    checked_in_at: Option<u64>,
     created_at: u64,
     updated_at: Option<u64>,
+    // Bumped on every write, None on tickets stored before versions
+    version: Option<u64>,
 }
 
 // Implement the 'Storable' trait for 'Event', 'User', and 'Ticket'
 impl Storable for Event {
     // Conversion to bytes
-    fn to_bytes(&self) -> Cow<[u8]> {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
         Cow::Owned(Encode!(self).unwrap())
     }
     // Conversion from bytes
@@ -62,7 +329,7 @@ impl Storable for Event {
 
 impl Storable for User {
     // Conversion to bytes
-    fn to_bytes(&self) -> Cow<[u8]> {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
         Cow::Owned(Encode!(self).unwrap())
     }
     // Conversion from bytes
@@ -71,9 +338,20 @@ impl Storable for User {
     }
 }
 
+impl Storable for StringKey {
+    // Conversion to bytes, kept as raw UTF-8 so keys sort alphabetically
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Borrowed(self.0.as_bytes())
+    }
+    // Conversion from bytes
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        StringKey(String::from_utf8(bytes.into_owned()).unwrap())
+    }
+}
+
 impl Storable for Ticket {
     // Conversion to bytes
-    fn to_bytes(&self) -> Cow<[u8]> {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
         Cow::Owned(Encode!(self).unwrap())
     }
     // Conversion from bytes
@@ -98,6 +376,11 @@ impl BoundedStorable for Ticket {
     const IS_FIXED_SIZE: bool = false;
 }
 
+impl BoundedStorable for StringKey {
+    const MAX_SIZE: u32 = 128;
+    const IS_FIXED_SIZE: bool = false;
+}
+
 // Define thread-local static variables for memory management and storage
 thread_local! {
     static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> = RefCell::new(
@@ -123,16 +406,48 @@ thread_local! {
         RefCell::new(StableBTreeMap::init(
             MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(3)))
     ));
+
+    // Ticket ids of the events keyed by (event id, position), kept out of the events so an
+    // event doesn't outgrow its entry as it sells
+    static EVENT_TICKET_IDS: RefCell<IdList> = RefCell::new(StableBTreeMap::init(
+        MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(147)))
+    ));
+
+    // Attendee ids of the events keyed by (event id, position)
+    static EVENT_ATTENDEE_IDS: RefCell<IdList> = RefCell::new(StableBTreeMap::init(
+        MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(148)))
+    ));
+
+    // Ticket ids of the users keyed by (user id, position)
+    static USER_TICKET_IDS: RefCell<IdList> = RefCell::new(StableBTreeMap::init(
+        MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(149)))
+    ));
+
+    // Event ids of the users keyed by (user id, position)
+    static USER_EVENT_IDS: RefCell<IdList> = RefCell::new(StableBTreeMap::init(
+        MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(150)))
+    ));
 }
 
 // Define structs for payload data (used in update calls)
-#[derive(candid::CandidType, Serialize, Deserialize, Default)]
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
 struct EventPayload {
     name: String,
     description: String,
     date: String,
     start_time: String,
-    location: String,
+    // Left out while the venue is to be announced
+    venue: Option<Venue>,
+    capacity: Option<u64>,
+    max_tickets_per_user: Option<u64>,
+    // Stage to publish the event at, all details are final if omitted
+    announcement: Option<AnnouncementStage>,
+    // Left unchanged on update when omitted
+    category: Option<EventCategory>,
+    tags: Option<Vec<String>>,
+    metadata: Option<Metadata>,
+    visibility: Option<EventVisibility>,
+    accessibility: Option<Vec<AccessibilityFeature>>,
 }
 
 #[derive(candid::CandidType, Serialize, Deserialize, Default)]
@@ -142,39 +457,296 @@ struct UserPayload {
     password: String,
 }
 
+// Passwords change through 'change_password', which checks the current one
 #[derive(candid::CandidType, Serialize, Deserialize, Default)]
+struct UserUpdatePayload {
+    name: String,
+    email: String,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
 struct TicketPayload {
     event_id: u64,
     user_id: u64,
+    tier_id: Option<u64>,
+    promo_code: Option<String>,
+    // Buy the event's refund insurance along with the ticket
+    insured: bool,
+    // Optional donation to the event, recorded as its own line item
+    donation: Option<u64>,
+    // Seat to reserve on the event's seat map, fixed once purchased
+    seat: Option<Seat>,
+    // Left unchanged on update when omitted
+    metadata: Option<Metadata>,
+    // Unlocks a hidden tier of the event
+    unlock_code: Option<String>,
+    // Add-ons of the event bought along with the ticket
+    addons: Option<Vec<AddOnSelection>>,
+}
+
+// Define a struct for the price of a ticket along with the add-ons checked for it
+struct TicketQuote {
+    price: u64,
+    currency: Option<String>,
+    promo: Option<PromoCode>,
+    // Flash sale the price was discounted by
+    flash_sale: Option<u64>,
+    insurance: Option<TicketInsurance>,
+    addons: Vec<AddOnLine>,
+    metadata: Metadata,
+}
+
+#[ic_cdk::init]
+fn init() {
+    certification::certify_all_events();
+    notifications::schedule_delivery();
+    webhooks::schedule_delivery();
+    federation::schedule_pushes();
+    rates::schedule_refresh();
+    entropy::schedule_seeding();
+    jobs::schedule_jobs();
+    embargo::schedule_embargoes();
+}
+
+#[ic_cdk::post_upgrade]
+fn post_upgrade() {
+    // Heap state and timers are lost on upgrade, rebuild them from stable memory
+    certification::certify_all_events();
+    notifications::schedule_delivery();
+    webhooks::schedule_delivery();
+    federation::schedule_pushes();
+    rates::schedule_refresh();
+    entropy::schedule_seeding();
+    jobs::schedule_jobs();
+    credentials::schedule_migration();
+    emails::schedule_indexing();
+    discovery::schedule_name_indexing(0);
+    broadcasts::resume_fan_out();
+    embargo::schedule_embargoes();
+    geo::schedule_migration();
+    compaction::resume_compaction();
+    sandbox::resume_resets();
 }
 
 // Define the Candid interface
+// Events are given in the language asked for where they have a translation. The witness covers
+// them as stored, clients verifying it ask without a language. Every event comes in full, so
+// large catalogues are better paged through with 'list_events'.
 #[ic_cdk::query]
-fn get_all_events() -> Vec<Event> {
+fn get_all_events(lang: Option<String>) -> CertifiedEvents {
+    // Return all events with a witness covering every one of them
+    CertifiedEvents {
+        events: _get_public_events()
+            .into_iter()
+            .map(|event| translations::localize(event, lang.as_deref()))
+            .collect(),
+        certificate: certification::certificate(),
+        witness: certification::all_events_witness(),
+    }
+}
+
+fn _get_all_events() -> Vec<Event> {
     // Retrieve all events from the storage and return them as a Vec
     let events_map: Vec<(u64, Event)> =
         EVENT_STORAGE.with(|events| events.borrow().iter().collect());
-    events_map.into_iter().map(|(_, event)| event).collect()
+    events_map
+        .into_iter()
+        .map(|(_, event)| event)
+        .filter(|event| event.deleted_at.is_none())
+        .map(load_event)
+        .collect()
 }
 
 #[ic_cdk::query]
-fn get_event(id: u64) -> Result<Event, Error> {
+fn get_event(id: u64, lang: Option<String>) -> Result<CertifiedEvent, Error> {
     // Retrieve a specific event by ID and return it, or return a NotFound error if not found
-    match _get_event(&id) {
-        Some(event) => Ok(event),
-        None => Err(Error::NotFound {
-            msg: format!("event id:{} does not exist", id),
+    match _get_public_event(&id) {
+        Some(event) => Ok(CertifiedEvent {
+            event: translations::localize(event, lang.as_deref()),
+            certificate: certification::certificate(),
+            witness: certification::event_witness(id),
         }),
+        None => Err(Error::not_found("event", id)),
     }
 }
 
+// Helper function to get the events everyone can see, embargoed ones are left out
+fn _get_public_events() -> Vec<Event> {
+    // Events everyone can see and that show up in listings
+    _get_all_events()
+        .into_iter()
+        .filter(|event| {
+            embargo::is_public(event.id)
+                && invitations::is_listed(event)
+                && !moderation::is_suspended(event.id)
+        })
+        .collect()
+}
+
+fn _get_listed_event(id: &u64) -> Option<Event> {
+    // Helper function to get an event if it shows up in listings and search
+    _get_public_event(id)
+        .filter(|event| invitations::is_listed(event) && !moderation::is_suspended(event.id))
+}
+
+fn _get_public_event(id: &u64) -> Option<Event> {
+    // Helper function to get an event if everyone can see it
+    _get_event(id).filter(|event| embargo::is_public(event.id))
+}
+
 fn _get_event(id: &u64) -> Option<Event> {
     // Helper function to get an event from the storage based on the provided ID
-    EVENT_STORAGE.with(|events| events.borrow().get(id))
+    // Events in the trash are left out
+    _get_stored_event(id).filter(|event| event.deleted_at.is_none())
+}
+
+// Helper function to get an event from the storage, including one in the trash
+fn _get_stored_event(id: &u64) -> Option<Event> {
+    EVENT_STORAGE
+        .with(|events| events.borrow().get(id))
+        .map(load_event)
+}
+
+// Helper function to fill in the ticket and attendee ids of an event read from the storage
+fn load_event(mut event: Event) -> Event {
+    event.ticket_ids = load_ids(&EVENT_TICKET_IDS, event.id, event.ticket_ids);
+    event.attendee_ids = load_ids(&EVENT_ATTENDEE_IDS, event.id, event.attendee_ids);
+    event
+}
+
+// Helper function to store an event, bumping its version on the way in. Its ticket and
+// attendee ids go to maps of their own, the previous event is returned without them.
+fn store_event(event: &mut Event) -> Option<Event> {
+    event.version = Some(event.version.unwrap_or(0) + 1);
+    store_ids(&EVENT_TICKET_IDS, event.id, &event.ticket_ids);
+    store_ids(&EVENT_ATTENDEE_IDS, event.id, &event.attendee_ids);
+    let stored = Event {
+        ticket_ids: vec![],
+        attendee_ids: vec![],
+        ..event.clone()
+    };
+    let previous = EVENT_STORAGE.with(|events| events.borrow_mut().insert(event.id, stored));
+    changes::record_change(
+        ChangeEntity::Event,
+        event.id,
+        None,
+        changes::stored(previous.is_some(), event.deleted_at),
+    );
+    previous
+}
+
+// Helper function to drop the ids of a purged event
+fn remove_event_ids(event_id: u64) {
+    store_ids(&EVENT_TICKET_IDS, event_id, &[]);
+    store_ids(&EVENT_ATTENDEE_IDS, event_id, &[]);
+}
+
+// Check an event fits in its entry in the storage, as storing a larger one would trap
+fn check_event_size(event: &Event) -> Result<(), Error> {
+    let size = Event {
+        ticket_ids: vec![],
+        attendee_ids: vec![],
+        ..event.clone()
+    }
+    .to_bytes()
+    .len();
+    if size > Event::MAX_SIZE as usize {
+        return Err(Error::ValidationFailed {
+            msg: format!(
+                "event {} takes {} bytes, more than the {} an event can take, shorten its \
+                 description, tags or metadata",
+                event.name,
+                size,
+                Event::MAX_SIZE
+            ),
+        });
+    }
+    Ok(())
+}
+
+// Ids kept for a record in the order they were added. A record stored before its ids moved
+// out still holds them until it is stored again.
+fn load_ids(index: &'static LocalKey<RefCell<IdList>>, owner_id: u64, held: Vec<u64>) -> Vec<u64> {
+    let stored: Vec<u64> = index.with(|index| {
+        index
+            .borrow()
+            .range((owner_id, 0)..=(owner_id, u64::MAX))
+            .map(|(_, id)| id)
+            .collect()
+    });
+    if stored.is_empty() {
+        held
+    } else {
+        stored
+    }
 }
 
-#[ic_cdk::update]
-fn create_event(payload: EventPayload) -> Result<Event, Error> {
+// Replace the ids kept for a record. New ids are appended after those already stored, the
+// list is only written again when one was removed or they were reordered.
+fn store_ids(index: &'static LocalKey<RefCell<IdList>>, owner_id: u64, ids: &[u64]) {
+    index.with(|index| {
+        let mut index = index.borrow_mut();
+        let stored: Vec<((u64, u64), u64)> =
+            index.range((owner_id, 0)..=(owner_id, u64::MAX)).collect();
+        let kept = stored.len() <= ids.len()
+            && stored.iter().zip(ids).all(|((_, id), wanted)| id == wanted);
+        let from = if kept {
+            stored.len()
+        } else {
+            for (key, _) in stored {
+                index.remove(&key);
+            }
+            0
+        };
+        for (position, id) in ids.iter().enumerate().skip(from) {
+            index.insert((owner_id, position as u64), *id);
+        }
+    });
+}
+
+#[ic_cdk::update(guard = "rate_limit")]
+async fn create_event(payload: EventPayload) -> Result<Event, Error> {
+    organizers::check_can_create()?;
+    validation::validate_event(&payload)
+        .await
+        .map_err(|msg| Error::ValidationFailed { msg })?;
+    _create_event(payload)
+}
+
+// Function to store a new event once any validation hook approved it
+fn _create_event(payload: EventPayload) -> Result<Event, Error> {
+    storage::check_quota(storage::EVENTS)?;
+    // Events can be published as a teaser, with the details required by their stage
+    let announcement = payload
+        .announcement
+        .unwrap_or(AnnouncementStage::FullyAnnounced);
+    if payload.announcement.is_some() {
+        announcements::validate_stage(
+            announcement,
+            &payload.date,
+            &payload.start_time,
+            payload.venue.as_ref(),
+        )
+        .map_err(|msg| Error::ValidationFailed { msg })?;
+    }
+    let tags = match &payload.tags {
+        Some(tags) => {
+            discovery::validate_tags(tags).map_err(|msg| Error::ValidationFailed { msg })?
+        }
+        None => vec![],
+    };
+    if let Some(venue) = &payload.venue {
+        geo::validate_venue(venue).map_err(|msg| Error::ValidationFailed { msg })?;
+    }
+    validate_purchase_limit(payload.max_tickets_per_user)
+        .map_err(|msg| Error::ValidationFailed { msg })?;
+    let metadata = match &payload.metadata {
+        Some(metadata) => {
+            metadata::validate_metadata(metadata).map_err(|msg| Error::ValidationFailed { msg })?
+        }
+        None => vec![],
+    };
+
     // Increment the global ID counter to get a new ID for the event
     let id = ID_COUNTER
         .with(|counter| {
@@ -184,34 +756,96 @@ fn create_event(payload: EventPayload) -> Result<Event, Error> {
         .expect("Cannot increment Ids");
 
     // Create a new Event with the provided payload and the generated ID
-    let event = Event {
+    let mut event = Event {
         id,
         name: payload.name.clone(),
         description: payload.description,
         date: payload.date,
         start_time: payload.start_time,
-        location: payload.location,
+        venue: payload.venue,
+        capacity: payload.capacity,
+        max_tickets_per_user: payload.max_tickets_per_user,
+        series_id: None,
+        // The organizer creating the event runs it, events created by admins start without one
+        organizer_ids: Some(organizers::caller_organizer_id().into_iter().collect()),
+        visibility: Some(payload.visibility.unwrap_or_default()),
+        announcement: Some(announcement),
+        category: payload.category,
+        tags: Some(tags),
+        metadata: Some(metadata),
+        accessibility: Some(geo::normalize_accessibility(
+            payload.accessibility.as_deref().unwrap_or_default(),
+        )),
+        completed_at: None,
         attendee_ids: vec![],
         ticket_ids: vec![],
         created_at: time(),
         updated_at: None,
+        version: None,
+        deleted_at: None,
     };
+    check_event_size(&event)?;
 
     // Insert the new event into the storage
-    match EVENT_STORAGE.with(|events| events.borrow_mut().insert(id, event.clone())) {
-        None => Ok(event),
-        Some(_) => Err(Error::NotCreated {
+    match store_event(&mut event) {
+        None => {
+            certification::certify_event(id);
+            discovery::index_event(&event);
+            geo::index_event(&event);
+            organizers::index_event(&event);
+            Ok(event)
+        }
+        Some(_) => Err(Error::InternalError {
             msg: format!("event {} could not be created", payload.name),
+            ticket_id: None,
         }),
     }
 }
 
-#[ic_cdk::update]
-fn update_event(id: u64, payload: EventPayload) -> Result<Event, Error> {
+#[ic_cdk::update(guard = "rate_limit")]
+fn update_event(
+    id: u64,
+    payload: EventPayload,
+    expected_version: Option<u64>,
+) -> Result<Event, Error> {
+    let (event, updated_event) = prepare_event_update(id, payload, expected_version)?;
+    apply_event_update(&event, updated_event)
+}
+
+// Helper function to check an update of an event, returning the event along with its updated
+// version. Nothing is changed yet, so several updates can be checked before any is applied.
+fn prepare_event_update(
+    id: u64,
+    mut payload: EventPayload,
+    expected_version: Option<u64>,
+) -> Result<(Event, Event), Error> {
     // Retrieve the existing event with the given ID, or return a NotFound error if not found
-    let event = _get_event(&id).ok_or(Error::NotFound {
-        msg: format!("event id:{} does not exist", id),
-    })?;
+    let event = _get_event(&id).ok_or(Error::not_found("event", id))?;
+    organizers::check_event_organizer(&event)?;
+    // Two organizers editing at once must not overwrite each other's changes
+    check_version("event", id, event.version, expected_version)?;
+
+    let tags = match &payload.tags {
+        Some(tags) => {
+            Some(discovery::validate_tags(tags).map_err(|msg| Error::ValidationFailed { msg })?)
+        }
+        None => event.tags.clone(),
+    };
+    // An event held at a managed venue keeps its details, it moves through 'set_event_venue'
+    if let Some(venue) = venues::linked_venue(id) {
+        payload.venue = Some(venue);
+    }
+    if let Some(venue) = &payload.venue {
+        geo::validate_venue(venue).map_err(|msg| Error::ValidationFailed { msg })?;
+    }
+    validate_purchase_limit(payload.max_tickets_per_user)
+        .map_err(|msg| Error::ValidationFailed { msg })?;
+    let metadata = match &payload.metadata {
+        Some(metadata) => Some(
+            metadata::validate_metadata(metadata).map_err(|msg| Error::ValidationFailed { msg })?,
+        ),
+        None => event.metadata.clone(),
+    };
 
     // Create an updated event based on the provided payload
     let updated_event = Event {
@@ -220,31 +854,191 @@ fn update_event(id: u64, payload: EventPayload) -> Result<Event, Error> {
         description: payload.description,
         date: payload.date,
         start_time: payload.start_time,
-        location: payload.location,
-        attendee_ids: event.attendee_ids,
-        ticket_ids: event.ticket_ids,
+        venue: payload.venue,
+        // Capacity changes go through 'expand_event_capacity'
+        capacity: event.capacity,
+        max_tickets_per_user: payload.max_tickets_per_user,
+        series_id: event.series_id,
+        // Organizer changes go through 'add_event_organizer' and 'remove_event_organizer'
+        organizer_ids: event.organizer_ids.clone(),
+        visibility: payload.visibility.or(event.visibility),
+        // Stage changes go through 'announce_event', which notifies watchers
+        announcement: event.announcement,
+        category: payload.category.or(event.category),
+        tags,
+        metadata,
+        accessibility: payload
+            .accessibility
+            .as_deref()
+            .map(geo::normalize_accessibility)
+            .or(event.accessibility.clone()),
+        completed_at: event.completed_at,
+        attendee_ids: event.attendee_ids.clone(),
+        ticket_ids: event.ticket_ids.clone(),
         created_at: event.created_at,
         updated_at: Some(time()),
+        version: event.version,
+        deleted_at: event.deleted_at,
     };
+    if rescheduled(&event, &updated_event) {
+        venues::check_schedule(&updated_event)?;
+    }
+    check_event_size(&updated_event)?;
+
+    Ok((event, updated_event))
+}
+
+// Helper function to store an update checked by 'prepare_event_update'
+fn apply_event_update(event: &Event, mut updated_event: Event) -> Result<Event, Error> {
+    let id = event.id;
+    // A rescheduled event gets reminded again before its new start
+    let rescheduled = rescheduled(event, &updated_event);
+    if rescheduled {
+        jobs::remove_event_reminder(id);
+    }
+    let moved = updated_event.venue != event.venue;
+
+    // The event is indexed again under its new category, tags and venue once stored
+    discovery::unindex_event(event);
+    geo::unindex_event(event);
 
     // Insert the updated event into the storage
-    match EVENT_STORAGE.with(|events| events.borrow_mut().insert(id, updated_event.clone())) {
-        Some(_) => Ok(updated_event),
-        None => Err(Error::NotCreated {
+    match store_event(&mut updated_event) {
+        Some(_) => {
+            certification::certify_event(id);
+            discovery::index_event(&updated_event);
+            geo::index_event(&updated_event);
+            history::record_event_update(event, &updated_event);
+            // Ticket holders hear of a new date, time or venue
+            if rescheduled || moved {
+                notifications::notify_event_changed(&updated_event);
+            }
+            Ok(updated_event)
+        }
+        None => Err(Error::InternalError {
             msg: format!("event id:{} could not be updated", id),
+            ticket_id: None,
         }),
     }
 }
 
-#[ic_cdk::update]
-fn delete_event(id: u64) -> Result<String, Error> {
-    // Check if the event with the given ID exists, or return a NotFound error if not found
-    _get_event(&id).ok_or(Error::NotFound {
-        msg: format!("event id:{} does not exist", id),
+// Whether an update moves an event to another date or time
+fn rescheduled(event: &Event, updated_event: &Event) -> bool {
+    updated_event.date != event.date || updated_event.start_time != event.start_time
+}
+
+#[ic_cdk::update(guard = "rate_limit")]
+fn expand_event_capacity(id: u64, capacity: u64) -> Result<Event, Error> {
+    // Retrieve the existing event with the given ID, or return a NotFound error if not found
+    let mut event = _get_event(&id).ok_or(Error::not_found("event", id))?;
+    organizers::check_event_organizer(&event)?;
+
+    // Only a limited capacity can be raised, and only upwards
+    let current_capacity = event.capacity.ok_or(Error::ValidationFailed {
+        msg: format!("event id:{} has unlimited capacity", id),
     })?;
+    if capacity <= current_capacity {
+        return Err(Error::ValidationFailed {
+            msg: format!(
+                "capacity of event id:{} can only be raised above {}",
+                id, current_capacity
+            ),
+        });
+    }
+
+    event.capacity = Some(capacity);
+    event.updated_at = Some(time());
+    store_event(&mut event);
+    certification::certify_event(id);
+    history::record_event_change(
+        id,
+        "capacity",
+        current_capacity.to_string(),
+        capacity.to_string(),
+    );
+
+    // Hand the new seats to the waitlist before anyone else can buy them
+    waitlist::promote_waitlist(id);
+
+    _get_event(&id).ok_or(Error::not_found("event", id))
+}
+
+#[ic_cdk::update(guard = "rate_limit")]
+fn complete_event(id: u64) -> Result<Event, Error> {
+    // Retrieve the existing event with the given ID, or return a NotFound error if not found
+    let mut event = _get_event(&id).ok_or(Error::not_found("event", id))?;
+    organizers::check_event_organizer(&event)?;
+
+    if event.completed_at.is_some() {
+        return Err(Error::ValidationFailed {
+            msg: format!("event id:{} is already completed", id),
+        });
+    }
+
+    event.completed_at = Some(time());
+    event.updated_at = event.completed_at;
+    store_event(&mut event);
+    certification::certify_event(id);
+    history::record_event_change(id, "completed", "false".to_string(), "true".to_string());
+
+    // Ask the attendees who showed up for their feedback
+    surveys::push_event_survey(id);
+
+    Ok(event)
+}
+
+// Helper function returning the number of tickets still available, or None if unlimited
+fn remaining_capacity(event: &Event) -> Option<u64> {
+    // Places held by reservations and group bookings aren't on sale until they expire
+    event.capacity.map(|capacity| {
+        capacity
+            .saturating_sub(event.ticket_ids.len() as u64)
+            .saturating_sub(reservations::reserved(event.id, None))
+            .saturating_sub(group_bookings::held(event.id, None))
+    })
+}
+
+#[ic_cdk::update(guard = "rate_limit")]
+fn delete_event(id: u64, mode: Option<DeleteMode>) -> Result<String, Error> {
+    // Check if the event with the given ID exists, or return a NotFound error if not found
+    let event = _get_event(&id).ok_or(Error::not_found("event", id))?;
+    organizers::check_event_organizer(&event)?;
+    let mode = mode.unwrap_or_default();
+    integrity::check_event_dependents(&event, mode)?;
+    // A cascade removes the tickets, which are held along with their holders and orders
+    if mode == DeleteMode::Cascade {
+        legal_holds::check_event(&event)?;
+    } else {
+        legal_holds::check_record(HoldTarget::Event { id })?;
+    }
+
+    // Attendees are told before the cascade unlinks them
+    notifications::notify_event_attendees(
+        &event,
+        NotificationKind::EventCancelled,
+        format!("{} has been cancelled", event.name),
+        format!(
+            "{} planned {} has been cancelled.",
+            event.name,
+            broadcasts::WHEN
+        ),
+    );
+    webhooks::enqueue_webhooks(
+        id,
+        WebhookEventType::EventCancelled,
+        serde_json::json!({ "name": event.name, "date": event.date }),
+    );
+    if mode == DeleteMode::Cascade {
+        integrity::cascade_event(&event);
+    }
 
-    // Remove the event with the given ID from the storage
-    EVENT_STORAGE.with(|events| events.borrow_mut().remove(&id));
+    // Move the event to the trash, everything kept for it stays until 'purge_deleted'
+    let mut event = _get_stored_event(&id).unwrap_or(event);
+    event.deleted_at = Some(time());
+    discovery::unindex_event(&event);
+    geo::unindex_event(&event);
+    store_event(&mut event);
+    certification::certify_event(id);
 
     // Return Ok indicating a successful deletion
     Ok(format!("event id: {} deleted", id))
@@ -255,19 +1049,64 @@ fn get_user(id: u64) -> Result<User, Error> {
     // Retrieve a specific user by ID and return it, or return a NotFound error if not found
     match _get_user(&id) {
         Some(user) => Ok(user),
-        None => Err(Error::NotFound {
-            msg: format!("user id:{} does not exist", id),
-        }),
+        None => Err(Error::not_found("user", id)),
     }
 }
 
 fn _get_user(id: &u64) -> Option<User> {
     // Helper function to get a user from the storage based on the provided ID
-    USER_STORAGE.with(|users| users.borrow().get(id))
+    // Users in the trash are left out
+    _get_stored_user(id).filter(|user| user.deleted_at.is_none())
+}
+
+// Helper function to get a user from the storage, including one in the trash
+fn _get_stored_user(id: &u64) -> Option<User> {
+    USER_STORAGE
+        .with(|users| users.borrow().get(id))
+        .map(load_user)
+}
+
+// Helper function to fill in the ticket and event ids of a user read from the storage
+fn load_user(mut user: User) -> User {
+    user.ticket_ids = load_ids(&USER_TICKET_IDS, user.id, user.ticket_ids);
+    user.event_ids = load_ids(&USER_EVENT_IDS, user.id, user.event_ids);
+    user
+}
+
+// Helper function to store a user, bumping its version on the way in. Their ticket and event
+// ids go to maps of their own, the previous user is returned without them.
+fn store_user(user: &mut User) -> Option<User> {
+    user.version = Some(user.version.unwrap_or(0) + 1);
+    store_ids(&USER_TICKET_IDS, user.id, &user.ticket_ids);
+    store_ids(&USER_EVENT_IDS, user.id, &user.event_ids);
+    let stored = User {
+        ticket_ids: vec![],
+        event_ids: vec![],
+        ..user.clone()
+    };
+    let previous = USER_STORAGE.with(|users| users.borrow_mut().insert(user.id, stored));
+    changes::record_change(
+        ChangeEntity::User,
+        user.id,
+        Some(user.id),
+        changes::stored(previous.is_some(), user.deleted_at),
+    );
+    previous
 }
 
-#[ic_cdk::update]
+// Helper function to drop the ids of a purged user
+fn remove_user_ids(user_id: u64) {
+    store_ids(&USER_TICKET_IDS, user_id, &[]);
+    store_ids(&USER_EVENT_IDS, user_id, &[]);
+}
+
+#[ic_cdk::update(guard = "rate_limit")]
 fn create_user(payload: UserPayload) -> Result<User, Error> {
+    storage::check_quota(storage::USERS)?;
+    credentials::validate_password(&payload.password)
+        .map_err(|msg| Error::ValidationFailed { msg })?;
+    emails::check_available(&payload.email, None)?;
+
     // Increment the global ID counter to get a new ID for the user
     let id = ID_COUNTER
         .with(|counter| {
@@ -277,63 +1116,97 @@ fn create_user(payload: UserPayload) -> Result<User, Error> {
         .expect("Cannot increment Ids");
 
     // Create a new User with the provided payload and the generated ID
-    let user = User {
+    let mut user = User {
         id,
         name: payload.name,
         email: payload.email,
-        password: payload.password,
         event_ids: vec![],
         ticket_ids: vec![],
         created_at: time(),
         updated_at: None,
+        version: None,
+        deleted_at: None,
     };
 
     // Insert the new user into the storage
-    match USER_STORAGE.with(|users| users.borrow_mut().insert(id, user.clone())) {
-        None => Ok(user),
-        Some(_) => Err(Error::NotCreated {
+    match store_user(&mut user) {
+        None => {
+            // Only a salted hash of the password is kept, apart from the user
+            credentials::store_password(id, &payload.password);
+            emails::index_user(&user);
+            Ok(user)
+        }
+        Some(_) => Err(Error::InternalError {
             msg: format!("user id:{} could not be created", id),
+            ticket_id: None,
         }),
     }
 }
 
-#[ic_cdk::update]
-fn update_user(id: u64, payload: UserPayload) -> Result<User, Error> {
+#[ic_cdk::update(guard = "rate_limit")]
+fn update_user(
+    id: u64,
+    payload: UserUpdatePayload,
+    expected_version: Option<u64>,
+) -> Result<User, Error> {
     // Retrieve the existing user with the given ID, or return a NotFound error if not found
-    let user = _get_user(&id).ok_or(Error::NotFound {
-        msg: format!("user id:{} does not exist", id),
-    })?;
+    let user = _get_user(&id).ok_or(Error::not_found("user", id))?;
+    check_version("user", id, user.version, expected_version)?;
+    // Users sharing an email from before it had to be unique can keep it
+    let email_changed = emails::normalize(&payload.email) != emails::normalize(&user.email);
+    if email_changed {
+        emails::check_available(&payload.email, Some(id))?;
+    }
 
     // Create an updated user based on the provided payload
-    let updated_user = User {
+    let mut updated_user = User {
         id,
         name: payload.name,
         email: payload.email,
-        password: payload.password,
-        event_ids: user.event_ids,
-        ticket_ids: user.ticket_ids,
+        event_ids: user.event_ids.clone(),
+        ticket_ids: user.ticket_ids.clone(),
         created_at: user.created_at,
         updated_at: Some(time()),
+        version: user.version,
+        deleted_at: user.deleted_at,
     };
 
     // Insert the updated user into the storage
-    match USER_STORAGE.with(|users| users.borrow_mut().insert(id, updated_user.clone())) {
-        None => Ok(updated_user),
-        Some(_) => Err(Error::NotCreated {
+    match store_user(&mut updated_user) {
+        None => {
+            if email_changed {
+                emails::unindex_user(&user);
+                emails::index_user(&updated_user);
+            }
+            Ok(updated_user)
+        }
+        Some(_) => Err(Error::InternalError {
             msg: format!("user id:{} could not be updated", id),
+            ticket_id: None,
         }),
     }
 }
 
-#[ic_cdk::update]
-fn delete_user(id: u64) -> Result<String, Error> {
+#[ic_cdk::update(guard = "rate_limit")]
+fn delete_user(id: u64, mode: Option<DeleteMode>) -> Result<String, Error> {
     // Check if the user with the given ID exists, or return a NotFound error if not found
-    _get_user(&id).ok_or(Error::NotFound {
-        msg: format!("user id:{} does not exist", id),
-    })?;
+    let user = _get_user(&id).ok_or(Error::not_found("user", id))?;
+    let mode = mode.unwrap_or_default();
+    integrity::check_user_dependents(&user, mode)?;
+    if mode == DeleteMode::Cascade {
+        legal_holds::check_user(&user)?;
+    } else {
+        legal_holds::check_record(HoldTarget::User { id })?;
+    }
+    if mode == DeleteMode::Cascade {
+        integrity::cascade_user(&user);
+    }
 
-    // Remove the user with the given ID from the storage
-    USER_STORAGE.with(|users| users.borrow_mut().remove(&id));
+    // Move the user to the trash, their password is kept until 'purge_deleted'
+    let mut user = _get_stored_user(&id).unwrap_or(user);
+    user.deleted_at = Some(time());
+    store_user(&mut user);
+    credentials::remove_user_sessions(id);
 
     // Return Ok indicating a successful deletion
     Ok(format!("user id: {} deleted", id))
@@ -344,9 +1217,7 @@ fn get_ticket(id: u64) -> Result<Ticket, Error> {
     // Retrieve a specific ticket by ID and return it, or return a NotFound error if not found
     match _get_ticket(&id) {
         Some(ticket) => Ok(ticket),
-        None => Err(Error::NotFound {
-            msg: format!("ticket id:{} does not exist", id),
-        }),
+        None => Err(Error::not_found("ticket", id)),
     }
 }
 
@@ -355,8 +1226,365 @@ fn _get_ticket(id: &u64) -> Option<Ticket> {
     TICKET_STORAGE.with(|tickets| tickets.borrow().get(id))
 }
 
-#[ic_cdk::update]
-fn create_ticket(payload: TicketPayload) -> Result<Ticket, AssociationError> {
+// Helper function to store a ticket, bumping its version on the way in
+fn store_ticket(ticket: &mut Ticket) -> Option<Ticket> {
+    ticket.version = Some(ticket.version.unwrap_or(0) + 1);
+    let previous =
+        TICKET_STORAGE.with(|tickets| tickets.borrow_mut().insert(ticket.id, ticket.clone()));
+    // To its former holder, a ticket that changed hands is gone
+    if let Some(holder) = previous
+        .as_ref()
+        .map(|previous| previous.user_id)
+        .filter(|&holder| holder != ticket.user_id)
+    {
+        changes::record_change(
+            ChangeEntity::Ticket,
+            ticket.id,
+            Some(holder),
+            ChangeOperation::Deleted,
+        );
+    }
+    changes::record_change(
+        ChangeEntity::Ticket,
+        ticket.id,
+        Some(ticket.user_id),
+        changes::stored(previous.is_some(), None),
+    );
+    previous
+}
+
+// Reject an update based on an older copy of a record, callers that send no version skip the check
+fn check_version(
+    record: &str,
+    id: u64,
+    version: Option<u64>,
+    expected_version: Option<u64>,
+) -> Result<(), Error> {
+    let current_version = version.unwrap_or(0);
+    match expected_version {
+        Some(expected) if expected != current_version => Err(Error::Conflict {
+            msg: format!(
+                "{} id:{} is at version {}, not {}",
+                record, id, current_version, expected
+            ),
+            current_version,
+        }),
+        _ => Ok(()),
+    }
+}
+
+#[ic_cdk::update(guard = "rate_limit")]
+async fn create_ticket(
+    payload: TicketPayload,
+    idempotency_key: Option<String>,
+) -> Result<Ticket, Error> {
+    // A retried purchase gets the ticket bought the first time instead of a second one
+    if let Some(ticket) = idempotency::begin(&idempotency_key, "create_ticket", &payload)
+        .map_err(|msg| Error::ValidationFailed { msg })?
+    {
+        return Ok(ticket);
+    }
+
+    let result = async {
+        health::check_sales_open().map_err(|msg| Error::ValidationFailed { msg })?;
+        // Checked before the hook is consulted, so a sold out event doesn't cost an outcall
+        quote_ticket(&payload)?;
+        validation::validate_tickets(std::slice::from_ref(&payload))
+            .await
+            .map_err(|msg| Error::ValidationFailed { msg })?;
+
+        // A flash sale place is held while the payment is taken, so it can't be sold twice
+        let holds = flash_sales::hold(std::slice::from_ref(&payload))
+            .map_err(|msg| Error::ValidationFailed { msg })?;
+        let result = async {
+            let charge = payments::charge_for(std::slice::from_ref(&payload))
+                .map_err(|msg| Error::ValidationFailed { msg })?;
+            let payment = payments::collect(&charge)
+                .await
+                .map_err(|msg| Error::PaymentFailed { msg })?;
+            // Checked again once paid, a ticket that can no longer be sold is refunded
+            let ticket = payments::check_charge(&charge, std::slice::from_ref(&payload))
+                .map_err(|msg| Error::ValidationFailed { msg })
+                .and_then(|_| _create_ticket(payload));
+            payments::settle(payment, issued_ticket_ids(&ticket)).await;
+            // Read back, settling records the rate a converted price was paid at
+            ticket.map(|ticket| _get_ticket(&ticket.id).unwrap_or(ticket))
+        }
+        .await;
+        flash_sales::release(holds);
+        result
+    }
+    .await;
+    idempotency::finish(&idempotency_key, &result);
+    result
+}
+
+// Ticket a purchase issued, even if it couldn't be associated with its event or user
+fn issued_ticket_ids(result: &Result<Ticket, Error>) -> Vec<u64> {
+    match result {
+        Ok(ticket) => vec![ticket.id],
+        Err(Error::InternalError {
+            ticket_id: Some(ticket_id),
+            ..
+        }) => vec![*ticket_id],
+        Err(_) => vec![],
+    }
+}
+
+// Function to sell a ticket once any validation hook approved it, checking it again first
+fn _create_ticket(payload: TicketPayload) -> Result<Ticket, Error> {
+    let ticket = sell_ticket(payload)?;
+
+    // Let the buyer know the purchase went through
+    if let Some(event) = _get_event(&ticket.event_id) {
+        notifications::enqueue_notification(
+            NotificationKind::TicketPurchased,
+            event.id,
+            ticket.user_id,
+            format!("Your ticket for {}", event.name),
+            ticket_summary(&ticket, &event),
+        );
+    }
+
+    Ok(ticket)
+}
+
+// Issue a ticket and record its donation, leaving it to the caller to confirm the purchase
+fn sell_ticket(payload: TicketPayload) -> Result<Ticket, Error> {
+    let quote = quote_ticket(&payload)?;
+
+    // Create a new Ticket with the provided payload
+    let ticket = issue_ticket(Ticket {
+        event_id: payload.event_id,
+        user_id: payload.user_id,
+        tier_id: payload.tier_id,
+        price: quote.price,
+        currency: quote.currency,
+        promo_code: payload.promo_code,
+        insurance: quote.insurance,
+        seat: payload.seat,
+        addons: (!quote.addons.is_empty()).then_some(quote.addons),
+        metadata: Some(quote.metadata),
+        ..Default::default()
+    })?;
+
+    // Use up the promo code now that the ticket is issued
+    if let Some(promo) = quote.promo {
+        promo::consume_promo_code(promo);
+    }
+    if let Some(sale_id) = quote.flash_sale {
+        flash_sales::record_sale(sale_id, payload.user_id);
+    }
+
+    // Record the donation with a receipt for the donor
+    if let Some(amount) = payload.donation {
+        donations::record_donation(ticket.event_id, ticket.user_id, ticket.id, amount);
+    }
+    if let Some(event) = _get_event(&ticket.event_id) {
+        fraud::screen_purchase(&ticket, &event);
+    }
+
+    Ok(ticket)
+}
+
+// Line describing a purchased ticket in a confirmation
+fn ticket_summary(ticket: &Ticket, event: &Event) -> String {
+    format!(
+        "Ticket id {} for {} {}, {}.",
+        ticket.id,
+        event.name,
+        display::event_when(event, ticket.user_id),
+        geo::venue_label(event.venue.as_ref())
+    )
+}
+
+// Tickets a user holds for an event, cancelled tickets are deleted and no longer count
+fn user_ticket_count(event_id: u64, user_id: u64) -> u64 {
+    _get_user(&user_id)
+        .map(|user| {
+            user.ticket_ids
+                .iter()
+                .filter_map(_get_ticket)
+                .filter(|ticket| ticket.event_id == event_id)
+                .count() as u64
+        })
+        .unwrap_or(0)
+}
+
+fn validate_purchase_limit(max_tickets_per_user: Option<u64>) -> Result<(), String> {
+    if max_tickets_per_user == Some(0) {
+        return Err("max tickets per user must be at least 1".to_string());
+    }
+    Ok(())
+}
+
+// Check that a ticket can be sold and work out its price, without changing any state
+fn quote_ticket(payload: &TicketPayload) -> Result<TicketQuote, Error> {
+    // Reject the purchase if the event is sold out, embargoed or not fully announced yet
+    if let Some(event) = _get_event(&payload.event_id) {
+        if !embargo::is_public(event.id) {
+            return Err(Error::ValidationFailed {
+                msg: format!("event id:{} is under embargo", payload.event_id),
+            });
+        }
+        if announcements::stage(&event) != AnnouncementStage::FullyAnnounced {
+            return Err(Error::ValidationFailed {
+                msg: format!(
+                    "event id:{} is not on sale until all details are announced",
+                    payload.event_id
+                ),
+            });
+        }
+        if reschedules::is_postponed(event.id) {
+            return Err(Error::ValidationFailed {
+                msg: format!(
+                    "event id:{} is postponed, tickets sell again once it has a new date",
+                    payload.event_id
+                ),
+            });
+        }
+        moderation::check_purchase(&event, payload.user_id)
+            .map_err(|msg| Error::ValidationFailed { msg })?;
+        memberships::check_on_sale(&event, payload.user_id)
+            .map_err(|msg| Error::ValidationFailed { msg })?;
+        registrations::check_registered(&event, payload.user_id)
+            .map_err(|msg| Error::ValidationFailed { msg })?;
+        if remaining_capacity(&event) == Some(0) {
+            return Err(Error::CapacityExceeded {
+                msg: format!("event id:{} is sold out", payload.event_id),
+            });
+        }
+        invitations::check_invited(&event, payload.user_id)
+            .map_err(|msg| Error::ValidationFailed { msg })?;
+        if let Some(limit) = event.max_tickets_per_user {
+            let count = user_ticket_count(event.id, payload.user_id);
+            if count >= limit {
+                return Err(Error::PurchaseLimitExceeded {
+                    msg: format!(
+                        "user id:{} already holds {} of the {} tickets allowed for event id:{}",
+                        payload.user_id, count, limit, event.id
+                    ),
+                    count,
+                    limit,
+                });
+            }
+        }
+    }
+
+    // Resolve the tier of the ticket, which sets its price
+    let mut price = 0;
+    match payload.tier_id {
+        Some(tier_id) => {
+            let tier =
+                tiers::_get_tier(payload.event_id, tier_id).ok_or(Error::ValidationFailed {
+                    msg: format!(
+                        "tier id:{} does not exist for event id:{}",
+                        tier_id, payload.event_id
+                    ),
+                })?;
+            tiers::check_tier_access(
+                payload.event_id,
+                tier_id,
+                payload.user_id,
+                payload.unlock_code.as_deref(),
+            )
+            .map_err(|msg| Error::ValidationFailed { msg })?;
+            if tiers::tier_remaining(&tier) == Some(0) {
+                return Err(Error::CapacityExceeded {
+                    msg: format!("tier id:{} is sold out", tier_id),
+                });
+            }
+            price = tier.price;
+        }
+        None => {
+            if !tiers::_get_event_tiers(payload.event_id).is_empty() {
+                return Err(Error::ValidationFailed {
+                    msg: format!("event id:{} requires a tier", payload.event_id),
+                });
+            }
+        }
+    }
+
+    // Take the price of a flash sale running for the ticket, promo codes don't add to it
+    let flash_sale =
+        flash_sales::flash_price(payload.event_id, payload.tier_id, payload.user_id, price);
+    if let Some((sale_id, flash_price)) = flash_sale {
+        if payload.promo_code.is_some() {
+            return Err(Error::ValidationFailed {
+                msg: format!(
+                    "promo codes can't be used on tickets of flash sale id:{}",
+                    sale_id
+                ),
+            });
+        }
+        price = flash_price;
+    }
+
+    // Validate the promo code, if any, and apply its discount
+    let promo = match &payload.promo_code {
+        Some(code) => Some(
+            promo::check_promo_code(payload.event_id, code, payload.tier_id)
+                .map_err(|msg| Error::ValidationFailed { msg })?,
+        ),
+        None => None,
+    };
+    if let Some(promo) = &promo {
+        price = promo::apply_discount(promo, price);
+    }
+
+    // Quote the insurance add-on on the discounted price
+    let insurance = match payload.insured {
+        true => Some(
+            insurance::quote_insurance(payload.event_id, price)
+                .map_err(|msg| Error::ValidationFailed { msg })?,
+        ),
+        false => None,
+    };
+
+    // Make sure the seat is free, or held by the buyer
+    if let Some(seat) = &payload.seat {
+        seats::check_seat(payload.event_id, seat).map_err(|msg| Error::ValidationFailed { msg })?;
+    }
+
+    // Make sure the event accepts the donation, if any
+    if let Some(amount) = payload.donation {
+        donations::check_donation(payload.event_id, amount)
+            .map_err(|msg| Error::ValidationFailed { msg })?;
+    }
+
+    // Make sure the add-ons are in stock, they are priced apart from the ticket
+    let addons = match &payload.addons {
+        Some(selections) => addons::quote_addons(payload.event_id, selections)
+            .map_err(|msg| Error::ValidationFailed { msg })?,
+        None => vec![],
+    };
+
+    let metadata = match &payload.metadata {
+        Some(metadata) => {
+            metadata::validate_metadata(metadata).map_err(|msg| Error::ValidationFailed { msg })?
+        }
+        None => vec![],
+    };
+
+    Ok(TicketQuote {
+        price,
+        currency: payments::pricing_currency(payload.event_id, payload.tier_id),
+        promo,
+        flash_sale: flash_sale.map(|(sale_id, _)| sale_id),
+        insurance,
+        addons,
+        metadata,
+    })
+}
+
+// Function to store a new ticket, count the sale and associate it with its event and user
+fn issue_ticket(mut ticket: Ticket) -> Result<Ticket, Error> {
+    // Nothing is stored for an event or user that doesn't exist
+    _get_event(&ticket.event_id).ok_or(Error::not_found("event", ticket.event_id))?;
+    _get_user(&ticket.user_id).ok_or(Error::not_found("user", ticket.user_id))?;
+
+    storage::check_quota(storage::TICKETS)?;
+
     // Increment the global ID counter to get a new ID for the ticket
     let id = ID_COUNTER
         .with(|counter| {
@@ -364,74 +1592,156 @@ fn create_ticket(payload: TicketPayload) -> Result<Ticket, AssociationError> {
             counter.borrow_mut().set(current_id + 1)
         })
         .expect("Cannot increment Ids");
+    ticket.id = id;
+    ticket.created_at = time();
+    let (event_id, user_id) = (ticket.event_id, ticket.user_id);
 
-    // Create a new Ticket with the provided payload and the generated ID
-    let ticket = Ticket {
-        id,
-        event_id: payload.event_id,
-        user_id: payload.user_id,
-        created_at: time(),
-        updated_at: None,
-    };
+    // Give the ticket its printed code, if the event has a code format
+    ticket.code =
+        ticket_codes::generate_code(event_id, id).map_err(|msg| Error::ValidationFailed { msg })?;
 
     // Insert the new ticket into the storage
-    TICKET_STORAGE.with(|tickets| tickets.borrow_mut().insert(id, ticket.clone()));
+    store_ticket(&mut ticket);
+
+    // Count the sale in the tier and the event's analytics
+    if let Some(tier_id) = ticket.tier_id {
+        tiers::record_tier_sale(event_id, tier_id);
+    }
+    if let Some(seat) = &ticket.seat {
+        seats::assign_seat(event_id, seat, id);
+    }
+    if let Some(lines) = &ticket.addons {
+        addons::record_addon_sale(event_id, lines);
+    }
+    stats::record_ticket_sold(event_id, ticket.created_at, ticket.price);
+    if let Some(insurance) = &ticket.insurance {
+        stats::record_insurance_sold(event_id, insurance.fee);
+    }
 
     // Call helper functions to associate the ticket with the event and user
-    match add_event_attendee(payload.event_id, payload.user_id) {
+    match add_event_attendee(event_id, user_id) {
         Ok(_) => (),
         Err(_) => {
-            return Err(AssociationError::Err {
-                msg: format!("Could not add attendee to event id:{} ", payload.event_id),
-                ticket: ticket.clone(),
+            return Err(Error::InternalError {
+                msg: format!("Could not add attendee to event id:{} ", event_id),
+                ticket_id: Some(id),
             })
         }
     }
 
-    match add_user_ticket(payload.user_id, id) {
+    match add_user_ticket(user_id, id) {
         Ok(_) => (),
         Err(_) => {
-            return Err(AssociationError::Err {
-                msg: format!(
-                    "Could not add ticket id:{} to user id:{} ",
-                    id, payload.user_id
-                ),
-                ticket: ticket.clone(),
+            return Err(Error::InternalError {
+                msg: format!("Could not add ticket id:{} to user id:{} ", id, user_id),
+                ticket_id: Some(id),
             })
         }
     }
 
-    match add_event_ticket(payload.event_id, id) {
+    match add_event_ticket(event_id, id) {
         Ok(_) => (),
         Err(_) => {
-            return Err(AssociationError::Err {
-                msg: format!(
-                    "Could not add ticket id:{} to event id:{} ",
-                    id, payload.event_id
-                ),
-                ticket: ticket.clone(),
+            return Err(Error::InternalError {
+                msg: format!("Could not add ticket id:{} to event id:{} ", id, event_id),
+                ticket_id: Some(id),
             })
         }
     }
+    webhooks::enqueue_webhooks(
+        event_id,
+        WebhookEventType::TicketCreated,
+        serde_json::json!({
+            "ticket_id": id,
+            "user_id": user_id,
+            "tier_id": ticket.tier_id,
+            "price": ticket.price,
+        }),
+    );
+    alerts::check_capacity(event_id);
 
-    // Return the ID of the newly created ticket
+    // Return the newly created ticket
     Ok(ticket)
 }
 
-#[ic_cdk::update]
-fn update_ticket(id: u64, payload: TicketPayload) -> Result<Ticket, Error> {
+#[ic_cdk::update(guard = "rate_limit")]
+fn update_ticket(
+    id: u64,
+    payload: TicketPayload,
+    idempotency_key: Option<String>,
+    expected_version: Option<u64>,
+) -> Result<Ticket, Error> {
+    // A retried transfer returns the ticket as it was first updated
+    if let Some(ticket) = idempotency::begin(&idempotency_key, "update_ticket", (id, &payload))
+        .map_err(|msg| Error::ValidationFailed { msg })?
+    {
+        return Ok(ticket);
+    }
+    let result = _update_ticket(id, payload, expected_version);
+    idempotency::finish(&idempotency_key, &result);
+    result
+}
+
+fn _update_ticket(
+    id: u64,
+    payload: TicketPayload,
+    expected_version: Option<u64>,
+) -> Result<Ticket, Error> {
     // Retrieve the existing ticket with the given ID, or return a NotFound error if not found
-    let ticket = _get_ticket(&id).ok_or(Error::NotFound {
-        msg: format!("ticket id:{} does not exist", id),
-    })?;
+    let ticket = _get_ticket(&id).ok_or(Error::not_found("ticket", id))?;
+    check_version("ticket", id, ticket.version, expected_version)?;
+
+    // Tiers, seats and add-ons belong to a single event, so such tickets can't move between events
+    if payload.event_id != ticket.event_id
+        && (ticket.tier_id.is_some() || ticket.seat.is_some() || ticket.addons.is_some())
+    {
+        return Err(Error::ValidationFailed {
+            msg: format!(
+                "ticket id:{} belongs to a tier, seat or add-on of event id:{}",
+                id, ticket.event_id
+            ),
+        });
+    }
+
+    // Handing the ticket to someone else is a transfer, frozen during blackout windows and
+    // closed once the ticket was used to get in. A ticket of value may also need a recent login.
+    if payload.user_id != ticket.user_id {
+        resale::check_transfer_open(ticket.event_id)?;
+        fraud::check_not_flagged(&ticket).map_err(|msg| Error::ValidationFailed { msg })?;
+        if ticket.checked_in_at.is_some() {
+            return Err(Error::ValidationFailed {
+                msg: format!("ticket id:{} is checked in and can't be transferred", id),
+            });
+        }
+        credentials::check_transfer(ticket.price)?;
+    }
 
-    // Create an updated ticket based on the provided payload
-    let updated_ticket = Ticket {
+    let metadata = match &payload.metadata {
+        Some(metadata) => Some(
+            metadata::validate_metadata(metadata).map_err(|msg| Error::ValidationFailed { msg })?,
+        ),
+        None => ticket.metadata.clone(),
+    };
+
+    // Create an updated ticket based on the provided payload, the tier and price stay as purchased
+    let mut updated_ticket = Ticket {
         id,
+        code: ticket.code.clone(),
         event_id: payload.event_id,
         user_id: payload.user_id,
+        tier_id: ticket.tier_id,
+        price: ticket.price,
+        currency: ticket.currency.clone(),
+        quoted_rate: ticket.quoted_rate.clone(),
+        promo_code: ticket.promo_code.clone(),
+        insurance: ticket.insurance.clone(),
+        seat: ticket.seat.clone(),
+        addons: ticket.addons.clone(),
+        metadata,
+        checked_in_at: ticket.checked_in_at,
         created_at: ticket.created_at,
         updated_at: Some(time()),
+        version: ticket.version,
     };
 
     // Call helper functions to associate the ticket with the event and user
@@ -439,11 +1749,12 @@ fn update_ticket(id: u64, payload: TicketPayload) -> Result<Ticket, Error> {
         match add_user_ticket(payload.user_id, id) {
             Ok(_) => (),
             Err(_) => {
-                return Err(Error::NotCreated {
+                return Err(Error::InternalError {
                     msg: format!(
                         "Could not add ticket id:{} to user id:{} ",
                         id, payload.user_id
                     ),
+                    ticket_id: None,
                 })
             }
         }
@@ -453,99 +1764,188 @@ fn update_ticket(id: u64, payload: TicketPayload) -> Result<Ticket, Error> {
         match add_event_ticket(payload.event_id, id) {
             Ok(_) => (),
             Err(_) => {
-                return Err(Error::NotCreated {
+                return Err(Error::InternalError {
                     msg: format!(
                         "Could not add ticket id:{} to event id:{} ",
                         id, payload.event_id
                     ),
+                    ticket_id: None,
                 })
             }
         }
     }
 
+    // Move the sale over to the new event's analytics
+    if payload.event_id != ticket.event_id {
+        stats::record_ticket_removed(ticket.event_id, ticket.price);
+        stats::record_ticket_sold(payload.event_id, ticket.created_at, ticket.price);
+    }
+
     // Insert the updated ticket into the storage
-    match TICKET_STORAGE.with(|tickets| tickets.borrow_mut().insert(id, updated_ticket.clone())) {
-        Some(_) => Ok(updated_ticket),
-        None => Err(Error::NotCreated {
+    match store_ticket(&mut updated_ticket) {
+        Some(_) => {
+            // The ticket shows up in its events' certified ticket listings
+            certification::certify_event(ticket.event_id);
+            if payload.event_id != ticket.event_id {
+                certification::certify_event(payload.event_id);
+            }
+            // A listing stands for its seller, it goes once the ticket moves
+            if payload.user_id != ticket.user_id || payload.event_id != ticket.event_id {
+                resale::remove_listing(ticket.event_id, id);
+            }
+            Ok(updated_ticket)
+        }
+        None => Err(Error::InternalError {
             msg: format!("ticket id:{} could not be updated", id),
+            ticket_id: None,
         }),
     }
 }
 
-#[ic_cdk::update]
-fn delete_ticket(id: u64) -> Result<String, Error> {
+#[ic_cdk::update(guard = "rate_limit")]
+fn check_in_ticket(
+    id: u64,
+    api_key: Option<String>,
+    zone: Option<String>,
+) -> Result<Ticket, Error> {
+    // Box office devices check in with a scoped key instead of an admin identity
+    auth::authorize(api_key, ApiScope::CheckIn)?;
+    _check_in_ticket(id, zone).map(|(ticket, _)| ticket)
+}
+
+// Check a ticket in, returning it along with the zone it was sent to
+fn _check_in_ticket(id: u64, zone: Option<String>) -> Result<(Ticket, Option<String>), Error> {
+    // Retrieve the ticket with the given ID, or return a NotFound error if not found
+    let mut ticket = _get_ticket(&id).ok_or(Error::not_found("ticket", id))?;
+
+    if ticket.checked_in_at.is_some() {
+        return Err(Error::ValidationFailed {
+            msg: format!("ticket id:{} is already checked in", id),
+        });
+    }
+    // Find the ticket a room, it may be sent to an overflow room when the one asked for is full
+    let zone = rooms::assign_zone(ticket.event_id, id, zone)
+        .map_err(|msg| Error::ValidationFailed { msg })?;
+
+    ticket.checked_in_at = Some(time());
+    ticket.updated_at = ticket.checked_in_at;
+    store_ticket(&mut ticket);
+    stats::record_check_in(ticket.event_id);
+    attendance::record_attendance(&ticket);
+    certification::certify_event(ticket.event_id);
+    webhooks::enqueue_checked_in(&ticket, zone.clone());
+
+    Ok((ticket, zone))
+}
+
+#[ic_cdk::update(guard = "rate_limit")]
+fn delete_ticket(id: u64, idempotency_key: Option<String>) -> Result<String, Error> {
+    // A retried cancellation reports success again instead of a missing ticket
+    if let Some(msg) = idempotency::begin(&idempotency_key, "delete_ticket", id)
+        .map_err(|msg| Error::ValidationFailed { msg })?
+    {
+        return Ok(msg);
+    }
+    let result = _delete_ticket(id);
+    idempotency::finish(&idempotency_key, &result);
+    result
+}
+
+fn _delete_ticket(id: u64) -> Result<String, Error> {
     // Retrieve the ticket ID from the payload
     let ticket_id = id;
 
     // Retrieve the ticket with the given ID, or return a NotFound error if not found
-    let ticket = _get_ticket(&ticket_id).ok_or(Error::NotFound {
-        msg: format!("ticket id:{} does not exist", ticket_id),
-    })?;
+    let ticket = _get_ticket(&ticket_id).ok_or(Error::not_found("ticket", ticket_id))?;
+    legal_holds::check_ticket(&ticket)?;
 
     // Retrieve the user with the given ID, or return a NotFound error if not found
     let user_id = ticket.user_id;
-    let mut user = _get_user(&user_id).ok_or(Error::NotFound {
-        msg: format!("user id:{} does not exist", user_id),
-    })?;
+    let mut user = _get_user(&user_id).ok_or(Error::not_found("user", user_id))?;
 
     // Remove the ticket ID from the user's ticket IDs
     user.ticket_ids.retain(|&id| id != ticket_id);
 
     // Update the user in the storage
-    match USER_STORAGE.with(|users| users.borrow_mut().insert(user_id, user)) {
+    match store_user(&mut user) {
         Some(_) => (),
         None => {
-            return Err(Error::NotFound {
+            return Err(Error::InternalError {
                 msg: format!("user id:{} could not be updated", user_id),
+                ticket_id: None,
             })
         }
     }
     // Retrieve the event with the given ID, or return a NotFound error if not found
     let event_id = ticket.event_id;
-    let mut event = _get_event(&event_id).ok_or(Error::NotFound {
-        msg: format!("event id:{} does not exist", event_id),
-    })?;
+    let mut event = _get_event(&event_id).ok_or(Error::not_found("event", event_id))?;
 
     // Remove the ticket ID from the event's ticket IDs
     event.ticket_ids.retain(|&id| id != ticket_id);
 
     // Update the event in the storage
-    match EVENT_STORAGE.with(|events| events.borrow_mut().insert(event_id, event)) {
+    match store_event(&mut event) {
         Some(_) => (),
         None => {
-            return Err(Error::NotFound {
+            return Err(Error::InternalError {
                 msg: format!("event id:{} could not be updated", event_id),
+                ticket_id: None,
             })
         }
     }
     // Delete the ticket from the storage
     match TICKET_STORAGE.with(|tickets| tickets.borrow_mut().remove(&ticket_id)) {
-        Some(_) => (),
+        Some(ticket) => changes::record_change(
+            ChangeEntity::Ticket,
+            ticket_id,
+            Some(ticket.user_id),
+            ChangeOperation::Deleted,
+        ),
         None => {
-            return Err(Error::NotFound {
+            return Err(Error::InternalError {
                 msg: format!("ticket id:{} could not be deleted from event", ticket_id),
+                ticket_id: None,
             })
         }
     }
+
+    // Give the ticket back to its tier and count the cancellation in the event's analytics
+    if let Some(tier_id) = ticket.tier_id {
+        tiers::record_tier_release(event_id, tier_id);
+    }
+    if let Some(seat) = &ticket.seat {
+        seats::release_seat(event_id, seat);
+    }
+    if let Some(lines) = &ticket.addons {
+        addons::record_addon_release(event_id, lines);
+    }
+    if let Some(code) = &ticket.code {
+        ticket_codes::release_code(code);
+    }
+    resale::remove_listing(event_id, ticket_id);
+    stats::record_ticket_cancelled(event_id);
+    certification::certify_event(event_id);
+
     // Return Ok indicating a successful deletion
     Ok(format!("ticket id: {} deleted", ticket_id))
 }
 
 #[ic_cdk::query]
-fn get_event_attendees(id: u64) -> Result<Vec<User>, Error> {
+fn get_event_attendees(id: u64, api_key: Option<String>) -> Result<Vec<User>, Error> {
+    auth::authorize(api_key, ApiScope::ViewAttendees)?;
+
     // Retrieve the event with the given ID, or return a NotFound error if not found
-    let event = _get_event(&id).ok_or(Error::NotFound {
-        msg: format!("event id:{} does not exist", id),
-    })?;
+    let event = _get_event(&id).ok_or(Error::not_found("event", id))?;
 
     // Initialize a vector to store the attendees
     let mut attendees = vec![];
 
     // Iterate over the attendee IDs of the event and retrieve the corresponding users
     for attendee_id in event.attendee_ids {
-        let attendee = _get_user(&attendee_id).ok_or(Error::NotFound {
-            msg: format!("user id:{} does not exist", attendee_id),
-        })?;
+        // Attendees in the trash are left out
+        let Some(attendee) = _get_user(&attendee_id) else {
+            continue;
+        };
 
         // Add the attendee to the vector
         attendees.push(attendee);
@@ -558,35 +1958,45 @@ fn get_event_attendees(id: u64) -> Result<Vec<User>, Error> {
 // Function to add an attendee to an event
 fn add_event_attendee(event_id: u64, user_id: u64) -> Result<(), Error> {
     // Retrieve the event with the given ID, or return a NotFound error if not found
-    let event = _get_event(&event_id).ok_or(Error::NotFound {
-        msg: format!("event id:{} does not exist", event_id),
-    })?;
+    let event = _get_event(&event_id).ok_or(Error::not_found("event", event_id))?;
 
     // Retrieve the user with the given ID, or return a NotFound error if not found
-    let user = _get_user(&user_id).ok_or(Error::NotFound {
-        msg: format!("user id:{} does not exist", user_id),
-    })?;
+    let user = _get_user(&user_id).ok_or(Error::not_found("user", user_id))?;
 
     // Clone the current attendee IDs and add the new user ID
     let mut attendees = event.attendee_ids.clone();
     attendees.push(user.id);
 
     // Create an updated event with the new attendee IDs
-    let updated_event = Event {
+    let mut updated_event = Event {
         id: event.id,
         name: event.name,
         description: event.description,
         date: event.date,
         start_time: event.start_time,
-        location: event.location,
+        venue: event.venue,
+        capacity: event.capacity,
+        max_tickets_per_user: event.max_tickets_per_user,
+        series_id: event.series_id,
+        organizer_ids: event.organizer_ids,
+        visibility: event.visibility,
+        announcement: event.announcement,
+        category: event.category,
+        tags: event.tags,
+        metadata: event.metadata,
+        accessibility: event.accessibility,
+        completed_at: event.completed_at,
         attendee_ids: attendees,
         ticket_ids: event.ticket_ids,
         created_at: event.created_at,
         updated_at: Some(time()),
+        version: event.version,
+        deleted_at: event.deleted_at,
     };
 
     // Update the event in the storage
-    EVENT_STORAGE.with(|events| events.borrow_mut().insert(event.id, updated_event));
+    store_event(&mut updated_event);
+    certification::certify_event(event_id);
 
     // Return Ok indicating a successful update
     Ok(())
@@ -595,35 +2005,45 @@ fn add_event_attendee(event_id: u64, user_id: u64) -> Result<(), Error> {
 // Function to add a ticket to an event
 fn add_event_ticket(event_id: u64, ticket_id: u64) -> Result<(), Error> {
     // Retrieve the event with the given ID, or return a NotFound error if not found
-    let event = _get_event(&event_id).ok_or(Error::NotFound {
-        msg: format!("event id:{} does not exist", event_id),
-    })?;
+    let event = _get_event(&event_id).ok_or(Error::not_found("event", event_id))?;
 
     // Retrieve the ticket with the given ID, or return a NotFound error if not found
-    let ticket = _get_ticket(&ticket_id).ok_or(Error::NotFound {
-        msg: format!("ticket id:{} does not exist", ticket_id),
-    })?;
+    let ticket = _get_ticket(&ticket_id).ok_or(Error::not_found("ticket", ticket_id))?;
 
     // Clone the current ticket IDs and add the new ticket ID
     let mut tickets = event.ticket_ids.clone();
     tickets.push(ticket.id);
 
     // Create an updated event with the new ticket IDs
-    let updated_event = Event {
+    let mut updated_event = Event {
         id: event.id,
         name: event.name,
         description: event.description,
         date: event.date,
         start_time: event.start_time,
-        location: event.location,
+        venue: event.venue,
+        capacity: event.capacity,
+        max_tickets_per_user: event.max_tickets_per_user,
+        series_id: event.series_id,
+        organizer_ids: event.organizer_ids,
+        visibility: event.visibility,
+        announcement: event.announcement,
+        category: event.category,
+        tags: event.tags,
+        metadata: event.metadata,
+        accessibility: event.accessibility,
+        completed_at: event.completed_at,
         attendee_ids: event.attendee_ids,
         ticket_ids: tickets,
         created_at: event.created_at,
         updated_at: Some(time()),
+        version: event.version,
+        deleted_at: event.deleted_at,
     };
 
     // Update the event in the storage
-    EVENT_STORAGE.with(|events| events.borrow_mut().insert(event.id, updated_event));
+    store_event(&mut updated_event);
+    certification::certify_event(event_id);
 
     // Return Ok indicating a successful update
     Ok(())
@@ -632,18 +2052,14 @@ fn add_event_ticket(event_id: u64, ticket_id: u64) -> Result<(), Error> {
 #[ic_cdk::query]
 fn get_user_tickets(id: u64) -> Result<Vec<Ticket>, Error> {
     // Retrieve the user with the given ID, or return a NotFound error if not found
-    let user = _get_user(&id).ok_or(Error::NotFound {
-        msg: format!("user id:{} does not exist", id),
-    })?;
+    let user = _get_user(&id).ok_or(Error::not_found("user", id))?;
 
     // Initialize a vector to store the user's tickets
     let mut tickets = vec![];
 
     // Iterate over the ticket IDs of the user and retrieve the corresponding tickets
     for ticket_id in user.ticket_ids {
-        let ticket = _get_ticket(&ticket_id).ok_or(Error::NotFound {
-            msg: format!("ticket id:{} does not exist", ticket_id),
-        })?;
+        let ticket = _get_ticket(&ticket_id).ok_or(Error::not_found("ticket", ticket_id))?;
 
         // Add the ticket to the vector
         tickets.push(ticket);
@@ -652,6 +2068,8 @@ fn get_user_tickets(id: u64) -> Result<Vec<Ticket>, Error> {
     // Return the vector of tickets
     match tickets.len() {
         0 => Err(Error::NotFound {
+            entity: "ticket".to_string(),
+            id: id.to_string(),
             msg: format!("event id:{} has no tickets", id),
         }),
         _ => Ok(tickets),
@@ -661,18 +2079,14 @@ fn get_user_tickets(id: u64) -> Result<Vec<Ticket>, Error> {
 #[ic_cdk::query]
 fn get_event_tickets(id: u64) -> Result<Vec<Ticket>, Error> {
     // Retrieve the event with the given ID, or return a NotFound error if not found
-    let event = _get_event(&id).ok_or(Error::NotFound {
-        msg: format!("event id:{} does not exist", id),
-    })?;
+    let event = _get_event(&id).ok_or(Error::not_found("event", id))?;
 
     // Initialize a vector to store the event's tickets
     let mut tickets = vec![];
 
     // Iterate over the ticket IDs of the event and retrieve the corresponding tickets
     for ticket_id in event.ticket_ids {
-        let ticket = _get_ticket(&ticket_id).ok_or(Error::NotFound {
-            msg: format!("ticket id:{} does not exist", ticket_id),
-        })?;
+        let ticket = _get_ticket(&ticket_id).ok_or(Error::not_found("ticket", ticket_id))?;
 
         // Add the ticket to the vector
         tickets.push(ticket);
@@ -685,48 +2099,43 @@ fn get_event_tickets(id: u64) -> Result<Vec<Ticket>, Error> {
 // Function to add a ticket to a user's tickets
 fn add_user_ticket(user_id: u64, ticket_id: u64) -> Result<(), Error> {
     // Retrieve the user with the given ID, or return a NotFound error if not found
-    let user = _get_user(&user_id).ok_or(Error::NotFound {
-        msg: format!("user id:{} does not exist", user_id),
-    })?;
+    let user = _get_user(&user_id).ok_or(Error::not_found("user", user_id))?;
 
     // Retrieve the ticket with the given ID, or return a NotFound error if not found
-    let ticket = _get_ticket(&ticket_id).ok_or(Error::NotFound {
-        msg: format!("ticket id:{} does not exist", ticket_id),
-    })?;
+    let ticket = _get_ticket(&ticket_id).ok_or(Error::not_found("ticket", ticket_id))?;
 
     // Clone the current ticket IDs and add the new ticket ID
     let mut tickets = user.ticket_ids.clone();
     tickets.push(ticket.id);
 
     // Create an updated user with the new ticket IDs
-    let updated_user = User {
+    let mut updated_user = User {
         id: user.id,
         name: user.name,
         email: user.email,
-        password: user.password,
         event_ids: user.event_ids,
         ticket_ids: tickets,
         created_at: user.created_at,
         updated_at: Some(time()),
+        version: user.version,
+        deleted_at: user.deleted_at,
     };
 
     // Update the user in the storage
-    USER_STORAGE.with(|users| users.borrow_mut().insert(user.id, updated_user));
+    store_user(&mut updated_user);
 
     // Return Ok indicating a successful update
     Ok(())
 }
 
-#[ic_cdk::update]
+#[ic_cdk::update(guard = "rate_limit")]
 fn remove_user_ticket(payload: TicketPayload) -> Result<String, Error> {
     // Retrieve the event ID and user ID from the payload
     let event_id = payload.event_id;
     let user_id = payload.user_id;
 
     // Retrieve the user with the given ID, or return a NotFound error if not found
-    let user = _get_user(&user_id).ok_or(Error::NotFound {
-        msg: format!("user id:{} does not exist", user_id),
-    })?;
+    let user = _get_user(&user_id).ok_or(Error::not_found("user", user_id))?;
 
     // Find the ticket with the given event ID that belongs to the user
     let ticket_id = user.ticket_ids.iter().find(|&&ticket_id| {
@@ -736,34 +2145,41 @@ fn remove_user_ticket(payload: TicketPayload) -> Result<String, Error> {
 
     // If the ticket is not found, return a NotFound error
     let ticket_id = ticket_id.ok_or(Error::NotFound {
+        entity: "ticket".to_string(),
+        id: String::new(),
         msg: format!(
             "No ticket found for event id:{} for user id:{}",
             event_id, user_id
         ),
     })?;
+    if let Some(ticket) = _get_ticket(ticket_id) {
+        legal_holds::check_ticket(&ticket)?;
+    }
 
     // Clone the current ticket IDs and remove the specified ticket ID
     let mut tickets = user.ticket_ids.clone();
     tickets.retain(|&id| id != *ticket_id);
 
     // Create an updated user with the modified ticket IDs
-    let updated_user = User {
+    let mut updated_user = User {
         id: user.id,
         name: user.name,
         email: user.email,
-        password: user.password,
         event_ids: user.event_ids,
         ticket_ids: tickets,
         created_at: user.created_at,
         updated_at: Some(time()),
+        version: user.version,
+        deleted_at: user.deleted_at,
     };
 
     // Update the user in the storage
-    match USER_STORAGE.with(|users| users.borrow_mut().insert(user.id, updated_user)) {
+    match store_user(&mut updated_user) {
         Some(_) => (),
         None => {
-            return Err(Error::NotFound {
+            return Err(Error::InternalError {
                 msg: format!("user id:{} could not be deleted", user.id),
+                ticket_id: None,
             })
         }
     }
@@ -774,18 +2190,187 @@ fn remove_user_ticket(payload: TicketPayload) -> Result<String, Error> {
     ))
 }
 
-// Define an Error enum for handling errors
-#[derive(candid::CandidType, Deserialize, Serialize)]
+// Guard function restricting admin endpoints to the canister controllers
+fn caller_is_admin() -> Result<(), String> {
+    if ic_cdk::api::is_controller(&ic_cdk::caller()) {
+        Ok(())
+    } else {
+        Err("caller is not an admin".to_string())
+    }
+}
+
+// Define an Error enum for handling errors, every endpoint fails with one of these so clients
+// can branch on the variant, 'msg' is for people. Clients get it along with a hint of what to do
+// next, see 'remediation'.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+// 'InternalError' is the name clients match on
+#[allow(clippy::enum_variant_names)]
 enum Error {
-    NotFound { msg: String },
-    NotCreated { msg: String },
+    // 'entity' names the kind of record, e.g. "event", "tier" or "promo code", and 'id' the
+    // id or key it was looked up by, empty when there was none
+    NotFound {
+        entity: String,
+        id: String,
+        msg: String,
+    },
+    ValidationFailed {
+        msg: String,
+    },
+    // The caller isn't allowed to act on the record, or couldn't be identified
+    Unauthorized {
+        msg: String,
+    },
+    // The record changed since the version the caller based its update on
+    Conflict {
+        msg: String,
+        current_version: u64,
+    },
+    // A delete was refused because tickets or attendee links still point at the record
+    HasDependents {
+        msg: String,
+    },
+    // Tickets of the event can't change hands during one of its resale blackout windows
+    TransferFrozen {
+        msg: String,
+        reopens_at: u64,
+    },
+    // Another record already uses a value that must be unique, e.g. an email
+    AlreadyExists {
+        msg: String,
+    },
+    // The record, or one it belongs to, is kept under a legal hold until the hold is lifted
+    OnLegalHold {
+        msg: String,
+        hold_id: u64,
+    },
+    // The event, tier or allocation has no places left
+    CapacityExceeded {
+        msg: String,
+    },
+    // The user already holds as many tickets for the event as it allows
+    PurchaseLimitExceeded {
+        msg: String,
+        count: u64,
+        limit: u64,
+    },
+    // The charge couldn't be taken or checked, nothing was sold for it
+    PaymentFailed {
+        msg: String,
+    },
+    // The write would take a map, or stable memory as a whole, past its quota. 'memory_id' is
+    // the map's memory, None when it is stable memory as a whole
+    StorageQuotaExceeded {
+        msg: String,
+        memory_id: Option<u8>,
+    },
+    InternalError {
+        msg: String,
+        // Ticket stored before the failure, if any, which still counts as sold
+        ticket_id: Option<u64>,
+    },
 }
 
-// Define an Error enum for handling errors
-#[derive(candid::CandidType, Deserialize, Serialize)]
-enum AssociationError {
-    Err { msg: String, ticket: Ticket },
+impl Error {
+    // The usual error for a record missing under its id
+    fn not_found(entity: &str, id: impl std::fmt::Display) -> Error {
+        Error::NotFound {
+            entity: entity.to_string(),
+            id: id.to_string(),
+            msg: format!("{} id:{} does not exist", entity, id),
+        }
+    }
+}
+
+// The canister's Candid interface as the code exports it, for dfx and frontend codegen to fetch
+// from a deployed canister
+#[ic_cdk::query(name = "__get_candid_interface")]
+fn get_candid_interface() -> String {
+    __export_service()
 }
 
 // Candid generator for exporting the Candid interface
 ic_cdk::export_candid!();
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    #[test]
+    fn event_ids_keep_the_order_they_were_added_in() {
+        testing::event(1001);
+        for (ticket_id, user_id) in [(3003, 2003), (3001, 2001), (3002, 2002)] {
+            testing::user(user_id);
+            testing::ticket(ticket_id, 1001, user_id, 100);
+        }
+
+        let event = _get_event(&1001).unwrap();
+        assert_eq!(event.ticket_ids, vec![3003, 3001, 3002]);
+        assert_eq!(event.attendee_ids, vec![2003, 2001, 2002]);
+        // The ids are only kept in their own maps
+        let stored = EVENT_STORAGE
+            .with(|events| events.borrow().get(&1001))
+            .unwrap();
+        assert!(stored.ticket_ids.is_empty() && stored.attendee_ids.is_empty());
+    }
+
+    #[test]
+    fn removing_an_id_keeps_the_order_of_the_others() {
+        testing::event(1001);
+        testing::user(2001);
+        for ticket_id in [3003, 3001, 3002] {
+            testing::ticket(ticket_id, 1001, 2001, 100);
+        }
+
+        let mut user = _get_user(&2001).unwrap();
+        user.ticket_ids.retain(|id| *id != 3001);
+        store_user(&mut user);
+        let mut user = _get_user(&2001).unwrap();
+        assert_eq!(user.ticket_ids, vec![3003, 3002]);
+        assert_eq!(user.event_ids, vec![1001]);
+
+        user.ticket_ids.push(3004);
+        store_user(&mut user);
+        assert_eq!(_get_user(&2001).unwrap().ticket_ids, vec![3003, 3002, 3004]);
+    }
+
+    #[test]
+    fn ids_held_by_a_record_stored_earlier_move_out_when_stored_again() {
+        clock::set_time(testing::NOW);
+        let event = Event {
+            id: 1001,
+            ticket_ids: vec![3002, 3001],
+            attendee_ids: vec![2001],
+            ..Default::default()
+        };
+        EVENT_STORAGE.with(|events| events.borrow_mut().insert(event.id, event));
+
+        let mut event = _get_event(&1001).unwrap();
+        assert_eq!(event.ticket_ids, vec![3002, 3001]);
+        store_event(&mut event);
+        let stored = EVENT_STORAGE
+            .with(|events| events.borrow().get(&1001))
+            .unwrap();
+        assert!(stored.ticket_ids.is_empty());
+        assert_eq!(_get_event(&1001).unwrap().ticket_ids, vec![3002, 3001]);
+    }
+
+    #[test]
+    fn events_too_large_to_store_are_turned_down() {
+        let event = Event {
+            description: "x".repeat(Event::MAX_SIZE as usize),
+            ..Default::default()
+        };
+        assert!(matches!(
+            check_event_size(&event),
+            Err(Error::ValidationFailed { .. })
+        ));
+
+        // Ticket and attendee ids don't count, they are kept apart
+        let event = Event {
+            ticket_ids: (0..Event::MAX_SIZE as u64).collect(),
+            ..Default::default()
+        };
+        assert!(check_event_size(&event).is_ok());
+    }
+}
@@ -0,0 +1,80 @@
+use crate::clock::time;
+use crate::{_get_public_event, certification, stats, waitlist, Error};
+use serde_bytes::ByteBuf;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+
+// Define a struct for the counters shown on screens at the venue while an event runs
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct LiveCounters {
+    event_id: u64,
+    // None for events of unlimited capacity
+    capacity: Option<u64>,
+    tickets_sold: u64,
+    // Tickets checked in at the entrance
+    inside: u64,
+    waitlist_length: u64,
+    // Last time one of the counts changed
+    updated_at: u64,
+}
+
+// Define a struct for the live counters of an event returned along with their certification
+#[derive(candid::CandidType, Serialize)]
+pub struct CertifiedLiveCounters {
+    counters: LiveCounters,
+    // IC certificate of the canister's certified data, absent in replicated calls
+    certificate: Option<ByteBuf>,
+    // CBOR hash tree proving the SHA-256 of the candid-encoded counters under /live/<id>
+    witness: ByteBuf,
+}
+
+thread_local! {
+    // Counters of each public event, recomputed on every sale, check-in and waitlist change so
+    // polling only reads them. Kept on the heap and rebuilt with the certified tree on upgrade.
+    static LIVE_COUNTERS: RefCell<BTreeMap<u64, LiveCounters>> = const { RefCell::new(BTreeMap::new()) };
+}
+
+// Counters meant to be polled often by screens at the venue, with a witness so they can be
+// served from any replica without trusting it
+#[ic_cdk::query]
+fn get_live_counters(event_id: u64) -> Result<CertifiedLiveCounters, Error> {
+    let counters = LIVE_COUNTERS
+        .with(|counters| counters.borrow().get(&event_id).cloned())
+        .ok_or(Error::not_found("event", event_id))?;
+    Ok(CertifiedLiveCounters {
+        counters,
+        certificate: certification::certificate(),
+        witness: certification::live_counters_witness(event_id),
+    })
+}
+
+// Recompute the counters of an event, or drop them if it is gone, in the trash or embargoed
+pub(crate) fn refresh_counters(event_id: u64) -> Option<LiveCounters> {
+    let Some(event) = _get_public_event(&event_id) else {
+        LIVE_COUNTERS.with(|counters| counters.borrow_mut().remove(&event_id));
+        return None;
+    };
+    let (tickets_sold, inside) = stats::live_totals(event_id);
+    let waitlist_length = waitlist::waitlist_length(event_id);
+
+    LIVE_COUNTERS.with(|counters| {
+        let mut counters = counters.borrow_mut();
+        // Unchanged counts keep their timestamp, so screens can tell nothing happened
+        let unchanged = counters.get(&event_id).is_some_and(|current| {
+            (current.capacity, current.tickets_sold) == (event.capacity, tickets_sold)
+                && (current.inside, current.waitlist_length) == (inside, waitlist_length)
+        });
+        if !unchanged {
+            let live = LiveCounters {
+                event_id,
+                capacity: event.capacity,
+                tickets_sold,
+                inside,
+                waitlist_length,
+                updated_at: time(),
+            };
+            counters.insert(event_id, live);
+        }
+        counters.get(&event_id).cloned()
+    })
+}
@@ -0,0 +1,362 @@
+use crate::clock::time;
+use crate::ratelimit::rate_limit;
+use crate::{
+    _get_public_event, _get_user, organizers, Error, Event, Memory, ID_COUNTER, MEMORY_MANAGER,
+};
+use candid::{Decode, Encode};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use std::{borrow::Cow, cell::RefCell};
+
+const NANOS_PER_HOUR: u64 = 60 * 60 * 1_000_000_000;
+const MAX_NAME_LEN: usize = 64;
+// Presales open at most this long before the general on-sale
+const MAX_EARLY_ACCESS_HOURS: u64 = 30 * 24;
+
+// Define a struct for a membership an organizer offers, e.g. a fan club
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct MembershipTier {
+    id: u64,
+    organizer_id: u64,
+    name: String,
+    // Members can buy this long before the on-sale of each of the organizer's events
+    early_access_hours: u64,
+    created_at: u64,
+    updated_at: Option<u64>,
+}
+
+// Define a struct for the payload used to create and update membership tiers
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub struct MembershipTierPayload {
+    name: String,
+    early_access_hours: u64,
+}
+
+// Define a struct for a user's membership of a tier
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct Membership {
+    tier_id: u64,
+    user_id: u64,
+    // Lapsed memberships give no early access and are removed by the expiry job
+    expires_at: Option<u64>,
+    granted_at: u64,
+}
+
+// Define a struct for when an event's tickets go on sale, to members and to everyone
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub struct SaleWindows {
+    event_id: u64,
+    // None when the event is on sale as soon as it is fully announced
+    opens_at: Option<u64>,
+    presales: Vec<Presale>,
+}
+
+// Define a struct for the presale of a membership tier
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub struct Presale {
+    tier_id: u64,
+    name: String,
+    opens_at: u64,
+}
+
+impl Storable for MembershipTier {
+    // Conversion to bytes
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    // Conversion from bytes
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for MembershipTier {
+    const MAX_SIZE: u32 = 256;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl Storable for Membership {
+    // Conversion to bytes
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    // Conversion from bytes
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for Membership {
+    const MAX_SIZE: u32 = 128;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    static TIER_STORAGE: RefCell<StableBTreeMap<u64, MembershipTier, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(89)))
+    ));
+
+    // Memberships keyed by (user id, tier id)
+    static MEMBERSHIP_STORAGE: RefCell<StableBTreeMap<(u64, u64), Membership, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(90)))
+    ));
+
+    // General on-sale time keyed by event id, events missing are on sale once announced
+    static ON_SALE_STORAGE: RefCell<StableBTreeMap<u64, u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(91)))
+    ));
+}
+
+#[ic_cdk::update(guard = "rate_limit")]
+fn create_membership_tier(payload: MembershipTierPayload) -> Result<MembershipTier, Error> {
+    let organizer_id = organizers::caller_organizer_id().ok_or(Error::Unauthorized {
+        msg: "only organizers can offer memberships, call 'register_organizer' first".to_string(),
+    })?;
+    validate_tier(&payload).map_err(|msg| Error::ValidationFailed { msg })?;
+
+    // Increment the global ID counter to get a new ID for the membership tier
+    let id = ID_COUNTER
+        .with(|counter| {
+            let current_id = *counter.borrow().get();
+            counter.borrow_mut().set(current_id + 1)
+        })
+        .expect("Cannot increment Ids");
+    let tier = MembershipTier {
+        id,
+        organizer_id,
+        name: payload.name,
+        early_access_hours: payload.early_access_hours,
+        created_at: time(),
+        updated_at: None,
+    };
+    TIER_STORAGE.with(|tiers| tiers.borrow_mut().insert(id, tier.clone()));
+    Ok(tier)
+}
+
+#[ic_cdk::update(guard = "rate_limit")]
+fn update_membership_tier(
+    tier_id: u64,
+    payload: MembershipTierPayload,
+) -> Result<MembershipTier, Error> {
+    let mut tier = authorize_tier(tier_id)?;
+    validate_tier(&payload).map_err(|msg| Error::ValidationFailed { msg })?;
+    tier.name = payload.name;
+    tier.early_access_hours = payload.early_access_hours;
+    tier.updated_at = Some(time());
+    TIER_STORAGE.with(|tiers| tiers.borrow_mut().insert(tier_id, tier.clone()));
+    Ok(tier)
+}
+
+#[ic_cdk::query]
+fn get_membership_tiers(organizer_id: u64) -> Vec<MembershipTier> {
+    organizer_tiers(organizer_id)
+}
+
+// Make a user a member of a tier, or change when their membership expires
+#[ic_cdk::update(guard = "rate_limit")]
+fn grant_membership(
+    tier_id: u64,
+    user_id: u64,
+    expires_at: Option<u64>,
+) -> Result<Membership, Error> {
+    authorize_tier(tier_id)?;
+    _get_user(&user_id).ok_or(Error::not_found("user", user_id))?;
+    if expires_at.is_some_and(|at| at <= time()) {
+        return Err(Error::ValidationFailed {
+            msg: "membership must expire in the future".to_string(),
+        });
+    }
+    let membership = Membership {
+        tier_id,
+        user_id,
+        expires_at,
+        granted_at: time(),
+    };
+    MEMBERSHIP_STORAGE.with(|memberships| {
+        memberships
+            .borrow_mut()
+            .insert((user_id, tier_id), membership.clone())
+    });
+    Ok(membership)
+}
+
+#[ic_cdk::update(guard = "rate_limit")]
+fn revoke_membership(tier_id: u64, user_id: u64) -> Result<Membership, Error> {
+    authorize_tier(tier_id)?;
+    MEMBERSHIP_STORAGE
+        .with(|memberships| memberships.borrow_mut().remove(&(user_id, tier_id)))
+        .ok_or(Error::NotFound {
+            entity: "membership".to_string(),
+            id: user_id.to_string(),
+            msg: format!(
+                "user id:{} is not a member of membership tier id:{}",
+                user_id, tier_id
+            ),
+        })
+}
+
+#[ic_cdk::query]
+pub(crate) fn get_user_memberships(user_id: u64) -> Vec<Membership> {
+    user_memberships(user_id)
+}
+
+// Set when an event goes on sale to everyone, members of its organizers' tiers get in earlier
+#[ic_cdk::update(guard = "rate_limit")]
+fn set_event_on_sale(event_id: u64, opens_at: Option<u64>) -> Result<SaleWindows, Error> {
+    let event = organizers::authorize_event(event_id)?;
+    match opens_at {
+        Some(opens_at) => {
+            ON_SALE_STORAGE.with(|times| times.borrow_mut().insert(event_id, opens_at));
+        }
+        None => {
+            ON_SALE_STORAGE.with(|times| times.borrow_mut().remove(&event_id));
+        }
+    }
+    Ok(sale_windows(&event))
+}
+
+#[ic_cdk::query]
+fn get_event_sale_windows(event_id: u64) -> Result<SaleWindows, Error> {
+    let event = _get_public_event(&event_id).ok_or(Error::not_found("event", event_id))?;
+    Ok(sale_windows(&event))
+}
+
+fn validate_tier(payload: &MembershipTierPayload) -> Result<(), String> {
+    if payload.name.trim().is_empty() || payload.name.len() > MAX_NAME_LEN {
+        return Err(format!(
+            "membership tier name must be 1 to {} bytes",
+            MAX_NAME_LEN
+        ));
+    }
+    if payload.early_access_hours > MAX_EARLY_ACCESS_HOURS {
+        return Err(format!(
+            "early access can start at most {} hours before the on-sale",
+            MAX_EARLY_ACCESS_HOURS
+        ));
+    }
+    Ok(())
+}
+
+// Retrieve a membership tier the caller runs
+fn authorize_tier(tier_id: u64) -> Result<MembershipTier, Error> {
+    let tier = TIER_STORAGE
+        .with(|tiers| tiers.borrow().get(&tier_id))
+        .ok_or(Error::not_found("membership tier", tier_id))?;
+    organizers::check_organizer(tier.organizer_id)?;
+    Ok(tier)
+}
+
+fn organizer_tiers(organizer_id: u64) -> Vec<MembershipTier> {
+    TIER_STORAGE.with(|tiers| {
+        tiers
+            .borrow()
+            .iter()
+            .map(|(_, tier)| tier)
+            .filter(|tier| tier.organizer_id == organizer_id)
+            .collect()
+    })
+}
+
+fn user_memberships(user_id: u64) -> Vec<Membership> {
+    MEMBERSHIP_STORAGE.with(|memberships| {
+        memberships
+            .borrow()
+            .range((user_id, 0)..=(user_id, u64::MAX))
+            .map(|(_, membership)| membership)
+            .collect()
+    })
+}
+
+// Membership tiers of the organizers of an event
+fn event_tiers(event: &Event) -> Vec<MembershipTier> {
+    event
+        .organizer_ids
+        .iter()
+        .flatten()
+        .flat_map(|organizer_id| organizer_tiers(*organizer_id))
+        .collect()
+}
+
+fn sale_windows(event: &Event) -> SaleWindows {
+    let opens_at = ON_SALE_STORAGE.with(|times| times.borrow().get(&event.id));
+    let presales = match opens_at {
+        Some(opens_at) => event_tiers(event)
+            .into_iter()
+            .filter(|tier| tier.early_access_hours > 0)
+            .map(|tier| Presale {
+                tier_id: tier.id,
+                opens_at: opens_at.saturating_sub(tier.early_access_hours * NANOS_PER_HOUR),
+                name: tier.name,
+            })
+            .collect(),
+        None => vec![],
+    };
+    SaleWindows {
+        event_id: event.id,
+        opens_at,
+        presales,
+    }
+}
+
+// Check an event's tickets are on sale to a user, to everyone once the on-sale passed and
+// before that to members whose presale opened and whose membership hasn't lapsed
+pub(crate) fn check_on_sale(event: &Event, user_id: u64) -> Result<(), String> {
+    let windows = sale_windows(event);
+    let Some(opens_at) = windows.opens_at else {
+        return Ok(());
+    };
+    let now = time();
+    if now >= opens_at {
+        return Ok(());
+    }
+    let presale_open = user_memberships(user_id)
+        .iter()
+        .filter(|membership| membership.expires_at.is_none_or(|at| at > now))
+        .any(|membership| {
+            windows
+                .presales
+                .iter()
+                .any(|presale| presale.tier_id == membership.tier_id && presale.opens_at <= now)
+        });
+    if presale_open {
+        return Ok(());
+    }
+    Err(format!(
+        "event id:{} goes on sale at {}",
+        event.id, opens_at
+    ))
+}
+
+// Remove lapsed memberships, returning how many were removed
+pub(crate) fn expire_memberships() -> u64 {
+    let now = time();
+    MEMBERSHIP_STORAGE.with(|memberships| {
+        let mut memberships = memberships.borrow_mut();
+        let expired: Vec<(u64, u64)> = memberships
+            .iter()
+            .filter(|(_, membership)| membership.expires_at.is_some_and(|at| at <= now))
+            .map(|(key, _)| key)
+            .collect();
+        for key in &expired {
+            memberships.remove(key);
+        }
+        expired.len() as u64
+    })
+}
+
+pub(crate) fn remove_event_on_sale(event_id: u64) {
+    ON_SALE_STORAGE.with(|times| times.borrow_mut().remove(&event_id));
+}
+
+pub(crate) fn remove_user_memberships(user_id: u64) {
+    for membership in user_memberships(user_id) {
+        MEMBERSHIP_STORAGE.with(|memberships| {
+            memberships
+                .borrow_mut()
+                .remove(&(user_id, membership.tier_id))
+        });
+    }
+}
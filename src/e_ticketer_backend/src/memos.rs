@@ -0,0 +1,159 @@
+use crate::clock::time;
+use crate::icrc7::Account;
+use crate::{caller_is_admin, Error, Memory, MEMORY_MANAGER};
+use candid::{Decode, Encode, Nat, Principal};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use serde_bytes::ByteBuf;
+use std::{borrow::Cow, cell::RefCell};
+
+// Memos start with this, so they can be told apart from those of other senders on the ledger
+const MEMO_PREFIX: &[u8; 3] = b"ETK";
+// Prefix, purpose, record id and sequence number, well within the 32 bytes ICRC-1 allows
+const MEMO_LEN: usize = 3 + 1 + 8 + 8;
+
+// Define an enum for what an outgoing ledger transfer was made for
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub enum MemoPurpose {
+    // A purchase fell through and its payment went back to the payer
+    Refund {
+        payment_id: u64,
+        user_id: u64,
+    },
+    // An organizer's share of an event's revenue
+    Payout {
+        payout_id: u64,
+        event_id: u64,
+        organizer_id: u64,
+    },
+    // The seller's share of a resold ticket's price
+    ResalePayout {
+        sale_id: u64,
+        seller_id: u64,
+    },
+}
+
+// Define a struct for the memo sent with an outgoing ledger transfer, kept for reconciliation
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct TransferMemo {
+    // Counts every outgoing transfer, so no two transfers share a memo
+    sequence: u64,
+    memo: ByteBuf,
+    purpose: MemoPurpose,
+    ledger: Principal,
+    to: Account,
+    // Sent to the account, the ledger fee not included
+    amount: Nat,
+    // Set once the ledger took the transfer
+    block_index: Option<Nat>,
+    // Set if it didn't, the memo isn't used again
+    error: Option<String>,
+    created_at: u64,
+}
+
+impl MemoPurpose {
+    fn tag(&self) -> (u8, u64) {
+        match self {
+            MemoPurpose::Refund { payment_id, .. } => (b'R', *payment_id),
+            MemoPurpose::Payout { payout_id, .. } => (b'P', *payout_id),
+            MemoPurpose::ResalePayout { sale_id, .. } => (b'S', *sale_id),
+        }
+    }
+}
+
+impl Storable for TransferMemo {
+    // Conversion to bytes
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    // Conversion from bytes
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for TransferMemo {
+    const MAX_SIZE: u32 = 1024;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    // Memos keyed by sequence number, never removed as the ledger keeps the transfers for good
+    static MEMO_STORAGE: RefCell<StableBTreeMap<u64, TransferMemo, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(130)))
+    ));
+}
+
+// What an outgoing transfer seen on the ledger was made for, by the memo it carries
+#[ic_cdk::query(guard = "caller_is_admin")]
+fn find_order_by_memo(memo: ByteBuf) -> Result<TransferMemo, Error> {
+    let hex: String = memo.iter().map(|byte| format!("{:02x}", byte)).collect();
+    let not_found = || Error::NotFound {
+        entity: "memo".to_string(),
+        id: hex.clone(),
+        msg: format!("no transfer was sent with memo {}", hex),
+    };
+    let sequence = parse_sequence(&memo).ok_or_else(not_found)?;
+    MEMO_STORAGE
+        .with(|memos| memos.borrow().get(&sequence))
+        .filter(|stored| stored.memo == memo)
+        .ok_or_else(not_found)
+}
+
+// Take the next memo for a transfer about to be sent, returning its sequence number with it
+pub(crate) fn issue(
+    purpose: MemoPurpose,
+    ledger: Principal,
+    to: &Account,
+    amount: &Nat,
+) -> (u64, ByteBuf) {
+    let sequence = MEMO_STORAGE.with(|memos| {
+        memos
+            .borrow()
+            .last_key_value()
+            .map_or(1, |(sequence, _)| sequence + 1)
+    });
+    let (kind, id) = purpose.tag();
+    let mut memo = Vec::with_capacity(MEMO_LEN);
+    memo.extend_from_slice(MEMO_PREFIX);
+    memo.push(kind);
+    memo.extend_from_slice(&id.to_be_bytes());
+    memo.extend_from_slice(&sequence.to_be_bytes());
+    let memo = ByteBuf::from(memo);
+
+    let entry = TransferMemo {
+        sequence,
+        memo: memo.clone(),
+        purpose,
+        ledger,
+        to: to.clone(),
+        amount: amount.clone(),
+        block_index: None,
+        error: None,
+        created_at: time(),
+    };
+    MEMO_STORAGE.with(|memos| memos.borrow_mut().insert(sequence, entry));
+    (sequence, memo)
+}
+
+// Keep the outcome of the transfer a memo was issued for
+pub(crate) fn record_result(sequence: u64, result: &Result<Nat, String>) {
+    MEMO_STORAGE.with(|memos| {
+        let mut memos = memos.borrow_mut();
+        if let Some(mut entry) = memos.get(&sequence) {
+            match result {
+                Ok(block_index) => entry.block_index = Some(block_index.clone()),
+                Err(msg) => entry.error = Some(msg.clone()),
+            }
+            memos.insert(sequence, entry);
+        }
+    });
+}
+
+fn parse_sequence(memo: &[u8]) -> Option<u64> {
+    if memo.len() != MEMO_LEN || !memo.starts_with(MEMO_PREFIX) {
+        return None;
+    }
+    Some(u64::from_be_bytes(memo[MEMO_LEN - 8..].try_into().ok()?))
+}
@@ -0,0 +1,45 @@
+// Metadata is kept small, as it is stored within the size bound of an event or ticket
+const MAX_ENTRIES: usize = 4;
+const MAX_KEY_LEN: usize = 24;
+const MAX_VALUE_LEN: usize = 64;
+
+// Key-value pairs integrators attach to events and tickets, e.g. a CRM or seat-map vendor id
+pub type Metadata = Vec<(String, String)>;
+
+// Check metadata and sort it by key, so the same entries are always stored the same
+pub(crate) fn validate_metadata(metadata: &Metadata) -> Result<Metadata, String> {
+    if metadata.len() > MAX_ENTRIES {
+        return Err(format!("metadata can have at most {} entries", MAX_ENTRIES));
+    }
+
+    for (key, value) in metadata {
+        if key.is_empty()
+            || key.len() > MAX_KEY_LEN
+            || !key
+                .chars()
+                .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || "_.-".contains(c))
+        {
+            return Err(format!(
+                "metadata key '{}' must be 1 to {} bytes of lowercase letters, digits, '_', '.' and '-'",
+                key, MAX_KEY_LEN
+            ));
+        }
+        if value.len() > MAX_VALUE_LEN {
+            return Err(format!(
+                "metadata value of '{}' must be at most {} bytes",
+                key, MAX_VALUE_LEN
+            ));
+        }
+    }
+
+    let mut metadata = metadata.clone();
+    metadata.sort();
+    if let Some(pair) = metadata.windows(2).find(|pair| pair[0].0 == pair[1].0) {
+        return Err(format!(
+            "metadata key '{}' is given more than once",
+            pair[0].0
+        ));
+    }
+
+    Ok(metadata)
+}
@@ -0,0 +1,358 @@
+use crate::clock::time;
+use crate::ratelimit::rate_limit;
+use crate::{
+    _get_event, _get_public_event, _get_user, caller_is_admin, federation, Error, Event, Memory,
+    ID_COUNTER, MEMORY_MANAGER,
+};
+use candid::{Decode, Encode, Principal};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use std::{borrow::Cow, cell::RefCell};
+
+const MAX_REASON_LENGTH: usize = 500;
+
+// Define an enum for what a report or sanction is about
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq)]
+pub enum ModerationTarget {
+    Event { id: u64 },
+    User { id: u64 },
+}
+
+// Define an enum for where a report stands in the queue
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq)]
+pub enum ReportStatus {
+    Open,
+    // Reviewed and found to need nothing done
+    Dismissed,
+    // Reviewed and acted on, e.g. by suspending the event or banning the user
+    Actioned,
+}
+
+// Define a struct for a report of an event or user, queued for admins to review
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct Report {
+    id: u64,
+    target: ModerationTarget,
+    reason: String,
+    reported_by: Principal,
+    status: ReportStatus,
+    created_at: u64,
+    reviewed_by: Option<Principal>,
+    reviewed_at: Option<u64>,
+}
+
+// Define a struct for a suspension of an event or a ban of a user. A suspended event is hidden
+// from listings and its sales are frozen, a banned user can't buy tickets.
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct Sanction {
+    id: u64,
+    target: ModerationTarget,
+    reason: String,
+    imposed_by: Principal,
+    imposed_at: u64,
+    lifted_by: Option<Principal>,
+    lifted_at: Option<u64>,
+}
+
+impl Storable for Report {
+    // Conversion to bytes
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    // Conversion from bytes
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for Report {
+    const MAX_SIZE: u32 = 1024;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl Storable for Sanction {
+    // Conversion to bytes
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    // Conversion from bytes
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for Sanction {
+    const MAX_SIZE: u32 = 1024;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    // Reports keyed by id, reviewed ones are kept as a record of the review
+    static REPORT_STORAGE: RefCell<StableBTreeMap<u64, Report, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(118)))
+    ));
+
+    // Sanctions keyed by id, lifted ones are kept as a record of the sanction
+    static SANCTION_STORAGE: RefCell<StableBTreeMap<u64, Sanction, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(119)))
+    ));
+
+    // Suspended events pointing at their sanction, checked on every listing and sale
+    static SUSPENDED_EVENTS: RefCell<StableBTreeMap<u64, u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(120)))
+    ));
+
+    // Banned users pointing at their sanction, checked on every sale
+    static BANNED_USERS: RefCell<StableBTreeMap<u64, u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(121)))
+    ));
+}
+
+#[ic_cdk::update(guard = "rate_limit")]
+fn report_event(event_id: u64, reason: String) -> Result<Report, Error> {
+    _get_public_event(&event_id).ok_or(Error::not_found("event", event_id))?;
+    file_report(ModerationTarget::Event { id: event_id }, reason)
+}
+
+#[ic_cdk::update(guard = "rate_limit")]
+fn report_user(user_id: u64, reason: String) -> Result<Report, Error> {
+    _get_user(&user_id).ok_or(Error::not_found("user", user_id))?;
+    file_report(ModerationTarget::User { id: user_id }, reason)
+}
+
+// The report queue, oldest first, all of it unless a status is given
+#[ic_cdk::query(guard = "caller_is_admin")]
+fn get_reports(status: Option<ReportStatus>) -> Vec<Report> {
+    REPORT_STORAGE.with(|reports| {
+        reports
+            .borrow()
+            .iter()
+            .map(|(_, report)| report)
+            .filter(|report| status.is_none_or(|status| report.status == status))
+            .collect()
+    })
+}
+
+// Close a report as dismissed or actioned, sanctions are imposed with their own calls
+#[ic_cdk::update(guard = "caller_is_admin")]
+fn review_report(id: u64, status: ReportStatus) -> Result<Report, Error> {
+    if status == ReportStatus::Open {
+        return Err(Error::ValidationFailed {
+            msg: "a report is reviewed as dismissed or actioned".to_string(),
+        });
+    }
+    let mut report = REPORT_STORAGE
+        .with(|reports| reports.borrow().get(&id))
+        .filter(|report| report.status == ReportStatus::Open)
+        .ok_or(Error::NotFound {
+            entity: "report".to_string(),
+            id: id.to_string(),
+            msg: format!("report id:{} does not exist or was reviewed", id),
+        })?;
+    report.status = status;
+    report.reviewed_by = Some(ic_cdk::caller());
+    report.reviewed_at = Some(time());
+    REPORT_STORAGE.with(|reports| reports.borrow_mut().insert(id, report.clone()));
+    Ok(report)
+}
+
+// Hide an event from listings and freeze its sales, tickets already sold stay valid
+#[ic_cdk::update(guard = "caller_is_admin")]
+fn suspend_event(event_id: u64, reason: String) -> Result<Sanction, Error> {
+    _get_event(&event_id).ok_or(Error::not_found("event", event_id))?;
+    let sanction = impose(ModerationTarget::Event { id: event_id }, reason)?;
+    federation::track_event(event_id);
+    Ok(sanction)
+}
+
+#[ic_cdk::update(guard = "caller_is_admin")]
+fn reinstate_event(event_id: u64) -> Result<Sanction, Error> {
+    let sanction = lift(ModerationTarget::Event { id: event_id })?;
+    federation::track_event(event_id);
+    Ok(sanction)
+}
+
+// Turn down every purchase of a user from now on, tickets they hold stay valid
+#[ic_cdk::update(guard = "caller_is_admin")]
+fn ban_user(user_id: u64, reason: String) -> Result<Sanction, Error> {
+    _get_user(&user_id).ok_or(Error::not_found("user", user_id))?;
+    impose(ModerationTarget::User { id: user_id }, reason)
+}
+
+#[ic_cdk::update(guard = "caller_is_admin")]
+fn unban_user(user_id: u64) -> Result<Sanction, Error> {
+    lift(ModerationTarget::User { id: user_id })
+}
+
+// Sanctions in force, along with lifted ones if asked
+#[ic_cdk::query(guard = "caller_is_admin")]
+fn get_sanctions(include_lifted: Option<bool>) -> Vec<Sanction> {
+    let include_lifted = include_lifted.unwrap_or(false);
+    SANCTION_STORAGE.with(|sanctions| {
+        sanctions
+            .borrow()
+            .iter()
+            .map(|(_, sanction)| sanction)
+            .filter(|sanction| include_lifted || sanction.lifted_at.is_none())
+            .collect()
+    })
+}
+
+// Queue a report, a caller's open report on the same target is returned instead of a second one
+fn file_report(target: ModerationTarget, reason: String) -> Result<Report, Error> {
+    check_reason(&reason).map_err(|msg| Error::ValidationFailed { msg })?;
+    let caller = ic_cdk::caller();
+    let open = REPORT_STORAGE.with(|reports| {
+        reports
+            .borrow()
+            .iter()
+            .map(|(_, report)| report)
+            .find(|report| {
+                report.status == ReportStatus::Open
+                    && report.target == target
+                    && report.reported_by == caller
+            })
+    });
+    if let Some(report) = open {
+        return Ok(report);
+    }
+
+    // Increment the global ID counter to get a new ID for the report
+    let id = ID_COUNTER
+        .with(|counter| {
+            let current_id = *counter.borrow().get();
+            counter.borrow_mut().set(current_id + 1)
+        })
+        .expect("Cannot increment Ids");
+    let report = Report {
+        id,
+        target,
+        reason,
+        reported_by: caller,
+        status: ReportStatus::Open,
+        created_at: time(),
+        reviewed_by: None,
+        reviewed_at: None,
+    };
+    REPORT_STORAGE.with(|reports| reports.borrow_mut().insert(id, report.clone()));
+    Ok(report)
+}
+
+fn impose(target: ModerationTarget, reason: String) -> Result<Sanction, Error> {
+    check_reason(&reason).map_err(|msg| Error::ValidationFailed { msg })?;
+    if let Some(sanction) = active_sanction(target) {
+        return Err(Error::ValidationFailed {
+            msg: format!(
+                "{} is already sanctioned by sanction id:{}",
+                describe(target),
+                sanction.id
+            ),
+        });
+    }
+
+    // Increment the global ID counter to get a new ID for the sanction
+    let id = ID_COUNTER
+        .with(|counter| {
+            let current_id = *counter.borrow().get();
+            counter.borrow_mut().set(current_id + 1)
+        })
+        .expect("Cannot increment Ids");
+    let sanction = Sanction {
+        id,
+        target,
+        reason,
+        imposed_by: ic_cdk::caller(),
+        imposed_at: time(),
+        lifted_by: None,
+        lifted_at: None,
+    };
+    SANCTION_STORAGE.with(|sanctions| sanctions.borrow_mut().insert(id, sanction.clone()));
+    match target {
+        ModerationTarget::Event { id: event_id } => {
+            SUSPENDED_EVENTS.with(|events| events.borrow_mut().insert(event_id, id))
+        }
+        ModerationTarget::User { id: user_id } => {
+            BANNED_USERS.with(|users| users.borrow_mut().insert(user_id, id))
+        }
+    };
+    Ok(sanction)
+}
+
+fn lift(target: ModerationTarget) -> Result<Sanction, Error> {
+    let mut sanction = active_sanction(target).ok_or(Error::NotFound {
+        entity: "sanction".to_string(),
+        id: match target {
+            ModerationTarget::Event { id } | ModerationTarget::User { id } => id.to_string(),
+        },
+        msg: format!("{} is not sanctioned", describe(target)),
+    })?;
+    sanction.lifted_by = Some(ic_cdk::caller());
+    sanction.lifted_at = Some(time());
+    SANCTION_STORAGE.with(|sanctions| sanctions.borrow_mut().insert(sanction.id, sanction.clone()));
+    remove_active(target);
+    Ok(sanction)
+}
+
+fn active_sanction(target: ModerationTarget) -> Option<Sanction> {
+    let id = match target {
+        ModerationTarget::Event { id } => SUSPENDED_EVENTS.with(|events| events.borrow().get(&id)),
+        ModerationTarget::User { id } => BANNED_USERS.with(|users| users.borrow().get(&id)),
+    }?;
+    SANCTION_STORAGE.with(|sanctions| sanctions.borrow().get(&id))
+}
+
+fn remove_active(target: ModerationTarget) {
+    match target {
+        ModerationTarget::Event { id } => {
+            SUSPENDED_EVENTS.with(|events| events.borrow_mut().remove(&id))
+        }
+        ModerationTarget::User { id } => BANNED_USERS.with(|users| users.borrow_mut().remove(&id)),
+    };
+}
+
+fn check_reason(reason: &str) -> Result<(), String> {
+    if reason.trim().is_empty() || reason.len() > MAX_REASON_LENGTH {
+        return Err(format!(
+            "a reason of 1 to {} characters is needed",
+            MAX_REASON_LENGTH
+        ));
+    }
+    Ok(())
+}
+
+fn describe(target: ModerationTarget) -> String {
+    match target {
+        ModerationTarget::Event { id } => format!("event id:{}", id),
+        ModerationTarget::User { id } => format!("user id:{}", id),
+    }
+}
+
+// Suspended events are left out of listings and search
+pub(crate) fn is_suspended(event_id: u64) -> bool {
+    SUSPENDED_EVENTS.with(|events| events.borrow().contains_key(&event_id))
+}
+
+// Check a user may buy a ticket of an event, neither may be sanctioned
+pub(crate) fn check_purchase(event: &Event, user_id: u64) -> Result<(), String> {
+    if is_suspended(event.id) {
+        return Err(format!("sales of event id:{} are suspended", event.id));
+    }
+    if BANNED_USERS.with(|users| users.borrow().contains_key(&user_id)) {
+        return Err(format!("user id:{} is banned from buying tickets", user_id));
+    }
+    Ok(())
+}
+
+// Forget the suspension of a purged event, the sanction stays as a record
+pub(crate) fn remove_event_suspension(event_id: u64) {
+    remove_active(ModerationTarget::Event { id: event_id });
+}
+
+// Forget the ban of a purged user, the sanction stays as a record
+pub(crate) fn remove_user_ban(user_id: u64) {
+    remove_active(ModerationTarget::User { id: user_id });
+}
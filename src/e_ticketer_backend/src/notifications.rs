@@ -0,0 +1,421 @@
+use crate::clock::time;
+use crate::ratelimit::rate_limit;
+use crate::{
+    _get_event, _get_user, backup, broadcasts, caller_is_admin, geo, Error, Event, Memory,
+    ID_COUNTER, MEMORY_MANAGER,
+};
+use candid::{Decode, Encode};
+use ic_cdk::api::management_canister::http_request::{
+    http_request, CanisterHttpRequestArgument, HttpHeader, HttpMethod,
+    HttpResponse as OutcallResponse, TransformArgs, TransformContext,
+};
+use ic_cdk_timers::TimerId;
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, Cell, StableBTreeMap, Storable};
+use std::time::Duration;
+use std::{borrow::Cow, cell::RefCell};
+
+// How often the outbox is drained, and how many messages go out per run
+const DELIVERY_INTERVAL: Duration = Duration::from_secs(60);
+const DELIVERY_BATCH: usize = 10;
+
+// Retries back off exponentially from the base delay, until the message is marked failed
+const RETRY_BASE_DELAY: u64 = 60 * 1_000_000_000;
+const MAX_ATTEMPTS: u32 = 8;
+
+// Delivered messages stay in the outbox this long, so their status can be looked up
+const DELIVERED_RETENTION: u64 = 7 * 24 * 60 * 60 * 1_000_000_000;
+
+// Cycles attached to each outcall, enough for a small JSON request and a status-only response
+const OUTCALL_CYCLES: u128 = 2_000_000_000;
+const OUTCALL_MAX_RESPONSE_BYTES: u64 = 2048;
+
+// Define an enum for the reason a notification is sent
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq)]
+pub enum NotificationKind {
+    TicketPurchased,
+    EventCancelled,
+    EventReminder,
+    SurveyAvailable,
+    EventAnnounced,
+    OrderConfirmed,
+    CapacityAlert,
+    EventChanged,
+}
+
+// Define an enum for the delivery state of a notification
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq)]
+pub enum NotificationStatus {
+    Pending,
+    // Gave up after 'MAX_ATTEMPTS' deliveries, kept until resent
+    Failed,
+    Delivered,
+}
+
+// Define a struct for a message waiting in the outbox
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct Notification {
+    id: u64,
+    kind: NotificationKind,
+    event_id: u64,
+    user_id: u64,
+    recipient: String,
+    subject: String,
+    body: String,
+    status: NotificationStatus,
+    attempts: u32,
+    next_attempt_at: u64,
+    last_error: Option<String>,
+    delivered_at: Option<u64>,
+    created_at: u64,
+}
+
+// Define a struct for the delivery settings of the notification subsystem
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub(crate) struct NotificationSettings {
+    // Webhook or SMTP relay receiving each notification as a JSON POST
+    webhook_url: Option<String>,
+}
+
+impl Storable for Notification {
+    // Conversion to bytes
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    // Conversion from bytes
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl Storable for NotificationSettings {
+    // Conversion to bytes
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    // Conversion from bytes
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for Notification {
+    const MAX_SIZE: u32 = 4096;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    // Notifications waiting to be delivered, keyed by id
+    static OUTBOX: RefCell<StableBTreeMap<u64, Notification, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(18)))
+    ));
+
+    static SETTINGS: RefCell<Cell<NotificationSettings, Memory>> = RefCell::new(
+        Cell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(19))),
+            NotificationSettings::default(),
+        )
+        .expect("Cannot create the notification settings")
+    );
+
+    // Set while a delivery run is waiting on outcalls, so timer ticks don't overlap
+    static DELIVERING: std::cell::Cell<bool> = const { std::cell::Cell::new(false) };
+
+    static DELIVERY_TIMER: RefCell<Option<TimerId>> = const { RefCell::new(None) };
+}
+
+#[ic_cdk::query(guard = "caller_is_admin")]
+fn get_pending_notifications() -> Vec<Notification> {
+    OUTBOX.with(|outbox| {
+        outbox
+            .borrow()
+            .iter()
+            .map(|(_, notification)| notification)
+            .filter(|notification| notification.status != NotificationStatus::Delivered)
+            .collect()
+    })
+}
+
+#[ic_cdk::query(guard = "caller_is_admin")]
+fn get_event_notifications(event_id: u64) -> Vec<Notification> {
+    _get_event_notifications(event_id)
+}
+
+#[ic_cdk::update(guard = "caller_is_admin")]
+fn resend_failed(event_id: u64, kind: Option<NotificationKind>) -> Result<String, Error> {
+    // Failed messages start over with a fresh backoff, pending and delivered ones are left alone
+    let now = time();
+    let mut resent = 0;
+    for mut notification in _get_event_notifications(event_id) {
+        if notification.status != NotificationStatus::Failed
+            || kind.is_some_and(|kind| kind != notification.kind)
+        {
+            continue;
+        }
+        notification.status = NotificationStatus::Pending;
+        notification.attempts = 0;
+        notification.next_attempt_at = now;
+        OUTBOX.with(|outbox| outbox.borrow_mut().insert(notification.id, notification));
+        resent += 1;
+    }
+
+    Ok(format!(
+        "{} failed notifications of event id: {} queued again",
+        resent, event_id
+    ))
+}
+
+#[ic_cdk::update(guard = "caller_is_admin")]
+fn set_notification_webhook(webhook_url: Option<String>) -> Result<String, Error> {
+    if let Some(url) = &webhook_url {
+        if !url.starts_with("https://") {
+            return Err(Error::ValidationFailed {
+                msg: format!("webhook url {} must use https", url),
+            });
+        }
+    }
+
+    SETTINGS
+        .with(|settings| {
+            settings
+                .borrow_mut()
+                .set(NotificationSettings { webhook_url })
+        })
+        .map_err(|_| Error::InternalError {
+            msg: "notification settings could not be saved".to_string(),
+            ticket_id: None,
+        })?;
+
+    Ok("notification webhook updated".to_string())
+}
+
+#[ic_cdk::update(guard = "rate_limit")]
+fn send_event_reminder(event_id: u64) -> Result<String, Error> {
+    // Retrieve the event with the given ID, or return a NotFound error if not found
+    let event = _get_event(&event_id).ok_or(Error::not_found("event", event_id))?;
+
+    remind_event_attendees(&event);
+
+    Ok(format!("reminder for event id: {} queued", event_id))
+}
+
+#[ic_cdk::query]
+fn transform_notification_response(args: TransformArgs) -> OutcallResponse {
+    // Only the status is used, headers and body differ between replicas
+    OutcallResponse {
+        status: args.response.status,
+        ..Default::default()
+    }
+}
+
+// Queue a notification for a user, users without an email can't be reached
+pub(crate) fn enqueue_notification(
+    kind: NotificationKind,
+    event_id: u64,
+    user_id: u64,
+    subject: String,
+    body: String,
+) {
+    let Some(user) = _get_user(&user_id) else {
+        return;
+    };
+    if user.email.is_empty() {
+        return;
+    }
+
+    // Increment the global ID counter to get a new ID for the notification
+    let id = ID_COUNTER
+        .with(|counter| {
+            let current_id = *counter.borrow().get();
+            counter.borrow_mut().set(current_id + 1)
+        })
+        .expect("Cannot increment Ids");
+
+    let notification = Notification {
+        id,
+        kind,
+        event_id,
+        user_id,
+        recipient: user.email,
+        subject,
+        body,
+        status: NotificationStatus::Pending,
+        attempts: 0,
+        next_attempt_at: time(),
+        last_error: None,
+        delivered_at: None,
+        created_at: time(),
+    };
+    OUTBOX.with(|outbox| outbox.borrow_mut().insert(id, notification));
+}
+
+// Send a notification to every attendee of an event, '{when}' in the body is replaced with when
+// the event starts as each of them prefers it written
+pub(crate) fn notify_event_attendees(
+    event: &Event,
+    kind: NotificationKind,
+    subject: String,
+    body: String,
+) {
+    broadcasts::broadcast(event, kind, subject, body, event.attendee_ids.clone());
+}
+
+// Send a reminder of the date and place of an event to every attendee
+pub(crate) fn remind_event_attendees(event: &Event) {
+    notify_event_attendees(
+        event,
+        NotificationKind::EventReminder,
+        format!("Reminder: {} is coming up", event.name),
+        format!(
+            "{} starts {}, {}.",
+            event.name,
+            broadcasts::WHEN,
+            geo::venue_label(event.venue.as_ref())
+        ),
+    );
+}
+
+// Tell every attendee the date, time or venue of an event changed
+pub(crate) fn notify_event_changed(event: &Event) {
+    notify_event_attendees(
+        event,
+        NotificationKind::EventChanged,
+        format!("{} has changed", event.name),
+        format!(
+            "{} now takes place {}, {}.",
+            event.name,
+            broadcasts::WHEN,
+            geo::venue_label(event.venue.as_ref())
+        ),
+    );
+}
+
+// Start the timer draining the outbox, timers don't survive upgrades
+pub(crate) fn schedule_delivery() {
+    let timer = ic_cdk_timers::set_timer_interval(DELIVERY_INTERVAL, || {
+        ic_cdk::spawn(deliver_notifications())
+    });
+    DELIVERY_TIMER.with(|delivery_timer| *delivery_timer.borrow_mut() = Some(timer));
+}
+
+// Whether the outbox is being drained
+pub(crate) fn delivery_scheduled() -> bool {
+    DELIVERY_TIMER.with(|delivery_timer| delivery_timer.borrow().is_some())
+}
+
+// Whether notifications have somewhere to go, they stay in the outbox until then
+pub(crate) fn webhook_configured() -> bool {
+    SETTINGS.with(|settings| settings.borrow().get().webhook_url.is_some())
+}
+
+// Helper function to get all notifications of an event still in the outbox
+fn _get_event_notifications(event_id: u64) -> Vec<Notification> {
+    OUTBOX.with(|outbox| {
+        outbox
+            .borrow()
+            .iter()
+            .map(|(_, notification)| notification)
+            .filter(|notification| notification.event_id == event_id)
+            .collect()
+    })
+}
+
+// Drop delivered notifications once their status no longer needs to be looked up
+fn prune_delivered() {
+    let now = time();
+    OUTBOX.with(|outbox| {
+        let mut outbox = outbox.borrow_mut();
+        let expired: Vec<u64> = outbox
+            .iter()
+            .filter(|(_, n)| {
+                n.delivered_at
+                    .is_some_and(|at| at + DELIVERED_RETENTION <= now)
+            })
+            .map(|(id, _)| id)
+            .collect();
+        for id in expired {
+            outbox.remove(&id);
+        }
+    });
+}
+
+// Send the notifications that are due to the webhook, rescheduling the ones that fail
+async fn deliver_notifications() {
+    if backup::in_maintenance() {
+        return;
+    }
+    prune_delivered();
+
+    let Some(url) = SETTINGS.with(|settings| settings.borrow().get().webhook_url.clone()) else {
+        return;
+    };
+    if DELIVERING.with(|delivering| delivering.replace(true)) {
+        return;
+    }
+
+    let now = time();
+    let due: Vec<Notification> = OUTBOX.with(|outbox| {
+        outbox
+            .borrow()
+            .iter()
+            .map(|(_, notification)| notification)
+            .filter(|n| n.status == NotificationStatus::Pending && n.next_attempt_at <= now)
+            .take(DELIVERY_BATCH)
+            .collect()
+    });
+
+    for mut notification in due {
+        match send_notification(&url, &notification).await {
+            Ok(()) => {
+                notification.status = NotificationStatus::Delivered;
+                notification.delivered_at = Some(time());
+            }
+            Err(error) => {
+                notification.attempts += 1;
+                notification.last_error = Some(error);
+                if notification.attempts >= MAX_ATTEMPTS {
+                    notification.status = NotificationStatus::Failed;
+                } else {
+                    let delay = RETRY_BASE_DELAY << (notification.attempts - 1);
+                    notification.next_attempt_at = time() + delay;
+                }
+            }
+        }
+        // Attempts and backoff live in stable memory, so retries carry on after an upgrade
+        OUTBOX.with(|outbox| outbox.borrow_mut().insert(notification.id, notification));
+    }
+
+    DELIVERING.with(|delivering| delivering.set(false));
+}
+
+// POST a notification to the webhook as JSON
+async fn send_notification(url: &str, notification: &Notification) -> Result<(), String> {
+    let request = CanisterHttpRequestArgument {
+        url: url.to_string(),
+        max_response_bytes: Some(OUTCALL_MAX_RESPONSE_BYTES),
+        method: HttpMethod::POST,
+        headers: vec![
+            HttpHeader {
+                name: "Content-Type".to_string(),
+                value: "application/json".to_string(),
+            },
+            // Every replica sends the request, the receiver dedupes on this key
+            HttpHeader {
+                name: "Idempotency-Key".to_string(),
+                value: notification.id.to_string(),
+            },
+        ],
+        body: Some(serde_json::to_vec(notification).unwrap()),
+        transform: Some(TransformContext::from_name(
+            "transform_notification_response".to_string(),
+            vec![],
+        )),
+    };
+
+    match http_request(request, OUTCALL_CYCLES).await {
+        Ok((response,)) if response.status >= 200u64 && response.status < 300u64 => Ok(()),
+        Ok((response,)) => Err(format!("webhook answered with status {}", response.status)),
+        Err((code, msg)) => Err(format!("outcall failed ({:?}): {}", code, msg)),
+    }
+}
@@ -0,0 +1,255 @@
+use crate::clock::time;
+use crate::ratelimit::rate_limit;
+use crate::{_get_event, _get_ticket, caller_is_admin, Error, Memory, MEMORY_MANAGER};
+use candid::{Decode, Encode, Principal};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use std::{borrow::Cow, cell::RefCell};
+
+// Bounds keeping the gates of an event within a single stable map entry
+const MAX_GATE_DEVICES: usize = 32;
+pub(crate) const MAX_ZONE_LEN: usize = 32;
+const MAX_ALERTS: usize = 20;
+
+// Device and scan counts may drift this far apart, in percent of the scans, before raising an alert
+const DISCREPANCY_TOLERANCE_PERCENT: u64 = 10;
+// Small crowds always get this much slack, so a handful of people don't trigger alerts
+const DISCREPANCY_TOLERANCE_MIN: u64 = 5;
+
+// Define a struct for a turnstile or clicker counting people in a zone of an event
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct GateDevice {
+    // Devices report with their own identity
+    device: Principal,
+    zone: String,
+    registered_at: u64,
+}
+
+// Define a struct for the last occupancy reported for a zone
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct ZoneCount {
+    zone: String,
+    count: u64,
+    device: Principal,
+    reported_at: u64,
+}
+
+// Define a struct for a mismatch between the device counts and the scanned tickets
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct OccupancyAlert {
+    device_count: u64,
+    scanned_count: u64,
+    raised_at: u64,
+}
+
+// Define a struct for the gates of an event along with their counts
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct EventGates {
+    devices: Vec<GateDevice>,
+    zone_counts: Vec<ZoneCount>,
+    // Most recent last, only the latest 'MAX_ALERTS' are kept
+    alerts: Vec<OccupancyAlert>,
+    // Set while the counts disagree, so a lasting mismatch raises a single alert
+    discrepancy: bool,
+}
+
+// Define a struct for the live occupancy of an event
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub struct EventOccupancy {
+    event_id: u64,
+    // Tickets checked in at the entrance
+    scanned_count: u64,
+    // Sum of the latest count of every zone
+    device_count: u64,
+    zones: Vec<ZoneCount>,
+    discrepancy: bool,
+    alerts: Vec<OccupancyAlert>,
+}
+
+impl Storable for EventGates {
+    // Conversion to bytes
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    // Conversion from bytes
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for EventGates {
+    const MAX_SIZE: u32 = 16384;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    // Gates keyed by event id
+    static GATE_STORAGE: RefCell<StableBTreeMap<u64, EventGates, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(25)))
+    ));
+}
+
+// Helper function to get the gates of an event
+fn _get_gates(event_id: u64) -> EventGates {
+    GATE_STORAGE
+        .with(|gates| gates.borrow().get(&event_id))
+        .unwrap_or_default()
+}
+
+#[ic_cdk::query(guard = "caller_is_admin")]
+fn get_gate_devices(event_id: u64) -> Vec<GateDevice> {
+    _get_gates(event_id).devices
+}
+
+#[ic_cdk::update(guard = "caller_is_admin")]
+fn register_gate_device(
+    event_id: u64,
+    device: Principal,
+    zone: String,
+) -> Result<GateDevice, Error> {
+    // Make sure the event exists, or return a NotFound error if not found
+    _get_event(&event_id).ok_or(Error::not_found("event", event_id))?;
+
+    if device == Principal::anonymous() {
+        return Err(Error::ValidationFailed {
+            msg: "gate devices need their own identity".to_string(),
+        });
+    }
+    let zone = zone.trim().to_string();
+    if zone.is_empty() || zone.len() > MAX_ZONE_LEN {
+        return Err(Error::ValidationFailed {
+            msg: format!("zone must be between 1 and {} bytes", MAX_ZONE_LEN),
+        });
+    }
+
+    // Registering a known device again moves it to the new zone
+    let mut gates = _get_gates(event_id);
+    gates.devices.retain(|gate| gate.device != device);
+    if gates.devices.len() >= MAX_GATE_DEVICES {
+        return Err(Error::ValidationFailed {
+            msg: format!(
+                "event id:{} can have at most {} gate devices",
+                event_id, MAX_GATE_DEVICES
+            ),
+        });
+    }
+    let gate = GateDevice {
+        device,
+        zone,
+        registered_at: time(),
+    };
+    gates.devices.push(gate.clone());
+    GATE_STORAGE.with(|storage| storage.borrow_mut().insert(event_id, gates));
+
+    Ok(gate)
+}
+
+#[ic_cdk::update(guard = "caller_is_admin")]
+fn remove_gate_device(event_id: u64, device: Principal) -> Result<String, Error> {
+    let mut gates = _get_gates(event_id);
+    let count = gates.devices.len();
+    gates.devices.retain(|gate| gate.device != device);
+    if gates.devices.len() == count {
+        return Err(Error::NotFound {
+            entity: "device".to_string(),
+            id: device.to_string(),
+            msg: format!("device {} is not a gate of event id:{}", device, event_id),
+        });
+    }
+
+    GATE_STORAGE.with(|storage| storage.borrow_mut().insert(event_id, gates));
+
+    Ok(format!(
+        "device {} removed from the gates of event id: {}",
+        device, event_id
+    ))
+}
+
+#[ic_cdk::update(guard = "rate_limit")]
+fn report_gate_count(event_id: u64, count: u64) -> Result<EventOccupancy, Error> {
+    // Only registered devices can report, each for the zone it was registered in
+    let caller = ic_cdk::caller();
+    let mut gates = _get_gates(event_id);
+    let gate = gates
+        .devices
+        .iter()
+        .find(|gate| gate.device == caller)
+        .cloned()
+        .ok_or(Error::Unauthorized {
+            msg: format!(
+                "caller {} is not a gate device of event id:{}",
+                caller, event_id
+            ),
+        })?;
+
+    // The latest report of a zone replaces the previous one, whichever device sent it
+    gates.zone_counts.retain(|zone| zone.zone != gate.zone);
+    gates.zone_counts.push(ZoneCount {
+        zone: gate.zone,
+        count,
+        device: caller,
+        reported_at: time(),
+    });
+
+    let scanned_count = scanned_count(event_id);
+    let device_count = gates.zone_counts.iter().map(|zone| zone.count).sum();
+    let discrepancy = is_discrepancy(device_count, scanned_count);
+    if discrepancy && !gates.discrepancy {
+        gates.alerts.push(OccupancyAlert {
+            device_count,
+            scanned_count,
+            raised_at: time(),
+        });
+        if gates.alerts.len() > MAX_ALERTS {
+            gates.alerts.remove(0);
+        }
+    }
+    gates.discrepancy = discrepancy;
+    GATE_STORAGE.with(|storage| storage.borrow_mut().insert(event_id, gates));
+
+    get_event_occupancy(event_id)
+}
+
+#[ic_cdk::query]
+fn get_event_occupancy(event_id: u64) -> Result<EventOccupancy, Error> {
+    // Make sure the event exists, or return a NotFound error if not found
+    _get_event(&event_id).ok_or(Error::not_found("event", event_id))?;
+
+    let gates = _get_gates(event_id);
+    let scanned_count = scanned_count(event_id);
+    let device_count = gates.zone_counts.iter().map(|zone| zone.count).sum();
+
+    Ok(EventOccupancy {
+        event_id,
+        scanned_count,
+        device_count,
+        zones: gates.zone_counts,
+        discrepancy: gates.discrepancy,
+        alerts: gates.alerts,
+    })
+}
+
+// Number of tickets of an event checked in at the entrance
+fn scanned_count(event_id: u64) -> u64 {
+    _get_event(&event_id).map_or(0, |event| {
+        event
+            .ticket_ids
+            .iter()
+            .filter_map(_get_ticket)
+            .filter(|ticket| ticket.checked_in_at.is_some())
+            .count() as u64
+    })
+}
+
+// Whether device and scan counts disagree by more than the tolerance
+fn is_discrepancy(device_count: u64, scanned_count: u64) -> bool {
+    let tolerance =
+        (scanned_count * DISCREPANCY_TOLERANCE_PERCENT / 100).max(DISCREPANCY_TOLERANCE_MIN);
+    device_count.abs_diff(scanned_count) > tolerance
+}
+
+// Drop the gates of a deleted event
+pub(crate) fn remove_event_gates(event_id: u64) {
+    GATE_STORAGE.with(|gates| gates.borrow_mut().remove(&event_id));
+}
@@ -0,0 +1,365 @@
+use crate::bulk::{self, BulkItemError};
+use crate::clock::time;
+use crate::notifications::{self, NotificationKind};
+use crate::ratelimit::rate_limit;
+use crate::{
+    _get_event, _get_ticket, addons, donations, flash_sales, health, idempotency, organizers,
+    payments, sell_ticket, ticket_summary, validation, Error, Memory, Ticket, TicketPayload,
+    ID_COUNTER, MEMORY_MANAGER,
+};
+use candid::{Decode, Encode};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use std::ops::Bound;
+use std::{borrow::Cow, cell::RefCell};
+
+// Tickets in one order, so the order and its confirmation fit in a stable map entry
+const MAX_ORDER_ITEMS: usize = 10;
+// Rows per page of the order export
+const EXPORT_PAGE_SIZE: usize = 500;
+// Orders looked at per page of the order export, so a page stays within the instruction limit
+// however few of them belong to the organizer
+const EXPORT_SCAN_LIMIT: usize = 10_000;
+
+// Define a struct for the part of an order that goes to one organizer
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct OrderSplit {
+    // First organizer of the events, None for events run by the platform
+    organizer_id: Option<u64>,
+    ticket_ids: Vec<u64>,
+    // Ticket prices after promo codes
+    tickets: u64,
+    donations: u64,
+    // Withheld from donations
+    fees: u64,
+    // What the organizer is owed, tickets, add-ons and donations less fees
+    payout: u64,
+    // Add-ons bought with the tickets, None on orders placed before add-ons
+    addons: Option<u64>,
+}
+
+// Define a struct for a checkout of tickets, possibly for events of different organizers
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct Order {
+    id: u64,
+    user_id: u64,
+    ticket_ids: Vec<u64>,
+    splits: Vec<OrderSplit>,
+    // Refund insurance bought along with the tickets, kept by the platform
+    insurance: u64,
+    // What the buyer pays for the whole order
+    total: u64,
+    created_at: u64,
+}
+
+// Define a struct for an organizer's part of an order, as fed to accounting systems
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub struct OrderRow {
+    order_id: u64,
+    user_id: u64,
+    created_at: u64,
+    ticket_ids: Vec<u64>,
+    tickets: u64,
+    addons: u64,
+    donations: u64,
+    fees: u64,
+    payout: u64,
+}
+
+// Define a struct for a page of the order export
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub struct OrderExportPage {
+    rows: Vec<OrderRow>,
+    // Passed back to get the next page, None once every order has been looked at
+    next_cursor: Option<u64>,
+}
+
+impl Storable for Order {
+    // Conversion to bytes
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    // Conversion from bytes
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for Order {
+    const MAX_SIZE: u32 = 4096;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    // Orders keyed by order id, kept as the buyer's record when tickets or events go
+    static ORDER_STORAGE: RefCell<StableBTreeMap<u64, Order, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(57)))
+    ));
+}
+
+#[ic_cdk::update(guard = "rate_limit")]
+async fn checkout(
+    payloads: Vec<TicketPayload>,
+    idempotency_key: Option<String>,
+) -> Result<Order, Vec<BulkItemError>> {
+    let single_error = |msg| vec![BulkItemError { index: 0, msg }];
+
+    // A retried checkout gets the order placed the first time
+    if let Some(order) =
+        idempotency::begin(&idempotency_key, "checkout", &payloads).map_err(single_error)?
+    {
+        return Ok(order);
+    }
+
+    let result = async {
+        health::check_sales_open().map_err(single_error)?;
+        bulk::check_batch_size(payloads.len(), MAX_ORDER_ITEMS)?;
+        check_single_buyer(&payloads)?;
+        bulk::check_ticket_batch(&payloads)?;
+
+        // The hook approves or rejects the order as a whole
+        validation::validate_tickets(&payloads)
+            .await
+            .map_err(single_error)?;
+        // Sales may have gone on while the hook was consulted
+        bulk::check_ticket_batch(&payloads)?;
+
+        // Flash sale places are held while the payment is taken, so they can't be sold twice
+        let holds = flash_sales::hold(&payloads).map_err(single_error)?;
+        let result = async {
+            let charge = payments::charge_for(&payloads).map_err(single_error)?;
+            let payment = payments::collect(&charge).await.map_err(single_error)?;
+            // Checked again once paid, an order that can no longer be placed is refunded
+            let checked = bulk::check_ticket_batch(&payloads)
+                .and_then(|_| payments::check_charge(&charge, &payloads).map_err(single_error));
+            if let Err(errors) = checked {
+                payments::settle(payment, vec![]).await;
+                return Err(errors);
+            }
+
+            let order = place_order(payloads);
+            payments::settle(payment, order.ticket_ids.clone()).await;
+            Ok(order)
+        }
+        .await;
+        flash_sales::release(holds);
+        result
+    }
+    .await;
+    idempotency::finish(&idempotency_key, &result);
+    result
+}
+
+#[ic_cdk::query]
+fn get_order(id: u64) -> Option<Order> {
+    ORDER_STORAGE.with(|orders| orders.borrow().get(&id))
+}
+
+pub(crate) fn order_ticket_ids(id: u64) -> Option<Vec<u64>> {
+    get_order(id).map(|order| order.ticket_ids)
+}
+
+#[ic_cdk::query]
+pub(crate) fn get_user_orders(user_id: u64) -> Vec<Order> {
+    ORDER_STORAGE.with(|orders| {
+        orders
+            .borrow()
+            .iter()
+            .map(|(_, order)| order)
+            .filter(|order| order.user_id == user_id)
+            .collect()
+    })
+}
+
+// The organizer's part of each order placed from 'from' up to 'to', oldest first. A page may
+// have fewer rows than fit while more are left, the export is done once 'next_cursor' is None.
+#[ic_cdk::query]
+fn export_orders(
+    organizer_id: u64,
+    from: u64,
+    to: u64,
+    cursor: Option<u64>,
+) -> Result<OrderExportPage, Error> {
+    organizers::check_organizer(organizer_id)?;
+    if from >= to {
+        return Err(Error::ValidationFailed {
+            msg: "'from' must be before 'to'".to_string(),
+        });
+    }
+
+    let start = cursor.map_or(Bound::Unbounded, Bound::Excluded);
+    let mut rows = vec![];
+    let mut next_cursor = None;
+    ORDER_STORAGE.with(|orders| {
+        for (scanned, (id, order)) in orders.borrow().range((start, Bound::Unbounded)).enumerate() {
+            if scanned == EXPORT_SCAN_LIMIT || rows.len() == EXPORT_PAGE_SIZE {
+                break;
+            }
+            next_cursor = Some(id);
+            if order.created_at < from || order.created_at >= to {
+                continue;
+            }
+            let split = order
+                .splits
+                .into_iter()
+                .find(|split| split.organizer_id == Some(organizer_id));
+            if let Some(split) = split {
+                rows.push(OrderRow {
+                    order_id: order.id,
+                    user_id: order.user_id,
+                    created_at: order.created_at,
+                    ticket_ids: split.ticket_ids,
+                    tickets: split.tickets,
+                    addons: split.addons.unwrap_or(0),
+                    donations: split.donations,
+                    fees: split.fees,
+                    payout: split.payout,
+                });
+            }
+        }
+    });
+    // Past the last order there is nothing left to page through
+    let last_id = ORDER_STORAGE.with(|orders| orders.borrow().last_key_value().map(|(id, _)| id));
+    if next_cursor == last_id {
+        next_cursor = None;
+    }
+    Ok(OrderExportPage { rows, next_cursor })
+}
+
+// Remove the orders from id 'from' on whose tickets are all of the event, looking at up to 'limit'
+// of them. Returns how many were removed and the id to carry on from, None once past the last.
+pub(crate) fn remove_event_orders(event_id: u64, from: u64, limit: u64) -> (u64, Option<u64>) {
+    let batch: Vec<Order> = ORDER_STORAGE.with(|orders| {
+        orders
+            .borrow()
+            .range(from..)
+            .take(limit as usize + 1)
+            .map(|(_, order)| order)
+            .collect()
+    });
+    let mut removed = 0;
+    for order in batch.iter().take(limit as usize) {
+        let of_event = order
+            .ticket_ids
+            .iter()
+            .all(|ticket_id| _get_ticket(ticket_id).is_some_and(|t| t.event_id == event_id));
+        if of_event {
+            ORDER_STORAGE.with(|orders| orders.borrow_mut().remove(&order.id));
+            removed += 1;
+        }
+    }
+    (removed, batch.get(limit as usize).map(|order| order.id))
+}
+
+// An order is placed for one buyer, who gets a single confirmation for it
+fn check_single_buyer(payloads: &[TicketPayload]) -> Result<(), Vec<BulkItemError>> {
+    let user_id = payloads[0].user_id;
+    let errors: Vec<BulkItemError> = payloads
+        .iter()
+        .enumerate()
+        .filter(|(_, payload)| payload.user_id != user_id)
+        .map(|(index, _)| BulkItemError {
+            index: index as u64,
+            msg: format!("an order can only hold tickets for user id:{}", user_id),
+        })
+        .collect();
+    match errors.is_empty() {
+        true => Ok(()),
+        false => Err(errors),
+    }
+}
+
+// Sell every ticket of a checked order and split it per organizer. A failure here traps,
+// which rolls back the tickets already sold.
+fn place_order(payloads: Vec<TicketPayload>) -> Order {
+    let user_id = payloads[0].user_id;
+    let mut tickets: Vec<(Ticket, u64)> = vec![];
+    for (index, payload) in payloads.into_iter().enumerate() {
+        let donation = payload.donation.unwrap_or(0);
+        let ticket =
+            sell_ticket(payload).unwrap_or_else(|error| bulk::abort(index, bulk::error_msg(error)));
+        tickets.push((ticket, donation));
+    }
+
+    let mut splits: Vec<OrderSplit> = vec![];
+    let mut insurance = 0;
+    for (ticket, donation) in &tickets {
+        let organizer_id = _get_event(&ticket.event_id)
+            .and_then(|event| event.organizer_ids)
+            .and_then(|organizer_ids| organizer_ids.first().copied());
+        let index = match splits
+            .iter()
+            .position(|split| split.organizer_id == organizer_id)
+        {
+            Some(index) => index,
+            None => {
+                splits.push(OrderSplit {
+                    organizer_id,
+                    ticket_ids: vec![],
+                    tickets: 0,
+                    donations: 0,
+                    fees: 0,
+                    payout: 0,
+                    addons: Some(0),
+                });
+                splits.len() - 1
+            }
+        };
+        let split = &mut splits[index];
+        let fee = donations::donation_fee(ticket.event_id, *donation);
+        let addons = addons::ticket_addons_total(ticket);
+        split.ticket_ids.push(ticket.id);
+        split.tickets += ticket.price;
+        split.addons = Some(split.addons.unwrap_or(0) + addons);
+        split.donations += donation;
+        split.fees += fee;
+        split.payout += ticket.price + addons + donation - fee;
+        insurance += ticket
+            .insurance
+            .as_ref()
+            .map_or(0, |insurance| insurance.fee);
+    }
+
+    // Increment the global ID counter to get a new ID for the order
+    let id = ID_COUNTER
+        .with(|counter| {
+            let current_id = *counter.borrow().get();
+            counter.borrow_mut().set(current_id + 1)
+        })
+        .expect("Cannot increment Ids");
+    let order = Order {
+        id,
+        user_id,
+        ticket_ids: tickets.iter().map(|(ticket, _)| ticket.id).collect(),
+        total: splits
+            .iter()
+            .map(|split| split.tickets + split.addons.unwrap_or(0) + split.donations)
+            .sum::<u64>()
+            + insurance,
+        splits,
+        insurance,
+        created_at: time(),
+    };
+    ORDER_STORAGE.with(|orders| orders.borrow_mut().insert(id, order.clone()));
+
+    confirm_order(&order, &tickets);
+    order
+}
+
+// Send the buyer one confirmation listing every ticket of the order
+fn confirm_order(order: &Order, tickets: &[(Ticket, u64)]) {
+    let lines: Vec<String> = tickets
+        .iter()
+        .filter_map(|(ticket, _)| {
+            _get_event(&ticket.event_id).map(|event| ticket_summary(ticket, &event))
+        })
+        .collect();
+    notifications::enqueue_notification(
+        NotificationKind::OrderConfirmed,
+        tickets[0].0.event_id,
+        order.user_id,
+        format!("Your order {}", order.id),
+        format!("{}\nTotal paid: {}.", lines.join("\n"), order.total),
+    );
+}
@@ -0,0 +1,258 @@
+use crate::clock::time;
+use crate::discovery::{self, EventPage};
+use crate::ratelimit::rate_limit;
+use crate::{
+    _get_event, _get_listed_event, certification, store_event, Error, Event, Memory, StringKey,
+    ID_COUNTER, MEMORY_MANAGER,
+};
+use candid::{Decode, Encode, Principal};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use std::{borrow::Cow, cell::RefCell};
+
+const MAX_NAME_LEN: usize = 64;
+// Organizers are stored within the size bound of an event
+const MAX_EVENT_ORGANIZERS: usize = 5;
+
+// Define a struct for an 'Organizer', who runs events rather than attends them
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct Organizer {
+    id: u64,
+    name: String,
+    principal: Principal,
+    created_at: u64,
+}
+
+impl Storable for Organizer {
+    // Conversion to bytes
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    // Conversion from bytes
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for Organizer {
+    const MAX_SIZE: u32 = 256;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    static ORGANIZER_STORAGE: RefCell<StableBTreeMap<u64, Organizer, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(43)))
+    ));
+
+    // Organizer id of each principal, keyed by the principal's text form
+    static ORGANIZER_PRINCIPALS: RefCell<StableBTreeMap<StringKey, u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(44)))
+    ));
+
+    // Events keyed by (organizer id, event id)
+    static ORGANIZER_INDEX: RefCell<StableBTreeMap<(u64, u64), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(45)))
+    ));
+}
+
+#[ic_cdk::update(guard = "rate_limit")]
+fn register_organizer(name: String) -> Result<Organizer, Error> {
+    let principal = ic_cdk::caller();
+    if let Some(id) = caller_organizer_id() {
+        return Err(Error::ValidationFailed {
+            msg: format!("caller is already organizer id:{}", id),
+        });
+    }
+    if name.trim().is_empty() || name.len() > MAX_NAME_LEN {
+        return Err(Error::ValidationFailed {
+            msg: format!("organizer name must be 1 to {} bytes", MAX_NAME_LEN),
+        });
+    }
+
+    // Increment the global ID counter to get a new ID for the organizer
+    let id = ID_COUNTER
+        .with(|counter| {
+            let current_id = *counter.borrow().get();
+            counter.borrow_mut().set(current_id + 1)
+        })
+        .expect("Cannot increment Ids");
+    let organizer = Organizer {
+        id,
+        name,
+        principal,
+        created_at: time(),
+    };
+    ORGANIZER_STORAGE.with(|organizers| organizers.borrow_mut().insert(id, organizer.clone()));
+    ORGANIZER_PRINCIPALS.with(|principals| {
+        principals
+            .borrow_mut()
+            .insert(StringKey(principal.to_text()), id)
+    });
+
+    Ok(organizer)
+}
+
+#[ic_cdk::query]
+fn get_organizer(id: u64) -> Result<Organizer, Error> {
+    _get_organizer(id).ok_or(Error::not_found("organizer", id))
+}
+
+#[ic_cdk::update(guard = "rate_limit")]
+fn add_event_organizer(event_id: u64, organizer_id: u64) -> Result<Event, Error> {
+    let mut event = authorize_event(event_id)?;
+    get_organizer(organizer_id)?;
+
+    let mut organizer_ids = event.organizer_ids.clone().unwrap_or_default();
+    if organizer_ids.contains(&organizer_id) {
+        return Err(Error::ValidationFailed {
+            msg: format!(
+                "organizer id:{} already organizes event id:{}",
+                organizer_id, event_id
+            ),
+        });
+    }
+    if organizer_ids.len() >= MAX_EVENT_ORGANIZERS {
+        return Err(Error::ValidationFailed {
+            msg: format!(
+                "an event can have at most {} organizers",
+                MAX_EVENT_ORGANIZERS
+            ),
+        });
+    }
+
+    organizer_ids.push(organizer_id);
+    event.organizer_ids = Some(organizer_ids);
+    event.updated_at = Some(time());
+    store_event(&mut event);
+    certification::certify_event(event_id);
+    ORGANIZER_INDEX.with(|index| index.borrow_mut().insert((organizer_id, event_id), ()));
+
+    Ok(event)
+}
+
+#[ic_cdk::update(guard = "rate_limit")]
+fn remove_event_organizer(event_id: u64, organizer_id: u64) -> Result<Event, Error> {
+    let mut event = authorize_event(event_id)?;
+
+    let mut organizer_ids = event.organizer_ids.clone().unwrap_or_default();
+    if !organizer_ids.contains(&organizer_id) {
+        return Err(Error::NotFound {
+            entity: "event organizer".to_string(),
+            id: organizer_id.to_string(),
+            msg: format!(
+                "organizer id:{} does not organize event id:{}",
+                organizer_id, event_id
+            ),
+        });
+    }
+    // Someone has to be left to run the event
+    if organizer_ids.len() == 1 {
+        return Err(Error::ValidationFailed {
+            msg: format!("organizer id:{} is the last organizer", organizer_id),
+        });
+    }
+
+    organizer_ids.retain(|&id| id != organizer_id);
+    event.organizer_ids = Some(organizer_ids);
+    event.updated_at = Some(time());
+    store_event(&mut event);
+    certification::certify_event(event_id);
+    ORGANIZER_INDEX.with(|index| index.borrow_mut().remove(&(organizer_id, event_id)));
+
+    Ok(event)
+}
+
+#[ic_cdk::query]
+fn get_events_by_organizer(organizer_id: u64, page: u64) -> EventPage {
+    let event_ids = organizer_event_ids(organizer_id);
+    discovery::page_of(
+        event_ids.iter().filter_map(_get_listed_event).collect(),
+        page,
+    )
+}
+
+pub(crate) fn _get_organizer(id: u64) -> Option<Organizer> {
+    ORGANIZER_STORAGE.with(|organizers| organizers.borrow().get(&id))
+}
+
+// Organizer id of the caller, if they registered as one
+pub(crate) fn caller_organizer_id() -> Option<u64> {
+    let principal = StringKey(ic_cdk::caller().to_text());
+    ORGANIZER_PRINCIPALS.with(|principals| principals.borrow().get(&principal))
+}
+
+// Check the caller is the given organizer, or an admin
+pub(crate) fn check_organizer(organizer_id: u64) -> Result<(), Error> {
+    if is_trusted_caller() || caller_organizer_id() == Some(organizer_id) {
+        return Ok(());
+    }
+    Err(Error::Unauthorized {
+        msg: format!("caller is not organizer id:{}", organizer_id),
+    })
+}
+
+// Events can be created by organizers and admins only
+pub(crate) fn check_can_create() -> Result<(), Error> {
+    if is_trusted_caller() || caller_organizer_id().is_some() {
+        return Ok(());
+    }
+    Err(Error::Unauthorized {
+        msg: "only organizers can create events, call 'register_organizer' first".to_string(),
+    })
+}
+
+// Check the caller may change an event: one of its organizers, an admin or the canister's
+// own timers. Events from before organizers existed are left to admins.
+pub(crate) fn check_event_organizer(event: &Event) -> Result<(), Error> {
+    let listed = caller_organizer_id().is_some_and(|id| {
+        event
+            .organizer_ids
+            .as_ref()
+            .is_some_and(|organizer_ids| organizer_ids.contains(&id))
+    });
+    if is_trusted_caller() || listed {
+        return Ok(());
+    }
+    Err(Error::Unauthorized {
+        msg: format!("caller does not organize event id:{}", event.id),
+    })
+}
+
+// Retrieve an event the caller may change, for endpoints managing what belongs to it
+pub(crate) fn authorize_event(event_id: u64) -> Result<Event, Error> {
+    let event = _get_event(&event_id).ok_or(Error::not_found("event", event_id))?;
+    check_event_organizer(&event)?;
+    Ok(event)
+}
+
+pub(crate) fn organizer_event_ids(organizer_id: u64) -> Vec<u64> {
+    ORGANIZER_INDEX.with(|index| {
+        index
+            .borrow()
+            .range((organizer_id, 0)..=(organizer_id, u64::MAX))
+            .map(|((_, event_id), _)| event_id)
+            .collect()
+    })
+}
+
+// Add an event to the index under each of its organizers
+pub(crate) fn index_event(event: &Event) {
+    for organizer_id in event.organizer_ids.iter().flatten() {
+        ORGANIZER_INDEX.with(|index| index.borrow_mut().insert((*organizer_id, event.id), ()));
+    }
+}
+
+// Remove a purged event from the index
+pub(crate) fn unindex_event(event: &Event) {
+    for organizer_id in event.organizer_ids.iter().flatten() {
+        ORGANIZER_INDEX.with(|index| index.borrow_mut().remove(&(*organizer_id, event.id)));
+    }
+}
+
+fn is_trusted_caller() -> bool {
+    let caller = ic_cdk::caller();
+    caller == ic_cdk::id() || ic_cdk::api::is_controller(&caller)
+}
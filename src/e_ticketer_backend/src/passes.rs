@@ -0,0 +1,313 @@
+use crate::auth::{self, ApiScope};
+use crate::clock::time;
+use crate::ratelimit::rate_limit;
+use crate::{
+    _check_in_ticket, _get_event, _get_ticket, _get_user, issue_ticket, organizers, privacy,
+    remaining_capacity, series, Error, Event, Memory, Ticket, ID_COUNTER, MEMORY_MANAGER,
+};
+use candid::{Decode, Encode};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use std::{borrow::Cow, cell::RefCell};
+
+// Events a pass can be limited to, so the pass fits in a stable map entry
+const MAX_SELECTED_EVENTS: usize = 100;
+
+// Define an enum for the events a pass gives access to
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, PartialEq)]
+pub enum PassScope {
+    // Every event of a series
+    Series(u64),
+    // Every event an organizer runs
+    Organizer(u64),
+}
+
+// Define a struct for the payload used to issue a pass
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub struct PassPayload {
+    user_id: u64,
+    scope: PassScope,
+    // Limit the pass to these events of its scope, None for all of them
+    event_ids: Option<Vec<u64>>,
+    expires_at: Option<u64>,
+}
+
+// Define a struct for a season ticket, the holder gets a ticket of each event it covers when
+// claiming it or at the entrance
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct Pass {
+    id: u64,
+    user_id: u64,
+    scope: PassScope,
+    event_ids: Option<Vec<u64>>,
+    expires_at: Option<u64>,
+    created_at: u64,
+}
+
+// Define a struct for an event a pass covers and the ticket it got for it
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub struct PassEventUsage {
+    event_id: u64,
+    event_name: String,
+    ticket_id: Option<u64>,
+    checked_in_at: Option<u64>,
+}
+
+// Define a struct for a pass along with the events it covers
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub struct PassUsage {
+    pass: Pass,
+    events: Vec<PassEventUsage>,
+}
+
+impl Storable for Pass {
+    // Conversion to bytes
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    // Conversion from bytes
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for Pass {
+    const MAX_SIZE: u32 = 2048;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    static PASS_STORAGE: RefCell<StableBTreeMap<u64, Pass, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(114)))
+    ));
+
+    // Tickets minted from passes, keyed by (pass id, event id)
+    static PASS_TICKETS: RefCell<StableBTreeMap<(u64, u64), u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(115)))
+    ));
+}
+
+// Issue a pass to a user. No payment is taken, the organizer settles with the holder, and the
+// tickets minted from the pass are free.
+#[ic_cdk::update(guard = "rate_limit")]
+fn issue_pass(payload: PassPayload) -> Result<Pass, Error> {
+    authorize_scope(&payload.scope)?;
+    _get_user(&payload.user_id).ok_or(Error::not_found("user", payload.user_id))?;
+    if payload.expires_at.is_some_and(|at| at <= time()) {
+        return Err(Error::ValidationFailed {
+            msg: "pass must expire in the future".to_string(),
+        });
+    }
+    if let Some(event_ids) = &payload.event_ids {
+        if event_ids.is_empty() || event_ids.len() > MAX_SELECTED_EVENTS {
+            return Err(Error::ValidationFailed {
+                msg: format!(
+                    "a pass can be limited to 1 to {} events",
+                    MAX_SELECTED_EVENTS
+                ),
+            });
+        }
+        let scope_event_ids = scope_event_ids(&payload.scope);
+        if let Some(event_id) = event_ids.iter().find(|id| !scope_event_ids.contains(id)) {
+            return Err(Error::ValidationFailed {
+                msg: format!("event id:{} is not in the scope of the pass", event_id),
+            });
+        }
+    }
+
+    // Increment the global ID counter to get a new ID for the pass
+    let id = ID_COUNTER
+        .with(|counter| {
+            let current_id = *counter.borrow().get();
+            counter.borrow_mut().set(current_id + 1)
+        })
+        .expect("Cannot increment Ids");
+    let pass = Pass {
+        id,
+        user_id: payload.user_id,
+        scope: payload.scope,
+        event_ids: payload.event_ids,
+        expires_at: payload.expires_at,
+        created_at: time(),
+    };
+    PASS_STORAGE.with(|passes| passes.borrow_mut().insert(id, pass.clone()));
+    Ok(pass)
+}
+
+// The events a pass covers and the tickets it got for them, for its holder and organizers
+#[ic_cdk::query]
+fn get_pass_usage(pass_id: u64) -> Result<PassUsage, Error> {
+    let pass = _get_pass(pass_id)?;
+    let is_holder = privacy::caller_user().is_ok_and(|user| user.id == pass.user_id);
+    if !is_holder {
+        authorize_scope(&pass.scope)?;
+    }
+
+    let events = pass_event_ids(&pass)
+        .iter()
+        .filter_map(_get_event)
+        .map(|event| {
+            let ticket = pass_ticket(pass.id, event.id);
+            PassEventUsage {
+                event_id: event.id,
+                event_name: event.name,
+                ticket_id: ticket.as_ref().map(|ticket| ticket.id),
+                checked_in_at: ticket.and_then(|ticket| ticket.checked_in_at),
+            }
+        })
+        .collect();
+    Ok(PassUsage { pass, events })
+}
+
+// Claim the ticket of an event the caller's pass covers, ahead of the event
+#[ic_cdk::update(guard = "rate_limit")]
+fn claim_pass_ticket(pass_id: u64, event_id: u64) -> Result<Ticket, Error> {
+    let user = privacy::caller_user()?;
+    let pass = _get_pass(pass_id)?;
+    if pass.user_id != user.id {
+        return Err(Error::Unauthorized {
+            msg: format!("pass id:{} is not held by the caller", pass_id),
+        });
+    }
+    mint_ticket(&pass, event_id)
+}
+
+// Check the holder of a pass in at an event, minting their ticket first if they never claimed it
+#[ic_cdk::update(guard = "rate_limit")]
+fn check_in_pass(
+    pass_id: u64,
+    event_id: u64,
+    api_key: Option<String>,
+    zone: Option<String>,
+) -> Result<Ticket, Error> {
+    auth::authorize(api_key, ApiScope::CheckIn)?;
+    let pass = _get_pass(pass_id)?;
+    let ticket = mint_ticket(&pass, event_id)?;
+    _check_in_ticket(ticket.id, zone).map(|(ticket, _)| ticket)
+}
+
+fn _get_pass(pass_id: u64) -> Result<Pass, Error> {
+    PASS_STORAGE
+        .with(|passes| passes.borrow().get(&pass_id))
+        .ok_or(Error::not_found("pass", pass_id))
+}
+
+// Check the caller may issue passes of a scope, organizing every event of a series will do
+fn authorize_scope(scope: &PassScope) -> Result<(), Error> {
+    match scope {
+        PassScope::Organizer(organizer_id) => organizers::check_organizer(*organizer_id),
+        PassScope::Series(series_id) => {
+            let event_ids = series::series_event_ids(*series_id)
+                .ok_or(Error::not_found("series", series_id))?;
+            for event in event_ids.iter().filter_map(_get_event) {
+                organizers::check_event_organizer(&event)?;
+            }
+            Ok(())
+        }
+    }
+}
+
+fn scope_event_ids(scope: &PassScope) -> Vec<u64> {
+    match scope {
+        PassScope::Series(series_id) => series::series_event_ids(*series_id).unwrap_or_default(),
+        PassScope::Organizer(organizer_id) => organizers::organizer_event_ids(*organizer_id),
+    }
+}
+
+// Events a pass covers now, events added to its series or organizer later are included
+fn pass_event_ids(pass: &Pass) -> Vec<u64> {
+    let mut event_ids = scope_event_ids(&pass.scope);
+    if let Some(selected) = &pass.event_ids {
+        event_ids.retain(|id| selected.contains(id));
+    }
+    event_ids
+}
+
+fn in_scope(pass: &Pass, event: &Event) -> bool {
+    let in_scope = match pass.scope {
+        PassScope::Series(series_id) => event.series_id == Some(series_id),
+        PassScope::Organizer(organizer_id) => event
+            .organizer_ids
+            .as_ref()
+            .is_some_and(|organizer_ids| organizer_ids.contains(&organizer_id)),
+    };
+    let selected = pass
+        .event_ids
+        .as_ref()
+        .is_none_or(|event_ids| event_ids.contains(&event.id));
+    in_scope && selected
+}
+
+// Ticket minted from a pass for an event, if it is still around
+fn pass_ticket(pass_id: u64, event_id: u64) -> Option<Ticket> {
+    PASS_TICKETS
+        .with(|tickets| tickets.borrow().get(&(pass_id, event_id)))
+        .and_then(|ticket_id| _get_ticket(&ticket_id))
+}
+
+// The ticket of an event a pass covers, minted the first time it is asked for. A minted ticket
+// takes a place like any other, so the pass can't be used once the event is sold out.
+fn mint_ticket(pass: &Pass, event_id: u64) -> Result<Ticket, Error> {
+    if let Some(ticket) = pass_ticket(pass.id, event_id) {
+        return Ok(ticket);
+    }
+    if pass.expires_at.is_some_and(|at| at <= time()) {
+        return Err(Error::ValidationFailed {
+            msg: format!("pass id:{} has expired", pass.id),
+        });
+    }
+    let event = _get_event(&event_id).ok_or(Error::not_found("event", event_id))?;
+    if !in_scope(pass, &event) {
+        return Err(Error::ValidationFailed {
+            msg: format!("pass id:{} does not cover event id:{}", pass.id, event_id),
+        });
+    }
+    if event.completed_at.is_some() {
+        return Err(Error::ValidationFailed {
+            msg: format!("event id:{} has already completed", event_id),
+        });
+    }
+    if remaining_capacity(&event) == Some(0) {
+        return Err(Error::CapacityExceeded {
+            msg: format!("event id:{} is sold out", event_id),
+        });
+    }
+
+    let ticket = issue_ticket(Ticket {
+        event_id,
+        user_id: pass.user_id,
+        ..Default::default()
+    })?;
+    PASS_TICKETS.with(|tickets| tickets.borrow_mut().insert((pass.id, event_id), ticket.id));
+    Ok(ticket)
+}
+
+pub(crate) fn get_user_passes(user_id: u64) -> Vec<Pass> {
+    PASS_STORAGE.with(|passes| {
+        passes
+            .borrow()
+            .iter()
+            .map(|(_, pass)| pass)
+            .filter(|pass| pass.user_id == user_id)
+            .collect()
+    })
+}
+
+// Drop the passes of a purged user, the tickets minted from them stay like any others
+pub(crate) fn remove_user_passes(user_id: u64) {
+    for pass in get_user_passes(user_id) {
+        PASS_STORAGE.with(|passes| passes.borrow_mut().remove(&pass.id));
+        PASS_TICKETS.with(|tickets| {
+            let mut tickets = tickets.borrow_mut();
+            let keys: Vec<(u64, u64)> = tickets
+                .range((pass.id, 0)..=(pass.id, u64::MAX))
+                .map(|(key, _)| key)
+                .collect();
+            for key in keys {
+                tickets.remove(&key);
+            }
+        });
+    }
+}
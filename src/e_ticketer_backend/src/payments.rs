@@ -0,0 +1,1188 @@
+use crate::bulk::error_msg;
+use crate::clock::time;
+use crate::credits::{CreditReason, RefundTarget};
+use crate::icrc7::Account;
+use crate::memos::{self, MemoPurpose};
+use crate::ratelimit::rate_limit;
+use crate::rates::QuotedRate;
+use crate::{
+    _get_event, _get_ticket, addons, caller_is_admin, config, credits, organizers, payouts,
+    quote_ticket, rates, store_ticket, tiers, Error, Memory, TicketPayload, ID_COUNTER,
+    MEMORY_MANAGER,
+};
+use candid::{Decode, Encode, Nat, Principal};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, Cell, StableBTreeMap, Storable};
+use serde_bytes::ByteBuf;
+use sha2::{Digest, Sha256};
+use std::{borrow::Cow, cell::RefCell};
+
+// Define an enum for how an event takes payment, prices are in the smallest unit of its ledger
+// unless their tier is priced in another currency. Ledgers name their token, e.g. "ICP", for
+// such prices to be converted.
+#[derive(candid::CandidType, Clone, PartialEq, Serialize, Deserialize)]
+pub enum PaymentMethod {
+    // Nothing is collected, for free events and complimentary tickets
+    Free,
+    // Buyers approve the canister on the ledger, which then pulls the price (ICRC-2)
+    Icrc1Pull {
+        ledger: Principal,
+        token: Option<String>,
+    },
+    // Buyers send the price to their deposit account, which the purchase then claims
+    PushClaim {
+        ledger: Principal,
+        token: Option<String>,
+    },
+    // Paid in ckBTC to the buyer's deposit account, directly or as bitcoin minted by the ckBTC
+    // minter. Pegged events price tickets in a currency, e.g. "USD", converted at purchase.
+    CkBtc {
+        peg: Option<String>,
+    },
+}
+
+// Define a struct for the ckBTC ledger and minter the canister takes payment through
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub struct CkBtcConfig {
+    ledger: Option<Principal>,
+    // Leave out to only accept ckBTC, bitcoin deposits need the minter
+    minter: Option<Principal>,
+}
+
+// Define a struct for where and how a buyer pays for tickets of a ckBTC event
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub struct CkBtcDeposit {
+    // Send ckBTC here, or bitcoin to the address
+    account: Account,
+    btc_address: Option<String>,
+    // Charged by the ledger when the payment is claimed, on top of the price
+    ledger_fee: u64,
+    // Withheld by the minter from every bitcoin deposit
+    minter_fee: Option<u64>,
+    // Confirmations a bitcoin deposit needs before it is minted
+    min_confirmations: Option<u32>,
+}
+
+// Define a struct for what the ckBTC ledger and minter take from a payment
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub struct CkBtcFees {
+    // Charged by the ledger when the payment is claimed, on top of the price
+    ledger_fee: u64,
+    // Withheld by the minter from every bitcoin deposit
+    minter_fee: Option<u64>,
+    // Confirmations a bitcoin deposit needs before it is minted
+    min_confirmations: Option<u32>,
+}
+
+// Define a struct for a buyer's deposit account and what it holds on the ledger
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub struct PaymentDeposit {
+    account: Account,
+    // A purchase claims its price and the ledger fee from this
+    balance: Nat,
+    ledger_fee: u64,
+}
+
+// Define a struct for a price converted to the event's token at the cached exchange rate
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct PricePeg {
+    currency: String,
+    // In the smallest unit of the currency, e.g. hundredths of a fiat currency
+    price: u64,
+    // Price of one token in the currency, scaled by 10^decimals
+    rate: u64,
+    decimals: u32,
+    // Token the price was paid in, None on payments from before other tokens, which were ckBTC
+    token: Option<String>,
+}
+
+// Define an enum for what became of a payment
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub enum PaymentStatus {
+    // Collected and the tickets were issued
+    Settled,
+    // The sale fell through and the payment went back to the payer, less the ledger fee
+    Refunded { block_index: Option<Nat> },
+    // The sale fell through and the ledger didn't take the refund, it's owed to the payer
+    RefundFailed { msg: String },
+    // The sale fell through and the payment was kept as the user's credit, in price units
+    Credited { amount: u64 },
+}
+
+// Define a struct for a payment taken for tickets
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct Payment {
+    id: u64,
+    user_id: u64,
+    payer: Principal,
+    method: PaymentMethod,
+    amount: u64,
+    // Ledger block the payment was taken in
+    block_index: Option<Nat>,
+    ticket_ids: Vec<u64>,
+    status: PaymentStatus,
+    paid_at: u64,
+    // Price the amount was converted from, for tickets priced in another currency
+    peg: Option<PricePeg>,
+    // Paid from the user's credit on top of the amount, in price units
+    credit: Option<u64>,
+    // Event of the first ticket, whose refund policy applies
+    event_id: Option<u64>,
+}
+
+// What a purchase costs and how it is paid, worked out before any payment is taken
+pub(crate) struct Charge {
+    method: PaymentMethod,
+    user_id: u64,
+    // Sum of the prices, in the unit tickets of the events are priced in
+    price: u64,
+    // Taken from the buyer's credit before the ledger is asked for the rest
+    credit: u64,
+    // What the ledger is asked for
+    amount: u64,
+    peg: Option<PricePeg>,
+    event_id: u64,
+}
+
+impl Storable for PaymentMethod {
+    // Conversion to bytes
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    // Conversion from bytes
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl Storable for CkBtcConfig {
+    // Conversion to bytes
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    // Conversion from bytes
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl Storable for Payment {
+    // Conversion to bytes
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    // Conversion from bytes
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for PaymentMethod {
+    const MAX_SIZE: u32 = 128;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl BoundedStorable for Payment {
+    const MAX_SIZE: u32 = 1024;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    // Payment methods keyed by event id, events without one hand out tickets for free
+    static METHOD_STORAGE: RefCell<StableBTreeMap<u64, PaymentMethod, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(69)))
+    ));
+
+    // Payments keyed by id, kept as the record of what was paid when tickets or events go
+    static PAYMENT_STORAGE: RefCell<StableBTreeMap<u64, Payment, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(70)))
+    ));
+
+    static CKBTC_CONFIG: RefCell<Cell<CkBtcConfig, Memory>> = RefCell::new(
+        Cell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(76))),
+            CkBtcConfig::default(),
+        )
+        .expect("Cannot create the ckBTC config")
+    );
+}
+
+// Define a struct for the arguments of an ICRC-1 'icrc1_transfer'
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct LedgerTransferArg {
+    from_subaccount: Option<ByteBuf>,
+    to: Account,
+    amount: Nat,
+    fee: Option<Nat>,
+    memo: Option<ByteBuf>,
+    created_at_time: Option<u64>,
+}
+
+// Define an enum for the errors of an ICRC-1 'icrc1_transfer'
+#[derive(candid::CandidType, Serialize, Deserialize)]
+enum LedgerTransferError {
+    BadFee { expected_fee: Nat },
+    BadBurn { min_burn_amount: Nat },
+    InsufficientFunds { balance: Nat },
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    Duplicate { duplicate_of: Nat },
+    TemporarilyUnavailable,
+    GenericError { error_code: Nat, message: String },
+}
+
+// Define a struct for the arguments of an ICRC-2 'icrc2_transfer_from'
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct TransferFromArgs {
+    spender_subaccount: Option<ByteBuf>,
+    from: Account,
+    to: Account,
+    amount: Nat,
+    fee: Option<Nat>,
+    memo: Option<ByteBuf>,
+    created_at_time: Option<u64>,
+}
+
+// Define an enum for the errors of an ICRC-2 'icrc2_transfer_from'
+#[derive(candid::CandidType, Serialize, Deserialize)]
+enum TransferFromError {
+    BadFee { expected_fee: Nat },
+    BadBurn { min_burn_amount: Nat },
+    InsufficientFunds { balance: Nat },
+    InsufficientAllowance { allowance: Nat },
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    Duplicate { duplicate_of: Nat },
+    TemporarilyUnavailable,
+    GenericError { error_code: Nat, message: String },
+}
+
+// Define a struct for an account of the ckBTC minter
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct MinterAccount {
+    owner: Option<Principal>,
+    subaccount: Option<ByteBuf>,
+}
+
+// Define a struct for the parts of the ckBTC minter's info used to quote deposits
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct MinterInfo {
+    min_confirmations: u32,
+    // Renamed by newer minters, one of them is set
+    kyt_fee: Option<u64>,
+    check_fee: Option<u64>,
+}
+
+// Define a struct for a bitcoin deposit the ckBTC minter is waiting on
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct PendingUtxo {
+    confirmations: u32,
+    value: u64,
+}
+
+// Define an enum for the errors of the ckBTC minter's 'update_balance'
+#[derive(candid::CandidType, Serialize, Deserialize)]
+enum UpdateBalanceError {
+    GenericError {
+        error_message: String,
+        error_code: u64,
+    },
+    TemporarilyUnavailable(String),
+    AlreadyProcessing,
+    NoNewUtxos {
+        required_confirmations: u32,
+        pending_utxos: Option<Vec<PendingUtxo>>,
+        current_confirmations: Option<u32>,
+    },
+}
+
+// A way of taking payment for tickets. Adding a provider takes an implementation, a
+// 'PaymentMethod' variant and an arm in 'collect_with' and 'refund_with', purchases only go
+// through 'collect' and 'settle'.
+trait PaymentProvider {
+    // Take the amount from the payer, returning the ledger block it was taken in, if any
+    async fn collect(
+        &self,
+        payer: Principal,
+        user_id: u64,
+        amount: u64,
+    ) -> Result<Option<Nat>, String>;
+    // Give a collected amount back to the payer, less any ledger fee. Ledger transfers carry a
+    // memo issued for the purpose.
+    async fn refund(
+        &self,
+        payer: Principal,
+        amount: u64,
+        purpose: MemoPurpose,
+    ) -> Result<Option<Nat>, String>;
+}
+
+struct FreeProvider;
+
+impl PaymentProvider for FreeProvider {
+    async fn collect(&self, _: Principal, _: u64, _: u64) -> Result<Option<Nat>, String> {
+        Ok(None)
+    }
+
+    async fn refund(&self, _: Principal, _: u64, _: MemoPurpose) -> Result<Option<Nat>, String> {
+        Ok(None)
+    }
+}
+
+struct Icrc1PullProvider {
+    ledger: Principal,
+}
+
+impl PaymentProvider for Icrc1PullProvider {
+    async fn collect(&self, payer: Principal, _: u64, amount: u64) -> Result<Option<Nat>, String> {
+        let args = TransferFromArgs {
+            spender_subaccount: None,
+            from: Account {
+                owner: payer,
+                subaccount: None,
+            },
+            to: canister_account(),
+            amount: Nat::from(amount),
+            fee: None,
+            memo: None,
+            created_at_time: None,
+        };
+        let (result,): (Result<Nat, TransferFromError>,) =
+            ic_cdk::call(self.ledger, "icrc2_transfer_from", (args,))
+                .await
+                .map_err(|(_, msg)| format!("ledger could not be reached: {}", msg))?;
+        result.map(Some).map_err(transfer_from_error_msg)
+    }
+
+    async fn refund(
+        &self,
+        payer: Principal,
+        amount: u64,
+        purpose: MemoPurpose,
+    ) -> Result<Option<Nat>, String> {
+        refund_from(self.ledger, payer, amount, purpose).await
+    }
+}
+
+struct PushClaimProvider {
+    ledger: Principal,
+}
+
+impl PaymentProvider for PushClaimProvider {
+    // Claim the amount from the buyer's deposit account into the canister's own. Anything
+    // sent beyond it stays there for the next purchase.
+    async fn collect(
+        &self,
+        _: Principal,
+        user_id: u64,
+        amount: u64,
+    ) -> Result<Option<Nat>, String> {
+        claim_deposit(self.ledger, user_id, amount).await
+    }
+
+    async fn refund(
+        &self,
+        payer: Principal,
+        amount: u64,
+        purpose: MemoPurpose,
+    ) -> Result<Option<Nat>, String> {
+        refund_from(self.ledger, payer, amount, purpose).await
+    }
+}
+
+struct CkBtcProvider {
+    ledger: Principal,
+    minter: Option<Principal>,
+}
+
+impl PaymentProvider for CkBtcProvider {
+    // Have the minter mint any confirmed bitcoin sent to the deposit address, then claim from
+    // the deposit account like any push payment
+    async fn collect(
+        &self,
+        _: Principal,
+        user_id: u64,
+        amount: u64,
+    ) -> Result<Option<Nat>, String> {
+        let pending = match self.minter {
+            Some(minter) => mint_deposits(minter, user_id).await,
+            None => None,
+        };
+        claim_deposit(self.ledger, user_id, amount)
+            .await
+            .map_err(|msg| match pending {
+                Some(pending) => format!("{}, {}", msg, pending),
+                None => msg,
+            })
+    }
+
+    // Refunds go back as ckBTC, buyers who want bitcoin retrieve it through the minter
+    async fn refund(
+        &self,
+        payer: Principal,
+        amount: u64,
+        purpose: MemoPurpose,
+    ) -> Result<Option<Nat>, String> {
+        refund_from(self.ledger, payer, amount, purpose).await
+    }
+}
+
+impl CkBtcProvider {
+    fn configured() -> Result<CkBtcProvider, String> {
+        let config = CKBTC_CONFIG.with(|config| config.borrow().get().clone());
+        Ok(CkBtcProvider {
+            ledger: config
+                .ledger
+                .ok_or("ckBTC payments aren't configured".to_string())?,
+            minter: config.minter,
+        })
+    }
+}
+
+async fn collect_with(
+    method: &PaymentMethod,
+    payer: Principal,
+    user_id: u64,
+    amount: u64,
+) -> Result<Option<Nat>, String> {
+    match method {
+        PaymentMethod::Free => FreeProvider.collect(payer, user_id, amount).await,
+        PaymentMethod::Icrc1Pull { ledger, .. } => {
+            Icrc1PullProvider { ledger: *ledger }
+                .collect(payer, user_id, amount)
+                .await
+        }
+        PaymentMethod::PushClaim { ledger, .. } => {
+            PushClaimProvider { ledger: *ledger }
+                .collect(payer, user_id, amount)
+                .await
+        }
+        PaymentMethod::CkBtc { .. } => {
+            CkBtcProvider::configured()?
+                .collect(payer, user_id, amount)
+                .await
+        }
+    }
+}
+
+async fn refund_with(
+    method: &PaymentMethod,
+    payer: Principal,
+    amount: u64,
+    purpose: MemoPurpose,
+) -> Result<Option<Nat>, String> {
+    match method {
+        PaymentMethod::Free => FreeProvider.refund(payer, amount, purpose).await,
+        PaymentMethod::Icrc1Pull { ledger, .. } => {
+            Icrc1PullProvider { ledger: *ledger }
+                .refund(payer, amount, purpose)
+                .await
+        }
+        PaymentMethod::PushClaim { ledger, .. } => {
+            PushClaimProvider { ledger: *ledger }
+                .refund(payer, amount, purpose)
+                .await
+        }
+        PaymentMethod::CkBtc { .. } => {
+            CkBtcProvider::configured()?
+                .refund(payer, amount, purpose)
+                .await
+        }
+    }
+}
+
+#[ic_cdk::query]
+fn get_event_payment(event_id: u64) -> PaymentMethod {
+    event_payment(event_id)
+}
+
+#[ic_cdk::update(guard = "rate_limit")]
+fn set_event_payment(event_id: u64, method: PaymentMethod) -> Result<PaymentMethod, Error> {
+    organizers::authorize_event(event_id)?;
+    match &method {
+        PaymentMethod::Free => {
+            METHOD_STORAGE.with(|methods| methods.borrow_mut().remove(&event_id))
+        }
+        PaymentMethod::Icrc1Pull { ledger, token } | PaymentMethod::PushClaim { ledger, token } => {
+            if *ledger == Principal::anonymous() || *ledger == ic_cdk::id() {
+                return Err(Error::ValidationFailed {
+                    msg: format!("{} is not a ledger", ledger),
+                });
+            }
+            config::check_ledger(*ledger).map_err(|msg| Error::ValidationFailed { msg })?;
+            if let Some(token) = token.as_ref().filter(|token| !rates::is_token(token)) {
+                return Err(Error::ValidationFailed {
+                    msg: format!("{} is not a token payments can be taken in", token),
+                });
+            }
+            track_event_rates(event_id, &method).map_err(|msg| Error::ValidationFailed { msg })?;
+            METHOD_STORAGE.with(|methods| methods.borrow_mut().insert(event_id, method.clone()))
+        }
+        PaymentMethod::CkBtc { peg } => {
+            CkBtcProvider::configured().map_err(|msg| Error::ValidationFailed { msg })?;
+            if let Some(currency) = peg {
+                rates::currency_decimals(currency)
+                    .map_err(|msg| Error::ValidationFailed { msg })?;
+            }
+            track_event_rates(event_id, &method).map_err(|msg| Error::ValidationFailed { msg })?;
+            METHOD_STORAGE.with(|methods| methods.borrow_mut().insert(event_id, method.clone()))
+        }
+    };
+    Ok(method)
+}
+
+#[ic_cdk::query(guard = "caller_is_admin")]
+fn get_ckbtc_config() -> CkBtcConfig {
+    CKBTC_CONFIG.with(|config| config.borrow().get().clone())
+}
+
+#[ic_cdk::update(guard = "caller_is_admin")]
+fn set_ckbtc_config(config: CkBtcConfig) -> Result<CkBtcConfig, Error> {
+    CKBTC_CONFIG
+        .with(|cell| cell.borrow_mut().set(config.clone()))
+        .map_err(|_| Error::InternalError {
+            msg: "ckBTC config could not be saved".to_string(),
+            ticket_id: None,
+        })?;
+    Ok(config)
+}
+
+// Where a buyer pays for tickets of a ckBTC event and what the ledger and minter take. Bitcoin
+// sent to the address is credited less the minter fee once it has enough confirmations.
+#[ic_cdk::update(guard = "rate_limit")]
+async fn get_ckbtc_deposit(event_id: u64, user_id: u64) -> Result<CkBtcDeposit, Error> {
+    let provider = ckbtc_event_provider(event_id)?;
+    let fees = ckbtc_fees(&provider).await?;
+
+    let account = deposit_account(user_id);
+    let mut btc_address = None;
+    if let Some(minter) = provider.minter {
+        let minter_account = MinterAccount {
+            owner: Some(account.owner),
+            subaccount: account.subaccount.clone(),
+        };
+        // The minter derives the address in an update call, this is why the method isn't a query
+        if let Ok((address,)) =
+            ic_cdk::call::<_, (String,)>(minter, "get_btc_address", (minter_account,)).await
+        {
+            btc_address = Some(address);
+        }
+    }
+    Ok(CkBtcDeposit {
+        account,
+        btc_address,
+        ledger_fee: fees.ledger_fee,
+        minter_fee: fees.minter_fee,
+        min_confirmations: fees.min_confirmations,
+    })
+}
+
+// What the ckBTC ledger and minter take from a payment for an event. Both are asked with
+// queries, so the fees are read on the query path.
+#[ic_cdk::query(composite = true)]
+async fn get_ckbtc_fees(event_id: u64) -> Result<CkBtcFees, Error> {
+    let provider = ckbtc_event_provider(event_id)?;
+    ckbtc_fees(&provider).await
+}
+
+// The ckBTC ledger and minter of an event paid in ckBTC
+fn ckbtc_event_provider(event_id: u64) -> Result<CkBtcProvider, Error> {
+    if !matches!(event_payment(event_id), PaymentMethod::CkBtc { .. }) {
+        return Err(Error::ValidationFailed {
+            msg: format!("event id:{} isn't paid in ckBTC", event_id),
+        });
+    }
+    CkBtcProvider::configured().map_err(|msg| Error::ValidationFailed { msg })
+}
+
+async fn ckbtc_fees(provider: &CkBtcProvider) -> Result<CkBtcFees, Error> {
+    let ledger_fee = ledger_fee(provider.ledger)
+        .await
+        .ok()
+        .and_then(|fee| u64::try_from(fee.0).ok())
+        .ok_or(Error::PaymentFailed {
+            msg: "ckBTC ledger could not be reached".to_string(),
+        })?;
+    let mut fees = CkBtcFees {
+        ledger_fee,
+        minter_fee: None,
+        min_confirmations: None,
+    };
+    if let Some(minter) = provider.minter {
+        if let Ok((info,)) = ic_cdk::call::<_, (MinterInfo,)>(minter, "get_minter_info", ()).await {
+            fees.minter_fee = info.kyt_fee.or(info.check_fee);
+            fees.min_confirmations = Some(info.min_confirmations);
+        }
+    }
+    Ok(fees)
+}
+
+// Where a buyer sends payment for events paid by push and claim, one account per user. ckBTC
+// events use the same account, 'get_ckbtc_deposit' also gives the bitcoin address and fees.
+#[ic_cdk::query]
+fn get_payment_deposit_account(event_id: u64, user_id: u64) -> Result<Account, Error> {
+    deposit_ledger(event_id)?;
+    Ok(deposit_account(user_id))
+}
+
+// A buyer's deposit account along with what it holds, so a payment can be watched arriving
+// before the purchase claims it. The ledger is asked with queries, on the query path.
+#[ic_cdk::query(composite = true)]
+async fn get_payment_deposit(event_id: u64, user_id: u64) -> Result<PaymentDeposit, Error> {
+    let ledger = deposit_ledger(event_id)?;
+    let account = deposit_account(user_id);
+    let ledger_fee = ledger_fee(ledger)
+        .await
+        .ok()
+        .and_then(|fee| u64::try_from(fee.0).ok())
+        .ok_or(Error::PaymentFailed {
+            msg: "ledger could not be reached".to_string(),
+        })?;
+    let balance = ledger_balance(ledger, &account)
+        .await
+        .map_err(|msg| Error::PaymentFailed { msg })?;
+    Ok(PaymentDeposit {
+        account,
+        balance,
+        ledger_fee,
+    })
+}
+
+// The ledger buyers of an event pay into their deposit account on
+fn deposit_ledger(event_id: u64) -> Result<Principal, Error> {
+    match event_payment(event_id) {
+        PaymentMethod::PushClaim { ledger, .. } => Ok(ledger),
+        PaymentMethod::CkBtc { .. } => CkBtcProvider::configured()
+            .map(|provider| provider.ledger)
+            .map_err(|msg| Error::ValidationFailed { msg }),
+        _ => Err(Error::ValidationFailed {
+            msg: format!("event id:{} isn't paid by deposit", event_id),
+        }),
+    }
+}
+
+#[ic_cdk::query]
+pub(crate) fn get_user_payments(user_id: u64) -> Vec<Payment> {
+    PAYMENT_STORAGE.with(|payments| {
+        payments
+            .borrow()
+            .iter()
+            .map(|(_, payment)| payment)
+            .filter(|payment| payment.user_id == user_id)
+            .collect()
+    })
+}
+
+pub(crate) fn event_payment(event_id: u64) -> PaymentMethod {
+    METHOD_STORAGE
+        .with(|methods| methods.borrow().get(&event_id))
+        .unwrap_or(PaymentMethod::Free)
+}
+
+// Token a payment method takes payment in, if its ledger named one
+pub(crate) fn event_token(method: &PaymentMethod) -> Option<String> {
+    match method {
+        PaymentMethod::Free => None,
+        PaymentMethod::Icrc1Pull { token, .. } | PaymentMethod::PushClaim { token, .. } => {
+            token.clone()
+        }
+        PaymentMethod::CkBtc { .. } => Some("BTC".to_string()),
+    }
+}
+
+// Currency a ticket is priced in, its tier's or the event's peg. None when priced in the
+// event's token, which needs no conversion.
+pub(crate) fn pricing_currency(event_id: u64, tier_id: Option<u64>) -> Option<String> {
+    let method = event_payment(event_id);
+    let peg = match &method {
+        PaymentMethod::CkBtc { peg } => peg.clone(),
+        _ => None,
+    };
+    tier_id
+        .and_then(|tier_id| tiers::_get_tier(event_id, tier_id))
+        .and_then(|tier| tier.currency)
+        .or(peg)
+        .filter(|currency| Some(currency) != event_token(&method).as_ref())
+}
+
+// Follow the rates an event's prices are converted at, from now on purchases wait until the
+// first rate is in
+fn track_event_rates(event_id: u64, method: &PaymentMethod) -> Result<(), String> {
+    let Some(token) = event_token(method) else {
+        return Ok(());
+    };
+    let mut currencies: Vec<String> = tiers::_get_event_tiers(event_id)
+        .into_iter()
+        .filter_map(|tier| tier.currency)
+        .collect();
+    if let PaymentMethod::CkBtc { peg: Some(peg) } = method {
+        currencies.push(peg.clone());
+    }
+    for currency in currencies.iter().filter(|currency| **currency != token) {
+        rates::track_pair(&token, currency)?;
+    }
+    Ok(())
+}
+
+// Work out what tickets of one buyer cost, with their insurance, add-ons and donations. They are
+// paid in one go, so their events must take payment the same way.
+pub(crate) fn charge_for(payloads: &[TicketPayload]) -> Result<Charge, String> {
+    let method = event_payment(payloads[0].event_id);
+    let currency = pricing_currency(payloads[0].event_id, payloads[0].tier_id);
+    let mut price: u64 = 0;
+    for payload in payloads {
+        _get_event(&payload.event_id)
+            .ok_or(format!("event id:{} does not exist", payload.event_id))?;
+        if event_payment(payload.event_id) != method {
+            return Err(format!(
+                "event id:{} takes payment differently from event id:{}, they must be bought separately",
+                payload.event_id, payloads[0].event_id
+            ));
+        }
+        if pricing_currency(payload.event_id, payload.tier_id) != currency {
+            return Err(
+                "tickets priced in different currencies must be bought separately".to_string(),
+            );
+        }
+        let quote = quote_ticket(payload).map_err(error_msg)?;
+        price += quote.price
+            + quote.insurance.map_or(0, |insurance| insurance.fee)
+            + addons::lines_total(&quote.addons)
+            + payload.donation.unwrap_or(0);
+    }
+
+    // Credit goes first, free events have nothing to spend it on
+    let user_id = payloads[0].user_id;
+    let credit = match method {
+        PaymentMethod::Free => 0,
+        _ => credits::balance(user_id).min(price),
+    };
+    priced_charge(
+        method,
+        user_id,
+        payloads[0].event_id,
+        price,
+        credit,
+        currency,
+    )
+}
+
+// What a resold ticket costs its buyer, paid the same way as the event's own tickets and in
+// the currency of its tier. Resales don't spend credit, the seller is paid out of what the
+// buyer sends.
+pub(crate) fn charge_resale(
+    event_id: u64,
+    tier_id: Option<u64>,
+    user_id: u64,
+    price: u64,
+) -> Result<Charge, String> {
+    let currency = pricing_currency(event_id, tier_id);
+    priced_charge(
+        event_payment(event_id),
+        user_id,
+        event_id,
+        price,
+        0,
+        currency,
+    )
+}
+
+// Work out what the ledger is asked for the part of a price not paid by credit. Prices in
+// another currency are converted at the cached rate, which must be recent.
+fn priced_charge(
+    method: PaymentMethod,
+    user_id: u64,
+    event_id: u64,
+    price: u64,
+    credit: u64,
+    currency: Option<String>,
+) -> Result<Charge, String> {
+    let due = price - credit;
+    let (amount, peg) = match (&method, &currency) {
+        (PaymentMethod::Free, _) | (_, None) => (due, None),
+        (_, Some(currency)) => {
+            let token = event_token(&method).ok_or(format!(
+                "the ledger of event id:{} names no token, prices in {} can't be converted",
+                event_id, currency
+            ))?;
+            let rate = rates::fresh_rate(&token, currency)?;
+            let amount = rates::convert_to_base(
+                &rate,
+                due,
+                rates::currency_decimals(currency)?,
+                rates::currency_decimals(&token)?,
+            )
+            .ok_or(format!(
+                "a price of {} {} can't be paid in {}",
+                due, currency, token
+            ))?;
+            let peg = PricePeg {
+                currency: currency.clone(),
+                price: due,
+                rate: rate.rate,
+                decimals: rate.decimals,
+                token: Some(token),
+            };
+            (amount, Some(peg))
+        }
+    };
+    Ok(Charge {
+        method,
+        user_id,
+        price,
+        credit,
+        amount,
+        peg,
+        event_id,
+    })
+}
+
+// Make sure tickets still cost what was paid for them, prices may have changed while paying
+pub(crate) fn check_charge(charge: &Charge, payloads: &[TicketPayload]) -> Result<(), String> {
+    // Compared before conversion, a pegged price is kept even if the rate moved while paying
+    let current = charge_for(payloads)?;
+    if current.method != charge.method || current.price != charge.price {
+        return Err(format!(
+            "the tickets now cost {} instead of the {} paid",
+            current.price, charge.price
+        ));
+    }
+    Ok(())
+}
+
+// Take payment from the caller. Paid methods await the ledger, so a purchase checks its
+// tickets again afterwards and hands the payment to 'settle' whatever the outcome.
+pub(crate) async fn collect(charge: &Charge) -> Result<Payment, String> {
+    let payer = ic_cdk::caller();
+    // Taken before the ledger is awaited, so the same credit can't pay for two purchases
+    credits::debit(charge.user_id, charge.credit, CreditReason::Purchase)?;
+    let block_index = match charge.amount {
+        0 => None,
+        amount => match collect_with(&charge.method, payer, charge.user_id, amount).await {
+            Ok(block_index) => block_index,
+            Err(msg) => {
+                credits::credit(charge.user_id, charge.credit, CreditReason::PurchaseFailed);
+                return Err(msg);
+            }
+        },
+    };
+    Ok(Payment {
+        id: 0,
+        user_id: charge.user_id,
+        payer,
+        method: charge.method.clone(),
+        amount: charge.amount,
+        block_index,
+        ticket_ids: vec![],
+        status: PaymentStatus::Settled,
+        paid_at: time(),
+        peg: charge.peg.clone(),
+        credit: Some(charge.credit).filter(|credit| *credit > 0),
+        event_id: Some(charge.event_id),
+    })
+}
+
+// Record a payment against the tickets it bought, or refund it when none were issued.
+// Payments that took nothing aren't kept.
+pub(crate) async fn settle(payment: Payment, ticket_ids: Vec<u64>) {
+    settle_for(payment, ticket_ids, true).await
+}
+
+// Record the payment for a resold ticket. The platform keeps its fee and the rest goes to the
+// seller, so none of it is owed to the event's organizers.
+pub(crate) async fn settle_resale(payment: Payment, ticket_id: u64) {
+    settle_for(payment, vec![ticket_id], false).await
+}
+
+async fn settle_for(mut payment: Payment, ticket_ids: Vec<u64>, owed_to_organizers: bool) {
+    if let Some(peg) = &payment.peg {
+        record_quoted_rate(peg, &ticket_ids);
+    }
+    let credit = payment.credit.unwrap_or(0);
+    if (payment.amount == 0 && credit == 0) || payment.method == PaymentMethod::Free {
+        return;
+    }
+
+    // Increment the global ID counter to get a new ID for the payment, taken before any refund
+    // so its memo can point back to the payment
+    payment.id = ID_COUNTER
+        .with(|counter| {
+            let current_id = *counter.borrow().get();
+            counter.borrow_mut().set(current_id + 1)
+        })
+        .expect("Cannot increment Ids");
+    if ticket_ids.is_empty() {
+        // Credit spent always goes back as credit, the rest where the refund policy says
+        let target = payment.event_id.map_or(RefundTarget::Ledger, |event_id| {
+            credits::refund_target(event_id, payment.user_id, None)
+        });
+        payment.status = if payment.amount == 0 || target == RefundTarget::Credit {
+            // Pegged amounts go back at the price they were converted from
+            let amount = credit + payment.peg.as_ref().map_or(payment.amount, |peg| peg.price);
+            credits::credit(payment.user_id, amount, CreditReason::PurchaseFailed);
+            PaymentStatus::Credited { amount }
+        } else {
+            credits::credit(payment.user_id, credit, CreditReason::PurchaseFailed);
+            let purpose = MemoPurpose::Refund {
+                payment_id: payment.id,
+                user_id: payment.user_id,
+            };
+            match refund_with(&payment.method, payment.payer, payment.amount, purpose).await {
+                Ok(block_index) => PaymentStatus::Refunded { block_index },
+                Err(msg) => PaymentStatus::RefundFailed { msg },
+            }
+        };
+    }
+    // What the ledger took for tickets issued is owed to their events' organizers
+    if let Some(ledger) =
+        method_ledger(&payment.method).filter(|_| owed_to_organizers && !ticket_ids.is_empty())
+    {
+        payouts::record_collected(ledger, payment.amount, &ticket_ids);
+    }
+    payment.ticket_ids = ticket_ids;
+    PAYMENT_STORAGE.with(|payments| payments.borrow_mut().insert(payment.id, payment));
+}
+
+// Send part of a collected payment on to someone else's default account, such as the seller
+// of a resold ticket. The share is in the unit of the price, and converted like the price was
+// for pegged events. The ledger fee comes out of it.
+pub(crate) async fn pay_out(
+    charge: &Charge,
+    recipient: Principal,
+    share: u64,
+    purpose: MemoPurpose,
+) -> Result<Option<Nat>, String> {
+    match share_of_amount(charge.amount, charge.price, share) {
+        0 => Ok(None),
+        amount => refund_with(&charge.method, recipient, amount, purpose).await,
+    }
+}
+
+// Part of an amount in proportion to a share of the price it was converted from, rounded down
+fn share_of_amount(amount: u64, price: u64, share: u64) -> u64 {
+    match price {
+        0 => 0,
+        price => (amount as u128 * share.min(price) as u128 / price as u128) as u64,
+    }
+}
+
+// Ledger a payment method takes payment through
+fn method_ledger(method: &PaymentMethod) -> Option<Principal> {
+    match method {
+        PaymentMethod::Free => None,
+        PaymentMethod::Icrc1Pull { ledger, .. } | PaymentMethod::PushClaim { ledger, .. } => {
+            Some(*ledger)
+        }
+        PaymentMethod::CkBtc { .. } => CkBtcProvider::configured().ok().map(|p| p.ledger),
+    }
+}
+
+// Keep on the tickets bought the rate their price was converted at
+fn record_quoted_rate(peg: &PricePeg, ticket_ids: &[u64]) {
+    let rate = QuotedRate {
+        base: peg.token.clone().unwrap_or("BTC".to_string()),
+        quote: peg.currency.clone(),
+        rate: peg.rate,
+        decimals: peg.decimals,
+    };
+    for mut ticket in ticket_ids.iter().filter_map(_get_ticket) {
+        ticket.quoted_rate = Some(rate.clone());
+        store_ticket(&mut ticket);
+    }
+}
+
+fn canister_account() -> Account {
+    Account {
+        owner: ic_cdk::id(),
+        subaccount: None,
+    }
+}
+
+// Subaccount of the canister a user pays into, derived from the user id
+fn deposit_account(user_id: u64) -> Account {
+    let mut hasher = Sha256::new();
+    hasher.update(b"ticket-deposit");
+    hasher.update(user_id.to_be_bytes());
+    Account {
+        owner: ic_cdk::id(),
+        subaccount: Some(ByteBuf::from(hasher.finalize().to_vec())),
+    }
+}
+
+// Claim an amount from a user's deposit account into the canister's own, the ledger fee is
+// taken from the deposit on top of it
+async fn claim_deposit(
+    ledger: Principal,
+    user_id: u64,
+    amount: u64,
+) -> Result<Option<Nat>, String> {
+    let deposit = deposit_account(user_id);
+    let fee = ledger_fee(ledger).await?;
+    let balance = ledger_balance(ledger, &deposit).await?;
+    if balance < Nat::from(amount) + fee.clone() {
+        return Err(format!(
+            "deposit account holds {}, the purchase needs {} and the ledger fee of {}",
+            balance, amount, fee
+        ));
+    }
+    transfer(
+        ledger,
+        deposit.subaccount,
+        canister_account(),
+        Nat::from(amount),
+        None,
+    )
+    .await
+    .map(Some)
+}
+
+// Have the minter mint the confirmed bitcoin sent to a user's deposit address. Returns a note
+// on deposits still waiting for confirmations, a minter that can't be reached is left for the
+// next purchase.
+async fn mint_deposits(minter: Principal, user_id: u64) -> Option<String> {
+    let deposit = deposit_account(user_id);
+    let account = MinterAccount {
+        owner: Some(deposit.owner),
+        subaccount: deposit.subaccount,
+    };
+    let result: Result<(Result<candid::Reserved, UpdateBalanceError>,), _> =
+        ic_cdk::call(minter, "update_balance", (account,)).await;
+    match result {
+        Ok((Err(UpdateBalanceError::NoNewUtxos {
+            required_confirmations,
+            pending_utxos: Some(pending),
+            current_confirmations,
+        }),))
+            if !pending.is_empty() =>
+        {
+            Some(format!(
+                "a bitcoin deposit has {} of the {} confirmations it needs",
+                current_confirmations
+                    .or(pending.iter().map(|utxo| utxo.confirmations).max())
+                    .unwrap_or(0),
+                required_confirmations
+            ))
+        }
+        _ => None,
+    }
+}
+
+pub(crate) async fn ledger_fee(ledger: Principal) -> Result<Nat, String> {
+    let (fee,): (Nat,) = ic_cdk::call(ledger, "icrc1_fee", ())
+        .await
+        .map_err(|(_, msg)| format!("ledger could not be reached: {}", msg))?;
+    Ok(fee)
+}
+
+async fn ledger_balance(ledger: Principal, account: &Account) -> Result<Nat, String> {
+    let (balance,): (Nat,) = ic_cdk::call(ledger, "icrc1_balance_of", (account,))
+        .await
+        .map_err(|(_, msg)| format!("ledger could not be reached: {}", msg))?;
+    Ok(balance)
+}
+
+pub(crate) async fn transfer(
+    ledger: Principal,
+    from_subaccount: Option<ByteBuf>,
+    to: Account,
+    amount: Nat,
+    memo: Option<ByteBuf>,
+) -> Result<Nat, String> {
+    let arg = LedgerTransferArg {
+        from_subaccount,
+        to,
+        amount,
+        fee: None,
+        memo,
+        created_at_time: None,
+    };
+    let (result,): (Result<Nat, LedgerTransferError>,) =
+        ic_cdk::call(ledger, "icrc1_transfer", (arg,))
+            .await
+            .map_err(|(_, msg)| format!("ledger could not be reached: {}", msg))?;
+    result.map_err(transfer_error_msg)
+}
+
+// Send an amount back to the payer's default account, the ledger fee comes out of it
+async fn refund_from(
+    ledger: Principal,
+    payer: Principal,
+    amount: u64,
+    purpose: MemoPurpose,
+) -> Result<Option<Nat>, String> {
+    let fee = ledger_fee(ledger).await?;
+    let amount = Nat::from(amount);
+    if amount <= fee {
+        return Ok(None);
+    }
+    let to = Account {
+        owner: payer,
+        subaccount: None,
+    };
+    let amount = amount - fee;
+    let (sequence, memo) = memos::issue(purpose, ledger, &to, &amount);
+    let result = transfer(ledger, None, to, amount, Some(memo)).await;
+    memos::record_result(sequence, &result);
+    result.map(Some)
+}
+
+fn transfer_error_msg(error: LedgerTransferError) -> String {
+    match error {
+        LedgerTransferError::BadFee { expected_fee } => {
+            format!("ledger expects a fee of {}", expected_fee)
+        }
+        LedgerTransferError::InsufficientFunds { balance } => {
+            format!("insufficient funds, the balance is {}", balance)
+        }
+        LedgerTransferError::TemporarilyUnavailable => "ledger is unavailable".to_string(),
+        LedgerTransferError::GenericError { message, .. } => message,
+        _ => "ledger rejected the transfer".to_string(),
+    }
+}
+
+fn transfer_from_error_msg(error: TransferFromError) -> String {
+    match error {
+        TransferFromError::BadFee { expected_fee } => {
+            format!("ledger expects a fee of {}", expected_fee)
+        }
+        TransferFromError::InsufficientFunds { balance } => {
+            format!("insufficient funds, the balance is {}", balance)
+        }
+        TransferFromError::InsufficientAllowance { allowance } => format!(
+            "the canister is approved for {}, approve the price and the ledger fee",
+            allowance
+        ),
+        TransferFromError::TemporarilyUnavailable => "ledger is unavailable".to_string(),
+        TransferFromError::GenericError { message, .. } => message,
+        _ => "ledger rejected the transfer".to_string(),
+    }
+}
+
+// Drop the payment method of a purged event, its payments are kept
+pub(crate) fn remove_event_payment(event_id: u64) {
+    METHOD_STORAGE.with(|methods| methods.borrow_mut().remove(&event_id));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn share_is_paid_in_proportion_to_the_amount_collected() {
+        // A price of 100.00 converted to 250_000 satoshis, 95.00 of it goes to the seller
+        assert_eq!(share_of_amount(250_000, 10_000, 9_500), 237_500);
+        assert_eq!(share_of_amount(1_000, 1_000, 950), 950);
+    }
+
+    #[test]
+    fn share_never_exceeds_the_amount_collected() {
+        assert_eq!(share_of_amount(1_000, 1_000, 5_000), 1_000);
+        assert_eq!(share_of_amount(1_000, 0, 500), 0);
+        assert_eq!(
+            share_of_amount(u64::MAX, u64::MAX, u64::MAX - 1),
+            u64::MAX - 1
+        );
+    }
+}
@@ -0,0 +1,505 @@
+use crate::clock::time;
+use crate::icrc7::Account;
+use crate::memos::{self, MemoPurpose};
+use crate::payments;
+use crate::ratelimit::rate_limit;
+use crate::{
+    _get_event, _get_ticket, addons, credentials, organizers, statements, Error, Memory,
+    ID_COUNTER, MEMORY_MANAGER,
+};
+use candid::{Decode, Encode, Nat, Principal};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use std::{borrow::Cow, cell::RefCell};
+
+// Shares are in basis points, 10000 splits the whole balance
+const WHOLE_SHARE: u64 = 10_000;
+
+// Define a struct for the account an organizer is paid out to
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct PayoutAccount {
+    organizer_id: u64,
+    account: Account,
+    updated_at: u64,
+}
+
+// Define a struct for an organizer's share of an event's revenue
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct RevenueShare {
+    organizer_id: u64,
+    // Basis points of the balance, the shares of an event add up to 10000
+    share: u64,
+}
+
+// Define a struct for how an event's revenue is split among its organizers
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct RevenueSplit {
+    event_id: u64,
+    shares: Vec<RevenueShare>,
+}
+
+// Define a struct for what an event took in through a ledger and what was paid out of it
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct LedgerBalance {
+    ledger: Principal,
+    collected: u64,
+    withdrawn: u64,
+}
+
+// Define a struct for the balance of an event, per ledger it took payment through
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct EventBalance {
+    event_id: u64,
+    balances: Vec<LedgerBalance>,
+}
+
+// Define an enum for the outcome of a payout transfer
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub enum PayoutStatus {
+    Paid { block_index: Nat },
+    // The share stays in the event's balance for the next withdrawal
+    Failed { msg: String },
+}
+
+// Define a struct for an entry of the payout ledger
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct PayoutEntry {
+    id: u64,
+    event_id: u64,
+    organizer_id: u64,
+    ledger: Principal,
+    account: Account,
+    // Taken from the event's balance, the ledger fee comes out of it
+    amount: u64,
+    fee: u64,
+    status: PayoutStatus,
+    paid_at: u64,
+}
+
+impl Storable for PayoutAccount {
+    // Conversion to bytes
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    // Conversion from bytes
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for PayoutAccount {
+    const MAX_SIZE: u32 = 256;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl Storable for RevenueSplit {
+    // Conversion to bytes
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    // Conversion from bytes
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for RevenueSplit {
+    const MAX_SIZE: u32 = 1024;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl Storable for EventBalance {
+    // Conversion to bytes
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    // Conversion from bytes
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for EventBalance {
+    const MAX_SIZE: u32 = 1024;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl Storable for PayoutEntry {
+    // Conversion to bytes
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    // Conversion from bytes
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for PayoutEntry {
+    const MAX_SIZE: u32 = 512;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    // Payout accounts keyed by organizer id
+    static ACCOUNT_STORAGE: RefCell<StableBTreeMap<u64, PayoutAccount, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(92)))
+    ));
+
+    // Splits keyed by event id, events without one pay everything to their first organizer
+    static SPLIT_STORAGE: RefCell<StableBTreeMap<u64, RevenueSplit, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(93)))
+    ));
+
+    static BALANCE_STORAGE: RefCell<StableBTreeMap<u64, EventBalance, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(94)))
+    ));
+
+    // Payout ledger keyed by (organizer id, entry id)
+    static PAYOUT_STORAGE: RefCell<StableBTreeMap<(u64, u64), PayoutEntry, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(95)))
+    ));
+}
+
+// Set the account the calling organizer's shares of event revenue are sent to. Redirecting
+// money needs a recent login, like withdrawing it.
+#[ic_cdk::update(guard = "rate_limit")]
+fn set_payout_account(account: Account) -> Result<PayoutAccount, Error> {
+    credentials::check_recent_login()?;
+    let organizer_id = organizers::caller_organizer_id().ok_or(Error::Unauthorized {
+        msg: "only organizers have payout accounts, call 'register_organizer' first".to_string(),
+    })?;
+    if account
+        .subaccount
+        .as_ref()
+        .is_some_and(|subaccount| subaccount.len() != 32)
+    {
+        return Err(Error::ValidationFailed {
+            msg: "subaccount must be 32 bytes".to_string(),
+        });
+    }
+    let payout_account = PayoutAccount {
+        organizer_id,
+        account,
+        updated_at: time(),
+    };
+    ACCOUNT_STORAGE.with(|accounts| {
+        accounts
+            .borrow_mut()
+            .insert(organizer_id, payout_account.clone())
+    });
+    Ok(payout_account)
+}
+
+#[ic_cdk::query]
+fn get_payout_account(organizer_id: u64) -> Result<PayoutAccount, Error> {
+    organizers::check_organizer(organizer_id)?;
+    ACCOUNT_STORAGE
+        .with(|accounts| accounts.borrow().get(&organizer_id))
+        .ok_or(Error::NotFound {
+            entity: "payout account".to_string(),
+            id: organizer_id.to_string(),
+            msg: format!("organizer id:{} has no payout account", organizer_id),
+        })
+}
+
+#[ic_cdk::query]
+fn get_revenue_split(event_id: u64) -> Result<RevenueSplit, Error> {
+    organizers::authorize_event(event_id)?;
+    Ok(revenue_split(event_id))
+}
+
+// Split an event's revenue among its organizers, in basis points adding up to 10000
+#[ic_cdk::update(guard = "rate_limit")]
+fn set_revenue_split(event_id: u64, mut shares: Vec<RevenueShare>) -> Result<RevenueSplit, Error> {
+    let event = organizers::authorize_event(event_id)?;
+    let organizer_ids = event.organizer_ids.unwrap_or_default();
+    shares.retain(|share| share.share > 0);
+    shares.sort_by_key(|share| share.organizer_id);
+    if shares
+        .windows(2)
+        .any(|pair| pair[0].organizer_id == pair[1].organizer_id)
+    {
+        return Err(Error::ValidationFailed {
+            msg: "an organizer can only have one share".to_string(),
+        });
+    }
+    if let Some(share) = shares
+        .iter()
+        .find(|share| !organizer_ids.contains(&share.organizer_id))
+    {
+        return Err(Error::ValidationFailed {
+            msg: format!(
+                "organizer id:{} does not organize event id:{}",
+                share.organizer_id, event_id
+            ),
+        });
+    }
+    if shares.iter().map(|share| share.share).sum::<u64>() != WHOLE_SHARE {
+        return Err(Error::ValidationFailed {
+            msg: format!("shares must add up to {} basis points", WHOLE_SHARE),
+        });
+    }
+    let split = RevenueSplit { event_id, shares };
+    SPLIT_STORAGE.with(|splits| splits.borrow_mut().insert(event_id, split.clone()));
+    Ok(split)
+}
+
+#[ic_cdk::query]
+fn get_event_balance(event_id: u64) -> Result<EventBalance, Error> {
+    organizers::authorize_event(event_id)?;
+    Ok(event_balance(event_id))
+}
+
+// Send what an event holds to its organizers' payout accounts, split by the event's shares.
+// Shares of organizers without a payout account, and transfers that fail, stay in the balance.
+#[ic_cdk::update(guard = "rate_limit")]
+async fn withdraw_event_revenue(event_id: u64) -> Result<Vec<PayoutEntry>, Error> {
+    organizers::authorize_event(event_id)?;
+    credentials::check_recent_login()?;
+    let split = revenue_split(event_id);
+    if split.shares.is_empty() {
+        return Err(Error::ValidationFailed {
+            msg: format!("event id:{} has no organizer to pay out to", event_id),
+        });
+    }
+
+    // Take the shares out of the balance before any transfer, so a withdrawal running
+    // meanwhile can't pay them again
+    let mut pending = vec![];
+    let mut balance = event_balance(event_id);
+    for ledger_balance in balance.balances.iter_mut() {
+        let available = ledger_balance.collected - ledger_balance.withdrawn;
+        for (organizer_id, amount) in split_amount(&split, available) {
+            let Some(payout_account) = ACCOUNT_STORAGE.with(|a| a.borrow().get(&organizer_id))
+            else {
+                continue;
+            };
+            if amount > 0 {
+                ledger_balance.withdrawn += amount;
+                pending.push((ledger_balance.ledger, organizer_id, payout_account, amount));
+            }
+        }
+    }
+    if pending.is_empty() {
+        return Err(Error::ValidationFailed {
+            msg: format!(
+                "event id:{} has nothing to pay out to organizers with a payout account",
+                event_id
+            ),
+        });
+    }
+    store_balance(&balance);
+
+    let mut entries = vec![];
+    for (ledger, organizer_id, payout_account, amount) in pending {
+        // Increment the global ID counter to get a new ID for the payout, taken before the
+        // transfer so its memo can point back to the payout
+        let id = ID_COUNTER
+            .with(|counter| {
+                let current_id = *counter.borrow().get();
+                counter.borrow_mut().set(current_id + 1)
+            })
+            .expect("Cannot increment Ids");
+        let purpose = MemoPurpose::Payout {
+            payout_id: id,
+            event_id,
+            organizer_id,
+        };
+        let (fee, result) = pay_out(ledger, payout_account.account.clone(), amount, purpose).await;
+        let status = match result {
+            Ok(block_index) => {
+                statements::record_event_payout(organizer_id, amount);
+                PayoutStatus::Paid { block_index }
+            }
+            Err(msg) => {
+                restore(event_id, ledger, amount);
+                PayoutStatus::Failed { msg }
+            }
+        };
+        let entry = PayoutEntry {
+            id,
+            event_id,
+            organizer_id,
+            ledger,
+            account: payout_account.account,
+            amount,
+            fee,
+            status,
+            paid_at: time(),
+        };
+        PAYOUT_STORAGE.with(|payouts| {
+            payouts
+                .borrow_mut()
+                .insert((organizer_id, id), entry.clone())
+        });
+        entries.push(entry);
+    }
+    Ok(entries)
+}
+
+// Every payout made to an organizer, failed ones included, the oldest first
+#[ic_cdk::query]
+fn get_payout_ledger(organizer_id: u64) -> Result<Vec<PayoutEntry>, Error> {
+    organizers::check_organizer(organizer_id)?;
+    Ok(PAYOUT_STORAGE.with(|payouts| {
+        payouts
+            .borrow()
+            .range((organizer_id, 0)..=(organizer_id, u64::MAX))
+            .map(|(_, entry)| entry)
+            .collect()
+    }))
+}
+
+// Transfer an amount less the ledger fee, returning the fee charged
+async fn pay_out(
+    ledger: Principal,
+    to: Account,
+    amount: u64,
+    purpose: MemoPurpose,
+) -> (u64, Result<Nat, String>) {
+    let fee = match payments::ledger_fee(ledger).await {
+        Ok(fee) => fee,
+        Err(msg) => return (0, Err(msg)),
+    };
+    let charged = u64::try_from(fee.0.clone()).unwrap_or(u64::MAX);
+    if amount <= charged {
+        let msg = format!("{} does not cover the ledger fee of {}", amount, charged);
+        return (charged, Err(msg));
+    }
+    let amount = Nat::from(amount) - fee;
+    let (sequence, memo) = memos::issue(purpose, ledger, &to, &amount);
+    let result = payments::transfer(ledger, None, to, amount, Some(memo)).await;
+    memos::record_result(sequence, &result);
+    (charged, result)
+}
+
+fn revenue_split(event_id: u64) -> RevenueSplit {
+    SPLIT_STORAGE
+        .with(|splits| splits.borrow().get(&event_id))
+        .unwrap_or_else(|| RevenueSplit {
+            event_id,
+            shares: _get_event(&event_id)
+                .and_then(|event| event.organizer_ids)
+                .and_then(|organizer_ids| organizer_ids.first().copied())
+                .map(|organizer_id| RevenueShare {
+                    organizer_id,
+                    share: WHOLE_SHARE,
+                })
+                .into_iter()
+                .collect(),
+        })
+}
+
+// Amounts of each share, what rounding leaves over goes to the first
+fn split_amount(split: &RevenueSplit, amount: u64) -> Vec<(u64, u64)> {
+    let mut amounts: Vec<(u64, u64)> = split
+        .shares
+        .iter()
+        .map(|share| {
+            let part = amount as u128 * share.share as u128 / WHOLE_SHARE as u128;
+            (share.organizer_id, part as u64)
+        })
+        .collect();
+    let rest = amount - amounts.iter().map(|(_, part)| part).sum::<u64>();
+    if let Some((_, part)) = amounts.first_mut() {
+        *part += rest;
+    }
+    amounts
+}
+
+fn event_balance(event_id: u64) -> EventBalance {
+    BALANCE_STORAGE
+        .with(|balances| balances.borrow().get(&event_id))
+        .unwrap_or(EventBalance {
+            event_id,
+            balances: vec![],
+        })
+}
+
+fn store_balance(balance: &EventBalance) {
+    BALANCE_STORAGE.with(|balances| {
+        balances
+            .borrow_mut()
+            .insert(balance.event_id, balance.clone())
+    });
+}
+
+fn ledger_balance(balance: &mut EventBalance, ledger: Principal) -> &mut LedgerBalance {
+    let index = match balance.balances.iter().position(|b| b.ledger == ledger) {
+        Some(index) => index,
+        None => {
+            balance.balances.push(LedgerBalance {
+                ledger,
+                collected: 0,
+                withdrawn: 0,
+            });
+            balance.balances.len() - 1
+        }
+    };
+    &mut balance.balances[index]
+}
+
+// Put back the share of a transfer that failed
+fn restore(event_id: u64, ledger: Principal, amount: u64) {
+    let mut balance = event_balance(event_id);
+    let ledger_balance = ledger_balance(&mut balance, ledger);
+    ledger_balance.withdrawn = ledger_balance.withdrawn.saturating_sub(amount);
+    store_balance(&balance);
+}
+
+// Credit the events of the tickets a payment bought with what the ledger took, split by the
+// tickets' prices with their add-ons. A purchase with nothing but free tickets goes to the first ticket's event.
+pub(crate) fn record_collected(ledger: Principal, amount: u64, ticket_ids: &[u64]) {
+    let tickets: Vec<(u64, u64)> = ticket_ids
+        .iter()
+        .filter_map(_get_ticket)
+        .map(|ticket| {
+            let price = ticket.price + addons::ticket_addons_total(&ticket);
+            (ticket.event_id, price)
+        })
+        .collect();
+    let Some((first_event_id, _)) = tickets.first().copied() else {
+        return;
+    };
+    let total: u64 = tickets.iter().map(|(_, price)| price).sum();
+    let mut parts: Vec<(u64, u64)> = vec![];
+    for (event_id, price) in tickets {
+        let part = match total {
+            0 => 0,
+            _ => (amount as u128 * price as u128 / total as u128) as u64,
+        };
+        match parts.iter_mut().find(|(id, _)| *id == event_id) {
+            Some((_, sum)) => *sum += part,
+            None => parts.push((event_id, part)),
+        }
+    }
+    let rest = amount - parts.iter().map(|(_, part)| part).sum::<u64>();
+    if let Some((_, part)) = parts.iter_mut().find(|(id, _)| *id == first_event_id) {
+        *part += rest;
+    }
+
+    for (event_id, part) in parts.into_iter().filter(|(_, part)| *part > 0) {
+        let mut balance = event_balance(event_id);
+        ledger_balance(&mut balance, ledger).collected += part;
+        store_balance(&balance);
+    }
+}
+
+// Whether an event took in revenue its organizers haven't withdrawn yet
+pub(crate) fn has_balance(event_id: u64) -> bool {
+    event_balance(event_id)
+        .balances
+        .iter()
+        .any(|balance| balance.collected > balance.withdrawn)
+}
+
+// Drop the split and balance of a purged event, its payouts stay on the ledger
+pub(crate) fn remove_event_payouts(event_id: u64) {
+    SPLIT_STORAGE.with(|splits| splits.borrow_mut().remove(&event_id));
+    BALANCE_STORAGE.with(|balances| balances.borrow_mut().remove(&event_id));
+}
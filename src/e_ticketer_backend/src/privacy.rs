@@ -0,0 +1,191 @@
+use crate::attendance::{self, AttendanceProof, AttendanceRecord};
+use crate::cancellations::{self, CancellationRefund};
+use crate::clock::time;
+use crate::credits::{self, CreditBalance};
+use crate::display::{self, DisplayPreferences};
+use crate::disputes::{self, Dispute};
+use crate::donations::{self, DonationReceipt};
+use crate::history::{self, EventChange};
+use crate::memberships::Membership;
+use crate::orders::{self, Order};
+use crate::passes::{self, Pass};
+use crate::payments::{self, Payment};
+use crate::ratelimit::rate_limit;
+use crate::registrations::{self, Registration};
+use crate::resale::{self, ResaleSale};
+use crate::support::{self, SupportAction};
+use crate::{
+    _get_ticket, _get_user, attendee_notes, caller_is_admin, credentials, emails, favorites, icrc7,
+    legal_holds, memberships, reviews, store_ticket, store_user, Error, Memory, Ticket, User,
+    ID_COUNTER, MEMORY_MANAGER,
+};
+use candid::{Decode, Encode, Principal};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use std::{borrow::Cow, cell::RefCell};
+
+// Name an erased user is left with
+const ERASED_NAME: &str = "Erased user";
+
+// Define a struct for everything stored about a user, as handed to them on request
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub struct UserDataExport {
+    user: User,
+    wallet: Option<Principal>,
+    tickets: Vec<Ticket>,
+    orders: Vec<Order>,
+    payments: Vec<Payment>,
+    credit: Option<CreditBalance>,
+    donations: Vec<DonationReceipt>,
+    resales: Vec<ResaleSale>,
+    cancellation_refunds: Vec<CancellationRefund>,
+    disputes: Vec<Dispute>,
+    attendance_proofs: Vec<AttendanceProof>,
+    attendance_records: Vec<AttendanceRecord>,
+    memberships: Vec<Membership>,
+    passes: Vec<Pass>,
+    // What admins did on the user's behalf
+    support_actions: Vec<SupportAction>,
+    display_preferences: Option<DisplayPreferences>,
+    presale_registrations: Vec<Registration>,
+    // Changes the user's wallet made to events, from the events' change logs
+    event_changes: Vec<EventChange>,
+    exported_at: u64,
+}
+
+// Define a struct for an entry of the erasure log
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct ErasureRecord {
+    id: u64,
+    user_id: u64,
+    // Wallet that asked for the erasure
+    requested_by: Principal,
+    // Tickets kept on the user record, so organizers can still account for them
+    tickets: u64,
+    erased_at: u64,
+}
+
+impl Storable for ErasureRecord {
+    // Conversion to bytes
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    // Conversion from bytes
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for ErasureRecord {
+    const MAX_SIZE: u32 = 256;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    static ERASURE_LOG: RefCell<StableBTreeMap<u64, ErasureRecord, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(82)))
+    ));
+}
+
+// Everything stored about the user whose wallet is calling
+#[ic_cdk::query]
+fn export_my_data() -> Result<UserDataExport, Error> {
+    let caller = ic_cdk::caller();
+    let user = caller_user()?;
+    let user_id = user.id;
+    Ok(UserDataExport {
+        wallet: Some(caller),
+        tickets: user.ticket_ids.iter().filter_map(_get_ticket).collect(),
+        orders: orders::get_user_orders(user_id),
+        payments: payments::get_user_payments(user_id),
+        credit: credits::get_user_credit(user_id).ok(),
+        donations: donations::get_user_donations(user_id).unwrap_or_default(),
+        resales: resale::get_user_resales(user_id),
+        cancellation_refunds: cancellations::get_user_refunds(user_id),
+        disputes: disputes::user_disputes(user_id),
+        attendance_proofs: attendance::get_user_attendance_proofs(user_id),
+        attendance_records: attendance::user_attendance_records(user_id),
+        memberships: memberships::get_user_memberships(user_id),
+        passes: passes::get_user_passes(user_id),
+        support_actions: support::user_actions(user_id),
+        display_preferences: display::user_preferences(user_id),
+        presale_registrations: registrations::get_user_registrations(user_id),
+        event_changes: history::changes_by(caller),
+        user,
+        exported_at: time(),
+    })
+}
+
+// Strip the calling user's record of personal data. The record and its ticket ids stay, so
+// organizers' sales and statements still add up, while the name, email, password, sessions,
+// wallet, display preferences, pre-sale registrations, ticket details, reviews, what the user
+// wrote in disputes and organizers' notes on the user go.
+#[ic_cdk::update(guard = "rate_limit")]
+fn erase_my_data() -> Result<ErasureRecord, Error> {
+    let caller = ic_cdk::caller();
+    let mut user = caller_user()?;
+    let user_id = user.id;
+    legal_holds::check_user(&user)?;
+
+    for mut ticket in user.ticket_ids.iter().filter_map(_get_ticket) {
+        if ticket.metadata.is_some() {
+            ticket.metadata = None;
+            ticket.updated_at = Some(time());
+            store_ticket(&mut ticket);
+        }
+    }
+    for event_id in &user.event_ids {
+        attendee_notes::remove_attendee_notes(*event_id, user_id);
+    }
+    reviews::remove_user_reviews(&user.event_ids, user_id);
+    disputes::erase_user_disputes(user_id);
+    attendance::erase_user_attendance(user_id);
+    favorites::remove_user_favorites(user_id);
+    memberships::remove_user_memberships(user_id);
+    display::remove_user_preferences(user_id);
+    registrations::remove_user_registrations(user_id);
+    credentials::remove_user_sessions(user_id);
+    credentials::remove_user_credentials(user_id);
+    icrc7::remove_user_wallet(user_id);
+    emails::unindex_user(&user);
+
+    user.name = ERASED_NAME.to_string();
+    user.email = String::new();
+    user.updated_at = Some(time());
+    store_user(&mut user);
+
+    // Increment the global ID counter to get a new ID for the erasure
+    let id = ID_COUNTER
+        .with(|counter| {
+            let current_id = *counter.borrow().get();
+            counter.borrow_mut().set(current_id + 1)
+        })
+        .expect("Cannot increment Ids");
+    let record = ErasureRecord {
+        id,
+        user_id,
+        requested_by: caller,
+        tickets: user.ticket_ids.len() as u64,
+        erased_at: time(),
+    };
+    ERASURE_LOG.with(|log| log.borrow_mut().insert(id, record.clone()));
+    Ok(record)
+}
+
+#[ic_cdk::query(guard = "caller_is_admin")]
+fn get_erasure_log() -> Vec<ErasureRecord> {
+    ERASURE_LOG.with(|log| log.borrow().iter().map(|(_, record)| record).collect())
+}
+
+// The user the caller's wallet is linked to, which proves the caller is that user
+pub(crate) fn caller_user() -> Result<User, Error> {
+    let caller = ic_cdk::caller();
+    let user_id = icrc7::wallet_user(caller).ok_or(Error::Unauthorized {
+        msg: format!(
+            "wallet {} is not linked to a user, link it with 'link_wallet' first",
+            caller
+        ),
+    })?;
+    _get_user(&user_id).ok_or(Error::not_found("user", user_id))
+}
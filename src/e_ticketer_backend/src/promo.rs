@@ -0,0 +1,335 @@
+use crate::clock::time;
+use crate::ratelimit::rate_limit;
+use crate::tiers::_get_tier;
+use crate::{_get_event, organizers, Error, Memory, StringKey, MEMORY_MANAGER};
+use candid::{Decode, Encode};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use std::{borrow::Cow, cell::RefCell};
+
+// Longest promo code accepted, well below the 'StringKey' bound
+const MAX_CODE_LEN: usize = 64;
+
+// Define an enum for the discount granted by a promo code
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub enum Discount {
+    Percent { percent: u8 },
+    Fixed { amount: u64 },
+}
+
+// Define a struct for the 'PromoCode'
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct PromoCode {
+    code: String,
+    event_id: u64,
+    discount: Discount,
+    max_uses: u64,
+    remaining_uses: u64,
+    expires_at: Option<u64>,
+    // An empty list makes the code valid for every tier
+    tier_ids: Vec<u64>,
+    created_at: u64,
+    updated_at: Option<u64>,
+}
+
+// Define a struct for the payload used to create and update promo codes
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub struct PromoCodePayload {
+    code: String,
+    discount: Discount,
+    max_uses: u64,
+    expires_at: Option<u64>,
+    tier_ids: Vec<u64>,
+}
+
+impl Storable for PromoCode {
+    // Conversion to bytes
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    // Conversion from bytes
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for PromoCode {
+    const MAX_SIZE: u32 = 1024;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    // Promo codes keyed by (event id, code)
+    static PROMO_STORAGE: RefCell<StableBTreeMap<(u64, StringKey), PromoCode, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(9)))
+    ));
+}
+
+// Helper function to get a promo code of an event
+fn _get_promo_code(event_id: u64, code: &str) -> Option<PromoCode> {
+    if code.len() > MAX_CODE_LEN {
+        return None;
+    }
+    PROMO_STORAGE.with(|codes| codes.borrow().get(&(event_id, StringKey(code.to_string()))))
+}
+
+// Helper function returning the key range covering all promo codes of an event
+fn event_promo_range(event_id: u64) -> std::ops::Range<(u64, StringKey)> {
+    (event_id, StringKey::default())..(event_id + 1, StringKey::default())
+}
+
+// Helper function to check a promo payload before it is stored
+fn validate_promo_payload(event_id: u64, payload: &PromoCodePayload) -> Result<(), Error> {
+    if payload.code.is_empty() || payload.code.len() > MAX_CODE_LEN {
+        return Err(Error::ValidationFailed {
+            msg: format!("promo code must be 1 to {} bytes long", MAX_CODE_LEN),
+        });
+    }
+    if let Discount::Percent { percent } = payload.discount {
+        if percent > 100 {
+            return Err(Error::ValidationFailed {
+                msg: format!("discount of {}% is above 100%", percent),
+            });
+        }
+    }
+    for tier_id in &payload.tier_ids {
+        _get_tier(event_id, *tier_id).ok_or(Error::NotFound {
+            entity: "tier".to_string(),
+            id: tier_id.to_string(),
+            msg: format!(
+                "tier id:{} does not exist for event id:{}",
+                tier_id, event_id
+            ),
+        })?;
+    }
+    Ok(())
+}
+
+#[ic_cdk::query]
+fn get_event_promo_codes(event_id: u64) -> Result<Vec<PromoCode>, Error> {
+    // Make sure the event exists, or return a NotFound error if not found
+    _get_event(&event_id).ok_or(Error::not_found("event", event_id))?;
+
+    Ok(PROMO_STORAGE.with(|codes| {
+        codes
+            .borrow()
+            .range(event_promo_range(event_id))
+            .map(|(_, promo)| promo)
+            .collect()
+    }))
+}
+
+#[ic_cdk::update(guard = "rate_limit")]
+pub(crate) fn create_promo_code(
+    event_id: u64,
+    payload: PromoCodePayload,
+) -> Result<PromoCode, Error> {
+    // Make sure the event exists and the caller organizes it
+    organizers::authorize_event(event_id)?;
+    validate_promo_payload(event_id, &payload)?;
+
+    if _get_promo_code(event_id, &payload.code).is_some() {
+        return Err(Error::InternalError {
+            msg: format!(
+                "promo code {} already exists for event id:{}",
+                payload.code, event_id
+            ),
+            ticket_id: None,
+        });
+    }
+
+    let promo = PromoCode {
+        code: payload.code.clone(),
+        event_id,
+        discount: payload.discount,
+        max_uses: payload.max_uses,
+        remaining_uses: payload.max_uses,
+        expires_at: payload.expires_at,
+        tier_ids: payload.tier_ids,
+        created_at: time(),
+        updated_at: None,
+    };
+
+    PROMO_STORAGE.with(|codes| {
+        codes
+            .borrow_mut()
+            .insert((event_id, StringKey(payload.code)), promo.clone())
+    });
+
+    Ok(promo)
+}
+
+#[ic_cdk::update(guard = "rate_limit")]
+fn update_promo_code(event_id: u64, payload: PromoCodePayload) -> Result<PromoCode, Error> {
+    organizers::authorize_event(event_id)?;
+
+    // Retrieve the existing promo code, or return a NotFound error if not found
+    let promo = _get_promo_code(event_id, &payload.code).ok_or(Error::NotFound {
+        entity: "promo code".to_string(),
+        id: payload.code.to_string(),
+        msg: format!(
+            "promo code {} does not exist for event id:{}",
+            payload.code, event_id
+        ),
+    })?;
+    validate_promo_payload(event_id, &payload)?;
+
+    // Uses already redeemed stay redeemed when the limit changes
+    let used = promo.max_uses.saturating_sub(promo.remaining_uses);
+    let updated_promo = PromoCode {
+        code: promo.code,
+        event_id,
+        discount: payload.discount,
+        max_uses: payload.max_uses,
+        remaining_uses: payload.max_uses.saturating_sub(used),
+        expires_at: payload.expires_at,
+        tier_ids: payload.tier_ids,
+        created_at: promo.created_at,
+        updated_at: Some(time()),
+    };
+
+    PROMO_STORAGE.with(|codes| {
+        codes
+            .borrow_mut()
+            .insert((event_id, StringKey(payload.code)), updated_promo.clone())
+    });
+
+    Ok(updated_promo)
+}
+
+#[ic_cdk::update(guard = "rate_limit")]
+fn delete_promo_code(event_id: u64, code: String) -> Result<String, Error> {
+    organizers::authorize_event(event_id)?;
+
+    // Check if the promo code exists, or return a NotFound error if not found
+    _get_promo_code(event_id, &code).ok_or(Error::NotFound {
+        entity: "promo code".to_string(),
+        id: code.to_string(),
+        msg: format!(
+            "promo code {} does not exist for event id:{}",
+            code, event_id
+        ),
+    })?;
+
+    PROMO_STORAGE.with(|codes| {
+        codes
+            .borrow_mut()
+            .remove(&(event_id, StringKey(code.clone())))
+    });
+
+    Ok(format!("promo code {} deleted", code))
+}
+
+// Validate a promo code for a purchase and return it, or a message explaining the rejection
+pub(crate) fn check_promo_code(
+    event_id: u64,
+    code: &str,
+    tier_id: Option<u64>,
+) -> Result<PromoCode, String> {
+    let promo = _get_promo_code(event_id, code).ok_or(format!(
+        "promo code {} is not valid for event id:{}",
+        code, event_id
+    ))?;
+
+    if promo
+        .expires_at
+        .is_some_and(|expires_at| expires_at <= time())
+    {
+        return Err(format!("promo code {} has expired", code));
+    }
+    if promo.remaining_uses == 0 {
+        return Err(format!("promo code {} has no uses left", code));
+    }
+    if !promo.tier_ids.is_empty() && !tier_id.is_some_and(|id| promo.tier_ids.contains(&id)) {
+        return Err(format!("promo code {} does not apply to this tier", code));
+    }
+
+    Ok(promo)
+}
+
+// Apply the discount of a promo code to a price
+pub(crate) fn apply_discount(promo: &PromoCode, price: u64) -> u64 {
+    discounted(&promo.discount, price)
+}
+
+pub(crate) fn discounted(discount: &Discount, price: u64) -> u64 {
+    match *discount {
+        Discount::Percent { percent } => price - price * percent as u64 / 100,
+        Discount::Fixed { amount } => price.saturating_sub(amount),
+    }
+}
+
+// Helper function returning the number of redemptions left on a promo code
+pub(crate) fn remaining_uses(promo: &PromoCode) -> u64 {
+    promo.remaining_uses
+}
+
+// Use up one redemption of a promo code
+pub(crate) fn consume_promo_code(mut promo: PromoCode) {
+    promo.remaining_uses -= 1;
+    let key = (promo.event_id, StringKey(promo.code.clone()));
+    PROMO_STORAGE.with(|codes| codes.borrow_mut().insert(key, promo));
+}
+
+// Promo codes of an event as they were configured, for a template. Tiers are given by their
+// position among the event's tiers, so the codes can follow the tiers to another event.
+pub(crate) fn promo_payloads(event_id: u64, tier_ids: &[u64]) -> Vec<PromoCodePayload> {
+    PROMO_STORAGE.with(|codes| {
+        codes
+            .borrow()
+            .range(event_promo_range(event_id))
+            .map(|(_, promo)| PromoCodePayload {
+                code: promo.code,
+                discount: promo.discount,
+                max_uses: promo.max_uses,
+                expires_at: promo.expires_at,
+                tier_ids: promo
+                    .tier_ids
+                    .iter()
+                    .filter_map(|id| tier_ids.iter().position(|tier_id| tier_id == id))
+                    .map(|position| position as u64)
+                    .collect(),
+            })
+            .collect()
+    })
+}
+
+// Point the tier positions of a promo code from a template at the tiers of the new event
+pub(crate) fn with_tier_ids(
+    mut payload: PromoCodePayload,
+    tier_ids: &[u64],
+) -> Result<PromoCodePayload, Error> {
+    payload.tier_ids = payload
+        .tier_ids
+        .iter()
+        .map(|position| {
+            tier_ids
+                .get(*position as usize)
+                .copied()
+                .ok_or(Error::ValidationFailed {
+                    msg: format!(
+                        "promo code {} refers to tier {} of a template with {} tiers",
+                        payload.code,
+                        position,
+                        tier_ids.len()
+                    ),
+                })
+        })
+        .collect::<Result<_, _>>()?;
+    Ok(payload)
+}
+
+// Drop the promo codes of a deleted event
+pub(crate) fn remove_event_promo_codes(event_id: u64) {
+    PROMO_STORAGE.with(|codes| {
+        let mut codes = codes.borrow_mut();
+        let keys: Vec<(u64, StringKey)> = codes
+            .range(event_promo_range(event_id))
+            .map(|(key, _)| key)
+            .collect();
+        for key in keys {
+            codes.remove(&key);
+        }
+    });
+}
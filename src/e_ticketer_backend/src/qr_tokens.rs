@@ -0,0 +1,269 @@
+use crate::clock::time;
+use crate::ratelimit::rate_limit;
+use crate::{
+    _get_ticket, checkin, entropy, organizers, webhooks, Error, Memory, Ticket, MEMORY_MANAGER,
+};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use candid::{Decode, Encode};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, Cell, StableBTreeMap, Storable};
+use serde_bytes::ByteBuf;
+use sha2::{Digest, Sha256};
+use std::{borrow::Cow, cell::RefCell};
+
+// Tokens start with this, the digit being the token format's version
+pub(crate) const TOKEN_PREFIX: &str = "ETQ1.";
+// Ticket id, event id, key version and nonce
+const BODY_LEN: usize = 8 + 8 + 4 + 8;
+// Truncated HMAC-SHA256, keeps the QR code small while staying out of reach of guessing
+const MAC_LEN: usize = 16;
+
+// Define a struct for the signed QR tokens of an event, events without one show ticket codes
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct QrTokenConfig {
+    event_id: u64,
+    // Bumped on every rotation, tokens signed with an earlier key are turned down
+    key_version: u32,
+    created_at: u64,
+    rotated_at: Option<u64>,
+}
+
+// Define a struct for the key scanners verify an event's tokens with while offline
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub struct QrVerificationKey {
+    event_id: u64,
+    key_version: u32,
+    // HMAC-SHA256 key, the first 16 bytes of the MAC over the token's prefix and body are sent
+    key: ByteBuf,
+}
+
+// Define a struct for the canister's secret the event keys are derived from
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct QrSecret {
+    secret: ByteBuf,
+}
+
+impl Storable for QrTokenConfig {
+    // Conversion to bytes
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    // Conversion from bytes
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for QrTokenConfig {
+    const MAX_SIZE: u32 = 128;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl Storable for QrSecret {
+    // Conversion to bytes
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    // Conversion from bytes
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+thread_local! {
+    // Token settings keyed by event id
+    static CONFIG_STORAGE: RefCell<StableBTreeMap<u64, QrTokenConfig, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(132)))
+    ));
+
+    // Drawn when the first event turns tokens on, never handed out
+    static SECRET: RefCell<Cell<QrSecret, Memory>> = RefCell::new(
+        Cell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(133))),
+            QrSecret::default(),
+        )
+        .expect("Cannot create the QR token secret")
+    );
+}
+
+#[ic_cdk::query]
+fn get_qr_token_config(event_id: u64) -> Result<QrTokenConfig, Error> {
+    organizers::authorize_event(event_id)?;
+    config(event_id).ok_or(not_enabled(event_id))
+}
+
+// Sign the QR codes of an event's tickets from now on. Calling it again rotates the key, which
+// voids the tokens handed out so far: holders fetch their pass again and scanners the new key.
+#[ic_cdk::update(guard = "rate_limit")]
+fn enable_qr_tokens(event_id: u64) -> Result<QrTokenConfig, Error> {
+    organizers::authorize_event(event_id)?;
+    ensure_secret().map_err(|msg| Error::ValidationFailed { msg })?;
+
+    let now = time();
+    let config = match config(event_id) {
+        Some(existing) => QrTokenConfig {
+            key_version: existing.key_version + 1,
+            rotated_at: Some(now),
+            ..existing
+        },
+        None => QrTokenConfig {
+            event_id,
+            key_version: 1,
+            created_at: now,
+            rotated_at: None,
+        },
+    };
+    CONFIG_STORAGE.with(|configs| configs.borrow_mut().insert(event_id, config.clone()));
+
+    Ok(config)
+}
+
+// Go back to the tickets' codes, which are accepted at check-in again
+#[ic_cdk::update(guard = "rate_limit")]
+fn disable_qr_tokens(event_id: u64) -> Result<String, Error> {
+    organizers::authorize_event(event_id)?;
+    CONFIG_STORAGE
+        .with(|configs| configs.borrow_mut().remove(&event_id))
+        .ok_or(not_enabled(event_id))?;
+    Ok(format!("QR tokens of event id: {} disabled", event_id))
+}
+
+// The current key of an event's tokens, for its scanner devices and organizers
+#[ic_cdk::query]
+fn get_qr_verification_key(event_id: u64) -> Result<QrVerificationKey, Error> {
+    if checkin::caller_device(event_id).is_err() {
+        organizers::authorize_event(event_id)?;
+    }
+    let config = config(event_id).ok_or(not_enabled(event_id))?;
+    Ok(QrVerificationKey {
+        event_id,
+        key_version: config.key_version,
+        key: ByteBuf::from(event_key(event_id, config.key_version).to_vec()),
+    })
+}
+
+// The QR payload of a ticket: a signed token if its event has them, or None to use its code
+pub(crate) fn ticket_token(ticket: &Ticket) -> Option<String> {
+    let config = config(ticket.event_id)?;
+    let mut body = Vec::with_capacity(BODY_LEN);
+    body.extend_from_slice(&ticket.id.to_be_bytes());
+    body.extend_from_slice(&ticket.event_id.to_be_bytes());
+    body.extend_from_slice(&config.key_version.to_be_bytes());
+    body.extend_from_slice(&nonce(ticket, config.key_version));
+
+    let mac = mac(&event_key(ticket.event_id, config.key_version), &body);
+    Some(format!(
+        "{}{}.{}",
+        TOKEN_PREFIX,
+        URL_SAFE_NO_PAD.encode(&body),
+        URL_SAFE_NO_PAD.encode(mac)
+    ))
+}
+
+// The ticket a scanned token was issued for, checking it against the current key and holder
+pub(crate) fn verify_token(token: &str) -> Result<Ticket, String> {
+    let invalid = || "QR code is not a valid ticket token".to_string();
+    let (body, signature) = token
+        .strip_prefix(TOKEN_PREFIX)
+        .and_then(|rest| rest.split_once('.'))
+        .ok_or_else(invalid)?;
+    let body = URL_SAFE_NO_PAD.decode(body).map_err(|_| invalid())?;
+    let signature = URL_SAFE_NO_PAD.decode(signature).map_err(|_| invalid())?;
+    if body.len() != BODY_LEN {
+        return Err(invalid());
+    }
+    let ticket_id = u64::from_be_bytes(body[0..8].try_into().unwrap());
+    let event_id = u64::from_be_bytes(body[8..16].try_into().unwrap());
+    let key_version = u32::from_be_bytes(body[16..20].try_into().unwrap());
+
+    let config = config(event_id).ok_or(format!(
+        "event id:{} does not sign its tickets' QR codes",
+        event_id
+    ))?;
+    if key_version != config.key_version {
+        return Err(format!(
+            "QR code was signed with a retired key of event id:{}, fetch the ticket again",
+            event_id
+        ));
+    }
+    if signature != mac(&event_key(event_id, key_version), &body) {
+        return Err(invalid());
+    }
+    // A ticket that changed hands gets a new nonce, the previous holder's code stops working
+    let ticket =
+        _get_ticket(&ticket_id).ok_or(format!("ticket id:{} does not exist", ticket_id))?;
+    if ticket.event_id != event_id || body[20..] != nonce(&ticket, key_version) {
+        return Err(format!(
+            "QR code of ticket id:{} was reissued, fetch the ticket again",
+            ticket_id
+        ));
+    }
+    Ok(ticket)
+}
+
+// Whether an event only admits signed tokens at check-in
+pub(crate) fn is_enabled(event_id: u64) -> bool {
+    CONFIG_STORAGE.with(|configs| configs.borrow().contains_key(&event_id))
+}
+
+// Drop the token settings of a deleted event
+pub(crate) fn remove_event_qr_tokens(event_id: u64) {
+    CONFIG_STORAGE.with(|configs| configs.borrow_mut().remove(&event_id));
+}
+
+fn config(event_id: u64) -> Option<QrTokenConfig> {
+    CONFIG_STORAGE.with(|configs| configs.borrow().get(&event_id))
+}
+
+fn not_enabled(event_id: u64) -> Error {
+    Error::NotFound {
+        entity: "QR token config".to_string(),
+        id: event_id.to_string(),
+        msg: format!("event id:{} does not sign its tickets' QR codes", event_id),
+    }
+}
+
+// Draw the secret if it isn't yet, it would only depend on the time before the seed is drawn
+fn ensure_secret() -> Result<(), String> {
+    if !SECRET.with(|secret| secret.borrow().get().secret.is_empty()) {
+        return Ok(());
+    }
+    if !entropy::is_seeded() {
+        return Err("the canister is still drawing its randomness, try again shortly".to_string());
+    }
+    let mut hasher = entropy::seeded_hasher();
+    hasher.update(b"qr-token-secret");
+    let secret = QrSecret {
+        secret: ByteBuf::from(hasher.finalize().to_vec()),
+    };
+    SECRET
+        .with(|cell| cell.borrow_mut().set(secret))
+        .expect("Cannot store the QR token secret");
+    Ok(())
+}
+
+// Key of an event's tokens, a new one for every rotation
+fn event_key(event_id: u64, key_version: u32) -> [u8; 32] {
+    let secret = SECRET.with(|secret| secret.borrow().get().secret.clone());
+    let mut message = b"qr-event-key".to_vec();
+    message.extend_from_slice(&event_id.to_be_bytes());
+    message.extend_from_slice(&key_version.to_be_bytes());
+    webhooks::hmac_sha256(&secret, &message)
+}
+
+// Changes with the key and the holder, so a token doesn't carry over to the ticket's next holder
+fn nonce(ticket: &Ticket, key_version: u32) -> [u8; 8] {
+    let mut hasher = Sha256::new();
+    hasher.update(event_key(ticket.event_id, key_version));
+    hasher.update(b"nonce");
+    hasher.update(ticket.id.to_be_bytes());
+    hasher.update(ticket.user_id.to_be_bytes());
+    hasher.finalize()[..8].try_into().unwrap()
+}
+
+fn mac(key: &[u8], body: &[u8]) -> Vec<u8> {
+    let mut message = TOKEN_PREFIX.as_bytes().to_vec();
+    message.extend_from_slice(body);
+    webhooks::hmac_sha256(key, &message)[..MAC_LEN].to_vec()
+}
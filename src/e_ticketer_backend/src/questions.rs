@@ -0,0 +1,89 @@
+// Upper bound on the options of a choice question and the length of a text answer
+const MAX_CHOICES: usize = 20;
+const MAX_TEXT_LEN: usize = 500;
+
+// Define an enum for the kind of answer a question expects
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub enum QuestionKind {
+    Text,
+    // Rating from 1 to 'max'
+    Rating { max: u8 },
+    // Index into 'options'
+    Choice { options: Vec<String> },
+}
+
+// Define a struct for a custom question asked to attendees
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct Question {
+    pub(crate) prompt: String,
+    pub(crate) kind: QuestionKind,
+    pub(crate) required: bool,
+}
+
+// Define an enum for the answer to a question, 'Skipped' leaves an optional question blank
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub enum Answer {
+    Skipped,
+    Text(String),
+    Rating(u8),
+    Choice(u32),
+}
+
+// Check that a list of questions can be asked
+pub(crate) fn validate_questions(questions: &[Question]) -> Result<(), String> {
+    for (index, question) in questions.iter().enumerate() {
+        if question.prompt.trim().is_empty() {
+            return Err(format!("question {} has no prompt", index + 1));
+        }
+        match &question.kind {
+            QuestionKind::Text => (),
+            QuestionKind::Rating { max } => {
+                if *max < 2 {
+                    return Err(format!(
+                        "question {} needs a rating of at least 2",
+                        index + 1
+                    ));
+                }
+            }
+            QuestionKind::Choice { options } => {
+                if options.len() < 2 || options.len() > MAX_CHOICES {
+                    return Err(format!(
+                        "question {} needs between 2 and {} options",
+                        index + 1,
+                        MAX_CHOICES
+                    ));
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+// Check that the answers match the questions they respond to, in order
+pub(crate) fn validate_answers(questions: &[Question], answers: &[Answer]) -> Result<(), String> {
+    if answers.len() != questions.len() {
+        return Err(format!(
+            "expected {} answers, got {}",
+            questions.len(),
+            answers.len()
+        ));
+    }
+
+    for (index, (question, answer)) in questions.iter().zip(answers).enumerate() {
+        let valid = match (&question.kind, answer) {
+            (_, Answer::Skipped) => !question.required,
+            (QuestionKind::Text, Answer::Text(text)) => {
+                !text.trim().is_empty() && text.len() <= MAX_TEXT_LEN
+            }
+            (QuestionKind::Rating { max }, Answer::Rating(rating)) => (1..=*max).contains(rating),
+            (QuestionKind::Choice { options }, Answer::Choice(choice)) => {
+                (*choice as usize) < options.len()
+            }
+            _ => false,
+        };
+        if !valid {
+            return Err(format!("answer to question {} is invalid", index + 1));
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,301 @@
+use crate::clock::time;
+use crate::{caller_is_admin, Error, Memory, StringKey, MEMORY_MANAGER};
+use candid::{Decode, Encode, Principal};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, Cell, StableBTreeMap, Storable};
+use std::collections::HashMap;
+use std::{borrow::Cow, cell::RefCell};
+
+// Buckets are kept in thousandths of a call, so slow refill rates still add up
+const MILLI: u64 = 1_000;
+const NANOS_PER_MINUTE: u128 = 60 * 1_000_000_000;
+// Principals tracked before idle ones, whose buckets refilled, are dropped
+const MAX_TRACKED_PRINCIPALS: usize = 10_000;
+// Principals listed in the stats, the busiest first
+const MAX_STATS_PRINCIPALS: usize = 50;
+const MAX_REASON_LEN: usize = 256;
+
+// Define a struct for the token bucket every principal gets for its update calls
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct RateLimitSettings {
+    enabled: bool,
+    // Calls a principal can make in a burst
+    capacity: u64,
+    // Calls given back to a principal every minute
+    refill_per_minute: u64,
+}
+
+// Define a struct for the calls of a principal since its bucket was created
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct PrincipalUsage {
+    principal: Principal,
+    // Update calls let through
+    calls: u64,
+    // Calls it can make right now
+    available: u64,
+}
+
+// Define a struct for what the rate limiter is doing. Calls turned away aren't counted, a
+// rejection discards any change the call would make.
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub struct RateLimitStats {
+    settings: RateLimitSettings,
+    tracked_principals: u64,
+    // Principals whose bucket is empty, their next call is rejected
+    throttled: Vec<PrincipalUsage>,
+    busiest: Vec<PrincipalUsage>,
+    denied_principals: u64,
+}
+
+// Define a struct for a principal whose calls are all rejected
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct DeniedPrincipal {
+    principal: Principal,
+    reason: Option<String>,
+    denied_at: u64,
+}
+
+// Tokens of a principal, in thousandths of a call
+struct Bucket {
+    tokens: u64,
+    updated_at: u64,
+    calls: u64,
+}
+
+impl Default for RateLimitSettings {
+    fn default() -> Self {
+        RateLimitSettings {
+            enabled: true,
+            capacity: 60,
+            refill_per_minute: 60,
+        }
+    }
+}
+
+impl Storable for RateLimitSettings {
+    // Conversion to bytes
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    // Conversion from bytes
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl Storable for DeniedPrincipal {
+    // Conversion to bytes
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    // Conversion from bytes
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for DeniedPrincipal {
+    const MAX_SIZE: u32 = 512;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl Bucket {
+    // Tokens after refilling up to now, capped at the capacity
+    fn refilled(&self, settings: &RateLimitSettings, now: u64) -> u64 {
+        let elapsed = now.saturating_sub(self.updated_at) as u128;
+        let refill = elapsed * (settings.refill_per_minute * MILLI) as u128 / NANOS_PER_MINUTE;
+        (self.tokens as u128 + refill).min((settings.capacity * MILLI) as u128) as u64
+    }
+}
+
+thread_local! {
+    static SETTINGS: RefCell<Cell<RateLimitSettings, Memory>> = RefCell::new(
+        Cell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(73))),
+            RateLimitSettings::default(),
+        )
+        .expect("Cannot create the rate limit settings")
+    );
+
+    // Denied principals keyed by their text form
+    static DENYLIST: RefCell<StableBTreeMap<StringKey, DeniedPrincipal, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(74)))
+    ));
+
+    // Kept on the heap, buckets start full again after an upgrade
+    static BUCKETS: RefCell<HashMap<Principal, Bucket>> = RefCell::new(HashMap::new());
+}
+
+#[ic_cdk::query(guard = "caller_is_admin")]
+fn get_rate_limit_stats() -> RateLimitStats {
+    let settings = settings();
+    let now = time();
+    let mut usage: Vec<PrincipalUsage> = BUCKETS.with(|buckets| {
+        buckets
+            .borrow()
+            .iter()
+            .map(|(principal, bucket)| PrincipalUsage {
+                principal: *principal,
+                calls: bucket.calls,
+                available: bucket.refilled(&settings, now) / MILLI,
+            })
+            .collect()
+    });
+    usage.sort_by_key(|usage| std::cmp::Reverse(usage.calls));
+
+    RateLimitStats {
+        tracked_principals: usage.len() as u64,
+        throttled: usage
+            .iter()
+            .filter(|usage| usage.available == 0)
+            .take(MAX_STATS_PRINCIPALS)
+            .cloned()
+            .collect(),
+        busiest: usage.into_iter().take(MAX_STATS_PRINCIPALS).collect(),
+        denied_principals: DENYLIST.with(|denylist| denylist.borrow().len()),
+        settings,
+    }
+}
+
+#[ic_cdk::update(guard = "caller_is_admin")]
+fn set_rate_limit(settings: RateLimitSettings) -> Result<RateLimitSettings, Error> {
+    if settings.capacity == 0 || settings.refill_per_minute == 0 {
+        return Err(Error::ValidationFailed {
+            msg: "capacity and refill per minute must be at least 1".to_string(),
+        });
+    }
+    SETTINGS
+        .with(|cell| cell.borrow_mut().set(settings.clone()))
+        .map_err(|_| Error::InternalError {
+            msg: "rate limit settings could not be saved".to_string(),
+            ticket_id: None,
+        })?;
+    Ok(settings)
+}
+
+#[ic_cdk::query(guard = "caller_is_admin")]
+fn get_denied_principals() -> Vec<DeniedPrincipal> {
+    DENYLIST.with(|denylist| denylist.borrow().iter().map(|(_, denied)| denied).collect())
+}
+
+#[ic_cdk::update(guard = "caller_is_admin")]
+fn deny_principal(principal: Principal, reason: Option<String>) -> Result<DeniedPrincipal, Error> {
+    if principal == Principal::anonymous() || ic_cdk::api::is_controller(&principal) {
+        return Err(Error::ValidationFailed {
+            msg: format!("principal {} can't be denied", principal),
+        });
+    }
+    if reason
+        .as_ref()
+        .is_some_and(|reason| reason.len() > MAX_REASON_LEN)
+    {
+        return Err(Error::ValidationFailed {
+            msg: format!("reason can be at most {} bytes", MAX_REASON_LEN),
+        });
+    }
+    let denied = DeniedPrincipal {
+        principal,
+        reason,
+        denied_at: time(),
+    };
+    DENYLIST.with(|denylist| {
+        denylist
+            .borrow_mut()
+            .insert(StringKey(principal.to_text()), denied.clone())
+    });
+    Ok(denied)
+}
+
+#[ic_cdk::update(guard = "caller_is_admin")]
+fn allow_principal(principal: Principal) -> Result<String, Error> {
+    DENYLIST
+        .with(|denylist| {
+            denylist
+                .borrow_mut()
+                .remove(&StringKey(principal.to_text()))
+        })
+        .ok_or(Error::NotFound {
+            entity: "denial".to_string(),
+            id: principal.to_string(),
+            msg: format!("principal {} is not denied", principal),
+        })?;
+    Ok(format!("principal {} allowed again", principal))
+}
+
+fn settings() -> RateLimitSettings {
+    SETTINGS.with(|cell| cell.borrow().get().clone())
+}
+
+// Whether the caller may make an update call now, for 'inspect_message'. Nothing is taken
+// from the bucket, changes made while inspecting are discarded.
+pub(crate) fn check_caller() -> Result<(), String> {
+    let caller = ic_cdk::caller();
+    check_denied(&caller)?;
+    let settings = settings();
+    if !settings.enabled || caller_is_admin().is_ok() {
+        return Ok(());
+    }
+    let available = BUCKETS.with(|buckets| {
+        buckets
+            .borrow()
+            .get(&caller)
+            .map_or(settings.capacity * MILLI, |bucket| {
+                bucket.refilled(&settings, time())
+            })
+    });
+    match available >= MILLI {
+        true => Ok(()),
+        false => Err(limited_msg(&settings)),
+    }
+}
+
+// Guard of the update methods, taking a call from the caller's bucket. Calls from other
+// canisters aren't inspected, so they are only held back here.
+pub(crate) fn rate_limit() -> Result<(), String> {
+    let caller = ic_cdk::caller();
+    check_denied(&caller)?;
+    let settings = settings();
+    if !settings.enabled || caller_is_admin().is_ok() {
+        return Ok(());
+    }
+
+    let now = time();
+    BUCKETS.with(|buckets| {
+        let mut buckets = buckets.borrow_mut();
+        if buckets.len() >= MAX_TRACKED_PRINCIPALS && !buckets.contains_key(&caller) {
+            let full = settings.capacity * MILLI;
+            buckets.retain(|_, bucket| bucket.refilled(&settings, now) < full);
+        }
+        let bucket = buckets.entry(caller).or_insert(Bucket {
+            tokens: settings.capacity * MILLI,
+            updated_at: now,
+            calls: 0,
+        });
+        let tokens = bucket.refilled(&settings, now);
+        if tokens < MILLI {
+            return Err(limited_msg(&settings));
+        }
+        bucket.tokens = tokens - MILLI;
+        bucket.updated_at = now;
+        bucket.calls += 1;
+        Ok(())
+    })
+}
+
+fn check_denied(caller: &Principal) -> Result<(), String> {
+    match DENYLIST.with(|denylist| denylist.borrow().contains_key(&StringKey(caller.to_text()))) {
+        true => Err(format!(
+            "principal {} is not allowed to call this canister",
+            caller
+        )),
+        false => Ok(()),
+    }
+}
+
+fn limited_msg(settings: &RateLimitSettings) -> String {
+    format!(
+        "too many calls, at most {} calls a minute are allowed, try again shortly",
+        settings.refill_per_minute
+    )
+}
@@ -0,0 +1,323 @@
+use crate::clock::time;
+use crate::{caller_is_admin, Error, Memory, StringKey, MEMORY_MANAGER};
+use candid::{Decode, Encode, Principal};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use std::time::Duration;
+use std::{borrow::Cow, cell::RefCell};
+
+// Exchange rate canister of the NNS subnet
+const XRC_CANISTER_ID: &str = "uf6dk-hyaaa-aaaaq-qaaaq-cai";
+// Cycles the exchange rate canister charges per request
+const XRC_CYCLES: u128 = 1_000_000_000;
+// How often the rates in use are fetched again
+const REFRESH_INTERVAL: Duration = Duration::from_secs(5 * 60);
+// Rates older than this aren't used for prices, so a stalled refresh can't sell at a stale rate
+pub(crate) const MAX_RATE_AGE: u64 = 30 * 60 * 1_000_000_000;
+// Pairs followed at once, each refresh costs a request
+const MAX_TRACKED_PAIRS: usize = 16;
+// Fiat currencies are counted in hundredths
+const FIAT_DECIMALS: u32 = 2;
+// Tokens prices can be paid in or quoted in, with the decimals of their smallest unit. Any
+// other three letter code is taken for a fiat currency.
+const CRYPTO_ASSETS: [(&str, u32); 4] = [("BTC", 8), ("ICP", 8), ("USDC", 6), ("USDT", 6)];
+
+// Define a struct for a cached rate, the price of one unit of the base asset in the quote asset
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct ExchangeRate {
+    base: String,
+    quote: String,
+    // Scaled by 10^decimals
+    pub(crate) rate: u64,
+    pub(crate) decimals: u32,
+    // Time the rate was observed at, as reported by the exchange rate canister
+    observed_at: u64,
+    fetched_at: u64,
+}
+
+// Define a struct for the rate a price was converted at when it was paid, the price of one
+// unit of the base token in the quote currency
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct QuotedRate {
+    pub(crate) base: String,
+    pub(crate) quote: String,
+    // Scaled by 10^decimals
+    pub(crate) rate: u64,
+    pub(crate) decimals: u32,
+}
+
+// Define an enum for the classes of asset the exchange rate canister knows
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+enum AssetClass {
+    Cryptocurrency,
+    FiatCurrency,
+}
+
+// Define a struct for an asset of the exchange rate canister
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct Asset {
+    symbol: String,
+    class: AssetClass,
+}
+
+// Define a struct for a request to the exchange rate canister
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct GetExchangeRateRequest {
+    base_asset: Asset,
+    quote_asset: Asset,
+    timestamp: Option<u64>,
+}
+
+// Define a struct for the metadata of a rate, only the scale is used
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct ExchangeRateMetadata {
+    decimals: u32,
+}
+
+// Define a struct for a rate returned by the exchange rate canister
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct XrcExchangeRate {
+    timestamp: u64,
+    rate: u64,
+    metadata: ExchangeRateMetadata,
+}
+
+// Define an enum for the errors of the exchange rate canister
+#[derive(candid::CandidType, Serialize, Deserialize)]
+enum ExchangeRateError {
+    AnonymousPrincipalNotAllowed,
+    Pending,
+    CryptoBaseAssetNotFound,
+    CryptoQuoteAssetNotFound,
+    StablecoinRateNotFound,
+    StablecoinRateTooFewRates,
+    StablecoinRateZeroRate,
+    ForexInvalidTimestamp,
+    ForexBaseAssetNotFound,
+    ForexQuoteAssetNotFound,
+    ForexAssetsNotFound,
+    RateLimited,
+    NotEnoughCycles,
+    FailedToAcceptCycles,
+    InconsistentRatesReceived,
+    Other(OtherError),
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct OtherError {
+    code: u32,
+    description: String,
+}
+
+impl Storable for ExchangeRate {
+    // Conversion to bytes
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    // Conversion from bytes
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for ExchangeRate {
+    const MAX_SIZE: u32 = 128;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    // Rates keyed by "BASE/QUOTE", every pair in the cache is refreshed by the timer
+    static RATE_STORAGE: RefCell<StableBTreeMap<StringKey, ExchangeRate, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(75)))
+    ));
+}
+
+#[ic_cdk::query]
+fn get_exchange_rates() -> Vec<ExchangeRate> {
+    RATE_STORAGE.with(|rates| rates.borrow().iter().map(|(_, rate)| rate).collect())
+}
+
+// Fetch every cached pair now instead of waiting for the timer
+#[ic_cdk::update(guard = "caller_is_admin")]
+async fn refresh_exchange_rates() -> Result<Vec<ExchangeRate>, Error> {
+    refresh_all().await;
+    Ok(get_exchange_rates())
+}
+
+// Start following a pair, it is fetched right away and then with every refresh
+pub(crate) fn track_pair(base: &str, quote: &str) -> Result<(), String> {
+    let key = pair_key(base, quote);
+    if RATE_STORAGE.with(|rates| rates.borrow().contains_key(&key)) {
+        return Ok(());
+    }
+    if RATE_STORAGE.with(|rates| rates.borrow().len()) as usize >= MAX_TRACKED_PAIRS {
+        return Err(format!(
+            "at most {} exchange rates can be followed",
+            MAX_TRACKED_PAIRS
+        ));
+    }
+    // Never used for prices until fetched, 'fetched_at' of 0 is always too old
+    let rate = ExchangeRate {
+        base: base.to_string(),
+        quote: quote.to_string(),
+        rate: 0,
+        decimals: 0,
+        observed_at: 0,
+        fetched_at: 0,
+    };
+    RATE_STORAGE.with(|rates| rates.borrow_mut().insert(key, rate.clone()));
+    ic_cdk_timers::set_timer(Duration::ZERO, move || {
+        ic_cdk::spawn(async move {
+            let _ = refresh_rate(rate).await;
+        })
+    });
+    Ok(())
+}
+
+// Rate of a pair if it was fetched recently enough to price with
+pub(crate) fn fresh_rate(base: &str, quote: &str) -> Result<ExchangeRate, String> {
+    RATE_STORAGE
+        .with(|rates| rates.borrow().get(&pair_key(base, quote)))
+        .filter(|rate| rate.rate > 0 && rate.fetched_at + MAX_RATE_AGE > time())
+        .ok_or(format!(
+            "no recent {}/{} exchange rate, try again shortly",
+            base, quote
+        ))
+}
+
+// Amount of the base asset, in units of 10^-base_decimals, that a quote amount in units of
+// 10^-quote_decimals buys, rounded up
+pub(crate) fn convert_to_base(
+    rate: &ExchangeRate,
+    quote_amount: u64,
+    quote_decimals: u32,
+    base_decimals: u32,
+) -> Option<u64> {
+    let numerator = (quote_amount as u128)
+        .checked_mul(10u128.pow(base_decimals))?
+        .checked_mul(10u128.pow(rate.decimals))?;
+    let denominator = (rate.rate as u128).checked_mul(10u128.pow(quote_decimals))?;
+    u64::try_from(numerator.div_ceil(denominator)).ok()
+}
+
+// Amount of the quote currency, in units of 10^-quote_decimals, that a base amount in units of
+// 10^-base_decimals is worth, rounded down
+pub(crate) fn convert_from_base(
+    rate: &ExchangeRate,
+    base_amount: u64,
+    base_decimals: u32,
+    quote_decimals: u32,
+) -> Option<u64> {
+    let numerator = (base_amount as u128)
+        .checked_mul(rate.rate as u128)?
+        .checked_mul(10u128.pow(quote_decimals))?;
+    let denominator = 10u128
+        .checked_pow(rate.decimals)?
+        .checked_mul(10u128.pow(base_decimals))?;
+    u64::try_from(numerator / denominator).ok()
+}
+
+// Convert an amount between two currencies through the token they are both followed against,
+// the way prices are converted at purchase. Pairs without a recent rate start being followed,
+// so the conversion goes through once their first rate is in.
+pub(crate) fn convert_via(amount: u64, from: &str, to: &str, token: &str) -> Result<u64, String> {
+    if from == to {
+        return Ok(amount);
+    }
+    let token_decimals = currency_decimals(token)?;
+    let in_token = match from == token {
+        true => amount,
+        false => {
+            let rate = followed_rate(token, from)?;
+            convert_to_base(&rate, amount, currency_decimals(from)?, token_decimals).ok_or(
+                format!("{} {} can't be converted to {}", amount, from, token),
+            )?
+        }
+    };
+    match to == token {
+        true => Ok(in_token),
+        false => {
+            let rate = followed_rate(token, to)?;
+            convert_from_base(&rate, in_token, token_decimals, currency_decimals(to)?).ok_or(
+                format!("{} {} can't be converted to {}", in_token, token, to),
+            )
+        }
+    }
+}
+
+fn followed_rate(base: &str, quote: &str) -> Result<ExchangeRate, String> {
+    fresh_rate(base, quote).inspect_err(|_| {
+        let _ = track_pair(base, quote);
+    })
+}
+
+// Decimals of the smallest unit of a currency or token, for codes prices can be set in
+pub(crate) fn currency_decimals(code: &str) -> Result<u32, String> {
+    if let Some((_, decimals)) = CRYPTO_ASSETS.iter().find(|(symbol, _)| *symbol == code) {
+        return Ok(*decimals);
+    }
+    if code.len() == 3 && code.bytes().all(|b| b.is_ascii_uppercase()) {
+        return Ok(FIAT_DECIMALS);
+    }
+    Err(format!("{} is not a currency code", code))
+}
+
+// Whether a code is one of the tokens payments can be taken in
+pub(crate) fn is_token(code: &str) -> bool {
+    CRYPTO_ASSETS.iter().any(|(symbol, _)| *symbol == code)
+}
+
+// Start the timer refreshing the cached rates, timers don't survive upgrades
+pub(crate) fn schedule_refresh() {
+    ic_cdk_timers::set_timer_interval(REFRESH_INTERVAL, || ic_cdk::spawn(refresh_all()));
+}
+
+fn asset(symbol: &str) -> Asset {
+    Asset {
+        symbol: symbol.to_string(),
+        class: match is_token(symbol) {
+            true => AssetClass::Cryptocurrency,
+            false => AssetClass::FiatCurrency,
+        },
+    }
+}
+
+fn pair_key(base: &str, quote: &str) -> StringKey {
+    StringKey(format!("{}/{}", base, quote))
+}
+
+async fn refresh_all() {
+    let rates: Vec<ExchangeRate> = get_exchange_rates();
+    for rate in rates {
+        let _ = refresh_rate(rate).await;
+    }
+}
+
+async fn refresh_rate(mut rate: ExchangeRate) -> Result<(), String> {
+    let request = GetExchangeRateRequest {
+        base_asset: asset(&rate.base),
+        quote_asset: asset(&rate.quote),
+        timestamp: None,
+    };
+    let xrc = Principal::from_text(XRC_CANISTER_ID).unwrap();
+    let (result,): (Result<XrcExchangeRate, ExchangeRateError>,) =
+        ic_cdk::api::call::call_with_payment128(xrc, "get_exchange_rate", (request,), XRC_CYCLES)
+            .await
+            .map_err(|(_, msg)| msg)?;
+    let fetched = result.map_err(|_| "exchange rate canister has no rate".to_string())?;
+
+    rate.rate = fetched.rate;
+    rate.decimals = fetched.metadata.decimals;
+    rate.observed_at = fetched.timestamp * 1_000_000_000;
+    rate.fetched_at = time();
+    let key = pair_key(&rate.base, &rate.quote);
+    // The pair may have been dropped while the request was out
+    RATE_STORAGE.with(|rates| {
+        let mut rates = rates.borrow_mut();
+        if rates.contains_key(&key) {
+            rates.insert(key, rate);
+        }
+    });
+    Ok(())
+}
@@ -0,0 +1,325 @@
+use crate::clock::time;
+use crate::ratelimit::rate_limit;
+use crate::{_get_public_event, organizers, privacy, Error, Event, Memory, MEMORY_MANAGER};
+use candid::{Decode, Encode};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use std::{borrow::Cow, cell::RefCell};
+
+// Users verified per call, so a call stays well within the instruction limit
+const MAX_VERIFIED_PER_CALL: usize = 500;
+
+// Define a struct for the pre-sale registration of an event, which reserves the first sale
+// window to the fans the organizer verified
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct PresaleRegistration {
+    event_id: u64,
+    // Users register interest until then
+    closes_at: u64,
+    // Until then only verified registrants can buy tickets, everyone after
+    exclusive_until: u64,
+    // At most this many registrants are verified, None for no cap
+    max_verified: Option<u64>,
+    updated_at: u64,
+}
+
+// Define a struct for the payload used to set up the pre-sale registration of an event
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub struct PresaleRegistrationPayload {
+    closes_at: u64,
+    exclusive_until: u64,
+    max_verified: Option<u64>,
+}
+
+// Define an enum for where a registration stands
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq)]
+pub enum RegistrationStatus {
+    Pending,
+    Verified,
+}
+
+// Define a struct for a user's registration of interest in an event
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct Registration {
+    event_id: u64,
+    user_id: u64,
+    status: RegistrationStatus,
+    registered_at: u64,
+    verified_at: Option<u64>,
+}
+
+impl Storable for PresaleRegistration {
+    // Conversion to bytes
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    // Conversion from bytes
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for PresaleRegistration {
+    const MAX_SIZE: u32 = 128;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl Storable for Registration {
+    // Conversion to bytes
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    // Conversion from bytes
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for Registration {
+    const MAX_SIZE: u32 = 128;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    // Pre-sale registrations keyed by event id, events missing go on sale to everyone
+    static PRESALE_STORAGE: RefCell<StableBTreeMap<u64, PresaleRegistration, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(112)))
+    ));
+
+    // Registrations keyed by (event id, user id)
+    static REGISTRATION_STORAGE: RefCell<StableBTreeMap<(u64, u64), Registration, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(113)))
+    ));
+}
+
+#[ic_cdk::query]
+fn get_presale_registration(event_id: u64) -> Result<Option<PresaleRegistration>, Error> {
+    _get_public_event(&event_id).ok_or(Error::not_found("event", event_id))?;
+    Ok(_get_presale(event_id))
+}
+
+// Set up the pre-sale registration of an event, None to open the sale to everyone. Registrations
+// already made are kept.
+#[ic_cdk::update(guard = "rate_limit")]
+fn set_presale_registration(
+    event_id: u64,
+    payload: Option<PresaleRegistrationPayload>,
+) -> Result<Option<PresaleRegistration>, Error> {
+    organizers::authorize_event(event_id)?;
+    let Some(payload) = payload else {
+        PRESALE_STORAGE.with(|presales| presales.borrow_mut().remove(&event_id));
+        return Ok(None);
+    };
+    if payload.exclusive_until <= time() {
+        return Err(Error::ValidationFailed {
+            msg: "the registrants' sale window must end in the future".to_string(),
+        });
+    }
+    if payload.closes_at > payload.exclusive_until {
+        return Err(Error::ValidationFailed {
+            msg: "registration must close before the registrants' sale window ends".to_string(),
+        });
+    }
+
+    let presale = PresaleRegistration {
+        event_id,
+        closes_at: payload.closes_at,
+        exclusive_until: payload.exclusive_until,
+        max_verified: payload.max_verified,
+        updated_at: time(),
+    };
+    PRESALE_STORAGE.with(|presales| presales.borrow_mut().insert(event_id, presale.clone()));
+    Ok(Some(presale))
+}
+
+// Register the caller's interest in an event, the organizer verifies registrants later
+#[ic_cdk::update(guard = "rate_limit")]
+fn register_for_presale(event_id: u64) -> Result<Registration, Error> {
+    let user = privacy::caller_user()?;
+    _get_public_event(&event_id).ok_or(Error::not_found("event", event_id))?;
+    let presale = _get_presale(event_id).ok_or(Error::NotFound {
+        entity: "presale registration".to_string(),
+        id: event_id.to_string(),
+        msg: format!("event id:{} has no pre-sale registration", event_id),
+    })?;
+    if time() >= presale.closes_at {
+        return Err(Error::ValidationFailed {
+            msg: format!("registration for event id:{} has closed", event_id),
+        });
+    }
+    if let Some(registration) = _get_registration(event_id, user.id) {
+        return Ok(registration);
+    }
+
+    let registration = Registration {
+        event_id,
+        user_id: user.id,
+        status: RegistrationStatus::Pending,
+        registered_at: time(),
+        verified_at: None,
+    };
+    REGISTRATION_STORAGE.with(|registrations| {
+        registrations
+            .borrow_mut()
+            .insert((event_id, user.id), registration.clone())
+    });
+    Ok(registration)
+}
+
+#[ic_cdk::query]
+fn get_my_registration(event_id: u64) -> Result<Registration, Error> {
+    let user = privacy::caller_user()?;
+    _get_registration(event_id, user.id).ok_or(Error::NotFound {
+        entity: "registration".to_string(),
+        id: event_id.to_string(),
+        msg: format!("caller is not registered for event id:{}", event_id),
+    })
+}
+
+#[ic_cdk::query]
+fn get_registrations(event_id: u64) -> Result<Vec<Registration>, Error> {
+    organizers::authorize_event(event_id)?;
+    Ok(event_registrations(event_id))
+}
+
+// Verify registrants, who can then buy in the registrants' sale window. All of them are verified
+// or none, when one isn't registered or the cap would be passed.
+#[ic_cdk::update(guard = "rate_limit")]
+fn verify_registrations(event_id: u64, user_ids: Vec<u64>) -> Result<Vec<Registration>, Error> {
+    organizers::authorize_event(event_id)?;
+    if user_ids.is_empty() || user_ids.len() > MAX_VERIFIED_PER_CALL {
+        return Err(Error::ValidationFailed {
+            msg: format!(
+                "between 1 and {} registrants can be verified at once",
+                MAX_VERIFIED_PER_CALL
+            ),
+        });
+    }
+    let presale = _get_presale(event_id).ok_or(Error::NotFound {
+        entity: "presale registration".to_string(),
+        id: event_id.to_string(),
+        msg: format!("event id:{} has no pre-sale registration", event_id),
+    })?;
+
+    let mut user_ids = user_ids;
+    user_ids.sort_unstable();
+    user_ids.dedup();
+    let mut pending = vec![];
+    for user_id in user_ids {
+        let registration = _get_registration(event_id, user_id).ok_or(Error::NotFound {
+            entity: "registration".to_string(),
+            id: user_id.to_string(),
+            msg: format!(
+                "user id:{} is not registered for event id:{}",
+                user_id, event_id
+            ),
+        })?;
+        if registration.status == RegistrationStatus::Pending {
+            pending.push(registration);
+        }
+    }
+    if let Some(max_verified) = presale.max_verified {
+        let verified = verified_count(event_id);
+        if verified + pending.len() as u64 > max_verified {
+            return Err(Error::CapacityExceeded {
+                msg: format!(
+                    "event id:{} has {} of {} registrants verified",
+                    event_id, verified, max_verified
+                ),
+            });
+        }
+    }
+
+    let now = time();
+    Ok(pending
+        .into_iter()
+        .map(|mut registration| {
+            registration.status = RegistrationStatus::Verified;
+            registration.verified_at = Some(now);
+            REGISTRATION_STORAGE.with(|registrations| {
+                registrations
+                    .borrow_mut()
+                    .insert((event_id, registration.user_id), registration.clone())
+            });
+            registration
+        })
+        .collect())
+}
+
+fn _get_presale(event_id: u64) -> Option<PresaleRegistration> {
+    PRESALE_STORAGE.with(|presales| presales.borrow().get(&event_id))
+}
+
+fn _get_registration(event_id: u64, user_id: u64) -> Option<Registration> {
+    REGISTRATION_STORAGE.with(|registrations| registrations.borrow().get(&(event_id, user_id)))
+}
+
+fn event_registrations(event_id: u64) -> Vec<Registration> {
+    REGISTRATION_STORAGE.with(|registrations| {
+        registrations
+            .borrow()
+            .range((event_id, 0)..=(event_id, u64::MAX))
+            .map(|(_, registration)| registration)
+            .collect()
+    })
+}
+
+fn verified_count(event_id: u64) -> u64 {
+    event_registrations(event_id)
+        .iter()
+        .filter(|registration| registration.status == RegistrationStatus::Verified)
+        .count() as u64
+}
+
+// Check a user can buy tickets of an event, only verified registrants can in the registrants'
+// sale window of an event with a pre-sale registration
+pub(crate) fn check_registered(event: &Event, user_id: u64) -> Result<(), String> {
+    let Some(presale) = _get_presale(event.id) else {
+        return Ok(());
+    };
+    if time() >= presale.exclusive_until {
+        return Ok(());
+    }
+    match _get_registration(event.id, user_id) {
+        Some(registration) if registration.status == RegistrationStatus::Verified => Ok(()),
+        _ => Err(format!(
+            "tickets of event id:{} are sold to verified registrants until {}",
+            event.id, presale.exclusive_until
+        )),
+    }
+}
+
+pub(crate) fn get_user_registrations(user_id: u64) -> Vec<Registration> {
+    REGISTRATION_STORAGE.with(|registrations| {
+        registrations
+            .borrow()
+            .iter()
+            .map(|(_, registration)| registration)
+            .filter(|registration| registration.user_id == user_id)
+            .collect()
+    })
+}
+
+// Drop the pre-sale registration and registrations of a purged event
+pub(crate) fn remove_event_registrations(event_id: u64) {
+    PRESALE_STORAGE.with(|presales| presales.borrow_mut().remove(&event_id));
+    for registration in event_registrations(event_id) {
+        REGISTRATION_STORAGE.with(|registrations| {
+            registrations
+                .borrow_mut()
+                .remove(&(event_id, registration.user_id))
+        });
+    }
+}
+
+pub(crate) fn remove_user_registrations(user_id: u64) {
+    for registration in get_user_registrations(user_id) {
+        REGISTRATION_STORAGE.with(|registrations| {
+            registrations
+                .borrow_mut()
+                .remove(&(registration.event_id, user_id))
+        });
+    }
+}
@@ -0,0 +1,243 @@
+use crate::{icrc7, Error};
+use candid::types::{Serializer, Type};
+use candid::{CandidType, Principal};
+
+// Define an enum for the next step a client can take after an error, so frontends can offer it
+// without matching on messages
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub enum Hint {
+    // Sign in, the call was made anonymously
+    SignIn,
+    // Link the caller's wallet to a user with 'link_wallet'
+    LinkWallet,
+    // Join the waitlist of the sold out event with 'join_waitlist'
+    JoinWaitlist,
+    // Fetch the record again and base the update on its current version
+    Refetch,
+    // Delete with 'DeleteMode::Cascade', or remove the tickets and attendees first
+    DeleteDependents,
+    // Check the balance or allowance the payment is taken from
+    CheckPayment,
+    // Try again at this time, in nanoseconds since the epoch
+    RetryAt(u64),
+    // Nothing the caller can change, an admin has to look into it
+    ContactSupport,
+}
+
+// Define an enum for errors as clients receive them, each variant along with whether the same
+// call can succeed later and what to do next. Records only gain fields, so clients decoding
+// the variants without them keep working.
+#[derive(candid::CandidType)]
+#[allow(clippy::enum_variant_names)]
+enum WireError {
+    NotFound {
+        entity: String,
+        id: String,
+        msg: String,
+        retryable: bool,
+        remediation: Option<Hint>,
+    },
+    ValidationFailed {
+        msg: String,
+        retryable: bool,
+        remediation: Option<Hint>,
+    },
+    Unauthorized {
+        msg: String,
+        retryable: bool,
+        remediation: Option<Hint>,
+    },
+    Conflict {
+        msg: String,
+        current_version: u64,
+        retryable: bool,
+        remediation: Option<Hint>,
+    },
+    HasDependents {
+        msg: String,
+        retryable: bool,
+        remediation: Option<Hint>,
+    },
+    TransferFrozen {
+        msg: String,
+        reopens_at: u64,
+        retryable: bool,
+        remediation: Option<Hint>,
+    },
+    AlreadyExists {
+        msg: String,
+        retryable: bool,
+        remediation: Option<Hint>,
+    },
+    OnLegalHold {
+        msg: String,
+        hold_id: u64,
+        retryable: bool,
+        remediation: Option<Hint>,
+    },
+    CapacityExceeded {
+        msg: String,
+        retryable: bool,
+        remediation: Option<Hint>,
+    },
+    PurchaseLimitExceeded {
+        msg: String,
+        count: u64,
+        limit: u64,
+        retryable: bool,
+        remediation: Option<Hint>,
+    },
+    PaymentFailed {
+        msg: String,
+        retryable: bool,
+        remediation: Option<Hint>,
+    },
+    StorageQuotaExceeded {
+        msg: String,
+        memory_id: Option<u8>,
+        retryable: bool,
+        remediation: Option<Hint>,
+    },
+    InternalError {
+        msg: String,
+        ticket_id: Option<u64>,
+        retryable: bool,
+        remediation: Option<Hint>,
+    },
+}
+
+// Errors are sent with their hints, worked out from the variant when the reply is encoded
+impl CandidType for Error {
+    fn _ty() -> Type {
+        WireError::_ty()
+    }
+    fn idl_serialize<S>(&self, serializer: S) -> Result<(), S::Error>
+    where
+        S: Serializer,
+    {
+        wire_error(self).idl_serialize(serializer)
+    }
+}
+
+impl Error {
+    // Whether the same call can succeed later without the caller changing anything
+    pub(crate) fn retryable(&self) -> bool {
+        match self {
+            Error::TransferFrozen { .. } | Error::PaymentFailed { .. } => true,
+            // Once a ticket was stored the purchase went through, retrying would buy another
+            Error::InternalError { ticket_id, .. } => ticket_id.is_none(),
+            _ => false,
+        }
+    }
+
+    pub(crate) fn remediation(&self) -> Option<Hint> {
+        match self {
+            Error::Unauthorized { .. } => {
+                let caller = ic_cdk::caller();
+                if caller == Principal::anonymous() {
+                    Some(Hint::SignIn)
+                } else if icrc7::wallet_user(caller).is_none() {
+                    Some(Hint::LinkWallet)
+                } else {
+                    None
+                }
+            }
+            Error::CapacityExceeded { .. } => Some(Hint::JoinWaitlist),
+            Error::Conflict { .. } => Some(Hint::Refetch),
+            Error::HasDependents { .. } => Some(Hint::DeleteDependents),
+            Error::PaymentFailed { .. } => Some(Hint::CheckPayment),
+            Error::TransferFrozen { reopens_at, .. } => Some(Hint::RetryAt(*reopens_at)),
+            Error::OnLegalHold { .. }
+            | Error::StorageQuotaExceeded { .. }
+            | Error::InternalError { .. } => Some(Hint::ContactSupport),
+            Error::NotFound { .. }
+            | Error::ValidationFailed { .. }
+            | Error::AlreadyExists { .. }
+            | Error::PurchaseLimitExceeded { .. } => None,
+        }
+    }
+}
+
+fn wire_error(error: &Error) -> WireError {
+    let retryable = error.retryable();
+    let remediation = error.remediation();
+    match error.clone() {
+        Error::NotFound { entity, id, msg } => WireError::NotFound {
+            entity,
+            id,
+            msg,
+            retryable,
+            remediation,
+        },
+        Error::ValidationFailed { msg } => WireError::ValidationFailed {
+            msg,
+            retryable,
+            remediation,
+        },
+        Error::Unauthorized { msg } => WireError::Unauthorized {
+            msg,
+            retryable,
+            remediation,
+        },
+        Error::Conflict {
+            msg,
+            current_version,
+        } => WireError::Conflict {
+            msg,
+            current_version,
+            retryable,
+            remediation,
+        },
+        Error::HasDependents { msg } => WireError::HasDependents {
+            msg,
+            retryable,
+            remediation,
+        },
+        Error::TransferFrozen { msg, reopens_at } => WireError::TransferFrozen {
+            msg,
+            reopens_at,
+            retryable,
+            remediation,
+        },
+        Error::AlreadyExists { msg } => WireError::AlreadyExists {
+            msg,
+            retryable,
+            remediation,
+        },
+        Error::OnLegalHold { msg, hold_id } => WireError::OnLegalHold {
+            msg,
+            hold_id,
+            retryable,
+            remediation,
+        },
+        Error::CapacityExceeded { msg } => WireError::CapacityExceeded {
+            msg,
+            retryable,
+            remediation,
+        },
+        Error::PurchaseLimitExceeded { msg, count, limit } => WireError::PurchaseLimitExceeded {
+            msg,
+            count,
+            limit,
+            retryable,
+            remediation,
+        },
+        Error::PaymentFailed { msg } => WireError::PaymentFailed {
+            msg,
+            retryable,
+            remediation,
+        },
+        Error::StorageQuotaExceeded { msg, memory_id } => WireError::StorageQuotaExceeded {
+            msg,
+            memory_id,
+            retryable,
+            remediation,
+        },
+        Error::InternalError { msg, ticket_id } => WireError::InternalError {
+            msg,
+            ticket_id,
+            retryable,
+            remediation,
+        },
+    }
+}
@@ -0,0 +1,772 @@
+use crate::clock::time;
+use crate::memos::MemoPurpose;
+use crate::ratelimit::rate_limit;
+use crate::{
+    _get_event, _get_ticket, _get_user, _update_ticket, caller_is_admin, cancellations, fraud,
+    health, icrc7, invitations, jobs, moderation, organizers, payments, privacy, user_ticket_count,
+    Error, Memory, Ticket, TicketPayload, ID_COUNTER, MEMORY_MANAGER,
+};
+use candid::{Decode, Encode, Nat};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, Cell, StableBTreeMap, Storable};
+use std::{borrow::Cow, cell::RefCell};
+
+const NANOS_PER_SECOND: u64 = 1_000_000_000;
+// Windows an event can have, so its list stays within a stable map entry
+const MAX_BLACKOUT_WINDOWS: usize = 8;
+// Listings per page of 'get_resale_listings'
+const PAGE_SIZE: usize = 20;
+// Platform fee withheld from resales until an admin sets another, in basis points
+const DEFAULT_RESALE_FEE_BPS: u64 = 500;
+// Caps are a percentage of the price first paid, no event allows more than three times it
+const MAX_PRICE_CAP_PERCENT: u32 = 300;
+
+// Define an enum for a window during which tickets of an event can't change hands
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub enum BlackoutWindow {
+    // Between two timestamps, in nanoseconds since the epoch
+    Fixed { starts_at: u64, ends_at: u64 },
+    // From this long before the event starts until it starts, e.g. 24 hours before doors
+    BeforeStart { lead_secs: u64 },
+}
+
+// Define a struct for the blackout windows of an event
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct EventBlackouts {
+    windows: Vec<BlackoutWindow>,
+}
+
+// Define a struct for the highest resale price an organizer allows for tickets of an event
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct ResaleCap {
+    // Percentage of the price the ticket was first sold at, 100 allows resale at face value
+    max_percent: u32,
+}
+
+// Define a struct for a ticket its holder offers for resale
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct ResaleListing {
+    ticket_id: u64,
+    event_id: u64,
+    seller_id: u64,
+    price: u64,
+    listed_at: u64,
+}
+
+// Define a struct for a page of the tickets offered for resale for an event
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub struct ResaleListingPage {
+    listings: Vec<ResaleListing>,
+    page: u64,
+    // Number of listings over all pages
+    total: u64,
+}
+
+// Define a struct for a completed resale, kept as the record of what the seller is owed
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct ResaleSale {
+    id: u64,
+    ticket_id: u64,
+    event_id: u64,
+    seller_id: u64,
+    buyer_id: u64,
+    // What the buyer paid
+    price: u64,
+    // Withheld by the platform
+    fee: u64,
+    // What the seller is owed, the price less the fee
+    payout: u64,
+    sold_at: u64,
+}
+
+// Define an enum for what became of the seller's share of a resale
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub enum ResalePayout {
+    // Sent to the seller's wallet, less the ledger fee
+    Paid { block_index: Option<Nat> },
+    // The seller has no wallet or the ledger didn't take the transfer, it's owed to the seller
+    Failed { msg: String },
+}
+
+// Define a struct for the resale settings of the platform
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct ResaleSettings {
+    // None until an admin sets a fee, 'DEFAULT_RESALE_FEE_BPS' applies until then
+    fee_bps: Option<u64>,
+}
+
+impl Storable for EventBlackouts {
+    // Conversion to bytes
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    // Conversion from bytes
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl Storable for ResaleCap {
+    // Conversion to bytes
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    // Conversion from bytes
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl Storable for ResaleListing {
+    // Conversion to bytes
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    // Conversion from bytes
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl Storable for ResaleSale {
+    // Conversion to bytes
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    // Conversion from bytes
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl Storable for ResaleSettings {
+    // Conversion to bytes
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    // Conversion from bytes
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for EventBlackouts {
+    const MAX_SIZE: u32 = 512;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl BoundedStorable for ResaleCap {
+    const MAX_SIZE: u32 = 64;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl BoundedStorable for ResaleListing {
+    const MAX_SIZE: u32 = 128;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl Storable for ResalePayout {
+    // Conversion to bytes
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    // Conversion from bytes
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for ResalePayout {
+    const MAX_SIZE: u32 = 512;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl BoundedStorable for ResaleSale {
+    const MAX_SIZE: u32 = 192;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    // Blackout windows keyed by event id
+    static BLACKOUT_STORAGE: RefCell<StableBTreeMap<u64, EventBlackouts, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(48)))
+    ));
+
+    static RESALE_SETTINGS: RefCell<Cell<ResaleSettings, Memory>> = RefCell::new(
+        Cell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(65))),
+            ResaleSettings::default(),
+        )
+        .expect("Cannot create the resale settings")
+    );
+
+    // Price caps keyed by event id, events without one allow resale up to face value
+    static CAP_STORAGE: RefCell<StableBTreeMap<u64, ResaleCap, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(66)))
+    ));
+
+    // Listings keyed by (event id, ticket id), a ticket is listed once at a time
+    static LISTING_STORAGE: RefCell<StableBTreeMap<(u64, u64), ResaleListing, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(67)))
+    ));
+
+    // Completed resales keyed by sale id, kept when the ticket or event goes
+    static SALE_STORAGE: RefCell<StableBTreeMap<u64, ResaleSale, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(68)))
+    ));
+
+    // Payouts to sellers keyed by sale id
+    static PAYOUT_STORAGE: RefCell<StableBTreeMap<u64, ResalePayout, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(153)))
+    ));
+}
+
+#[ic_cdk::query]
+fn get_resale_blackouts(event_id: u64) -> Vec<BlackoutWindow> {
+    _get_blackouts(event_id)
+}
+
+#[ic_cdk::update(guard = "rate_limit")]
+fn set_resale_blackouts(
+    event_id: u64,
+    windows: Vec<BlackoutWindow>,
+) -> Result<Vec<BlackoutWindow>, Error> {
+    organizers::authorize_event(event_id)?;
+
+    if windows.len() > MAX_BLACKOUT_WINDOWS {
+        return Err(Error::ValidationFailed {
+            msg: format!(
+                "an event can have at most {} blackout windows",
+                MAX_BLACKOUT_WINDOWS
+            ),
+        });
+    }
+    for window in &windows {
+        match window {
+            BlackoutWindow::Fixed { starts_at, ends_at } if ends_at <= starts_at => {
+                return Err(Error::ValidationFailed {
+                    msg: "blackout window must end after it starts".to_string(),
+                });
+            }
+            BlackoutWindow::BeforeStart { lead_secs: 0 } => {
+                return Err(Error::ValidationFailed {
+                    msg: "blackout window before the start must last at least a second".to_string(),
+                });
+            }
+            _ => (),
+        }
+    }
+
+    BLACKOUT_STORAGE.with(|blackouts| match windows.is_empty() {
+        true => blackouts.borrow_mut().remove(&event_id),
+        false => blackouts.borrow_mut().insert(
+            event_id,
+            EventBlackouts {
+                windows: windows.clone(),
+            },
+        ),
+    });
+
+    Ok(windows)
+}
+
+fn _get_blackouts(event_id: u64) -> Vec<BlackoutWindow> {
+    BLACKOUT_STORAGE
+        .with(|blackouts| blackouts.borrow().get(&event_id))
+        .map(|blackouts| blackouts.windows)
+        .unwrap_or_default()
+}
+
+// Check tickets of an event can change hands now, with the time they can again if not.
+// Overlapping windows are followed through, so the time given is when all of them are over.
+pub(crate) fn check_transfer_open(event_id: u64) -> Result<(), Error> {
+    let windows = _get_blackouts(event_id);
+    if windows.is_empty() {
+        return Ok(());
+    }
+    let start = _get_event(&event_id).and_then(|event| jobs::event_start(&event));
+    let ranges: Vec<(u64, u64)> = windows
+        .iter()
+        .filter_map(|window| blackout_range(window, start))
+        .collect();
+
+    let now = time();
+    let mut reopens_at = now;
+    while let Some((_, ends_at)) = ranges
+        .iter()
+        .find(|(starts_at, ends_at)| *starts_at <= reopens_at && reopens_at < *ends_at)
+    {
+        reopens_at = *ends_at;
+    }
+    if reopens_at == now {
+        return Ok(());
+    }
+    Err(Error::TransferFrozen {
+        msg: format!(
+            "tickets of event id:{} can't be transferred until {}",
+            event_id, reopens_at
+        ),
+        reopens_at,
+    })
+}
+
+// Time range covered by a window, windows relative to the start are skipped for undated events
+fn blackout_range(window: &BlackoutWindow, start: Option<u64>) -> Option<(u64, u64)> {
+    match window {
+        BlackoutWindow::Fixed { starts_at, ends_at } => Some((*starts_at, *ends_at)),
+        BlackoutWindow::BeforeStart { lead_secs } => start.map(|start| {
+            (
+                start.saturating_sub(lead_secs.saturating_mul(NANOS_PER_SECOND)),
+                start,
+            )
+        }),
+    }
+}
+
+#[ic_cdk::query]
+fn get_resale_cap(event_id: u64) -> ResaleCap {
+    resale_cap(event_id)
+}
+
+#[ic_cdk::update(guard = "rate_limit")]
+fn set_resale_cap(event_id: u64, cap: Option<ResaleCap>) -> Result<ResaleCap, Error> {
+    organizers::authorize_event(event_id)?;
+    match cap {
+        Some(cap) => {
+            if cap.max_percent == 0 || cap.max_percent > MAX_PRICE_CAP_PERCENT {
+                return Err(Error::ValidationFailed {
+                    msg: format!(
+                        "resale cap must be between 1 and {} percent of face value",
+                        MAX_PRICE_CAP_PERCENT
+                    ),
+                });
+            }
+            CAP_STORAGE.with(|caps| caps.borrow_mut().insert(event_id, cap));
+        }
+        None => {
+            CAP_STORAGE.with(|caps| caps.borrow_mut().remove(&event_id));
+        }
+    }
+    // Listings above a lowered cap stay listed, the cap is checked again when they sell
+    Ok(resale_cap(event_id))
+}
+
+#[ic_cdk::query]
+fn get_resale_fee() -> u64 {
+    resale_fee_bps()
+}
+
+#[ic_cdk::update(guard = "caller_is_admin")]
+fn set_resale_fee(fee_bps: u64) -> Result<u64, Error> {
+    if fee_bps > 10_000 {
+        return Err(Error::ValidationFailed {
+            msg: "resale fee can be at most 10000 basis points".to_string(),
+        });
+    }
+    RESALE_SETTINGS
+        .with(|settings| {
+            settings.borrow_mut().set(ResaleSettings {
+                fee_bps: Some(fee_bps),
+            })
+        })
+        .expect("Cannot store the resale settings");
+    Ok(fee_bps)
+}
+
+#[ic_cdk::update(guard = "rate_limit")]
+fn list_ticket_for_resale(ticket_id: u64, price: u64) -> Result<ResaleListing, Error> {
+    // Only the holder of a ticket can list it
+    let ticket = cancellations::caller_ticket(ticket_id)?;
+    check_resellable(ticket.event_id, ticket.checked_in_at)?;
+    fraud::check_not_flagged(&ticket).map_err(|msg| Error::ValidationFailed { msg })?;
+    check_price(ticket.event_id, ticket.price, price)?;
+
+    // Listing again changes the price, the listing time is kept
+    let listed_at = _get_listing(ticket.event_id, ticket_id)
+        .filter(|listing| listing.seller_id == ticket.user_id)
+        .map_or(time(), |listing| listing.listed_at);
+    let listing = ResaleListing {
+        ticket_id,
+        event_id: ticket.event_id,
+        seller_id: ticket.user_id,
+        price,
+        listed_at,
+    };
+    LISTING_STORAGE.with(|listings| {
+        listings
+            .borrow_mut()
+            .insert((ticket.event_id, ticket_id), listing.clone())
+    });
+
+    Ok(listing)
+}
+
+#[ic_cdk::update(guard = "rate_limit")]
+fn cancel_resale_listing(ticket_id: u64) -> Result<String, Error> {
+    // Only the holder of a ticket can take it off the market
+    let ticket = cancellations::caller_ticket(ticket_id)?;
+    LISTING_STORAGE
+        .with(|listings| listings.borrow_mut().remove(&(ticket.event_id, ticket_id)))
+        .ok_or(Error::NotFound {
+            entity: "resale listing".to_string(),
+            id: ticket_id.to_string(),
+            msg: format!("ticket id:{} is not listed for resale", ticket_id),
+        })?;
+    Ok(format!(
+        "resale listing of ticket id: {} cancelled",
+        ticket_id
+    ))
+}
+
+#[ic_cdk::query]
+fn get_resale_listings(event_id: u64, page: u64) -> ResaleListingPage {
+    let listings: Vec<ResaleListing> = LISTING_STORAGE.with(|listings| {
+        listings
+            .borrow()
+            .range((event_id, 0)..=(event_id, u64::MAX))
+            .map(|(_, listing)| listing)
+            .filter(is_current)
+            .collect()
+    });
+    ResaleListingPage {
+        total: listings.len() as u64,
+        listings: listings
+            .into_iter()
+            .skip((page as usize).saturating_mul(PAGE_SIZE))
+            .take(PAGE_SIZE)
+            .collect(),
+        page,
+    }
+}
+
+// Buy a listed ticket for the caller's user. The price is what the buyer saw, so a relisting at
+// another price doesn't go through. The buyer pays the way the event takes payment, and the
+// seller is paid their share once the ticket moved.
+#[ic_cdk::update(guard = "rate_limit")]
+async fn buy_resale_ticket(ticket_id: u64, price: u64) -> Result<ResaleSale, Error> {
+    health::check_sales_open().map_err(|msg| Error::ValidationFailed { msg })?;
+    let buyer_id = privacy::caller_user()?.id;
+    let (ticket, _) = check_sale(ticket_id, buyer_id, price)?;
+
+    let charge = payments::charge_resale(ticket.event_id, ticket.tier_id, buyer_id, price)
+        .map_err(|msg| Error::PaymentFailed { msg })?;
+    let payment = payments::collect(&charge)
+        .await
+        .map_err(|msg| Error::PaymentFailed { msg })?;
+    // Checked again once paid, the ticket may have sold or moved meanwhile and is refunded then
+    let sale = check_sale(ticket_id, buyer_id, price)
+        .and_then(|(ticket, listing)| complete_sale(ticket, listing, buyer_id));
+    let sale = match sale {
+        Ok(sale) => sale,
+        Err(error) => {
+            payments::settle(payment, vec![]).await;
+            return Err(error);
+        }
+    };
+    payments::settle_resale(payment, ticket_id).await;
+
+    // The platform keeps its fee, the rest goes to the wallet of the seller
+    let payout = match icrc7::user_wallet(sale.seller_id) {
+        Some(wallet) => {
+            let purpose = MemoPurpose::ResalePayout {
+                sale_id: sale.id,
+                seller_id: sale.seller_id,
+            };
+            payments::pay_out(&charge, wallet, sale.payout, purpose).await
+        }
+        None => Err(format!("user id:{} has no wallet", sale.seller_id)),
+    };
+    let payout = match payout {
+        Ok(block_index) => ResalePayout::Paid { block_index },
+        Err(msg) => ResalePayout::Failed { msg },
+    };
+    PAYOUT_STORAGE.with(|payouts| payouts.borrow_mut().insert(sale.id, payout));
+
+    Ok(sale)
+}
+
+#[ic_cdk::query]
+fn get_resale_payout(sale_id: u64) -> Result<ResalePayout, Error> {
+    PAYOUT_STORAGE
+        .with(|payouts| payouts.borrow().get(&sale_id))
+        .ok_or(Error::NotFound {
+            entity: "resale payout".to_string(),
+            id: sale_id.to_string(),
+            msg: format!("resale id:{} has no payout", sale_id),
+        })
+}
+
+// Check a listed ticket can be sold to a buyer at the price the buyer saw
+fn check_sale(ticket_id: u64, buyer_id: u64, price: u64) -> Result<(Ticket, ResaleListing), Error> {
+    let ticket = _get_ticket(&ticket_id).ok_or(Error::not_found("ticket", ticket_id))?;
+    let listing = _get_listing(ticket.event_id, ticket_id)
+        .filter(is_current)
+        .ok_or(Error::NotFound {
+            entity: "resale listing".to_string(),
+            id: ticket_id.to_string(),
+            msg: format!("ticket id:{} is not listed for resale", ticket_id),
+        })?;
+    if listing.price != price {
+        return Err(Error::Conflict {
+            msg: format!(
+                "ticket id:{} is listed at {}, not {}",
+                ticket_id, listing.price, price
+            ),
+            current_version: ticket.version.unwrap_or(0),
+        });
+    }
+    check_price(ticket.event_id, ticket.price, listing.price)?;
+
+    let event = _get_event(&ticket.event_id).ok_or(Error::not_found("event", ticket.event_id))?;
+    _get_user(&buyer_id).ok_or(Error::not_found("user", buyer_id))?;
+    if buyer_id == listing.seller_id {
+        return Err(Error::ValidationFailed {
+            msg: format!("user id:{} already holds ticket id:{}", buyer_id, ticket_id),
+        });
+    }
+    // Buyers are held to the same rules as when buying from the organizer
+    moderation::check_purchase(&event, buyer_id).map_err(|msg| Error::ValidationFailed { msg })?;
+    invitations::check_invited(&event, buyer_id).map_err(|msg| Error::ValidationFailed { msg })?;
+    if let Some(limit) = event.max_tickets_per_user {
+        let count = user_ticket_count(event.id, buyer_id);
+        if count >= limit {
+            return Err(Error::PurchaseLimitExceeded {
+                msg: format!(
+                    "user id:{} already holds {} of the {} tickets allowed for event id:{}",
+                    buyer_id, count, limit, event.id
+                ),
+                count,
+                limit,
+            });
+        }
+    }
+    Ok((ticket, listing))
+}
+
+// Move a sold ticket to its buyer and record the sale
+fn complete_sale(
+    ticket: Ticket,
+    listing: ResaleListing,
+    buyer_id: u64,
+) -> Result<ResaleSale, Error> {
+    // Same path as any change of holder, so blackouts and check-ins are enforced there too
+    _update_ticket(
+        ticket.id,
+        TicketPayload {
+            event_id: ticket.event_id,
+            user_id: buyer_id,
+            ..Default::default()
+        },
+        None,
+    )?;
+
+    // Increment the global ID counter to get a new ID for the sale
+    let id = ID_COUNTER
+        .with(|counter| {
+            let current_id = *counter.borrow().get();
+            counter.borrow_mut().set(current_id + 1)
+        })
+        .expect("Cannot increment Ids");
+    let fee = resale_fee(listing.price, resale_fee_bps());
+    let sale = ResaleSale {
+        id,
+        ticket_id: ticket.id,
+        event_id: ticket.event_id,
+        seller_id: listing.seller_id,
+        buyer_id,
+        price: listing.price,
+        fee,
+        payout: listing.price - fee,
+        sold_at: time(),
+    };
+    SALE_STORAGE.with(|sales| sales.borrow_mut().insert(id, sale.clone()));
+
+    Ok(sale)
+}
+
+// Resales a user sold or bought, sellers are paid out from these
+#[ic_cdk::query]
+pub(crate) fn get_user_resales(user_id: u64) -> Vec<ResaleSale> {
+    SALE_STORAGE.with(|sales| {
+        sales
+            .borrow()
+            .iter()
+            .map(|(_, sale)| sale)
+            .filter(|sale| sale.seller_id == user_id || sale.buyer_id == user_id)
+            .collect()
+    })
+}
+
+fn resale_cap(event_id: u64) -> ResaleCap {
+    CAP_STORAGE
+        .with(|caps| caps.borrow().get(&event_id))
+        .unwrap_or(ResaleCap { max_percent: 100 })
+}
+
+// Share of a price withheld as the platform fee, rounded down
+fn resale_fee(price: u64, fee_bps: u64) -> u64 {
+    (price as u128 * fee_bps as u128 / 10_000) as u64
+}
+
+fn resale_fee_bps() -> u64 {
+    RESALE_SETTINGS
+        .with(|settings| settings.borrow().get().fee_bps)
+        .unwrap_or(DEFAULT_RESALE_FEE_BPS)
+}
+
+fn _get_listing(event_id: u64, ticket_id: u64) -> Option<ResaleListing> {
+    LISTING_STORAGE.with(|listings| listings.borrow().get(&(event_id, ticket_id)))
+}
+
+// Whether a ticket is currently offered for resale by its holder
+pub(crate) fn is_listed(event_id: u64, ticket_id: u64) -> bool {
+    _get_listing(event_id, ticket_id).is_some_and(|listing| is_current(&listing))
+}
+
+// A listing only stands while its seller still holds the unused ticket
+fn is_current(listing: &ResaleListing) -> bool {
+    _get_ticket(&listing.ticket_id)
+        .is_some_and(|ticket| ticket.user_id == listing.seller_id && ticket.checked_in_at.is_none())
+}
+
+// Tickets are resold while they could be transferred, and only before the event is over
+fn check_resellable(event_id: u64, checked_in_at: Option<u64>) -> Result<(), Error> {
+    let event = _get_event(&event_id).ok_or(Error::not_found("event", event_id))?;
+    if event.completed_at.is_some() {
+        return Err(Error::ValidationFailed {
+            msg: format!("event id:{} is over", event_id),
+        });
+    }
+    if checked_in_at.is_some() {
+        return Err(Error::ValidationFailed {
+            msg: "checked in tickets can't be resold".to_string(),
+        });
+    }
+    check_transfer_open(event_id)
+}
+
+fn check_price(event_id: u64, face_value: u64, price: u64) -> Result<(), Error> {
+    let max_price = face_value * resale_cap(event_id).max_percent as u64 / 100;
+    if price == 0 || price > max_price {
+        return Err(Error::ValidationFailed {
+            msg: format!(
+                "resale price must be between 1 and {} for this ticket",
+                max_price
+            ),
+        });
+    }
+    Ok(())
+}
+
+// Drop the resale listing of a ticket that changed hands or was cancelled
+pub(crate) fn remove_listing(event_id: u64, ticket_id: u64) {
+    LISTING_STORAGE.with(|listings| listings.borrow_mut().remove(&(event_id, ticket_id)));
+}
+
+// Drop the blackout windows, price cap and listings of a purged event, sales are kept for
+// the payouts
+pub(crate) fn remove_event_resale(event_id: u64) {
+    BLACKOUT_STORAGE.with(|blackouts| blackouts.borrow_mut().remove(&event_id));
+    CAP_STORAGE.with(|caps| caps.borrow_mut().remove(&event_id));
+    LISTING_STORAGE.with(|listings| {
+        let mut listings = listings.borrow_mut();
+        let keys: Vec<(u64, u64)> = listings
+            .range((event_id, 0)..=(event_id, u64::MAX))
+            .map(|(key, _)| key)
+            .collect();
+        for key in keys {
+            listings.remove(&key);
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    // A ticket of event 1001 held by user 2001 and listed at a price, user 2002 buys
+    fn listed_ticket(face_value: u64, price: u64) -> Ticket {
+        testing::event(1001);
+        testing::user(2001);
+        testing::user(2002);
+        let ticket = testing::ticket(3001, 1001, 2001, face_value);
+        let listing = ResaleListing {
+            ticket_id: ticket.id,
+            event_id: ticket.event_id,
+            seller_id: ticket.user_id,
+            price,
+            listed_at: testing::NOW,
+        };
+        LISTING_STORAGE.with(|listings| {
+            listings
+                .borrow_mut()
+                .insert((ticket.event_id, ticket.id), listing)
+        });
+        ticket
+    }
+
+    #[test]
+    fn fee_is_withheld_in_basis_points_and_rounds_down() {
+        assert_eq!(resale_fee(1_000, 500), 50);
+        assert_eq!(resale_fee(999, 500), 49);
+        assert_eq!(resale_fee(u64::MAX, 10_000), u64::MAX);
+    }
+
+    #[test]
+    fn price_above_the_cap_is_refused() {
+        testing::event(1001);
+        assert!(check_price(1001, 100, 100).is_ok());
+        assert!(check_price(1001, 100, 101).is_err());
+        assert!(check_price(1001, 100, 0).is_err());
+
+        CAP_STORAGE.with(|caps| {
+            caps.borrow_mut()
+                .insert(1001, ResaleCap { max_percent: 150 })
+        });
+        assert!(check_price(1001, 100, 150).is_ok());
+    }
+
+    #[test]
+    fn sale_at_the_listed_price_goes_through() {
+        let ticket = listed_ticket(100, 80);
+        let (sold, listing) = check_sale(ticket.id, 2002, 80).unwrap();
+        assert_eq!(sold.id, ticket.id);
+        assert_eq!(listing.seller_id, 2001);
+    }
+
+    #[test]
+    fn sale_at_another_price_than_listed_conflicts() {
+        let ticket = listed_ticket(100, 80);
+        assert!(matches!(
+            check_sale(ticket.id, 2002, 70),
+            Err(Error::Conflict { .. })
+        ));
+    }
+
+    #[test]
+    fn seller_cannot_buy_their_own_listing() {
+        let ticket = listed_ticket(100, 80);
+        assert!(matches!(
+            check_sale(ticket.id, 2001, 80),
+            Err(Error::ValidationFailed { .. })
+        ));
+    }
+
+    #[test]
+    fn listing_lapses_once_the_ticket_changed_hands() {
+        let mut ticket = listed_ticket(100, 80);
+        ticket.user_id = 2002;
+        crate::store_ticket(&mut ticket);
+        assert!(!is_listed(1001, ticket.id));
+        assert!(matches!(
+            check_sale(ticket.id, 2002, 80),
+            Err(Error::NotFound { .. })
+        ));
+    }
+}
@@ -0,0 +1,233 @@
+use crate::cancellations::{self, CancellationRefund};
+use crate::clock::time;
+use crate::credits::RefundTarget;
+use crate::notifications::{self, NotificationKind};
+use crate::ratelimit::rate_limit;
+use crate::{
+    addons, announcements, broadcasts, certification, dates, geo, history, jobs, organizers,
+    store_event, venues, Error, Event, Memory, MEMORY_MANAGER,
+};
+use candid::{Decode, Encode};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use std::{borrow::Cow, cell::RefCell};
+
+const NANOS_PER_DAY: u64 = 24 * 60 * 60 * 1_000_000_000;
+
+// Define an enum for where a rescheduled event stands
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq)]
+pub enum RescheduleStatus {
+    // Off its date with the new one to be announced, tickets don't sell meanwhile
+    Postponed,
+    // Moved to a new date or time
+    Rescheduled,
+}
+
+// Define a struct for the new date and start time of an event
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct NewTimes {
+    date: String,
+    start_time: String,
+}
+
+// Define a struct for the latest postponement or rescheduling of an event
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct Reschedule {
+    event_id: u64,
+    status: RescheduleStatus,
+    // Date and start time the tickets were bought for
+    previous_date: String,
+    previous_start_time: String,
+    // None while postponed
+    new_times: Option<NewTimes>,
+    // Holders of tickets bought before can get a full refund until then, if set
+    refund_until: Option<u64>,
+    rescheduled_at: u64,
+}
+
+impl Storable for Reschedule {
+    // Conversion to bytes
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    // Conversion from bytes
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for Reschedule {
+    const MAX_SIZE: u32 = 256;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    // Latest postponement or rescheduling keyed by event id
+    static RESCHEDULE_STORAGE: RefCell<StableBTreeMap<u64, Reschedule, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(122)))
+    ));
+}
+
+#[ic_cdk::query]
+fn get_event_reschedule(event_id: u64) -> Option<Reschedule> {
+    _get_reschedule(event_id)
+}
+
+// Move an event to new times, or postpone it with None until the new date is known. Tickets
+// stay valid, and holders who can't make it can be offered a full refund until 'refund_until'.
+// Everyone holding a ticket is told.
+#[ic_cdk::update(guard = "rate_limit")]
+fn reschedule_event(
+    id: u64,
+    new_times: Option<NewTimes>,
+    refund_until: Option<u64>,
+) -> Result<Reschedule, Error> {
+    let mut event = organizers::authorize_event(id)?;
+    if event.completed_at.is_some() {
+        return Err(Error::ValidationFailed {
+            msg: format!("event id:{} has already completed", id),
+        });
+    }
+    if !announcements::date_is_final(&event) && !is_postponed(id) {
+        return Err(Error::ValidationFailed {
+            msg: format!("event id:{} has no announced date to move", id),
+        });
+    }
+    if refund_until.is_some_and(|until| until <= time()) {
+        return Err(Error::ValidationFailed {
+            msg: "refund window must end in the future".to_string(),
+        });
+    }
+    if let Some(times) = &new_times {
+        announcements::validate_stage(
+            announcements::stage(&event),
+            &times.date,
+            &times.start_time,
+            event.venue.as_ref(),
+        )
+        .map_err(|msg| Error::ValidationFailed { msg })?;
+        // The event must still fit in its venue's schedule at its new times
+        venues::check_schedule(&Event {
+            date: times.date.clone(),
+            start_time: times.start_time.clone(),
+            ..event.clone()
+        })?;
+    }
+
+    // A postponed event keeps the times its tickets were bought for until it gets new ones
+    let (previous_date, previous_start_time) = match _get_reschedule(id) {
+        Some(reschedule) if reschedule.status == RescheduleStatus::Postponed => {
+            (reschedule.previous_date, reschedule.previous_start_time)
+        }
+        _ => (event.date.clone(), event.start_time.clone()),
+    };
+    let reschedule = Reschedule {
+        event_id: id,
+        status: match new_times {
+            Some(_) => RescheduleStatus::Rescheduled,
+            None => RescheduleStatus::Postponed,
+        },
+        previous_date,
+        previous_start_time,
+        new_times: new_times.clone(),
+        refund_until,
+        rescheduled_at: time(),
+    };
+    RESCHEDULE_STORAGE.with(|reschedules| reschedules.borrow_mut().insert(id, reschedule.clone()));
+
+    let refund_offer = refund_until.map_or(String::new(), |until| {
+        format!(
+            " If you can't make it, you can get a full refund of your ticket until {} (UTC).",
+            dates::format_date(dates::civil_from_days((until / NANOS_PER_DAY) as i64))
+        )
+    });
+    match new_times {
+        Some(times) => {
+            let before = event.clone();
+            event.date = times.date;
+            event.start_time = times.start_time;
+            event.updated_at = Some(time());
+            store_event(&mut event);
+            certification::certify_event(id);
+            // Reminded again before the new start
+            jobs::remove_event_reminder(id);
+            history::record_event_update(&before, &event);
+            notifications::notify_event_attendees(
+                &event,
+                NotificationKind::EventChanged,
+                format!("{} has been rescheduled", event.name),
+                format!(
+                    "{} now takes place {}, {}. Your ticket stays valid.{}",
+                    event.name,
+                    broadcasts::WHEN,
+                    geo::venue_label(event.venue.as_ref()),
+                    refund_offer
+                ),
+            );
+        }
+        None => {
+            // Its calendar file is withdrawn until the new date is known
+            certification::certify_event(id);
+            history::record_event_change(id, "status", String::new(), "postponed".to_string());
+            notifications::notify_event_attendees(
+                &event,
+                NotificationKind::EventChanged,
+                format!("{} has been postponed", event.name),
+                format!(
+                    "{} is postponed, the new date will be announced. Your ticket stays valid.{}",
+                    event.name, refund_offer
+                ),
+            );
+        }
+    }
+    Ok(reschedule)
+}
+
+// Refund the caller's ticket in full in the refund window of a rescheduled event. Tickets
+// bought after the event was moved are refunded under its cancellation policy only.
+#[ic_cdk::update(guard = "rate_limit")]
+fn refund_rescheduled_ticket(
+    ticket_id: u64,
+    refund_to: Option<RefundTarget>,
+) -> Result<CancellationRefund, Error> {
+    let ticket = cancellations::caller_ticket(ticket_id)?;
+    let reschedule = _get_reschedule(ticket.event_id)
+        .filter(|reschedule| reschedule.refund_until.is_some_and(|until| until > time()))
+        .ok_or(Error::ValidationFailed {
+            msg: format!(
+                "event id:{} has no refund window open for rescheduling",
+                ticket.event_id
+            ),
+        })?;
+    if ticket.created_at >= reschedule.rescheduled_at {
+        return Err(Error::ValidationFailed {
+            msg: format!(
+                "ticket id:{} was bought after the event was rescheduled",
+                ticket_id
+            ),
+        });
+    }
+    let amount = ticket.price + addons::ticket_addons_total(&ticket);
+    cancellations::refund_ticket(
+        ticket,
+        amount,
+        None,
+        refund_to,
+        Some(reschedule.rescheduled_at),
+    )
+}
+
+fn _get_reschedule(event_id: u64) -> Option<Reschedule> {
+    RESCHEDULE_STORAGE.with(|reschedules| reschedules.borrow().get(&event_id))
+}
+
+// Whether an event is postponed without a new date yet
+pub(crate) fn is_postponed(event_id: u64) -> bool {
+    _get_reschedule(event_id)
+        .is_some_and(|reschedule| reschedule.status == RescheduleStatus::Postponed)
+}
+
+pub(crate) fn remove_event_reschedule(event_id: u64) {
+    RESCHEDULE_STORAGE.with(|reschedules| reschedules.borrow_mut().remove(&event_id));
+}
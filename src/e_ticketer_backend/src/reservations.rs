@@ -0,0 +1,249 @@
+use crate::clock::time;
+use crate::ratelimit::rate_limit;
+use crate::{
+    _create_ticket, _get_event, _get_ticket, config, flash_sales, health, issued_ticket_ids,
+    payments, quote_ticket, validation, Error, Memory, Ticket, TicketPayload, ID_COUNTER,
+    MEMORY_MANAGER,
+};
+use candid::{Decode, Encode, Principal};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use std::{borrow::Cow, cell::RefCell};
+
+// Active reservations a caller can hold at once, so capacity can't be hoarded
+const MAX_RESERVATIONS_PER_CALLER: usize = 10;
+
+// Define a struct for a place held against an event's capacity until it is confirmed
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct Reservation {
+    id: u64,
+    event_id: u64,
+    tier_id: Option<u64>,
+    reserved_by: Principal,
+    expires_at: u64,
+    created_at: u64,
+}
+
+impl Storable for Reservation {
+    // Conversion to bytes
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    // Conversion from bytes
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for Reservation {
+    const MAX_SIZE: u32 = 256;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    // Reservations keyed by id, expired ones are ignored until the expiry job removes them
+    static RESERVATION_STORAGE: RefCell<StableBTreeMap<u64, Reservation, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(42)))
+    ));
+}
+
+#[ic_cdk::update(guard = "rate_limit")]
+fn reserve_ticket(event_id: u64, tier_id: Option<u64>) -> Result<Reservation, Error> {
+    _get_event(&event_id).ok_or(Error::ValidationFailed {
+        msg: format!("event id:{} does not exist", event_id),
+    })?;
+
+    let caller = ic_cdk::caller();
+    let now = time();
+    let held = active_reservations(now)
+        .filter(|reservation| reservation.reserved_by == caller)
+        .count();
+    if held >= MAX_RESERVATIONS_PER_CALLER {
+        return Err(Error::ValidationFailed {
+            msg: format!(
+                "at most {} reservations can be held at once",
+                MAX_RESERVATIONS_PER_CALLER
+            ),
+        });
+    }
+
+    // The same checks as a purchase, against the capacity left after other reservations
+    quote_ticket(&TicketPayload {
+        event_id,
+        tier_id,
+        ..Default::default()
+    })?;
+
+    let id = ID_COUNTER
+        .with(|counter| {
+            let current_id = *counter.borrow().get();
+            counter.borrow_mut().set(current_id + 1)
+        })
+        .expect("Cannot increment Ids");
+    let reservation = Reservation {
+        id,
+        event_id,
+        tier_id,
+        reserved_by: caller,
+        expires_at: now + config::reservation_ttl(),
+        created_at: now,
+    };
+    RESERVATION_STORAGE
+        .with(|reservations| reservations.borrow_mut().insert(id, reservation.clone()));
+
+    Ok(reservation)
+}
+
+#[ic_cdk::update(guard = "rate_limit")]
+async fn confirm_reservation(reservation_id: u64, payload: TicketPayload) -> Result<Ticket, Error> {
+    health::check_sales_open().map_err(|msg| Error::ValidationFailed { msg })?;
+    let reservation = _get_reservation(reservation_id)?;
+    if payload.event_id != reservation.event_id || payload.tier_id != reservation.tier_id {
+        return Err(Error::ValidationFailed {
+            msg: format!(
+                "reservation id:{} doesn't match the event and tier of the ticket",
+                reservation_id
+            ),
+        });
+    }
+
+    // The place stays held while the hook is consulted and the payment taken
+    validation::validate_tickets(std::slice::from_ref(&payload))
+        .await
+        .map_err(|msg| Error::ValidationFailed { msg })?;
+    // A flash sale place is held along with the reservation while the payment is taken
+    let holds = flash_sales::hold(std::slice::from_ref(&payload))
+        .map_err(|msg| Error::ValidationFailed { msg })?;
+    let paid = async {
+        let charge = without_hold(reservation_id, || {
+            payments::charge_for(std::slice::from_ref(&payload))
+        })?
+        .map_err(|msg| Error::ValidationFailed { msg })?;
+        let payment = payments::collect(&charge)
+            .await
+            .map_err(|msg| Error::PaymentFailed { msg })?;
+        Ok((charge, payment))
+    }
+    .await;
+    let (charge, payment) = match paid {
+        Ok(paid) => paid,
+        Err(error) => {
+            flash_sales::release(holds);
+            return Err(error);
+        }
+    };
+
+    let ticket = without_hold(reservation_id, || {
+        payments::check_charge(&charge, std::slice::from_ref(&payload))
+            .map_err(|msg| Error::ValidationFailed { msg })
+            .and_then(|_| _create_ticket(payload))
+    })
+    .and_then(|ticket| ticket);
+    flash_sales::release(holds);
+    // Released for good once a ticket was issued, a payment for none is refunded
+    match &ticket {
+        Ok(_)
+        | Err(Error::InternalError {
+            ticket_id: Some(_), ..
+        }) => {
+            RESERVATION_STORAGE
+                .with(|reservations| reservations.borrow_mut().remove(&reservation_id));
+        }
+        Err(_) => (),
+    }
+    payments::settle(payment, issued_ticket_ids(&ticket)).await;
+    // Read back, settling records the rate a converted price was paid at
+    ticket.map(|ticket| _get_ticket(&ticket.id).unwrap_or(ticket))
+}
+
+// Run a purchase step with the reservation released, so it doesn't count against itself. It is
+// held again afterwards, and stays held if the step was turned down.
+fn without_hold<T>(reservation_id: u64, step: impl FnOnce() -> T) -> Result<T, Error> {
+    let reservation = _get_reservation(reservation_id)?;
+    RESERVATION_STORAGE.with(|reservations| reservations.borrow_mut().remove(&reservation_id));
+    let result = step();
+    RESERVATION_STORAGE.with(|reservations| {
+        reservations
+            .borrow_mut()
+            .insert(reservation_id, reservation)
+    });
+    Ok(result)
+}
+
+#[ic_cdk::update(guard = "rate_limit")]
+fn cancel_reservation(reservation_id: u64) -> Result<String, Error> {
+    _get_reservation(reservation_id)?;
+    RESERVATION_STORAGE.with(|reservations| reservations.borrow_mut().remove(&reservation_id));
+    Ok(format!("reservation id: {} cancelled", reservation_id))
+}
+
+// Helper function to get an active reservation of the caller
+fn _get_reservation(reservation_id: u64) -> Result<Reservation, Error> {
+    RESERVATION_STORAGE
+        .with(|reservations| reservations.borrow().get(&reservation_id))
+        .filter(|reservation| {
+            reservation.reserved_by == ic_cdk::caller() && reservation.expires_at > time()
+        })
+        .ok_or(Error::ValidationFailed {
+            msg: format!(
+                "reservation id:{} does not exist or has expired",
+                reservation_id
+            ),
+        })
+}
+
+fn active_reservations(now: u64) -> impl Iterator<Item = Reservation> {
+    RESERVATION_STORAGE
+        .with(|reservations| {
+            reservations
+                .borrow()
+                .iter()
+                .map(|(_, reservation)| reservation)
+                .collect::<Vec<_>>()
+        })
+        .into_iter()
+        .filter(move |reservation| reservation.expires_at > now)
+}
+
+// Places of an event held by active reservations, in a single tier if one is given
+pub(crate) fn reserved(event_id: u64, tier_id: Option<u64>) -> u64 {
+    active_reservations(time())
+        .filter(|reservation| {
+            reservation.event_id == event_id
+                && (tier_id.is_none() || reservation.tier_id == tier_id)
+        })
+        .count() as u64
+}
+
+// Remove the reservations past their expiry, returning how many were released
+pub(crate) fn expire_reservations() -> u64 {
+    let now = time();
+    RESERVATION_STORAGE.with(|reservations| {
+        let mut reservations = reservations.borrow_mut();
+        let expired: Vec<u64> = reservations
+            .iter()
+            .filter(|(_, reservation)| reservation.expires_at <= now)
+            .map(|(id, _)| id)
+            .collect();
+        for id in &expired {
+            reservations.remove(id);
+        }
+        expired.len() as u64
+    })
+}
+
+// Drop the reservations of a deleted event
+pub(crate) fn remove_event_reservations(event_id: u64) {
+    RESERVATION_STORAGE.with(|reservations| {
+        let mut reservations = reservations.borrow_mut();
+        let ids: Vec<u64> = reservations
+            .iter()
+            .filter(|(_, reservation)| reservation.event_id == event_id)
+            .map(|(id, _)| id)
+            .collect();
+        for id in ids {
+            reservations.remove(&id);
+        }
+    });
+}
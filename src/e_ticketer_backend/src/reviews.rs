@@ -0,0 +1,321 @@
+use crate::clock::time;
+use crate::ratelimit::rate_limit;
+use crate::{
+    _get_event, _get_ticket, caller_is_admin, organizers, privacy, Error, Memory, ID_COUNTER,
+    MEMORY_MANAGER,
+};
+use candid::{Decode, Encode};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use std::{borrow::Cow, cell::RefCell};
+
+const PAGE_SIZE: usize = 20;
+const MAX_REVIEW_LENGTH: usize = 1000;
+const MAX_REPORT_REASON_LENGTH: usize = 200;
+
+// Define a struct for an attendee's review of an event
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct Review {
+    id: u64,
+    event_id: u64,
+    user_id: u64,
+    // Checked in ticket the review was written on
+    ticket_id: u64,
+    // 1 to 5 stars
+    rating: u8,
+    text: String,
+    // Hidden reviews are left out of listings and the event's rating
+    hidden_at: Option<u64>,
+    report: Option<ReviewReport>,
+    created_at: u64,
+    updated_at: Option<u64>,
+}
+
+// Define a struct for an organizer's report of a review to the admins
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct ReviewReport {
+    reason: String,
+    reported_at: u64,
+}
+
+// Define a struct for the rating of an event over its visible reviews
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct EventRating {
+    event_id: u64,
+    // Average in hundredths of a star, None before the first review
+    average: Option<u64>,
+    count: u64,
+    // Reviews given 1 to 5 stars
+    distribution: Vec<u64>,
+}
+
+// Define a struct for a page of an event's reviews
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub struct ReviewPage {
+    reviews: Vec<Review>,
+    rating: EventRating,
+    page: u64,
+    total: u64,
+}
+
+impl Storable for Review {
+    // Conversion to bytes
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    // Conversion from bytes
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for Review {
+    const MAX_SIZE: u32 = 2048;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    // Reviews keyed by (event id, review id)
+    static REVIEW_STORAGE: RefCell<StableBTreeMap<(u64, u64), Review, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(85)))
+    ));
+}
+
+// Review a completed event on a ticket of the caller's that was checked in. Reviewing the
+// event again replaces the caller's earlier review.
+#[ic_cdk::update(guard = "rate_limit")]
+fn submit_event_review(ticket_id: u64, rating: u8, text: String) -> Result<Review, Error> {
+    let user = privacy::caller_user()?;
+    let ticket = _get_ticket(&ticket_id).ok_or(Error::not_found("ticket", ticket_id))?;
+    if ticket.user_id != user.id {
+        return Err(Error::Unauthorized {
+            msg: format!(
+                "ticket id:{} does not belong to user id:{}",
+                ticket_id, user.id
+            ),
+        });
+    }
+    if ticket.checked_in_at.is_none() {
+        return Err(Error::Unauthorized {
+            msg: format!(
+                "ticket id:{} was never checked in, only attendees can review",
+                ticket_id
+            ),
+        });
+    }
+    let event = _get_event(&ticket.event_id).ok_or(Error::not_found("event", ticket.event_id))?;
+    if event.completed_at.is_none() {
+        return Err(Error::ValidationFailed {
+            msg: format!("event id:{} has not completed yet", event.id),
+        });
+    }
+    validate_review(rating, &text).map_err(|msg| Error::ValidationFailed { msg })?;
+
+    let review = match user_review(event.id, user.id) {
+        Some(mut review) => {
+            review.ticket_id = ticket_id;
+            review.rating = rating;
+            review.text = text;
+            review.updated_at = Some(time());
+            review
+        }
+        None => {
+            // Increment the global ID counter to get a new ID for the review
+            let id = ID_COUNTER
+                .with(|counter| {
+                    let current_id = *counter.borrow().get();
+                    counter.borrow_mut().set(current_id + 1)
+                })
+                .expect("Cannot increment Ids");
+            Review {
+                id,
+                event_id: event.id,
+                user_id: user.id,
+                ticket_id,
+                rating,
+                text,
+                hidden_at: None,
+                report: None,
+                created_at: time(),
+                updated_at: None,
+            }
+        }
+    };
+    store_review(&review);
+    Ok(review)
+}
+
+// Visible reviews of an event, the latest first
+#[ic_cdk::query]
+fn get_event_reviews(event_id: u64, page: u64) -> Result<ReviewPage, Error> {
+    _get_event(&event_id).ok_or(Error::not_found("event", event_id))?;
+    let mut reviews = visible_reviews(event_id);
+    reviews.sort_by_key(|review| std::cmp::Reverse(review.created_at));
+    Ok(ReviewPage {
+        rating: rating_of(event_id, &reviews),
+        total: reviews.len() as u64,
+        reviews: reviews
+            .into_iter()
+            .skip((page as usize).saturating_mul(PAGE_SIZE))
+            .take(PAGE_SIZE)
+            .collect(),
+        page,
+    })
+}
+
+#[ic_cdk::query]
+fn get_event_rating(event_id: u64) -> Result<EventRating, Error> {
+    _get_event(&event_id).ok_or(Error::not_found("event", event_id))?;
+    Ok(rating_of(event_id, &visible_reviews(event_id)))
+}
+
+// Hide a review of the organizer's event from listings and the rating, or show it again
+#[ic_cdk::update(guard = "rate_limit")]
+fn set_review_hidden(event_id: u64, review_id: u64, hidden: bool) -> Result<Review, Error> {
+    organizers::authorize_event(event_id)?;
+    let mut review = get_review(event_id, review_id)?;
+    review.hidden_at = match hidden {
+        true => review.hidden_at.or(Some(time())),
+        false => None,
+    };
+    store_review(&review);
+    Ok(review)
+}
+
+// Flag a review of the organizer's event to the admins, who can remove it
+#[ic_cdk::update(guard = "rate_limit")]
+fn report_review(event_id: u64, review_id: u64, reason: String) -> Result<Review, Error> {
+    organizers::authorize_event(event_id)?;
+    if reason.trim().is_empty() || reason.len() > MAX_REPORT_REASON_LENGTH {
+        return Err(Error::ValidationFailed {
+            msg: format!(
+                "a report needs a reason of at most {} characters",
+                MAX_REPORT_REASON_LENGTH
+            ),
+        });
+    }
+    let mut review = get_review(event_id, review_id)?;
+    review.report = Some(ReviewReport {
+        reason,
+        reported_at: time(),
+    });
+    store_review(&review);
+    Ok(review)
+}
+
+#[ic_cdk::query(guard = "caller_is_admin")]
+fn get_reported_reviews() -> Vec<Review> {
+    REVIEW_STORAGE.with(|reviews| {
+        reviews
+            .borrow()
+            .iter()
+            .map(|(_, review)| review)
+            .filter(|review| review.report.is_some())
+            .collect()
+    })
+}
+
+#[ic_cdk::update(guard = "caller_is_admin")]
+fn remove_review(event_id: u64, review_id: u64) -> Result<Review, Error> {
+    let review = get_review(event_id, review_id)?;
+    REVIEW_STORAGE.with(|reviews| reviews.borrow_mut().remove(&(event_id, review_id)));
+    Ok(review)
+}
+
+fn validate_review(rating: u8, text: &str) -> Result<(), String> {
+    if !(1..=5).contains(&rating) {
+        return Err("rating must be between 1 and 5".to_string());
+    }
+    if text.len() > MAX_REVIEW_LENGTH {
+        return Err(format!(
+            "review must be at most {} characters",
+            MAX_REVIEW_LENGTH
+        ));
+    }
+    Ok(())
+}
+
+fn get_review(event_id: u64, review_id: u64) -> Result<Review, Error> {
+    REVIEW_STORAGE
+        .with(|reviews| reviews.borrow().get(&(event_id, review_id)))
+        .ok_or(Error::NotFound {
+            entity: "review".to_string(),
+            id: review_id.to_string(),
+            msg: format!(
+                "review id:{} of event id:{} does not exist",
+                review_id, event_id
+            ),
+        })
+}
+
+fn store_review(review: &Review) {
+    REVIEW_STORAGE.with(|reviews| {
+        reviews
+            .borrow_mut()
+            .insert((review.event_id, review.id), review.clone())
+    });
+}
+
+fn event_reviews(event_id: u64) -> Vec<Review> {
+    REVIEW_STORAGE.with(|reviews| {
+        reviews
+            .borrow()
+            .range((event_id, 0)..=(event_id, u64::MAX))
+            .map(|(_, review)| review)
+            .collect()
+    })
+}
+
+fn visible_reviews(event_id: u64) -> Vec<Review> {
+    event_reviews(event_id)
+        .into_iter()
+        .filter(|review| review.hidden_at.is_none())
+        .collect()
+}
+
+fn user_review(event_id: u64, user_id: u64) -> Option<Review> {
+    event_reviews(event_id)
+        .into_iter()
+        .find(|review| review.user_id == user_id)
+}
+
+fn rating_of(event_id: u64, reviews: &[Review]) -> EventRating {
+    let mut distribution = vec![0; 5];
+    for review in reviews {
+        distribution[review.rating as usize - 1] += 1;
+    }
+    let count = reviews.len() as u64;
+    let stars: u64 = reviews.iter().map(|review| review.rating as u64).sum();
+    EventRating {
+        event_id,
+        average: (count > 0).then(|| stars * 100 / count),
+        count,
+        distribution,
+    }
+}
+
+// Erased users' reviews go along with their other data
+pub(crate) fn remove_user_reviews(event_ids: &[u64], user_id: u64) {
+    for event_id in event_ids {
+        if let Some(review) = user_review(*event_id, user_id) {
+            REVIEW_STORAGE.with(|reviews| reviews.borrow_mut().remove(&(*event_id, review.id)));
+        }
+    }
+}
+
+pub(crate) fn remove_event_reviews(event_id: u64) {
+    let keys: Vec<(u64, u64)> = REVIEW_STORAGE.with(|reviews| {
+        reviews
+            .borrow()
+            .range((event_id, 0)..=(event_id, u64::MAX))
+            .map(|(key, _)| key)
+            .collect()
+    });
+    REVIEW_STORAGE.with(|reviews| {
+        let mut reviews = reviews.borrow_mut();
+        for key in keys {
+            reviews.remove(&key);
+        }
+    });
+}
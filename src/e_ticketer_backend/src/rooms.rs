@@ -0,0 +1,331 @@
+use crate::clock::time;
+use crate::occupancy::MAX_ZONE_LEN;
+use crate::ratelimit::rate_limit;
+use crate::{_get_ticket, organizers, Error, Memory, MEMORY_MANAGER};
+use candid::{Decode, Encode};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use std::{borrow::Cow, cell::RefCell};
+
+// Rooms per event, so they fit in a single stable map entry
+const MAX_ROOMS: usize = 16;
+
+// Define a struct for a room or zone of an event as configured by its organizers
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct RoomPayload {
+    // Same names as the zones gate devices are registered in
+    zone: String,
+    capacity: u64,
+    // Where check-ins go once this room is full
+    overflow_zone: Option<String>,
+}
+
+// Define a struct for a room of an event along with the check-ins routed to it
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct Room {
+    zone: String,
+    capacity: u64,
+    overflow_zone: Option<String>,
+    checked_in: u64,
+    // Check-ins sent here because the room they asked for was full
+    redirected_in: u64,
+    // Check-ins that asked for this room and were sent elsewhere
+    redirected_out: u64,
+}
+
+// Define a struct for the rooms of an event, the first one is where check-ins go by default
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct EventRooms {
+    rooms: Vec<Room>,
+}
+
+// Define a struct for the room a checked in ticket was sent to
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct ZoneAssignment {
+    ticket_id: u64,
+    // Holder of the ticket at the time of the query
+    user_id: Option<u64>,
+    zone: String,
+    // Room asked for when it was full and the ticket was redirected, None otherwise
+    requested_zone: Option<String>,
+    assigned_at: u64,
+}
+
+impl Storable for EventRooms {
+    // Conversion to bytes
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    // Conversion from bytes
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl Storable for ZoneAssignment {
+    // Conversion to bytes
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    // Conversion from bytes
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for EventRooms {
+    const MAX_SIZE: u32 = 4096;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl BoundedStorable for ZoneAssignment {
+    const MAX_SIZE: u32 = 192;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    // Rooms keyed by event id, events without rooms check in without a zone
+    static ROOM_STORAGE: RefCell<StableBTreeMap<u64, EventRooms, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(61)))
+    ));
+
+    // Assignments keyed by (event id, ticket id), user ids are looked up on read so they
+    // follow transfers and anonymization
+    static ASSIGNMENT_STORAGE: RefCell<StableBTreeMap<(u64, u64), ZoneAssignment, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(62)))
+    ));
+}
+
+fn _get_rooms(event_id: u64) -> EventRooms {
+    ROOM_STORAGE
+        .with(|rooms| rooms.borrow().get(&event_id))
+        .unwrap_or_default()
+}
+
+// Rooms of an event as they were configured, for a template
+pub(crate) fn room_payloads(event_id: u64) -> Vec<RoomPayload> {
+    _get_rooms(event_id)
+        .rooms
+        .into_iter()
+        .map(|room| RoomPayload {
+            zone: room.zone,
+            capacity: room.capacity,
+            overflow_zone: room.overflow_zone,
+        })
+        .collect()
+}
+
+#[ic_cdk::query]
+fn get_event_rooms(event_id: u64) -> Result<Vec<Room>, Error> {
+    organizers::authorize_event(event_id)?;
+    Ok(_get_rooms(event_id).rooms)
+}
+
+#[ic_cdk::update(guard = "rate_limit")]
+pub(crate) fn set_event_rooms(
+    event_id: u64,
+    payloads: Vec<RoomPayload>,
+) -> Result<Vec<Room>, Error> {
+    organizers::authorize_event(event_id)?;
+    if payloads.len() > MAX_ROOMS {
+        return Err(Error::ValidationFailed {
+            msg: format!("event id:{} can have at most {} rooms", event_id, MAX_ROOMS),
+        });
+    }
+
+    let zones: Vec<String> = payloads
+        .iter()
+        .map(|payload| payload.zone.trim().to_string())
+        .collect();
+    for (index, payload) in payloads.iter().enumerate() {
+        let zone = &zones[index];
+        if zone.is_empty() || zone.len() > MAX_ZONE_LEN {
+            return Err(Error::ValidationFailed {
+                msg: format!("zone must be between 1 and {} bytes", MAX_ZONE_LEN),
+            });
+        }
+        if zones[..index].contains(zone) {
+            return Err(Error::ValidationFailed {
+                msg: format!("zone {} is listed more than once", zone),
+            });
+        }
+        if payload.capacity == 0 {
+            return Err(Error::ValidationFailed {
+                msg: format!("capacity of zone {} must be positive", zone),
+            });
+        }
+        if let Some(overflow_zone) = &payload.overflow_zone {
+            let overflow_zone = overflow_zone.trim().to_string();
+            if overflow_zone == *zone || !zones.contains(&overflow_zone) {
+                return Err(Error::ValidationFailed {
+                    msg: format!("overflow of zone {} must be another listed zone", zone),
+                });
+            }
+        }
+    }
+
+    // Rooms kept under the same name keep their counts, so rooms can be resized mid-event
+    let current = _get_rooms(event_id);
+    let rooms: Vec<Room> = payloads
+        .into_iter()
+        .zip(zones)
+        .map(|(payload, zone)| {
+            let counts = current.rooms.iter().find(|room| room.zone == zone);
+            Room {
+                capacity: payload.capacity,
+                overflow_zone: payload.overflow_zone.map(|zone| zone.trim().to_string()),
+                checked_in: counts.map_or(0, |room| room.checked_in),
+                redirected_in: counts.map_or(0, |room| room.redirected_in),
+                redirected_out: counts.map_or(0, |room| room.redirected_out),
+                zone,
+            }
+        })
+        .collect();
+    match rooms.is_empty() {
+        true => ROOM_STORAGE.with(|storage| storage.borrow_mut().remove(&event_id)),
+        false => ROOM_STORAGE.with(|storage| {
+            storage.borrow_mut().insert(
+                event_id,
+                EventRooms {
+                    rooms: rooms.clone(),
+                },
+            )
+        }),
+    };
+
+    Ok(rooms)
+}
+
+// Check-ins that were sent to an overflow room, for organizers to size rooms next time
+#[ic_cdk::query]
+fn get_zone_redirects(event_id: u64) -> Result<Vec<ZoneAssignment>, Error> {
+    organizers::authorize_event(event_id)?;
+    Ok(ASSIGNMENT_STORAGE.with(|assignments| {
+        assignments
+            .borrow()
+            .range((event_id, 0)..=(event_id, u64::MAX))
+            .map(|(_, assignment)| assignment)
+            .filter(|assignment| assignment.requested_zone.is_some())
+            .map(with_holder)
+            .collect()
+    }))
+}
+
+#[ic_cdk::query]
+fn get_ticket_zone(ticket_id: u64) -> Result<Option<ZoneAssignment>, Error> {
+    let ticket = _get_ticket(&ticket_id).ok_or(Error::not_found("ticket", ticket_id))?;
+    Ok(ASSIGNMENT_STORAGE
+        .with(|assignments| assignments.borrow().get(&(ticket.event_id, ticket_id)))
+        .map(with_holder))
+}
+
+fn with_holder(mut assignment: ZoneAssignment) -> ZoneAssignment {
+    assignment.user_id = _get_ticket(&assignment.ticket_id).map(|ticket| ticket.user_id);
+    assignment
+}
+
+// Send a ticket being checked in to a room, the one asked for or the event's first. A full
+// room passes the check-in on along its overflow rooms, to the first with space left. When
+// they are all full it stays in the room asked for. Returns the zone the ticket was sent to,
+// None for events without rooms.
+pub(crate) fn assign_zone(
+    event_id: u64,
+    ticket_id: u64,
+    zone: Option<String>,
+) -> Result<Option<String>, String> {
+    let mut rooms = _get_rooms(event_id);
+    let requested = match zone.map(|zone| zone.trim().to_string()) {
+        Some(zone) => rooms
+            .rooms
+            .iter()
+            .position(|room| room.zone == zone)
+            .ok_or(format!("event id:{} has no zone {}", event_id, zone))?,
+        None if rooms.rooms.is_empty() => return Ok(None),
+        None => 0,
+    };
+
+    let mut visited = vec![requested];
+    let mut index = requested;
+    let assigned = loop {
+        let room = &rooms.rooms[index];
+        if room.checked_in < room.capacity {
+            break index;
+        }
+        match room
+            .overflow_zone
+            .as_ref()
+            .and_then(|zone| rooms.rooms.iter().position(|room| &room.zone == zone))
+            .filter(|next| !visited.contains(next))
+        {
+            Some(next) => {
+                visited.push(next);
+                index = next;
+            }
+            None => break requested,
+        }
+    };
+
+    rooms.rooms[assigned].checked_in += 1;
+    if assigned != requested {
+        rooms.rooms[assigned].redirected_in += 1;
+        rooms.rooms[requested].redirected_out += 1;
+    }
+    let assignment = ZoneAssignment {
+        ticket_id,
+        user_id: None,
+        zone: rooms.rooms[assigned].zone.clone(),
+        requested_zone: (assigned != requested).then(|| rooms.rooms[requested].zone.clone()),
+        assigned_at: time(),
+    };
+    let zone = assignment.zone.clone();
+    ROOM_STORAGE.with(|storage| storage.borrow_mut().insert(event_id, rooms));
+    ASSIGNMENT_STORAGE.with(|assignments| {
+        assignments
+            .borrow_mut()
+            .insert((event_id, ticket_id), assignment)
+    });
+
+    Ok(Some(zone))
+}
+
+// Zone a checked in ticket was sent to, None for events without rooms
+pub(crate) fn assigned_zone(event_id: u64, ticket_id: u64) -> Option<String> {
+    ASSIGNMENT_STORAGE
+        .with(|assignments| assignments.borrow().get(&(event_id, ticket_id)))
+        .map(|assignment| assignment.zone)
+}
+
+// Empty the rooms of an event and forget where its tickets were sent, the rooms stay set up
+pub(crate) fn reset_event_occupancy(event_id: u64) {
+    let mut rooms = _get_rooms(event_id);
+    for room in rooms.rooms.iter_mut() {
+        room.checked_in = 0;
+        room.redirected_in = 0;
+        room.redirected_out = 0;
+    }
+    if !rooms.rooms.is_empty() {
+        ROOM_STORAGE.with(|storage| storage.borrow_mut().insert(event_id, rooms));
+    }
+    remove_event_assignments(event_id);
+}
+
+// Drop the rooms and assignments of a purged event
+pub(crate) fn remove_event_rooms(event_id: u64) {
+    ROOM_STORAGE.with(|rooms| rooms.borrow_mut().remove(&event_id));
+    remove_event_assignments(event_id);
+}
+
+fn remove_event_assignments(event_id: u64) {
+    ASSIGNMENT_STORAGE.with(|assignments| {
+        let mut assignments = assignments.borrow_mut();
+        let keys: Vec<(u64, u64)> = assignments
+            .range((event_id, 0)..=(event_id, u64::MAX))
+            .map(|(key, _)| key)
+            .collect();
+        for key in keys {
+            assignments.remove(&key);
+        }
+    });
+}
@@ -0,0 +1,257 @@
+use crate::clock::time;
+use crate::ratelimit::rate_limit;
+use crate::{
+    _get_event, attendance, backup, certification, checkin, fraud, integrity, legal_holds, orders,
+    organizers, rooms, stats, Error, Memory, ID_COUNTER, MEMORY_MANAGER,
+};
+use candid::{Decode, Encode};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use std::time::Duration;
+use std::{borrow::Cow, cell::RefCell};
+
+// Tickets and attendees removed per timer tick, each one rewrites and recertifies its event
+const RESET_BATCH: usize = 50;
+// Orders looked at per timer tick
+const ORDER_SCAN_BATCH: u64 = 1_000;
+
+// Define an enum for the step a reset is at
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq)]
+pub enum ResetPhase {
+    // Orders holding only tickets of the event are removed, while the tickets still say so
+    Orders,
+    Tickets,
+    Attendees,
+}
+
+// Define a struct for the progress of a reset under way
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct ResetProgress {
+    phase: ResetPhase,
+    // Next order id looked at in the 'Orders' phase
+    order_cursor: u64,
+    orders_removed: u64,
+    tickets_removed: u64,
+    started_at: u64,
+}
+
+// Define a struct for an event organizers rehearse on before the real on-sale, its tickets
+// can be wiped as often as needed
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct TestModeEvent {
+    event_id: u64,
+    enabled_at: u64,
+    // Orders from this id on were placed while in test mode
+    first_order_id: u64,
+    resets: u64,
+    last_reset_at: Option<u64>,
+    // Set while a reset runs, cleared once it is done
+    reset: Option<ResetProgress>,
+}
+
+impl Storable for TestModeEvent {
+    // Conversion to bytes
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    // Conversion from bytes
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for TestModeEvent {
+    const MAX_SIZE: u32 = 256;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    // Events in test mode keyed by event id
+    static TEST_EVENTS: RefCell<StableBTreeMap<u64, TestModeEvent, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(134)))
+    ));
+}
+
+#[ic_cdk::query]
+fn get_test_mode(event_id: u64) -> Result<TestModeEvent, Error> {
+    organizers::authorize_event(event_id)?;
+    _get_test_event(event_id).ok_or(not_in_test_mode(event_id))
+}
+
+// Put an event in test mode before it sells anything, so every ticket it gets is a test ticket
+#[ic_cdk::update(guard = "rate_limit")]
+fn enable_test_mode(event_id: u64) -> Result<TestModeEvent, Error> {
+    let event = organizers::authorize_event(event_id)?;
+    if _get_test_event(event_id).is_some() {
+        return Err(Error::AlreadyExists {
+            msg: format!("event id:{} is already in test mode", event_id),
+        });
+    }
+    if !event.ticket_ids.is_empty() {
+        return Err(Error::ValidationFailed {
+            msg: format!(
+                "event id:{} has sold tickets, only events without any can go in test mode",
+                event_id
+            ),
+        });
+    }
+
+    let test_event = TestModeEvent {
+        event_id,
+        enabled_at: time(),
+        first_order_id: ID_COUNTER.with(|counter| *counter.borrow().get()),
+        resets: 0,
+        last_reset_at: None,
+        reset: None,
+    };
+    TEST_EVENTS.with(|events| events.borrow_mut().insert(event_id, test_event.clone()));
+
+    Ok(test_event)
+}
+
+// Take an event out of test mode for the real on-sale, once the test tickets are wiped
+#[ic_cdk::update(guard = "rate_limit")]
+fn disable_test_mode(event_id: u64) -> Result<String, Error> {
+    let event = organizers::authorize_event(event_id)?;
+    let test_event = _get_test_event(event_id).ok_or(not_in_test_mode(event_id))?;
+    if test_event.reset.is_some() || !event.ticket_ids.is_empty() {
+        return Err(Error::ValidationFailed {
+            msg: format!(
+                "event id:{} still has test tickets, reset it and wait for the reset to finish",
+                event_id
+            ),
+        });
+    }
+
+    TEST_EVENTS.with(|events| events.borrow_mut().remove(&event_id));
+    Ok(format!("event id: {} is out of test mode", event_id))
+}
+
+// Wipe the tickets, orders, attendees and check-ins of an event in test mode, so the door flow
+// can be rehearsed again. It runs in batches from a timer, 'get_test_mode' shows how far it got.
+// Scanner devices, rooms and the event's settings are kept.
+#[ic_cdk::update(guard = "rate_limit")]
+fn reset_test_event(event_id: u64) -> Result<TestModeEvent, Error> {
+    let event = organizers::authorize_event(event_id)?;
+    let mut test_event = _get_test_event(event_id).ok_or(not_in_test_mode(event_id))?;
+    // Asking again while a reset runs leaves it be
+    if test_event.reset.is_some() {
+        return Ok(test_event);
+    }
+    legal_holds::check_event(&event)?;
+
+    test_event.reset = Some(ResetProgress {
+        phase: ResetPhase::Orders,
+        order_cursor: test_event.first_order_id,
+        orders_removed: 0,
+        tickets_removed: 0,
+        started_at: time(),
+    });
+    save(test_event.clone());
+    schedule_reset(event_id);
+
+    Ok(test_event)
+}
+
+// Pick the resets interrupted by an upgrade back up
+pub(crate) fn resume_resets() {
+    let event_ids: Vec<u64> = TEST_EVENTS.with(|events| {
+        events
+            .borrow()
+            .iter()
+            .filter(|(_, test_event)| test_event.reset.is_some())
+            .map(|(event_id, _)| event_id)
+            .collect()
+    });
+    for event_id in event_ids {
+        schedule_reset(event_id);
+    }
+}
+
+// Drop the test mode of a deleted event
+pub(crate) fn remove_event_test_mode(event_id: u64) {
+    TEST_EVENTS.with(|events| events.borrow_mut().remove(&event_id));
+}
+
+fn _get_test_event(event_id: u64) -> Option<TestModeEvent> {
+    TEST_EVENTS.with(|events| events.borrow().get(&event_id))
+}
+
+fn save(test_event: TestModeEvent) {
+    TEST_EVENTS.with(|events| events.borrow_mut().insert(test_event.event_id, test_event));
+}
+
+fn not_in_test_mode(event_id: u64) -> Error {
+    Error::NotFound {
+        entity: "test mode".to_string(),
+        id: event_id.to_string(),
+        msg: format!("event id:{} is not in test mode", event_id),
+    }
+}
+
+// Run the next batch of a reset, scheduling another one until it is done
+fn schedule_reset(event_id: u64) {
+    ic_cdk_timers::set_timer(Duration::ZERO, move || {
+        // Picked up again on the next upgrade
+        if backup::in_maintenance() {
+            return;
+        }
+        let Some(mut test_event) = _get_test_event(event_id) else {
+            return;
+        };
+        let Some(mut progress) = test_event.reset.take() else {
+            return;
+        };
+        let Some(event) = _get_event(&event_id) else {
+            return;
+        };
+
+        match progress.phase {
+            ResetPhase::Orders => {
+                let (removed, next) =
+                    orders::remove_event_orders(event_id, progress.order_cursor, ORDER_SCAN_BATCH);
+                progress.orders_removed += removed;
+                match next {
+                    Some(cursor) => progress.order_cursor = cursor,
+                    None => progress.phase = ResetPhase::Tickets,
+                }
+            }
+            ResetPhase::Tickets => {
+                let batch: Vec<u64> = event.ticket_ids.iter().take(RESET_BATCH).copied().collect();
+                for ticket_id in &batch {
+                    integrity::remove_ticket(*ticket_id);
+                }
+                progress.tickets_removed += batch.len() as u64;
+                if batch.len() < RESET_BATCH {
+                    progress.phase = ResetPhase::Attendees;
+                }
+            }
+            ResetPhase::Attendees => {
+                let batch: Vec<u64> = event
+                    .attendee_ids
+                    .iter()
+                    .take(RESET_BATCH)
+                    .copied()
+                    .collect();
+                integrity::unlink_attendees(event_id, &batch);
+                attendance::remove_event_attendance(event_id, &batch);
+                if batch.len() < RESET_BATCH {
+                    // Counts and check-ins start over along with the tickets
+                    stats::remove_event_stats(event_id);
+                    checkin::remove_event_conflicts(event_id);
+                    fraud::remove_event_fraud(event_id);
+                    rooms::reset_event_occupancy(event_id);
+                    certification::certify_event(event_id);
+                    test_event.resets += 1;
+                    test_event.last_reset_at = Some(time());
+                    save(test_event);
+                    return;
+                }
+            }
+        }
+        test_event.reset = Some(progress);
+        save(test_event);
+        schedule_reset(event_id);
+    });
+}
@@ -0,0 +1,379 @@
+use crate::clock::time;
+use crate::ratelimit::rate_limit;
+use crate::{organizers, Error, Memory, StringKey, MEMORY_MANAGER};
+use candid::{Decode, Encode, Principal};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use std::{borrow::Cow, cell::RefCell};
+
+// Seat maps are bounded so they fit a single stable map entry
+const MAX_SECTIONS: usize = 20;
+const MAX_ROWS: usize = 500;
+const MAX_SEATS_PER_ROW: u32 = 200;
+const MAX_SECTION_LEN: usize = 32;
+const MAX_ROW_LEN: usize = 8;
+
+// Holds keep a seat out of sale while a buyer checks out
+const MAX_HOLD_SECS: u64 = 900;
+const NANOS_PER_SEC: u64 = 1_000_000_000;
+
+// Define a struct for a row of seats, numbered from 1
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct SeatRow {
+    label: String,
+    seats: u32,
+}
+
+// Define a struct for a section of a venue, e.g. "Balcony"
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct SeatSection {
+    name: String,
+    rows: Vec<SeatRow>,
+}
+
+// Define a struct for the seat map of an event
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct SeatMap {
+    sections: Vec<SeatSection>,
+}
+
+// Define a struct for a single seat of a seat map
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, PartialEq)]
+pub struct Seat {
+    section: String,
+    row: String,
+    number: u32,
+}
+
+// Define a struct for a seat held for a buyer until it expires
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct SeatHold {
+    event_id: u64,
+    seat: Seat,
+    held_by: Principal,
+    expires_at: u64,
+}
+
+impl Storable for SeatMap {
+    // Conversion to bytes
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    // Conversion from bytes
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for SeatMap {
+    const MAX_SIZE: u32 = 16384;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl Storable for SeatHold {
+    // Conversion to bytes
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    // Conversion from bytes
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for SeatHold {
+    const MAX_SIZE: u32 = 256;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    // Seat maps keyed by event id
+    static SEAT_MAPS: RefCell<StableBTreeMap<u64, SeatMap, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(37)))
+    ));
+
+    // Ticket id each sold seat went to, keyed by (event id, seat key)
+    static SOLD_SEATS: RefCell<StableBTreeMap<(u64, StringKey), u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(38)))
+    ));
+
+    // Seat holds keyed by (event id, seat key), expired ones are ignored until swept
+    static SEAT_HOLDS: RefCell<StableBTreeMap<(u64, StringKey), SeatHold, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(39)))
+    ));
+}
+
+#[ic_cdk::query]
+fn get_seat_map(event_id: u64) -> Result<SeatMap, Error> {
+    _get_seat_map(event_id).ok_or(Error::NotFound {
+        entity: "seat map".to_string(),
+        id: event_id.to_string(),
+        msg: format!("event id:{} has no seat map", event_id),
+    })
+}
+
+#[ic_cdk::update(guard = "rate_limit")]
+pub(crate) fn set_seat_map(event_id: u64, seat_map: Option<SeatMap>) -> Result<String, Error> {
+    organizers::authorize_event(event_id)?;
+    if let Some(seat_map) = &seat_map {
+        validate_seat_map(seat_map).map_err(|msg| Error::ValidationFailed { msg })?;
+    }
+
+    // Seats already sold must still exist, so no ticket is left pointing at nothing
+    let missing = sold_seats(event_id)
+        .into_iter()
+        .find(|seat| match &seat_map {
+            Some(seat_map) => !on_map(seat_map, seat),
+            None => true,
+        });
+    if let Some(seat) = missing {
+        return Err(Error::HasDependents {
+            msg: format!(
+                "seat {} of event id:{} is already sold",
+                seat_label(&seat),
+                event_id
+            ),
+        });
+    }
+
+    match seat_map {
+        Some(seat_map) => {
+            SEAT_MAPS.with(|maps| maps.borrow_mut().insert(event_id, seat_map));
+            Ok(format!("seat map of event id: {} updated", event_id))
+        }
+        None => {
+            SEAT_MAPS.with(|maps| maps.borrow_mut().remove(&event_id));
+            remove_holds(event_id, |_| true);
+            Ok(format!("seat map of event id: {} removed", event_id))
+        }
+    }
+}
+
+#[ic_cdk::query]
+fn get_available_seats(event_id: u64, section: String) -> Result<Vec<Seat>, Error> {
+    let seat_map = get_seat_map(event_id)?;
+    let section = seat_map
+        .sections
+        .iter()
+        .find(|candidate| candidate.name == section)
+        .ok_or(Error::NotFound {
+            entity: "section".to_string(),
+            id: section.to_string(),
+            msg: format!(
+                "section {} does not exist for event id:{}",
+                section, event_id
+            ),
+        })?;
+
+    let now = time();
+    Ok(section
+        .rows
+        .iter()
+        .flat_map(|row| {
+            (1..=row.seats).map(|number| Seat {
+                section: section.name.clone(),
+                row: row.label.clone(),
+                number,
+            })
+        })
+        .filter(|seat| {
+            let key = (event_id, seat_key(seat));
+            !SOLD_SEATS.with(|sold| sold.borrow().contains_key(&key))
+                && SEAT_HOLDS
+                    .with(|holds| holds.borrow().get(&key))
+                    .is_none_or(|hold| hold.expires_at <= now)
+        })
+        .collect())
+}
+
+#[ic_cdk::update(guard = "rate_limit")]
+fn hold_seat(event_id: u64, seat: Seat, ttl: u64) -> Result<SeatHold, Error> {
+    if ttl == 0 || ttl > MAX_HOLD_SECS {
+        return Err(Error::ValidationFailed {
+            msg: format!("a hold lasts between 1 and {} seconds", MAX_HOLD_SECS),
+        });
+    }
+    check_seat(event_id, &seat).map_err(|msg| Error::ValidationFailed { msg })?;
+
+    // Drop the expired holds of the event, so they don't pile up
+    let now = time();
+    remove_holds(event_id, |hold| hold.expires_at <= now);
+
+    // The same buyer can hold a seat again to extend the hold
+    let hold = SeatHold {
+        event_id,
+        held_by: ic_cdk::caller(),
+        expires_at: now + ttl * NANOS_PER_SEC,
+        seat,
+    };
+    SEAT_HOLDS.with(|holds| {
+        holds
+            .borrow_mut()
+            .insert((event_id, seat_key(&hold.seat)), hold.clone())
+    });
+    Ok(hold)
+}
+
+// Check a seat can be sold: it is on the map, not sold yet and not held by someone else
+pub(crate) fn check_seat(event_id: u64, seat: &Seat) -> Result<(), String> {
+    let seat_map =
+        _get_seat_map(event_id).ok_or(format!("event id:{} has no seat map", event_id))?;
+    if !on_map(&seat_map, seat) {
+        return Err(format!(
+            "seat {} does not exist for event id:{}",
+            seat_label(seat),
+            event_id
+        ));
+    }
+    let key = (event_id, seat_key(seat));
+    if SOLD_SEATS.with(|sold| sold.borrow().contains_key(&key)) {
+        return Err(format!("seat {} is already sold", seat_label(seat)));
+    }
+    let hold = SEAT_HOLDS.with(|holds| holds.borrow().get(&key));
+    if hold.is_some_and(|hold| hold.expires_at > time() && hold.held_by != ic_cdk::caller()) {
+        return Err(format!(
+            "seat {} is held by another buyer",
+            seat_label(seat)
+        ));
+    }
+    Ok(())
+}
+
+// Seat key used to tell seats of a batch apart
+pub(crate) fn seat_label(seat: &Seat) -> String {
+    seat_key(seat).0
+}
+
+// Mark a seat as sold to a ticket, ending any hold on it
+pub(crate) fn assign_seat(event_id: u64, seat: &Seat, ticket_id: u64) {
+    let key = (event_id, seat_key(seat));
+    SEAT_HOLDS.with(|holds| holds.borrow_mut().remove(&key));
+    SOLD_SEATS.with(|sold| sold.borrow_mut().insert(key, ticket_id));
+}
+
+// Put the seat of a cancelled ticket back on sale
+pub(crate) fn release_seat(event_id: u64, seat: &Seat) {
+    SOLD_SEATS.with(|sold| sold.borrow_mut().remove(&(event_id, seat_key(seat))));
+}
+
+// Drop the seat map, sold seats and holds of a deleted event
+pub(crate) fn remove_event_seats(event_id: u64) {
+    SEAT_MAPS.with(|maps| maps.borrow_mut().remove(&event_id));
+    SOLD_SEATS.with(|sold| {
+        let mut sold = sold.borrow_mut();
+        for seat in sold_seats(event_id) {
+            sold.remove(&(event_id, seat_key(&seat)));
+        }
+    });
+    remove_holds(event_id, |_| true);
+}
+
+pub(crate) fn _get_seat_map(event_id: u64) -> Option<SeatMap> {
+    SEAT_MAPS.with(|maps| maps.borrow().get(&event_id))
+}
+
+fn validate_seat_map(seat_map: &SeatMap) -> Result<(), String> {
+    if seat_map.sections.is_empty() || seat_map.sections.len() > MAX_SECTIONS {
+        return Err(format!(
+            "a seat map has between 1 and {} sections",
+            MAX_SECTIONS
+        ));
+    }
+    let rows: usize = seat_map
+        .sections
+        .iter()
+        .map(|section| section.rows.len())
+        .sum();
+    if rows > MAX_ROWS {
+        return Err(format!("a seat map has at most {} rows", MAX_ROWS));
+    }
+
+    for (index, section) in seat_map.sections.iter().enumerate() {
+        check_label(&section.name, MAX_SECTION_LEN, "section name")?;
+        if seat_map.sections[..index]
+            .iter()
+            .any(|other| other.name == section.name)
+        {
+            return Err(format!("section {} is given more than once", section.name));
+        }
+        for (index, row) in section.rows.iter().enumerate() {
+            check_label(&row.label, MAX_ROW_LEN, "row label")?;
+            if section.rows[..index]
+                .iter()
+                .any(|other| other.label == row.label)
+            {
+                return Err(format!(
+                    "row {} of section {} is given more than once",
+                    row.label, section.name
+                ));
+            }
+            if row.seats == 0 || row.seats > MAX_SEATS_PER_ROW {
+                return Err(format!(
+                    "row {} of section {} must have between 1 and {} seats",
+                    row.label, section.name, MAX_SEATS_PER_ROW
+                ));
+            }
+        }
+    }
+    Ok(())
+}
+
+// Labels make up seat keys, so they can't contain the separator
+fn check_label(label: &str, max_len: usize, what: &str) -> Result<(), String> {
+    if label.trim().is_empty() || label.len() > max_len || label.contains('/') {
+        return Err(format!(
+            "{} '{}' must be 1 to {} bytes without '/'",
+            what, label, max_len
+        ));
+    }
+    Ok(())
+}
+
+fn seat_key(seat: &Seat) -> StringKey {
+    StringKey(format!("{}/{}/{}", seat.section, seat.row, seat.number))
+}
+
+fn on_map(seat_map: &SeatMap, seat: &Seat) -> bool {
+    seat_map
+        .sections
+        .iter()
+        .filter(|section| section.name == seat.section)
+        .flat_map(|section| &section.rows)
+        .any(|row| row.label == seat.row && (1..=row.seats).contains(&seat.number))
+}
+
+// Seats of an event already sold, read back from their keys
+fn sold_seats(event_id: u64) -> Vec<Seat> {
+    SOLD_SEATS.with(|sold| {
+        sold.borrow()
+            .range((event_id, StringKey::default())..)
+            .take_while(|((id, _), _)| *id == event_id)
+            .filter_map(|((_, key), _)| {
+                let mut parts = key.0.splitn(3, '/');
+                Some(Seat {
+                    section: parts.next()?.to_string(),
+                    row: parts.next()?.to_string(),
+                    number: parts.next()?.parse().ok()?,
+                })
+            })
+            .collect()
+    })
+}
+
+fn remove_holds(event_id: u64, f: impl Fn(&SeatHold) -> bool) {
+    SEAT_HOLDS.with(|holds| {
+        let mut holds = holds.borrow_mut();
+        let keys: Vec<(u64, StringKey)> = holds
+            .range((event_id, StringKey::default())..)
+            .take_while(|((id, _), _)| *id == event_id)
+            .filter(|(_, hold)| f(hold))
+            .map(|(key, _)| key)
+            .collect();
+        for key in keys {
+            holds.remove(&key);
+        }
+    });
+}
@@ -0,0 +1,246 @@
+use crate::clock::time;
+use crate::dates::{add_days, add_months, format_date, parse_date};
+use crate::ratelimit::rate_limit;
+use crate::{
+    _create_event, _get_event, apply_event_update, certification, organizers, prepare_event_update,
+    store_event, update_event, validation, venues, Error, Event, EventPayload, Memory, ID_COUNTER,
+    MEMORY_MANAGER,
+};
+use candid::{Decode, Encode};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use std::{borrow::Cow, cell::RefCell};
+
+// Upper bound on the instances materialized for a single series
+const MAX_SERIES_INSTANCES: u32 = 100;
+
+// Define an enum for how often a series repeats
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize)]
+pub enum Frequency {
+    Daily,
+    Weekly,
+    Monthly,
+}
+
+// Define a struct for the 'RecurrenceRule' of a series
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct RecurrenceRule {
+    frequency: Frequency,
+    // Repeat every 'interval' days, weeks or months
+    interval: u32,
+    // At least one of 'count' and 'until' (an inclusive YYYY-MM-DD date) must be set
+    count: Option<u32>,
+    until: Option<String>,
+}
+
+// Define a struct for the 'EventSeries'
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct EventSeries {
+    id: u64,
+    rule: RecurrenceRule,
+    event_ids: Vec<u64>,
+    created_at: u64,
+    updated_at: Option<u64>,
+}
+
+// Define a struct for a series together with its instances
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub struct SeriesDetails {
+    series: EventSeries,
+    events: Vec<Event>,
+}
+
+// Define an enum for the instances touched by a series update
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub enum SeriesUpdateScope {
+    ThisInstance,
+    AllFutureInstances,
+}
+
+impl Storable for EventSeries {
+    // Conversion to bytes
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    // Conversion from bytes
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for EventSeries {
+    const MAX_SIZE: u32 = 2048;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    static SERIES_STORAGE: RefCell<StableBTreeMap<u64, EventSeries, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(11)))
+    ));
+}
+
+fn _get_series(id: &u64) -> Option<EventSeries> {
+    // Helper function to get a series from the storage based on the provided ID
+    SERIES_STORAGE.with(|series| series.borrow().get(id))
+}
+
+#[ic_cdk::query]
+fn get_series(series_id: u64) -> Result<SeriesDetails, Error> {
+    // Retrieve the series with the given ID, or return a NotFound error if not found
+    let series = _get_series(&series_id).ok_or(Error::not_found("series", series_id))?;
+
+    // Resolve the instances that still exist, in date order
+    let events = series.event_ids.iter().filter_map(_get_event).collect();
+
+    Ok(SeriesDetails { series, events })
+}
+
+#[ic_cdk::update(guard = "rate_limit")]
+async fn create_event_series(
+    payload: EventPayload,
+    rule: RecurrenceRule,
+) -> Result<SeriesDetails, Error> {
+    organizers::check_can_create()?;
+    let dates = expand_rule(&payload.date, &rule)?;
+
+    // The hook approves the series as a whole, as its instances only differ in date
+    validation::validate_event(&payload)
+        .await
+        .map_err(|msg| Error::ValidationFailed { msg })?;
+
+    // Increment the global ID counter to get a new ID for the series
+    let id = ID_COUNTER
+        .with(|counter| {
+            let current_id = *counter.borrow().get();
+            counter.borrow_mut().set(current_id + 1)
+        })
+        .expect("Cannot increment Ids");
+
+    // Materialize one event per occurrence, all pointing back at the series
+    let mut events = vec![];
+    for date in dates {
+        let mut event = _create_event(EventPayload {
+            date,
+            ..payload.clone()
+        })?;
+        event.series_id = Some(id);
+        venues::check_schedule(&event)?;
+        store_event(&mut event);
+        certification::certify_event(event.id);
+        events.push(event);
+    }
+
+    let series = EventSeries {
+        id,
+        rule,
+        event_ids: events.iter().map(|event| event.id).collect(),
+        created_at: time(),
+        updated_at: None,
+    };
+    SERIES_STORAGE.with(|storage| storage.borrow_mut().insert(id, series.clone()));
+
+    Ok(SeriesDetails { series, events })
+}
+
+#[ic_cdk::update(guard = "rate_limit")]
+fn update_series_event(
+    event_id: u64,
+    payload: EventPayload,
+    scope: SeriesUpdateScope,
+) -> Result<Vec<Event>, Error> {
+    // Retrieve the event with the given ID, or return a NotFound error if not found
+    let event = _get_event(&event_id).ok_or(Error::not_found("event", event_id))?;
+    let series_id = event.series_id.ok_or(Error::ValidationFailed {
+        msg: format!("event id:{} is not part of a series", event_id),
+    })?;
+    let series = _get_series(&series_id).ok_or(Error::not_found("series", series_id))?;
+
+    if let SeriesUpdateScope::ThisInstance = scope {
+        return Ok(vec![update_event(event_id, payload, None)?]);
+    }
+
+    // Later instances take the new details but keep their own dates. All of them are checked
+    // before any is changed, so a failed check doesn't leave the series half updated.
+    let mut prepared = vec![prepare_event_update(event_id, payload.clone(), None)?];
+    for instance in series.event_ids.iter().filter_map(_get_event) {
+        if instance.id != event_id && instance.date > event.date {
+            prepared.push(prepare_event_update(
+                instance.id,
+                EventPayload {
+                    date: instance.date,
+                    ..payload.clone()
+                },
+                None,
+            )?);
+        }
+    }
+
+    prepared
+        .into_iter()
+        .map(|(instance, updated_instance)| apply_event_update(&instance, updated_instance))
+        .collect()
+}
+
+pub(crate) fn series_event_ids(series_id: u64) -> Option<Vec<u64>> {
+    _get_series(&series_id).map(|series| series.event_ids)
+}
+
+// Detach a deleted event from its series
+pub(crate) fn remove_series_instance(series_id: u64, event_id: u64) {
+    if let Some(mut series) = _get_series(&series_id) {
+        series.event_ids.retain(|&id| id != event_id);
+        series.updated_at = Some(time());
+        SERIES_STORAGE.with(|storage| storage.borrow_mut().insert(series_id, series));
+    }
+}
+
+// Compute the dates of all occurrences of a rule starting at 'start'
+fn expand_rule(start: &str, rule: &RecurrenceRule) -> Result<Vec<String>, Error> {
+    let invalid = |msg: String| Error::ValidationFailed { msg };
+
+    let start = parse_date(start).ok_or(invalid(format!(
+        "series start date {} is not a YYYY-MM-DD date",
+        start
+    )))?;
+    let until = match &rule.until {
+        Some(until) => Some(parse_date(until).ok_or(invalid(format!(
+            "series end date {} is not a YYYY-MM-DD date",
+            until
+        )))?),
+        None => None,
+    };
+    if rule.interval == 0 {
+        return Err(invalid(
+            "recurrence interval must be at least 1".to_string(),
+        ));
+    }
+    if rule.count.is_none() && until.is_none() {
+        return Err(invalid(
+            "recurrence needs a count or an end date".to_string(),
+        ));
+    }
+    let count = rule.count.unwrap_or(MAX_SERIES_INSTANCES);
+    if count > MAX_SERIES_INSTANCES {
+        return Err(invalid(format!(
+            "a series can have at most {} instances",
+            MAX_SERIES_INSTANCES
+        )));
+    }
+
+    let mut dates = vec![];
+    for n in 0..count {
+        let step = n as i64 * rule.interval as i64;
+        let date = match rule.frequency {
+            Frequency::Daily => add_days(start, step),
+            Frequency::Weekly => add_days(start, step * 7),
+            Frequency::Monthly => add_months(start, step),
+        };
+        if until.is_some_and(|until| date > until) {
+            break;
+        }
+        dates.push(format_date(date));
+    }
+
+    Ok(dates)
+}
@@ -0,0 +1,471 @@
+use crate::clock::time;
+use crate::ratelimit::rate_limit;
+use crate::{
+    _get_event, _get_user, issue_ticket, organizers, stats, Error, Memory, Ticket, ID_COUNTER,
+    MEMORY_MANAGER,
+};
+use candid::{Decode, Encode};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use std::{borrow::Cow, cell::RefCell};
+
+// Tickets a single commitment can issue, so their ids fit in the commitment's entry
+const MAX_SPONSOR_TICKETS: u64 = 100;
+
+// Define a struct for a high-value 'SponsorshipOffer' (sponsor table, booth, ...)
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub struct SponsorshipOffer {
+    id: u64,
+    event_id: u64,
+    name: String,
+    description: String,
+    // Price of a single unit, invoiced outside the regular checkout
+    price: u64,
+    quantity: u64,
+    // Tickets issued to the sponsor for every unit paid
+    tickets_per_unit: u64,
+    committed: u64,
+    created_at: u64,
+    updated_at: Option<u64>,
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize, Default)]
+pub struct SponsorshipOfferPayload {
+    name: String,
+    description: String,
+    price: u64,
+    quantity: u64,
+    tickets_per_unit: u64,
+}
+
+// Define an enum for the lifecycle of a sponsorship commitment
+#[derive(candid::CandidType, Clone, Copy, PartialEq, Serialize, Deserialize, Default)]
+pub enum CommitmentStatus {
+    #[default]
+    Invoiced,
+    Paid,
+    Fulfilled,
+    Cancelled,
+}
+
+// Define a struct for a sponsor's 'SponsorshipCommitment' to an offer
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub struct SponsorshipCommitment {
+    id: u64,
+    offer_id: u64,
+    event_id: u64,
+    user_id: u64,
+    quantity: u64,
+    amount_due: u64,
+    invoice_reference: String,
+    status: CommitmentStatus,
+    ticket_ids: Vec<u64>,
+    fulfillment_notes: String,
+    created_at: u64,
+    updated_at: Option<u64>,
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize, Default)]
+pub struct CommitmentPayload {
+    user_id: u64,
+    quantity: u64,
+    invoice_reference: String,
+}
+
+impl Storable for SponsorshipOffer {
+    // Conversion to bytes
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    // Conversion from bytes
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl Storable for SponsorshipCommitment {
+    // Conversion to bytes
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    // Conversion from bytes
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for SponsorshipOffer {
+    const MAX_SIZE: u32 = 1024;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl BoundedStorable for SponsorshipCommitment {
+    const MAX_SIZE: u32 = 2048;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    static OFFER_STORAGE: RefCell<StableBTreeMap<u64, SponsorshipOffer, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(12)))
+    ));
+
+    static COMMITMENT_STORAGE: RefCell<StableBTreeMap<u64, SponsorshipCommitment, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(13)))
+    ));
+}
+
+fn _get_offer(id: &u64) -> Option<SponsorshipOffer> {
+    // Helper function to get an offer from the storage based on the provided ID
+    OFFER_STORAGE.with(|offers| offers.borrow().get(id))
+}
+
+fn _get_commitment(id: &u64) -> Option<SponsorshipCommitment> {
+    // Helper function to get a commitment from the storage based on the provided ID
+    COMMITMENT_STORAGE.with(|commitments| commitments.borrow().get(id))
+}
+
+#[ic_cdk::query]
+fn get_event_sponsorship_offers(event_id: u64) -> Result<Vec<SponsorshipOffer>, Error> {
+    // Only the event's organizers can change it, or return an error if the caller isn't one
+    organizers::authorize_event(event_id)?;
+
+    Ok(OFFER_STORAGE.with(|offers| {
+        offers
+            .borrow()
+            .iter()
+            .filter(|(_, offer)| offer.event_id == event_id)
+            .map(|(_, offer)| offer)
+            .collect()
+    }))
+}
+
+#[ic_cdk::query]
+fn get_event_sponsorships(event_id: u64) -> Result<Vec<SponsorshipCommitment>, Error> {
+    // Make sure the event exists, or return a NotFound error if not found
+    _get_event(&event_id).ok_or(Error::not_found("event", event_id))?;
+
+    Ok(COMMITMENT_STORAGE.with(|commitments| {
+        commitments
+            .borrow()
+            .iter()
+            .filter(|(_, commitment)| commitment.event_id == event_id)
+            .map(|(_, commitment)| commitment)
+            .collect()
+    }))
+}
+
+#[ic_cdk::update(guard = "rate_limit")]
+fn create_sponsorship_offer(
+    event_id: u64,
+    payload: SponsorshipOfferPayload,
+) -> Result<SponsorshipOffer, Error> {
+    // Make sure the event exists, or return a NotFound error if not found
+    _get_event(&event_id).ok_or(Error::not_found("event", event_id))?;
+
+    // Increment the global ID counter to get a new ID for the offer
+    let id = ID_COUNTER
+        .with(|counter| {
+            let current_id = *counter.borrow().get();
+            counter.borrow_mut().set(current_id + 1)
+        })
+        .expect("Cannot increment Ids");
+
+    let offer = SponsorshipOffer {
+        id,
+        event_id,
+        name: payload.name,
+        description: payload.description,
+        price: payload.price,
+        quantity: payload.quantity,
+        tickets_per_unit: payload.tickets_per_unit,
+        committed: 0,
+        created_at: time(),
+        updated_at: None,
+    };
+
+    OFFER_STORAGE.with(|offers| offers.borrow_mut().insert(id, offer.clone()));
+
+    Ok(offer)
+}
+
+#[ic_cdk::update(guard = "rate_limit")]
+fn record_sponsorship_commitment(
+    offer_id: u64,
+    payload: CommitmentPayload,
+) -> Result<SponsorshipCommitment, Error> {
+    // Retrieve the offer and the sponsor, or return a NotFound error if not found
+    let mut offer = _get_offer(&offer_id).ok_or(Error::not_found("sponsorship offer", offer_id))?;
+    organizers::authorize_event(offer.event_id)?;
+    _get_user(&payload.user_id).ok_or(Error::not_found("user", payload.user_id))?;
+
+    // Units are held for the sponsor as soon as the invoice goes out
+    let (committed, amount_due) = price_commitment(&offer, payload.quantity)?;
+
+    // Increment the global ID counter to get a new ID for the commitment
+    let id = ID_COUNTER
+        .with(|counter| {
+            let current_id = *counter.borrow().get();
+            counter.borrow_mut().set(current_id + 1)
+        })
+        .expect("Cannot increment Ids");
+
+    let commitment = SponsorshipCommitment {
+        id,
+        offer_id,
+        event_id: offer.event_id,
+        user_id: payload.user_id,
+        quantity: payload.quantity,
+        amount_due,
+        invoice_reference: payload.invoice_reference,
+        status: CommitmentStatus::Invoiced,
+        ticket_ids: vec![],
+        fulfillment_notes: String::new(),
+        created_at: time(),
+        updated_at: None,
+    };
+
+    offer.committed = committed;
+    offer.updated_at = Some(time());
+    OFFER_STORAGE.with(|offers| offers.borrow_mut().insert(offer_id, offer));
+    COMMITMENT_STORAGE.with(|commitments| commitments.borrow_mut().insert(id, commitment.clone()));
+
+    Ok(commitment)
+}
+
+#[ic_cdk::update(guard = "rate_limit")]
+fn mark_sponsorship_paid(commitment_id: u64) -> Result<SponsorshipCommitment, Error> {
+    let mut commitment = _get_invoiced_commitment(commitment_id)?;
+    // Only the event's organizers confirm the invoice was paid
+    organizers::authorize_event(commitment.event_id)?;
+    let offer = _get_offer(&commitment.offer_id)
+        .ok_or(Error::not_found("sponsorship offer", commitment.offer_id))?;
+
+    // Everything the tickets need is checked before the first is issued
+    let count = sponsor_ticket_count(&commitment, &offer)?;
+    _get_user(&commitment.user_id).ok_or(Error::not_found("user", commitment.user_id))?;
+
+    // Issue the sponsor's tickets, outside of the regular tiers and capacity. A failure traps,
+    // which rolls back the tickets already issued so the commitment can be marked paid again.
+    for _ in 0..count {
+        let ticket = issue_ticket(Ticket {
+            event_id: commitment.event_id,
+            user_id: commitment.user_id,
+            ..Default::default()
+        })
+        .unwrap_or_else(|_| {
+            ic_cdk::trap(&format!(
+                "tickets for sponsorship id:{} could not be issued, none were",
+                commitment_id
+            ))
+        });
+        commitment.ticket_ids.push(ticket.id);
+    }
+
+    commitment.status = CommitmentStatus::Paid;
+    commitment.updated_at = Some(time());
+    COMMITMENT_STORAGE.with(|commitments| {
+        commitments
+            .borrow_mut()
+            .insert(commitment_id, commitment.clone())
+    });
+    stats::record_sponsorship_paid(commitment.event_id, commitment.amount_due);
+
+    Ok(commitment)
+}
+
+#[ic_cdk::update(guard = "rate_limit")]
+fn fulfill_sponsorship(
+    commitment_id: u64,
+    fulfillment_notes: String,
+) -> Result<SponsorshipCommitment, Error> {
+    let mut commitment =
+        _get_commitment(&commitment_id).ok_or(Error::not_found("sponsorship", commitment_id))?;
+    organizers::authorize_event(commitment.event_id)?;
+
+    // Deliverables are only fulfilled once the invoice is paid
+    if commitment.status != CommitmentStatus::Paid {
+        return Err(Error::ValidationFailed {
+            msg: format!("sponsorship id:{} is not paid", commitment_id),
+        });
+    }
+
+    commitment.status = CommitmentStatus::Fulfilled;
+    commitment.fulfillment_notes = fulfillment_notes;
+    commitment.updated_at = Some(time());
+    COMMITMENT_STORAGE.with(|commitments| {
+        commitments
+            .borrow_mut()
+            .insert(commitment_id, commitment.clone())
+    });
+
+    Ok(commitment)
+}
+
+#[ic_cdk::update(guard = "rate_limit")]
+fn cancel_sponsorship(commitment_id: u64) -> Result<SponsorshipCommitment, Error> {
+    let mut commitment = _get_invoiced_commitment(commitment_id)?;
+    organizers::authorize_event(commitment.event_id)?;
+
+    // Give the held units back to the offer
+    if let Some(mut offer) = _get_offer(&commitment.offer_id) {
+        offer.committed = offer.committed.saturating_sub(commitment.quantity);
+        offer.updated_at = Some(time());
+        OFFER_STORAGE.with(|offers| offers.borrow_mut().insert(offer.id, offer));
+    }
+
+    commitment.status = CommitmentStatus::Cancelled;
+    commitment.updated_at = Some(time());
+    COMMITMENT_STORAGE.with(|commitments| {
+        commitments
+            .borrow_mut()
+            .insert(commitment_id, commitment.clone())
+    });
+
+    Ok(commitment)
+}
+
+// Units an offer has committed once a commitment of a quantity is added, and what the
+// commitment is invoiced
+fn price_commitment(offer: &SponsorshipOffer, quantity: u64) -> Result<(u64, u64), Error> {
+    let committed = offer
+        .committed
+        .checked_add(quantity)
+        .ok_or(Error::ValidationFailed {
+            msg: format!("quantity of {} is too large", quantity),
+        })?;
+    if quantity == 0 || committed > offer.quantity {
+        return Err(Error::CapacityExceeded {
+            msg: format!(
+                "sponsorship offer id:{} has {} units left",
+                offer.id,
+                offer.quantity.saturating_sub(offer.committed)
+            ),
+        });
+    }
+    let amount_due = offer
+        .price
+        .checked_mul(quantity)
+        .ok_or(Error::ValidationFailed {
+            msg: format!(
+                "{} units of sponsorship offer id:{} cost more than can be invoiced",
+                quantity, offer.id
+            ),
+        })?;
+    Ok((committed, amount_due))
+}
+
+// Tickets a commitment issues once paid
+fn sponsor_ticket_count(
+    commitment: &SponsorshipCommitment,
+    offer: &SponsorshipOffer,
+) -> Result<u64, Error> {
+    commitment
+        .quantity
+        .checked_mul(offer.tickets_per_unit)
+        .filter(|count| *count <= MAX_SPONSOR_TICKETS)
+        .ok_or(Error::ValidationFailed {
+            msg: format!(
+                "sponsorship id:{} would issue more than {} tickets",
+                commitment.id, MAX_SPONSOR_TICKETS
+            ),
+        })
+}
+
+// Helper function to get a commitment that is still awaiting payment
+fn _get_invoiced_commitment(commitment_id: u64) -> Result<SponsorshipCommitment, Error> {
+    let commitment =
+        _get_commitment(&commitment_id).ok_or(Error::not_found("sponsorship", commitment_id))?;
+    if commitment.status != CommitmentStatus::Invoiced {
+        return Err(Error::ValidationFailed {
+            msg: format!("sponsorship id:{} is no longer invoiced", commitment_id),
+        });
+    }
+    Ok(commitment)
+}
+
+// Drop the offers and commitments of a deleted event
+pub(crate) fn remove_event_sponsorships(event_id: u64) {
+    OFFER_STORAGE.with(|offers| {
+        let mut offers = offers.borrow_mut();
+        let ids: Vec<u64> = offers
+            .iter()
+            .filter(|(_, offer)| offer.event_id == event_id)
+            .map(|(id, _)| id)
+            .collect();
+        for id in ids {
+            offers.remove(&id);
+        }
+    });
+    COMMITMENT_STORAGE.with(|commitments| {
+        let mut commitments = commitments.borrow_mut();
+        let ids: Vec<u64> = commitments
+            .iter()
+            .filter(|(_, commitment)| commitment.event_id == event_id)
+            .map(|(id, _)| id)
+            .collect();
+        for id in ids {
+            commitments.remove(&id);
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn offer(quantity: u64, committed: u64, price: u64) -> SponsorshipOffer {
+        SponsorshipOffer {
+            id: 1,
+            quantity,
+            committed,
+            price,
+            tickets_per_unit: 2,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn commitment_holds_units_and_invoices_their_price() {
+        let (committed, amount_due) = price_commitment(&offer(10, 4, 250), 3).unwrap();
+        assert_eq!(committed, 7);
+        assert_eq!(amount_due, 750);
+    }
+
+    #[test]
+    fn commitment_beyond_the_units_left_is_refused() {
+        assert!(matches!(
+            price_commitment(&offer(10, 8, 250), 3),
+            Err(Error::CapacityExceeded { .. })
+        ));
+        assert!(matches!(
+            price_commitment(&offer(10, 0, 250), 0),
+            Err(Error::CapacityExceeded { .. })
+        ));
+    }
+
+    #[test]
+    fn overflowing_quantity_or_amount_is_refused() {
+        assert!(matches!(
+            price_commitment(&offer(u64::MAX, 1, 250), u64::MAX),
+            Err(Error::ValidationFailed { .. })
+        ));
+        assert!(matches!(
+            price_commitment(&offer(u64::MAX, 0, u64::MAX), 2),
+            Err(Error::ValidationFailed { .. })
+        ));
+    }
+
+    #[test]
+    fn commitment_issues_at_most_the_ticket_limit() {
+        let commitment = |quantity| SponsorshipCommitment {
+            quantity,
+            ..Default::default()
+        };
+        let offer = offer(u64::MAX, 0, 0);
+        assert_eq!(sponsor_ticket_count(&commitment(50), &offer).unwrap(), 100);
+        assert!(sponsor_ticket_count(&commitment(51), &offer).is_err());
+        assert!(sponsor_ticket_count(&commitment(u64::MAX), &offer).is_err());
+    }
+}
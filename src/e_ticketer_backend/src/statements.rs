@@ -0,0 +1,303 @@
+use crate::clock::time;
+use crate::export::{self, ExportChunk, ExportFormat};
+use crate::ratelimit::rate_limit;
+use crate::{_get_event, caller_is_admin, dates, organizers, Error, Memory, MEMORY_MANAGER};
+use candid::{Decode, Encode};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use std::{borrow::Cow, cell::RefCell};
+
+const NANOS_PER_DAY: u64 = 24 * 60 * 60 * 1_000_000_000;
+
+// Define a struct for the money that went through an organizer's events in a calendar month.
+// Movements are booked to the month they happen in, so a closed month never changes again.
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct Statement {
+    organizer_id: u64,
+    // Month as YYYY-MM
+    period: String,
+    // Ending balance of the last closed statement before this one
+    opening_balance: i64,
+    // Ticket sales, paid sponsorships and donations, gross
+    revenue: u64,
+    refunds: u64,
+    // Withheld from donations
+    fees: u64,
+    payouts: u64,
+    ending_balance: i64,
+    // Set once closed, the statement is locked from then on
+    closed_at: Option<u64>,
+}
+
+// Define a struct for the movements of a month as stored, balances are worked out on read
+// while the month is open
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct MonthTotals {
+    revenue: u64,
+    refunds: u64,
+    fees: u64,
+    payouts: u64,
+    opening_balance: i64,
+    closed_at: Option<u64>,
+}
+
+impl Storable for MonthTotals {
+    // Conversion to bytes
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    // Conversion from bytes
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for MonthTotals {
+    const MAX_SIZE: u32 = 128;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    // Totals keyed by (organizer id, months since year 0)
+    static STATEMENT_STORAGE: RefCell<StableBTreeMap<(u64, u64), MonthTotals, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(53)))
+    ));
+}
+
+#[ic_cdk::query]
+fn get_statement(organizer_id: u64, period: String) -> Result<Statement, Error> {
+    organizers::check_organizer(organizer_id)?;
+    let month = parse_period(&period).map_err(|msg| Error::ValidationFailed { msg })?;
+    Ok(statement(
+        organizer_id,
+        month,
+        &month_totals(organizer_id, month),
+    ))
+}
+
+#[ic_cdk::query]
+fn get_statements(organizer_id: u64) -> Result<Vec<Statement>, Error> {
+    organizers::check_organizer(organizer_id)?;
+    Ok(statements(organizer_id))
+}
+
+#[ic_cdk::query]
+fn export_statements(
+    organizer_id: u64,
+    format: ExportFormat,
+    chunk: Option<u64>,
+) -> Result<ExportChunk, Error> {
+    organizers::check_organizer(organizer_id)?;
+    let statements = statements(organizer_id);
+
+    let data = match format {
+        ExportFormat::Json => serde_json::to_vec(&statements).unwrap(),
+        ExportFormat::Csv => export::to_csv(
+            &[
+                "period",
+                "opening_balance",
+                "revenue",
+                "refunds",
+                "fees",
+                "payouts",
+                "ending_balance",
+                "closed_at",
+            ],
+            statements.into_iter().map(|statement| {
+                vec![
+                    statement.period,
+                    statement.opening_balance.to_string(),
+                    statement.revenue.to_string(),
+                    statement.refunds.to_string(),
+                    statement.fees.to_string(),
+                    statement.payouts.to_string(),
+                    statement.ending_balance.to_string(),
+                    statement
+                        .closed_at
+                        .map_or(String::new(), |at| at.to_string()),
+                ]
+            }),
+        ),
+    };
+    export::export_chunk(data, format, chunk)
+}
+
+#[ic_cdk::update(guard = "rate_limit")]
+fn close_statement(organizer_id: u64, period: String) -> Result<Statement, Error> {
+    organizers::check_organizer(organizer_id)?;
+    let month = parse_period(&period).map_err(|msg| Error::ValidationFailed { msg })?;
+    if month >= current_month() {
+        return Err(Error::ValidationFailed {
+            msg: format!(
+                "statement {} can only be closed once the month is over",
+                period
+            ),
+        });
+    }
+    let mut totals = month_totals(organizer_id, month);
+    if totals.closed_at.is_some() {
+        return Err(Error::ValidationFailed {
+            msg: format!("statement {} is already closed", period),
+        });
+    }
+    // Balances carry over, so months are closed in order
+    if let Some(open) = statements(organizer_id)
+        .into_iter()
+        .find(|statement| statement.closed_at.is_none() && statement.period < period)
+    {
+        return Err(Error::ValidationFailed {
+            msg: format!("statement {} must be closed first", open.period),
+        });
+    }
+
+    totals.opening_balance = opening_balance(organizer_id, month);
+    totals.closed_at = Some(time());
+    STATEMENT_STORAGE.with(|statements| {
+        statements
+            .borrow_mut()
+            .insert((organizer_id, month), totals.clone())
+    });
+
+    Ok(statement(organizer_id, month, &totals))
+}
+
+// Payouts made outside the canister, admins record them as they go out
+#[ic_cdk::update(guard = "caller_is_admin")]
+fn record_payout(organizer_id: u64, amount: u64) -> Result<Statement, Error> {
+    organizers::_get_organizer(organizer_id).ok_or(Error::not_found("organizer", organizer_id))?;
+    if amount == 0 {
+        return Err(Error::ValidationFailed {
+            msg: "payout amount must be positive".to_string(),
+        });
+    }
+    book(organizer_id, |totals| totals.payouts += amount);
+    let month = current_month();
+    Ok(statement(
+        organizer_id,
+        month,
+        &month_totals(organizer_id, month),
+    ))
+}
+
+fn month_totals(organizer_id: u64, month: u64) -> MonthTotals {
+    STATEMENT_STORAGE
+        .with(|statements| statements.borrow().get(&(organizer_id, month)))
+        .unwrap_or_default()
+}
+
+// Statements of every month with movements or closed, oldest first
+fn statements(organizer_id: u64) -> Vec<Statement> {
+    let months: Vec<(u64, MonthTotals)> = STATEMENT_STORAGE.with(|statements| {
+        statements
+            .borrow()
+            .range((organizer_id, 0)..=(organizer_id, u64::MAX))
+            .map(|((_, month), totals)| (month, totals))
+            .collect()
+    });
+    months
+        .iter()
+        .map(|(month, totals)| statement(organizer_id, *month, totals))
+        .collect()
+}
+
+fn statement(organizer_id: u64, month: u64, totals: &MonthTotals) -> Statement {
+    let opening_balance = match totals.closed_at {
+        Some(_) => totals.opening_balance,
+        None => opening_balance(organizer_id, month),
+    };
+    Statement {
+        organizer_id,
+        period: format_period(month),
+        opening_balance,
+        revenue: totals.revenue,
+        refunds: totals.refunds,
+        fees: totals.fees,
+        payouts: totals.payouts,
+        ending_balance: opening_balance + totals.revenue as i64
+            - totals.refunds as i64
+            - totals.fees as i64
+            - totals.payouts as i64,
+        closed_at: totals.closed_at,
+    }
+}
+
+// Ending balance of the latest closed statement before a month
+fn opening_balance(organizer_id: u64, month: u64) -> i64 {
+    let last_closed = STATEMENT_STORAGE.with(|statements| {
+        statements
+            .borrow()
+            .range((organizer_id, 0)..(organizer_id, month))
+            .filter(|(_, totals)| totals.closed_at.is_some())
+            .last()
+    });
+    last_closed.map_or(0, |((_, month), totals)| {
+        statement(organizer_id, month, &totals).ending_balance
+    })
+}
+
+// Months since year 0 of a YYYY-MM period
+fn parse_period(period: &str) -> Result<u64, String> {
+    let invalid = || format!("period {} is not a YYYY-MM month", period);
+    let (year, month) = period.split_once('-').ok_or_else(invalid)?;
+    let year: u64 = year.parse().map_err(|_| invalid())?;
+    let month: u64 = month.parse().map_err(|_| invalid())?;
+    if year.to_string().len() != 4 || !(1..=12).contains(&month) {
+        return Err(invalid());
+    }
+    Ok(year * 12 + month - 1)
+}
+
+fn format_period(month: u64) -> String {
+    format!("{:04}-{:02}", month / 12, month % 12 + 1)
+}
+
+fn current_month() -> u64 {
+    let (year, month, _) = dates::civil_from_days((time() / NANOS_PER_DAY) as i64);
+    year as u64 * 12 + month as u64 - 1
+}
+
+// Add a movement to the current month of an organizer
+fn book(organizer_id: u64, f: impl FnOnce(&mut MonthTotals)) {
+    let key = (organizer_id, current_month());
+    STATEMENT_STORAGE.with(|statements| {
+        let mut statements = statements.borrow_mut();
+        let mut totals = statements.get(&key).unwrap_or_default();
+        f(&mut totals);
+        statements.insert(key, totals);
+    });
+}
+
+// Book a movement of an event to its first organizer, events run by admins have no statement
+fn book_event(event_id: u64, f: impl FnOnce(&mut MonthTotals)) {
+    let organizer_id = _get_event(&event_id)
+        .and_then(|event| event.organizer_ids)
+        .and_then(|organizer_ids| organizer_ids.first().copied());
+    if let Some(organizer_id) = organizer_id {
+        book(organizer_id, f);
+    }
+}
+
+pub(crate) fn record_revenue(event_id: u64, amount: u64) {
+    book_event(event_id, |totals| totals.revenue += amount);
+}
+
+// Revenue of a ticket leaving an event, taken back from the current month
+pub(crate) fn record_revenue_reversed(event_id: u64, amount: u64) {
+    book_event(event_id, |totals| {
+        totals.revenue = totals.revenue.saturating_sub(amount)
+    });
+}
+
+// Payouts sent through the ledger by an event's withdrawal
+pub(crate) fn record_event_payout(organizer_id: u64, amount: u64) {
+    book(organizer_id, |totals| totals.payouts += amount);
+}
+
+pub(crate) fn record_refund(event_id: u64, amount: u64) {
+    book_event(event_id, |totals| totals.refunds += amount);
+}
+
+pub(crate) fn record_fee(event_id: u64, amount: u64) {
+    book_event(event_id, |totals| totals.fees += amount);
+}
@@ -0,0 +1,356 @@
+use crate::ratelimit::rate_limit;
+use crate::{
+    _get_event, _get_ticket, caller_is_admin, certification, organizers, payments, rates,
+    statements, Error, Event, Memory, EVENT_STORAGE, MEMORY_MANAGER, TICKET_STORAGE, USER_STORAGE,
+};
+use candid::{Decode, Encode};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use std::{borrow::Cow, cell::RefCell};
+
+// Number of nanoseconds in a day, used to bucket sales by day
+const NANOS_PER_DAY: u64 = 24 * 60 * 60 * 1_000_000_000;
+
+// Define a struct for the per-event counters kept in stable memory
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct EventCounters {
+    tickets_sold: u64,
+    check_ins: u64,
+    cancellations: u64,
+    revenue: u64,
+    insurance_revenue: u64,
+    sponsorship_revenue: u64,
+    donations: u64,
+    donation_fees: u64,
+    refunds: u64,
+}
+
+// Define a struct for one bucket of the sales-over-time histogram
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub struct DailySales {
+    // Day index since the Unix epoch
+    day: u64,
+    tickets_sold: u64,
+}
+
+// Define a struct for the 'EventStats' returned by 'get_event_stats'
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub struct EventStats {
+    event_id: u64,
+    tickets_sold: u64,
+    check_ins: u64,
+    cancellations: u64,
+    // Tickets neither checked in nor cancelled, None until the event completes
+    no_shows: Option<u64>,
+    revenue: u64,
+    // Insurance fees are reported apart from ticket revenue
+    insurance_revenue: u64,
+    // Invoiced sponsorships are reported apart from ticket revenue
+    sponsorship_revenue: u64,
+    // Donations are reported gross, along with the fees withheld from them
+    donations: u64,
+    donation_fees: u64,
+    refunds: u64,
+    daily_sales: Vec<DailySales>,
+}
+
+// Define a struct for the ticket revenue of an event taken in one currency
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct CurrencyRevenue {
+    // None for prices in the payment token of an event whose ledger names no token
+    currency: Option<String>,
+    tickets: u64,
+    amount: u64,
+    // In the currency asked for, None while there is no recent rate to convert at
+    converted: Option<u64>,
+}
+
+// Define a struct for the ticket revenue of an event normalized to one currency
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct EventRevenue {
+    event_id: u64,
+    currency: String,
+    // Sum of the converted amounts, revenue without a rate yet is left out
+    total: u64,
+    by_currency: Vec<CurrencyRevenue>,
+}
+
+// Define a struct for the 'PlatformStats' returned by 'get_platform_stats'
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub struct PlatformStats {
+    total_users: u64,
+    total_events: u64,
+    total_tickets: u64,
+}
+
+impl Storable for EventCounters {
+    // Conversion to bytes
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    // Conversion from bytes
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for EventCounters {
+    const MAX_SIZE: u32 = 256;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    static EVENT_COUNTERS: RefCell<StableBTreeMap<u64, EventCounters, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(4)))
+    ));
+
+    // Tickets sold keyed by (event id, day index)
+    static DAILY_SALES: RefCell<StableBTreeMap<(u64, u64), u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(5)))
+    ));
+}
+
+#[ic_cdk::query]
+fn get_event_stats(event_id: u64) -> Result<EventStats, Error> {
+    // Make sure the event exists, or return a NotFound error if not found
+    let event = _get_event(&event_id).ok_or(Error::not_found("event", event_id))?;
+    Ok(event_stats(&event))
+}
+
+fn event_stats(event: &Event) -> EventStats {
+    let event_id = event.id;
+    let counters = EVENT_COUNTERS
+        .with(|counters| counters.borrow().get(&event_id))
+        .unwrap_or_default();
+
+    // Collect the histogram buckets for this event in day order
+    let daily_sales = DAILY_SALES.with(|sales| {
+        sales
+            .borrow()
+            .range((event_id, 0)..=(event_id, u64::MAX))
+            .map(|((_, day), tickets_sold)| DailySales { day, tickets_sold })
+            .collect()
+    });
+
+    EventStats {
+        event_id,
+        tickets_sold: counters.tickets_sold,
+        check_ins: counters.check_ins,
+        cancellations: counters.cancellations,
+        // Cancelled tickets are deleted, so only the tickets still held count
+        no_shows: event.completed_at.map(|_| {
+            event
+                .ticket_ids
+                .iter()
+                .filter_map(_get_ticket)
+                .filter(|ticket| ticket.checked_in_at.is_none())
+                .count() as u64
+        }),
+        revenue: counters.revenue,
+        insurance_revenue: counters.insurance_revenue,
+        sponsorship_revenue: counters.sponsorship_revenue,
+        donations: counters.donations,
+        donation_fees: counters.donation_fees,
+        refunds: counters.refunds,
+        daily_sales,
+    }
+}
+
+// Statistics of an event kept when it is archived, without the sales histogram
+pub(crate) fn summary_stats(event: &Event) -> EventStats {
+    EventStats {
+        daily_sales: vec![],
+        ..event_stats(event)
+    }
+}
+
+// Ticket revenue of an event in the currency asked for, converted at the current rates
+// through the event's payment token. An update, so rates not followed yet start being fetched.
+#[ic_cdk::update(guard = "rate_limit")]
+fn get_event_revenue(event_id: u64, currency: String) -> Result<EventRevenue, Error> {
+    let event = organizers::authorize_event(event_id)?;
+    rates::currency_decimals(&currency).map_err(|msg| Error::ValidationFailed { msg })?;
+    let token = payments::event_token(&payments::event_payment(event_id));
+
+    let mut by_currency: Vec<CurrencyRevenue> = vec![];
+    for ticket in event.ticket_ids.iter().filter_map(_get_ticket) {
+        let ticket_currency = ticket.currency.or(token.clone());
+        match by_currency
+            .iter_mut()
+            .find(|revenue| revenue.currency == ticket_currency)
+        {
+            Some(revenue) => {
+                revenue.tickets += 1;
+                revenue.amount += ticket.price;
+            }
+            None => by_currency.push(CurrencyRevenue {
+                currency: ticket_currency,
+                tickets: 1,
+                amount: ticket.price,
+                converted: None,
+            }),
+        }
+    }
+    for revenue in &mut by_currency {
+        revenue.converted = match (&revenue.currency, &token) {
+            (Some(from), _) if *from == currency => Some(revenue.amount),
+            (Some(from), Some(token)) => {
+                rates::convert_via(revenue.amount, from, &currency, token).ok()
+            }
+            _ => None,
+        };
+    }
+
+    Ok(EventRevenue {
+        event_id,
+        total: by_currency
+            .iter()
+            .filter_map(|revenue| revenue.converted)
+            .sum(),
+        currency,
+        by_currency,
+    })
+}
+
+#[ic_cdk::query(guard = "caller_is_admin")]
+fn get_platform_stats() -> PlatformStats {
+    // The stable maps track their own lengths, so no scan is needed here
+    PlatformStats {
+        // Records in the trash don't count
+        total_users: USER_STORAGE.with(|users| {
+            users
+                .borrow()
+                .iter()
+                .filter(|(_, user)| user.deleted_at.is_none())
+                .count() as u64
+        }),
+        total_events: EVENT_STORAGE.with(|events| {
+            events
+                .borrow()
+                .iter()
+                .filter(|(_, event)| event.deleted_at.is_none())
+                .count() as u64
+        }),
+        total_tickets: TICKET_STORAGE.with(|tickets| tickets.borrow().len()),
+    }
+}
+
+// Helper function to read-modify-write the counters of an event
+fn update_counters(event_id: u64, f: impl FnOnce(&mut EventCounters)) {
+    EVENT_COUNTERS.with(|counters| {
+        let mut counters = counters.borrow_mut();
+        let mut entry = counters.get(&event_id).unwrap_or_default();
+        f(&mut entry);
+        counters.insert(event_id, entry);
+    });
+    certification::certify_live_counters(event_id);
+}
+
+// Tickets sold and checked in at an event, for the live counters
+pub(crate) fn live_totals(event_id: u64) -> (u64, u64) {
+    let counters = EVENT_COUNTERS
+        .with(|counters| counters.borrow().get(&event_id))
+        .unwrap_or_default();
+    (counters.tickets_sold, counters.check_ins)
+}
+
+// Record a ticket sale for an event at the given timestamp
+pub(crate) fn record_ticket_sold(event_id: u64, at: u64, price: u64) {
+    update_counters(event_id, |c| {
+        c.tickets_sold += 1;
+        c.revenue += price;
+    });
+    statements::record_revenue(event_id, price);
+
+    let key = (event_id, at / NANOS_PER_DAY);
+    DAILY_SALES.with(|sales| {
+        let mut sales = sales.borrow_mut();
+        let count = sales.get(&key).unwrap_or(0);
+        sales.insert(key, count + 1);
+    });
+}
+
+// Tickets sold over the last days up to and including 'today', and the number of days that
+// covers. Events that started selling within the window only count the days since.
+pub(crate) fn recent_sales(event_id: u64, today: u64, window_days: u64) -> (u64, u64) {
+    DAILY_SALES.with(|sales| {
+        let sales = sales.borrow();
+        let Some(((_, first_day), _)) = sales.range((event_id, 0)..=(event_id, u64::MAX)).next()
+        else {
+            return (0, 0);
+        };
+        let from = (today + 1).saturating_sub(window_days).max(first_day);
+        let sold = sales
+            .range((event_id, from)..=(event_id, today))
+            .map(|(_, count)| count)
+            .sum();
+        (sold, (today + 1).saturating_sub(from))
+    })
+}
+
+// Record a ticket leaving an event without being cancelled (e.g. moved to another event)
+pub(crate) fn record_ticket_removed(event_id: u64, price: u64) {
+    update_counters(event_id, |c| {
+        c.tickets_sold = c.tickets_sold.saturating_sub(1);
+        c.revenue = c.revenue.saturating_sub(price);
+    });
+    statements::record_revenue_reversed(event_id, price);
+}
+
+// Record an attendee checked in at an event
+pub(crate) fn record_check_in(event_id: u64) {
+    update_counters(event_id, |c| c.check_ins += 1);
+}
+
+// Record a ticket cancellation for an event
+pub(crate) fn record_ticket_cancelled(event_id: u64) {
+    update_counters(event_id, |c| {
+        c.tickets_sold = c.tickets_sold.saturating_sub(1);
+        c.cancellations += 1;
+    });
+}
+
+// Record the insurance fee paid with a ticket
+pub(crate) fn record_insurance_sold(event_id: u64, fee: u64) {
+    update_counters(event_id, |c| c.insurance_revenue += fee);
+}
+
+// Record a paid sponsorship invoice
+pub(crate) fn record_sponsorship_paid(event_id: u64, amount: u64) {
+    update_counters(event_id, |c| c.sponsorship_revenue += amount);
+    statements::record_revenue(event_id, amount);
+}
+
+// Record a donation made at checkout
+pub(crate) fn record_donation(event_id: u64, amount: u64, fee: u64) {
+    update_counters(event_id, |c| {
+        c.donations += amount;
+        c.donation_fees += fee;
+    });
+    statements::record_revenue(event_id, amount);
+    statements::record_fee(event_id, fee);
+}
+
+// Record an amount refunded to a ticket holder
+pub(crate) fn record_refund(event_id: u64, amount: u64) {
+    update_counters(event_id, |c| c.refunds += amount);
+    statements::record_refund(event_id, amount);
+}
+
+// Drop all counters and histogram buckets of a deleted event
+pub(crate) fn remove_event_stats(event_id: u64) {
+    EVENT_COUNTERS.with(|counters| counters.borrow_mut().remove(&event_id));
+
+    DAILY_SALES.with(|sales| {
+        let mut sales = sales.borrow_mut();
+        let keys: Vec<(u64, u64)> = sales
+            .range((event_id, 0)..=(event_id, u64::MAX))
+            .map(|(key, _)| key)
+            .collect();
+        for key in keys {
+            sales.remove(&key);
+        }
+    });
+}
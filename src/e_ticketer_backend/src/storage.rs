@@ -0,0 +1,218 @@
+use crate::clock::time;
+use crate::{caller_is_admin, health, Error, Memory, MEMORY_MANAGER};
+use candid::{Decode, Encode};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{Cell, Storable};
+use std::{borrow::Cow, cell::RefCell};
+
+const PAGE_BYTES: u64 = 65_536;
+// The memory manager hands out at most 32768 buckets of 128 pages
+const MANAGED_PAGES: u64 = 32_768 * 128;
+
+// Memories of the maps whose writes are checked against their quotas
+pub(crate) const EVENTS: u8 = 1;
+pub(crate) const USERS: u8 = 2;
+pub(crate) const TICKETS: u8 = 3;
+// Quotas can only be set on these, the other maps grow with the records above
+const ENFORCED: [u8; 3] = [EVENTS, USERS, TICKETS];
+
+// Define a struct for the most a map may grow to, either limit left unset doesn't apply
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct MapQuota {
+    memory_id: u8,
+    max_records: Option<u64>,
+    max_pages: Option<u64>,
+}
+
+// Define a struct for the soft quotas on stable memory, writes past them are turned down
+// before stable memory runs out and the canister traps
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct StorageQuotas {
+    // Pages stable memory as a whole may grow to
+    max_stable_pages: u64,
+    quotas: Vec<MapQuota>,
+    updated_at: Option<u64>,
+}
+
+// Define a struct for the space one memory takes, along with its quota
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub struct MapUsage {
+    memory_id: u8,
+    name: Option<String>,
+    pages: u64,
+    bytes: u64,
+    // None for memories that don't hold a map
+    records: Option<u64>,
+    quota: Option<MapQuota>,
+    over_quota: bool,
+}
+
+// Define a struct for how stable memory is split between the maps, for operators
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub struct StorageBreakdown {
+    stable_memory_pages: u64,
+    stable_memory_bytes: u64,
+    max_stable_pages: u64,
+    maps: Vec<MapUsage>,
+    checked_at: u64,
+}
+
+impl Default for StorageQuotas {
+    fn default() -> Self {
+        StorageQuotas {
+            // A tenth is left for the maps to finish the writes under way
+            max_stable_pages: MANAGED_PAGES / 10 * 9,
+            quotas: vec![],
+            updated_at: None,
+        }
+    }
+}
+
+impl Storable for StorageQuotas {
+    // Conversion to bytes
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    // Conversion from bytes
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+thread_local! {
+    static QUOTAS: RefCell<Cell<StorageQuotas, Memory>> = RefCell::new(
+        Cell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(141))),
+            StorageQuotas::default(),
+        )
+        .expect("Cannot create the storage quotas")
+    );
+}
+
+#[ic_cdk::query(guard = "caller_is_admin")]
+fn get_storage_breakdown() -> StorageBreakdown {
+    let quotas = quotas();
+    let maps = (0..health::MEMORY_COUNT)
+        .filter_map(|memory_id| {
+            let (pages, records) = health::memory_usage(memory_id);
+            let quota = quota(&quotas, memory_id);
+            (pages > 0 || quota.is_some()).then(|| MapUsage {
+                memory_id,
+                name: health::memory_name(memory_id),
+                pages,
+                bytes: pages * PAGE_BYTES,
+                records,
+                over_quota: quota
+                    .as_ref()
+                    .is_some_and(|quota| exceeds(quota, pages, records)),
+                quota,
+            })
+        })
+        .collect();
+    let stable_memory_pages = ic_cdk::api::stable::stable64_size();
+
+    StorageBreakdown {
+        stable_memory_pages,
+        stable_memory_bytes: stable_memory_pages * PAGE_BYTES,
+        max_stable_pages: quotas.max_stable_pages,
+        maps,
+        checked_at: time(),
+    }
+}
+
+#[ic_cdk::query(guard = "caller_is_admin")]
+fn get_storage_quotas() -> StorageQuotas {
+    quotas()
+}
+
+#[ic_cdk::update(guard = "caller_is_admin")]
+fn set_storage_quotas(mut quotas: StorageQuotas) -> Result<StorageQuotas, Error> {
+    validate_quotas(&quotas).map_err(|msg| Error::ValidationFailed { msg })?;
+    quotas.updated_at = Some(time());
+    QUOTAS
+        .with(|cell| cell.borrow_mut().set(quotas.clone()))
+        .map_err(|_| Error::InternalError {
+            msg: "storage quotas could not be saved".to_string(),
+            ticket_id: None,
+        })?;
+    Ok(quotas)
+}
+
+// Check a map, and stable memory as a whole, have room for another record before it is written
+pub(crate) fn check_quota(memory_id: u8) -> Result<(), Error> {
+    let quotas = quotas();
+    let stable_memory_pages = ic_cdk::api::stable::stable64_size();
+    if stable_memory_pages >= quotas.max_stable_pages {
+        return Err(Error::StorageQuotaExceeded {
+            msg: format!(
+                "stable memory reached its quota of {} pages",
+                quotas.max_stable_pages
+            ),
+            memory_id: None,
+        });
+    }
+    if let Some(quota) = quota(&quotas, memory_id) {
+        let (pages, records) = health::memory_usage(memory_id);
+        if exceeds(&quota, pages, records) {
+            return Err(Error::StorageQuotaExceeded {
+                msg: format!(
+                    "{} reached its storage quota",
+                    health::memory_name(memory_id)
+                        .unwrap_or_else(|| format!("memory {}", memory_id))
+                ),
+                memory_id: Some(memory_id),
+            });
+        }
+    }
+    Ok(())
+}
+
+fn quotas() -> StorageQuotas {
+    QUOTAS.with(|cell| cell.borrow().get().clone())
+}
+
+fn quota(quotas: &StorageQuotas, memory_id: u8) -> Option<MapQuota> {
+    quotas
+        .quotas
+        .iter()
+        .find(|quota| quota.memory_id == memory_id)
+        .cloned()
+}
+
+// Whether a map is at its quota, so another record would take it past
+fn exceeds(quota: &MapQuota, pages: u64, records: Option<u64>) -> bool {
+    quota.max_pages.is_some_and(|max_pages| pages >= max_pages)
+        || quota
+            .max_records
+            .is_some_and(|max_records| records.unwrap_or(0) >= max_records)
+}
+
+fn validate_quotas(quotas: &StorageQuotas) -> Result<(), String> {
+    if quotas.max_stable_pages == 0 || quotas.max_stable_pages > MANAGED_PAGES {
+        return Err(format!(
+            "stable memory quota must be 1 to {} pages",
+            MANAGED_PAGES
+        ));
+    }
+    for (index, quota) in quotas.quotas.iter().enumerate() {
+        if !ENFORCED.contains(&quota.memory_id) {
+            return Err(format!(
+                "memory {} has no quota, quotas apply to memories {:?}",
+                quota.memory_id, ENFORCED
+            ));
+        }
+        if quotas.quotas[..index]
+            .iter()
+            .any(|other| other.memory_id == quota.memory_id)
+        {
+            return Err(format!(
+                "memory {} has more than one quota",
+                quota.memory_id
+            ));
+        }
+        if quota.max_records.is_none() && quota.max_pages.is_none() {
+            return Err(format!("quota of memory {} sets no limit", quota.memory_id));
+        }
+    }
+    Ok(())
+}
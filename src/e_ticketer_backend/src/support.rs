@@ -0,0 +1,288 @@
+use crate::clock::time;
+use crate::notifications::{self, NotificationKind};
+use crate::{
+    _get_event, _get_ticket, _get_user, _update_ticket, caller_is_admin, icrc7, privacy,
+    ticket_summary, Error, Memory, Ticket, TicketPayload, ID_COUNTER, MEMORY_MANAGER,
+};
+use candid::{Decode, Encode, Principal};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use std::{borrow::Cow, cell::RefCell};
+
+// How long an admin can act on behalf of a user before starting over
+const SESSION_TTL: u64 = 30 * 60 * 1_000_000_000;
+const MAX_REASON_LENGTH: usize = 200;
+
+// Define a struct for an admin acting on behalf of a user to help them
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct SupportSession {
+    id: u64,
+    admin: Principal,
+    user_id: u64,
+    // Why the admin acts for the user, e.g. a support ticket reference
+    reason: String,
+    started_at: u64,
+    expires_at: u64,
+    ended_at: Option<u64>,
+}
+
+// Define an enum for what an admin did on behalf of a user
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub enum SupportActionKind {
+    SessionStarted { reason: String },
+    SessionEnded,
+    ViewedTickets,
+    ResentTicket { ticket_id: u64 },
+    TransferredTicket { ticket_id: u64, to_user_id: u64 },
+}
+
+// Define a struct for an entry of the support log, which the affected user can read
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct SupportAction {
+    id: u64,
+    session_id: u64,
+    admin: Principal,
+    user_id: u64,
+    // Wallet of the user at the time, None if they had none linked
+    user_principal: Option<Principal>,
+    kind: SupportActionKind,
+    at: u64,
+}
+
+impl Storable for SupportSession {
+    // Conversion to bytes
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    // Conversion from bytes
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for SupportSession {
+    const MAX_SIZE: u32 = 512;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl Storable for SupportAction {
+    // Conversion to bytes
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    // Conversion from bytes
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for SupportAction {
+    const MAX_SIZE: u32 = 512;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    static SESSION_STORAGE: RefCell<StableBTreeMap<u64, SupportSession, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(99)))
+    ));
+
+    // Support log keyed by (user id, action id)
+    static ACTION_LOG: RefCell<StableBTreeMap<(u64, u64), SupportAction, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(100)))
+    ));
+}
+
+// Start acting on behalf of a user, ending the admin's previous session if any
+#[ic_cdk::update(guard = "caller_is_admin")]
+fn begin_support_session(user_id: u64, reason: String) -> Result<SupportSession, Error> {
+    _get_user(&user_id).ok_or(Error::not_found("user", user_id))?;
+    if reason.trim().is_empty() || reason.len() > MAX_REASON_LENGTH {
+        return Err(Error::ValidationFailed {
+            msg: format!(
+                "a support session needs a reason of at most {} characters",
+                MAX_REASON_LENGTH
+            ),
+        });
+    }
+    if let Some(session) = active_session() {
+        end(session);
+    }
+
+    // Increment the global ID counter to get a new ID for the session
+    let id = ID_COUNTER
+        .with(|counter| {
+            let current_id = *counter.borrow().get();
+            counter.borrow_mut().set(current_id + 1)
+        })
+        .expect("Cannot increment Ids");
+    let now = time();
+    let session = SupportSession {
+        id,
+        admin: ic_cdk::caller(),
+        user_id,
+        reason: reason.clone(),
+        started_at: now,
+        expires_at: now + SESSION_TTL,
+        ended_at: None,
+    };
+    SESSION_STORAGE.with(|sessions| sessions.borrow_mut().insert(id, session.clone()));
+    log(&session, SupportActionKind::SessionStarted { reason });
+    Ok(session)
+}
+
+#[ic_cdk::update(guard = "caller_is_admin")]
+fn end_support_session() -> Result<SupportSession, Error> {
+    let session = active_session().ok_or(Error::NotFound {
+        entity: "support session".to_string(),
+        id: String::new(),
+        msg: "caller has no support session running".to_string(),
+    })?;
+    Ok(end(session))
+}
+
+// Tickets of the user the admin acts for. An update, so the look is logged.
+#[ic_cdk::update(guard = "caller_is_admin")]
+fn support_get_tickets() -> Result<Vec<Ticket>, Error> {
+    let session = require_session()?;
+    let user = _get_user(&session.user_id).ok_or(Error::not_found("user", session.user_id))?;
+    log(&session, SupportActionKind::ViewedTickets);
+    Ok(user
+        .ticket_ids
+        .iter()
+        .filter_map(_get_ticket)
+        .filter(|ticket| ticket.user_id == user.id)
+        .collect())
+}
+
+// Send the user the confirmation of one of their tickets again
+#[ic_cdk::update(guard = "caller_is_admin")]
+fn support_resend_ticket(ticket_id: u64) -> Result<Ticket, Error> {
+    let session = require_session()?;
+    let ticket = session_ticket(&session, ticket_id)?;
+    let event = _get_event(&ticket.event_id).ok_or(Error::not_found("event", ticket.event_id))?;
+    notifications::enqueue_notification(
+        NotificationKind::TicketPurchased,
+        event.id,
+        ticket.user_id,
+        format!("Your ticket for {}", event.name),
+        ticket_summary(&ticket, &event),
+    );
+    log(&session, SupportActionKind::ResentTicket { ticket_id });
+    Ok(ticket)
+}
+
+// Hand a ticket of the user to another user, as the user could themselves, e.g. to finish a
+// transfer to a recipient who couldn't link a wallet
+#[ic_cdk::update(guard = "caller_is_admin")]
+fn support_transfer_ticket(ticket_id: u64, to_user_id: u64) -> Result<Ticket, Error> {
+    let session = require_session()?;
+    let ticket = session_ticket(&session, ticket_id)?;
+    _get_user(&to_user_id).ok_or(Error::not_found("user", to_user_id))?;
+    if to_user_id == ticket.user_id {
+        return Err(Error::ValidationFailed {
+            msg: format!(
+                "ticket id:{} already belongs to user id:{}",
+                ticket_id, to_user_id
+            ),
+        });
+    }
+    let payload = TicketPayload {
+        event_id: ticket.event_id,
+        user_id: to_user_id,
+        ..Default::default()
+    };
+    let ticket = _update_ticket(ticket_id, payload, ticket.version)?;
+    log(
+        &session,
+        SupportActionKind::TransferredTicket {
+            ticket_id,
+            to_user_id,
+        },
+    );
+    Ok(ticket)
+}
+
+#[ic_cdk::query(guard = "caller_is_admin")]
+fn get_support_log(user_id: u64) -> Vec<SupportAction> {
+    user_actions(user_id)
+}
+
+// What admins did on behalf of the caller's user
+#[ic_cdk::query]
+fn get_my_support_log() -> Result<Vec<SupportAction>, Error> {
+    let user = privacy::caller_user()?;
+    Ok(user_actions(user.id))
+}
+
+// Running session of the calling admin
+fn active_session() -> Option<SupportSession> {
+    let caller = ic_cdk::caller();
+    let now = time();
+    SESSION_STORAGE.with(|sessions| {
+        sessions
+            .borrow()
+            .iter()
+            .map(|(_, session)| session)
+            .find(|session| {
+                session.admin == caller && session.ended_at.is_none() && now < session.expires_at
+            })
+    })
+}
+
+fn require_session() -> Result<SupportSession, Error> {
+    active_session().ok_or(Error::Unauthorized {
+        msg: "start a support session for the user with 'begin_support_session' first".to_string(),
+    })
+}
+
+// A ticket the user of the session holds, the only ones support can act on
+fn session_ticket(session: &SupportSession, ticket_id: u64) -> Result<Ticket, Error> {
+    _get_ticket(&ticket_id)
+        .filter(|ticket| ticket.user_id == session.user_id)
+        .ok_or(Error::NotFound {
+            entity: "ticket".to_string(),
+            id: ticket_id.to_string(),
+            msg: format!(
+                "ticket id:{} does not belong to user id:{}",
+                ticket_id, session.user_id
+            ),
+        })
+}
+
+fn end(mut session: SupportSession) -> SupportSession {
+    session.ended_at = Some(time());
+    SESSION_STORAGE.with(|sessions| sessions.borrow_mut().insert(session.id, session.clone()));
+    log(&session, SupportActionKind::SessionEnded);
+    session
+}
+
+fn log(session: &SupportSession, kind: SupportActionKind) {
+    // Increment the global ID counter to get a new ID for the support action
+    let id = ID_COUNTER
+        .with(|counter| {
+            let current_id = *counter.borrow().get();
+            counter.borrow_mut().set(current_id + 1)
+        })
+        .expect("Cannot increment Ids");
+    let action = SupportAction {
+        id,
+        session_id: session.id,
+        admin: session.admin,
+        user_id: session.user_id,
+        user_principal: icrc7::user_wallet(session.user_id),
+        kind,
+        at: time(),
+    };
+    ACTION_LOG.with(|log| log.borrow_mut().insert((session.user_id, id), action));
+}
+
+pub(crate) fn user_actions(user_id: u64) -> Vec<SupportAction> {
+    ACTION_LOG.with(|log| {
+        log.borrow()
+            .range((user_id, 0)..=(user_id, u64::MAX))
+            .map(|(_, action)| action)
+            .collect()
+    })
+}
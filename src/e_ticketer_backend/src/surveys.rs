@@ -0,0 +1,385 @@
+use crate::clock::time;
+use crate::notifications::{self, NotificationKind};
+use crate::questions::{validate_answers, validate_questions, Answer, Question, QuestionKind};
+use crate::ratelimit::rate_limit;
+use crate::{
+    _get_event, _get_ticket, _get_user, anonymization, organizers, Error, Memory, MEMORY_MANAGER,
+};
+use candid::{Decode, Encode};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use std::{borrow::Cow, cell::RefCell};
+
+// Upper bound on the questions of a single survey
+const MAX_SURVEY_QUESTIONS: usize = 20;
+
+// Define a struct for the feedback survey of an event
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct EventSurvey {
+    event_id: u64,
+    // External survey form, if the organizer collects feedback elsewhere
+    link: Option<String>,
+    questions: Vec<Question>,
+    // Set once the survey went out to the attendees
+    sent_at: Option<u64>,
+    created_at: u64,
+    updated_at: Option<u64>,
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub struct SurveyPayload {
+    link: Option<String>,
+    questions: Vec<Question>,
+}
+
+// Define a struct for the survey sent to an attendee, along with their answers
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct SurveyInvitation {
+    event_id: u64,
+    user_id: u64,
+    link: Option<String>,
+    answers: Vec<Answer>,
+    sent_at: u64,
+    responded_at: Option<u64>,
+}
+
+// Define a struct for the aggregated answers to one question
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub struct QuestionResults {
+    prompt: String,
+    answered: u64,
+    // Answers per rating (1 first) or per choice option, empty for text questions
+    counts: Vec<u64>,
+    text_answers: Vec<String>,
+}
+
+// Define a struct for the aggregated survey responses of an event
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub struct SurveyResults {
+    event_id: u64,
+    invited: u64,
+    responded: u64,
+    questions: Vec<QuestionResults>,
+}
+
+impl Storable for EventSurvey {
+    // Conversion to bytes
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    // Conversion from bytes
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl Storable for SurveyInvitation {
+    // Conversion to bytes
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    // Conversion from bytes
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for EventSurvey {
+    const MAX_SIZE: u32 = 16384;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl BoundedStorable for SurveyInvitation {
+    const MAX_SIZE: u32 = 16384;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    // Surveys keyed by event id
+    static SURVEY_STORAGE: RefCell<StableBTreeMap<u64, EventSurvey, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(16)))
+    ));
+
+    // Invitations keyed by (event id, user id)
+    static SURVEY_INVITATIONS: RefCell<StableBTreeMap<(u64, u64), SurveyInvitation, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(17)))
+    ));
+}
+
+fn _get_survey(event_id: &u64) -> Option<EventSurvey> {
+    // Helper function to get the survey of an event
+    SURVEY_STORAGE.with(|surveys| surveys.borrow().get(event_id))
+}
+
+// Helper function to get all invitations sent for an event
+fn _get_event_invitations(event_id: u64) -> Vec<SurveyInvitation> {
+    SURVEY_INVITATIONS.with(|invitations| {
+        invitations
+            .borrow()
+            .range((event_id, 0)..=(event_id, u64::MAX))
+            .map(|(_, invitation)| invitation)
+            .collect()
+    })
+}
+
+// Survey of an event as it was configured, for a template
+pub(crate) fn survey_payload(event_id: u64) -> Option<SurveyPayload> {
+    _get_survey(&event_id).map(|survey| SurveyPayload {
+        link: survey.link,
+        questions: survey.questions,
+    })
+}
+
+#[ic_cdk::query]
+fn get_event_survey(event_id: u64) -> Result<EventSurvey, Error> {
+    _get_survey(&event_id).ok_or(Error::NotFound {
+        entity: "survey".to_string(),
+        id: event_id.to_string(),
+        msg: format!("event id:{} has no survey", event_id),
+    })
+}
+
+#[ic_cdk::update(guard = "rate_limit")]
+pub(crate) fn set_event_survey(
+    event_id: u64,
+    payload: SurveyPayload,
+) -> Result<EventSurvey, Error> {
+    // Only the event's organizers can change it, or return an error if the caller isn't one
+    let event = organizers::authorize_event(event_id)?;
+
+    let existing = _get_survey(&event_id);
+    if existing
+        .as_ref()
+        .is_some_and(|survey| survey.sent_at.is_some())
+    {
+        return Err(Error::ValidationFailed {
+            msg: format!("survey of event id:{} was already sent", event_id),
+        });
+    }
+
+    if payload.link.is_none() && payload.questions.is_empty() {
+        return Err(Error::ValidationFailed {
+            msg: "survey needs a link or at least one question".to_string(),
+        });
+    }
+    if payload.questions.len() > MAX_SURVEY_QUESTIONS {
+        return Err(Error::ValidationFailed {
+            msg: format!(
+                "a survey can have at most {} questions",
+                MAX_SURVEY_QUESTIONS
+            ),
+        });
+    }
+    validate_questions(&payload.questions).map_err(|msg| Error::ValidationFailed { msg })?;
+
+    let survey = EventSurvey {
+        event_id,
+        link: payload.link,
+        questions: payload.questions,
+        sent_at: None,
+        created_at: existing.as_ref().map_or(time(), |survey| survey.created_at),
+        updated_at: existing.map(|_| time()),
+    };
+    SURVEY_STORAGE.with(|surveys| surveys.borrow_mut().insert(event_id, survey));
+
+    // A survey added after the event is over goes out right away
+    if event.completed_at.is_some() {
+        push_event_survey(event_id);
+    }
+
+    get_event_survey(event_id)
+}
+
+#[ic_cdk::query]
+fn get_user_surveys(user_id: u64) -> Result<Vec<SurveyInvitation>, Error> {
+    // Make sure the user exists, or return a NotFound error if not found
+    _get_user(&user_id).ok_or(Error::not_found("user", user_id))?;
+
+    Ok(SURVEY_INVITATIONS.with(|invitations| {
+        invitations
+            .borrow()
+            .iter()
+            .filter(|(_, invitation)| invitation.user_id == user_id)
+            .map(|(_, invitation)| invitation)
+            .collect()
+    }))
+}
+
+#[ic_cdk::update(guard = "rate_limit")]
+fn submit_survey_response(
+    event_id: u64,
+    user_id: u64,
+    answers: Vec<Answer>,
+) -> Result<SurveyInvitation, Error> {
+    // Only attendees the survey was sent to can answer it, and only once
+    let mut invitation = SURVEY_INVITATIONS
+        .with(|invitations| invitations.borrow().get(&(event_id, user_id)))
+        .ok_or(Error::NotFound {
+            entity: "survey invitation".to_string(),
+            id: user_id.to_string(),
+            msg: format!(
+                "user id:{} has no survey for event id:{}",
+                user_id, event_id
+            ),
+        })?;
+    if invitation.responded_at.is_some() {
+        return Err(Error::ValidationFailed {
+            msg: format!(
+                "user id:{} already answered the survey of event id:{}",
+                user_id, event_id
+            ),
+        });
+    }
+
+    let survey = _get_survey(&event_id).ok_or(Error::NotFound {
+        entity: "survey".to_string(),
+        id: event_id.to_string(),
+        msg: format!("event id:{} has no survey", event_id),
+    })?;
+    validate_answers(&survey.questions, &answers).map_err(|msg| Error::ValidationFailed { msg })?;
+
+    invitation.answers = answers;
+    invitation.responded_at = Some(time());
+    SURVEY_INVITATIONS.with(|invitations| {
+        invitations
+            .borrow_mut()
+            .insert((event_id, user_id), invitation.clone())
+    });
+
+    Ok(invitation)
+}
+
+#[ic_cdk::query]
+fn get_survey_results(event_id: u64) -> Result<SurveyResults, Error> {
+    let survey = _get_survey(&event_id).ok_or(Error::NotFound {
+        entity: "survey".to_string(),
+        id: event_id.to_string(),
+        msg: format!("event id:{} has no survey", event_id),
+    })?;
+    let invitations = _get_event_invitations(event_id);
+
+    let mut questions: Vec<QuestionResults> = survey
+        .questions
+        .iter()
+        .map(|question| QuestionResults {
+            prompt: question.prompt.clone(),
+            answered: 0,
+            counts: match &question.kind {
+                QuestionKind::Text => vec![],
+                QuestionKind::Rating { max } => vec![0; *max as usize],
+                QuestionKind::Choice { options } => vec![0; options.len()],
+            },
+            text_answers: vec![],
+        })
+        .collect();
+
+    // Tally the answers, they were checked against the questions when submitted
+    let mut responded = 0;
+    for invitation in &invitations {
+        if invitation.responded_at.is_none() {
+            continue;
+        }
+        responded += 1;
+        for (results, answer) in questions.iter_mut().zip(&invitation.answers) {
+            match answer {
+                Answer::Skipped => continue,
+                Answer::Text(text) => results.text_answers.push(text.clone()),
+                Answer::Rating(rating) => results.counts[*rating as usize - 1] += 1,
+                Answer::Choice(choice) => results.counts[*choice as usize] += 1,
+            }
+            results.answered += 1;
+        }
+    }
+
+    Ok(SurveyResults {
+        event_id,
+        invited: invitations.len() as u64,
+        responded,
+        questions,
+    })
+}
+
+// Send the survey of a completed event to every attendee who checked in
+pub(crate) fn push_event_survey(event_id: u64) {
+    let (Some(mut survey), Some(event)) = (_get_survey(&event_id), _get_event(&event_id)) else {
+        return;
+    };
+    if survey.sent_at.is_some() {
+        return;
+    }
+
+    let now = time();
+    for ticket in event.ticket_ids.iter().filter_map(_get_ticket) {
+        if ticket.checked_in_at.is_none() {
+            continue;
+        }
+        let invited = SURVEY_INVITATIONS.with(|invitations| {
+            invitations
+                .borrow()
+                .contains_key(&(event_id, ticket.user_id))
+        });
+        let invitation = SurveyInvitation {
+            event_id,
+            user_id: ticket.user_id,
+            link: survey.link.clone(),
+            answers: vec![],
+            sent_at: now,
+            responded_at: None,
+        };
+        SURVEY_INVITATIONS.with(|invitations| {
+            invitations
+                .borrow_mut()
+                .insert((event_id, ticket.user_id), invitation)
+        });
+
+        // Attendees holding several tickets get a single invitation
+        if !invited {
+            notifications::enqueue_notification(
+                NotificationKind::SurveyAvailable,
+                event_id,
+                ticket.user_id,
+                format!("How was {}?", event.name),
+                format!("Tell us what you thought of {}.", event.name),
+            );
+        }
+    }
+
+    survey.sent_at = Some(now);
+    SURVEY_STORAGE.with(|surveys| surveys.borrow_mut().insert(event_id, survey));
+}
+
+// Detach the responses to an event's survey from the users who gave them. Each response
+// moves to an id counting down from the anonymous user, and free text answers are dropped
+// as they can say anything.
+pub(crate) fn anonymize_event_responses(event_id: u64) {
+    let invitations = _get_event_invitations(event_id);
+    SURVEY_INVITATIONS.with(|storage| {
+        let mut storage = storage.borrow_mut();
+        for invitation in &invitations {
+            storage.remove(&(event_id, invitation.user_id));
+        }
+        for (index, mut invitation) in invitations.into_iter().enumerate() {
+            invitation.user_id = anonymization::ANONYMOUS_USER_ID - index as u64;
+            for answer in &mut invitation.answers {
+                if let Answer::Text(_) = answer {
+                    *answer = Answer::Skipped;
+                }
+            }
+            storage.insert((event_id, invitation.user_id), invitation);
+        }
+    });
+}
+
+// Drop the survey of a deleted event along with its responses
+pub(crate) fn remove_event_survey(event_id: u64) {
+    SURVEY_STORAGE.with(|surveys| surveys.borrow_mut().remove(&event_id));
+    for invitation in _get_event_invitations(event_id) {
+        SURVEY_INVITATIONS.with(|invitations| {
+            invitations
+                .borrow_mut()
+                .remove(&(event_id, invitation.user_id))
+        });
+    }
+}
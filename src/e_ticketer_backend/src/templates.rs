@@ -0,0 +1,369 @@
+use crate::announcements::AnnouncementStage;
+use crate::clock::time;
+use crate::geo::Venue;
+use crate::promo::{self, PromoCodePayload};
+use crate::ratelimit::rate_limit;
+use crate::rooms::{self, RoomPayload};
+use crate::seats::{self, SeatMap};
+use crate::surveys::{self, SurveyPayload};
+use crate::tiers::{self, TierPayload};
+use crate::{
+    _create_event, bulk, organizers, validation, Error, Event, EventPayload, Memory, ID_COUNTER,
+    MEMORY_MANAGER,
+};
+use candid::{Decode, Encode};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use serde_bytes::ByteBuf;
+use std::{borrow::Cow, cell::RefCell};
+
+// Layout of the templates this deployment writes, bumped whenever it changes
+const TEMPLATE_VERSION: u32 = 1;
+const MAX_TEMPLATES_PER_ORGANIZER: usize = 50;
+const MAX_TEMPLATE_NAME_LENGTH: usize = 100;
+// Bound of a saved template, which holds a seat map and a survey at most
+const MAX_SAVED_TEMPLATE_SIZE: u32 = 65536;
+
+// Define an enum for the encodings a template can be exported in
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize)]
+pub enum TemplateFormat {
+    Candid,
+    Json,
+}
+
+// Define a struct for the definition of an event, without its attendees or sales, that can be
+// imported into another deployment of the canister
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct EventTemplate {
+    version: u32,
+    event: EventPayload,
+    tiers: Vec<TierPayload>,
+    seat_map: Option<SeatMap>,
+    // Survey questions asked to attendees once the event is over
+    survey: Option<SurveyPayload>,
+    rooms: Vec<RoomPayload>,
+    // Tiers of the codes are positions in 'tiers', None in templates from before promo codes
+    promo_codes: Option<Vec<PromoCodePayload>>,
+}
+
+// Define a struct for the details changed on an event created from another one or a template
+#[derive(candid::CandidType, Serialize, Deserialize, Default)]
+pub struct EventOverrides {
+    name: Option<String>,
+    description: Option<String>,
+    date: Option<String>,
+    start_time: Option<String>,
+    venue: Option<Venue>,
+    capacity: Option<u64>,
+    // Stage to publish the new event at, a teaser draft if omitted
+    announcement: Option<AnnouncementStage>,
+}
+
+// Define a struct for a template an organizer saved to create events from
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct SavedTemplate {
+    id: u64,
+    organizer_id: u64,
+    name: String,
+    // Event the template was saved from
+    source_event_id: u64,
+    template: EventTemplate,
+    created_at: u64,
+}
+
+// Define a struct for what an organizer sees of a saved template
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub struct SavedTemplateInfo {
+    id: u64,
+    name: String,
+    source_event_id: u64,
+    event_name: String,
+    tiers: u64,
+    promo_codes: u64,
+    created_at: u64,
+}
+
+impl Storable for SavedTemplate {
+    // Conversion to bytes
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    // Conversion from bytes
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for SavedTemplate {
+    const MAX_SIZE: u32 = MAX_SAVED_TEMPLATE_SIZE;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    // Saved templates keyed by (organizer id, template id)
+    static TEMPLATE_STORAGE: RefCell<StableBTreeMap<(u64, u64), SavedTemplate, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(103)))
+    ));
+}
+
+#[ic_cdk::query]
+fn export_event_template(event_id: u64, format: TemplateFormat) -> Result<ByteBuf, Error> {
+    let event = organizers::authorize_event(event_id)?;
+    let template = event_template(event);
+
+    Ok(ByteBuf::from(match format {
+        TemplateFormat::Candid => Encode!(&template).unwrap(),
+        TemplateFormat::Json => serde_json::to_vec(&template).unwrap(),
+    }))
+}
+
+// Create an event from a template exported by this or another deployment, the caller
+// organizes the new event
+#[ic_cdk::update(guard = "rate_limit")]
+async fn import_event_template(template: ByteBuf, format: TemplateFormat) -> Result<Event, Error> {
+    organizers::check_can_create()?;
+    let template = match format {
+        TemplateFormat::Candid => Decode!(&template, EventTemplate).map_err(|e| e.to_string()),
+        TemplateFormat::Json => serde_json::from_slice(&template).map_err(|e| e.to_string()),
+    }
+    .map_err(|msg| Error::ValidationFailed {
+        msg: format!("template could not be read: {}", msg),
+    })?;
+    if template.version != TEMPLATE_VERSION {
+        return Err(Error::ValidationFailed {
+            msg: format!(
+                "template version {} is not supported, expected {}",
+                template.version, TEMPLATE_VERSION
+            ),
+        });
+    }
+    create_from_template(template).await
+}
+
+// Copy an event with its tiers, seat map, survey, rooms and promo codes into a new event the
+// caller organizes. The copy is a teaser draft, off sale until it is announced in full.
+#[ic_cdk::update(guard = "rate_limit")]
+async fn clone_event(id: u64, overrides: EventOverrides) -> Result<Event, Error> {
+    let event = organizers::authorize_event(id)?;
+    organizers::check_can_create()?;
+    let mut template = event_template(event);
+    apply_overrides(&mut template.event, overrides);
+    create_from_template(template).await
+}
+
+// Keep the setup of one of the caller's events to create further events from
+#[ic_cdk::update(guard = "rate_limit")]
+fn save_event_template(event_id: u64, name: String) -> Result<SavedTemplateInfo, Error> {
+    let event = organizers::authorize_event(event_id)?;
+    let organizer_id = organizers::caller_organizer_id().ok_or(Error::Unauthorized {
+        msg: "only organizers can save templates, call 'register_organizer' first".to_string(),
+    })?;
+    if name.trim().is_empty() || name.len() > MAX_TEMPLATE_NAME_LENGTH {
+        return Err(Error::ValidationFailed {
+            msg: format!(
+                "template name must be 1 to {} characters",
+                MAX_TEMPLATE_NAME_LENGTH
+            ),
+        });
+    }
+    if organizer_templates(organizer_id).len() >= MAX_TEMPLATES_PER_ORGANIZER {
+        return Err(Error::ValidationFailed {
+            msg: format!(
+                "at most {} templates can be saved, delete one first",
+                MAX_TEMPLATES_PER_ORGANIZER
+            ),
+        });
+    }
+
+    // Increment the global ID counter to get a new ID for the template
+    let id = ID_COUNTER
+        .with(|counter| {
+            let current_id = *counter.borrow().get();
+            counter.borrow_mut().set(current_id + 1)
+        })
+        .expect("Cannot increment Ids");
+    let saved = SavedTemplate {
+        id,
+        organizer_id,
+        name,
+        source_event_id: event_id,
+        template: event_template(event),
+        created_at: time(),
+    };
+    if Encode!(&saved).map_or(true, |bytes| bytes.len() > MAX_SAVED_TEMPLATE_SIZE as usize) {
+        return Err(Error::ValidationFailed {
+            msg: format!("event id:{} is too large to save as a template", event_id),
+        });
+    }
+    let info = template_info(&saved);
+    TEMPLATE_STORAGE.with(|templates| templates.borrow_mut().insert((organizer_id, id), saved));
+    Ok(info)
+}
+
+#[ic_cdk::query]
+fn get_event_templates() -> Result<Vec<SavedTemplateInfo>, Error> {
+    let organizer_id = caller_organizer()?;
+    Ok(organizer_templates(organizer_id)
+        .iter()
+        .map(template_info)
+        .collect())
+}
+
+// Create a teaser draft from one of the caller's saved templates
+#[ic_cdk::update(guard = "rate_limit")]
+async fn create_event_from_template(
+    template_id: u64,
+    overrides: Option<EventOverrides>,
+) -> Result<Event, Error> {
+    let organizer_id = caller_organizer()?;
+    let saved = get_template(organizer_id, template_id)?;
+    let mut template = saved.template;
+    apply_overrides(&mut template.event, overrides.unwrap_or_default());
+    create_from_template(template).await
+}
+
+#[ic_cdk::update(guard = "rate_limit")]
+fn delete_event_template(template_id: u64) -> Result<SavedTemplateInfo, Error> {
+    let organizer_id = caller_organizer()?;
+    let saved = get_template(organizer_id, template_id)?;
+    TEMPLATE_STORAGE.with(|templates| templates.borrow_mut().remove(&(organizer_id, template_id)));
+    Ok(template_info(&saved))
+}
+
+fn caller_organizer() -> Result<u64, Error> {
+    organizers::caller_organizer_id().ok_or(Error::Unauthorized {
+        msg: "only organizers have templates, call 'register_organizer' first".to_string(),
+    })
+}
+
+fn get_template(organizer_id: u64, template_id: u64) -> Result<SavedTemplate, Error> {
+    TEMPLATE_STORAGE
+        .with(|templates| templates.borrow().get(&(organizer_id, template_id)))
+        .ok_or(Error::not_found("template", template_id))
+}
+
+fn organizer_templates(organizer_id: u64) -> Vec<SavedTemplate> {
+    TEMPLATE_STORAGE.with(|templates| {
+        templates
+            .borrow()
+            .range((organizer_id, 0)..=(organizer_id, u64::MAX))
+            .map(|(_, template)| template)
+            .collect()
+    })
+}
+
+fn template_info(saved: &SavedTemplate) -> SavedTemplateInfo {
+    SavedTemplateInfo {
+        id: saved.id,
+        name: saved.name.clone(),
+        source_event_id: saved.source_event_id,
+        event_name: saved.template.event.name.clone(),
+        tiers: saved.template.tiers.len() as u64,
+        promo_codes: saved.template.promo_codes.as_ref().map_or(0, Vec::len) as u64,
+        created_at: saved.created_at,
+    }
+}
+
+// Everything about an event a template carries over
+fn event_template(event: Event) -> EventTemplate {
+    let event_id = event.id;
+    let tier_ids: Vec<u64> = tiers::_get_event_tiers(event_id)
+        .iter()
+        .map(|tier| tier.id)
+        .collect();
+    EventTemplate {
+        version: TEMPLATE_VERSION,
+        event: event_payload(event),
+        tiers: tiers::tier_payloads(event_id),
+        seat_map: seats::_get_seat_map(event_id),
+        survey: surveys::survey_payload(event_id),
+        rooms: rooms::room_payloads(event_id),
+        promo_codes: Some(promo::promo_payloads(event_id, &tier_ids)),
+    }
+}
+
+// New events start as teaser drafts unless asked otherwise, so nothing goes on sale before
+// the organizer has gone over the copy
+fn apply_overrides(event: &mut EventPayload, overrides: EventOverrides) {
+    if let Some(name) = overrides.name {
+        event.name = name;
+    }
+    if let Some(description) = overrides.description {
+        event.description = description;
+    }
+    if let Some(date) = overrides.date {
+        event.date = date;
+    }
+    if let Some(start_time) = overrides.start_time {
+        event.start_time = start_time;
+    }
+    if overrides.venue.is_some() {
+        event.venue = overrides.venue;
+    }
+    if overrides.capacity.is_some() {
+        event.capacity = overrides.capacity;
+    }
+    event.announcement = Some(overrides.announcement.unwrap_or(AnnouncementStage::Teaser));
+}
+
+async fn create_from_template(template: EventTemplate) -> Result<Event, Error> {
+    validation::validate_event(&template.event)
+        .await
+        .map_err(|msg| Error::ValidationFailed { msg })?;
+
+    // Everything below runs in one message, a part of the template that doesn't fit traps,
+    // which rolls back the event created for it
+    let event = _create_event(template.event)?;
+    let abort = |part: &str, error: Error| -> ! {
+        ic_cdk::trap(&format!(
+            "{} of the template could not be imported: {}",
+            part,
+            bulk::error_msg(error)
+        ))
+    };
+    let mut tier_ids = vec![];
+    for tier in template.tiers {
+        let tier = tiers::create_ticket_tier(event.id, tier)
+            .unwrap_or_else(|error| abort("a tier", error));
+        tier_ids.push(tier.id);
+    }
+    if let Some(seat_map) = template.seat_map {
+        seats::set_seat_map(event.id, Some(seat_map))
+            .unwrap_or_else(|error| abort("the seat map", error));
+    }
+    if let Some(survey) = template.survey {
+        surveys::set_event_survey(event.id, survey)
+            .unwrap_or_else(|error| abort("the survey", error));
+    }
+    if !template.rooms.is_empty() {
+        rooms::set_event_rooms(event.id, template.rooms)
+            .unwrap_or_else(|error| abort("the rooms", error));
+    }
+    for promo_code in template.promo_codes.unwrap_or_default() {
+        promo::with_tier_ids(promo_code, &tier_ids)
+            .and_then(|payload| promo::create_promo_code(event.id, payload))
+            .unwrap_or_else(|error| abort("a promo code", error));
+    }
+
+    Ok(event)
+}
+
+// Details of an event as they were given when it was created or last updated
+fn event_payload(event: Event) -> EventPayload {
+    EventPayload {
+        name: event.name,
+        description: event.description,
+        date: event.date,
+        start_time: event.start_time,
+        venue: event.venue,
+        capacity: event.capacity,
+        max_tickets_per_user: event.max_tickets_per_user,
+        announcement: event.announcement,
+        category: event.category,
+        tags: event.tags,
+        metadata: event.metadata,
+        visibility: event.visibility,
+        accessibility: event.accessibility,
+    }
+}
@@ -0,0 +1,61 @@
+use crate::clock;
+use crate::{_get_event, _get_user, store_event, store_ticket, store_user, Event, Ticket, User};
+
+// Noon of 2030-01-01, fixtures are created at this time unless a test sets another
+pub(crate) const NOW: u64 = 1_893_499_200_000_000_000;
+
+// Store an event with the given id, on sale and without limits
+pub(crate) fn event(id: u64) -> Event {
+    clock::set_time(NOW);
+    let mut event = Event {
+        id,
+        name: format!("Event {}", id),
+        created_at: NOW,
+        ..Default::default()
+    };
+    store_event(&mut event);
+    event
+}
+
+// Store a user with the given id
+pub(crate) fn user(id: u64) -> User {
+    clock::set_time(NOW);
+    let mut user = User {
+        id,
+        name: format!("User {}", id),
+        email: format!("user{}@example.com", id),
+        created_at: NOW,
+        ..Default::default()
+    };
+    store_user(&mut user);
+    user
+}
+
+// Store a ticket of an event held by a user, linked to both like a purchased one. The event's
+// certified hash isn't touched, there is no replica to publish it to.
+pub(crate) fn ticket(id: u64, event_id: u64, user_id: u64, price: u64) -> Ticket {
+    clock::set_time(NOW);
+    let mut ticket = Ticket {
+        id,
+        event_id,
+        user_id,
+        price,
+        created_at: NOW,
+        ..Default::default()
+    };
+    store_ticket(&mut ticket);
+
+    let mut event = _get_event(&event_id).expect("the ticket's event is stored first");
+    event.ticket_ids.push(id);
+    if !event.attendee_ids.contains(&user_id) {
+        event.attendee_ids.push(user_id);
+    }
+    store_event(&mut event);
+    let mut user = _get_user(&user_id).expect("the ticket's holder is stored first");
+    user.ticket_ids.push(id);
+    if !user.event_ids.contains(&event_id) {
+        user.event_ids.push(event_id);
+    }
+    store_user(&mut user);
+    ticket
+}
@@ -0,0 +1,269 @@
+use crate::clock::time;
+use crate::ratelimit::rate_limit;
+use crate::{_get_ticket, entropy, organizers, Error, Memory, StringKey, Ticket, MEMORY_MANAGER};
+use candid::{Decode, Encode};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use sha2::Digest;
+use std::{borrow::Cow, cell::RefCell};
+
+// Bounds on the parts of a ticket code, keeping full codes within a 'StringKey'
+const MAX_PREFIX_LEN: usize = 16;
+const MIN_CODE_LEN: u8 = 4;
+const MAX_CODE_LEN: u8 = 32;
+
+// Random codes are redrawn on collision, up to this many times
+const MAX_CODE_ATTEMPTS: u32 = 16;
+
+// Define an enum for the characters a ticket code is made of
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize)]
+pub enum CodeCharset {
+    Numeric,
+    // Uppercase letters and digits, without the look-alikes 0/O and 1/I
+    Alphanumeric,
+    Hex,
+}
+
+// Define an enum for how the codes of an event are generated
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize)]
+pub enum CodeSequence {
+    Sequential,
+    Random,
+}
+
+// Define a struct for the format of the ticket codes of an event
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct TicketCodeFormat {
+    prefix: String,
+    // Number of characters after the prefix
+    length: u8,
+    charset: CodeCharset,
+    sequence: CodeSequence,
+}
+
+// Define a struct for the code format of an event along with its sequence counter
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct EventCodeFormat {
+    event_id: u64,
+    format: TicketCodeFormat,
+    next_sequence: u64,
+    created_at: u64,
+    updated_at: Option<u64>,
+}
+
+impl CodeCharset {
+    fn alphabet(&self) -> &'static [u8] {
+        match self {
+            CodeCharset::Numeric => b"0123456789",
+            CodeCharset::Alphanumeric => b"ABCDEFGHJKLMNPQRSTUVWXYZ23456789",
+            CodeCharset::Hex => b"0123456789ABCDEF",
+        }
+    }
+}
+
+impl Storable for EventCodeFormat {
+    // Conversion to bytes
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    // Conversion from bytes
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for EventCodeFormat {
+    const MAX_SIZE: u32 = 256;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    // Code formats keyed by event id, tickets of events without one get no code
+    static CODE_FORMATS: RefCell<StableBTreeMap<u64, EventCodeFormat, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(21)))
+    ));
+
+    // Index of every issued code to its ticket id, keeping codes unique across events
+    static TICKET_CODES: RefCell<StableBTreeMap<StringKey, u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(22)))
+    ));
+}
+
+#[ic_cdk::query]
+fn get_ticket_code_format(event_id: u64) -> Result<EventCodeFormat, Error> {
+    CODE_FORMATS
+        .with(|formats| formats.borrow().get(&event_id))
+        .ok_or(Error::NotFound {
+            entity: "ticket code format".to_string(),
+            id: event_id.to_string(),
+            msg: format!("event id:{} has no ticket code format", event_id),
+        })
+}
+
+#[ic_cdk::update(guard = "rate_limit")]
+fn set_ticket_code_format(
+    event_id: u64,
+    format: TicketCodeFormat,
+) -> Result<EventCodeFormat, Error> {
+    // Only the event's organizers can change it, or return an error if the caller isn't one
+    organizers::authorize_event(event_id)?;
+
+    validate_format(&format).map_err(|msg| Error::ValidationFailed { msg })?;
+
+    // Issued codes stay as they are, the sequence carries on where it was
+    let existing = CODE_FORMATS.with(|formats| formats.borrow().get(&event_id));
+    let code_format = EventCodeFormat {
+        event_id,
+        format,
+        next_sequence: existing
+            .as_ref()
+            .map_or(0, |existing| existing.next_sequence),
+        created_at: existing
+            .as_ref()
+            .map_or(time(), |existing| existing.created_at),
+        updated_at: existing.map(|_| time()),
+    };
+
+    CODE_FORMATS.with(|formats| formats.borrow_mut().insert(event_id, code_format.clone()));
+
+    Ok(code_format)
+}
+
+#[ic_cdk::update(guard = "rate_limit")]
+fn remove_ticket_code_format(event_id: u64) -> Result<String, Error> {
+    organizers::authorize_event(event_id)?;
+    match CODE_FORMATS.with(|formats| formats.borrow_mut().remove(&event_id)) {
+        Some(_) => Ok(format!(
+            "ticket code format of event id: {} removed",
+            event_id
+        )),
+        None => Err(Error::NotFound {
+            entity: "ticket code format".to_string(),
+            id: event_id.to_string(),
+            msg: format!("event id:{} has no ticket code format", event_id),
+        }),
+    }
+}
+
+#[ic_cdk::query]
+fn get_ticket_by_code(code: String) -> Result<Ticket, Error> {
+    _get_ticket_by_code(&code)
+}
+
+pub(crate) fn _get_ticket_by_code(code: &str) -> Result<Ticket, Error> {
+    TICKET_CODES
+        .with(|codes| codes.borrow().get(&StringKey(code.to_string())))
+        .and_then(|ticket_id| _get_ticket(&ticket_id))
+        .ok_or(Error::NotFound {
+            entity: "ticket code".to_string(),
+            id: code.to_string(),
+            msg: format!("ticket code:{} does not exist", code),
+        })
+}
+
+// Generate a unique code for a ticket of an event, or None if the event has no code format
+pub(crate) fn generate_code(event_id: u64, ticket_id: u64) -> Result<Option<String>, String> {
+    let Some(mut code_format) = CODE_FORMATS.with(|formats| formats.borrow().get(&event_id)) else {
+        return Ok(None);
+    };
+    let format = &code_format.format;
+    let alphabet = format.charset.alphabet();
+
+    let mut code = None;
+    for attempt in 0..MAX_CODE_ATTEMPTS {
+        let body = match format.sequence {
+            CodeSequence::Sequential => {
+                let body =
+                    encode_sequence(code_format.next_sequence, alphabet, format.length).ok_or(
+                        format!("ticket codes of event id:{} are exhausted", event_id),
+                    )?;
+                code_format.next_sequence += 1;
+                body
+            }
+            CodeSequence::Random => random_code(ticket_id, attempt, alphabet, format.length),
+        };
+        let candidate = format!("{}{}", format.prefix, body);
+        if !TICKET_CODES.with(|codes| codes.borrow().contains_key(&StringKey(candidate.clone()))) {
+            code = Some(candidate);
+            break;
+        }
+    }
+    let code = code.ok_or(format!(
+        "no free ticket code left for event id:{}, try a longer format",
+        event_id
+    ))?;
+
+    CODE_FORMATS.with(|formats| formats.borrow_mut().insert(event_id, code_format));
+    TICKET_CODES.with(|codes| {
+        codes
+            .borrow_mut()
+            .insert(StringKey(code.clone()), ticket_id)
+    });
+
+    Ok(Some(code))
+}
+
+// Free the code of a deleted ticket
+pub(crate) fn release_code(code: &str) {
+    TICKET_CODES.with(|codes| codes.borrow_mut().remove(&StringKey(code.to_string())));
+}
+
+// Drop the code format of a deleted event, codes of its tickets stay reserved
+pub(crate) fn remove_event_code_format(event_id: u64) {
+    CODE_FORMATS.with(|formats| formats.borrow_mut().remove(&event_id));
+}
+
+// Check that a code format can be used
+fn validate_format(format: &TicketCodeFormat) -> Result<(), String> {
+    if format.prefix.len() > MAX_PREFIX_LEN {
+        return Err(format!(
+            "code prefix is longer than {} characters",
+            MAX_PREFIX_LEN
+        ));
+    }
+    if !format
+        .prefix
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == '-')
+    {
+        return Err("code prefix can only contain letters, digits and '-'".to_string());
+    }
+    if format.length < MIN_CODE_LEN || format.length > MAX_CODE_LEN {
+        return Err(format!(
+            "code length must be between {} and {}",
+            MIN_CODE_LEN, MAX_CODE_LEN
+        ));
+    }
+    Ok(())
+}
+
+// Write a sequence number in the given alphabet, left-padded to 'length' characters
+fn encode_sequence(mut value: u64, alphabet: &[u8], length: u8) -> Option<String> {
+    let base = alphabet.len() as u64;
+    let mut chars = vec![alphabet[0]; length as usize];
+    for slot in chars.iter_mut().rev() {
+        *slot = alphabet[(value % base) as usize];
+        value /= base;
+    }
+    // Anything left over doesn't fit in the code length
+    match value {
+        0 => Some(String::from_utf8(chars).unwrap()),
+        _ => None,
+    }
+}
+
+// Derive a random code from the seed, the ticket id and the attempt number
+fn random_code(ticket_id: u64, attempt: u32, alphabet: &[u8], length: u8) -> String {
+    let mut hasher = entropy::seeded_hasher();
+    hasher.update(ticket_id.to_be_bytes());
+    hasher.update(attempt.to_be_bytes());
+    let digest = hasher.finalize();
+
+    digest
+        .iter()
+        .take(length as usize)
+        .map(|byte| alphabet[*byte as usize % alphabet.len()] as char)
+        .collect()
+}
@@ -0,0 +1,174 @@
+use crate::geo::Venue;
+use crate::seats::Seat;
+use crate::{
+    _get_event, _get_public_event, _get_ticket, _get_user, announcements, display, jobs,
+    organizers, privacy, qr_tokens, resale, rooms, tiers, Error, Event, Ticket,
+};
+
+const PAGE_SIZE: usize = 20;
+
+// Define an enum for where a ticket stands, as shown to its holder
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq)]
+pub enum TicketStatus {
+    // Good for entry
+    Valid,
+    // Offered for resale, still good for entry until it sells
+    ListedForResale,
+    CheckedIn,
+    // The event completed without the ticket being checked in
+    Expired,
+}
+
+// Define a struct for a ticket along with what its holder needs to know about the event
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub struct TicketView {
+    ticket_id: u64,
+    code: Option<String>,
+    event_id: u64,
+    event_name: String,
+    // Left out until the date is announced
+    date: Option<String>,
+    start_time: Option<String>,
+    // Nanoseconds since the epoch, for clients sorting or counting down on their own
+    starts_at: Option<u64>,
+    // Date and time written the way the holder prefers, e.g. "on 2026-10-16 at 19:30"
+    when: Option<String>,
+    venue: Option<Venue>,
+    tier_id: Option<u64>,
+    tier_name: Option<String>,
+    seat: Option<Seat>,
+    status: TicketStatus,
+}
+
+// Define an enum for how the barcode of a ticket pass is drawn
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq)]
+pub enum BarcodeFormat {
+    Qr,
+}
+
+// Define a struct for the barcode scanned at the entrance
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub struct Barcode {
+    format: BarcodeFormat,
+    // The ticket's code, or its id for tickets of events without a code format
+    message: String,
+    // Printed under the barcode for staff to type in when it won't scan
+    alt_text: String,
+}
+
+// Define a struct for a ticket as wallet apps present it, holding everything needed at the
+// entrance so it can be shown offline
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub struct TicketPass {
+    ticket: TicketView,
+    holder_name: String,
+    // Room or area the holder is let into, if the event assigns them
+    zone: Option<String>,
+    barcode: Barcode,
+    // Path of the event's iCalendar file on the canister's HTTP gateway
+    calendar_path: String,
+}
+
+// Define a struct for a page of the caller's tickets
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub struct TicketViewPage {
+    tickets: Vec<TicketView>,
+    page: u64,
+    // Number of tickets over all pages
+    total: u64,
+}
+
+// The caller's tickets with the details of their events, the soonest event first. Events without
+// an announced date come last.
+#[ic_cdk::query]
+fn get_my_tickets(page: u64) -> Result<TicketViewPage, Error> {
+    let user = privacy::caller_user()?;
+    let mut tickets: Vec<(Option<u64>, TicketView)> = user
+        .ticket_ids
+        .iter()
+        .filter_map(_get_ticket)
+        .filter_map(|ticket| {
+            let event = _get_event(&ticket.event_id)?;
+            let starts_at = announcements::date_is_final(&event)
+                .then(|| jobs::event_start(&event))
+                .flatten();
+            Some((starts_at, ticket_view(ticket, &event, starts_at)))
+        })
+        .collect();
+    tickets.sort_by_key(|(starts_at, view)| (starts_at.is_none(), *starts_at, view.ticket_id));
+
+    Ok(TicketViewPage {
+        total: tickets.len() as u64,
+        tickets: tickets
+            .into_iter()
+            .skip((page as usize).saturating_mul(PAGE_SIZE))
+            .take(PAGE_SIZE)
+            .map(|(_, view)| view)
+            .collect(),
+        page,
+    })
+}
+
+// A ticket ready to add to a wallet app, for its holder or the event's organizers
+#[ic_cdk::query]
+fn get_ticket_pass(ticket_id: u64) -> Result<TicketPass, Error> {
+    let ticket = _get_ticket(&ticket_id).ok_or(Error::not_found("ticket", ticket_id))?;
+    let event =
+        _get_public_event(&ticket.event_id).ok_or(Error::not_found("event", ticket.event_id))?;
+    let is_holder = privacy::caller_user().is_ok_and(|user| user.id == ticket.user_id);
+    if !is_holder {
+        organizers::check_event_organizer(&event)?;
+    }
+
+    let code = ticket.code.clone().unwrap_or_else(|| ticket.id.to_string());
+    // Events signing their QR codes get the token scanned, the code stays readable below it
+    let message = qr_tokens::ticket_token(&ticket).unwrap_or_else(|| code.clone());
+    let holder_name = _get_user(&ticket.user_id).map_or(String::new(), |user| user.name);
+    let zone = rooms::assigned_zone(event.id, ticket.id);
+    let starts_at = announcements::date_is_final(&event)
+        .then(|| jobs::event_start(&event))
+        .flatten();
+    Ok(TicketPass {
+        ticket: ticket_view(ticket, &event, starts_at),
+        holder_name,
+        zone,
+        barcode: Barcode {
+            format: BarcodeFormat::Qr,
+            alt_text: code,
+            message,
+        },
+        calendar_path: format!("/events/{}/event.ics", event.id),
+    })
+}
+
+fn ticket_view(ticket: Ticket, event: &Event, starts_at: Option<u64>) -> TicketView {
+    let status = if ticket.checked_in_at.is_some() {
+        TicketStatus::CheckedIn
+    } else if event.completed_at.is_some() {
+        TicketStatus::Expired
+    } else if resale::is_listed(event.id, ticket.id) {
+        TicketStatus::ListedForResale
+    } else {
+        TicketStatus::Valid
+    };
+    let date_is_final = announcements::date_is_final(event);
+
+    TicketView {
+        ticket_id: ticket.id,
+        event_id: event.id,
+        event_name: event.name.clone(),
+        date: date_is_final.then(|| event.date.clone()),
+        start_time: date_is_final.then(|| event.start_time.clone()),
+        starts_at,
+        when: date_is_final.then(|| display::event_when(event, ticket.user_id)),
+        venue: event.venue.clone(),
+        tier_name: ticket
+            .tier_id
+            .and_then(|tier_id| tiers::_get_tier(event.id, tier_id))
+            .map(|tier| tier.name),
+        tier_id: ticket.tier_id,
+        code: ticket.code,
+        seat: ticket.seat,
+        status,
+    }
+}
@@ -0,0 +1,437 @@
+use crate::clock::time;
+use crate::ratelimit::rate_limit;
+use crate::{
+    _get_event, group_bookings, icrc7, organizers, payments, rates, reservations, Error, Memory,
+    ID_COUNTER, MEMORY_MANAGER,
+};
+use candid::{Decode, Encode, Principal};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use std::{borrow::Cow, cell::RefCell};
+
+const MIN_UNLOCK_CODE_LEN: usize = 6;
+const MAX_UNLOCK_CODE_LEN: usize = 32;
+const MAX_TIER_PRINCIPALS: usize = 50;
+
+// Define a struct for the 'TicketTier' of an event (e.g. "VIP", "Early bird")
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub struct TicketTier {
+    pub(crate) id: u64,
+    event_id: u64,
+    pub(crate) name: String,
+    pub(crate) price: u64,
+    // Currency the price is in, e.g. "USD", None when in the event's payment token
+    pub(crate) currency: Option<String>,
+    capacity: Option<u64>,
+    sold: u64,
+    created_at: u64,
+    updated_at: Option<u64>,
+}
+
+// Define an enum for who can see and buy the tickets of a tier
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default, PartialEq)]
+pub enum TierVisibility {
+    #[default]
+    Public,
+    // Left out of public listings, e.g. for a member presale. Sold with the unlock code, or to
+    // the listed principals whether they call themselves or are the buyer's linked wallet.
+    Hidden {
+        unlock_code: Option<String>,
+        principals: Vec<Principal>,
+    },
+}
+
+// Define a struct for the payload used to create and update tiers
+#[derive(candid::CandidType, Serialize, Deserialize, Default)]
+pub struct TierPayload {
+    name: String,
+    price: u64,
+    // Prices without a currency are in the event's payment token
+    currency: Option<String>,
+    capacity: Option<u64>,
+    // Left unchanged on update when omitted, public on creation
+    visibility: Option<TierVisibility>,
+}
+
+// Define a struct for a hidden tier along with who it is unlocked for, as organizers see it
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub struct HiddenTier {
+    tier: TicketTier,
+    visibility: TierVisibility,
+}
+
+impl Storable for TicketTier {
+    // Conversion to bytes
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    // Conversion from bytes
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for TicketTier {
+    const MAX_SIZE: u32 = 512;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl Storable for TierVisibility {
+    // Conversion to bytes
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    // Conversion from bytes
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for TierVisibility {
+    const MAX_SIZE: u32 = 2048;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    // Tiers keyed by (event id, tier id)
+    static TIER_STORAGE: RefCell<StableBTreeMap<(u64, u64), TicketTier, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(8)))
+    ));
+
+    // Visibility of the hidden tiers keyed by (event id, tier id), tiers missing are public
+    static VISIBILITY_STORAGE: RefCell<StableBTreeMap<(u64, u64), TierVisibility, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(88)))
+    ));
+}
+
+// Helper function to get a tier of an event
+pub(crate) fn _get_tier(event_id: u64, tier_id: u64) -> Option<TicketTier> {
+    TIER_STORAGE.with(|tiers| tiers.borrow().get(&(event_id, tier_id)))
+}
+
+// Helper function to get all tiers of an event
+pub(crate) fn _get_event_tiers(event_id: u64) -> Vec<TicketTier> {
+    TIER_STORAGE.with(|tiers| {
+        tiers
+            .borrow()
+            .range((event_id, 0)..=(event_id, u64::MAX))
+            .map(|(_, tier)| tier)
+            .collect()
+    })
+}
+
+// Helper function returning the number of tickets left in a tier, or None if unlimited
+pub(crate) fn tier_remaining(tier: &TicketTier) -> Option<u64> {
+    tier.capacity.map(|capacity| {
+        capacity
+            .saturating_sub(tier.sold)
+            .saturating_sub(reservations::reserved(tier.event_id, Some(tier.id)))
+            .saturating_sub(group_bookings::held(tier.event_id, Some(tier.id)))
+    })
+}
+
+// Tiers of an event as they were configured, for a template
+pub(crate) fn tier_payloads(event_id: u64) -> Vec<TierPayload> {
+    _get_event_tiers(event_id)
+        .into_iter()
+        .map(|tier| TierPayload {
+            visibility: Some(tier_visibility(event_id, tier.id)),
+            name: tier.name,
+            price: tier.price,
+            currency: tier.currency,
+            capacity: tier.capacity,
+        })
+        .collect()
+}
+
+// Visibility of a tier, public unless it was hidden
+pub(crate) fn tier_visibility(event_id: u64, tier_id: u64) -> TierVisibility {
+    VISIBILITY_STORAGE
+        .with(|visibilities| visibilities.borrow().get(&(event_id, tier_id)))
+        .unwrap_or_default()
+}
+
+// Helper function to get the tiers of an event everyone can see
+pub(crate) fn _get_public_tiers(event_id: u64) -> Vec<TicketTier> {
+    _get_event_tiers(event_id)
+        .into_iter()
+        .filter(|tier| tier_visibility(event_id, tier.id) == TierVisibility::Public)
+        .collect()
+}
+
+// Check a ticket of a tier can be sold to a user. Hidden tiers are reported as missing to
+// anyone they aren't unlocked for, so their existence doesn't leak.
+pub(crate) fn check_tier_access(
+    event_id: u64,
+    tier_id: u64,
+    user_id: u64,
+    unlock_code: Option<&str>,
+) -> Result<(), String> {
+    if unlocks(event_id, tier_id, unlock_code, Some(user_id)) {
+        return Ok(());
+    }
+    Err(format!(
+        "tier id:{} does not exist for event id:{}",
+        tier_id, event_id
+    ))
+}
+
+// Whether a tier is unlocked for the caller, by the code or for the buyer's wallet. Organizers
+// of the event and the canister's own timers, which only promote checked waitlist entries,
+// see every tier.
+fn unlocks(event_id: u64, tier_id: u64, unlock_code: Option<&str>, user_id: Option<u64>) -> bool {
+    let TierVisibility::Hidden {
+        unlock_code: code,
+        principals,
+    } = tier_visibility(event_id, tier_id)
+    else {
+        return true;
+    };
+    code.is_some_and(|code| unlock_code == Some(code.as_str()))
+        || principals.contains(&ic_cdk::caller())
+        || user_id
+            .and_then(icrc7::user_wallet)
+            .is_some_and(|wallet| principals.contains(&wallet))
+        || _get_event(&event_id)
+            .is_some_and(|event| organizers::check_event_organizer(&event).is_ok())
+}
+
+// Check a tier's currency and follow its rate to the event's token, if it takes payment in one
+fn track_rate(event_id: u64, currency: &str) -> Result<(), Error> {
+    rates::currency_decimals(currency).map_err(|msg| Error::ValidationFailed { msg })?;
+    let token = payments::event_token(&payments::event_payment(event_id));
+    match token.filter(|token| token != currency) {
+        Some(token) => {
+            rates::track_pair(&token, currency).map_err(|msg| Error::ValidationFailed { msg })
+        }
+        None => Ok(()),
+    }
+}
+
+fn validate_visibility(visibility: &TierVisibility) -> Result<(), String> {
+    let TierVisibility::Hidden {
+        unlock_code,
+        principals,
+    } = visibility
+    else {
+        return Ok(());
+    };
+    if unlock_code.is_none() && principals.is_empty() {
+        return Err("a hidden tier needs an unlock code or principals to sell to".to_string());
+    }
+    if let Some(code) = unlock_code {
+        if !(MIN_UNLOCK_CODE_LEN..=MAX_UNLOCK_CODE_LEN).contains(&code.len())
+            || !code.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+        {
+            return Err(format!(
+                "unlock code must be {} to {} letters, digits or dashes",
+                MIN_UNLOCK_CODE_LEN, MAX_UNLOCK_CODE_LEN
+            ));
+        }
+    }
+    if principals.len() > MAX_TIER_PRINCIPALS {
+        return Err(format!(
+            "a hidden tier can be unlocked for at most {} principals",
+            MAX_TIER_PRINCIPALS
+        ));
+    }
+    Ok(())
+}
+
+fn store_visibility(event_id: u64, tier_id: u64, visibility: TierVisibility) {
+    VISIBILITY_STORAGE.with(|visibilities| match visibility {
+        TierVisibility::Public => visibilities.borrow_mut().remove(&(event_id, tier_id)),
+        _ => visibilities
+            .borrow_mut()
+            .insert((event_id, tier_id), visibility),
+    });
+}
+
+// Tiers of an event on public sale, hidden tiers are left out for everyone but its organizers
+#[ic_cdk::query]
+fn get_event_tiers(event_id: u64) -> Result<Vec<TicketTier>, Error> {
+    // Make sure the event exists, or return a NotFound error if not found
+    let event = _get_event(&event_id).ok_or(Error::not_found("event", event_id))?;
+
+    match organizers::check_event_organizer(&event) {
+        Ok(()) => Ok(_get_event_tiers(event_id)),
+        Err(_) => Ok(_get_public_tiers(event_id)),
+    }
+}
+
+// Public tiers along with the hidden ones the code or the caller's principal unlocks
+#[ic_cdk::query]
+fn get_unlocked_tiers(
+    event_id: u64,
+    unlock_code: Option<String>,
+) -> Result<Vec<TicketTier>, Error> {
+    _get_event(&event_id).ok_or(Error::not_found("event", event_id))?;
+
+    Ok(_get_event_tiers(event_id)
+        .into_iter()
+        .filter(|tier| unlocks(event_id, tier.id, unlock_code.as_deref(), None))
+        .collect())
+}
+
+#[ic_cdk::query]
+fn get_hidden_tiers(event_id: u64) -> Result<Vec<HiddenTier>, Error> {
+    organizers::authorize_event(event_id)?;
+    Ok(_get_event_tiers(event_id)
+        .into_iter()
+        .filter_map(|tier| {
+            VISIBILITY_STORAGE
+                .with(|visibilities| visibilities.borrow().get(&(event_id, tier.id)))
+                .map(|visibility| HiddenTier { tier, visibility })
+        })
+        .collect())
+}
+
+#[ic_cdk::update(guard = "rate_limit")]
+pub(crate) fn create_ticket_tier(event_id: u64, payload: TierPayload) -> Result<TicketTier, Error> {
+    // Make sure the event exists and the caller organizes it
+    organizers::authorize_event(event_id)?;
+    let visibility = payload.visibility.unwrap_or_default();
+    validate_visibility(&visibility).map_err(|msg| Error::ValidationFailed { msg })?;
+    if let Some(currency) = &payload.currency {
+        track_rate(event_id, currency)?;
+    }
+
+    // Increment the global ID counter to get a new ID for the tier
+    let id = ID_COUNTER
+        .with(|counter| {
+            let current_id = *counter.borrow().get();
+            counter.borrow_mut().set(current_id + 1)
+        })
+        .expect("Cannot increment Ids");
+
+    let tier = TicketTier {
+        id,
+        event_id,
+        name: payload.name,
+        price: payload.price,
+        currency: payload.currency,
+        capacity: payload.capacity,
+        sold: 0,
+        created_at: time(),
+        updated_at: None,
+    };
+
+    TIER_STORAGE.with(|tiers| tiers.borrow_mut().insert((event_id, id), tier.clone()));
+    store_visibility(event_id, id, visibility);
+
+    Ok(tier)
+}
+
+#[ic_cdk::update(guard = "rate_limit")]
+fn update_ticket_tier(
+    event_id: u64,
+    tier_id: u64,
+    payload: TierPayload,
+) -> Result<TicketTier, Error> {
+    organizers::authorize_event(event_id)?;
+
+    // Retrieve the existing tier, or return a NotFound error if not found
+    let tier = _get_tier(event_id, tier_id).ok_or(Error::NotFound {
+        entity: "tier".to_string(),
+        id: tier_id.to_string(),
+        msg: format!(
+            "tier id:{} does not exist for event id:{}",
+            tier_id, event_id
+        ),
+    })?;
+
+    if let Some(visibility) = &payload.visibility {
+        validate_visibility(visibility).map_err(|msg| Error::ValidationFailed { msg })?;
+    }
+    if let Some(currency) = &payload.currency {
+        track_rate(event_id, currency)?;
+    }
+
+    // A tier can't shrink below what has already been sold
+    if let Some(capacity) = payload.capacity {
+        if capacity < tier.sold {
+            return Err(Error::ValidationFailed {
+                msg: format!(
+                    "tier id:{} already sold {} tickets, more than capacity {}",
+                    tier_id, tier.sold, capacity
+                ),
+            });
+        }
+    }
+
+    let updated_tier = TicketTier {
+        id: tier.id,
+        event_id,
+        name: payload.name,
+        price: payload.price,
+        currency: payload.currency,
+        capacity: payload.capacity,
+        sold: tier.sold,
+        created_at: tier.created_at,
+        updated_at: Some(time()),
+    };
+
+    TIER_STORAGE.with(|tiers| {
+        tiers
+            .borrow_mut()
+            .insert((event_id, tier_id), updated_tier.clone())
+    });
+    if let Some(visibility) = payload.visibility {
+        store_visibility(event_id, tier_id, visibility);
+    }
+
+    Ok(updated_tier)
+}
+
+#[ic_cdk::update(guard = "rate_limit")]
+fn delete_ticket_tier(event_id: u64, tier_id: u64) -> Result<String, Error> {
+    organizers::authorize_event(event_id)?;
+
+    // Retrieve the existing tier, or return a NotFound error if not found
+    let tier = _get_tier(event_id, tier_id).ok_or(Error::NotFound {
+        entity: "tier".to_string(),
+        id: tier_id.to_string(),
+        msg: format!(
+            "tier id:{} does not exist for event id:{}",
+            tier_id, event_id
+        ),
+    })?;
+
+    // Tickets keep referencing their tier, so sold tiers can't be removed
+    if tier.sold > 0 {
+        return Err(Error::ValidationFailed {
+            msg: format!("tier id:{} has sold tickets", tier_id),
+        });
+    }
+
+    TIER_STORAGE.with(|tiers| tiers.borrow_mut().remove(&(event_id, tier_id)));
+    store_visibility(event_id, tier_id, TierVisibility::Public);
+
+    Ok(format!("tier id: {} deleted", tier_id))
+}
+
+// Count a ticket sold in a tier
+pub(crate) fn record_tier_sale(event_id: u64, tier_id: u64) {
+    if let Some(mut tier) = _get_tier(event_id, tier_id) {
+        tier.sold += 1;
+        TIER_STORAGE.with(|tiers| tiers.borrow_mut().insert((event_id, tier_id), tier));
+    }
+}
+
+// Give a ticket back to its tier, e.g. after a cancellation
+pub(crate) fn record_tier_release(event_id: u64, tier_id: u64) {
+    if let Some(mut tier) = _get_tier(event_id, tier_id) {
+        tier.sold = tier.sold.saturating_sub(1);
+        TIER_STORAGE.with(|tiers| tiers.borrow_mut().insert((event_id, tier_id), tier));
+    }
+}
+
+// Drop the tiers of a deleted event
+pub(crate) fn remove_event_tiers(event_id: u64) {
+    for tier in _get_event_tiers(event_id) {
+        TIER_STORAGE.with(|tiers| tiers.borrow_mut().remove(&(event_id, tier.id)));
+        store_visibility(event_id, tier.id, TierVisibility::Public);
+    }
+}
@@ -0,0 +1,170 @@
+use crate::clock::time;
+use crate::ratelimit::rate_limit;
+use crate::{organizers, Error, Event, Memory, StringKey, MEMORY_MANAGER};
+use candid::{Decode, Encode};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use std::{borrow::Cow, cell::RefCell};
+
+// Longest language tag accepted, enough for a language, script, region and variant
+const MAX_LANG_LEN: usize = 35;
+const MAX_TRANSLATIONS_PER_EVENT: usize = 20;
+const MAX_NAME_LEN: usize = 256;
+const MAX_DESCRIPTION_LEN: usize = 1024;
+
+// Define a struct for the name and description of an event in another language
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct TranslationPayload {
+    name: String,
+    description: String,
+}
+
+// Define a struct for a stored translation of an event
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct EventTranslation {
+    // BCP-47 language tag in lowercase, e.g. "pt-br"
+    lang: String,
+    name: String,
+    description: String,
+    updated_at: u64,
+}
+
+impl Storable for EventTranslation {
+    // Conversion to bytes
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    // Conversion from bytes
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for EventTranslation {
+    const MAX_SIZE: u32 = 2048;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    // Translations keyed by (event id, language tag)
+    static TRANSLATION_STORAGE: RefCell<StableBTreeMap<(u64, StringKey), EventTranslation, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(111)))
+    ));
+}
+
+#[ic_cdk::query]
+fn get_event_translations(event_id: u64) -> Vec<EventTranslation> {
+    _get_event_translations(event_id)
+}
+
+// Set the name and description of an event in a language, None to remove the translation
+#[ic_cdk::update(guard = "rate_limit")]
+fn set_event_translation(
+    event_id: u64,
+    lang: String,
+    payload: Option<TranslationPayload>,
+) -> Result<Option<EventTranslation>, Error> {
+    organizers::authorize_event(event_id)?;
+    let lang = normalize_lang(&lang).map_err(|msg| Error::ValidationFailed { msg })?;
+    let key = (event_id, StringKey(lang.clone()));
+
+    let Some(payload) = payload else {
+        TRANSLATION_STORAGE.with(|translations| translations.borrow_mut().remove(&key));
+        return Ok(None);
+    };
+    if payload.name.trim().is_empty() || payload.name.len() > MAX_NAME_LEN {
+        return Err(Error::ValidationFailed {
+            msg: format!("translated name must be 1 to {} bytes", MAX_NAME_LEN),
+        });
+    }
+    if payload.description.len() > MAX_DESCRIPTION_LEN {
+        return Err(Error::ValidationFailed {
+            msg: format!(
+                "translated description must be at most {} bytes",
+                MAX_DESCRIPTION_LEN
+            ),
+        });
+    }
+    let exists = TRANSLATION_STORAGE.with(|translations| translations.borrow().contains_key(&key));
+    if !exists && _get_event_translations(event_id).len() >= MAX_TRANSLATIONS_PER_EVENT {
+        return Err(Error::ValidationFailed {
+            msg: format!(
+                "an event can have at most {} translations",
+                MAX_TRANSLATIONS_PER_EVENT
+            ),
+        });
+    }
+
+    let translation = EventTranslation {
+        lang,
+        name: payload.name,
+        description: payload.description,
+        updated_at: time(),
+    };
+    TRANSLATION_STORAGE
+        .with(|translations| translations.borrow_mut().insert(key, translation.clone()));
+    Ok(Some(translation))
+}
+
+// Check a BCP-47 language tag, e.g. "en", "pt-BR" or "zh-Hant-TW", and put it in lowercase
+fn normalize_lang(lang: &str) -> Result<String, String> {
+    let lang = lang.trim().to_lowercase();
+    let mut subtags = lang.split('-');
+    let valid = lang.len() <= MAX_LANG_LEN
+        && subtags
+            .next()
+            .is_some_and(|primary| (2..=3).contains(&primary.len()))
+        && lang.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+        && subtags.all(|subtag| (1..=8).contains(&subtag.len()));
+    if !valid {
+        return Err(format!("'{}' is not a BCP-47 language tag", lang));
+    }
+    Ok(lang)
+}
+
+fn _get_event_translations(event_id: u64) -> Vec<EventTranslation> {
+    TRANSLATION_STORAGE.with(|translations| {
+        translations
+            .borrow()
+            .range((event_id, StringKey::default())..)
+            .take_while(|((id, _), _)| *id == event_id)
+            .map(|(_, translation)| translation)
+            .collect()
+    })
+}
+
+// Swap in the name and description of an event in the language asked for. A regional tag falls
+// back to its language, e.g. "pt-br" to "pt", and the event is left as it is without either.
+pub(crate) fn localize(mut event: Event, lang: Option<&str>) -> Event {
+    let Some(lang) = lang.and_then(|lang| normalize_lang(lang).ok()) else {
+        return event;
+    };
+    let primary = lang.split('-').next().unwrap_or_default().to_string();
+    let translation = TRANSLATION_STORAGE.with(|translations| {
+        let translations = translations.borrow();
+        translations
+            .get(&(event.id, StringKey(lang.clone())))
+            .or_else(|| translations.get(&(event.id, StringKey(primary))))
+    });
+    if let Some(translation) = translation {
+        event.name = translation.name;
+        event.description = translation.description;
+    }
+    event
+}
+
+// Drop the translations of a purged event
+pub(crate) fn remove_event_translations(event_id: u64) {
+    TRANSLATION_STORAGE.with(|translations| {
+        let mut translations = translations.borrow_mut();
+        let keys: Vec<(u64, StringKey)> = translations
+            .range((event_id, StringKey::default())..)
+            .take_while(|((id, _), _)| *id == event_id)
+            .map(|(key, _)| key)
+            .collect();
+        for key in keys {
+            translations.remove(&key);
+        }
+    });
+}
@@ -0,0 +1,250 @@
+use crate::changes::{self, ChangeEntity, ChangeOperation};
+use crate::clock::time;
+use crate::legal_holds::HoldTarget;
+use crate::ratelimit::rate_limit;
+use crate::{
+    _get_stored_event, _get_stored_user, addons, alerts, announcements, anonymization, assets,
+    attendance, attendee_notes, caller_is_admin, cancellations, certification, checkin,
+    credentials, credits, discovery, display, donations, emails, embargo, favorites, flash_sales,
+    fraud, geo, group_bookings, history, icrc7, insurance, invitations, jobs, legal_holds,
+    load_event, load_user, memberships, moderation, occupancy, organizers, passes, payments,
+    payouts, promo, qr_tokens, registrations, remove_event_ids, remove_user_ids, resale,
+    reschedules, reservations, reviews, rooms, sandbox, seats, series, sponsorship, stats,
+    store_event, store_user, surveys, ticket_codes, tiers, translations, venues, waitlist,
+    webhooks, Error, Event, User, EVENT_STORAGE, TICKET_STORAGE, USER_STORAGE,
+};
+
+#[ic_cdk::query(guard = "caller_is_admin")]
+fn get_deleted_events() -> Vec<Event> {
+    let events: Vec<Event> = EVENT_STORAGE.with(|events| {
+        events
+            .borrow()
+            .iter()
+            .map(|(_, event)| event)
+            .filter(|event| event.deleted_at.is_some())
+            .collect()
+    });
+    events.into_iter().map(load_event).collect()
+}
+
+#[ic_cdk::query(guard = "caller_is_admin")]
+fn get_deleted_users() -> Vec<User> {
+    let users: Vec<User> = USER_STORAGE.with(|users| {
+        users
+            .borrow()
+            .iter()
+            .map(|(_, user)| user)
+            .filter(|user| user.deleted_at.is_some())
+            .collect()
+    });
+    users.into_iter().map(load_user).collect()
+}
+
+#[ic_cdk::update(guard = "rate_limit")]
+fn restore_event(id: u64) -> Result<Event, Error> {
+    // Only events in the trash can be restored, purged ones are gone
+    let mut event = _get_stored_event(&id)
+        .filter(|event| event.deleted_at.is_some())
+        .ok_or(Error::NotFound {
+            entity: "event".to_string(),
+            id: id.to_string(),
+            msg: format!("event id:{} is not in the trash", id),
+        })?;
+    organizers::check_event_organizer(&event)?;
+
+    event.deleted_at = None;
+    // The details of its venue may have changed while it was in the trash
+    if let Some(venue) = venues::linked_venue(id) {
+        event.venue = Some(venue);
+    }
+    // Its slot at the venue may have been taken while it was in the trash
+    venues::check_schedule(&event)?;
+    event.updated_at = Some(time());
+    store_event(&mut event);
+    certification::certify_event(id);
+    discovery::index_event(&event);
+    geo::index_event(&event);
+
+    Ok(event)
+}
+
+#[ic_cdk::update(guard = "rate_limit")]
+fn restore_user(id: u64) -> Result<User, Error> {
+    // Only users in the trash can be restored, purged ones are gone
+    let mut user = _get_stored_user(&id)
+        .filter(|user| user.deleted_at.is_some())
+        .ok_or(Error::NotFound {
+            entity: "user".to_string(),
+            id: id.to_string(),
+            msg: format!("user id:{} is not in the trash", id),
+        })?;
+
+    user.deleted_at = None;
+    user.updated_at = Some(time());
+    store_user(&mut user);
+
+    Ok(user)
+}
+
+#[ic_cdk::update(guard = "caller_is_admin")]
+fn purge_deleted(older_than_ns: u64) -> String {
+    // Only records that sat in the trash for at least 'older_than_ns' are purged, held ones stay
+    let cutoff = time().saturating_sub(older_than_ns);
+    let expired = |deleted_at: Option<u64>| deleted_at.is_some_and(|at| at <= cutoff);
+
+    let events: Vec<Event> = EVENT_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .map(|(_, event)| event)
+            .filter(|event| expired(event.deleted_at))
+            .map(load_event)
+            .filter(|event| legal_holds::check_event(event).is_ok())
+            .collect()
+    });
+    let user_ids: Vec<u64> = USER_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .filter(|(_, user)| expired(user.deleted_at))
+            .filter(|(id, _)| legal_holds::check_record(HoldTarget::User { id: *id }).is_ok())
+            .map(|(id, _)| id)
+            .collect()
+    });
+
+    for event in &events {
+        purge_event(event);
+    }
+    for user_id in &user_ids {
+        if let Some(user) = USER_STORAGE.with(|users| users.borrow_mut().remove(user_id)) {
+            emails::unindex_user(&user);
+            changes::record_change(
+                ChangeEntity::User,
+                *user_id,
+                Some(*user_id),
+                ChangeOperation::Deleted,
+            );
+        }
+        remove_user_ids(*user_id);
+        credentials::remove_user_credentials(*user_id);
+        icrc7::remove_user_wallet(*user_id);
+        credits::remove_user_credit(*user_id);
+        favorites::remove_user_favorites(*user_id);
+        memberships::remove_user_memberships(*user_id);
+        display::remove_user_preferences(*user_id);
+        registrations::remove_user_registrations(*user_id);
+        passes::remove_user_passes(*user_id);
+        moderation::remove_user_ban(*user_id);
+    }
+    remove_dangling_references();
+
+    format!(
+        "{} events and {} users purged",
+        events.len(),
+        user_ids.len()
+    )
+}
+
+// Remove an event for good, along with its tickets and everything else kept for it
+pub(crate) fn purge_event(event: &Event) {
+    let id = event.id;
+    EVENT_STORAGE.with(|events| events.borrow_mut().remove(&id));
+    remove_event_ids(id);
+    changes::record_change(ChangeEntity::Event, id, None, ChangeOperation::Deleted);
+    organizers::unindex_event(event);
+    for ticket_id in &event.ticket_ids {
+        let ticket = TICKET_STORAGE.with(|tickets| tickets.borrow_mut().remove(ticket_id));
+        if let Some(ticket) = &ticket {
+            changes::record_change(
+                ChangeEntity::Ticket,
+                *ticket_id,
+                Some(ticket.user_id),
+                ChangeOperation::Deleted,
+            );
+        }
+        if let Some(code) = ticket.and_then(|ticket| ticket.code) {
+            ticket_codes::release_code(&code);
+        }
+    }
+    certification::certify_event(id);
+
+    stats::remove_event_stats(id);
+    history::remove_event_history(id);
+    waitlist::remove_event_waitlist(id);
+    tiers::remove_event_tiers(id);
+    addons::remove_event_addons(id);
+    fraud::remove_event_fraud(id);
+    venues::remove_event_booking(id);
+    seats::remove_event_seats(id);
+    reservations::remove_event_reservations(id);
+    group_bookings::remove_event_group_bookings(id);
+    invitations::remove_event_invitations(id);
+    resale::remove_event_resale(id);
+    payments::remove_event_payment(id);
+    alerts::remove_event_alerts(id);
+    credits::remove_event_refund_policy(id);
+    cancellations::remove_event_cancellation_policy(id);
+    moderation::remove_event_suspension(id);
+    reschedules::remove_event_reschedule(id);
+    webhooks::remove_event_webhooks(id);
+    promo::remove_event_promo_codes(id);
+    flash_sales::remove_event_flash_sales(id);
+    insurance::remove_event_insurance_offer(id);
+    sponsorship::remove_event_sponsorships(id);
+    donations::remove_event_donation_settings(id);
+    surveys::remove_event_survey(id);
+    reviews::remove_event_reviews(id);
+    favorites::remove_event_favorites(id);
+    memberships::remove_event_on_sale(id);
+    payouts::remove_event_payouts(id);
+    attendee_notes::remove_event_attendee_notes(id);
+    attendance::remove_event_badge(id);
+    ticket_codes::remove_event_code_format(id);
+    qr_tokens::remove_event_qr_tokens(id);
+    sandbox::remove_event_test_mode(id);
+    jobs::remove_event_reminder(id);
+    occupancy::remove_event_gates(id);
+    checkin::remove_event_checkins(id);
+    anonymization::remove_event_anonymization(id);
+    announcements::remove_event_watchers(id);
+    embargo::remove_event_embargo(id);
+    assets::remove_event_image(id);
+    rooms::remove_event_rooms(id);
+    display::remove_event_timezone(id);
+    translations::remove_event_translations(id);
+    registrations::remove_event_registrations(id);
+    if let Some(series_id) = event.series_id {
+        series::remove_series_instance(series_id, id);
+    }
+}
+
+// Strip ids of purged records from the events and users that are left, including those in the trash
+fn remove_dangling_references() {
+    let ticket_exists = |id: &u64| TICKET_STORAGE.with(|tickets| tickets.borrow().contains_key(id));
+    let event_exists = |id: &u64| EVENT_STORAGE.with(|events| events.borrow().contains_key(id));
+    let user_exists = |id: &u64| USER_STORAGE.with(|users| users.borrow().contains_key(id));
+
+    let events: Vec<Event> =
+        EVENT_STORAGE.with(|storage| storage.borrow().iter().map(|(_, event)| event).collect());
+    for mut event in events.into_iter().map(load_event) {
+        let count = (event.attendee_ids.len(), event.ticket_ids.len());
+        event.attendee_ids.retain(user_exists);
+        event.ticket_ids.retain(ticket_exists);
+        if (event.attendee_ids.len(), event.ticket_ids.len()) != count {
+            let id = event.id;
+            store_event(&mut event);
+            certification::certify_event(id);
+        }
+    }
+
+    let users: Vec<User> =
+        USER_STORAGE.with(|storage| storage.borrow().iter().map(|(_, user)| user).collect());
+    for mut user in users.into_iter().map(load_user) {
+        let count = (user.event_ids.len(), user.ticket_ids.len());
+        user.event_ids.retain(event_exists);
+        user.ticket_ids.retain(ticket_exists);
+        if (user.event_ids.len(), user.ticket_ids.len()) != count {
+            store_user(&mut user);
+        }
+    }
+}
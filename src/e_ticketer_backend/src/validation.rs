@@ -0,0 +1,217 @@
+use crate::{caller_is_admin, Error, EventPayload, Memory, TicketPayload, MEMORY_MANAGER};
+use candid::{Decode, Encode, Principal};
+use ic_cdk::api::management_canister::http_request::{
+    http_request, CanisterHttpRequestArgument, HttpHeader, HttpMethod,
+    HttpResponse as OutcallResponse, TransformArgs, TransformContext,
+};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{Cell, Storable};
+use std::{borrow::Cow, cell::RefCell};
+
+// Cycles attached to each outcall, enough for a JSON request and a status-only response
+const OUTCALL_CYCLES: u128 = 2_000_000_000;
+const OUTCALL_MAX_RESPONSE_BYTES: u64 = 2048;
+
+// Define an enum for where a validation hook is reached
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub enum HookTarget {
+    // Called with a 'ValidationRequest', answering 'variant { Ok; Err : text }'
+    Canister {
+        canister_id: Principal,
+        method: String,
+    },
+    // Sent the 'ValidationRequest' as a JSON POST, any 2xx status approves it
+    Url {
+        url: String,
+    },
+}
+
+// Define an enum for what happens when the hook can't be reached or gives no answer
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq)]
+pub enum HookFallback {
+    Allow,
+    Reject,
+}
+
+// Define a struct for the hook consulted before events publish and tickets issue
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct ValidationHook {
+    target: HookTarget,
+    validate_events: bool,
+    validate_tickets: bool,
+    on_timeout: HookFallback,
+}
+
+// Define an enum for what a validation hook is asked to approve
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub enum ValidationSubject {
+    EventPublish { event: Box<EventPayload> },
+    TicketIssue { tickets: Vec<TicketPayload> },
+}
+
+// Define a struct for the request sent to a validation hook
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub struct ValidationRequest {
+    caller: Principal,
+    subject: ValidationSubject,
+}
+
+// Define a struct for the settings of the validation subsystem
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub(crate) struct ValidationSettings {
+    hook: Option<ValidationHook>,
+}
+
+impl Storable for ValidationSettings {
+    // Conversion to bytes
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    // Conversion from bytes
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+thread_local! {
+    static SETTINGS: RefCell<Cell<ValidationSettings, Memory>> = RefCell::new(
+        Cell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(35))),
+            ValidationSettings::default(),
+        )
+        .expect("Cannot create the validation settings")
+    );
+}
+
+#[ic_cdk::query(guard = "caller_is_admin")]
+fn get_validation_hook() -> Option<ValidationHook> {
+    SETTINGS.with(|settings| settings.borrow().get().hook.clone())
+}
+
+#[ic_cdk::update(guard = "caller_is_admin")]
+fn set_validation_hook(hook: Option<ValidationHook>) -> Result<String, Error> {
+    if let Some(hook) = &hook {
+        match &hook.target {
+            HookTarget::Url { url } if !url.starts_with("https://") => {
+                return Err(Error::ValidationFailed {
+                    msg: format!("validation url {} must use https", url),
+                });
+            }
+            HookTarget::Canister { method, .. } if method.is_empty() => {
+                return Err(Error::ValidationFailed {
+                    msg: "validation canister method must not be empty".to_string(),
+                });
+            }
+            _ => (),
+        }
+    }
+
+    SETTINGS
+        .with(|settings| settings.borrow_mut().set(ValidationSettings { hook }))
+        .map_err(|_| Error::InternalError {
+            msg: "validation settings could not be saved".to_string(),
+            ticket_id: None,
+        })?;
+
+    Ok("validation hook updated".to_string())
+}
+
+#[ic_cdk::query]
+fn transform_validation_response(args: TransformArgs) -> OutcallResponse {
+    // Only the status is used, headers and body differ between replicas
+    OutcallResponse {
+        status: args.response.status,
+        ..Default::default()
+    }
+}
+
+// Ask the hook whether an event may publish, approving right away without a hook
+pub(crate) async fn validate_event(payload: &EventPayload) -> Result<(), String> {
+    match hook().filter(|hook| hook.validate_events) {
+        Some(hook) => {
+            let subject = ValidationSubject::EventPublish {
+                event: Box::new(payload.clone()),
+            };
+            consult(hook, subject).await
+        }
+        None => Ok(()),
+    }
+}
+
+// Ask the hook whether tickets may issue, approving right away without a hook
+pub(crate) async fn validate_tickets(payloads: &[TicketPayload]) -> Result<(), String> {
+    match hook().filter(|hook| hook.validate_tickets) {
+        Some(hook) => {
+            let subject = ValidationSubject::TicketIssue {
+                tickets: payloads.to_vec(),
+            };
+            consult(hook, subject).await
+        }
+        None => Ok(()),
+    }
+}
+
+fn hook() -> Option<ValidationHook> {
+    SETTINGS.with(|settings| settings.borrow().get().hook.clone())
+}
+
+async fn consult(hook: ValidationHook, subject: ValidationSubject) -> Result<(), String> {
+    let request = ValidationRequest {
+        caller: ic_cdk::caller(),
+        subject,
+    };
+    let answer = match &hook.target {
+        HookTarget::Canister {
+            canister_id,
+            method,
+        } => call_canister(*canister_id, method, request).await,
+        HookTarget::Url { url } => call_url(url, &request).await,
+    };
+
+    match answer {
+        Some(verdict) => {
+            verdict.map_err(|reason| format!("rejected by the validation hook: {}", reason))
+        }
+        None if hook.on_timeout == HookFallback::Allow => Ok(()),
+        None => Err("the validation hook could not be reached, try again later".to_string()),
+    }
+}
+
+// The verdict of a validation canister, or None if it gave no answer
+async fn call_canister(
+    canister_id: Principal,
+    method: &str,
+    request: ValidationRequest,
+) -> Option<Result<(), String>> {
+    ic_cdk::call::<_, (Result<(), String>,)>(canister_id, method, (request,))
+        .await
+        .ok()
+        .map(|(verdict,)| verdict)
+}
+
+// The verdict of a validation endpoint, or None if it gave no answer
+async fn call_url(url: &str, request: &ValidationRequest) -> Option<Result<(), String>> {
+    let request = CanisterHttpRequestArgument {
+        url: url.to_string(),
+        max_response_bytes: Some(OUTCALL_MAX_RESPONSE_BYTES),
+        method: HttpMethod::POST,
+        headers: vec![HttpHeader {
+            name: "Content-Type".to_string(),
+            value: "application/json".to_string(),
+        }],
+        body: Some(serde_json::to_vec(request).unwrap()),
+        transform: Some(TransformContext::from_name(
+            "transform_validation_response".to_string(),
+            vec![],
+        )),
+    };
+
+    match http_request(request, OUTCALL_CYCLES).await {
+        Ok((response,)) if response.status >= 200u64 && response.status < 300u64 => Some(Ok(())),
+        // A client error is the endpoint saying no, anything else is treated as no answer
+        Ok((response,)) if response.status >= 400u64 && response.status < 500u64 => Some(Err(
+            format!("endpoint answered with status {}", response.status),
+        )),
+        _ => None,
+    }
+}
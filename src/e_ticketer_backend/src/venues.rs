@@ -0,0 +1,448 @@
+use crate::announcements::{self, AnnouncementStage};
+use crate::clock::time;
+use crate::geo::{self, Venue};
+use crate::ratelimit::rate_limit;
+use crate::{
+    _get_event, caller_is_admin, certification, discovery, history, jobs, notifications,
+    organizers, store_event, Error, Event, Memory, ID_COUNTER, MEMORY_MANAGER,
+};
+use candid::{Decode, Encode, Principal};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use std::{borrow::Cow, cell::RefCell};
+
+const NANOS_PER_MINUTE: u64 = 60 * 1_000_000_000;
+// Managers per venue, so a venue fits in its stable map entry
+const MAX_MANAGERS: usize = 10;
+const DEFAULT_DURATION_MINS: u64 = 3 * 60;
+// Events held for longer than this are split into several events
+const MAX_DURATION_MINS: u64 = 7 * 24 * 60;
+
+// Define an enum for what happens when a published event would overlap another at the venue
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq, Default)]
+pub enum ConflictPolicy {
+    // The change that causes the overlap is turned down
+    #[default]
+    Reject,
+    // The change goes through, the overlap shows in the venue's schedule
+    Warn,
+}
+
+// Define a struct for a venue kept as a record of its own, so the events held there can be
+// scheduled against each other
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct ManagedVenue {
+    id: u64,
+    details: Venue,
+    // Identities that can change the venue and see its schedule, besides admins
+    managers: Vec<Principal>,
+    conflict_policy: ConflictPolicy,
+    // How long events linked without a duration of their own are taken to last
+    default_duration_mins: u64,
+    created_at: u64,
+    updated_at: Option<u64>,
+}
+
+// Define a struct for the payload used to create and update venues
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub struct ManagedVenuePayload {
+    details: Venue,
+    // The creator is added to the managers of a new venue
+    managers: Vec<Principal>,
+    conflict_policy: Option<ConflictPolicy>,
+    default_duration_mins: Option<u64>,
+}
+
+// Define a struct for the link between an event and the venue it is held at
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct VenueBooking {
+    venue_id: u64,
+    event_id: u64,
+    // None to use the venue's default
+    duration_mins: Option<u64>,
+    booked_at: u64,
+}
+
+// Define a struct for an event on a venue's schedule
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub struct VenueScheduleEntry {
+    venue_id: u64,
+    event_id: u64,
+    event_name: String,
+    // None for events without a valid date
+    starts_at: Option<u64>,
+    ends_at: Option<u64>,
+    // Only published events are checked for overlaps
+    published: bool,
+    // Published events at the venue whose time overlaps this one's
+    conflicts: Vec<u64>,
+}
+
+impl Storable for ManagedVenue {
+    // Conversion to bytes
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    // Conversion from bytes
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for ManagedVenue {
+    const MAX_SIZE: u32 = 1024;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl Storable for VenueBooking {
+    // Conversion to bytes
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    // Conversion from bytes
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for VenueBooking {
+    const MAX_SIZE: u32 = 128;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    // Venues keyed by venue id
+    static VENUE_STORAGE: RefCell<StableBTreeMap<u64, ManagedVenue, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(142)))
+    ));
+
+    // Bookings keyed by (venue id, event id)
+    static BOOKING_STORAGE: RefCell<StableBTreeMap<(u64, u64), VenueBooking, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(143)))
+    ));
+
+    // Venue id keyed by event id, an event is held at one venue
+    static EVENT_VENUES: RefCell<StableBTreeMap<u64, u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(144)))
+    ));
+}
+
+#[ic_cdk::query]
+fn get_venue(venue_id: u64) -> Result<ManagedVenue, Error> {
+    _get_venue(venue_id).ok_or(Error::not_found("venue", venue_id))
+}
+
+#[ic_cdk::query]
+fn get_venues() -> Vec<ManagedVenue> {
+    VENUE_STORAGE.with(|venues| venues.borrow().iter().map(|(_, venue)| venue).collect())
+}
+
+// Create a venue, open to organizers as they set up the places they hold events at
+#[ic_cdk::update(guard = "rate_limit")]
+fn create_venue(payload: ManagedVenuePayload) -> Result<ManagedVenue, Error> {
+    organizers::check_can_create()?;
+    let mut managers = payload.managers.clone();
+    let caller = ic_cdk::caller();
+    if !managers.contains(&caller) {
+        managers.insert(0, caller);
+    }
+    validate_payload(&payload, &managers).map_err(|msg| Error::ValidationFailed { msg })?;
+
+    // Increment the global ID counter to get a new ID for the venue
+    let id = ID_COUNTER
+        .with(|counter| {
+            let current_id = *counter.borrow().get();
+            counter.borrow_mut().set(current_id + 1)
+        })
+        .expect("Cannot increment Ids");
+
+    let venue = ManagedVenue {
+        id,
+        details: payload.details,
+        managers,
+        conflict_policy: payload.conflict_policy.unwrap_or_default(),
+        default_duration_mins: payload
+            .default_duration_mins
+            .unwrap_or(DEFAULT_DURATION_MINS),
+        created_at: time(),
+        updated_at: None,
+    };
+    VENUE_STORAGE.with(|venues| venues.borrow_mut().insert(id, venue.clone()));
+
+    Ok(venue)
+}
+
+// Change a venue, its managers are replaced by those given
+#[ic_cdk::update(guard = "rate_limit")]
+fn update_venue(venue_id: u64, payload: ManagedVenuePayload) -> Result<ManagedVenue, Error> {
+    let venue = authorize_venue(venue_id)?;
+    validate_payload(&payload, &payload.managers).map_err(|msg| Error::ValidationFailed { msg })?;
+
+    let updated_venue = ManagedVenue {
+        details: payload.details,
+        managers: payload.managers,
+        conflict_policy: payload.conflict_policy.unwrap_or(venue.conflict_policy),
+        default_duration_mins: payload
+            .default_duration_mins
+            .unwrap_or(venue.default_duration_mins),
+        updated_at: Some(time()),
+        ..venue
+    };
+    VENUE_STORAGE.with(|venues| venues.borrow_mut().insert(venue_id, updated_venue.clone()));
+    // The events held there take the new details
+    for booking in venue_bookings(venue_id) {
+        apply_details(booking.event_id, &updated_venue.details);
+    }
+
+    Ok(updated_venue)
+}
+
+#[ic_cdk::update(guard = "rate_limit")]
+fn delete_venue(venue_id: u64) -> Result<String, Error> {
+    authorize_venue(venue_id)?;
+    if !venue_bookings(venue_id).is_empty() {
+        return Err(Error::HasDependents {
+            msg: format!(
+                "venue id:{} still has events, move them to another venue first",
+                venue_id
+            ),
+        });
+    }
+    VENUE_STORAGE.with(|venues| venues.borrow_mut().remove(&venue_id));
+    Ok(format!("venue id: {} deleted", venue_id))
+}
+
+// Hold an event at a venue, or take it off the venue's schedule with None. A published event
+// is checked against the venue's other published events. The event takes the venue's details
+// for as long as it is held there, and keeps them once taken off.
+#[ic_cdk::update(guard = "rate_limit")]
+fn set_event_venue(
+    event_id: u64,
+    venue_id: Option<u64>,
+    duration_mins: Option<u64>,
+) -> Result<Option<VenueScheduleEntry>, Error> {
+    let event = organizers::authorize_event(event_id)?;
+    let Some(venue_id) = venue_id else {
+        remove_event_booking(event_id);
+        return Ok(None);
+    };
+    let venue = _get_venue(venue_id).ok_or(Error::not_found("venue", venue_id))?;
+    if let Some(duration_mins) = duration_mins {
+        check_duration(duration_mins).map_err(|msg| Error::ValidationFailed { msg })?;
+    }
+
+    let booking = VenueBooking {
+        venue_id,
+        event_id,
+        duration_mins,
+        booked_at: time(),
+    };
+    let conflicts = conflicts(&event, &venue, &booking);
+    check_conflicts(&event, &venue, &conflicts)?;
+
+    remove_event_booking(event_id);
+    BOOKING_STORAGE.with(|bookings| {
+        bookings
+            .borrow_mut()
+            .insert((venue_id, event_id), booking.clone())
+    });
+    EVENT_VENUES.with(|venues| venues.borrow_mut().insert(event_id, venue_id));
+    apply_details(event_id, &venue.details);
+
+    Ok(Some(schedule_entry(&event, &venue, &booking, conflicts)))
+}
+
+// Events held at a venue starting within [from, to), by start
+#[ic_cdk::query]
+fn get_venue_schedule(venue_id: u64, from: u64, to: u64) -> Result<Vec<VenueScheduleEntry>, Error> {
+    let venue = authorize_venue(venue_id)?;
+    if to <= from {
+        return Err(Error::ValidationFailed {
+            msg: "the schedule must end after it starts".to_string(),
+        });
+    }
+
+    let mut entries: Vec<VenueScheduleEntry> = venue_bookings(venue_id)
+        .into_iter()
+        .filter_map(|booking| {
+            let event = _get_event(&booking.event_id).filter(|event| event.deleted_at.is_none())?;
+            let starts_at = jobs::event_start(&event)?;
+            if starts_at < from || starts_at >= to {
+                return None;
+            }
+            let conflicts = conflicts(&event, &venue, &booking);
+            Some(schedule_entry(&event, &venue, &booking, conflicts))
+        })
+        .collect();
+    entries.sort_by_key(|entry| (entry.starts_at, entry.event_id));
+    Ok(entries)
+}
+
+// Check an event about to be stored, e.g. rescheduled or published, doesn't overlap another
+// published event at its venue, if the venue turns overlaps down
+pub(crate) fn check_schedule(event: &Event) -> Result<(), Error> {
+    let Some(venue_id) = EVENT_VENUES.with(|venues| venues.borrow().get(&event.id)) else {
+        return Ok(());
+    };
+    let (Some(venue), Some(booking)) = (_get_venue(venue_id), _get_booking(venue_id, event.id))
+    else {
+        return Ok(());
+    };
+    check_conflicts(event, &venue, &conflicts(event, &venue, &booking))
+}
+
+// Details of the venue an event is held at, which take the place of those given for the event
+pub(crate) fn linked_venue(event_id: u64) -> Option<Venue> {
+    let venue_id = EVENT_VENUES.with(|venues| venues.borrow().get(&event_id))?;
+    _get_venue(venue_id).map(|venue| venue.details)
+}
+
+// Take a deleted event off its venue's schedule
+pub(crate) fn remove_event_booking(event_id: u64) {
+    if let Some(venue_id) = EVENT_VENUES.with(|venues| venues.borrow_mut().remove(&event_id)) {
+        BOOKING_STORAGE.with(|bookings| bookings.borrow_mut().remove(&(venue_id, event_id)));
+    }
+}
+
+fn _get_venue(venue_id: u64) -> Option<ManagedVenue> {
+    VENUE_STORAGE.with(|venues| venues.borrow().get(&venue_id))
+}
+
+fn _get_booking(venue_id: u64, event_id: u64) -> Option<VenueBooking> {
+    BOOKING_STORAGE.with(|bookings| bookings.borrow().get(&(venue_id, event_id)))
+}
+
+// Copy a venue's details into an event held there, so the event reads the same as the venue.
+// Events in the trash pick them up when restored.
+fn apply_details(event_id: u64, details: &Venue) {
+    let Some(mut event) = _get_event(&event_id) else {
+        return;
+    };
+    if event.venue.as_ref() == Some(details) {
+        return;
+    }
+    let before = event.clone();
+    discovery::unindex_event(&event);
+    geo::unindex_event(&event);
+    event.venue = Some(details.clone());
+    event.updated_at = Some(time());
+    store_event(&mut event);
+    certification::certify_event(event_id);
+    discovery::index_event(&event);
+    geo::index_event(&event);
+    history::record_event_update(&before, &event);
+    // Ticket holders hear of a new venue, as with any other change of it
+    notifications::notify_event_changed(&event);
+}
+
+fn venue_bookings(venue_id: u64) -> Vec<VenueBooking> {
+    BOOKING_STORAGE.with(|bookings| {
+        bookings
+            .borrow()
+            .range((venue_id, 0)..=(venue_id, u64::MAX))
+            .map(|(_, booking)| booking)
+            .collect()
+    })
+}
+
+// Check the caller manages a venue, or is an admin
+fn authorize_venue(venue_id: u64) -> Result<ManagedVenue, Error> {
+    let venue = _get_venue(venue_id).ok_or(Error::not_found("venue", venue_id))?;
+    if caller_is_admin().is_err() && !venue.managers.contains(&ic_cdk::caller()) {
+        return Err(Error::Unauthorized {
+            msg: format!("caller does not manage venue id:{}", venue_id),
+        });
+    }
+    Ok(venue)
+}
+
+// Published events are fully announced and not deleted, only those take up the venue
+fn is_published(event: &Event) -> bool {
+    event.deleted_at.is_none() && announcements::stage(event) == AnnouncementStage::FullyAnnounced
+}
+
+// Start and end of an event at a venue, None for events without a valid date
+fn time_range(event: &Event, venue: &ManagedVenue, booking: &VenueBooking) -> Option<(u64, u64)> {
+    let starts_at = jobs::event_start(event)?;
+    let duration_mins = booking.duration_mins.unwrap_or(venue.default_duration_mins);
+    Some((starts_at, starts_at + duration_mins * NANOS_PER_MINUTE))
+}
+
+// Other published events at the venue whose time overlaps the event's, if it is published
+fn conflicts(event: &Event, venue: &ManagedVenue, booking: &VenueBooking) -> Vec<u64> {
+    let Some((starts_at, ends_at)) = time_range(event, venue, booking) else {
+        return vec![];
+    };
+    if !is_published(event) {
+        return vec![];
+    }
+    venue_bookings(venue.id)
+        .into_iter()
+        .filter(|other| other.event_id != event.id)
+        .filter_map(|other| {
+            let other_event = _get_event(&other.event_id).filter(is_published)?;
+            let (other_starts_at, other_ends_at) = time_range(&other_event, venue, &other)?;
+            (other_starts_at < ends_at && starts_at < other_ends_at).then_some(other_event.id)
+        })
+        .collect()
+}
+
+fn check_conflicts(event: &Event, venue: &ManagedVenue, conflicts: &[u64]) -> Result<(), Error> {
+    if conflicts.is_empty() || venue.conflict_policy == ConflictPolicy::Warn {
+        return Ok(());
+    }
+    let ids: Vec<String> = conflicts.iter().map(|id| id.to_string()).collect();
+    Err(Error::ValidationFailed {
+        msg: format!(
+            "event id:{} overlaps event id:{} at venue id:{}",
+            event.id,
+            ids.join(", "),
+            venue.id
+        ),
+    })
+}
+
+fn schedule_entry(
+    event: &Event,
+    venue: &ManagedVenue,
+    booking: &VenueBooking,
+    conflicts: Vec<u64>,
+) -> VenueScheduleEntry {
+    let range = time_range(event, venue, booking);
+    VenueScheduleEntry {
+        venue_id: venue.id,
+        event_id: event.id,
+        event_name: event.name.clone(),
+        starts_at: range.map(|(starts_at, _)| starts_at),
+        ends_at: range.map(|(_, ends_at)| ends_at),
+        published: is_published(event),
+        conflicts,
+    }
+}
+
+fn check_duration(duration_mins: u64) -> Result<(), String> {
+    if duration_mins == 0 || duration_mins > MAX_DURATION_MINS {
+        return Err(format!(
+            "events must last 1 to {} minutes",
+            MAX_DURATION_MINS
+        ));
+    }
+    Ok(())
+}
+
+fn validate_payload(payload: &ManagedVenuePayload, managers: &[Principal]) -> Result<(), String> {
+    geo::validate_venue(&payload.details)?;
+    if managers.is_empty() || managers.len() > MAX_MANAGERS {
+        return Err(format!("a venue must have 1 to {} managers", MAX_MANAGERS));
+    }
+    if managers.contains(&Principal::anonymous()) {
+        return Err("the anonymous identity can't manage a venue".to_string());
+    }
+    if let Some(duration_mins) = payload.default_duration_mins {
+        check_duration(duration_mins)?;
+    }
+    Ok(())
+}
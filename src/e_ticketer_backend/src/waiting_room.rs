@@ -0,0 +1,186 @@
+use crate::{_get_public_event, dates, waitlist, Error, Memory, MEMORY_MANAGER};
+use candid::{Decode, Encode};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use std::{borrow::Cow, cell::RefCell};
+
+const NANOS_PER_SECOND: u64 = 1_000_000_000;
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+// Browsers without an agent poll the page by reloading it
+const REFRESH_SECONDS: u64 = 30;
+
+// Define a struct for how fast an event's waitlist has been moving, to estimate admission times
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct Admissions {
+    admitted: u64,
+    first_admitted_at: u64,
+    last_admitted_at: u64,
+}
+
+// Define a struct for a queued buyer's place on an event's waitlist, as the waiting room shows it
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct WaitingRoomStatus {
+    event_id: u64,
+    event_name: String,
+    pub(crate) entry_id: u64,
+    // 1 for the next buyer to be admitted
+    position: u64,
+    ahead: u64,
+    queue_length: u64,
+    joined_at: u64,
+    // None until the waitlist has moved at least twice, as there is no pace to go by before
+    estimated_admission_at: Option<u64>,
+}
+
+impl Storable for Admissions {
+    // Conversion to bytes
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    // Conversion from bytes
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for Admissions {
+    const MAX_SIZE: u32 = 128;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    // Admissions from the waitlist keyed by event id
+    static ADMISSION_STORAGE: RefCell<StableBTreeMap<u64, Admissions, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(131)))
+    ));
+}
+
+// Place of a waitlist entry in its event's queue, also served over HTTP at
+// '/events/<id>/waitlist/<entry id>' as a page and with '.json' appended as JSON
+#[ic_cdk::query]
+fn get_waiting_room_status(event_id: u64, entry_id: u64) -> Result<WaitingRoomStatus, Error> {
+    waiting_room_status(event_id, entry_id)
+}
+
+pub(crate) fn waiting_room_status(
+    event_id: u64,
+    entry_id: u64,
+) -> Result<WaitingRoomStatus, Error> {
+    event_statuses(event_id)?
+        .into_iter()
+        .find(|status| status.entry_id == entry_id)
+        .ok_or(Error::NotFound {
+            entity: "waitlist entry".to_string(),
+            id: entry_id.to_string(),
+            msg: format!(
+                "entry id:{} is not on the waitlist of event id:{}, it may have been admitted",
+                entry_id, event_id
+            ),
+        })
+}
+
+// Statuses of everyone on the waitlist of a public event in join order. They only change along
+// with the waitlist, so their certified hashes stay valid: estimates are worked out from past
+// admissions rather than the time of the call.
+pub(crate) fn event_statuses(event_id: u64) -> Result<Vec<WaitingRoomStatus>, Error> {
+    let event = _get_public_event(&event_id).ok_or(Error::not_found("event", event_id))?;
+    let queue = waitlist::queue(event_id);
+    let queue_length = queue.len() as u64;
+    // Average time between two admissions so far
+    let pace = ADMISSION_STORAGE
+        .with(|admissions| admissions.borrow().get(&event_id))
+        .filter(|admissions| admissions.admitted >= 2)
+        .map(|admissions| {
+            let pace = (admissions.last_admitted_at - admissions.first_admitted_at)
+                / (admissions.admitted - 1);
+            (admissions.last_admitted_at, pace)
+        });
+
+    Ok(queue
+        .into_iter()
+        .zip(1..)
+        .map(|((entry_id, joined_at), position)| WaitingRoomStatus {
+            event_id,
+            event_name: event.name.clone(),
+            entry_id,
+            position,
+            ahead: position - 1,
+            queue_length,
+            joined_at,
+            estimated_admission_at: pace.map(|(last, pace)| last + pace * position),
+        })
+        .collect())
+}
+
+// Build the waiting room page of a status, refreshing itself for buyers to follow their place
+pub(crate) fn waiting_room_html(status: &WaitingRoomStatus) -> String {
+    let estimate = match status.estimated_admission_at {
+        Some(at) => format!("Estimated admission: {}", utc_label(at)),
+        None => "An estimate shows up once the first buyers have been admitted.".to_string(),
+    };
+    format!(
+        "<!DOCTYPE html>\n\
+         <html lang=\"en\">\n\
+         <head>\n\
+         <meta charset=\"utf-8\">\n\
+         <meta http-equiv=\"refresh\" content=\"{}\">\n\
+         <title>Waiting room - {}</title>\n\
+         </head>\n\
+         <body>\n\
+         <h1>{}</h1>\n\
+         <p>You are number {} in line, {} of {} waiting are ahead of you.</p>\n\
+         <p>{}</p>\n\
+         <p>Your ticket is issued as soon as it is your turn and a ticket frees up.</p>\n\
+         </body>\n\
+         </html>\n",
+        REFRESH_SECONDS,
+        escape(&status.event_name),
+        escape(&status.event_name),
+        status.position,
+        status.ahead,
+        status.queue_length,
+        estimate,
+    )
+}
+
+// Count a buyer admitted from the waitlist of an event
+pub(crate) fn record_admission(event_id: u64, at: u64) {
+    ADMISSION_STORAGE.with(|admissions| {
+        let mut admissions = admissions.borrow_mut();
+        let mut entry = admissions.get(&event_id).unwrap_or(Admissions {
+            first_admitted_at: at,
+            ..Default::default()
+        });
+        entry.admitted += 1;
+        entry.last_admitted_at = at;
+        admissions.insert(event_id, entry);
+    });
+}
+
+// Drop the admissions of a deleted event
+pub(crate) fn remove_event_admissions(event_id: u64) {
+    ADMISSION_STORAGE.with(|admissions| admissions.borrow_mut().remove(&event_id));
+}
+
+// Nanoseconds since the epoch as a UTC date and time, e.g. "2026-10-16 19:30 UTC"
+fn utc_label(nanos: u64) -> String {
+    let seconds = nanos / NANOS_PER_SECOND;
+    let date = dates::civil_from_days((seconds / SECONDS_PER_DAY) as i64);
+    let seconds = seconds % SECONDS_PER_DAY;
+    format!(
+        "{} {:02}:{:02} UTC",
+        dates::format_date(date),
+        seconds / 3600,
+        seconds / 60 % 60
+    )
+}
+
+// Escape the characters that have a meaning in HTML
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
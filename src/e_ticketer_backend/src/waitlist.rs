@@ -0,0 +1,236 @@
+use crate::clock::time;
+use crate::ratelimit::rate_limit;
+use crate::{
+    _create_ticket, _get_event, _get_user, certification, health, invitations, remaining_capacity,
+    tiers, waiting_room, Error, Memory, TicketPayload, ID_COUNTER, MEMORY_MANAGER,
+};
+use candid::{Decode, Encode};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use std::{borrow::Cow, cell::RefCell};
+
+// Define a struct for the 'WaitlistEntry'
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub struct WaitlistEntry {
+    id: u64,
+    event_id: u64,
+    user_id: u64,
+    tier_id: Option<u64>,
+    created_at: u64,
+}
+
+impl Storable for WaitlistEntry {
+    // Conversion to bytes
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    // Conversion from bytes
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for WaitlistEntry {
+    const MAX_SIZE: u32 = 256;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    // Waitlist entries keyed by (event id, entry id), so iteration order is join order
+    static WAITLIST_STORAGE: RefCell<StableBTreeMap<(u64, u64), WaitlistEntry, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(6)))
+    ));
+}
+
+// Helper function to get the waitlist entries of an event in join order
+fn _get_event_waitlist(event_id: u64) -> Vec<WaitlistEntry> {
+    WAITLIST_STORAGE.with(|waitlist| {
+        waitlist
+            .borrow()
+            .range((event_id, 0)..=(event_id, u64::MAX))
+            .map(|(_, entry)| entry)
+            .collect()
+    })
+}
+
+#[ic_cdk::query]
+fn get_event_waitlist(event_id: u64) -> Result<Vec<WaitlistEntry>, Error> {
+    // Make sure the event exists, or return a NotFound error if not found
+    _get_event(&event_id).ok_or(Error::not_found("event", event_id))?;
+
+    Ok(_get_event_waitlist(event_id))
+}
+
+#[ic_cdk::update(guard = "rate_limit")]
+fn join_waitlist(payload: TicketPayload) -> Result<WaitlistEntry, Error> {
+    // Retrieve the event and the user, or return a NotFound error if not found
+    let event = _get_event(&payload.event_id).ok_or(Error::not_found("event", payload.event_id))?;
+    _get_user(&payload.user_id).ok_or(Error::not_found("user", payload.user_id))?;
+    invitations::check_invited(&event, payload.user_id)
+        .map_err(|msg| Error::ValidationFailed { msg })?;
+    // Promotions run from a timer, which sees every tier, so hidden tiers are checked here
+    if let Some(tier_id) = payload.tier_id {
+        tiers::check_tier_access(
+            event.id,
+            tier_id,
+            payload.user_id,
+            payload.unlock_code.as_deref(),
+        )
+        .map_err(|msg| Error::ValidationFailed { msg })?;
+    }
+
+    // Only sold out events have a waitlist
+    if remaining_capacity(&event) != Some(0) {
+        return Err(Error::ValidationFailed {
+            msg: format!("event id:{} still has tickets available", payload.event_id),
+        });
+    }
+
+    if _get_event_waitlist(payload.event_id)
+        .iter()
+        .any(|entry| entry.user_id == payload.user_id)
+    {
+        return Err(Error::ValidationFailed {
+            msg: format!(
+                "user id:{} is already on the waitlist of event id:{}",
+                payload.user_id, payload.event_id
+            ),
+        });
+    }
+
+    // Increment the global ID counter to get a new ID for the entry
+    let id = ID_COUNTER
+        .with(|counter| {
+            let current_id = *counter.borrow().get();
+            counter.borrow_mut().set(current_id + 1)
+        })
+        .expect("Cannot increment Ids");
+
+    let entry = WaitlistEntry {
+        id,
+        event_id: payload.event_id,
+        user_id: payload.user_id,
+        tier_id: payload.tier_id,
+        created_at: time(),
+    };
+
+    WAITLIST_STORAGE.with(|waitlist| {
+        waitlist
+            .borrow_mut()
+            .insert((payload.event_id, id), entry.clone())
+    });
+    certification::certify_waiting_room(payload.event_id, &[]);
+
+    Ok(entry)
+}
+
+#[ic_cdk::update(guard = "rate_limit")]
+fn leave_waitlist(payload: TicketPayload) -> Result<String, Error> {
+    // Find the user's entry on the event's waitlist, or return a NotFound error if not found
+    let entry = _get_event_waitlist(payload.event_id)
+        .into_iter()
+        .find(|entry| entry.user_id == payload.user_id)
+        .ok_or(Error::NotFound {
+            entity: "waitlist entry".to_string(),
+            id: payload.user_id.to_string(),
+            msg: format!(
+                "user id:{} is not on the waitlist of event id:{}",
+                payload.user_id, payload.event_id
+            ),
+        })?;
+
+    WAITLIST_STORAGE.with(|waitlist| waitlist.borrow_mut().remove(&(entry.event_id, entry.id)));
+    certification::certify_waiting_room(entry.event_id, &[entry.id]);
+
+    Ok(format!(
+        "user id: {} removed from the waitlist of event id: {}",
+        payload.user_id, payload.event_id
+    ))
+}
+
+// Issue tickets to the head of the waitlist until the event is full again, returning how many
+pub(crate) fn promote_waitlist(event_id: u64) -> u64 {
+    // Left on the waitlist until sales open again
+    if health::is_read_only() {
+        return 0;
+    }
+    let mut issued = 0;
+    let mut promoted = Vec::new();
+    for entry in _get_event_waitlist(event_id) {
+        let has_room = _get_event(&event_id)
+            .map(|event| remaining_capacity(&event) != Some(0))
+            .unwrap_or(false);
+        if !has_room {
+            break;
+        }
+
+        // The entry is consumed even if issuing fails, e.g. when the user was deleted.
+        // Promotions run from a timer and don't wait on the validation hook.
+        WAITLIST_STORAGE.with(|waitlist| waitlist.borrow_mut().remove(&(event_id, entry.id)));
+        promoted.push(entry.id);
+        let ticket = _create_ticket(TicketPayload {
+            event_id,
+            user_id: entry.user_id,
+            tier_id: entry.tier_id,
+            promo_code: None,
+            insured: false,
+            donation: None,
+            seat: None,
+            metadata: None,
+            unlock_code: None,
+            addons: None,
+        });
+        if ticket.is_ok() {
+            waiting_room::record_admission(event_id, time());
+            issued += 1;
+        }
+    }
+    certification::certify_waiting_room(event_id, &promoted);
+    issued
+}
+
+// Number of people on the waitlist of an event
+pub(crate) fn waitlist_length(event_id: u64) -> u64 {
+    WAITLIST_STORAGE.with(|waitlist| {
+        waitlist
+            .borrow()
+            .range((event_id, 0)..=(event_id, u64::MAX))
+            .count() as u64
+    })
+}
+
+// Ids of the entries on the waitlist of an event along with when they joined, in join order
+pub(crate) fn queue(event_id: u64) -> Vec<(u64, u64)> {
+    WAITLIST_STORAGE.with(|waitlist| {
+        waitlist
+            .borrow()
+            .range((event_id, 0)..=(event_id, u64::MAX))
+            .map(|((_, entry_id), entry)| (entry_id, entry.created_at))
+            .collect()
+    })
+}
+
+// Ids of the events with someone on their waitlist
+pub(crate) fn waitlisted_event_ids() -> Vec<u64> {
+    let mut event_ids: Vec<u64> = WAITLIST_STORAGE.with(|waitlist| {
+        waitlist
+            .borrow()
+            .iter()
+            .map(|((event_id, _), _)| event_id)
+            .collect()
+    });
+    event_ids.dedup();
+    event_ids
+}
+
+// Drop the waitlist of a deleted event
+pub(crate) fn remove_event_waitlist(event_id: u64) {
+    let entries = _get_event_waitlist(event_id);
+    for entry in &entries {
+        WAITLIST_STORAGE.with(|waitlist| waitlist.borrow_mut().remove(&(event_id, entry.id)));
+    }
+    waiting_room::remove_event_admissions(event_id);
+    let entry_ids: Vec<u64> = entries.iter().map(|entry| entry.id).collect();
+    certification::certify_waiting_room(event_id, &entry_ids);
+}
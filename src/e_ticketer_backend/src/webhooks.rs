@@ -0,0 +1,531 @@
+use crate::clock::time;
+use crate::ratelimit::rate_limit;
+use crate::{
+    backup, config, entropy, organizers, Error, Memory, Ticket, ID_COUNTER, MEMORY_MANAGER,
+};
+use candid::{Decode, Encode};
+use ic_cdk::api::management_canister::http_request::{
+    http_request, CanisterHttpRequestArgument, HttpHeader, HttpMethod,
+    HttpResponse as OutcallResponse, TransformArgs, TransformContext,
+};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use sha2::{Digest, Sha256};
+use std::time::Duration;
+use std::{borrow::Cow, cell::RefCell};
+
+// Webhooks an event can have, each mutation is delivered to all of them
+const MAX_WEBHOOKS_PER_EVENT: usize = 5;
+const MAX_URL_LEN: usize = 512;
+
+// How often due deliveries go out, and how many per run
+const DELIVERY_INTERVAL: Duration = Duration::from_secs(30);
+const DELIVERY_BATCH: usize = 10;
+
+// Retries back off exponentially from the base delay, until the delivery is marked failed
+const RETRY_BASE_DELAY: u64 = 30 * 1_000_000_000;
+const MAX_ATTEMPTS: u32 = 8;
+
+// Finished deliveries stay this long, so organizers can look back at them while debugging
+const DELIVERY_RETENTION: u64 = 14 * 24 * 60 * 60 * 1_000_000_000;
+
+// Cycles attached to each outcall, enough for a small JSON request and a status-only response
+const OUTCALL_CYCLES: u128 = 2_000_000_000;
+const OUTCALL_MAX_RESPONSE_BYTES: u64 = 2048;
+
+// Define an enum for the mutations a webhook can subscribe to
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq)]
+pub enum WebhookEventType {
+    // ticket.created
+    TicketCreated,
+    // ticket.checked_in
+    TicketCheckedIn,
+    // event.cancelled
+    EventCancelled,
+    // event.capacity_alert
+    CapacityAlert,
+}
+
+// Define a struct for a webhook as registered by an organizer
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct WebhookPayload {
+    url: String,
+    event_types: Vec<WebhookEventType>,
+}
+
+// Define a struct for a webhook of an event
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct Webhook {
+    id: u64,
+    event_id: u64,
+    url: String,
+    event_types: Vec<WebhookEventType>,
+    // Key of the signature sent with every delivery, only returned when the webhook is registered
+    secret: Option<String>,
+    created_at: u64,
+}
+
+// Define an enum for the delivery state of a webhook call
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq)]
+pub enum DeliveryStatus {
+    Pending,
+    // Gave up after 'MAX_ATTEMPTS' deliveries
+    Failed,
+    Delivered,
+}
+
+// Define a struct for a call made, or to be made, to a webhook
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct WebhookDelivery {
+    id: u64,
+    webhook_id: u64,
+    event_id: u64,
+    event_type: WebhookEventType,
+    // JSON body, signed anew on every attempt
+    payload: String,
+    status: DeliveryStatus,
+    attempts: u32,
+    next_attempt_at: u64,
+    // HTTP status of the last attempt, None if the outcall itself failed
+    last_response_status: Option<u64>,
+    last_error: Option<String>,
+    delivered_at: Option<u64>,
+    created_at: u64,
+}
+
+impl WebhookEventType {
+    fn name(self) -> &'static str {
+        match self {
+            WebhookEventType::TicketCreated => "ticket.created",
+            WebhookEventType::TicketCheckedIn => "ticket.checked_in",
+            WebhookEventType::EventCancelled => "event.cancelled",
+            WebhookEventType::CapacityAlert => "event.capacity_alert",
+        }
+    }
+}
+
+impl Storable for Webhook {
+    // Conversion to bytes
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    // Conversion from bytes
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl Storable for WebhookDelivery {
+    // Conversion to bytes
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    // Conversion from bytes
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for Webhook {
+    const MAX_SIZE: u32 = 1024;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl BoundedStorable for WebhookDelivery {
+    const MAX_SIZE: u32 = 2048;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    // Webhooks keyed by (event id, webhook id)
+    static WEBHOOK_STORAGE: RefCell<StableBTreeMap<(u64, u64), Webhook, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(71)))
+    ));
+
+    // Deliveries keyed by id, which is also the order they were queued in
+    static DELIVERY_STORAGE: RefCell<StableBTreeMap<u64, WebhookDelivery, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(72)))
+    ));
+
+    // Set while a delivery run is waiting on outcalls, so timer ticks don't overlap
+    static DELIVERING: std::cell::Cell<bool> = const { std::cell::Cell::new(false) };
+}
+
+#[ic_cdk::update(guard = "rate_limit")]
+fn register_event_webhook(event_id: u64, payload: WebhookPayload) -> Result<Webhook, Error> {
+    organizers::authorize_event(event_id)?;
+    let url = payload.url.trim().to_string();
+    validate_url(&url).map_err(|msg| Error::ValidationFailed { msg })?;
+    let mut event_types: Vec<WebhookEventType> = vec![];
+    for event_type in payload.event_types {
+        if !event_types.contains(&event_type) {
+            event_types.push(event_type);
+        }
+    }
+    if event_types.is_empty() {
+        return Err(Error::ValidationFailed {
+            msg: "webhook must subscribe to at least one event type".to_string(),
+        });
+    }
+    if _get_event_webhooks(event_id).len() >= MAX_WEBHOOKS_PER_EVENT {
+        return Err(Error::ValidationFailed {
+            msg: format!(
+                "event id:{} can have at most {} webhooks",
+                event_id, MAX_WEBHOOKS_PER_EVENT
+            ),
+        });
+    }
+    // Secrets drawn before the seed would only depend on the time
+    if !entropy::is_seeded() {
+        return Err(Error::ValidationFailed {
+            msg: "webhooks can't be registered yet, try again shortly".to_string(),
+        });
+    }
+
+    // Increment the global ID counter to get a new ID for the webhook
+    let id = ID_COUNTER
+        .with(|counter| {
+            let current_id = *counter.borrow().get();
+            counter.borrow_mut().set(current_id + 1)
+        })
+        .expect("Cannot increment Ids");
+    let webhook = Webhook {
+        id,
+        event_id,
+        url,
+        event_types,
+        secret: Some(entropy::random_token(b"webhook", id)),
+        created_at: time(),
+    };
+    WEBHOOK_STORAGE.with(|webhooks| {
+        webhooks
+            .borrow_mut()
+            .insert((event_id, id), webhook.clone())
+    });
+
+    Ok(webhook)
+}
+
+#[ic_cdk::update(guard = "rate_limit")]
+fn remove_event_webhook(event_id: u64, webhook_id: u64) -> Result<String, Error> {
+    organizers::authorize_event(event_id)?;
+    WEBHOOK_STORAGE
+        .with(|webhooks| webhooks.borrow_mut().remove(&(event_id, webhook_id)))
+        .ok_or(Error::NotFound {
+            entity: "webhook".to_string(),
+            id: webhook_id.to_string(),
+            msg: format!(
+                "webhook id:{} does not exist for event id:{}",
+                webhook_id, event_id
+            ),
+        })?;
+    // Its history goes with it, nothing is delivered to it anymore
+    remove_deliveries(|delivery| delivery.webhook_id == webhook_id);
+    Ok(format!("webhook id: {} removed", webhook_id))
+}
+
+#[ic_cdk::query]
+fn get_event_webhooks(event_id: u64) -> Result<Vec<Webhook>, Error> {
+    organizers::authorize_event(event_id)?;
+    Ok(_get_event_webhooks(event_id)
+        .into_iter()
+        .map(|webhook| Webhook {
+            secret: None,
+            ..webhook
+        })
+        .collect())
+}
+
+// Deliveries of an event's webhooks, most recent first, to see what was sent and what failed
+#[ic_cdk::query]
+fn get_webhook_deliveries(
+    event_id: u64,
+    webhook_id: Option<u64>,
+) -> Result<Vec<WebhookDelivery>, Error> {
+    organizers::authorize_event(event_id)?;
+    let mut deliveries: Vec<WebhookDelivery> = DELIVERY_STORAGE.with(|deliveries| {
+        deliveries
+            .borrow()
+            .iter()
+            .map(|(_, delivery)| delivery)
+            .filter(|delivery| {
+                delivery.event_id == event_id
+                    && webhook_id.is_none_or(|webhook_id| delivery.webhook_id == webhook_id)
+            })
+            .collect()
+    });
+    deliveries.reverse();
+    Ok(deliveries)
+}
+
+#[ic_cdk::query]
+fn transform_webhook_response(args: TransformArgs) -> OutcallResponse {
+    // Only the status is used, headers and body differ between replicas
+    OutcallResponse {
+        status: args.response.status,
+        ..Default::default()
+    }
+}
+
+fn _get_event_webhooks(event_id: u64) -> Vec<Webhook> {
+    WEBHOOK_STORAGE.with(|webhooks| {
+        webhooks
+            .borrow()
+            .range((event_id, 0)..=(event_id, u64::MAX))
+            .map(|(_, webhook)| webhook)
+            .collect()
+    })
+}
+
+// Queue a delivery to every webhook of the event subscribed to the mutation
+pub(crate) fn enqueue_webhooks(
+    event_id: u64,
+    event_type: WebhookEventType,
+    data: serde_json::Value,
+) {
+    for webhook in _get_event_webhooks(event_id) {
+        if !webhook.event_types.contains(&event_type) {
+            continue;
+        }
+
+        // Increment the global ID counter to get a new ID for the delivery
+        let id = ID_COUNTER
+            .with(|counter| {
+                let current_id = *counter.borrow().get();
+                counter.borrow_mut().set(current_id + 1)
+            })
+            .expect("Cannot increment Ids");
+        // Receivers dedupe on the id, every replica makes the call
+        let payload = serde_json::json!({
+            "id": id,
+            "type": event_type.name(),
+            "event_id": event_id,
+            "created_at": time(),
+            "data": data,
+        });
+        let delivery = WebhookDelivery {
+            id,
+            webhook_id: webhook.id,
+            event_id,
+            event_type,
+            payload: payload.to_string(),
+            status: DeliveryStatus::Pending,
+            attempts: 0,
+            next_attempt_at: time(),
+            last_response_status: None,
+            last_error: None,
+            delivered_at: None,
+            created_at: time(),
+        };
+        DELIVERY_STORAGE.with(|deliveries| deliveries.borrow_mut().insert(id, delivery));
+    }
+}
+
+// Queue the check-in of a ticket, from the gate or synced from a scanner device
+pub(crate) fn enqueue_checked_in(ticket: &Ticket, zone: Option<String>) {
+    enqueue_webhooks(
+        ticket.event_id,
+        WebhookEventType::TicketCheckedIn,
+        serde_json::json!({
+            "ticket_id": ticket.id,
+            "user_id": ticket.user_id,
+            "checked_in_at": ticket.checked_in_at,
+            "zone": zone,
+        }),
+    );
+}
+
+// Start the timer sending due deliveries, timers don't survive upgrades
+pub(crate) fn schedule_delivery() {
+    ic_cdk_timers::set_timer_interval(DELIVERY_INTERVAL, || ic_cdk::spawn(deliver_webhooks()));
+}
+
+fn remove_deliveries(filter: impl Fn(&WebhookDelivery) -> bool) {
+    DELIVERY_STORAGE.with(|deliveries| {
+        let mut deliveries = deliveries.borrow_mut();
+        let ids: Vec<u64> = deliveries
+            .iter()
+            .filter(|(_, delivery)| filter(delivery))
+            .map(|(id, _)| id)
+            .collect();
+        for id in ids {
+            deliveries.remove(&id);
+        }
+    });
+}
+
+// Send the deliveries that are due, rescheduling the ones that fail
+async fn deliver_webhooks() {
+    if backup::in_maintenance() {
+        return;
+    }
+    let now = time();
+    remove_deliveries(|delivery| {
+        delivery.status != DeliveryStatus::Pending
+            && delivery.created_at + DELIVERY_RETENTION <= now
+    });
+    if DELIVERING.with(|delivering| delivering.replace(true)) {
+        return;
+    }
+
+    let due: Vec<WebhookDelivery> = DELIVERY_STORAGE.with(|deliveries| {
+        deliveries
+            .borrow()
+            .iter()
+            .map(|(_, delivery)| delivery)
+            .filter(|d| d.status == DeliveryStatus::Pending && d.next_attempt_at <= now)
+            .take(DELIVERY_BATCH)
+            .collect()
+    });
+
+    for mut delivery in due {
+        // Removed while the batch was out, along with its deliveries
+        let Some(webhook) = WEBHOOK_STORAGE.with(|webhooks| {
+            webhooks
+                .borrow()
+                .get(&(delivery.event_id, delivery.webhook_id))
+        }) else {
+            continue;
+        };
+        delivery.attempts += 1;
+        match send_delivery(&webhook, &delivery).await {
+            Ok(status) => {
+                delivery.last_response_status = Some(status);
+                delivery.last_error = None;
+                delivery.status = DeliveryStatus::Delivered;
+                delivery.delivered_at = Some(time());
+            }
+            Err((status, error)) => {
+                delivery.last_response_status = status;
+                delivery.last_error = Some(error);
+                if delivery.attempts >= MAX_ATTEMPTS {
+                    delivery.status = DeliveryStatus::Failed;
+                } else {
+                    let delay = RETRY_BASE_DELAY << (delivery.attempts - 1);
+                    delivery.next_attempt_at = time() + delay;
+                }
+            }
+        }
+        // Attempts and backoff live in stable memory, so retries carry on after an upgrade
+        if WEBHOOK_STORAGE.with(|webhooks| {
+            webhooks
+                .borrow()
+                .contains_key(&(delivery.event_id, delivery.webhook_id))
+        }) {
+            DELIVERY_STORAGE
+                .with(|deliveries| deliveries.borrow_mut().insert(delivery.id, delivery));
+        }
+    }
+
+    DELIVERING.with(|delivering| delivering.set(false));
+}
+
+// Check a URL deliveries can be posted to
+pub(crate) fn validate_url(url: &str) -> Result<(), String> {
+    if !url.starts_with("https://") || url.len() > MAX_URL_LEN {
+        return Err(format!(
+            "webhook url must use https and be at most {} bytes",
+            MAX_URL_LEN
+        ));
+    }
+    Ok(())
+}
+
+// POST a delivery to its webhook, signed with the webhook's secret. The signature covers the
+// timestamp and the body, so receivers can also reject replays.
+async fn send_delivery(
+    webhook: &Webhook,
+    delivery: &WebhookDelivery,
+) -> Result<u64, (Option<u64>, String)> {
+    let timestamp = (time() / 1_000_000_000).to_string();
+    let secret = webhook.secret.clone().unwrap_or_default();
+    let signed = format!("{}.{}", timestamp, delivery.payload);
+    let signature: String = hmac_sha256(secret.as_bytes(), signed.as_bytes())
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect();
+
+    let mut headers = vec![
+        HttpHeader {
+            name: "Content-Type".to_string(),
+            value: "application/json".to_string(),
+        },
+        HttpHeader {
+            name: "Idempotency-Key".to_string(),
+            value: delivery.id.to_string(),
+        },
+        HttpHeader {
+            name: "X-Webhook-Timestamp".to_string(),
+            value: timestamp,
+        },
+        HttpHeader {
+            name: "X-Webhook-Signature".to_string(),
+            value: format!("sha256={}", signature),
+        },
+    ];
+    // A relay passes the delivery on to the webhook, e.g. for receivers only reachable over IPv4
+    let url = match config::webhook_relay_url() {
+        Some(relay_url) => {
+            headers.push(HttpHeader {
+                name: "X-Forward-To".to_string(),
+                value: webhook.url.clone(),
+            });
+            relay_url
+        }
+        None => webhook.url.clone(),
+    };
+
+    let request = CanisterHttpRequestArgument {
+        url,
+        max_response_bytes: Some(OUTCALL_MAX_RESPONSE_BYTES),
+        method: HttpMethod::POST,
+        headers,
+        body: Some(delivery.payload.clone().into_bytes()),
+        transform: Some(TransformContext::from_name(
+            "transform_webhook_response".to_string(),
+            vec![],
+        )),
+    };
+
+    match http_request(request, OUTCALL_CYCLES).await {
+        Ok((response,)) => {
+            let status = u64::try_from(response.status.0).unwrap_or(0);
+            match (200..300).contains(&status) {
+                true => Ok(status),
+                false => Err((
+                    Some(status),
+                    format!("webhook answered with status {}", status),
+                )),
+            }
+        }
+        Err((code, msg)) => Err((None, format!("outcall failed ({:?}): {}", code, msg))),
+    }
+}
+
+// HMAC-SHA256 as in RFC 2104
+pub(crate) fn hmac_sha256(key: &[u8], message: &[u8]) -> [u8; 32] {
+    const BLOCK_SIZE: usize = 64;
+    let mut block = [0u8; BLOCK_SIZE];
+    match key.len() > BLOCK_SIZE {
+        true => block[..32].copy_from_slice(&Sha256::digest(key)),
+        false => block[..key.len()].copy_from_slice(key),
+    }
+
+    let mut inner = Sha256::new();
+    inner.update(block.map(|byte| byte ^ 0x36));
+    inner.update(message);
+    let mut outer = Sha256::new();
+    outer.update(block.map(|byte| byte ^ 0x5c));
+    outer.update(inner.finalize());
+    outer.finalize().into()
+}
+
+// Drop the webhooks and deliveries of a purged event
+pub(crate) fn remove_event_webhooks(event_id: u64) {
+    for webhook in _get_event_webhooks(event_id) {
+        WEBHOOK_STORAGE.with(|webhooks| webhooks.borrow_mut().remove(&(event_id, webhook.id)));
+    }
+    remove_deliveries(|delivery| delivery.event_id == event_id);
+}
@@ -0,0 +1,262 @@
+// Harness for the integration tests: installs the backend's wasm on a PocketIC instance and
+// calls it like a client would. The types below mirror the canister's interface, only with the
+// fields the tests look at, candid leaves out the rest when decoding.
+use candid::utils::ArgumentEncoder;
+use candid::{decode_one, encode_args, CandidType, Deserialize, Principal};
+use pocket_ic::{PocketIc, WasmResult};
+use std::path::PathBuf;
+
+// Enough to stay well above the canister's low cycles threshold, under which sales stop
+const INITIAL_CYCLES: u128 = 10_000_000_000_000;
+
+#[derive(CandidType, Deserialize, Clone, Default)]
+pub struct EventPayload {
+    pub name: String,
+    pub description: String,
+    pub date: String,
+    pub start_time: String,
+    pub capacity: Option<u64>,
+    pub max_tickets_per_user: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Default)]
+pub struct UserPayload {
+    pub name: String,
+    pub email: String,
+    pub password: String,
+}
+
+#[derive(CandidType, Deserialize, Clone, Default)]
+pub struct TicketPayload {
+    pub event_id: u64,
+    pub user_id: u64,
+    pub insured: bool,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct Event {
+    pub id: u64,
+    pub name: String,
+    pub capacity: Option<u64>,
+    pub attendee_ids: Vec<u64>,
+    pub ticket_ids: Vec<u64>,
+    pub version: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct CertifiedEvent {
+    pub event: Event,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct User {
+    pub id: u64,
+    pub name: String,
+    pub event_ids: Vec<u64>,
+    pub ticket_ids: Vec<u64>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct Ticket {
+    pub id: u64,
+    pub event_id: u64,
+    pub user_id: u64,
+    pub price: u64,
+}
+
+#[derive(CandidType, Deserialize, Clone, Copy, Debug)]
+pub enum DeleteMode {
+    Restrict,
+    Cascade,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub enum Error {
+    NotFound {
+        entity: String,
+        id: String,
+        msg: String,
+    },
+    ValidationFailed {
+        msg: String,
+    },
+    Unauthorized {
+        msg: String,
+    },
+    Conflict {
+        msg: String,
+        current_version: u64,
+    },
+    HasDependents {
+        msg: String,
+        retryable: bool,
+    },
+    TransferFrozen {
+        msg: String,
+        reopens_at: u64,
+    },
+    AlreadyExists {
+        msg: String,
+    },
+    OnLegalHold {
+        msg: String,
+        hold_id: u64,
+    },
+    CapacityExceeded {
+        msg: String,
+        retryable: bool,
+    },
+    PurchaseLimitExceeded {
+        msg: String,
+        count: u64,
+        limit: u64,
+    },
+    PaymentFailed {
+        msg: String,
+    },
+    InternalError {
+        msg: String,
+        ticket_id: Option<u64>,
+    },
+}
+
+// The canister installed on a fresh PocketIC instance, controlled by 'admin'
+pub struct Canister {
+    pub pic: PocketIc,
+    pub id: Principal,
+    pub admin: Principal,
+}
+
+impl Canister {
+    pub fn install() -> Canister {
+        let pic = PocketIc::new();
+        let admin = principal(1);
+        let id = pic.create_canister_with_settings(Some(admin), None);
+        pic.add_cycles(id, INITIAL_CYCLES);
+        pic.install_canister(id, wasm(), encode_args(()).unwrap(), Some(admin));
+        Canister { pic, id, admin }
+    }
+
+    // Upgrade to the same wasm, heap state is lost and only stable memory carries over
+    pub fn upgrade(&self) {
+        self.pic
+            .upgrade_canister(self.id, wasm(), encode_args(()).unwrap(), Some(self.admin))
+            .expect("upgrade failed");
+    }
+
+    pub fn update<R>(&self, sender: Principal, method: &str, args: impl ArgumentEncoder) -> R
+    where
+        R: CandidType + for<'de> Deserialize<'de>,
+    {
+        decode_reply(method, self.update_raw(sender, method, args))
+    }
+
+    pub fn query<R>(&self, sender: Principal, method: &str, args: impl ArgumentEncoder) -> R
+    where
+        R: CandidType + for<'de> Deserialize<'de>,
+    {
+        let result = self
+            .pic
+            .query_call(self.id, sender, method, encode_args(args).unwrap())
+            .map_err(|error| error.to_string());
+        decode_reply(method, result.and_then(into_reply))
+    }
+
+    // An update call's reply, or the message it was rejected with, e.g. by a guard
+    pub fn update_raw(
+        &self,
+        sender: Principal,
+        method: &str,
+        args: impl ArgumentEncoder,
+    ) -> Result<Vec<u8>, String> {
+        self.pic
+            .update_call(self.id, sender, method, encode_args(args).unwrap())
+            .map_err(|error| error.to_string())
+            .and_then(into_reply)
+    }
+
+    // Shorthands for the records most tests need, created by the admin
+
+    pub fn create_event(&self, capacity: Option<u64>) -> Event {
+        let payload = EventPayload {
+            name: "Launch party".to_string(),
+            description: "Drinks on the roof".to_string(),
+            date: "2030-06-01".to_string(),
+            start_time: "19:30".to_string(),
+            capacity,
+            max_tickets_per_user: None,
+        };
+        let result: Result<Event, Error> = self.update(self.admin, "create_event", (payload,));
+        result.expect("event could not be created")
+    }
+
+    pub fn create_user(&self, name: &str) -> User {
+        let payload = UserPayload {
+            name: name.to_string(),
+            email: format!("{}@example.com", name.to_lowercase()),
+            password: "correct horse battery".to_string(),
+        };
+        let result: Result<User, Error> = self.update(self.admin, "create_user", (payload,));
+        result.expect("user could not be created")
+    }
+
+    pub fn buy_ticket(&self, event_id: u64, user_id: u64) -> Result<Ticket, Error> {
+        let payload = TicketPayload {
+            event_id,
+            user_id,
+            insured: false,
+        };
+        self.update(self.admin, "create_ticket", (payload, None::<String>))
+    }
+
+    pub fn get_event(&self, id: u64) -> Result<Event, Error> {
+        let result: Result<CertifiedEvent, Error> =
+            self.query(self.admin, "get_event", (id, None::<String>));
+        result.map(|certified| certified.event)
+    }
+
+    pub fn get_user(&self, id: u64) -> Result<User, Error> {
+        self.query(self.admin, "get_user", (id,))
+    }
+
+    pub fn get_ticket(&self, id: u64) -> Result<Ticket, Error> {
+        self.query(self.admin, "get_ticket", (id,))
+    }
+}
+
+// A principal that isn't the anonymous one, told apart by 'n'
+pub fn principal(n: u8) -> Principal {
+    Principal::from_slice(&[n; 29])
+}
+
+// The release build of the backend, or the wasm 'E_TICKETER_WASM' points to
+fn wasm() -> Vec<u8> {
+    let path = std::env::var_os("E_TICKETER_WASM")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| {
+            PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+                .join("../target/wasm32-unknown-unknown/release/e_ticketer_backend.wasm")
+        });
+    std::fs::read(&path).unwrap_or_else(|error| {
+        panic!(
+            "cannot read {}, build the backend for wasm32-unknown-unknown first: {}",
+            path.display(),
+            error
+        )
+    })
+}
+
+fn into_reply(result: WasmResult) -> Result<Vec<u8>, String> {
+    match result {
+        WasmResult::Reply(bytes) => Ok(bytes),
+        WasmResult::Reject(msg) => Err(msg),
+    }
+}
+
+fn decode_reply<R>(method: &str, reply: Result<Vec<u8>, String>) -> R
+where
+    R: CandidType + for<'de> Deserialize<'de>,
+{
+    let bytes = reply.unwrap_or_else(|msg| panic!("'{}' was rejected: {}", method, msg));
+    decode_one(&bytes).unwrap_or_else(|error| panic!("cannot decode '{}': {}", method, error))
+}
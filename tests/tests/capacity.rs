@@ -0,0 +1,73 @@
+use e_ticketer_tests::{Canister, Error, Event, EventPayload};
+
+#[test]
+fn sold_out_event_turns_down_further_purchases() {
+    let canister = Canister::install();
+    let event = canister.create_event(Some(2));
+    let users: Vec<_> = ["Ada", "Grace", "Barbara"]
+        .iter()
+        .map(|name| canister.create_user(name))
+        .collect();
+
+    canister.buy_ticket(event.id, users[0].id).unwrap();
+    canister.buy_ticket(event.id, users[1].id).unwrap();
+    match canister.buy_ticket(event.id, users[2].id) {
+        // Sold out events point buyers to the waitlist, which the same call can't change
+        Err(Error::CapacityExceeded { retryable, .. }) => assert!(!retryable),
+        other => panic!("expected CapacityExceeded, got {:?}", other),
+    }
+    assert_eq!(canister.get_event(event.id).unwrap().ticket_ids.len(), 2);
+}
+
+#[test]
+fn event_without_capacity_sells_without_limit() {
+    let canister = Canister::install();
+    let event = canister.create_event(None);
+    for n in 0..5 {
+        let user = canister.create_user(&format!("Buyer{}", n));
+        canister.buy_ticket(event.id, user.id).unwrap();
+    }
+    assert_eq!(canister.get_event(event.id).unwrap().ticket_ids.len(), 5);
+}
+
+#[test]
+fn purchase_limit_caps_the_tickets_a_user_holds() {
+    let canister = Canister::install();
+    let payload = EventPayload {
+        name: "Workshop".to_string(),
+        description: "Hands on".to_string(),
+        date: "2030-06-02".to_string(),
+        start_time: "10:00".to_string(),
+        capacity: Some(10),
+        max_tickets_per_user: Some(2),
+    };
+    let event: Result<Event, Error> = canister.update(canister.admin, "create_event", (payload,));
+    let event = event.unwrap();
+    let user = canister.create_user("Ada");
+
+    canister.buy_ticket(event.id, user.id).unwrap();
+    canister.buy_ticket(event.id, user.id).unwrap();
+    match canister.buy_ticket(event.id, user.id) {
+        Err(Error::PurchaseLimitExceeded { count, limit, .. }) => {
+            assert_eq!((count, limit), (2, 2))
+        }
+        other => panic!("expected PurchaseLimitExceeded, got {:?}", other),
+    }
+}
+
+#[test]
+fn event_keeps_selling_past_the_size_of_its_record() {
+    let canister = Canister::install();
+    let event = canister.create_event(None);
+    let mut ticket_ids = vec![];
+    for n in 0..30 {
+        let user = canister.create_user(&format!("Buyer{}", n));
+        ticket_ids.push(canister.buy_ticket(event.id, user.id).unwrap().id);
+    }
+
+    // The ids are kept apart from the event, and carry over an upgrade
+    canister.upgrade();
+    let event = canister.get_event(event.id).unwrap();
+    assert_eq!(event.ticket_ids, ticket_ids);
+    assert_eq!(event.attendee_ids.len(), 30);
+}
@@ -0,0 +1,69 @@
+use e_ticketer_tests::{Canister, DeleteMode, Error};
+
+#[test]
+fn event_with_tickets_is_only_deleted_by_a_cascade() {
+    let canister = Canister::install();
+    let event = canister.create_event(None);
+    let user = canister.create_user("Ada");
+    let ticket = canister.buy_ticket(event.id, user.id).unwrap();
+
+    let restricted: Result<String, Error> = canister.update(
+        canister.admin,
+        "delete_event",
+        (event.id, Some(DeleteMode::Restrict)),
+    );
+    match restricted {
+        Err(Error::HasDependents { retryable, .. }) => assert!(!retryable),
+        other => panic!("expected HasDependents, got {:?}", other),
+    }
+    assert!(canister.get_event(event.id).is_ok());
+
+    let cascaded: Result<String, Error> = canister.update(
+        canister.admin,
+        "delete_event",
+        (event.id, Some(DeleteMode::Cascade)),
+    );
+    cascaded.unwrap();
+    assert!(matches!(
+        canister.get_event(event.id),
+        Err(Error::NotFound { .. })
+    ));
+    // The cascade cancels the tickets and unlinks their holders
+    assert!(matches!(
+        canister.get_ticket(ticket.id),
+        Err(Error::NotFound { .. })
+    ));
+    let user = canister.get_user(user.id).unwrap();
+    assert!(user.ticket_ids.is_empty());
+    assert!(user.event_ids.is_empty());
+}
+
+#[test]
+fn user_cascade_takes_their_tickets_off_the_event() {
+    let canister = Canister::install();
+    let event = canister.create_event(Some(10));
+    let ada = canister.create_user("Ada");
+    let grace = canister.create_user("Grace");
+    let ada_ticket = canister.buy_ticket(event.id, ada.id).unwrap();
+    let grace_ticket = canister.buy_ticket(event.id, grace.id).unwrap();
+
+    let restricted: Result<String, Error> =
+        canister.update(canister.admin, "delete_user", (ada.id, None::<DeleteMode>));
+    assert!(matches!(restricted, Err(Error::HasDependents { .. })));
+
+    let cascaded: Result<String, Error> = canister.update(
+        canister.admin,
+        "delete_user",
+        (ada.id, Some(DeleteMode::Cascade)),
+    );
+    cascaded.unwrap();
+    assert!(matches!(
+        canister.get_user(ada.id),
+        Err(Error::NotFound { .. })
+    ));
+    assert!(canister.get_ticket(ada_ticket.id).is_err());
+
+    let event = canister.get_event(event.id).unwrap();
+    assert_eq!(event.ticket_ids, vec![grace_ticket.id]);
+    assert_eq!(event.attendee_ids, vec![grace.id]);
+}
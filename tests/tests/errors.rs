@@ -0,0 +1,85 @@
+use e_ticketer_tests::{principal, Canister, Error, Event, EventPayload, User, UserPayload};
+
+#[test]
+fn missing_records_are_not_found() {
+    let canister = Canister::install();
+    match canister.get_event(404) {
+        Err(Error::NotFound { entity, id, .. }) => {
+            assert_eq!((entity.as_str(), id.as_str()), ("event", "404"))
+        }
+        other => panic!("expected NotFound, got {:?}", other),
+    }
+    assert!(matches!(
+        canister.get_user(404),
+        Err(Error::NotFound { .. })
+    ));
+    assert!(matches!(
+        canister.get_ticket(404),
+        Err(Error::NotFound { .. })
+    ));
+}
+
+#[test]
+fn invalid_payloads_fail_validation() {
+    let canister = Canister::install();
+    let payload = UserPayload {
+        name: "Ada".to_string(),
+        email: "ada@example.com".to_string(),
+        password: "short".to_string(),
+    };
+    let result: Result<User, Error> = canister.update(canister.admin, "create_user", (payload,));
+    assert!(matches!(result, Err(Error::ValidationFailed { .. })));
+
+    let payload = EventPayload {
+        name: "Limitless".to_string(),
+        max_tickets_per_user: Some(0),
+        ..Default::default()
+    };
+    let result: Result<Event, Error> = canister.update(canister.admin, "create_event", (payload,));
+    assert!(matches!(result, Err(Error::ValidationFailed { .. })));
+
+    // An event too large to store is turned down rather than trapping
+    let payload = EventPayload {
+        name: "Verbose".to_string(),
+        description: "x".repeat(2_000),
+        date: "2030-06-01".to_string(),
+        start_time: "19:30".to_string(),
+        ..Default::default()
+    };
+    let result: Result<Event, Error> = canister.update(canister.admin, "create_event", (payload,));
+    assert!(matches!(result, Err(Error::ValidationFailed { .. })));
+}
+
+#[test]
+fn email_can_only_be_used_once() {
+    let canister = Canister::install();
+    canister.create_user("Ada");
+    let payload = UserPayload {
+        name: "Someone else".to_string(),
+        email: "ADA@example.com".to_string(),
+        password: "correct horse battery".to_string(),
+    };
+    let result: Result<User, Error> = canister.update(canister.admin, "create_user", (payload,));
+    assert!(matches!(result, Err(Error::AlreadyExists { .. })));
+}
+
+#[test]
+fn only_organizers_create_events() {
+    let canister = Canister::install();
+    let payload = EventPayload {
+        name: "Unsanctioned".to_string(),
+        date: "2030-06-01".to_string(),
+        start_time: "19:30".to_string(),
+        ..Default::default()
+    };
+    let result: Result<Event, Error> = canister.update(principal(7), "create_event", (payload,));
+    assert!(matches!(result, Err(Error::Unauthorized { .. })));
+}
+
+#[test]
+fn admin_methods_reject_other_callers() {
+    let canister = Canister::install();
+    let result = canister.update_raw(principal(7), "purge_deleted", (0u64,));
+    let msg = result.expect_err("a non-admin purged the trash");
+    assert!(msg.contains("caller is not an admin"), "{}", msg);
+}
@@ -0,0 +1,65 @@
+use e_ticketer_tests::{Canister, Error, Ticket};
+
+#[test]
+fn ticket_purchase_links_the_ticket_to_its_event_and_holder() {
+    let canister = Canister::install();
+    let event = canister.create_event(Some(100));
+    let user = canister.create_user("Ada");
+
+    let ticket = canister.buy_ticket(event.id, user.id).unwrap();
+    assert_eq!(ticket.event_id, event.id);
+    assert_eq!(ticket.user_id, user.id);
+    // Events are free unless a payment method is set
+    assert_eq!(ticket.price, 0);
+
+    assert_eq!(canister.get_ticket(ticket.id).unwrap().id, ticket.id);
+    let event = canister.get_event(event.id).unwrap();
+    assert_eq!(event.ticket_ids, vec![ticket.id]);
+    assert_eq!(event.attendee_ids, vec![user.id]);
+    let user = canister.get_user(user.id).unwrap();
+    assert_eq!(user.ticket_ids, vec![ticket.id]);
+    assert_eq!(user.event_ids, vec![event.id]);
+}
+
+#[test]
+fn retried_purchase_with_the_same_idempotency_key_returns_the_first_ticket() {
+    let canister = Canister::install();
+    let event = canister.create_event(None);
+    let user = canister.create_user("Grace");
+    let payload = e_ticketer_tests::TicketPayload {
+        event_id: event.id,
+        user_id: user.id,
+        insured: false,
+    };
+    let key = Some("purchase-1".to_string());
+
+    let first: Result<Ticket, Error> = canister.update(
+        canister.admin,
+        "create_ticket",
+        (payload.clone(), key.clone()),
+    );
+    let retry: Result<Ticket, Error> =
+        canister.update(canister.admin, "create_ticket", (payload, key));
+    assert_eq!(first.unwrap().id, retry.unwrap().id);
+    assert_eq!(canister.get_event(event.id).unwrap().ticket_ids.len(), 1);
+}
+
+#[test]
+fn purchase_for_an_unknown_user_stores_no_ticket() {
+    let canister = Canister::install();
+    let event = canister.create_event(None);
+    let user = canister.create_user("Alan");
+
+    match canister.buy_ticket(event.id, 9_999) {
+        Err(Error::NotFound { entity, id, .. }) => {
+            assert_eq!(entity, "user");
+            assert_eq!(id, "9999");
+        }
+        other => panic!("expected NotFound, got {:?}", other),
+    }
+    assert!(canister.get_event(event.id).unwrap().ticket_ids.is_empty());
+
+    // The failed purchase took no id, the next ticket gets the one after the user's
+    let ticket = canister.buy_ticket(event.id, user.id).unwrap();
+    assert_eq!(ticket.id, user.id + 1);
+}
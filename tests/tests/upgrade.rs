@@ -0,0 +1,52 @@
+use e_ticketer_tests::Canister;
+
+#[test]
+fn records_survive_an_upgrade() {
+    let canister = Canister::install();
+    let event = canister.create_event(Some(50));
+    let user = canister.create_user("Ada");
+    let ticket = canister.buy_ticket(event.id, user.id).unwrap();
+    let before = canister.get_event(event.id).unwrap();
+
+    canister.upgrade();
+
+    let after = canister.get_event(event.id).unwrap();
+    assert_eq!(after.name, before.name);
+    assert_eq!(after.capacity, Some(50));
+    assert_eq!(after.ticket_ids, vec![ticket.id]);
+    assert_eq!(after.version, before.version);
+    assert_eq!(
+        canister.get_user(user.id).unwrap().ticket_ids,
+        vec![ticket.id]
+    );
+    assert_eq!(canister.get_ticket(ticket.id).unwrap().user_id, user.id);
+}
+
+#[test]
+fn ids_keep_counting_after_an_upgrade() {
+    let canister = Canister::install();
+    let first = canister.create_event(None);
+    canister.upgrade();
+    let second = canister.create_event(None);
+    assert!(
+        second.id > first.id,
+        "id {} was handed out again",
+        second.id
+    );
+}
+
+#[test]
+fn sales_carry_on_after_an_upgrade() {
+    let canister = Canister::install();
+    let event = canister.create_event(Some(2));
+    let ada = canister.create_user("Ada");
+    canister.buy_ticket(event.id, ada.id).unwrap();
+
+    canister.upgrade();
+
+    // Capacity is counted from the stored tickets, not from heap state
+    let grace = canister.create_user("Grace");
+    let barbara = canister.create_user("Barbara");
+    canister.buy_ticket(event.id, grace.id).unwrap();
+    assert!(canister.buy_ticket(event.id, barbara.id).is_err());
+}